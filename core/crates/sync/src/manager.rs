@@ -31,6 +31,14 @@ pub struct GetOpsArgs {
 	pub count: u32,
 }
 
+/// Which models' operations [`Manager::get_ops_of_models`] returns, by the names in their `NAME` constant
+#[derive(Debug, Clone, Copy)]
+pub enum ModelFilter<'a> {
+	All,
+	Only(&'a [&'a str]),
+	Except(&'a [&'a str]),
+}
+
 pub struct New {
 	pub manager: Manager,
 	pub rx: broadcast::Receiver<SyncMessage>,
@@ -144,6 +152,15 @@ impl Manager {
 	pub async fn get_ops(
 		&self,
 		args: GetOpsArgs,
+	) -> prisma_client_rust::Result<Vec<CRDTOperation>> {
+		self.get_ops_of_models(args, ModelFilter::All).await
+	}
+
+	/// Like [`Self::get_ops`], only returning the operations of the models `models` lets through
+	pub async fn get_ops_of_models(
+		&self,
+		args: GetOpsArgs,
+		models: ModelFilter<'_>,
 	) -> prisma_client_rust::Result<Vec<CRDTOperation>> {
 		let db = &self.db;
 
@@ -179,9 +196,22 @@ impl Manager {
 			};
 		}
 
+		let mut where_args = db_args!(args, crdt_operation);
+		let to_strings =
+			|models: &[&str]| -> Vec<String> { models.iter().map(ToString::to_string).collect() };
+		match models {
+			ModelFilter::All => {}
+			ModelFilter::Only(models) => {
+				where_args.push(crdt_operation::model::in_vec(to_strings(models)));
+			}
+			ModelFilter::Except(models) => {
+				where_args.push(crdt_operation::model::not_in_vec(to_strings(models)));
+			}
+		}
+
 		let mut ops = db
 			.crdt_operation()
-			.find_many(db_args!(args, crdt_operation))
+			.find_many(where_args)
 			.take(i64::from(args.count))
 			.order_by(crdt_operation::timestamp::order(SortOrder::Asc))
 			.include(crdt_include::include())
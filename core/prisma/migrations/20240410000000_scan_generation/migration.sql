@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "scan_generation" BIGINT;
+
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "scan_generation" BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "indexer_rule" ADD COLUMN "scope" TEXT;
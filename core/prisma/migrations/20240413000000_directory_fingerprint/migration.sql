@@ -0,0 +1,11 @@
+-- CreateTable
+CREATE TABLE "directory_fingerprint" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "materialized_path" TEXT NOT NULL,
+    "fingerprint" BLOB NOT NULL,
+    CONSTRAINT "directory_fingerprint_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "directory_fingerprint_location_id_materialized_path_key" ON "directory_fingerprint"("location_id", "materialized_path");
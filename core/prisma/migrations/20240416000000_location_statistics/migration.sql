@@ -0,0 +1,11 @@
+-- CreateTable
+CREATE TABLE "location_statistics" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "date_captured" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "extensions" BLOB NOT NULL,
+    CONSTRAINT "location_statistics_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "location_statistics_location_id_key" ON "location_statistics"("location_id");
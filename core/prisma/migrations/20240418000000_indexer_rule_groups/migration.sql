@@ -0,0 +1,34 @@
+-- CreateTable
+CREATE TABLE "indexer_rule_group" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "pub_id" BLOB NOT NULL,
+    "name" TEXT NOT NULL,
+    "date_created" DATETIME,
+    "date_modified" DATETIME
+);
+
+-- CreateTable
+CREATE TABLE "indexer_rule_in_group" (
+    "group_id" INTEGER NOT NULL,
+    "indexer_rule_id" INTEGER NOT NULL,
+
+    PRIMARY KEY ("group_id", "indexer_rule_id"),
+    CONSTRAINT "indexer_rule_in_group_group_id_fkey" FOREIGN KEY ("group_id") REFERENCES "indexer_rule_group" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "indexer_rule_in_group_indexer_rule_id_fkey" FOREIGN KEY ("indexer_rule_id") REFERENCES "indexer_rule" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "indexer_rule_group_in_location" (
+    "location_id" INTEGER NOT NULL,
+    "group_id" INTEGER NOT NULL,
+
+    PRIMARY KEY ("location_id", "group_id"),
+    CONSTRAINT "indexer_rule_group_in_location_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "indexer_rule_group_in_location_group_id_fkey" FOREIGN KEY ("group_id") REFERENCES "indexer_rule_group" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "indexer_rule_group_pub_id_key" ON "indexer_rule_group"("pub_id");
+
+-- CreateIndex
+CREATE UNIQUE INDEX "indexer_rule_group_name_key" ON "indexer_rule_group"("name");
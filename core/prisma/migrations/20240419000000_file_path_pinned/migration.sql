@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "pinned" BOOLEAN NOT NULL DEFAULT false;
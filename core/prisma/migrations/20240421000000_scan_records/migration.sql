@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "scan_record" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "location_id" INTEGER NOT NULL,
+    "generation" INTEGER NOT NULL,
+    "finished_at" DATETIME NOT NULL,
+    "summary" BLOB NOT NULL,
+    CONSTRAINT "scan_record_location_id_fkey" FOREIGN KEY ("location_id") REFERENCES "location" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "scan_record_location_id_generation_key" ON "scan_record"("location_id", "generation");
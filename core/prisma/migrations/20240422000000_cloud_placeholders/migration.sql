@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "exclude_cloud_placeholders" BOOLEAN;
+
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "is_placeholder" BOOLEAN;
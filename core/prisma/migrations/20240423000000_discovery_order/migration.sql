@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "track_discovery_order" BOOLEAN;
+
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "discovery_seq" BLOB;
@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "file_path" ADD COLUMN "child_files" INTEGER;
+ALTER TABLE "file_path" ADD COLUMN "child_dirs" INTEGER;
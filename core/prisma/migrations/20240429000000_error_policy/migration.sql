@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "location" ADD COLUMN "max_noncritical_ratio" REAL;
+ALTER TABLE "location" ADD COLUMN "max_noncritical_count" INTEGER;
@@ -85,6 +85,7 @@ mod library {
 								MaybeUndefined::Undefined,
 								MaybeUndefined::Value(cloud_library.id),
 								None,
+								None,
 							)
 							.await?;
 
@@ -129,6 +130,7 @@ mod library {
 							MaybeUndefined::Undefined,
 							MaybeUndefined::Value(cloud_library.id),
 							None,
+							None,
 						)
 						.await?;
 
@@ -381,6 +381,20 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					Ok(())
 				})
 		})
+		.procedure("pin", {
+			R.with2(library()).mutation(
+				|(_, library), file_path_ids: Vec<file_path::id::Type>| async move {
+					set_pinned(&library, file_path_ids, true).await
+				},
+			)
+		})
+		.procedure("unpin", {
+			R.with2(library()).mutation(
+				|(_, library), file_path_ids: Vec<file_path::id::Type>| async move {
+					set_pinned(&library, file_path_ids, false).await
+				},
+			)
+		})
 		// .procedure("encryptFiles", {
 		// 	R.with2(library())
 		// 		.mutation(|(node, library), args: FileEncryptorJobInit| async move {
@@ -872,6 +886,28 @@ pub(super) async fn create_directory(
 		.to_string())
 }
 
+/// Pinned file_paths are kept by the indexer even when their file goes missing. Pins are local to
+/// this instance, so they aren't synced.
+async fn set_pinned(
+	library: &Library,
+	file_path_ids: Vec<file_path::id::Type>,
+	pinned: bool,
+) -> Result<(), rspc::Error> {
+	library
+		.db
+		.file_path()
+		.update_many(
+			vec![file_path::id::in_vec(file_path_ids)],
+			vec![file_path::pinned::set(pinned)],
+		)
+		.exec()
+		.await?;
+
+	invalidate_query!(library, "search.paths");
+
+	Ok(())
+}
+
 #[derive(Type, Deserialize)]
 pub struct FromPattern {
 	pub pattern: String,
@@ -1,6 +1,8 @@
 use crate::{
 	invalidate_query,
-	location::{find_location, LocationError},
+	location::{
+		find_location, indexer::old_verifier_job::OldLocationVerifierJobInit, LocationError,
+	},
 	object::{
 		media::OldMediaProcessorJobInit,
 		old_file_identifier::old_file_identifier_job::OldFileIdentifierJobInit,
@@ -337,6 +339,29 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				},
 			)
 		})
+		.procedure("verifyLocation", {
+			#[derive(Type, Deserialize)]
+			pub struct VerifyLocationArgs {
+				pub id: location::id::Type,
+				#[serde(default)]
+				pub fix: bool,
+			}
+
+			R.with2(library())
+				.mutation(|(node, library), args: VerifyLocationArgs| async move {
+					let Some(location) = find_location(&library, args.id).exec().await? else {
+						return Err(LocationError::IdNotFound(args.id).into());
+					};
+
+					Job::new(OldLocationVerifierJobInit {
+						location,
+						fix: args.fix,
+					})
+					.spawn(&node, &library)
+					.await
+					.map_err(Into::into)
+				})
+		})
 		.procedure("newThumbnail", {
 			R.with2(library())
 				.subscription(|(node, _), _: ()| async move {
@@ -327,6 +327,8 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				pub id: Uuid,
 				pub name: Option<LibraryName>,
 				pub description: MaybeUndefined<String>,
+				#[serde(default)]
+				pub p2p_enabled: Option<bool>,
 			}
 
 			R.mutation(
@@ -335,10 +337,18 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				     id,
 				     name,
 				     description,
+				     p2p_enabled,
 				 }: EditLibraryArgs| async move {
 					Ok(node
 						.libraries
-						.edit(id, name, description, MaybeUndefined::Undefined, None)
+						.edit(
+							id,
+							name,
+							description,
+							MaybeUndefined::Undefined,
+							None,
+							p2p_enabled,
+						)
 						.await?)
 				},
 			)
@@ -2,11 +2,20 @@ use crate::{
 	invalidate_query,
 	location::{
 		delete_location, find_location,
-		indexer::{rules::IndexerRuleCreateArgs, OldIndexerJobInit},
+		indexer::{
+			preview_scan,
+			rules::{
+				document::{IndexerRulesExportArgs, IndexerRulesImportArgs},
+				groups::{RuleGroup, RuleGroupCreateArgs, RuleGroupUpdateArgs},
+				preview::{IndexerRulesPreviewArgs, PREVIEW_TIMEOUT},
+				IndexerRule, IndexerRuleCreateArgs,
+			},
+			scan_records, OldIndexerJobInit,
+		},
 		light_scan_location, location_with_indexer_rules,
 		non_indexed::NonIndexedPathItem,
-		relink_location, scan_location, scan_location_sub_path, LocationCreateArgs, LocationError,
-		LocationUpdateArgs,
+		relink_location, scan_location, scan_location_sub_path, set_location_attention,
+		LocationAttention, LocationCreateArgs, LocationError, LocationUpdateArgs,
 	},
 	object::old_file_identifier::old_file_identifier_job::OldFileIdentifierJobInit,
 	old_job::StatefulJob,
@@ -19,13 +28,17 @@ use sd_prisma::prisma::{
 	file_path, indexer_rule, indexer_rules_in_location, location, object, SortOrder,
 };
 
-use std::path::{Path, PathBuf};
+use std::{
+	collections::HashSet,
+	path::{Path, PathBuf},
+};
 
 use chrono::{DateTime, FixedOffset, Utc};
 use directories::UserDirs;
 use rspc::{alpha::AlphaRouter, ErrorCode};
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use tokio::time::timeout;
 use tracing::{debug, error};
 
 use super::{labels::label_with_objects, utils::library, Ctx, R};
@@ -243,6 +256,15 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				pub generate_preview_media: Option<bool>,
 				pub sync_preview_media: Option<bool>,
 				pub hidden: Option<bool>,
+				pub background_indexing: Option<bool>,
+				pub defer_recently_modified: Option<bool>,
+				pub exclude_cloud_placeholders: Option<bool>,
+				pub track_discovery_order: Option<bool>,
+				pub cross_filesystems: Option<bool>,
+				pub max_walk_depth: Option<i32>,
+				pub walker_memory_budget: Option<i32>,
+				pub max_noncritical_ratio: Option<f64>,
+				pub max_noncritical_count: Option<i32>,
 				pub date_created: Option<DateTime<FixedOffset>>,
 				pub instance_id: Option<i32>,
 				pub indexer_rules: Vec<Reference<indexer_rule::Data>>,
@@ -271,6 +293,15 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 						generate_preview_media: value.generate_preview_media,
 						sync_preview_media: value.sync_preview_media,
 						hidden: value.hidden,
+						background_indexing: value.background_indexing,
+						defer_recently_modified: value.defer_recently_modified,
+						exclude_cloud_placeholders: value.exclude_cloud_placeholders,
+						track_discovery_order: value.track_discovery_order,
+						cross_filesystems: value.cross_filesystems,
+						max_walk_depth: value.max_walk_depth,
+						walker_memory_budget: value.walker_memory_budget,
+						max_noncritical_ratio: value.max_noncritical_ratio,
+						max_noncritical_count: value.max_noncritical_count,
 						date_created: value.date_created,
 						instance_id: value.instance_id,
 						indexer_rules: value
@@ -410,6 +441,45 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				},
 			)
 		})
+		// Answers a location appearing empty, its scan removes what the drive doesn't have anymore
+		.procedure("confirmEmpty", {
+			R.with2(library()).mutation(
+				|(node, library), location_id: location::id::Type| async move {
+					let location = find_location(&library, location_id)
+						.exec()
+						.await?
+						.ok_or(LocationError::IdNotFound(location_id))?;
+					if LocationAttention::from_db(location.attention)
+						!= Some(LocationAttention::AppearsEmpty)
+					{
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							"The location doesn't appear to be empty".to_string(),
+						));
+					}
+
+					set_location_attention(
+						location_id,
+						Some(LocationAttention::ConfirmedEmpty),
+						&library.db,
+					)
+					.await?;
+					invalidate_query!(library, "locations.list");
+
+					scan_location(
+						&node,
+						&library,
+						find_location(&library, location_id)
+							.include(location_with_indexer_rules::include())
+							.exec()
+							.await?
+							.ok_or(LocationError::IdNotFound(location_id))?,
+					)
+					.await
+					.map_err(Into::into)
+				},
+			)
+		})
 		.procedure("subPathRescan", {
 			#[derive(Clone, Serialize, Deserialize, Type, Debug)]
 			pub struct RescanArgs {
@@ -484,6 +554,63 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				},
 			)
 		})
+		.procedure("scanPreview", {
+			#[derive(Clone, Serialize, Deserialize, Type, Debug)]
+			pub struct ScanPreviewArgs {
+				pub location_id: location::id::Type,
+				#[serde(default)]
+				pub sub_path: Option<String>,
+			}
+
+			R.with2(library()).query(
+				|(_, library),
+				 ScanPreviewArgs {
+				     location_id,
+				     sub_path,
+				 }: ScanPreviewArgs| async move {
+					let location = find_location(&library, location_id)
+						.include(location_with_indexer_rules::include())
+						.exec()
+						.await?
+						.ok_or(LocationError::IdNotFound(location_id))?;
+
+					// Dropping the walk when it takes too long stops it, like the rules preview
+					timeout(
+						PREVIEW_TIMEOUT,
+						preview_scan(&location, sub_path.as_deref().map(Path::new), &library.db),
+					)
+					.await
+					.map_err(|_| {
+						rspc::Error::new(
+							ErrorCode::Timeout,
+							"Previewing the scan took too long".to_string(),
+						)
+					})?
+					.map_err(Into::into)
+				},
+			)
+		})
+		.procedure("scanRecords", {
+			R.with2(library())
+				.query(|(_, library), location_id: location::id::Type| async move {
+					scan_records(location_id, &library.db)
+						.await
+						.map_err(Into::into)
+				})
+		})
+		.procedure("indexingUpdates", {
+			R.with2(library()).subscription(
+				|(_, library), location_id: location::id::Type| async move {
+					let mut subscriber = library.indexing_updates.subscribe(location_id);
+
+					Ok(async_stream::stream! {
+						while let Some(update) = subscriber.next().await {
+							yield update;
+						}
+					})
+				},
+			)
+		})
 		.procedure(
 			"online",
 			R.subscription(|node, _: ()| async move {
@@ -515,6 +642,7 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 
 fn mount_indexer_rule_routes() -> AlphaRouter<Ctx> {
 	R.router()
+		.merge("groups.", mount_indexer_rule_group_routes())
 		.procedure("create", {
 			R.with2(library())
 				.mutation(|(_, library), args: IndexerRuleCreateArgs| async move {
@@ -568,6 +696,40 @@ fn mount_indexer_rule_routes() -> AlphaRouter<Ctx> {
 					Ok(())
 				})
 		})
+		.procedure("export", {
+			R.with2(library())
+				.query(|(_, library), args: IndexerRulesExportArgs| async move {
+					let groups = RuleGroup::list(&library)
+						.await?
+						.into_iter()
+						.filter(|group| args.group_ids.contains(&group.id))
+						.collect::<Vec<_>>();
+
+					let rule_ids = args
+						.rule_ids
+						.into_iter()
+						.chain(
+							groups
+								.iter()
+								.flat_map(|group| group.rule_ids.iter().copied()),
+						)
+						.collect::<HashSet<_>>();
+
+					let rules = library
+						.db
+						.indexer_rule()
+						.find_many(vec![indexer_rule::id::in_vec(
+							rule_ids.into_iter().collect(),
+						)])
+						.exec()
+						.await?
+						.into_iter()
+						.map(IndexerRule::try_from)
+						.collect::<Result<Vec<_>, _>>()?;
+
+					Ok(IndexerRule::export(&rules, &groups))
+				})
+		})
 		.procedure("get", {
 			R.with2(library())
 				.query(|(_, library), indexer_rule_id: i32| async move {
@@ -586,6 +748,21 @@ fn mount_indexer_rule_routes() -> AlphaRouter<Ctx> {
 						.map(|i| NormalisedResult::from(i, |i| i.id.to_string()))
 				})
 		})
+		.procedure("import", {
+			R.with2(library())
+				.mutation(|(_, library), args: IndexerRulesImportArgs| async move {
+					let imported = IndexerRule::import(&args.document)?;
+					let saved =
+						IndexerRule::save_imported(&library, imported, args.on_collision).await?;
+
+					if saved > 0 {
+						invalidate_query!(library, "locations.indexer_rules.list");
+						invalidate_query!(library, "locations.indexer_rules.groups.list");
+					}
+
+					Ok(saved)
+				})
+		})
 		.procedure("list", {
 			R.with2(library()).query(|(_, library), _: ()| async move {
 				let rules = library.db.indexer_rule().find_many(vec![]).exec().await?;
@@ -613,4 +790,90 @@ fn mount_indexer_rule_routes() -> AlphaRouter<Ctx> {
 					Ok(NormalisedResults { items, nodes })
 				})
 		})
+		.procedure("preview", {
+			R.query(|_, args: IndexerRulesPreviewArgs| async move {
+				timeout(PREVIEW_TIMEOUT, args.preview())
+					.await
+					.map_err(|_| {
+						rspc::Error::new(
+							ErrorCode::Timeout,
+							"Previewing the indexer rules took too long".to_string(),
+						)
+					})?
+					.map_err(Into::into)
+			})
+		})
+}
+
+fn mount_indexer_rule_group_routes() -> AlphaRouter<Ctx> {
+	#[derive(Type, Deserialize)]
+	pub struct RuleGroupForLocationArgs {
+		pub location_id: location::id::Type,
+		pub group_id: i32,
+		pub enabled: bool,
+	}
+
+	R.router()
+		.procedure("create", {
+			R.with2(library())
+				.mutation(|(_, library), args: RuleGroupCreateArgs| async move {
+					let group = args.create(&library).await?;
+
+					invalidate_query!(library, "locations.indexer_rules.groups.list");
+
+					Ok(group)
+				})
+		})
+		.procedure("delete", {
+			R.with2(library())
+				.mutation(|(_, library), group_id: i32| async move {
+					RuleGroup::delete(&library, group_id).await?;
+
+					invalidate_query!(library, "locations.indexer_rules.groups.list");
+					invalidate_query!(library, "locations.indexer_rules.groups.listForLocation");
+
+					Ok(())
+				})
+		})
+		.procedure("list", {
+			R.with2(library()).query(|(_, library), _: ()| async move {
+				RuleGroup::list(&library).await.map_err(Into::into)
+			})
+		})
+		.procedure("listForLocation", {
+			R.with2(library())
+				.query(|(_, library), location_id: location::id::Type| async move {
+					RuleGroup::list_for_location(&library, location_id)
+						.await
+						.map_err(Into::into)
+				})
+		})
+		// Takes effect from the location's next scan, as rules are read again for each of them
+		.procedure("setForLocation", {
+			R.with2(library())
+				.mutation(|(_, library), args: RuleGroupForLocationArgs| async move {
+					RuleGroup::set_for_location(
+						&library,
+						args.location_id,
+						args.group_id,
+						args.enabled,
+					)
+					.await?;
+
+					invalidate_query!(library, "locations.indexer_rules.groups.listForLocation");
+
+					Ok(())
+				})
+		})
+		.procedure("update", {
+			R.with2(library())
+				.mutation(|(_, library), args: RuleGroupUpdateArgs| async move {
+					args.update(&library).await?;
+
+					invalidate_query!(library, "locations.indexer_rules.groups.list");
+					invalidate_query!(library, "locations.indexer_rules.groups.listForLocation");
+
+					Ok(())
+				})
+		})
 }
@@ -1,11 +1,11 @@
 use crate::{
 	invalidate_query,
 	node::{
-		config::{NodeConfig, NodePreferences, P2PDiscoveryState, Port},
+		config::{NodeConfig, NodePreferences, P2PDiscoveryState, Port, SpacedropMode},
 		get_hardware_model_name, HardwareModel,
 	},
 	old_job::JobProgressEvent,
-	p2p::{into_listener2, Listener2},
+	p2p::{into_listener2, Listener2, OperationPolicy, StreamBudgets},
 	Node,
 };
 
@@ -92,6 +92,23 @@ pub struct SanitisedNodeConfig {
 	pub p2p_ipv4_port: Port,
 	pub p2p_ipv6_port: Port,
 	pub p2p_discovery: P2PDiscoveryState,
+	pub spacedrop_mode: SpacedropMode,
+	pub spacedrop_timeout_secs: Option<u32>,
+	pub queue_spacedrop_when_unattended: bool,
+	pub spacedrop_parallelism: Option<u32>,
+	pub file_serve_concurrency: Option<u32>,
+	pub file_serve_bytes_per_sec: Option<u32>,
+	pub p2p_max_bytes_per_sec: Option<u32>,
+	pub public_locations: Vec<Uuid>,
+	pub keep_alive_interval_secs: Option<u32>,
+	pub keep_alive_max_failures: Option<u32>,
+	pub clock_skew_warning_secs: Option<u32>,
+	pub p2p_header_timeout_secs: Option<u32>,
+	pub p2p_tunnel_timeout_secs: Option<u32>,
+	pub p2p_allowed_operations: Option<OperationPolicy>,
+	pub p2p_stream_budgets: Option<StreamBudgets>,
+	pub p2p_diagnostics: bool,
+	pub walks_per_spinning_disk: Option<u32>,
 	pub features: Vec<BackendFeature>,
 	pub preferences: NodePreferences,
 	pub image_labeler_version: Option<String>,
@@ -106,6 +123,23 @@ impl From<NodeConfig> for SanitisedNodeConfig {
 			p2p_ipv4_port: value.p2p_ipv4_port,
 			p2p_ipv6_port: value.p2p_ipv6_port,
 			p2p_discovery: value.p2p_discovery,
+			spacedrop_mode: value.spacedrop_mode,
+			spacedrop_timeout_secs: value.spacedrop_timeout_secs,
+			queue_spacedrop_when_unattended: value.queue_spacedrop_when_unattended,
+			spacedrop_parallelism: value.spacedrop_parallelism,
+			file_serve_concurrency: value.file_serve_concurrency,
+			file_serve_bytes_per_sec: value.file_serve_bytes_per_sec,
+			p2p_max_bytes_per_sec: value.p2p_max_bytes_per_sec,
+			public_locations: value.public_locations,
+			keep_alive_interval_secs: value.keep_alive_interval_secs,
+			keep_alive_max_failures: value.keep_alive_max_failures,
+			clock_skew_warning_secs: value.clock_skew_warning_secs,
+			p2p_header_timeout_secs: value.p2p_header_timeout_secs,
+			p2p_tunnel_timeout_secs: value.p2p_tunnel_timeout_secs,
+			p2p_allowed_operations: value.p2p_allowed_operations,
+			p2p_stream_budgets: value.p2p_stream_budgets,
+			p2p_diagnostics: value.p2p_diagnostics,
+			walks_per_spinning_disk: value.walks_per_spinning_disk,
 			features: value.features,
 			preferences: value.preferences,
 			image_labeler_version: value.image_labeler_version,
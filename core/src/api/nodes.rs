@@ -1,6 +1,9 @@
 use crate::{
 	invalidate_query,
-	node::config::{P2PDiscoveryState, Port},
+	node::config::{P2PDiscoveryState, Port, SpacedropMode},
+	p2p::{
+		operations::SPACEDROP_MIN_TIMEOUT, OperationPolicy, P2PManager, PortStatus, StreamBudgets,
+	},
 };
 
 use sd_prisma::prisma::{instance, location};
@@ -22,6 +25,23 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				pub p2p_ipv4_port: Option<Port>,
 				pub p2p_ipv6_port: Option<Port>,
 				pub p2p_discovery: Option<P2PDiscoveryState>,
+				pub spacedrop_mode: Option<SpacedropMode>,
+				pub spacedrop_timeout_secs: Option<u32>,
+				pub queue_spacedrop_when_unattended: Option<bool>,
+				pub spacedrop_parallelism: Option<u32>,
+				pub file_serve_concurrency: Option<u32>,
+				pub file_serve_bytes_per_sec: Option<u32>,
+				pub p2p_max_bytes_per_sec: Option<u32>,
+				pub public_locations: Option<Vec<Uuid>>,
+				pub keep_alive_interval_secs: Option<u32>,
+				pub keep_alive_max_failures: Option<u32>,
+				pub clock_skew_warning_secs: Option<u32>,
+				pub p2p_header_timeout_secs: Option<u32>,
+				pub p2p_tunnel_timeout_secs: Option<u32>,
+				pub p2p_allowed_operations: Option<OperationPolicy>,
+				pub p2p_stream_budgets: Option<StreamBudgets>,
+				pub p2p_diagnostics: Option<bool>,
+				pub walks_per_spinning_disk: Option<u32>,
 				pub image_labeler_version: Option<String>,
 			}
 			R.mutation(|node, args: ChangeNodeNameArgs| async move {
@@ -34,6 +54,50 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 					}
 				}
 
+				if let Some(secs) = args.spacedrop_timeout_secs {
+					if u64::from(secs) < SPACEDROP_MIN_TIMEOUT.as_secs() {
+						return Err(rspc::Error::new(
+							ErrorCode::BadRequest,
+							format!(
+								"the Spacedrop timeout can't be shorter than {} seconds",
+								SPACEDROP_MIN_TIMEOUT.as_secs()
+							),
+						));
+					}
+				}
+
+				// Refuse ports we can't listen on, instead of finding out once the listener fails to start
+				let config = node.config.get().await;
+				for (port, current, v6) in [
+					(args.p2p_ipv4_port, config.p2p_ipv4_port, false),
+					(args.p2p_ipv6_port, config.p2p_ipv6_port, true),
+				] {
+					let Some(Port::Discrete(port)) = port else {
+						continue;
+					};
+
+					// We are the ones using the port we are already listening on
+					if matches!(current, Port::Discrete(current) if current == port) {
+						continue;
+					}
+
+					match P2PManager::test_port(port, v6) {
+						PortStatus::Available => {}
+						PortStatus::InUse => {
+							return Err(rspc::Error::new(
+								ErrorCode::BadRequest,
+								format!("port {port} is already in use"),
+							));
+						}
+						PortStatus::Unavailable { error } => {
+							return Err(rspc::Error::new(
+								ErrorCode::BadRequest,
+								format!("port {port} can't be used: {error}"),
+							));
+						}
+					}
+				}
+
 				#[cfg(feature = "ai")]
 				let mut new_model = None;
 
@@ -52,6 +116,57 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 						if let Some(v) = args.p2p_discovery {
 							config.p2p_discovery = v;
 						};
+						if let Some(mode) = args.spacedrop_mode {
+							config.spacedrop_mode = mode;
+						};
+						if let Some(secs) = args.spacedrop_timeout_secs {
+							config.spacedrop_timeout_secs = Some(secs);
+						};
+						if let Some(queue) = args.queue_spacedrop_when_unattended {
+							config.queue_spacedrop_when_unattended = queue;
+						};
+						if let Some(parallelism) = args.spacedrop_parallelism {
+							config.spacedrop_parallelism = Some(parallelism);
+						};
+						if let Some(concurrency) = args.file_serve_concurrency {
+							config.file_serve_concurrency = Some(concurrency);
+						};
+						if let Some(bytes_per_sec) = args.file_serve_bytes_per_sec {
+							config.file_serve_bytes_per_sec = Some(bytes_per_sec);
+						};
+						if let Some(bytes_per_sec) = args.p2p_max_bytes_per_sec {
+							config.p2p_max_bytes_per_sec = Some(bytes_per_sec);
+						};
+						if let Some(locations) = args.public_locations {
+							config.public_locations = locations;
+						};
+						if let Some(secs) = args.keep_alive_interval_secs {
+							config.keep_alive_interval_secs = Some(secs);
+						};
+						if let Some(failures) = args.keep_alive_max_failures {
+							config.keep_alive_max_failures = Some(failures);
+						};
+						if let Some(secs) = args.clock_skew_warning_secs {
+							config.clock_skew_warning_secs = Some(secs);
+						};
+						if let Some(secs) = args.p2p_header_timeout_secs {
+							config.p2p_header_timeout_secs = Some(secs);
+						};
+						if let Some(secs) = args.p2p_tunnel_timeout_secs {
+							config.p2p_tunnel_timeout_secs = Some(secs);
+						};
+						if let Some(policy) = args.p2p_allowed_operations {
+							config.p2p_allowed_operations = Some(policy);
+						};
+						if let Some(budgets) = args.p2p_stream_budgets {
+							config.p2p_stream_budgets = Some(budgets);
+						};
+						if let Some(enabled) = args.p2p_diagnostics {
+							config.p2p_diagnostics = enabled;
+						};
+						if let Some(walks) = args.walks_per_spinning_disk {
+							config.walks_per_spinning_disk = Some(walks);
+						};
 
 						#[cfg(feature = "ai")]
 						if let Some(version) = args.image_labeler_version {
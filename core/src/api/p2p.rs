@@ -1,26 +1,38 @@
-use crate::p2p::{operations, ConnectionMethod, DiscoveryMethod, Header, P2PEvent, PeerMetadata};
+use crate::{
+	invalidate_query,
+	p2p::{
+		operations::{self, IdentifyError, SpacedropError},
+		ConnectionMethod, DiscoveryMethod, P2PEvent, P2PManager, PeerMetadata,
+		SpacedropHistoryFilter,
+	},
+};
 
 use sd_p2p::{PeerConnectionCandidate, RemoteIdentity};
+use sd_prisma::prisma::{file_path, object};
+use sd_utils::uuid_to_bytes;
 
 use rspc::{alpha::AlphaRouter, ErrorCode};
 use serde::Deserialize;
 use specta::Type;
-use std::path::PathBuf;
-use tokio::io::AsyncWriteExt;
+use std::{path::PathBuf, time::Duration};
 use uuid::Uuid;
 
-use super::{Ctx, R};
+use super::{utils::library, Ctx, R};
+
+/// How long `debugConnect` waits for the peer to answer its ping
+const DEBUG_PING_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub(crate) fn mount() -> AlphaRouter<Ctx> {
 	R.router()
 		.procedure("events", {
 			R.subscription(|node, _: ()| async move {
-				let mut rx = node.p2p.events.subscribe();
+				let (mut rx, held) = node.p2p.subscribe_frontend();
 
 				let mut queued = Vec::new();
 
 				for (_, peer, metadata) in node.p2p.p2p.peers().iter().filter_map(|(i, p)| {
 					PeerMetadata::from_hashmap(&p.metadata())
+						.or_else(|err| err.into_partial().ok_or(()))
 						.ok()
 						.map(|m| (i, p, m))
 				}) {
@@ -43,6 +55,8 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 						metadata,
 					});
 				}
+				// The Spacedrops which came in while no frontend was open
+				queued.extend(held);
 
 				Ok(async_stream::stream! {
 					for event in queued.drain(..queued.len()) {
@@ -55,35 +69,141 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				})
 			})
 		})
+		.procedure("diagnostics", {
+			R.subscription(|node, _: ()| async move {
+				let mut rx = node.p2p.events.subscribe_filtered(&["Diagnostic"]);
+
+				Ok(async_stream::stream! {
+					while let Ok(event) = rx.recv().await {
+						yield event;
+					}
+				})
+			})
+		})
 		.procedure("state", {
 			R.query(|node, _: ()| async move { Ok(node.p2p.state().await) })
 		})
-		.procedure("debugConnect", {
+		.procedure("connectionLog", {
+			R.query(|node, identity: Option<RemoteIdentity>| async move {
+				Ok(node.p2p.connection_log(identity))
+			})
+		})
+		.procedure("identify", {
+			R.query(|node, identity: RemoteIdentity| async move {
+				node.p2p.identify(identity).await.map_err(|err| {
+					rspc::Error::with_cause(
+						match &err {
+							IdentifyError::PeerNotFound => ErrorCode::NotFound,
+							_ => ErrorCode::InternalServerError,
+						},
+						"Failed to identify peer".into(),
+						err,
+					)
+				})
+			})
+		})
+		.procedure("knownPeers", {
+			R.query(|node, _: ()| async move { Ok(node.p2p.known_peers().await) })
+		})
+		.procedure("forgetPeer", {
 			R.mutation(|node, identity: RemoteIdentity| async move {
-				let peer = { node.p2p.p2p.peers().get(&identity).cloned() };
-				let mut stream = peer
-					.ok_or(rspc::Error::new(
-						ErrorCode::InternalServerError,
-						"big man, not found".into(),
-					))?
-					.new_stream()
+				node.p2p.known_peers.forget(identity).await;
+				invalidate_query!(node; node, "p2p.knownPeers");
+
+				Ok(())
+			})
+		})
+		.procedure("metrics", {
+			R.query(|node, _: ()| async move { Ok(node.p2p.metrics.snapshot()) })
+		})
+		.procedure("resetMetrics", {
+			R.mutation(|node, _: ()| async move {
+				node.p2p.metrics.reset();
+				Ok(())
+			})
+		})
+		.procedure("pair", {
+			R.mutation(|node, identity: RemoteIdentity| async move {
+				operations::pair(node.p2p.clone(), identity)
 					.await
 					.map_err(|err| {
-						rspc::Error::new(
+						rspc::Error::with_cause(
 							ErrorCode::InternalServerError,
-							format!("error in peer.new_stream: {:?}", err),
+							"Failed to start pairing".into(),
+							err,
 						)
-					})?;
+					})
+			})
+		})
+		.procedure("confirmPairing", {
+			#[derive(Type, Deserialize)]
+			pub struct ConfirmPairingArgs {
+				id: Uuid,
+				confirmed: bool,
+			}
 
-				stream
-					.write_all(&Header::Ping.to_bytes())
+			R.mutation(|node, args: ConfirmPairingArgs| async move {
+				node.p2p.confirm_pairing(args.id, args.confirmed);
+				Ok(())
+			})
+		})
+		.procedure("rotateIdentity", {
+			R.mutation(|node, _: ()| async move {
+				node.p2p.rotate_identity().await.map_err(|err| {
+					rspc::Error::with_cause(
+						ErrorCode::InternalServerError,
+						"Failed to rotate the node identity".into(),
+						err,
+					)
+				})
+			})
+		})
+		.procedure("setPeerAlias", {
+			#[derive(Type, Deserialize)]
+			pub struct SetPeerAliasArgs {
+				identity: RemoteIdentity,
+				// `None` goes back to the name the peer advertises
+				alias: Option<String>,
+			}
+
+			R.mutation(|node, args: SetPeerAliasArgs| async move {
+				node.p2p
+					.set_peer_alias(args.identity, args.alias)
 					.await
 					.map_err(|err| {
-						rspc::Error::new(
+						rspc::Error::with_cause(
 							ErrorCode::InternalServerError,
-							format!("error sending ping header: {:?}", err),
+							"Failed to save the peer alias".into(),
+							err,
 						)
 					})?;
+				invalidate_query!(node; node, "p2p.state");
+
+				Ok(())
+			})
+		})
+		.procedure("testPort", {
+			#[derive(Type, Deserialize)]
+			pub struct TestPortArgs {
+				port: u16,
+				v6: bool,
+			}
+
+			R.query(
+				|_, args: TestPortArgs| async move { Ok(P2PManager::test_port(args.port, args.v6)) },
+			)
+		})
+		.procedure("selfTest", {
+			R.mutation(|node, _: ()| async move { Ok(node.p2p.self_test().await) })
+		})
+		.procedure("debugConnect", {
+			R.mutation(|node, identity: RemoteIdentity| async move {
+				let peer = { node.p2p.p2p.peers().get(&identity).cloned() }.ok_or_else(|| {
+					rspc::Error::new(ErrorCode::NotFound, "peer not found".into())
+				})?;
+				let protocol = node.p2p.peer_protocol(identity).await;
+				operations::ping::ping(&peer, DEBUG_PING_TIMEOUT, &protocol, &node.p2p.metrics)
+					.await?;
 
 				Ok("connected")
 			})
@@ -105,17 +225,102 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 						.collect::<Vec<_>>(),
 				)
 				.await
-				.map_err(|_err| {
-					rspc::Error::new(ErrorCode::InternalServerError, "todo: error".into())
+				.map_err(spacedrop_error)
+			})
+		})
+		.procedure("spacedropObjects", {
+			#[derive(Type, Deserialize)]
+			pub struct SpacedropObjectsArgs {
+				identity: RemoteIdentity,
+				object_ids: Vec<object::id::Type>,
+			}
+
+			R.with2(library())
+				.mutation(|(node, library), args: SpacedropObjectsArgs| async move {
+					operations::spacedrop_objects(&node, &library, args.identity, args.object_ids)
+						.await
+						.map_err(spacedrop_error)
 				})
+		})
+		.procedure("spacedropText", {
+			#[derive(Type, Deserialize)]
+			pub struct SpacedropTextArgs {
+				identity: RemoteIdentity,
+				text: String,
+			}
+
+			R.mutation(|node, args: SpacedropTextArgs| async move {
+				operations::spacedrop_text(node.p2p.clone(), args.identity, args.text)
+					.await
+					.map_err(spacedrop_error)
 			})
 		})
+		.procedure("downloadFile", {
+			#[derive(Type, Deserialize)]
+			pub struct DownloadFileArgs {
+				identity: RemoteIdentity,
+				file_path_id: Uuid,
+				to: String,
+			}
+
+			// Returns how many bytes were saved
+			R.with2(library())
+				.mutation(|(node, library), args: DownloadFileArgs| async move {
+					// Checked against what the library knows of it, so a file which changed since isn't saved
+					let cas_id = library
+						.db
+						.file_path()
+						.find_unique(file_path::pub_id::equals(uuid_to_bytes(args.file_path_id)))
+						.select(file_path::select!({ cas_id }))
+						.exec()
+						.await?
+						.and_then(|file_path| file_path.cas_id);
+
+					Ok(operations::download_file(
+						&node.p2p,
+						args.identity,
+						library.id,
+						args.file_path_id,
+						cas_id,
+						&PathBuf::from(args.to),
+					)
+					.await?
+					.to_string())
+				})
+		})
 		.procedure("acceptSpacedrop", {
-			R.mutation(|node, (id, path): (Uuid, Option<String>)| async move {
-				match path {
-					Some(path) => node.p2p.accept_spacedrop(id, path).await,
-					None => node.p2p.reject_spacedrop(id).await,
-				};
+			#[derive(Type, Deserialize)]
+			pub struct AcceptSpacedropArgs {
+				id: Uuid,
+				// `None` rejects the Spacedrop
+				path: Option<String>,
+				overwrite: bool,
+				apply_permissions: bool,
+			}
+
+			// Returns the text for text Spacedrops small enough to go straight to the clipboard
+			R.mutation(|node, args: AcceptSpacedropArgs| async move {
+				Ok(match args.path {
+					Some(path) => node
+						.p2p
+						.accept_spacedrop(
+							args.id,
+							PathBuf::from(path),
+							args.overwrite,
+							args.apply_permissions,
+						)
+						.await
+						.map_err(|err| rspc::Error::new(ErrorCode::BadRequest, err.to_string()))?,
+					None => {
+						node.p2p.reject_spacedrop(args.id).await;
+						None
+					}
+				})
+			})
+		})
+		.procedure("keepAliveSpacedrop", {
+			R.mutation(|node, id: Uuid| async move {
+				node.p2p.keep_alive_spacedrop(id).await;
 
 				Ok(())
 			})
@@ -127,4 +332,28 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 				Ok(())
 			})
 		})
+		.procedure("spacedropHistory", {
+			R.query(|node, filter: SpacedropHistoryFilter| async move {
+				Ok(node.p2p.spacedrop_history.list(&filter).await)
+			})
+		})
+		.procedure("clearHistory", {
+			R.mutation(|node, _: ()| async move {
+				node.p2p.spacedrop_history.clear().await;
+
+				Ok(())
+			})
+		})
+}
+
+fn spacedrop_error(err: SpacedropError) -> rspc::Error {
+	match err {
+		SpacedropError::ReceivingDisabled(_) => {
+			rspc::Error::new(ErrorCode::Forbidden, err.to_string())
+		}
+		SpacedropError::P2P(err) => err.into(),
+		SpacedropError::TooManyFiles(_)
+		| SpacedropError::Empty
+		| SpacedropError::TextTooLong(_) => rspc::Error::new(ErrorCode::BadRequest, err.to_string()),
+	}
 }
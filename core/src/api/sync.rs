@@ -60,6 +60,7 @@ pub(crate) fn mount() -> AlphaRouter<Ctx> {
 							MaybeUndefined::Undefined,
 							MaybeUndefined::Undefined,
 							Some(true),
+							None,
 						)
 						.await?;
 
@@ -10,20 +10,14 @@ use crate::{
 use http_body::combinators::UnsyncBoxBody;
 use hyper::{header, upgrade::OnUpgrade};
 use sd_file_ext::text::is_text;
-use sd_file_path_helper::{file_path_to_handle_custom_uri, IsolatedFilePathData};
+use sd_file_path_helper::{
+	file_path_to_handle_custom_uri, full_path_on_disk, IsolatedFilePathData,
+};
 use sd_p2p::{RemoteIdentity, P2P};
 use sd_prisma::prisma::{file_path, location};
 use sd_utils::db::maybe_missing;
 
-use std::{
-	cmp::min,
-	ffi::OsStr,
-	fmt::Debug,
-	fs::Metadata,
-	path::{Path, PathBuf},
-	str::FromStr,
-	sync::Arc,
-};
+use std::{cmp::min, ffi::OsStr, fmt::Debug, fs::Metadata, path::PathBuf, str::FromStr, sync::Arc};
 
 use axum::{
 	body::{self, Body, BoxBody, Full},
@@ -171,8 +165,11 @@ async fn get_or_init_lru_entry(
 		let instance = maybe_missing(&location.instance, "file_path.location.instance")
 			.map_err(internal_server_error)?;
 
-		let path = Path::new(path)
-			.join(IsolatedFilePathData::try_from((location_id, &file_path)).map_err(not_found)?);
+		let path = full_path_on_disk(
+			path,
+			&IsolatedFilePathData::try_from((location_id, &file_path)).map_err(not_found)?,
+			file_path.name_bytes.as_deref(),
+		);
 
 		let identity =
 			RemoteIdentity::from_bytes(&instance.remote_identity).map_err(internal_server_error)?;
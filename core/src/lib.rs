@@ -43,6 +43,9 @@ pub(crate) mod notifications;
 pub(crate) mod object;
 pub(crate) mod old_job;
 pub(crate) mod p2p;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)] // Only for the fuzz targets in `core/fuzz`
+pub use p2p::fuzz;
 pub(crate) mod preferences;
 #[doc(hidden)] // TODO(@Oscar): Make this private when breaking out `utils` into `sd-utils`
 pub mod util;
@@ -84,6 +87,15 @@ impl Node {
 	pub async fn new(
 		data_dir: impl AsRef<Path>,
 		env: env::Env,
+	) -> Result<(Arc<Node>, Arc<Router>), NodeError> {
+		Self::new_with_p2p_transport(data_dir, env, p2p::P2PTransport::Quic).await
+	}
+
+	/// Like [`Self::new`], reaching other nodes through `p2p_transport`
+	pub(crate) async fn new_with_p2p_transport(
+		data_dir: impl AsRef<Path>,
+		env: env::Env,
+		p2p_transport: p2p::P2PTransport,
 	) -> Result<(Arc<Node>, Arc<Router>), NodeError> {
 		let data_dir = data_dir.as_ref();
 
@@ -116,9 +128,10 @@ impl Node {
 		let (old_jobs, jobs_actor) = old_job::OldJobs::new();
 		let libraries = library::Libraries::new(data_dir.join("libraries")).await?;
 
-		let (p2p, start_p2p) = p2p::P2PManager::new(config.clone(), libraries.clone())
-			.await
-			.map_err(NodeError::P2PManager)?;
+		let (p2p, start_p2p) =
+			p2p::P2PManager::new_with_transport(config.clone(), libraries.clone(), p2p_transport)
+				.await
+				.map_err(NodeError::P2PManager)?;
 		let node = Arc::new(Node {
 			data_dir: data_dir.to_path_buf(),
 			old_jobs,
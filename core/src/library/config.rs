@@ -43,9 +43,16 @@ pub struct LibraryConfig {
 	// true = sync is enabled as either the library is new or it has been manually toggled on
 	#[serde(default)]
 	pub generate_sync_operations: Arc<AtomicBool>,
+	/// p2p_enabled controls whether this library is advertised to, synced with and served to other nodes over P2P.
+	#[serde(default = "default_p2p_enabled")]
+	pub p2p_enabled: bool,
 	version: LibraryConfigVersion,
 }
 
+fn default_p2p_enabled() -> bool {
+	true
+}
+
 #[derive(
 	IntEnum,
 	Debug,
@@ -97,6 +104,7 @@ impl LibraryConfig {
 			cloud_id: None,
 			// will always be `true` eventually
 			generate_sync_operations: Arc::new(AtomicBool::new(generate_sync_operations)),
+			p2p_enabled: true,
 		};
 
 		this.save(path).await.map(|()| this)
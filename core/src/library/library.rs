@@ -1,8 +1,12 @@
 use crate::{
-	api::CoreEvent, cloud, object::media::old_thumbnail::get_indexed_thumbnail_path, sync, Node,
+	api::CoreEvent,
+	cloud,
+	location::indexer::{InFlightUpdates, IndexingUpdates},
+	object::media::old_thumbnail::get_indexed_thumbnail_path,
+	sync, Node,
 };
 
-use sd_file_path_helper::{file_path_to_full_path, IsolatedFilePathData};
+use sd_file_path_helper::{file_path_to_full_path, full_path_on_disk, IsolatedFilePathData};
 use sd_p2p::Identity;
 use sd_prisma::prisma::{file_path, location, PrismaClient};
 use sd_utils::{db::maybe_missing, error::FileIOError};
@@ -54,6 +58,10 @@ pub struct Library {
 	event_bus_tx: broadcast::Sender<CoreEvent>,
 
 	pub actors: Arc<sd_actors::Actors>,
+	/// Entries written by the indexer, for the Explorer to show them while indexing
+	pub indexing_updates: IndexingUpdates,
+	/// Updates written by the indexer scans currently running, see [`InFlightUpdates`]
+	pub in_flight_updates: InFlightUpdates,
 }
 
 impl Debug for Library {
@@ -97,6 +105,8 @@ impl Library {
 			env: node.env.clone(),
 			event_bus_tx: node.event_bus.0.clone(),
 			actors,
+			indexing_updates: IndexingUpdates::default(),
+			in_flight_updates: InFlightUpdates::default(),
 		})
 	}
 
@@ -167,8 +177,9 @@ impl Library {
 							.path
 							.as_ref()
 							.map(|location_path| {
+								let name_bytes = file_path.name_bytes.as_deref();
 								IsolatedFilePathData::try_from((location.id, &file_path))
-									.map(|data| Path::new(&location_path).join(data))
+									.map(|data| full_path_on_disk(location_path, &data, name_bytes))
 							})
 							.transpose()?,
 					))
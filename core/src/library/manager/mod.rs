@@ -256,6 +256,7 @@ impl Libraries {
 		description: MaybeUndefined<String>,
 		cloud_id: MaybeUndefined<String>,
 		enable_sync: Option<bool>,
+		p2p_enabled: Option<bool>,
 	) -> Result<(), LibraryManagerError> {
 		// check library is valid
 		let libraries = self.libraries.read().await;
@@ -290,6 +291,9 @@ impl Libraries {
 							.generate_sync_operations
 							.store(value, Ordering::SeqCst),
 					}
+					if let Some(p2p_enabled) = p2p_enabled {
+						config.p2p_enabled = p2p_enabled;
+					}
 				},
 				self.libraries_dir.join(format!("{id}.sdlibrary")),
 			)
@@ -513,6 +517,10 @@ impl Libraries {
 
 		// This is an exception. Generally subscribe to this by `self.tx.subscribe`.
 		tokio::spawn(sync_rx_actor(library.clone(), node.clone(), sync.rx));
+		tokio::spawn(p2p::sync::backfill_on_load(
+			library.clone(),
+			node.p2p.clone(),
+		));
 
 		self.tx
 			.emit(LibraryManagerEvent::Load(library.clone()))
@@ -646,6 +654,7 @@ impl Libraries {
 											MaybeUndefined::Undefined,
 											MaybeUndefined::Null,
 											None,
+											None,
 										)
 										.await;
 								}
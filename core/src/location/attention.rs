@@ -0,0 +1,63 @@
+use sd_prisma::prisma::{location, PrismaClient};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+/// Why a location is waiting on the user, stored as `location.attention`
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Type, Eq, PartialEq)]
+pub enum LocationAttention {
+	/// A scan found the root empty while the database has entries for it, which usually means its
+	/// drive isn't connected, so none of them were removed
+	AppearsEmpty = 0,
+	/// The user answered that the location really is empty, so its next scan removes what it
+	/// doesn't find
+	ConfirmedEmpty = 1,
+}
+
+impl LocationAttention {
+	/// Values this version doesn't know, eg. written by a newer one, are left alone
+	pub fn from_db(value: Option<i32>) -> Option<Self> {
+		match value? {
+			0 => Some(Self::AppearsEmpty),
+			1 => Some(Self::ConfirmedEmpty),
+			_ => None,
+		}
+	}
+}
+
+pub(crate) async fn set_location_attention(
+	location_id: location::id::Type,
+	attention: Option<LocationAttention>,
+	db: &PrismaClient,
+) -> Result<(), prisma_client_rust::QueryError> {
+	db.location()
+		.update(
+			location::id::equals(location_id),
+			vec![location::attention::set(attention.map(|a| a as i32))],
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn attention_round_trips_through_the_database_value() {
+		for attention in [
+			LocationAttention::AppearsEmpty,
+			LocationAttention::ConfirmedEmpty,
+		] {
+			assert_eq!(
+				LocationAttention::from_db(Some(attention as i32)),
+				Some(attention)
+			);
+		}
+		assert_eq!(LocationAttention::from_db(None), None);
+		assert_eq!(LocationAttention::from_db(Some(7)), None);
+	}
+}
@@ -25,8 +25,6 @@ pub enum LocationError {
 	IdNotFound(location::id::Type),
 
 	// User errors
-	#[error("location not a directory <path='{}'>", .0.display())]
-	NotDirectory(Box<Path>),
 	#[error("could not find directory in location <path='{}'>", .0.display())]
 	DirectoryNotFound(Box<Path>),
 	#[error(
@@ -75,6 +73,11 @@ pub enum LocationError {
 	MissingPath(location::id::Type),
 	#[error("missing-field: {0}")]
 	MissingField(#[from] MissingFieldError),
+	#[error(
+		"max walk depth can't be over the hard cap of {max} <depth='{0}'>",
+		max = super::indexer::DEFAULT_MAX_WALK_DEPTH
+	)]
+	InvalidMaxWalkDepth(u32),
 }
 
 impl From<LocationError> for rspc::Error {
@@ -91,7 +94,7 @@ impl From<LocationError> for rspc::Error {
 			}
 
 			// User's fault errors
-			NotDirectory(_) | NestedLocation(_) | LocationAlreadyExists(_) => {
+			NestedLocation(_) | LocationAlreadyExists(_) | InvalidMaxWalkDepth(_) => {
 				Self::with_cause(ErrorCode::BadRequest, err.to_string(), err)
 			}
 
@@ -0,0 +1,189 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use super::AggregatedIndexerError;
+
+/// Entries an indexer job must have found before [`ErrorPolicy::max_noncritical_ratio`] is checked,
+/// so a single unreadable file at the start of a scan doesn't count as everything failing
+pub const MIN_ENTRIES_FOR_ERROR_RATIO: u64 = 100;
+
+/// How many non critical errors an indexer job accepts before pausing, as that many usually means
+/// the location isn't readable anymore (eg. a network share dropped mid scan) and carrying on
+/// would leave a half indexed library. Unlimited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ErrorPolicy {
+	/// Errors per entry found by the job, from 0 to 1
+	#[serde(default)]
+	pub max_noncritical_ratio: Option<f32>,
+	#[serde(default)]
+	pub max_noncritical_count: Option<u64>,
+}
+
+impl ErrorPolicy {
+	/// The policy of a location, from its `max_noncritical_ratio` and `max_noncritical_count` options
+	pub fn for_location(
+		max_noncritical_ratio: Option<f64>,
+		max_noncritical_count: Option<i32>,
+	) -> Self {
+		Self {
+			max_noncritical_ratio: max_noncritical_ratio.map(|ratio| ratio as f32),
+			max_noncritical_count: max_noncritical_count
+				.and_then(|count| u64::try_from(count).ok()),
+		}
+	}
+
+	/// The breach of this policy by a job which found `found_entries` entries and had `errors`,
+	/// `None` while they are within it
+	pub fn breach<'e>(
+		&self,
+		errors: impl IntoIterator<Item = &'e AggregatedIndexerError>,
+		found_entries: u64,
+	) -> Option<ErrorPolicyBreach> {
+		if self.max_noncritical_ratio.is_none() && self.max_noncritical_count.is_none() {
+			return None;
+		}
+
+		let mut positions = HashMap::new();
+		let mut kinds = Vec::<AggregatedIndexerError>::new();
+		for err in errors {
+			match positions.get(&err.kind) {
+				Some(&i) => kinds[i].merge(AggregatedIndexerError {
+					directory: None,
+					..err.clone()
+				}),
+				None => {
+					positions.insert(err.kind.clone(), kinds.len());
+					kinds.push(AggregatedIndexerError {
+						directory: None,
+						..err.clone()
+					});
+				}
+			}
+		}
+
+		let errors = kinds.iter().map(|kind| kind.count).sum::<u64>();
+
+		let too_many = self
+			.max_noncritical_count
+			.is_some_and(|max_count| errors > max_count);
+		let too_frequent = self.max_noncritical_ratio.is_some_and(|max_ratio| {
+			found_entries >= MIN_ENTRIES_FOR_ERROR_RATIO
+				&& errors as f64 / found_entries as f64 > f64::from(max_ratio)
+		});
+
+		if !too_many && !too_frequent {
+			return None;
+		}
+
+		// The first kind to appear wins ties, it's probably what caused the others
+		let dominant = kinds.into_iter().rev().max_by_key(|kind| kind.count)?;
+
+		Some(ErrorPolicyBreach {
+			errors,
+			found_entries,
+			dominant,
+		})
+	}
+}
+
+/// The errors which made an indexer job pause, see [`ErrorPolicy`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorPolicyBreach {
+	pub errors: u64,
+	pub found_entries: u64,
+	/// The most common kind of error, across all directories
+	pub dominant: AggregatedIndexerError,
+}
+
+impl fmt::Display for ErrorPolicyBreach {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"indexing paused after {} error(s) for {} entries found, \
+			the location may not be readable anymore; mostly {}",
+			self.errors, self.found_entries, self.dominant
+		)
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::*;
+
+	fn errors(kind: &str, directory: &str, count: u64) -> AggregatedIndexerError {
+		AggregatedIndexerError {
+			kind: kind.to_string(),
+			directory: Some(PathBuf::from(directory)),
+			count,
+			sample_paths: (0..count.min(2))
+				.map(|i| PathBuf::from(directory).join(i.to_string()))
+				.collect(),
+		}
+	}
+
+	#[test]
+	fn locations_without_options_are_unlimited() {
+		assert_eq!(
+			ErrorPolicy::for_location(None, None),
+			ErrorPolicy::default()
+		);
+		assert_eq!(
+			ErrorPolicy::for_location(Some(0.5), Some(10)),
+			ErrorPolicy {
+				max_noncritical_ratio: Some(0.5),
+				max_noncritical_count: Some(10),
+			}
+		);
+		// A negative count can only come from a corrupted column
+		assert_eq!(
+			ErrorPolicy::for_location(None, Some(-1)),
+			ErrorPolicy::default()
+		);
+	}
+
+	#[test]
+	fn breaches_name_the_most_common_kind() {
+		let found = [
+			errors("NotFound", "/a", 2),
+			errors("PermissionDenied", "/a", 3),
+			errors("PermissionDenied", "/b", 3),
+		];
+
+		assert_eq!(ErrorPolicy::default().breach(&found, 10), None);
+		// At the limit is still fine
+		let at_limit = ErrorPolicy {
+			max_noncritical_count: Some(8),
+			..Default::default()
+		};
+		assert_eq!(at_limit.breach(&found, 10), None);
+
+		let by_count = ErrorPolicy {
+			max_noncritical_count: Some(7),
+			..Default::default()
+		};
+		let breach = by_count.breach(&found, 10).unwrap();
+		assert_eq!(breach.errors, 8);
+		assert_eq!(breach.dominant.kind, "PermissionDenied");
+		assert_eq!(breach.dominant.count, 6);
+		assert_eq!(breach.dominant.directory, None);
+		assert_eq!(
+			breach.dominant.sample_paths,
+			["/a/0", "/a/1", "/b/0", "/b/1"].map(PathBuf::from)
+		);
+		assert!(breach.to_string().contains("PermissionDenied"));
+		assert!(breach.to_string().contains("/b/1"));
+
+		let by_ratio = ErrorPolicy {
+			max_noncritical_ratio: Some(0.05),
+			..Default::default()
+		};
+		// Too few entries found to tell
+		assert_eq!(by_ratio.breach(&found, 10), None);
+		assert_eq!(by_ratio.breach(&found, 160), None);
+		assert!(by_ratio.breach(&found, 159).is_some());
+	}
+}
@@ -0,0 +1,324 @@
+use sd_prisma::prisma::location;
+
+use std::{
+	collections::{hash_map::DefaultHasher, HashMap},
+	hash::{Hash, Hasher},
+	mem,
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+use super::old_walk::WalkedEntry;
+
+/// Updates the indexer wrote to the entries of a location while scans of it are running, so overlapping
+/// scans (eg. one the watcher started for a sub path during a full scan) don't write the same update twice.
+///
+/// A location's generation lasts from the first of its scans starting until the last one is done, which
+/// is when the updates it saw are forgotten.
+#[derive(Debug, Default)]
+pub struct InFlightUpdates {
+	locations: Mutex<HashMap<location::id::Type, Arc<LocationUpdates>>>,
+}
+
+#[derive(Debug, Default)]
+struct LocationUpdates {
+	// Held while an update step writes, so conflicting updates to the same entry are written one at a time
+	write: Arc<AsyncMutex<()>>,
+	generation: Mutex<Generation>,
+}
+
+#[derive(Debug, Default)]
+struct Generation {
+	scans: usize,
+	written: HashMap<Uuid, WrittenUpdate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WrittenUpdate {
+	hash: u64,
+	modified_at: DateTime<Utc>,
+}
+
+impl WrittenUpdate {
+	fn new(entry: &WalkedEntry) -> Self {
+		// Everything an update writes, the pub_id aside as it's the key
+		let mut hasher = DefaultHasher::new();
+		entry.maybe_object_id.hash(&mut hasher);
+		entry.iso_file_path.hash(&mut hasher);
+		entry.metadata.inode.hash(&mut hasher);
+		entry.metadata.size_in_bytes.hash(&mut hasher);
+		entry.metadata.created_at.hash(&mut hasher);
+		entry.metadata.modified_at.hash(&mut hasher);
+		entry.metadata.hidden.hash(&mut hasher);
+		entry.name_bytes.hash(&mut hasher);
+
+		Self {
+			hash: hasher.finish(),
+			modified_at: entry.metadata.modified_at,
+		}
+	}
+}
+
+/// The entries of an update step which still have to be written, see [`InFlightUpdates::claim`].
+pub(super) struct ClaimedUpdates<'e> {
+	pub(super) to_write: Vec<&'e WalkedEntry>,
+	/// Exact duplicates of updates already written in this generation, or older than another update
+	pub(super) skipped: Vec<&'e WalkedEntry>,
+	location: Arc<LocationUpdates>,
+	_write: OwnedMutexGuard<()>,
+}
+
+/// A running scan, dropped with the job so failed or cancelled scans don't keep the generation open
+#[derive(Debug)]
+pub struct InFlightScan {
+	location: Arc<LocationUpdates>,
+}
+
+impl Drop for InFlightScan {
+	fn drop(&mut self) {
+		let mut generation = self
+			.location
+			.generation
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+
+		generation.scans = generation.scans.saturating_sub(1);
+		if generation.scans == 0 {
+			generation.written = HashMap::new();
+		}
+	}
+}
+
+impl ClaimedUpdates<'_> {
+	/// Remember the claimed updates once they're written, so other scans skip them
+	pub(super) fn written(self) {
+		let mut generation = self
+			.location
+			.generation
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		// Scans which were all done while we wrote don't need them anymore
+		if generation.scans == 0 {
+			return;
+		}
+
+		for entry in self.to_write {
+			generation
+				.written
+				.insert(entry.pub_id, WrittenUpdate::new(entry));
+		}
+	}
+}
+
+impl InFlightUpdates {
+	fn location(&self, location_id: location::id::Type) -> Arc<LocationUpdates> {
+		self.locations
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.entry(location_id)
+			.or_default()
+			.clone()
+	}
+
+	/// Start a scan of the location, it's part of the generation until the returned [`InFlightScan`] is dropped
+	pub(crate) fn begin_scan(&self, location_id: location::id::Type) -> InFlightScan {
+		let location = self.location(location_id);
+		location
+			.generation
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.scans += 1;
+
+		InFlightScan { location }
+	}
+
+	/// Wait for other update steps of the location to be done, and split `entries` into the ones to write
+	/// and the ones already covered this generation. When the same entry has different updates, the
+	/// one with the latest modification date wins whichever order they arrive in.
+	///
+	/// Outside of scans, eg. for the verifier, everything is written.
+	pub(super) async fn claim<'e>(
+		&self,
+		location_id: location::id::Type,
+		entries: &'e [WalkedEntry],
+	) -> ClaimedUpdates<'e> {
+		let location = self.location(location_id);
+		let write = location.write.clone().lock_owned().await;
+
+		let mut to_write = Vec::with_capacity(entries.len());
+		let mut skipped = vec![];
+		{
+			let generation = location
+				.generation
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner);
+
+			// Later entries of the same step win like they would against an earlier step
+			let mut claimed = HashMap::<_, (usize, WrittenUpdate)>::new();
+			for entry in entries {
+				let update = WrittenUpdate::new(entry);
+				let in_step = claimed.get(&entry.pub_id).copied();
+				let previous = in_step.map(|(_, update)| update).or_else(|| {
+					(generation.scans > 0)
+						.then(|| generation.written.get(&entry.pub_id).copied())
+						.flatten()
+				});
+
+				if previous.is_some_and(|previous| {
+					previous.hash == update.hash || previous.modified_at > update.modified_at
+				}) {
+					skipped.push(entry);
+					continue;
+				}
+
+				match in_step {
+					// An older update from the same step is replaced instead of written first
+					Some((index, _)) => {
+						skipped.push(mem::replace(&mut to_write[index], entry));
+						claimed.insert(entry.pub_id, (index, update));
+					}
+					None => {
+						claimed.insert(entry.pub_id, (to_write.len(), update));
+						to_write.push(entry);
+					}
+				}
+			}
+		}
+
+		ClaimedUpdates {
+			to_write,
+			skipped,
+			location,
+			_write: write,
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use sd_file_path_helper::{FilePathMetadata, IsolatedFilePathData};
+
+	use std::path::Path;
+
+	use chrono::Duration;
+
+	use super::*;
+
+	fn entry(pub_id: Uuid, size_in_bytes: u64, modified_at: DateTime<Utc>) -> WalkedEntry {
+		WalkedEntry {
+			pub_id,
+			maybe_object_id: None,
+			iso_file_path: IsolatedFilePathData::new(
+				1,
+				Path::new("/location"),
+				Path::new("/location/photos/photo.png"),
+				false,
+			)
+			.unwrap(),
+			metadata: FilePathMetadata {
+				inode: 42,
+				size_in_bytes,
+				created_at: modified_at,
+				modified_at,
+				hidden: false,
+				created_at_is_fallback: false,
+			},
+			name_bytes: None,
+			is_location_boundary: false,
+			is_placeholder: false,
+			discovery_seq: None,
+			crossed_mount: false,
+		}
+	}
+
+	fn sizes(entries: &[&WalkedEntry]) -> Vec<u64> {
+		entries
+			.iter()
+			.map(|entry| entry.metadata.size_in_bytes)
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn overlapping_scans_write_an_update_once() {
+		let in_flight = InFlightUpdates::default();
+		let pub_id = Uuid::new_v4();
+		let now = Utc::now();
+
+		// A full scan and a sub path scan started by the watcher both found the same change
+		let first = in_flight.begin_scan(1);
+		let second = in_flight.begin_scan(1);
+		let full_scan = [entry(pub_id, 10, now), entry(Uuid::new_v4(), 5, now)];
+		let sub_path_scan = [entry(pub_id, 10, now)];
+
+		let claimed = in_flight.claim(1, &full_scan).await;
+		assert_eq!(sizes(&claimed.to_write), [10, 5]);
+		claimed.written();
+
+		let claimed = in_flight.claim(1, &sub_path_scan).await;
+		assert!(claimed.to_write.is_empty());
+		assert_eq!(sizes(&claimed.skipped), [10]);
+		claimed.written();
+
+		// Other locations aren't affected
+		let claimed = in_flight.claim(2, &sub_path_scan).await;
+		assert_eq!(sizes(&claimed.to_write), [10]);
+		drop(claimed);
+
+		// Once both scans are done the next one starts over
+		drop((first, second));
+		let _scan = in_flight.begin_scan(1);
+		let claimed = in_flight.claim(1, &sub_path_scan).await;
+		assert_eq!(sizes(&claimed.to_write), [10]);
+	}
+
+	#[tokio::test]
+	async fn the_latest_update_wins_whatever_the_order() {
+		let in_flight = InFlightUpdates::default();
+		let pub_id = Uuid::new_v4();
+		let earlier = Utc::now() - Duration::minutes(1);
+		let later = Utc::now();
+		let _scan = in_flight.begin_scan(1);
+
+		let newer = [entry(pub_id, 20, later)];
+		let claimed = in_flight.claim(1, &newer).await;
+		assert_eq!(sizes(&claimed.to_write), [20]);
+		claimed.written();
+
+		// A scan which saw the file before it changed doesn't get to overwrite the change
+		let older = [entry(pub_id, 10, earlier)];
+		let claimed = in_flight.claim(1, &older).await;
+		assert!(claimed.to_write.is_empty());
+		claimed.written();
+
+		// Within a single step too
+		let pub_id = Uuid::new_v4();
+		let both = [entry(pub_id, 10, earlier), entry(pub_id, 20, later)];
+		let claimed = in_flight.claim(1, &both).await;
+		assert_eq!(sizes(&claimed.to_write), [20]);
+		assert_eq!(sizes(&claimed.skipped), [10]);
+	}
+
+	#[tokio::test]
+	async fn updates_are_written_one_step_at_a_time() {
+		let in_flight = Arc::new(InFlightUpdates::default());
+		let entries = [entry(Uuid::new_v4(), 10, Utc::now())];
+
+		let claimed = in_flight.claim(1, &entries).await;
+		let waiting = tokio::spawn({
+			let in_flight = in_flight.clone();
+			async move {
+				let entries = [entry(Uuid::new_v4(), 10, Utc::now())];
+				in_flight.claim(1, &entries).await.to_write.len()
+			}
+		});
+
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		assert!(!waiting.is_finished());
+		drop(claimed);
+		assert_eq!(waiting.await.unwrap(), 1);
+	}
+}
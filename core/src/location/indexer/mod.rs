@@ -1,16 +1,33 @@
-use crate::library::Library;
+use crate::{
+	api::notifications::{NotificationData, NotificationKind},
+	invalidate_query,
+	library::Library,
+	location::{set_location_attention, LocationAttention},
+	Node,
+};
 
 use sd_file_path_helper::{
 	file_path_pub_and_cas_ids, FilePathError, IsolatedFilePathData, IsolatedFilePathDataParts,
 };
 use sd_prisma::{
-	prisma::{file_path, location, object as prisma_object, PrismaClient},
+	prisma::{
+		directory_fingerprint, file_path, label_on_object, location, location_statistics,
+		object as prisma_object, tag_on_object, PrismaClient,
+	},
 	prisma_sync,
 };
 use sd_sync::*;
-use sd_utils::{db::inode_to_db, error::FileIOError, from_bytes_to_uuid, msgpack};
+use sd_utils::{
+	db::{inode_to_db, MissingFieldError},
+	error::FileIOError,
+	from_bytes_to_uuid, msgpack,
+};
 
-use std::{collections::HashMap, path::Path};
+use std::{
+	collections::{HashMap, HashSet},
+	fmt,
+	path::{Path, PathBuf},
+};
 
 use chrono::Utc;
 use futures_concurrency::future::TryJoin;
@@ -23,27 +40,53 @@ use tracing::{trace, warn};
 
 use super::location_with_indexer_rules;
 
+mod error_policy;
+mod in_flight;
 pub mod old_indexer_job;
 mod old_shallow;
+pub mod old_verifier_job;
 mod old_walk;
 pub mod rules;
+mod scan;
+mod scan_preview;
+mod scan_records;
+mod throttle;
+mod updates;
+mod volume_walks;
 
-use old_walk::WalkedEntry;
+pub(crate) use old_walk::{evaluate_single_path, is_only_walked_through};
+use old_walk::{
+	ChildCounts, DirectoryFingerprint, ExtensionStatistics, ToRemoveEntry, WalkedEntry,
+};
 use rules::IndexerRuleError;
 
-pub use old_indexer_job::OldIndexerJobInit;
+pub use error_policy::*;
+pub use in_flight::{InFlightScan, InFlightUpdates};
+pub use old_indexer_job::{OldIndexerJobInit, DEFAULT_RECENTLY_MODIFIED_WINDOW};
 pub use old_shallow::*;
+pub use old_verifier_job::OldLocationVerifierJobInit;
+pub use old_walk::DEFAULT_MAX_WALK_DEPTH;
+pub use scan::*;
+pub use scan_preview::*;
+pub use scan_records::*;
+pub use throttle::*;
+pub use updates::*;
+pub use volume_walks::VolumeWalks;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OldIndexerJobSaveStep {
 	chunk_idx: usize,
 	walked: Vec<WalkedEntry>,
+	#[serde(default)]
+	scan_generation: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OldIndexerJobUpdateStep {
 	chunk_idx: usize,
 	to_update: Vec<WalkedEntry>,
+	#[serde(default)]
+	scan_generation: Option<i64>,
 }
 
 /// Error type for the indexer module
@@ -54,6 +97,25 @@ pub enum IndexerError {
 	IndexerRuleNotFound(i32),
 	#[error("received sub path not in database: <path='{}'>", .0.display())]
 	SubPathNotFound(Box<Path>),
+	#[error(
+		"location root is on another device than when it was added, \
+		its drive may not be mounted: <expected={expected}, found={found}>"
+	)]
+	WrongDevice { expected: u64, found: u64 },
+	#[error(
+		"location root is the same directory as the root of another location, \
+		probably mounted at another path: <other_location_id={other_location_id}>"
+	)]
+	DuplicateLocation {
+		other_location_id: location::id::Type,
+	},
+	#[error("pinned file is missing on disk, its file_path is kept: <path='{}'>", .path.display())]
+	PinnedFileMissing { path: PathBuf },
+	#[error(
+		"sub directories too deep to be walked, they're left out: <path='{}', depth={depth}>",
+		.path.display()
+	)]
+	MaxDepthExceeded { path: PathBuf, depth: u32 },
 
 	// Internal Errors
 	#[error("Database Error: {}", .0.to_string())]
@@ -62,6 +124,10 @@ pub enum IndexerError {
 	FileIO(#[from] FileIOError),
 	#[error(transparent)]
 	FilePath(#[from] FilePathError),
+	#[error("failed to serialize scan statistics: {0}")]
+	StatisticsSerialization(#[from] serde_json::Error),
+	#[error(transparent)]
+	MissingField(#[from] MissingFieldError),
 
 	// Mixed errors
 	#[error(transparent)]
@@ -75,6 +141,10 @@ impl From<IndexerError> for rspc::Error {
 				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
 			}
 
+			IndexerError::DuplicateLocation { .. } => {
+				rspc::Error::with_cause(ErrorCode::Conflict, err.to_string(), err)
+			}
+
 			IndexerError::IndexerRules(rule_err) => rule_err.into(),
 
 			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
@@ -82,6 +152,96 @@ impl From<IndexerError> for rspc::Error {
 	}
 }
 
+/// How many paths are kept as examples for each [`AggregatedIndexerError`]
+const MAX_SAMPLE_PATHS: usize = 5;
+
+/// Non critical errors of the same kind within the same directory, so a single unreadable directory
+/// doesn't fill the job report with thousands of identical errors.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregatedIndexerError {
+	pub kind: String,
+	pub directory: Option<PathBuf>,
+	pub count: u64,
+	pub sample_paths: Vec<PathBuf>,
+}
+
+impl AggregatedIndexerError {
+	fn merge(&mut self, other: Self) {
+		self.count += other.count;
+		let missing = MAX_SAMPLE_PATHS.saturating_sub(self.sample_paths.len());
+		self.sample_paths
+			.extend(other.sample_paths.into_iter().take(missing));
+	}
+}
+
+impl fmt::Display for AggregatedIndexerError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} error(s) of kind '{}'", self.count, self.kind)?;
+		if let Some(directory) = &self.directory {
+			write!(f, " in {}", directory.display())?;
+		}
+		if !self.sample_paths.is_empty() {
+			write!(
+				f,
+				", for example: {}",
+				self.sample_paths
+					.iter()
+					.map(|path| path.display().to_string())
+					.join(", ")
+			)?;
+		}
+		Ok(())
+	}
+}
+
+/// Group errors by their kind and the directory they happened in, keeping their order of first appearance.
+fn aggregate_errors(errors: impl IntoIterator<Item = IndexerError>) -> Vec<AggregatedIndexerError> {
+	merge_aggregated_errors(
+		vec![],
+		errors.into_iter().map(|err| {
+			let (kind, path) = match &err {
+				IndexerError::FileIO(FileIOError { path, source, .. }) => {
+					(format!("{:?}", source.kind()), Some(path.to_path_buf()))
+				}
+				_ => (err.to_string(), None),
+			};
+
+			AggregatedIndexerError {
+				kind,
+				directory: path
+					.as_ref()
+					.and_then(|path| path.parent())
+					.map(Path::to_path_buf),
+				count: 1,
+				sample_paths: path.into_iter().collect(),
+			}
+		}),
+	)
+}
+
+fn merge_aggregated_errors(
+	mut aggregated: Vec<AggregatedIndexerError>,
+	errors: impl IntoIterator<Item = AggregatedIndexerError>,
+) -> Vec<AggregatedIndexerError> {
+	let mut positions = aggregated
+		.iter()
+		.enumerate()
+		.map(|(i, err)| ((err.kind.clone(), err.directory.clone()), i))
+		.collect::<HashMap<_, _>>();
+
+	for err in errors {
+		match positions.get(&(err.kind.clone(), err.directory.clone())) {
+			Some(&i) => aggregated[i].merge(err),
+			None => {
+				positions.insert((err.kind.clone(), err.directory.clone()), aggregated.len());
+				aggregated.push(err);
+			}
+		}
+	}
+
+	aggregated
+}
+
 async fn execute_indexer_save_step(
 	location: &location_with_indexer_rules::Data,
 	save_step: &OldIndexerJobSaveStep,
@@ -105,7 +265,7 @@ async fn execute_indexer_save_step(
 
 			let pub_id = sd_utils::uuid_to_bytes(entry.pub_id);
 
-			let (sync_params, db_params): (Vec<_>, Vec<_>) = [
+			let (sync_params, mut db_params): (Vec<_>, Vec<_>) = [
 				(
 					(
 						location::NAME,
@@ -140,6 +300,30 @@ async fn execute_indexer_save_step(
 			]
 			.into_iter()
 			.unzip();
+			// Only used locally to find the file_paths the indexer didn't find again, so it isn't synced
+			db_params.extend(
+				save_step
+					.scan_generation
+					.map(|generation| scan_generation::set(Some(generation))),
+			);
+			// The same goes for the original bytes of names which aren't valid UTF-8
+			db_params.extend(
+				entry
+					.name_bytes
+					.clone()
+					.map(|bytes| name_bytes::set(Some(bytes))),
+			);
+			// And whether it's a cloud placeholder, as each device's sync client decides what it keeps online
+			db_params.push(is_placeholder::set(Some(entry.is_placeholder)));
+			// Which other locations are nested in this one is only known on this instance too
+			db_params.push(is_location_boundary::set(Some(entry.is_location_boundary)));
+			// The order it was found in only matters where its creation date isn't known
+			db_params.extend(
+				entry
+					.discovery_seq
+					.filter(|_| entry.metadata.created_at_is_fallback)
+					.map(|seq| discovery_seq::set(Some(seq.to_be_bytes().to_vec()))),
+			);
 
 			(
 				sync.shared_create(
@@ -165,15 +349,57 @@ async fn execute_indexer_save_step(
 
 	trace!("Inserted {count} records");
 
+	library.indexing_updates.push(
+		location.id,
+		save_step
+			.walked
+			.iter()
+			.map(|entry| IndexedEntry::new(entry, IndexedEntryKind::Created)),
+	);
+
 	Ok(count)
 }
 
 async fn execute_indexer_update_step(
 	update_step: &OldIndexerJobUpdateStep,
-	Library { sync, db, .. }: &Library,
+	library: &Library,
 ) -> Result<i64, IndexerError> {
-	let (sync_stuff, paths_to_update): (Vec<_>, Vec<_>) = update_step
+	let Library { sync, db, .. } = library;
+
+	let Some(location_id) = update_step
 		.to_update
+		.first()
+		.map(|entry| entry.iso_file_path.location_id())
+	else {
+		return Ok(0);
+	};
+
+	// Overlapping scans of the location can find the same changes, they're only written once
+	let claimed = library
+		.in_flight_updates
+		.claim(location_id, &update_step.to_update)
+		.await;
+	if !claimed.skipped.is_empty() {
+		trace!(
+			"Skipping {} updates already written by another scan",
+			claimed.skipped.len()
+		);
+		if let Some(scan_generation) = update_step.scan_generation {
+			mark_as_found(
+				claimed
+					.skipped
+					.iter()
+					.map(|entry| sd_utils::uuid_to_bytes(entry.pub_id))
+					.collect(),
+				scan_generation,
+				db,
+			)
+			.await?;
+		}
+	}
+
+	let (sync_stuff, paths_to_update): (Vec<_>, Vec<_>) = claimed
+		.to_write
 		.iter()
 		.map(|entry| async move {
 			let IsolatedFilePathDataParts { is_dir, .. } = &entry.iso_file_path.to_parts();
@@ -191,7 +417,7 @@ async fn execute_indexer_update_step(
 
 			use file_path::*;
 
-			let (sync_params, db_params): (Vec<_>, Vec<_>) = [
+			let (sync_params, mut db_params): (Vec<_>, Vec<_>) = [
 				// As this file was updated while Spacedrive was offline, we mark the object_id and cas_id as null
 				// So this file_path will be updated at file identifier job
 				should_unlink_object
@@ -216,6 +442,13 @@ async fn execute_indexer_update_step(
 			.into_iter()
 			.flatten()
 			.unzip();
+			db_params.extend(
+				update_step
+					.scan_generation
+					.map(|generation| scan_generation::set(Some(generation))),
+			);
+			db_params.push(is_placeholder::set(Some(entry.is_placeholder)));
+			db_params.push(is_location_boundary::set(Some(entry.is_location_boundary)));
 
 			Ok::<_, IndexerError>((
 				sync_params
@@ -250,6 +483,15 @@ async fn execute_indexer_update_step(
 
 	trace!("Updated {updated:?} records");
 
+	library.indexing_updates.push(
+		location_id,
+		claimed
+			.to_write
+			.iter()
+			.map(|entry| IndexedEntry::new(entry, IndexedEntryKind::Updated)),
+	);
+	claimed.written();
+
 	Ok(updated.len() as i64)
 }
 
@@ -262,13 +504,603 @@ fn iso_file_path_factory(
 	}
 }
 
+/// Starts a new generation of the location's scans, whose number the file_paths the scan finds are stamped with
+async fn next_scan_generation(
+	location_id: location::id::Type,
+	db: &PrismaClient,
+) -> Result<i64, IndexerError> {
+	Ok(db
+		.location()
+		.update(
+			location::id::equals(location_id),
+			vec![location::scan_generation::increment(1)],
+		)
+		.select(location::select!({ scan_generation }))
+		.exec()
+		.await?
+		.scan_generation)
+}
+
+/// The generation of the location's latest scan, for what's found outside of a full scan
+async fn current_scan_generation(
+	location_id: location::id::Type,
+	db: &PrismaClient,
+) -> Result<i64, IndexerError> {
+	db.location()
+		.find_unique(location::id::equals(location_id))
+		.select(location::select!({ scan_generation }))
+		.exec()
+		.await?
+		.map(|location| location.scan_generation)
+		.ok_or_else(|| FilePathError::LocationNotFound(location_id).into())
+}
+
+/// Marks the file_paths the walker found unchanged as found by the scan of `scan_generation`.
+async fn mark_as_found(
+	pub_ids: Vec<file_path::pub_id::Type>,
+	scan_generation: i64,
+	db: &PrismaClient,
+) -> Result<(), IndexerError> {
+	if pub_ids.is_empty() {
+		return Ok(());
+	}
+
+	db._batch(
+		pub_ids
+			.chunks(500)
+			.map(|chunk| {
+				db.file_path().update_many(
+					vec![file_path::pub_id::in_vec(chunk.to_vec())],
+					vec![file_path::scan_generation::set(Some(scan_generation))],
+				)
+			})
+			.collect::<Vec<_>>(),
+	)
+	.await?;
+
+	Ok(())
+}
+
+file_path::select!(file_path_to_remove {
+	id
+	pub_id
+	cas_id
+	pinned
+	is_dir
+	materialized_path
+	name
+	extension
+});
+
+/// The file_paths of a walked directory which the walk didn't keep, the pinned ones flagged as
+/// those are never removed
+fn removable_file_paths(
+	location_id: location::id::Type,
+	file_paths: Vec<file_path_to_remove::Data>,
+	kept_ids: &HashSet<file_path::id::Type>,
+) -> impl Iterator<Item = ToRemoveEntry> + '_ {
+	file_paths
+		.into_iter()
+		.filter(|file_path| !kept_ids.contains(&file_path.id))
+		.filter_map(move |file_path| {
+			let is_dir = file_path.is_dir == Some(true);
+			let iso_file_path = match (
+				file_path.is_dir,
+				file_path.materialized_path,
+				file_path.name,
+				file_path.extension,
+			) {
+				(Some(is_dir), Some(materialized_path), Some(name), Some(extension)) => {
+					Some(IsolatedFilePathData::from_db_data(
+						location_id,
+						is_dir,
+						materialized_path.into(),
+						name.into(),
+						extension.into(),
+					))
+				}
+				_ => None,
+			};
+
+			// A pinned row without its path can't be reported, but it's still never removed
+			if file_path.pinned && iso_file_path.is_none() {
+				return None;
+			}
+
+			Some(ToRemoveEntry {
+				dir: iso_file_path
+					.clone()
+					.filter(|_| is_dir && !file_path.pinned),
+				pinned: iso_file_path.filter(|_| file_path.pinned),
+				file_path: file_path_pub_and_cas_ids::Data {
+					id: file_path.id,
+					pub_id: file_path.pub_id,
+					cas_id: file_path.cas_id,
+				},
+			})
+		})
+}
+
+file_path::select!(file_path_in_subtree {
+	id
+	pub_id
+	cas_id
+	object_id
+	materialized_path
+});
+
+/// What's stored below the directories in `subtrees`, which the walker found gone from disk along with
+/// everything in them. Pinned ones are left alone, like everywhere else.
+async fn subtree_file_paths(
+	location_id: location::id::Type,
+	subtrees: &[IsolatedFilePathData<'_>],
+	db: &PrismaClient,
+) -> Result<Vec<file_path_in_subtree::Data>, IndexerError> {
+	let prefixes = subtrees
+		.iter()
+		.filter_map(IsolatedFilePathData::materialized_path_for_children)
+		.collect::<Vec<_>>();
+	if prefixes.is_empty() {
+		return Ok(vec![]);
+	}
+
+	let file_paths = db
+		._batch(
+			prefixes
+				.iter()
+				.map(|prefix| {
+					db.file_path()
+						.find_many(vec![
+							file_path::location_id::equals(Some(location_id)),
+							file_path::pinned::equals(false),
+							file_path::materialized_path::starts_with(prefix.clone()),
+						])
+						.select(file_path_in_subtree::select())
+				})
+				.collect::<Vec<_>>(),
+		)
+		.await?
+		.into_iter()
+		.flatten()
+		.collect();
+
+	Ok(in_subtrees(file_paths, &prefixes))
+}
+
+/// Only the `file_path`s really below one of the `prefixes`, as `LIKE` takes `_` and `%` in a name as
+/// wildcards. The prefixes end with a `/`, so `/foo/` never takes in `/foobar/`.
+fn in_subtrees(
+	file_paths: Vec<file_path_in_subtree::Data>,
+	prefixes: &[String],
+) -> Vec<file_path_in_subtree::Data> {
+	file_paths
+		.into_iter()
+		.filter(|file_path| {
+			file_path
+				.materialized_path
+				.as_deref()
+				.is_some_and(|materialized_path| {
+					prefixes
+						.iter()
+						.any(|prefix| materialized_path.starts_with(prefix.as_str()))
+				})
+		})
+		.unique_by(|file_path| file_path.id)
+		.collect()
+}
+
+/// Removes everything below the directories in `subtrees` at once, along with the objects left without
+/// any `file_path`, see [`old_walk::WalkResult::to_remove_subtrees`]. Returns how many were removed.
+async fn remove_subtrees(
+	location_id: location::id::Type,
+	subtrees: &[IsolatedFilePathData<'_>],
+	db: &PrismaClient,
+	sync: &sd_core_sync::Manager,
+) -> Result<u64, IndexerError> {
+	let file_paths = subtree_file_paths(location_id, subtrees, db).await?;
+	if file_paths.is_empty() {
+		return Ok(0);
+	}
+
+	let count = file_paths.len() as u64;
+	trace!(
+		"Removing {count} file_paths below {} directories gone from location <id='{location_id}'>",
+		subtrees.len()
+	);
+
+	let object_ids = file_paths
+		.iter()
+		.filter_map(|file_path| file_path.object_id)
+		.unique()
+		.collect();
+	remove_non_existing_file_paths(
+		file_paths
+			.into_iter()
+			.map(|file_path| file_path_pub_and_cas_ids::Data {
+				id: file_path.id,
+				pub_id: file_path.pub_id,
+				cas_id: file_path.cas_id,
+			}),
+		db,
+		sync,
+	)
+	.await?;
+	remove_orphaned_objects(object_ids, db, sync).await?;
+
+	Ok(count)
+}
+
+/// How many objects [`remove_orphaned_objects`] deletes at a time, to stay under SQLite's limit on query parameters
+const ORPHANED_OBJECTS_CHUNK_SIZE: usize = 512;
+
+prisma_object::select!(orphaned_object {
+	id
+	pub_id
+	tags: select { tag: select { pub_id } }
+	labels: select { label: select { name } }
+});
+
+/// Deletes the objects among `object_ids` which the indexer just removed the last `file_path`s of,
+/// along with the tags and labels on them. Objects in an album or a space are kept.
+async fn remove_orphaned_objects(
+	object_ids: Vec<prisma_object::id::Type>,
+	db: &PrismaClient,
+	sync: &sd_core_sync::Manager,
+) -> Result<(), IndexerError> {
+	for chunk in object_ids.chunks(ORPHANED_OBJECTS_CHUNK_SIZE) {
+		let orphans = db
+			.object()
+			.find_many(vec![
+				prisma_object::id::in_vec(chunk.to_vec()),
+				// https://www.prisma.io/docs/reference/api-reference/prisma-client-reference#none
+				prisma_object::file_paths::none(vec![]),
+				prisma_object::albums::none(vec![]),
+				prisma_object::spaces::none(vec![]),
+			])
+			.select(orphaned_object::select())
+			.exec()
+			.await?;
+		if orphans.is_empty() {
+			continue;
+		}
+
+		let object_sync_id = |pub_id: &Vec<u8>| prisma_sync::object::SyncId {
+			pub_id: pub_id.clone(),
+		};
+		let orphan_ids = orphans.iter().map(|object| object.id).collect::<Vec<_>>();
+
+		// The tags and labels on an object would keep it from being deleted
+		sync.write_ops(
+			db,
+			(
+				orphans
+					.iter()
+					.flat_map(|object| {
+						object.tags.iter().map(|tag_on_object| {
+							sync.relation_delete(prisma_sync::tag_on_object::SyncId {
+								tag: prisma_sync::tag::SyncId {
+									pub_id: tag_on_object.tag.pub_id.clone(),
+								},
+								object: object_sync_id(&object.pub_id),
+							})
+						})
+					})
+					.collect(),
+				db.tag_on_object()
+					.delete_many(vec![tag_on_object::object_id::in_vec(orphan_ids.clone())]),
+			),
+		)
+		.await?;
+		sync.write_ops(
+			db,
+			(
+				orphans
+					.iter()
+					.flat_map(|object| {
+						object.labels.iter().map(|label_on_object| {
+							sync.relation_delete(prisma_sync::label_on_object::SyncId {
+								label: prisma_sync::label::SyncId {
+									name: label_on_object.label.name.clone(),
+								},
+								object: object_sync_id(&object.pub_id),
+							})
+						})
+					})
+					.collect(),
+				db.label_on_object()
+					.delete_many(vec![label_on_object::object_id::in_vec(orphan_ids.clone())]),
+			),
+		)
+		.await?;
+
+		trace!("Removing {} orphaned objects", orphan_ids.len());
+		sync.write_ops(
+			db,
+			(
+				orphans
+					.iter()
+					.map(|object| sync.shared_delete(object_sync_id(&object.pub_id)))
+					.collect(),
+				db.object().delete_many(vec![
+					prisma_object::id::in_vec(orphan_ids),
+					prisma_object::file_paths::none(vec![]),
+				]),
+			),
+		)
+		.await?;
+	}
+
+	Ok(())
+}
+
+file_path::select!(file_path_to_verify {
+	id
+	pub_id
+	cas_id
+	materialized_path
+	scan_generation
+});
+
+/// The roots of the library's other locations which are inside `location_path`, eg. `~/Pictures` when
+/// `~` is a location too. The indexer leaves their contents to them.
+pub(crate) async fn nested_location_roots(
+	location_id: location::id::Type,
+	location_path: &Path,
+	db: &PrismaClient,
+) -> Result<Vec<PathBuf>, IndexerError> {
+	Ok(db
+		.location()
+		.find_many(vec![location::id::not(location_id)])
+		.select(location::select!({ path }))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|location| location.path.map(PathBuf::from))
+		.filter(|path| path != location_path && path.starts_with(location_path))
+		.collect())
+}
+
+/// Whether a walk of a location's root found nothing in it while the database has entries for it
+fn appears_empty(found_entries: u64, expected_entries: u64) -> bool {
+	found_entries == 0 && expected_entries > 0
+}
+
+/// A root which reads fine but is empty is more likely a drive which isn't connected than one whose
+/// files were all deleted, so removing what a walk of it didn't find waits for the user to confirm it
+fn should_hold_back_removals(
+	attention: Option<i32>,
+	found_entries: u64,
+	expected_entries: u64,
+) -> bool {
+	LocationAttention::from_db(attention) != Some(LocationAttention::ConfirmedEmpty)
+		&& appears_empty(found_entries, expected_entries)
+}
+
+/// Marks the location as appearing empty and asks the user whether its drive is connected,
+/// nothing is removed until they confirm it really is empty
+async fn hold_back_removals(
+	location_id: location::id::Type,
+	location_name: Option<&str>,
+	expected_entries: u64,
+	node: &Node,
+	library: &Library,
+) -> Result<(), IndexerError> {
+	warn!(
+		"The root of location <id='{location_id}'> is empty though {expected_entries} entries were \
+		indexed in it, not removing them until the user confirms it",
+	);
+	set_location_attention(
+		location_id,
+		Some(LocationAttention::AppearsEmpty),
+		&library.db,
+	)
+	.await?;
+	invalidate_query!(library, "locations.list");
+	invalidate_query!(library, "locations.get");
+
+	let name = location_name.unwrap_or("A location");
+	node.emit_notification(
+		NotificationData {
+			title: format!("{name} appears to be empty"),
+			content: format!(
+				"Is the drive connected? None of its {expected_entries} files were removed, \
+				confirm the location is empty to remove them"
+			),
+			kind: NotificationKind::Warning,
+		},
+		None,
+	)
+	.await;
+
+	Ok(())
+}
+
+/// Leaves a warning for the pinned files among `errors` which are missing on disk, as their
+/// file_paths are kept and would otherwise only show up in a job's report
+pub(crate) async fn warn_pinned_files_missing(errors: &[IndexerError], node: &Node) {
+	let missing = errors
+		.iter()
+		.filter_map(|e| match e {
+			IndexerError::PinnedFileMissing { path } => Some(path),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+	let Some(first) = missing.first() else {
+		return;
+	};
+
+	node.emit_notification(
+		NotificationData {
+			title: "Pinned files are missing".to_string(),
+			content: match missing.len() {
+				1 => format!(
+					"{} isn't on disk anymore, it was kept as it's pinned",
+					first.display()
+				),
+				count => format!(
+					"{} and {} other pinned files aren't on disk anymore, they were kept",
+					first.display(),
+					count - 1
+				),
+			},
+			kind: NotificationKind::Warning,
+		},
+		None,
+	)
+	.await;
+}
+
+/// Counts the file_paths below `path` a previous scan stored, which is how many entries a re-scan
+/// of it is expected to find. Zero on a first scan.
+async fn count_file_paths_in_location(
+	location_id: location::id::Type,
+	location_path: &Path,
+	path: &Path,
+	db: &PrismaClient,
+) -> Result<u64, IndexerError> {
+	let mut params = vec![file_path::location_id::equals(Some(location_id))];
+	if path != location_path {
+		if let Some(children_path) =
+			IsolatedFilePathData::new(location_id, location_path, path, true)?
+				.materialized_path_for_children()
+		{
+			params.push(file_path::materialized_path::starts_with(children_path));
+		}
+	}
+
+	Ok(db.file_path().count(params).exec().await? as u64)
+}
+
+/// Finds the file_paths of a location which a full scan didn't find again, so they were deleted at some point
+/// without the watcher or the walker noticing.
+///
+/// Those of an older `scan_generation` are, the ones the watcher created while the scan ran have its generation
+/// too. File paths within `exempt_directories`, where errors kept the scan from finding everything, aren't
+/// included, nor are the pinned ones.
+async fn find_unverified_file_paths(
+	location_id: location::id::Type,
+	location_path: &Path,
+	scan_generation: i64,
+	exempt_directories: &[PathBuf],
+	db: &PrismaClient,
+) -> Result<Vec<file_path_pub_and_cas_ids::Data>, IndexerError> {
+	let exempt_materialized_paths = exempt_directories
+		.iter()
+		.map(|directory| {
+			IsolatedFilePathData::new(location_id, location_path, directory, true)
+				.map(|iso_file_path| iso_file_path.materialized_path_for_children())
+		})
+		.filter_map(Result::transpose)
+		.collect::<Result<Vec<_>, _>>()?;
+
+	let file_paths = db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::pinned::equals(false),
+			or(vec![
+				file_path::scan_generation::lt(scan_generation),
+				file_path::scan_generation::equals(None),
+			]),
+		])
+		.select(file_path_to_verify::select())
+		.exec()
+		.await?;
+
+	Ok(unverified_file_paths(
+		file_paths,
+		scan_generation,
+		&exempt_materialized_paths,
+	))
+}
+
+fn unverified_file_paths(
+	file_paths: Vec<file_path_to_verify::Data>,
+	scan_generation: i64,
+	exempt_materialized_paths: &[String],
+) -> Vec<file_path_pub_and_cas_ids::Data> {
+	file_paths
+		.into_iter()
+		.filter(|file_path| {
+			file_path
+				.scan_generation
+				.map_or(true, |found_by| found_by < scan_generation)
+		})
+		// Without a materialized_path we can't tell whether it's exempt, so we leave it alone
+		.filter(|file_path| {
+			file_path
+				.materialized_path
+				.as_deref()
+				.is_some_and(|materialized_path| {
+					!exempt_materialized_paths
+						.iter()
+						.any(|exempt| materialized_path.starts_with(exempt.as_str()))
+				})
+		})
+		.map(|file_path| file_path_pub_and_cas_ids::Data {
+			id: file_path.id,
+			pub_id: file_path.pub_id,
+			cas_id: file_path.cas_id,
+		})
+		.collect()
+}
+
+file_path::select!(file_path_removed {
+	id
+	pinned
+	location_id
+	is_dir
+	materialized_path
+	name
+	extension
+});
+
 async fn remove_non_existing_file_paths(
 	to_remove: impl IntoIterator<Item = file_path_pub_and_cas_ids::Data>,
 	db: &PrismaClient,
 	sync: &sd_core_sync::Manager,
 ) -> Result<u64, IndexerError> {
+	let to_remove = to_remove.into_iter().collect::<Vec<_>>();
+	if to_remove.is_empty() {
+		return Ok(0);
+	}
+
+	let (pinned, removed): (Vec<_>, Vec<_>) = db
+		.file_path()
+		.find_many(vec![file_path::id::in_vec(
+			to_remove.iter().map(|d| d.id).collect(),
+		)])
+		.select(file_path_removed::select())
+		.exec()
+		.await?
+		.into_iter()
+		.partition(|file_path| file_path.pinned);
+	// Whichever way they got here, pinned file_paths are never removed
+	let pinned_ids = pinned
+		.into_iter()
+		.map(|file_path| file_path.id)
+		.collect::<HashSet<_>>();
+
+	forget_directory_fingerprints(
+		&removed
+			.into_iter()
+			.filter_map(|file_path| {
+				Some(IsolatedFilePathData::from_db_data(
+					file_path.location_id?,
+					file_path.is_dir?,
+					file_path.materialized_path?.into(),
+					file_path.name?.into(),
+					file_path.extension?.into(),
+				))
+			})
+			.collect::<Vec<_>>(),
+		db,
+	)
+	.await?;
+
 	let (sync_params, db_params): (Vec<_>, Vec<_>) = to_remove
 		.into_iter()
+		.filter(|d| !pinned_ids.contains(&d.id))
 		.map(|d| {
 			(
 				sync.shared_delete(prisma_sync::file_path::SyncId { pub_id: d.pub_id }),
@@ -323,25 +1155,40 @@ macro_rules! file_paths_db_fetcher_fn {
 }
 
 // TODO: Change this macro to a fn when we're able to return
-// `impl Fn(&Path, Vec<file_path::WhereParam>) -> impl Future<Output = Result<Vec<file_path_just_pub_id::Data>, IndexerError>>`
+// `impl Fn(&Path, Vec<IsolatedFilePathData<'static>>) -> impl Future<Output = Result<Vec<file_path_just_pub_id::Data>, IndexerError>>`
 // Maybe when TAITs arrive
 // FIXME: (fogodev) I was receiving this error here https://github.com/rust-lang/rust/issues/74497
 #[macro_export]
 macro_rules! to_remove_db_fetcher_fn {
-	($location_id:expr, $db:expr) => {{
-		|parent_iso_file_path, unique_location_id_materialized_path_name_extension_params| async {
+	($location_id:expr, $db:expr, $case_insensitive:expr) => {{
+		|parent_iso_file_path, kept_iso_file_paths| async {
 			let location_id: ::sd_prisma::prisma::location::id::Type = $location_id;
 			let db: &::sd_prisma::prisma::PrismaClient = $db;
-			let parent_iso_file_path: ::sd_file_path_helper::IsolatedFilePathData<
-				'static,
-			> = parent_iso_file_path;
-			let unique_location_id_materialized_path_name_extension_params: ::std::vec::Vec<
-				::sd_prisma::prisma::file_path::WhereParam,
-			> = unique_location_id_materialized_path_name_extension_params;
+			let case_insensitive: bool = $case_insensitive;
+			let parent_iso_file_path: ::sd_file_path_helper::IsolatedFilePathData<'static> =
+				parent_iso_file_path;
+			let kept_iso_file_paths: ::std::vec::Vec<
+				::sd_file_path_helper::IsolatedFilePathData<'static>,
+			> = kept_iso_file_paths;
+
+			// SQLite only compares extensions exactly, so where their case doesn't matter the rows are
+			// fetched whatever their extension and told apart here, see `ComparisonCaps::case_insensitive`
+			let kept_with_normalized_extension = kept_iso_file_paths
+				.iter()
+				.filter(|_| case_insensitive)
+				.map(|iso_file_path| iso_file_path.with_normalized_extension())
+				.collect::<::std::collections::HashSet<_>>();
 
 			// FIXME: Can't pass this chunks variable direct to _batch because of lifetime issues
-			let chunks = unique_location_id_materialized_path_name_extension_params
+			let chunks = kept_iso_file_paths
 				.into_iter()
+				.map(|iso_file_path| {
+					if case_insensitive {
+						iso_file_path.any_extension_case_where_param()
+					} else {
+						::sd_prisma::prisma::file_path::WhereParam::from(iso_file_path)
+					}
+				})
 				.chunks(200)
 				.into_iter()
 				.map(|unique_params| {
@@ -349,15 +1196,47 @@ macro_rules! to_remove_db_fetcher_fn {
 						.find_many(vec![::prisma_client_rust::operator::or(
 							unique_params.collect(),
 						)])
-						.select(::sd_prisma::prisma::file_path::select!({ id }))
+						.select(::sd_prisma::prisma::file_path::select!({
+							id
+							is_dir
+							materialized_path
+							name
+							extension
+						}))
 				})
 				.collect::<::std::vec::Vec<_>>();
 
 			let founds_ids = db._batch(chunks).await.map(|founds_chunk| {
 				founds_chunk
 					.into_iter()
-					.map(|file_paths| file_paths.into_iter().map(|file_path| file_path.id))
 					.flatten()
+					.filter(|file_path| {
+						!case_insensitive
+							|| match (
+								file_path.is_dir,
+								&file_path.materialized_path,
+								&file_path.name,
+								&file_path.extension,
+							) {
+								(
+									Some(is_dir),
+									Some(materialized_path),
+									Some(name),
+									Some(extension),
+								) => kept_with_normalized_extension.contains(
+									&::sd_file_path_helper::IsolatedFilePathData::from_db_data(
+										location_id,
+										is_dir,
+										materialized_path.as_str().into(),
+										name.as_str().into(),
+										extension.as_str().into(),
+									)
+									.with_normalized_extension(),
+								),
+								_ => false,
+							}
+					})
+					.map(|file_path| file_path.id)
 					.collect::<::std::collections::HashSet<_>>()
 			})?;
 
@@ -368,7 +1247,8 @@ macro_rules! to_remove_db_fetcher_fn {
 			let mut cursor = 1;
 
 			loop {
-				let found = $db.file_path()
+				let found = $db
+					.file_path()
 					.find_many(vec![
 						::sd_prisma::prisma::file_path::location_id::equals(Some(location_id)),
 						::sd_prisma::prisma::file_path::materialized_path::equals(Some(
@@ -377,10 +1257,12 @@ macro_rules! to_remove_db_fetcher_fn {
 								.expect("the received isolated file path must be from a directory"),
 						)),
 					])
-					.order_by(::sd_prisma::prisma::file_path::id::order(::sd_prisma::prisma::SortOrder::Asc))
+					.order_by(::sd_prisma::prisma::file_path::id::order(
+						::sd_prisma::prisma::SortOrder::Asc,
+					))
 					.take(BATCH_SIZE)
 					.cursor(::sd_prisma::prisma::file_path::id::equals(cursor))
-					.select(::sd_prisma::prisma::file_path::select!({ id pub_id cas_id }))
+					.select($crate::location::indexer::file_path_to_remove::select())
 					.exec()
 					.await?;
 
@@ -392,16 +1274,11 @@ macro_rules! to_remove_db_fetcher_fn {
 					break;
 				}
 
-				to_remove.extend(
-					found
-						.into_iter()
-						.filter(|file_path| !founds_ids.contains(&file_path.id))
-						.map(|file_path| ::sd_file_path_helper::file_path_pub_and_cas_ids::Data {
-							id: file_path.id,
-							pub_id: file_path.pub_id,
-							cas_id: file_path.cas_id,
-						}),
-				);
+				to_remove.extend($crate::location::indexer::removable_file_paths(
+					location_id,
+					found,
+					&founds_ids,
+				));
 
 				if should_stop {
 					break;
@@ -413,6 +1290,190 @@ macro_rules! to_remove_db_fetcher_fn {
 	}};
 }
 
+// TODO: Change this macro to a fn when we're able to return
+// `impl Fn(IsolatedFilePathData<'static>) -> impl Future<Output = Result<Option<Vec<u8>>, IndexerError>>`
+// Maybe when TAITs arrive
+#[macro_export]
+macro_rules! fingerprint_db_fetcher_fn {
+	($location_id:expr, $db:expr, $trust_fingerprints:expr) => {{
+		|iso_file_path| async {
+			let iso_file_path: ::sd_file_path_helper::IsolatedFilePathData<'static> = iso_file_path;
+
+			// Without trusting them we never skip a directory, so we don't even have to look
+			if !$trust_fingerprints {
+				return Ok(None);
+			}
+
+			$db.directory_fingerprint()
+				.find_unique(
+					::sd_prisma::prisma::directory_fingerprint::location_id_materialized_path(
+						$location_id,
+						iso_file_path
+							.materialized_path_for_children()
+							.expect("the received isolated file path must be from a directory"),
+					),
+				)
+				.select(::sd_prisma::prisma::directory_fingerprint::select!({
+					fingerprint
+				}))
+				.exec()
+				.await
+				.map(|maybe_stored| maybe_stored.map(|stored| stored.fingerprint))
+				.map_err(Into::into)
+		}
+	}};
+}
+
+/// Stores the fingerprints of walked directories for the next walks, see [`old_walk::walk`]
+async fn upsert_directory_fingerprints(
+	location_id: location::id::Type,
+	fingerprints: &[DirectoryFingerprint],
+	db: &PrismaClient,
+) -> Result<(), IndexerError> {
+	if fingerprints.is_empty() {
+		return Ok(());
+	}
+
+	db._batch(
+		fingerprints
+			.iter()
+			.map(
+				|DirectoryFingerprint {
+				     materialized_path,
+				     fingerprint,
+				 }| {
+					db.directory_fingerprint().upsert(
+						directory_fingerprint::location_id_materialized_path(
+							location_id,
+							materialized_path.clone(),
+						),
+						directory_fingerprint::create(
+							location::id::equals(location_id),
+							materialized_path.clone(),
+							fingerprint.clone(),
+							vec![],
+						),
+						vec![directory_fingerprint::fingerprint::set(fingerprint.clone())],
+					)
+				},
+			)
+			.collect::<Vec<_>>(),
+	)
+	.await?;
+
+	Ok(())
+}
+
+/// Unlike their sizes, the counts of directories aren't synced, other instances count with their own rules
+async fn update_directories_child_counts(
+	child_counts: &HashMap<PathBuf, ChildCounts>,
+	location_id: location::id::Type,
+	location_path: impl AsRef<Path>,
+	db: &PrismaClient,
+) -> Result<(), IndexerError> {
+	let location_path = location_path.as_ref();
+
+	let updates = child_counts
+		.iter()
+		// The location's root doesn't have a `file_path`
+		.filter(|(path, _)| path.as_path() != location_path)
+		.map(|(path, counts)| {
+			IsolatedFilePathData::new(location_id, location_path, path, true).map(|iso_file_path| {
+				db.file_path().update_many(
+					vec![iso_file_path.into()],
+					vec![
+						file_path::child_files::set(Some(counts.files as i32)),
+						file_path::child_dirs::set(Some(counts.directories as i32)),
+					],
+				)
+			})
+		})
+		.collect::<Result<Vec<_>, _>>()?;
+
+	for chunk in &updates.into_iter().chunks(200) {
+		db._batch(chunk.collect::<Vec<_>>()).await?;
+	}
+
+	Ok(())
+}
+
+/// How many file_paths [`forget_directory_fingerprints`] handles at a time, to stay under SQLite's limit on query parameters
+const FORGOTTEN_FINGERPRINTS_CHUNK_SIZE: usize = 256;
+
+/// A fingerprint only tells that a directory's entries in the database are up to date while nothing
+/// else changes them, so the ones of the parents of `file_paths` are forgotten, along with every
+/// fingerprint below those which are directories. Otherwise removing a directory and restoring it as
+/// it was would have the next walks skip its entries, which are gone from the database. Forgetting a
+/// few more is harmless, like those `starts_with` lets through for a `_` in a name.
+pub(crate) async fn forget_directory_fingerprints(
+	file_paths: &[IsolatedFilePathData<'_>],
+	db: &PrismaClient,
+) -> Result<(), prisma_client_rust::QueryError> {
+	if file_paths.is_empty() {
+		return Ok(());
+	}
+
+	db._batch(
+		file_paths
+			.chunks(FORGOTTEN_FINGERPRINTS_CHUNK_SIZE)
+			.map(|chunk| {
+				db.directory_fingerprint().delete_many(vec![or(chunk
+					.iter()
+					.map(|iso_file_path| {
+						let mut materialized_paths =
+							vec![directory_fingerprint::materialized_path::equals(
+								iso_file_path.to_parts().materialized_path.to_string(),
+							)];
+						if let Some(children_path) = iso_file_path.materialized_path_for_children()
+						{
+							materialized_paths.push(
+								directory_fingerprint::materialized_path::starts_with(
+									children_path,
+								),
+							);
+						}
+
+						prisma_client_rust::and![
+							directory_fingerprint::location_id::equals(iso_file_path.location_id()),
+							or(materialized_paths)
+						]
+					})
+					.collect())])
+			})
+			.collect::<Vec<_>>(),
+	)
+	.await?;
+
+	Ok(())
+}
+
+/// Replaces the location's statistics by the ones found by a full scan, see [`old_walk::ExtensionStatistics`]
+async fn upsert_location_statistics(
+	location_id: location::id::Type,
+	statistics: &ExtensionStatistics,
+	db: &PrismaClient,
+) -> Result<(), IndexerError> {
+	let extensions = serde_json::to_vec(statistics)?;
+
+	db.location_statistics()
+		.upsert(
+			location_statistics::location_id::equals(location_id),
+			location_statistics::create(
+				location::id::equals(location_id),
+				extensions.clone(),
+				vec![],
+			),
+			vec![
+				location_statistics::extensions::set(extensions),
+				location_statistics::date_captured::set(Utc::now().into()),
+			],
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
 pub async fn reverse_update_directories_sizes(
 	base_path: impl AsRef<Path>,
 	location_id: location::id::Type,
@@ -546,3 +1607,501 @@ pub async fn reverse_update_directories_sizes(
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use std::{io, sync::Arc};
+
+	use sd_prisma::prisma::{label, tag};
+	use uuid::Uuid;
+
+	use crate::{library::LibraryName, p2p::P2PTransport, Env, Node};
+
+	use super::*;
+
+	/// A node with a fresh library, for the tests which need a database
+	pub(super) async fn test_library() -> (Arc<Node>, Arc<Library>, tempfile::TempDir) {
+		let dir = tempfile::tempdir().unwrap();
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(Default::default()),
+		)
+		.await
+		.unwrap();
+		let library = node
+			.libraries
+			.create(LibraryName::new("Test").unwrap(), None, &node)
+			.await
+			.unwrap();
+
+		(node, library, dir)
+	}
+
+	/// A location at `path` in `library`, without writing anything to it or watching it
+	pub(super) async fn test_location(
+		library: &Library,
+		path: &Path,
+	) -> location_with_indexer_rules::Data {
+		crate::location::create_location(library, Uuid::new_v4(), path, &[], false)
+			.await
+			.unwrap()
+			.unwrap()
+			.data
+	}
+
+	#[tokio::test]
+	async fn an_empty_root_holds_back_removing_what_was_indexed() {
+		let (node, library, _dir) = test_library().await;
+		let db = &library.db;
+		let root = tempfile::tempdir().unwrap();
+		let files = ["a.txt", "b.txt"].map(|name| root.path().join(name));
+		for file in &files {
+			tokio::fs::write(file, b"hello").await.unwrap();
+		}
+
+		let location = test_location(&library, root.path()).await;
+		let refetch = || async {
+			db.location()
+				.find_unique(location::id::equals(location.id))
+				.include(location_with_indexer_rules::include())
+				.exec()
+				.await
+				.unwrap()
+				.unwrap()
+		};
+		let indexed = || async {
+			db.file_path()
+				.count(vec![file_path::location_id::equals(Some(location.id))])
+				.exec()
+				.await
+				.unwrap()
+		};
+
+		old_shallow(&location, &PathBuf::new(), &node, &library)
+			.await
+			.unwrap();
+		assert_eq!(indexed().await, 2);
+
+		// Its drive isn't connected
+		for file in &files {
+			tokio::fs::remove_file(file).await.unwrap();
+		}
+		old_shallow(&refetch().await, &PathBuf::new(), &node, &library)
+			.await
+			.unwrap();
+
+		assert_eq!(indexed().await, 2);
+		assert_eq!(
+			LocationAttention::from_db(refetch().await.attention),
+			Some(LocationAttention::AppearsEmpty)
+		);
+
+		// The user confirmed it really is empty
+		set_location_attention(location.id, Some(LocationAttention::ConfirmedEmpty), db)
+			.await
+			.unwrap();
+		old_shallow(&refetch().await, &PathBuf::new(), &node, &library)
+			.await
+			.unwrap();
+
+		assert_eq!(indexed().await, 0);
+
+		node.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn shallow_scans_store_how_many_entries_their_directory_has() {
+		let (node, library, _dir) = test_library().await;
+		let db = &library.db;
+		let root = tempfile::tempdir().unwrap();
+		let docs = root.path().join("docs");
+		tokio::fs::create_dir(&docs).await.unwrap();
+		for name in ["a.txt", "b.txt"] {
+			tokio::fs::write(docs.join(name), b"hello").await.unwrap();
+		}
+
+		let location = test_location(&library, root.path()).await;
+		old_shallow(&location, &PathBuf::from("docs"), &node, &library)
+			.await
+			.unwrap();
+
+		let stored = db
+			.file_path()
+			.find_first(vec![
+				file_path::location_id::equals(Some(location.id)),
+				file_path::materialized_path::equals(Some("/".to_string())),
+				file_path::name::equals(Some("docs".to_string())),
+			])
+			.exec()
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(stored.child_files, Some(2));
+		assert_eq!(stored.child_dirs, Some(0));
+
+		node.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn shallow_scans_persist_the_roots_of_nested_locations() {
+		let (node, library, _dir) = test_library().await;
+		let db = &library.db;
+		let root = tempfile::tempdir().unwrap();
+		let inner = root.path().join("inner");
+		tokio::fs::create_dir(&inner).await.unwrap();
+		tokio::fs::write(root.path().join("a.txt"), b"hello")
+			.await
+			.unwrap();
+		tokio::fs::write(inner.join("b.txt"), b"hello")
+			.await
+			.unwrap();
+
+		// Locations can't be created inside others, so the nested one is moved there afterwards
+		let elsewhere = tempfile::tempdir().unwrap();
+		let nested = test_location(&library, elsewhere.path()).await;
+		db.location()
+			.update(
+				location::id::equals(nested.id),
+				vec![location::path::set(Some(
+					inner.to_str().unwrap().to_string(),
+				))],
+			)
+			.exec()
+			.await
+			.unwrap();
+
+		let location = test_location(&library, root.path()).await;
+		old_shallow(&location, &PathBuf::new(), &node, &library)
+			.await
+			.unwrap();
+
+		let location_id = location.id;
+		let is_location_boundary = |name: &'static str| async move {
+			db.file_path()
+				.find_first(vec![
+					file_path::location_id::equals(Some(location_id)),
+					file_path::materialized_path::equals(Some("/".to_string())),
+					file_path::name::equals(Some(name.to_string())),
+				])
+				.exec()
+				.await
+				.unwrap()
+				.unwrap()
+				.is_location_boundary
+		};
+		assert_eq!(is_location_boundary("inner").await, Some(true));
+		assert_eq!(is_location_boundary("a").await, Some(false));
+
+		node.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn orphaned_objects_are_removed_with_their_tags_and_labels() {
+		let (node, library, _dir) = test_library().await;
+		let Library { db, sync, .. } = library.as_ref();
+
+		let object = db
+			.object()
+			.create(Uuid::new_v4().as_bytes().to_vec(), vec![])
+			.exec()
+			.await
+			.unwrap();
+		let tag = db
+			.tag()
+			.create(Uuid::new_v4().as_bytes().to_vec(), vec![])
+			.exec()
+			.await
+			.unwrap();
+		let label = db
+			.label()
+			.create("Holidays".to_string(), vec![])
+			.exec()
+			.await
+			.unwrap();
+		db.tag_on_object()
+			.create(
+				prisma_object::id::equals(object.id),
+				tag::id::equals(tag.id),
+				vec![],
+			)
+			.exec()
+			.await
+			.unwrap();
+		db.label_on_object()
+			.create(
+				prisma_object::id::equals(object.id),
+				label::id::equals(label.id),
+				vec![],
+			)
+			.exec()
+			.await
+			.unwrap();
+
+		remove_orphaned_objects(vec![object.id], db, sync)
+			.await
+			.unwrap();
+
+		assert_eq!(db.object().count(vec![]).exec().await.unwrap(), 0);
+		assert_eq!(db.tag_on_object().count(vec![]).exec().await.unwrap(), 0);
+		assert_eq!(db.label_on_object().count(vec![]).exec().await.unwrap(), 0);
+		// Only what was on the object goes, the tag and label themselves are the user's
+		assert_eq!(db.tag().count(vec![]).exec().await.unwrap(), 1);
+		assert_eq!(db.label().count(vec![]).exec().await.unwrap(), 1);
+
+		node.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn file_paths_created_during_a_scan_belong_to_its_generation() {
+		let (node, library, _dir) = test_library().await;
+		let db = &library.db;
+		let root = tempfile::tempdir().unwrap();
+		let location = test_location(&library, root.path()).await;
+
+		let scan_generation = next_scan_generation(location.id, db).await.unwrap();
+		assert_eq!(
+			current_scan_generation(location.id, db).await.unwrap(),
+			scan_generation
+		);
+
+		// Like the watcher does while the scan runs
+		let created = crate::location::create_file_path(
+			&library,
+			IsolatedFilePathData::new(location.id, root.path(), root.path().join("new.txt"), false)
+				.unwrap()
+				.to_parts(),
+			None,
+			sd_file_path_helper::FilePathMetadata {
+				inode: 1,
+				size_in_bytes: 0,
+				created_at: Utc::now(),
+				modified_at: Utc::now(),
+				hidden: false,
+				created_at_is_fallback: false,
+			},
+			false,
+		)
+		.await
+		.unwrap();
+		assert_eq!(created.scan_generation, Some(scan_generation));
+
+		let unverified = |scan_generation| {
+			find_unverified_file_paths(location.id, root.path(), scan_generation, &[], db)
+		};
+		assert!(unverified(scan_generation).await.unwrap().is_empty());
+
+		// The next scan didn't find it again
+		let next_generation = next_scan_generation(location.id, db).await.unwrap();
+		assert_eq!(next_generation, scan_generation + 1);
+		assert_eq!(
+			unverified(next_generation)
+				.await
+				.unwrap()
+				.into_iter()
+				.map(|file_path| file_path.id)
+				.collect::<Vec<_>>(),
+			[created.id]
+		);
+
+		node.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn forgetting_a_directory_forgets_its_fingerprints_and_its_parents() {
+		let (node, library, _dir) = test_library().await;
+		let db = &library.db;
+		let root = tempfile::tempdir().unwrap();
+		let location = test_location(&library, root.path()).await;
+
+		upsert_directory_fingerprints(
+			location.id,
+			&["/", "/photos/", "/photos/2024/", "/photos_old/", "/music/"].map(|path| {
+				DirectoryFingerprint {
+					materialized_path: path.to_string(),
+					fingerprint: vec![1],
+				}
+			}),
+			db,
+		)
+		.await
+		.unwrap();
+
+		forget_directory_fingerprints(
+			&[IsolatedFilePathData::from_db_data(
+				location.id,
+				true,
+				"/".into(),
+				"photos".into(),
+				"".into(),
+			)],
+			db,
+		)
+		.await
+		.unwrap();
+
+		let remaining = db
+			.directory_fingerprint()
+			.find_many(vec![directory_fingerprint::location_id::equals(
+				location.id,
+			)])
+			.exec()
+			.await
+			.unwrap()
+			.into_iter()
+			.map(|fingerprint| fingerprint.materialized_path)
+			.sorted()
+			.collect::<Vec<_>>();
+		assert_eq!(remaining, ["/music/", "/photos_old/"]);
+
+		node.shutdown().await;
+	}
+
+	#[test]
+	fn aggregates_errors_by_kind_and_directory() {
+		let directories = ["/a", "/b", "/b/c"];
+		let errors = (0..10_000).map(|i| {
+			let path = Path::new(directories[i % 3]).join(format!("file_{i}"));
+			IndexerError::FileIO(FileIOError::from((
+				path,
+				io::Error::from(io::ErrorKind::PermissionDenied),
+			)))
+		});
+
+		let aggregated = aggregate_errors(errors);
+		assert_eq!(aggregated.len(), 3);
+		for (err, (directory, count)) in aggregated
+			.iter()
+			.zip(directories.into_iter().zip([3334, 3333, 3333]))
+		{
+			assert_eq!(err.kind, "PermissionDenied");
+			assert_eq!(err.directory.as_deref(), Some(Path::new(directory)));
+			assert_eq!(err.count, count);
+			assert_eq!(err.sample_paths.len(), MAX_SAMPLE_PATHS);
+			assert!(err
+				.sample_paths
+				.iter()
+				.all(|path| path.parent() == Some(Path::new(directory))));
+		}
+
+		// Merging in another step's errors doesn't add entries for the same directories
+		let more = aggregate_errors([IndexerError::FileIO(FileIOError::from((
+			"/a/another_file",
+			io::Error::from(io::ErrorKind::PermissionDenied),
+		)))]);
+		let merged = merge_aggregated_errors(aggregated, more);
+		assert_eq!(merged.len(), 3);
+		assert_eq!(merged[0].count, 3335);
+		assert_eq!(merged[0].sample_paths.len(), MAX_SAMPLE_PATHS);
+	}
+
+	#[test]
+	fn subtrees_only_take_in_what_is_really_below_them() {
+		let file_paths = [
+			(1, Some("/foo/")),
+			(2, Some("/foo/bar/")),
+			// Shares the name's prefix, but is a sibling
+			(3, Some("/foobar/")),
+			// What `LIKE '/fo_/%'` would let through
+			(4, Some("/fox/")),
+			(5, Some("/")),
+			(6, None),
+			(7, Some("/fo_/photos/")),
+		]
+		.map(|(id, materialized_path)| file_path_in_subtree::Data {
+			id,
+			pub_id: vec![],
+			cas_id: None,
+			object_id: None,
+			materialized_path: materialized_path.map(str::to_string),
+		});
+
+		let subtrees = [
+			IsolatedFilePathData::from_relative_str(0, "foo/"),
+			IsolatedFilePathData::from_relative_str(0, "fo_/"),
+			// Nested in `foo`, its file_paths are only taken once
+			IsolatedFilePathData::from_relative_str(0, "foo/bar/"),
+		];
+		let prefixes = subtrees
+			.iter()
+			.filter_map(IsolatedFilePathData::materialized_path_for_children)
+			.collect::<Vec<_>>();
+		assert_eq!(prefixes, ["/foo/", "/fo_/", "/foo/bar/"]);
+
+		let mut file_paths = file_paths.to_vec();
+		// Like the batch of queries for each prefix, the nested one finds some of the same rows
+		file_paths.push(file_paths[1].clone());
+		assert_eq!(
+			in_subtrees(file_paths, &prefixes)
+				.into_iter()
+				.map(|file_path| file_path.id)
+				.collect::<Vec<_>>(),
+			[1, 2, 7]
+		);
+	}
+
+	#[tokio::test]
+	async fn locations_claim_their_root_before_their_first_walk() {
+		use sd_file_path_helper::{get_device_from_path, get_inode_from_path};
+
+		let (node, library, _dir) = test_library().await;
+		let (root, other_root) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+		let location = test_location(&library, root.path()).await;
+		let other_location = test_location(&library, other_root.path()).await;
+		let identity = (
+			get_device_from_path(root.path()).await.unwrap(),
+			get_inode_from_path(root.path()).await.unwrap(),
+		);
+
+		// Like on startup, neither location was walked yet
+		node.locations
+			.add(location.id, library.clone())
+			.await
+			.unwrap();
+		assert_eq!(
+			node.locations
+				.root_claim(library.id, other_location.id)
+				.claim(identity),
+			Err(location.id)
+		);
+
+		node.shutdown().await;
+	}
+
+	#[test]
+	fn only_unverified_file_paths_outside_exempt_directories_are_removed() {
+		let scan_generation = 7;
+		let previous_scan = scan_generation - 1;
+		let file_path = |id, materialized_path: &str, found_by| file_path_to_verify::Data {
+			id,
+			pub_id: vec![id as u8],
+			cas_id: None,
+			materialized_path: Some(materialized_path.to_string()),
+			scan_generation: found_by,
+		};
+
+		let to_remove = unverified_file_paths(
+			vec![
+				// Deleted while Spacedrive wasn't running, so an older scan was the last to find it
+				file_path(1, "/photos/", Some(previous_scan)),
+				// Indexed before file_paths were verified
+				file_path(2, "/", None),
+				// Found by the current scan, or created by the watcher while it ran
+				file_path(3, "/photos/", Some(scan_generation)),
+				// Within a directory which had errors during the current scan
+				file_path(4, "/unreadable/", Some(previous_scan)),
+				file_path(5, "/unreadable/nested/", None),
+			],
+			scan_generation,
+			&["/unreadable/".to_string()],
+		);
+
+		assert_eq!(
+			to_remove
+				.iter()
+				.map(|file_path| file_path.id)
+				.collect::<Vec<_>>(),
+			vec![1, 2]
+		);
+	}
+}
@@ -1,17 +1,21 @@
 use crate::{
-	file_paths_db_fetcher_fn, invalidate_query,
+	file_paths_db_fetcher_fn, fingerprint_db_fetcher_fn, invalidate_query,
 	library::Library,
-	location::{location_with_indexer_rules, update_location_size},
+	location::{
+		light_scan_location, location_with_indexer_rules, set_location_attention,
+		update_location_size, RootDevice,
+	},
 	old_job::{
-		CurrentStep, JobError, JobInitOutput, JobReportUpdate, JobResult, JobRunMetadata,
-		JobStepOutput, StatefulJob, WorkerContext,
+		CurrentStep, JobError, JobInitOutput, JobReportUpdate, JobResult, JobRunErrors,
+		JobRunMetadata, JobStepOutput, StatefulJob, WorkerContext,
 	},
 	to_remove_db_fetcher_fn,
+	volume::get_volumes,
 };
 
 use sd_file_path_helper::{
 	ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
-	IsolatedFilePathData,
+	get_device_from_path, IsolatedFilePathData,
 };
 use sd_prisma::{
 	prisma::{file_path, location},
@@ -21,31 +25,55 @@ use sd_sync::*;
 use sd_utils::{db::maybe_missing, from_bytes_to_uuid, msgpack};
 
 use std::{
-	collections::HashMap,
+	collections::{HashMap, HashSet},
+	future::Future,
 	hash::{Hash, Hasher},
 	path::{Path, PathBuf},
-	sync::Arc,
+	sync::{atomic::AtomicU64, Arc},
 	time::Duration,
 };
 
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
 use prisma_client_rust::operator::or;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::time::Instant;
-use tracing::{debug, info, warn};
+use tokio::time::{sleep, Instant};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 use super::{
-	execute_indexer_save_step, execute_indexer_update_step, iso_file_path_factory,
-	old_walk::{keep_walking, walk, ToWalkEntry, WalkResult},
-	remove_non_existing_file_paths, reverse_update_directories_sizes,
-	rules::IndexerRule,
-	IndexerError, OldIndexerJobSaveStep, OldIndexerJobUpdateStep,
+	aggregate_errors, count_file_paths_in_location, execute_indexer_save_step,
+	execute_indexer_update_step, find_unverified_file_paths, hold_back_removals,
+	in_flight::InFlightScan,
+	iso_file_path_factory, mark_as_found, merge_aggregated_errors, nested_location_roots,
+	next_scan_generation,
+	old_walk::{
+		check_root_device, claim_root, keep_walking, walk, ChildCounts, ComparisonCaps,
+		DirectoryFingerprint, ExtensionStatistics, ToWalkEntry, WalkOptions, WalkResult,
+		WalkedEntry, WalkerMemory, DEFAULT_WALKER_MEMORY_BUDGET,
+	},
+	record_scan, remove_non_existing_file_paths, remove_subtrees, reverse_update_directories_sizes,
+	rules::{IndexerRule, RuleHits},
+	saturating_u32, should_hold_back_removals, update_directories_child_counts,
+	upsert_directory_fingerprints, upsert_location_statistics,
+	volume_walks::{is_on_spinning_disk, walks_per_spinning_disk, Enqueued, VolumeWalkSlot},
+	warn_pinned_files_missing, AggregatedIndexerError, ErrorPolicy, ErrorPolicyBreach,
+	IndexerError, IoThrottle, IoTokenBucket, OldIndexerJobSaveStep, OldIndexerJobUpdateStep,
+	ScanSummary,
 };
 
 /// BATCH_SIZE is the number of files to index at each step, writing the chunk of files metadata in the database.
 const BATCH_SIZE: usize = 1000;
 
+/// INITIAL_WALK_LIMIT is how many entries the `init` walks before the first chunk is written to the database,
+/// the rest of the location is walked one directory per step.
+const INITIAL_WALK_LIMIT: u64 = BATCH_SIZE as u64;
+
+/// How recently a file must have been modified to be left for a later walk, for the locations
+/// which opted into deferring them
+pub const DEFAULT_RECENTLY_MODIFIED_WINDOW: Duration = Duration::from_secs(30);
+
 /// `IndexerJobInit` receives a `location::Data` object to be indexed
 /// and possibly a `sub_path` to be indexed. The `sub_path` is used when
 /// we want do index just a part of a location.
@@ -53,6 +81,128 @@ const BATCH_SIZE: usize = 1000;
 pub struct OldIndexerJobInit {
 	pub location: location_with_indexer_rules::Data,
 	pub sub_path: Option<PathBuf>,
+	/// Report every non critical error instead of aggregating them by kind and directory
+	#[serde(default)]
+	pub verbose_errors: bool,
+	/// How much memory the entries waiting to be written may take before the walker pauses,
+	/// [`DEFAULT_WALKER_MEMORY_BUDGET`] if not set. Low memory devices should set a smaller one.
+	#[serde(default)]
+	pub walker_memory_budget: Option<u64>,
+	/// Skip the database work for directories whose fingerprint didn't change since they were last
+	/// indexed. Full scans leave it unset, so anything a previous scan missed gets fixed.
+	#[serde(default)]
+	pub trust_fingerprints: bool,
+	/// Count the entries of each extension for the library statistics, only for full scans as the
+	/// stored statistics are replaced by the ones found
+	#[serde(default)]
+	pub extension_statistics: bool,
+	/// When to pause instead of carrying on with too many non critical errors
+	#[serde(default)]
+	pub error_policy: ErrorPolicy,
+	/// Leave out the files modified less than this long ago, as they're probably still being written,
+	/// and walk their directories again once it elapsed
+	#[serde(default)]
+	pub defer_recently_modified: Option<Duration>,
+	/// How deep below the location's root directories are walked, [`super::DEFAULT_MAX_WALK_DEPTH`] if not set
+	#[serde(default)]
+	pub max_walk_depth: Option<u32>,
+	/// Leave out the files cloud sync clients only keep online, instead of indexing them flagged as placeholders
+	#[serde(default)]
+	pub exclude_cloud_placeholders: bool,
+	/// Number the entries this scan creates in the order they're found, see [`WalkedEntry::discovery_seq`]
+	#[serde(default)]
+	pub track_discovery_order: bool,
+	/// Walk into the directories on other filesystems than the indexed path, eg. drives mounted inside the
+	/// location. Without it they're indexed but not what's in them, like `find -xdev`.
+	#[serde(default = "default_cross_filesystems")]
+	pub cross_filesystems: bool,
+}
+
+fn default_cross_filesystems() -> bool {
+	true
+}
+
+impl OldIndexerJobInit {
+	fn collects_statistics(&self) -> bool {
+		self.extension_statistics && self.sub_path.is_none()
+	}
+
+	/// Without `other_steps_pending` nothing would write the entries already found and give their memory
+	/// back, so walks go on whatever the budget
+	fn walker_memory(&self, used: &Arc<AtomicU64>, other_steps_pending: bool) -> WalkerMemory {
+		WalkerMemory::new(
+			Arc::clone(used),
+			if other_steps_pending {
+				self.walker_memory_budget
+					.unwrap_or(DEFAULT_WALKER_MEMORY_BUDGET)
+			} else {
+				u64::MAX
+			},
+		)
+	}
+
+	/// The location's bucket is shared with its other jobs and follows changes to its
+	/// `background_indexing` option while walking
+	async fn io_bucket(&self, ctx: &WorkerContext) -> IoTokenBucket {
+		ctx.node
+			.locations
+			.io_bucket(
+				ctx.library.id,
+				self.location.id,
+				IoThrottle::for_location(self.location.background_indexing),
+			)
+			.await
+	}
+
+	/// Waits for the walks of other jobs on the disk of `path` to be done if it's a spinning one.
+	/// The next walk of the disk starts once the returned slot is dropped.
+	async fn wait_for_disk(&self, ctx: &WorkerContext, path: &Path) -> Option<VolumeWalkSlot> {
+		// The walk fails by itself if the path can't be read, there's no disk to take turns on then
+		let device = get_device_from_path(path).await.ok()?;
+		let spinning = is_on_spinning_disk(&get_volumes().await, path);
+		let walks_per_disk = walks_per_spinning_disk(&ctx.node.config.get().await);
+
+		match ctx
+			.node
+			.locations
+			.volume_walks()
+			.enqueue(device, spinning, walks_per_disk)
+		{
+			Enqueued::Ready(slot) => Some(slot),
+			Enqueued::Queued { position, rx } => {
+				debug!(
+					"Location <id='{}'> is queued at position {position} behind other walks of its disk",
+					self.location.id
+				);
+				ctx.progress_msg(format!(
+					"Waiting for {position} other walk(s) of the same disk to finish"
+				));
+				rx.await.ok()
+			}
+		}
+	}
+
+	/// Walking a location whose drive isn't mounted would remove everything in it, so the location
+	/// is marked offline and the job finishes without touching the database instead
+	async fn walk_error(&self, ctx: &WorkerContext, err: IndexerError) -> JobError {
+		let IndexerError::WrongDevice { .. } = err else {
+			return err.into();
+		};
+
+		warn!(
+			"Not indexing location <id='{}'>: {err}, marking it as offline",
+			self.location.id
+		);
+		ctx.node
+			.locations
+			.remove_online(&from_bytes_to_uuid(&self.location.pub_id))
+			.await;
+
+		JobError::EarlyFinish {
+			name: <Self as StatefulJob>::NAME.to_string(),
+			reason: err.to_string(),
+		}
+	}
 }
 
 impl Hash for OldIndexerJobInit {
@@ -71,6 +221,34 @@ pub struct OldIndexerJobData {
 	location_path: PathBuf,
 	indexed_path: PathBuf,
 	indexer_rules: Vec<IndexerRule>,
+	/// For the scan's record, see [`ScanSummary`]
+	#[serde(default)]
+	scan_started_at: DateTime<Utc>,
+	/// Every file_path found by this scan is stamped with it, see [`super::next_scan_generation`].
+	/// Zero for jobs resumed from before file_paths were stamped
+	#[serde(default)]
+	scan_generation: i64,
+	/// Approximate memory taken by the entries walked but not written yet, see [`WalkerMemory`]
+	#[serde(skip)]
+	walker_memory_used: Arc<AtomicU64>,
+	/// Roots of the library's other locations inside this one, which index their contents themselves
+	#[serde(default)]
+	excluded_location_roots: Vec<PathBuf>,
+	/// Keeps this scan's updates from being written again by overlapping scans, see [`super::InFlightUpdates`]
+	#[serde(skip)]
+	in_flight_scan: Option<InFlightScan>,
+	/// How the walk started by this job compared entries with the database, for the ones continuing it
+	#[serde(default)]
+	comparison_caps: ComparisonCaps,
+	/// The next [`WalkedEntry::discovery_seq`], kept with the job so a resumed scan carries on numbering
+	#[serde(default)]
+	discovery_seq: AtomicU64,
+	/// This job's turn on a spinning disk, held until it's done so the disk's other walks don't interleave with it
+	#[serde(skip)]
+	volume_walk: Option<VolumeWalkSlot>,
+	/// The root was found empty, so nothing the job didn't find is removed, see [`should_hold_back_removals`]
+	#[serde(default)]
+	held_back_removals: bool,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -85,6 +263,136 @@ pub struct OldIndexerJobRunMetadata {
 	updated_count: u64,
 	removed_count: u64,
 	paths_and_sizes: HashMap<PathBuf, u64>,
+	#[serde(default)]
+	errors: Vec<AggregatedIndexerError>,
+	/// Entries which were deleted while being walked
+	#[serde(default)]
+	vanished: u64,
+	/// Entries rejected by their name before the walker read their metadata
+	#[serde(default)]
+	skipped_metadata_reads: u64,
+	/// Of the first walk only, stored once the job is done as its entries are written by later steps,
+	/// so an interrupted job doesn't leave directories marked as indexed. The other walks store theirs
+	/// right after writing their entries.
+	#[serde(default)]
+	fingerprints: Vec<DirectoryFingerprint>,
+	#[serde(default)]
+	progress: IndexerProgress,
+	#[serde(default)]
+	extension_statistics: Option<ExtensionStatistics>,
+	/// How many entries each indexer rule accepted and rejected across all walks
+	#[serde(default)]
+	rule_hits: Vec<RuleHits>,
+	/// The job already paused for its [`ErrorPolicy`], so resuming it carries on until the end
+	#[serde(default)]
+	error_policy_breached: bool,
+	/// Files the walks left out as they were modified too recently, see [`OldIndexerJobInit::defer_recently_modified`]
+	#[serde(default)]
+	deferred_recent: Vec<PathBuf>,
+	/// Depth of the deepest directory walked, relative to the job's indexed path
+	#[serde(default)]
+	max_depth_reached: u32,
+	/// Only the first walk's, as its entries are saved by later steps. The walks of the following steps
+	/// store theirs right away, see [`WalkResult::child_counts`]
+	#[serde(default)]
+	child_counts: HashMap<PathBuf, ChildCounts>,
+	/// See [`WalkResult::skipped_mounts`]
+	#[serde(default)]
+	skipped_mounts: Vec<PathBuf>,
+}
+
+/// Accounting of the job's steps, for a progress with a meaningful total even though directories
+/// to walk are only found while walking their parents.
+///
+/// Steps run in the order they were queued, so the ones from `init` are all done before any step
+/// spawned by a walk, and the total only grows as deeper directories are found.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexerProgress {
+	/// Steps queued by the job's `init`
+	queued: u64,
+	/// Steps queued by walks of directories, for their sub directories
+	spawned: u64,
+	completed: u64,
+	/// Entries found by the walks so far, to be shown as discovered items
+	found_entries: u64,
+	/// Entries the database had below the walked path when the job started, the progress is reported
+	/// as found out of these when they're known and as steps otherwise, eg. on a first scan
+	#[serde(default)]
+	expected_entries: u64,
+}
+
+impl IndexerProgress {
+	fn update(&mut self, new_data: Self) {
+		self.queued += new_data.queued;
+		self.spawned += new_data.spawned;
+		self.completed += new_data.completed;
+		self.found_entries += new_data.found_entries;
+		self.expected_entries += new_data.expected_entries;
+	}
+
+	/// Steps queued by `init` which aren't done yet
+	fn in_flight(&self) -> u64 {
+		self.queued.saturating_sub(self.completed)
+	}
+
+	/// Steps spawned by walks which are already done
+	fn completed_children(&self) -> u64 {
+		self.completed.saturating_sub(self.queued)
+	}
+
+	fn total_known(&self) -> u64 {
+		self.completed + self.in_flight() + self.spawned - self.completed_children()
+	}
+
+	/// Steps queued or spawned which aren't done yet, including the one running
+	fn pending(&self) -> u64 {
+		self.total_known() - self.completed
+	}
+
+	fn percentage(&self) -> u64 {
+		match self.total_known() {
+			0 => 100,
+			total => self.completed * 100 / total,
+		}
+	}
+
+	/// The job report's total and completed counts. Found entries can exceed the expected ones when
+	/// new files were added since the last scan.
+	fn task_counts(&self) -> [ScanProgress; 2] {
+		if self.expected_entries == 0 {
+			[
+				ScanProgress::TaskCount(self.total_known() as usize),
+				ScanProgress::CompletedTasks(self.completed as usize),
+			]
+		} else {
+			[
+				ScanProgress::TaskCount(self.expected_entries as usize),
+				ScanProgress::CompletedTasks(self.found_entries as usize),
+			]
+		}
+	}
+
+	fn message(&self) -> String {
+		if self.expected_entries == 0 {
+			format!(
+				"{}% done, discovered {} items",
+				self.percentage(),
+				self.found_entries
+			)
+		} else {
+			format!(
+				"Discovered {} items, {} expected",
+				self.found_entries, self.expected_entries
+			)
+		}
+	}
+
+	fn report(&self, ctx: &WorkerContext) {
+		let mut progress = self.task_counts().to_vec();
+		progress.push(ScanProgress::Message(self.message()));
+
+		OldIndexerJobData::on_scan_progress(ctx, progress);
+	}
 }
 
 impl JobRunMetadata for OldIndexerJobRunMetadata {
@@ -96,19 +404,100 @@ impl JobRunMetadata for OldIndexerJobRunMetadata {
 		self.total_save_steps += new_data.total_save_steps;
 		self.total_update_steps += new_data.total_update_steps;
 		self.indexed_count += new_data.indexed_count;
+		self.updated_count += new_data.updated_count;
 		self.removed_count += new_data.removed_count;
+		self.vanished += new_data.vanished;
+		self.skipped_metadata_reads += new_data.skipped_metadata_reads;
+		self.fingerprints.extend(new_data.fingerprints);
+		self.deferred_recent.extend(new_data.deferred_recent);
+		self.skipped_mounts.extend(new_data.skipped_mounts);
+		self.max_depth_reached = self.max_depth_reached.max(new_data.max_depth_reached);
+		self.progress.update(new_data.progress);
+
+		if let Some(statistics) = new_data.extension_statistics {
+			self.extension_statistics
+				.get_or_insert_with(Default::default)
+				.merge(statistics);
+		}
+
+		RuleHits::merge(&mut self.rule_hits, new_data.rule_hits);
 
 		for (path, size) in new_data.paths_and_sizes {
 			*self.paths_and_sizes.entry(path).or_default() += size;
 		}
+
+		self.child_counts.extend(new_data.child_counts);
+
+		self.errors = merge_aggregated_errors(std::mem::take(&mut self.errors), new_data.errors);
+		self.error_policy_breached |= new_data.error_policy_breached;
+	}
+}
+
+impl OldIndexerJobRunMetadata {
+	/// The job's progress once the step which produced `step_metadata` is done
+	fn progress_after(&self, step_metadata: &Self) -> IndexerProgress {
+		let mut progress = self.progress;
+		progress.update(step_metadata.progress);
+		progress
+	}
+
+	/// Whether the job should pause for `policy` once the step which produced `step_metadata` is done,
+	/// only the first time it's breached
+	fn error_policy_breach(
+		&self,
+		policy: &ErrorPolicy,
+		step_metadata: &Self,
+	) -> Option<ErrorPolicyBreach> {
+		if self.error_policy_breached {
+			return None;
+		}
+
+		policy.breach(
+			self.errors.iter().chain(&step_metadata.errors),
+			self.progress_after(step_metadata).found_entries,
+		)
+	}
+
+	/// What's kept of the job in the location's scan records once it's done
+	fn scan_summary(
+		&self,
+		data: &OldIndexerJobData,
+		removed_unverified_count: u64,
+		finished_at: DateTime<Utc>,
+	) -> ScanSummary {
+		ScanSummary {
+			started_at: data.scan_started_at,
+			finished_at,
+			sub_path: data
+				.indexed_path
+				.strip_prefix(&data.location_path)
+				.ok()
+				.filter(|sub_path| !sub_path.as_os_str().is_empty())
+				.map(|sub_path| sub_path.to_string_lossy().to_string()),
+			found_entries: saturating_u32(self.progress.found_entries),
+			indexed: saturating_u32(self.indexed_count),
+			updated: saturating_u32(self.updated_count),
+			removed: saturating_u32(self.removed_count + removed_unverified_count),
+			vanished: saturating_u32(self.vanished),
+			max_depth_reached: self.max_depth_reached,
+			skipped_mounts: saturating_u32(self.skipped_mounts.len()),
+			bytes_indexed: self
+				.paths_and_sizes
+				.get(&data.indexed_path)
+				.copied()
+				.unwrap_or_default(),
+			scan_read_ms: saturating_u32(self.scan_read_time.as_millis()),
+			db_write_ms: saturating_u32(self.db_write_time.as_millis()),
+			errors: ScanSummary::error_counts(&self.errors),
+			rule_hits: ScanSummary::rule_hits(&self.rule_hits),
+		}
 	}
 }
 
 #[derive(Clone)]
 pub enum ScanProgress {
-	ChunkCount(usize),
-	SavedChunks(usize),
-	UpdatedChunks(usize),
+	TaskCount(usize),
+	CompletedTasks(usize),
 	Message(String),
 }
 
@@ -118,10 +507,8 @@ impl OldIndexerJobData {
 			progress
 				.into_iter()
 				.map(|p| match p {
-					ScanProgress::ChunkCount(c) => JobReportUpdate::TaskCount(c),
-					ScanProgress::SavedChunks(p) | ScanProgress::UpdatedChunks(p) => {
-						JobReportUpdate::CompletedTaskCount(p)
-					}
+					ScanProgress::TaskCount(c) => JobReportUpdate::TaskCount(c),
+					ScanProgress::CompletedTasks(p) => JobReportUpdate::CompletedTaskCount(p),
 					ScanProgress::Message(m) => JobReportUpdate::Message(m),
 				})
 				.collect(),
@@ -138,8 +525,10 @@ pub enum OldIndexerJobStepInput {
 }
 
 /// A `IndexerJob` is a stateful job that walks a directory and indexes all files.
-/// First it walks the directory and generates a list of files to index, chunked into
+/// First it walks up to [`INITIAL_WALK_LIMIT`] entries and generates a list of files to index, chunked into
 /// batches of [`BATCH_SIZE`]. Then for each chunk it write the file metadata to the database.
+/// Every directory left to walk is a step of its own, which writes the entries it finds
+/// before returning the sub directories to walk, so a directory's own row always exists before its children.
 #[async_trait::async_trait]
 impl StatefulJob for OldIndexerJobInit {
 	type Data = OldIndexerJobData;
@@ -166,16 +555,25 @@ impl StatefulJob for OldIndexerJobInit {
 		let db = Arc::clone(&ctx.library.db);
 		let sync = &ctx.library.sync;
 
-		let indexer_rules = init
-			.location
-			.indexer_rules
-			.iter()
-			.map(|rule| IndexerRule::try_from(&rule.indexer_rule))
-			.collect::<Result<Vec<_>, _>>()
-			.map_err(IndexerError::from)?;
+		let indexer_rules =
+			IndexerRule::for_location(&init.location).map_err(IndexerError::from)?;
+
+		let root_device =
+			RootDevice::from_db(init.location.root_device.as_deref(), &init.location.pub_id);
 
-		let to_walk_path = match &init.sub_path {
+		let root_claim = ctx.node.locations.root_claim(ctx.library.id, location_id);
+
+		// Walks of a sub path check the location root themselves, as the sub path can be on a volume
+		// mounted inside the location
+		let (to_walk_path, expected_device, walk_root_claim) = match &init.sub_path {
 			Some(sub_path) if sub_path != Path::new("") => {
+				if let Err(err) = check_root_device(location_path, root_device).await {
+					return Err(init.walk_error(ctx, err).await);
+				}
+				if let Err(err) = claim_root(location_path, root_claim).await {
+					return Err(init.walk_error(ctx, err).await);
+				}
+
 				let full_path = ensure_sub_path_is_in_location(location_path, sub_path)
 					.await
 					.map_err(IndexerError::from)?;
@@ -192,33 +590,127 @@ impl StatefulJob for OldIndexerJobInit {
 				)
 				.await?;
 
-				full_path
+				(full_path, None, None)
 			}
-			_ => location_path.to_path_buf(),
+			_ => (location_path.to_path_buf(), root_device, Some(root_claim)),
 		};
 
+		let excluded_location_roots =
+			nested_location_roots(location_id, location_path, &db).await?;
+		if !excluded_location_roots.is_empty() {
+			debug!(
+				"Not walking into {} other locations nested in location <id='{location_id}'>",
+				excluded_location_roots.len()
+			);
+		}
+
+		let expected_entries = expected_entries(count_file_paths_in_location(
+			location_id,
+			location_path,
+			&to_walk_path,
+			&db,
+		))
+		.await;
+
+		let volume_walk = init.wait_for_disk(ctx, &to_walk_path).await;
+
+		let scan_started_at = Utc::now();
+		let scan_generation = next_scan_generation(location_id, &db).await?;
+		let in_flight_scan = ctx.library.in_flight_updates.begin_scan(location_id);
+		let walker_memory_used = Arc::default();
+		let walker_memory = init.walker_memory(&walker_memory_used, true);
+		let discovery_seq = AtomicU64::default();
+		let io_throttle = IoThrottle::for_location(init.location.background_indexing);
+		ctx.node
+			.locations
+			.set_io_throttle(ctx.library.id, location_id, io_throttle)
+			.await;
+		let io_bucket = init.io_bucket(ctx).await;
+		let walk_id = Uuid::new_v4();
+		// Known before walking, as the database is asked for the entries which weren't found accordingly
+		let (_, comparison_caps) = ComparisonCaps::detect(&to_walk_path);
 		let scan_start = Instant::now();
 		let WalkResult {
 			walked,
 			to_update,
+			unchanged,
 			to_walk,
 			to_remove,
+			to_remove_subtrees,
 			errors,
 			paths_and_sizes,
-		} = walk(
+			vanished,
+			skipped_metadata_reads,
+			fingerprints,
+			found_entries,
+			extension_statistics,
+			filesystem,
+			comparison_caps,
+			deferred_recent,
+			max_depth_reached,
+			child_counts,
+			skipped_mounts,
+			..
+		} = match walk(
 			&to_walk_path,
+			walk_id,
 			&indexer_rules,
 			update_notifier_fn(ctx),
 			file_paths_db_fetcher_fn!(&db),
-			to_remove_db_fetcher_fn!(location_id, &db),
+			to_remove_db_fetcher_fn!(location_id, &db, comparison_caps.case_insensitive),
+			fingerprint_db_fetcher_fn!(location_id, &db, init.trust_fingerprints),
 			iso_file_path_factory(location_id, location_path),
-			50_000,
+			&walker_memory,
+			&io_bucket,
+			WalkOptions {
+				expected_device,
+				comparison_caps: Some(comparison_caps),
+				limit: INITIAL_WALK_LIMIT,
+				collect_statistics: init.collects_statistics(),
+				excluded_location_roots: &excluded_location_roots,
+				root_claim: walk_root_claim,
+				defer_recently_modified: init.defer_recently_modified,
+				max_depth: init.max_walk_depth,
+				exclude_cloud_placeholders: init.exclude_cloud_placeholders,
+				cross_filesystems: init.cross_filesystems,
+				discovery_seq: init.track_discovery_order.then_some(&discovery_seq),
+				location_root: Some(location_path),
+				..Default::default()
+			},
 		)
-		.await?;
+		.await
+		{
+			Ok(walk_result) => walk_result,
+			Err(err) => return Err(init.walk_error(ctx, err).await),
+		};
 		let scan_read_time = scan_start.elapsed();
+		let rule_hits = IndexerRule::snapshot_and_reset(&indexer_rules);
+		warn_pinned_files_missing(&errors, &ctx.node).await;
+		let (aggregated_errors, errors) = report_errors(errors, init.verbose_errors);
 		let to_remove = to_remove.collect::<Vec<_>>();
 
+		let held_back_removals = to_walk_path == location_path
+			&& should_hold_back_removals(
+				init.location.attention,
+				found_entries as u64,
+				expected_entries,
+			);
+		let (to_remove, to_remove_subtrees) = if held_back_removals {
+			hold_back_removals(
+				init.location.id,
+				init.location.name.as_deref(),
+				expected_entries,
+				&ctx.node,
+				&ctx.library,
+			)
+			.await?;
+			(vec![], vec![])
+		} else {
+			(to_remove, to_remove_subtrees)
+		};
+
 		debug!(
+			%walk_id,
 			"Walker at indexer job found {} file_paths to be removed",
 			to_remove.len()
 		);
@@ -236,9 +728,25 @@ impl StatefulJob for OldIndexerJobInit {
 
 		let db_delete_start = Instant::now();
 		// TODO pass these uuids to sync system
-		let removed_count = remove_non_existing_file_paths(to_remove, &db, sync).await?;
+		let removed_count = remove_non_existing_file_paths(to_remove, &db, sync).await?
+			+ remove_subtrees(location_id, &to_remove_subtrees, &db, sync).await?;
+		mark_as_found(unchanged, scan_generation, &db).await?;
 		let db_delete_time = db_delete_start.elapsed();
 
+		// A sub path can be on another volume mounted inside the location, only its root tells
+		if let Some(filesystem) = filesystem.filter(|_| to_walk_path == location_path) {
+			let filesystem = filesystem.to_string();
+			if init.location.filesystem.as_ref() != Some(&filesystem) {
+				db.location()
+					.update(
+						location::id::equals(location_id),
+						vec![location::filesystem::set(Some(filesystem))],
+					)
+					.exec()
+					.await?;
+			}
+		}
+
 		let total_new_paths = &mut 0;
 		let total_updated_paths = &mut 0;
 		let to_walk_count = to_walk.len();
@@ -258,6 +766,7 @@ impl StatefulJob for OldIndexerJobInit {
 				OldIndexerJobStepInput::Save(OldIndexerJobSaveStep {
 					chunk_idx: i,
 					walked: chunk_steps,
+					scan_generation: Some(scan_generation),
 				})
 			})
 			.chain(
@@ -274,6 +783,7 @@ impl StatefulJob for OldIndexerJobInit {
 						OldIndexerJobStepInput::Update(OldIndexerJobUpdateStep {
 							chunk_idx: i,
 							to_update: chunk_updates,
+							scan_generation: Some(scan_generation),
 						})
 					}),
 			)
@@ -282,22 +792,33 @@ impl StatefulJob for OldIndexerJobInit {
 
 		debug!("Walker at indexer job found {total_updated_paths} file_paths to be updated");
 
-		OldIndexerJobData::on_scan_progress(
-			ctx,
-			vec![
-				ScanProgress::ChunkCount(*to_save_chunks + *to_update_chunks),
-				ScanProgress::Message(format!(
-					"Starting saving {total_new_paths} files or directories, \
-					{total_updated_paths} files or directories to update, \
-					there still {to_walk_count} directories to index",
-				)),
-			],
-		);
+		let progress = IndexerProgress {
+			queued: steps.len() as u64,
+			found_entries: found_entries as u64,
+			expected_entries,
+			..Default::default()
+		};
+		let mut progress_updates = progress.task_counts().to_vec();
+		progress_updates.push(ScanProgress::Message(format!(
+			"Starting saving {total_new_paths} files or directories, \
+			{total_updated_paths} files or directories to update, \
+			there still {to_walk_count} directories to index",
+		)));
+		OldIndexerJobData::on_scan_progress(ctx, progress_updates);
 
 		*data = Some(OldIndexerJobData {
 			location_path: location_path.to_path_buf(),
 			indexed_path: to_walk_path,
 			indexer_rules,
+			scan_started_at,
+			scan_generation,
+			walker_memory_used,
+			excluded_location_roots,
+			in_flight_scan: Some(in_flight_scan),
+			comparison_caps,
+			discovery_seq,
+			volume_walk,
+			held_back_removals,
 		});
 
 		Ok((
@@ -312,13 +833,20 @@ impl StatefulJob for OldIndexerJobInit {
 				total_save_steps: *to_save_chunks as u64,
 				total_update_steps: *to_update_chunks as u64,
 				paths_and_sizes,
+				errors: aggregated_errors,
+				vanished,
+				skipped_metadata_reads,
+				fingerprints,
+				progress,
+				extension_statistics,
+				rule_hits,
+				deferred_recent,
+				max_depth_reached,
+				child_counts,
+				skipped_mounts,
 			},
 			steps,
-			errors
-				.into_iter()
-				.map(|e| format!("{e}"))
-				.collect::<Vec<_>>()
-				.into(),
+			errors,
 		)
 			.into())
 	}
@@ -332,6 +860,10 @@ impl StatefulJob for OldIndexerJobInit {
 		run_metadata: &Self::RunMetadata,
 	) -> Result<JobStepOutput<Self::Step, Self::RunMetadata>, JobError> {
 		let init = self;
+		let walker_memory = init.walker_memory(
+			&data.walker_memory_used,
+			run_metadata.progress.pending() > 1,
+		);
 		let mut new_metadata = Self::RunMetadata::default();
 		match step {
 			OldIndexerJobStepInput::Save(step) => {
@@ -339,19 +871,19 @@ impl StatefulJob for OldIndexerJobInit {
 
 				OldIndexerJobData::on_scan_progress(
 					ctx,
-					vec![
-						ScanProgress::SavedChunks(step.chunk_idx + 1),
-						ScanProgress::Message(format!(
-							"Writing chunk {} of {} to database",
-							step.chunk_idx, run_metadata.total_save_steps
-						)),
-					],
+					vec![ScanProgress::Message(format!(
+						"Writing chunk {} of {} to database",
+						step.chunk_idx, run_metadata.total_save_steps
+					))],
 				);
 
 				let count = execute_indexer_save_step(&init.location, step, &ctx.library).await?;
+				walker_memory.release(estimated_size(&step.walked));
 
 				new_metadata.indexed_count = count as u64;
 				new_metadata.db_write_time = start_time.elapsed();
+				new_metadata.progress.completed = 1;
+				run_metadata.progress_after(&new_metadata).report(ctx);
 
 				Ok(new_metadata.into())
 			}
@@ -359,19 +891,19 @@ impl StatefulJob for OldIndexerJobInit {
 				let start_time = Instant::now();
 				OldIndexerJobData::on_scan_progress(
 					ctx,
-					vec![
-						ScanProgress::UpdatedChunks(to_update.chunk_idx + 1),
-						ScanProgress::Message(format!(
-							"Updating chunk {} of {} to database",
-							to_update.chunk_idx, run_metadata.total_save_steps
-						)),
-					],
+					vec![ScanProgress::Message(format!(
+						"Updating chunk {} of {} to database",
+						to_update.chunk_idx, run_metadata.total_save_steps
+					))],
 				);
 
 				let count = execute_indexer_update_step(to_update, &ctx.library).await?;
+				walker_memory.release(estimated_size(&to_update.to_update));
 
 				new_metadata.updated_count = count as u64;
 				new_metadata.db_write_time = start_time.elapsed();
+				new_metadata.progress.completed = 1;
+				run_metadata.progress_after(&new_metadata).report(ctx);
 
 				Ok(new_metadata.into())
 			}
@@ -389,85 +921,152 @@ impl StatefulJob for OldIndexerJobInit {
 				let WalkResult {
 					walked,
 					to_update,
+					unchanged,
 					to_walk,
 					to_remove,
+					to_remove_subtrees,
 					errors,
 					paths_and_sizes,
+					walk_id,
+					vanished,
+					skipped_metadata_reads,
+					fingerprints,
+					spawned_children,
+					found_entries,
+					extension_statistics,
+					deferred_recent,
+					max_depth_reached,
+					child_counts,
+					skipped_mounts,
+					..
 				} = keep_walking(
 					to_walk_entry,
 					&data.indexer_rules,
 					update_notifier_fn(ctx),
 					file_paths_db_fetcher_fn!(&db),
-					to_remove_db_fetcher_fn!(location_id, &db),
+					to_remove_db_fetcher_fn!(
+						location_id,
+						&db,
+						data.comparison_caps.case_insensitive
+					),
+					fingerprint_db_fetcher_fn!(location_id, &db, init.trust_fingerprints),
 					iso_file_path_factory(location_id, location_path),
+					&walker_memory,
+					&init.io_bucket(ctx).await,
+					init.collects_statistics(),
+					&data.excluded_location_roots,
+					data.comparison_caps,
+					init.defer_recently_modified,
+					init.exclude_cloud_placeholders,
+					init.track_discovery_order.then_some(&data.discovery_seq),
 				)
 				.await?;
 
+				new_metadata.rule_hits = IndexerRule::snapshot_and_reset(&data.indexer_rules);
 				new_metadata.paths_and_sizes = paths_and_sizes;
+				new_metadata.extension_statistics = extension_statistics;
+				new_metadata.vanished = vanished;
+				new_metadata.skipped_metadata_reads = skipped_metadata_reads;
+				new_metadata.deferred_recent = deferred_recent;
+				new_metadata.max_depth_reached = max_depth_reached;
+				new_metadata.skipped_mounts = skipped_mounts;
+				new_metadata.progress = IndexerProgress {
+					spawned: spawned_children as u64,
+					completed: 1,
+					found_entries: found_entries as u64,
+					..Default::default()
+				};
+				warn_pinned_files_missing(&errors, &ctx.node).await;
+				let (aggregated_errors, mut errors) = report_errors(errors, init.verbose_errors);
+				new_metadata.errors = aggregated_errors;
+
+				if let Some(breach) =
+					run_metadata.error_policy_breach(&init.error_policy, &new_metadata)
+				{
+					warn!("Pausing indexer job of location <id='{location_id}'>: {breach}");
+					new_metadata.error_policy_breached = true;
+					errors.0.push(breach.to_string());
+					// The step still finishes, the job waits for the user before running the next one
+					ctx.pause_job().await;
+				}
 
 				new_metadata.scan_read_time = scan_start.elapsed();
 
 				let db_delete_time = Instant::now();
 				// TODO pass these uuids to sync system
 				new_metadata.removed_count =
-					remove_non_existing_file_paths(to_remove, &db, sync).await?;
+					remove_non_existing_file_paths(to_remove, &db, sync).await?
+						+ remove_subtrees(location_id, &to_remove_subtrees, &db, sync).await?;
+				mark_as_found(unchanged, data.scan_generation, &db).await?;
 				new_metadata.db_write_time = db_delete_time.elapsed();
 
-				let to_walk_count = to_walk.len();
+				debug!(
+					%walk_id,
+					"Walker at indexer job found {spawned_children} more directories to walk"
+				);
 
-				let more_steps = walked
+				let save_steps = walked
 					.chunks(BATCH_SIZE)
 					.into_iter()
 					.enumerate()
-					.map(|(i, chunk)| {
-						let chunk_steps = chunk.collect::<Vec<_>>();
-						new_metadata.total_paths += chunk_steps.len() as u64;
-						new_metadata.total_save_steps += 1;
-
-						OldIndexerJobStepInput::Save(OldIndexerJobSaveStep {
-							chunk_idx: i,
-							walked: chunk_steps,
-						})
+					.map(|(i, chunk)| OldIndexerJobSaveStep {
+						chunk_idx: i,
+						walked: chunk.collect::<Vec<_>>(),
+						scan_generation: Some(data.scan_generation),
+					})
+					.collect::<Vec<_>>();
+				let update_steps = to_update
+					.chunks(BATCH_SIZE)
+					.into_iter()
+					.enumerate()
+					.map(|(i, chunk)| OldIndexerJobUpdateStep {
+						chunk_idx: i,
+						to_update: chunk.collect::<Vec<_>>(),
+						scan_generation: Some(data.scan_generation),
 					})
-					.chain(to_update.chunks(BATCH_SIZE).into_iter().enumerate().map(
-						|(i, chunk)| {
-							let chunk_updates = chunk.collect::<Vec<_>>();
-							new_metadata.total_updated_paths += chunk_updates.len() as u64;
-							new_metadata.total_update_steps += 1;
-
-							OldIndexerJobStepInput::Update(OldIndexerJobUpdateStep {
-								chunk_idx: i,
-								to_update: chunk_updates,
-							})
-						},
-					))
-					.chain(to_walk.into_iter().map(OldIndexerJobStepInput::Walk))
 					.collect::<Vec<_>>();
 
-				OldIndexerJobData::on_scan_progress(
-					ctx,
-					vec![
-						ScanProgress::ChunkCount(more_steps.len() - to_walk_count),
-						ScanProgress::Message(format!(
-							"Scanned {} more files or directories; \
-							{} more directories to scan and {} more entries to update",
-							new_metadata.total_paths,
-							to_walk_count,
-							new_metadata.total_updated_paths
-						)),
-					],
+				// Writing right away instead of queueing more steps, so the entries show up while the rest
+				// of the location is still being walked and we only hold a single directory's entries in memory
+				let db_write_start = Instant::now();
+				for step in &save_steps {
+					new_metadata.total_paths += step.walked.len() as u64;
+					new_metadata.total_save_steps += 1;
+					new_metadata.indexed_count +=
+						execute_indexer_save_step(&init.location, step, &ctx.library).await? as u64;
+					walker_memory.release(estimated_size(&step.walked));
+				}
+				for step in &update_steps {
+					new_metadata.total_updated_paths += step.to_update.len() as u64;
+					new_metadata.total_update_steps += 1;
+					new_metadata.updated_count +=
+						execute_indexer_update_step(step, &ctx.library).await? as u64;
+					walker_memory.release(estimated_size(&step.to_update));
+				}
+				upsert_directory_fingerprints(location_id, &fingerprints, &db).await?;
+				update_directories_child_counts(
+					&child_counts,
+					location_id,
+					&data.location_path,
+					&db,
+				)
+				.await?;
+				new_metadata.db_write_time += db_write_start.elapsed();
+
+				let more_steps = to_walk
+					.into_iter()
+					.map(OldIndexerJobStepInput::Walk)
+					.collect::<Vec<_>>();
+
+				debug!(
+					%walk_id,
+					"Indexed {} more files or directories and updated {} more entries",
+					new_metadata.total_paths,
+					new_metadata.total_updated_paths
 				);
+				run_metadata.progress_after(&new_metadata).report(ctx);
 
-				Ok((
-					more_steps,
-					new_metadata,
-					errors
-						.into_iter()
-						.map(|e| format!("{e}"))
-						.collect::<Vec<_>>()
-						.into(),
-				)
-					.into())
+				Ok((more_steps, new_metadata, errors).into())
 			}
 		}
 	}
@@ -486,15 +1085,101 @@ impl StatefulJob for OldIndexerJobInit {
 
 		info!(
 			"Scan of {indexed_path_str} completed in {:?}. {} new files found, \
-			indexed {} files in db, updated {} entries. db write completed in {:?}",
+			indexed {} files in db, updated {} entries, {} vanished while scanning, \
+			{} rejected by their name without reading their metadata, \
+			{} of {} expected entries found. db write completed in {:?}",
 			run_metadata.scan_read_time,
 			run_metadata.total_paths,
 			run_metadata.indexed_count,
 			run_metadata.total_updated_paths,
+			run_metadata.vanished,
+			run_metadata.skipped_metadata_reads,
+			run_metadata.progress.found_entries,
+			run_metadata.progress.expected_entries,
 			run_metadata.db_write_time,
 		);
+		for RuleHits {
+			name,
+			accepted,
+			rejected,
+			..
+		} in &run_metadata.rule_hits
+		{
+			debug!("Indexer rule '{name}' accepted {accepted} and rejected {rejected} entries");
+		}
 
-		if run_metadata.indexed_count > 0 || run_metadata.removed_count > 0 {
+		upsert_directory_fingerprints(
+			init.location.id,
+			&run_metadata.fingerprints,
+			&ctx.library.db,
+		)
+		.await?;
+
+		if let Some(statistics) = &run_metadata.extension_statistics {
+			upsert_location_statistics(init.location.id, statistics, &ctx.library.db).await?;
+		}
+
+		// Refreshed by every scan, even when nothing changed, as the watcher clears them
+		if let Some(data) = data
+			.as_ref()
+			.filter(|_| !run_metadata.child_counts.is_empty())
+		{
+			update_directories_child_counts(
+				&run_metadata.child_counts,
+				init.location.id,
+				&data.location_path,
+				&ctx.library.db,
+			)
+			.await?;
+		}
+
+		let removed_unverified_count = match data {
+			// Jobs resumed from before file_paths were stamped don't know their scan's generation,
+			// and skipped directories weren't verified at all when trusting their fingerprints
+			Some(data)
+				if data.indexed_path == data.location_path
+					&& data.scan_generation != 0
+					&& !init.trust_fingerprints
+					&& !data.held_back_removals =>
+			{
+				remove_unverified_file_paths(init.location.id, data, run_metadata, ctx).await?
+			}
+			_ => 0,
+		};
+
+		// Found its entries again, or removed them once the user confirmed it's empty
+		if init.location.attention.is_some()
+			&& data.as_ref().is_some_and(|data| {
+				data.indexed_path == data.location_path && !data.held_back_removals
+			}) {
+			set_location_attention(init.location.id, None, &ctx.library.db).await?;
+			invalidate_query!(ctx.library, "locations.list");
+			invalidate_query!(ctx.library, "locations.get");
+		}
+
+		ctx.library.indexing_updates.finish(init.location.id);
+
+		// The scan itself went fine, only its summary is missing from the location's last scans
+		if let Some(data) = data {
+			if let Err(e) = record_scan(
+				init.location.id,
+				data.scan_generation,
+				&run_metadata.scan_summary(data, removed_unverified_count, Utc::now()),
+				&ctx.library.db,
+			)
+			.await
+			{
+				error!(
+					"Failed to record the scan of location <id={}>: {e:#?}",
+					init.location.id
+				);
+			}
+		}
+
+		if run_metadata.indexed_count > 0
+			|| run_metadata.removed_count > 0
+			|| removed_unverified_count > 0
+		{
 			invalidate_query!(ctx.library, "search.paths");
 		}
 
@@ -506,6 +1191,7 @@ impl StatefulJob for OldIndexerJobInit {
 		if run_metadata.indexed_count > 0
 			|| run_metadata.removed_count > 0
 			|| run_metadata.updated_count > 0
+			|| removed_unverified_count > 0
 		{
 			if let Some(data) = data {
 				update_directories_sizes(
@@ -533,10 +1219,157 @@ impl StatefulJob for OldIndexerJobInit {
 			}
 		}
 
-		Ok(Some(json!({"init: ": init, "run_metadata": run_metadata})))
+		if let (Some(window), Some(data)) = (init.defer_recently_modified, data) {
+			rewalk_deferred(window, &run_metadata.deferred_recent, data, init, ctx);
+		}
+
+		Ok(Some(json!({
+			"init: ": init,
+			"run_metadata": run_metadata,
+			"expected_entries": run_metadata.progress.expected_entries,
+			"rule_hits": run_metadata.rule_hits,
+		})))
 	}
 }
 
+/// The directories of the files deferred by the scan are light scanned again once the files had
+/// `window` to settle, as there are usually only a few of them
+fn rewalk_deferred(
+	window: Duration,
+	deferred_recent: &[PathBuf],
+	data: &OldIndexerJobData,
+	init: &OldIndexerJobInit,
+	ctx: &WorkerContext,
+) {
+	// Relative to the location, as that's how sub paths are given to scans
+	let directories = deferred_recent
+		.iter()
+		.filter_map(|path| path.parent()?.strip_prefix(&data.location_path).ok())
+		.map(Path::to_path_buf)
+		.collect::<HashSet<_>>();
+	if directories.is_empty() {
+		return;
+	}
+
+	debug!(
+		"Walking {} directories of location <id='{}'> again in {window:?}, \
+		for their recently modified files",
+		directories.len(),
+		init.location.id
+	);
+
+	let node = Arc::clone(&ctx.node);
+	let library = Arc::clone(&ctx.library);
+	let location = init.location.clone();
+	tokio::spawn(async move {
+		sleep(window).await;
+
+		for directory in directories {
+			if let Err(e) = light_scan_location(
+				Arc::clone(&node),
+				Arc::clone(&library),
+				location.clone(),
+				&directory,
+			)
+			.await
+			{
+				warn!(
+					"Failed to walk '{}' again for its recently modified files: {e:#?}",
+					directory.display()
+				);
+			}
+		}
+	});
+}
+
+/// After a successful scan of a whole location, any file_path it didn't find again doesn't exist anymore.
+/// Directories with errors are left alone as the scan might have missed some of their contents, and so
+/// are the roots of nested locations and the mounted filesystems which it didn't walk into.
+async fn remove_unverified_file_paths(
+	location_id: location::id::Type,
+	data: &OldIndexerJobData,
+	run_metadata: &OldIndexerJobRunMetadata,
+	ctx: &WorkerContext,
+) -> Result<usize, IndexerError> {
+	let Some(exempt_directories) = run_metadata
+		.errors
+		.iter()
+		.map(|err| err.directory.clone())
+		.chain(data.excluded_location_roots.iter().cloned().map(Some))
+		.chain(run_metadata.skipped_mounts.iter().cloned().map(Some))
+		// Deferred files weren't verified but still exist, they're walked again later
+		.chain(
+			run_metadata
+				.deferred_recent
+				.iter()
+				.map(|path| path.parent().map(Path::to_path_buf)),
+		)
+		.collect::<Option<Vec<_>>>()
+	else {
+		warn!("Not removing file_paths the indexer didn't find again, as some errors aren't tied to a directory");
+		return Ok(0);
+	};
+
+	let to_remove = find_unverified_file_paths(
+		location_id,
+		&data.location_path,
+		data.scan_generation,
+		&exempt_directories,
+		&ctx.library.db,
+	)
+	.await?;
+	if to_remove.is_empty() {
+		return Ok(0);
+	}
+
+	let count = to_remove.len();
+	debug!("Removing {count} file_paths which the indexer didn't find again");
+
+	ctx.node
+		.thumbnailer
+		.remove_indexed_cas_ids(
+			to_remove
+				.iter()
+				.filter_map(|file_path| file_path.cas_id.clone())
+				.collect::<Vec<_>>(),
+			ctx.library.id,
+		)
+		.await;
+
+	remove_non_existing_file_paths(to_remove, &ctx.library.db, &ctx.library.sync).await?;
+
+	Ok(count)
+}
+
+/// How many entries the scan should find, falling back to discovering them as it goes when they
+/// can't be counted
+async fn expected_entries(count: impl Future<Output = Result<u64, IndexerError>>) -> u64 {
+	count.await.unwrap_or_else(|err| {
+		warn!("Failed to count the entries the indexer is expected to find: {err}");
+		0
+	})
+}
+
+/// Memory the entries took while waiting to be written, see [`WalkerMemory`]
+fn estimated_size(entries: &[WalkedEntry]) -> u64 {
+	entries.iter().map(WalkedEntry::estimated_size).sum()
+}
+
+/// The aggregated errors are kept in the run metadata, while the job report gets one error per
+/// aggregated entry, or every single one of them if `verbose` is set.
+fn report_errors(
+	errors: Vec<IndexerError>,
+	verbose: bool,
+) -> (Vec<AggregatedIndexerError>, JobRunErrors) {
+	let raw_errors = verbose.then(|| errors.iter().map(|e| format!("{e}")).collect::<Vec<_>>());
+	let aggregated = aggregate_errors(errors);
+	let errors = raw_errors
+		.unwrap_or_else(|| aggregated.iter().map(ToString::to_string).collect())
+		.into();
+
+	(aggregated, errors)
+}
+
 fn update_notifier_fn(ctx: &WorkerContext) -> impl FnMut(&Path, usize) + '_ {
 	move |path, total_entries| {
 		OldIndexerJobData::on_scan_progress(
@@ -626,3 +1459,509 @@ async fn update_directories_sizes(
 
 	Ok(())
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+	use crate::location::indexer::{
+		old_walk::tests::walk_with,
+		scan_records,
+		tests::{test_library, test_location},
+		MAX_SCAN_RECORDS,
+	};
+
+	use sd_utils::error::FileIOError;
+
+	use std::{cell::RefCell, collections::VecDeque, io};
+
+	use futures_concurrency::future::Join;
+	use tempfile::tempdir;
+	use tokio::fs;
+
+	/// Walks `root_path` a step at a time like the job does, starting with `init`'s walk and its save
+	/// step, adding the progress of each step to `progress` and calling `on_step` with it and how many
+	/// steps are left. Directories whose fingerprint is in `fingerprints` skip the database, like with
+	/// trusted fingerprints, and the ones of the walked directories are added to it.
+	async fn walk_in_steps(
+		root_path: &Path,
+		progress: &mut IndexerProgress,
+		fingerprints: &RefCell<HashMap<String, Vec<u8>>>,
+		mut on_step: impl FnMut(&IndexerProgress, usize),
+	) {
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+		let fingerprint_db_fetcher = |iso_file_path: IsolatedFilePathData<'static>| {
+			let fingerprint =
+				iso_file_path
+					.materialized_path_for_children()
+					.and_then(|materialized_path| {
+						fingerprints.borrow().get(&materialized_path).cloned()
+					});
+			async move { Ok(fingerprint) }
+		};
+		let store_fingerprints = |found: Vec<DirectoryFingerprint>| {
+			fingerprints.borrow_mut().extend(found.into_iter().map(
+				|DirectoryFingerprint {
+				     materialized_path,
+				     fingerprint,
+				 }| (materialized_path, fingerprint),
+			))
+		};
+		let memory = WalkerMemory::default();
+		let throttle = IoTokenBucket::default();
+
+		// Like `init`, stopping after the root's entries, with a save step for them
+		let WalkResult {
+			to_walk,
+			found_entries,
+			fingerprints: found_fingerprints,
+			..
+		} = walk(
+			root_path,
+			Uuid::new_v4(),
+			&[],
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			fingerprint_db_fetcher,
+			iso_file_path_factory,
+			&memory,
+			&throttle,
+			WalkOptions {
+				limit: 1,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		store_fingerprints(found_fingerprints);
+
+		let mut steps = VecDeque::from([None]);
+		steps.extend(to_walk.into_iter().map(Some));
+		progress.update(IndexerProgress {
+			queued: steps.len() as u64,
+			found_entries: found_entries as u64,
+			..Default::default()
+		});
+
+		while let Some(step) = steps.pop_front() {
+			let mut step_progress = IndexerProgress {
+				completed: 1,
+				..Default::default()
+			};
+
+			if let Some(to_walk_entry) = step {
+				let WalkResult {
+					to_walk,
+					spawned_children,
+					found_entries,
+					fingerprints: found_fingerprints,
+					..
+				} = keep_walking(
+					&to_walk_entry,
+					&[],
+					|_, _| {},
+					|_| async { Ok(vec![]) },
+					|_, _| async { Ok(vec![]) },
+					fingerprint_db_fetcher,
+					iso_file_path_factory,
+					&memory,
+					&throttle,
+					false,
+					&[],
+					ComparisonCaps::default(),
+					None,
+					false,
+					None,
+				)
+				.await
+				.unwrap();
+				store_fingerprints(found_fingerprints);
+
+				assert_eq!(spawned_children, to_walk.len());
+				step_progress.spawned = spawned_children as u64;
+				step_progress.found_entries = found_entries as u64;
+				steps.extend(to_walk.into_iter().map(Some));
+			}
+
+			progress.update(step_progress);
+			on_step(progress, steps.len());
+		}
+	}
+
+	#[tokio::test]
+	async fn progress_ends_with_every_step_known_and_done() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		for dir in ["docs", "photos/2023", "photos/2024/summer"] {
+			fs::create_dir_all(root_path.join(dir)).await.unwrap();
+		}
+		for file in [
+			"readme.md",
+			"docs/a.txt",
+			"docs/b.txt",
+			"photos/2023/1.png",
+			"photos/2024/2.png",
+			"photos/2024/summer/3.png",
+		] {
+			fs::File::create(root_path.join(file)).await.unwrap();
+		}
+
+		let mut progress = IndexerProgress::default();
+		let mut percentages = vec![];
+		walk_in_steps(
+			root_path,
+			&mut progress,
+			&RefCell::default(),
+			|progress, steps_left| {
+				assert!(progress.completed <= progress.total_known());
+				assert_eq!(
+					progress.total_known(),
+					progress.completed + steps_left as u64
+				);
+				percentages.push(progress.percentage());
+			},
+		)
+		.await;
+
+		assert_eq!(progress.completed, progress.total_known());
+		assert_eq!(percentages.last(), Some(&100));
+		assert_eq!(progress.found_entries, 11);
+	}
+
+	#[tokio::test]
+	async fn walk_steps_write_what_they_find_before_walking_deeper() {
+		let (node, library, _dir) = test_library().await;
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		// Enough entries for `init` to stop after the root, leaving the rest to walk steps
+		for i in 0..INITIAL_WALK_LIMIT {
+			fs::File::create(root_path.join(format!("{i}.txt")))
+				.await
+				.unwrap();
+		}
+		for dir in ["docs", "photos/2023", "photos/2024/summer"] {
+			fs::create_dir_all(root_path.join(dir)).await.unwrap();
+		}
+		for file in [
+			"docs/a.txt",
+			"photos/2023/1.png",
+			"photos/2024/2.png",
+			"photos/2024/summer/3.png",
+		] {
+			fs::File::create(root_path.join(file)).await.unwrap();
+		}
+
+		let location = test_location(&library, root_path).await;
+		let location_id = location.id;
+		let job = OldIndexerJobInit {
+			location,
+			sub_path: None,
+			verbose_errors: false,
+			walker_memory_budget: None,
+			trust_fingerprints: false,
+			extension_statistics: false,
+			error_policy: ErrorPolicy::default(),
+			defer_recently_modified: None,
+			max_walk_depth: None,
+			exclude_cloud_placeholders: false,
+			track_discovery_order: false,
+			cross_filesystems: true,
+		};
+		let indexed = || async {
+			library
+				.db
+				.file_path()
+				.find_many(vec![file_path::location_id::equals(Some(location_id))])
+				.exec()
+				.await
+				.unwrap()
+		};
+
+		// Like the worker runs the job, a step at a time
+		let (ctx, _events) = WorkerContext::for_tests(Arc::clone(&library), Arc::clone(&node));
+		let mut data = None;
+		let (mut run_metadata, mut steps) = job.init(&ctx, &mut data).await.unwrap().into_parts();
+		let data = data.unwrap();
+
+		let mut walk_steps = 0;
+		let mut step_number = 0;
+		while let Some(step) = steps.pop_front() {
+			let before = indexed().await;
+			let (more_steps, more_metadata) = job
+				.execute_step(
+					&ctx,
+					CurrentStep {
+						step: &step,
+						step_number,
+					},
+					&data,
+					&run_metadata,
+				)
+				.await
+				.unwrap()
+				.into_parts();
+			step_number += 1;
+			if let Some(more_metadata) = more_metadata {
+				run_metadata.update(more_metadata);
+			}
+
+			if matches!(step, OldIndexerJobStepInput::Walk(_)) {
+				walk_steps += 1;
+
+				// The step wrote what it found itself, under directories which were written before it
+				let known_directories = before
+					.iter()
+					.filter(|file_path| file_path.is_dir == Some(true))
+					.map(|file_path| {
+						format!(
+							"{}{}/",
+							file_path.materialized_path.as_deref().unwrap(),
+							file_path.name.as_deref().unwrap()
+						)
+					})
+					.collect::<HashSet<_>>();
+				let after = indexed().await;
+				assert!(after.len() > before.len());
+				for file_path in after
+					.iter()
+					.filter(|file_path| before.iter().all(|known| known.id != file_path.id))
+				{
+					let parent = file_path.materialized_path.as_deref().unwrap();
+					assert!(
+						parent == "/" || known_directories.contains(parent),
+						"{parent}{:?} was written before its directory",
+						file_path.name
+					);
+				}
+
+				// Only the directories below are left to later steps
+				assert!(more_steps
+					.iter()
+					.all(|step| matches!(step, OldIndexerJobStepInput::Walk(_))));
+			}
+
+			steps.extend(more_steps);
+		}
+
+		assert_eq!(walk_steps, 5);
+		let total = INITIAL_WALK_LIMIT + 9;
+		assert_eq!(run_metadata.indexed_count, total);
+		assert_eq!(indexed().await.len() as u64, total);
+
+		drop(ctx);
+		node.shutdown().await;
+	}
+
+	#[test]
+	fn too_many_errors_pause_the_job_once() {
+		let policy = ErrorPolicy {
+			max_noncritical_count: Some(10),
+			..Default::default()
+		};
+
+		// Like the job's run phase, with each walk failing on more entries than the one before
+		let mut run_metadata = OldIndexerJobRunMetadata::default();
+		let mut breaches = vec![];
+		for step in 0..8 {
+			let mut step_metadata = OldIndexerJobRunMetadata {
+				errors: aggregate_errors((0..step).map(|i| {
+					IndexerError::FileIO(FileIOError::from((
+						Path::new(&format!("/mnt/share/{step}/{i}")),
+						io::Error::from(io::ErrorKind::TimedOut),
+					)))
+				})),
+				progress: IndexerProgress {
+					completed: 1,
+					found_entries: 20,
+					..Default::default()
+				},
+				..Default::default()
+			};
+
+			if let Some(breach) = run_metadata.error_policy_breach(&policy, &step_metadata) {
+				step_metadata.error_policy_breached = true;
+				breaches.push((step, breach));
+			}
+			run_metadata.update(step_metadata);
+		}
+
+		// 0 + 1 + 2 + 3 + 4 errors are within the policy, the 5 more of the sixth step aren't
+		assert!(matches!(
+			breaches.as_slice(),
+			[(5, ErrorPolicyBreach { errors: 15, found_entries: 120, dominant })]
+				if dominant.kind == "TimedOut"
+					&& dominant.sample_paths.first() == Some(&PathBuf::from("/mnt/share/1/0"))
+		));
+		// Once resumed, the job runs the steps left without pausing again
+		assert_eq!(run_metadata.progress.completed, 8);
+		assert!(run_metadata.error_policy_breached);
+	}
+
+	#[tokio::test]
+	async fn rescans_report_against_the_expected_entries() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		fs::create_dir_all(root_path.join("photos/2024"))
+			.await
+			.unwrap();
+		for file in ["readme.md", "photos/1.png", "photos/2024/2.png"] {
+			fs::File::create(root_path.join(file)).await.unwrap();
+		}
+		let fingerprints = RefCell::default();
+
+		// A first scan, or one where counting failed, discovers its total while walking
+		let mut first_scan = IndexerProgress {
+			expected_entries: expected_entries(async {
+				Err(IndexerError::SubPathNotFound(Path::new("gone").into()))
+			})
+			.await,
+			..Default::default()
+		};
+		walk_in_steps(root_path, &mut first_scan, &fingerprints, |progress, _| {
+			assert!(matches!(
+				progress.task_counts(),
+				[ScanProgress::TaskCount(total), ScanProgress::CompletedTasks(completed)]
+					if total == progress.total_known() as usize
+						&& completed == progress.completed as usize
+			));
+		})
+		.await;
+		assert_eq!(first_scan.found_entries, 5);
+
+		// A re-scan expects what the first one stored. Nothing changed, so every directory skips the
+		// database, but their entries are still found.
+		let mut rescan = IndexerProgress {
+			expected_entries: expected_entries(async { Ok(5) }).await,
+			..Default::default()
+		};
+		walk_in_steps(root_path, &mut rescan, &fingerprints, |progress, _| {
+			let found = progress.found_entries as usize;
+			assert!(matches!(
+				progress.task_counts(),
+				[ScanProgress::TaskCount(5), ScanProgress::CompletedTasks(completed)]
+					if completed == found
+			));
+		})
+		.await;
+		assert_eq!(rescan.message(), "Discovered 5 items, 5 expected");
+
+		// New files are found on top of the expected ones
+		fs::File::create(root_path.join("photos/2024/3.png"))
+			.await
+			.unwrap();
+		let mut grown = IndexerProgress {
+			expected_entries: 5,
+			..Default::default()
+		};
+		walk_in_steps(root_path, &mut grown, &fingerprints, |_, _| {}).await;
+		assert_eq!(grown.message(), "Discovered 6 items, 5 expected");
+	}
+
+	#[tokio::test]
+	async fn each_scan_leaves_a_summary_of_how_it_went() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		fs::create_dir_all(root_path.join("docs")).await.unwrap();
+		fs::write(root_path.join("readme.md"), b"hello")
+			.await
+			.unwrap();
+		fs::write(root_path.join("docs/a.txt"), b"spacedrive")
+			.await
+			.unwrap();
+
+		// A whole scan in a single walk, as the records only keep what the job adds up
+		let scan = || async move {
+			let data = OldIndexerJobData {
+				location_path: root_path.to_path_buf(),
+				indexed_path: root_path.to_path_buf(),
+				indexer_rules: vec![],
+				scan_started_at: Utc::now(),
+				scan_generation: 1,
+				walker_memory_used: Arc::default(),
+				excluded_location_roots: vec![],
+				in_flight_scan: None,
+				comparison_caps: ComparisonCaps::default(),
+				discovery_seq: AtomicU64::default(),
+				volume_walk: None,
+				held_back_removals: false,
+			};
+			let WalkResult {
+				walked,
+				paths_and_sizes,
+				found_entries,
+				vanished,
+				..
+			} = walk_with(root_path, &[], WalkOptions::default())
+				.await
+				.unwrap();
+
+			let run_metadata = OldIndexerJobRunMetadata {
+				indexed_count: walked.count() as u64,
+				paths_and_sizes,
+				vanished,
+				progress: IndexerProgress {
+					found_entries: found_entries as u64,
+					..Default::default()
+				},
+				..Default::default()
+			};
+			let summary = run_metadata.scan_summary(&data, 0, Utc::now());
+
+			// Stored as JSON, the bytes as a string for the frontend
+			serde_json::from_slice::<ScanSummary>(&serde_json::to_vec(&summary).unwrap()).unwrap()
+		};
+
+		let (node, library, _dir) = test_library().await;
+		let db = &library.db;
+		let location_id = test_location(&library, root_path).await.id;
+		let record = |summary: ScanSummary| async move {
+			let generation = next_scan_generation(location_id, db).await.unwrap();
+			record_scan(location_id, generation, &summary, db)
+				.await
+				.unwrap();
+		};
+
+		record(scan().await).await;
+		fs::write(root_path.join("docs/b.txt"), b"more")
+			.await
+			.unwrap();
+		record(scan().await).await;
+
+		let records = scan_records(location_id, db).await.unwrap();
+		let [second, first] = &records[..] else {
+			panic!("expected both scans, got {records:#?}");
+		};
+		assert_eq!(first.generation + 1, second.generation);
+		let [first, second] = [&first.summary, &second.summary];
+		assert!(first.started_at <= first.finished_at);
+		assert!(first.finished_at <= second.started_at);
+
+		assert_eq!(first.sub_path, None);
+		assert_eq!((first.found_entries, first.indexed), (3, 3));
+		assert_eq!((second.found_entries, second.indexed), (4, 4));
+		// Directories count for their own size as well
+		assert!(first.bytes_indexed >= 15);
+		assert!(second.bytes_indexed >= first.bytes_indexed + 4);
+		assert!(first.errors.is_empty() && second.errors.is_empty());
+
+		// Scans finishing at the same time each keep their record, and only the last ones are kept
+		let second = second.clone();
+		(0..MAX_SCAN_RECORDS)
+			.map(|_| record(second.clone()))
+			.collect::<Vec<_>>()
+			.join()
+			.await;
+		let records = scan_records(location_id, db).await.unwrap();
+		assert_eq!(records.len(), MAX_SCAN_RECORDS as usize);
+		assert!(records
+			.iter()
+			.tuple_windows()
+			.all(|(newer, older)| newer.generation > older.generation));
+		assert!(records.iter().all(|record| record.summary == second));
+
+		node.shutdown().await;
+	}
+}
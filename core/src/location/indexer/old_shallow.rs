@@ -20,7 +20,7 @@ use sd_utils::db::maybe_missing;
 use std::{
 	collections::HashSet,
 	path::{Path, PathBuf},
-	sync::Arc,
+	sync::{atomic::AtomicU64, Arc},
 };
 
 use futures::future::join_all;
@@ -28,9 +28,13 @@ use itertools::Itertools;
 use tracing::{debug, error};
 
 use super::{
-	execute_indexer_save_step, iso_file_path_factory, location_with_indexer_rules,
-	old_walk::walk_single_dir, remove_non_existing_file_paths, rules::IndexerRule, IndexerError,
-	OldIndexerJobSaveStep,
+	count_file_paths_in_location, current_scan_generation, execute_indexer_save_step,
+	hold_back_removals, iso_file_path_factory, location_with_indexer_rules, nested_location_roots,
+	old_walk::{walk_single_dir, ComparisonCaps, SingleDirWalk},
+	remove_non_existing_file_paths, remove_subtrees,
+	rules::IndexerRule,
+	should_hold_back_removals, update_directories_child_counts, warn_pinned_files_missing,
+	IndexerError, OldIndexerJobSaveStep,
 };
 
 /// BATCH_SIZE is the number of files to index at each step, writing the chunk of files metadata in the database.
@@ -47,13 +51,9 @@ pub async fn old_shallow(
 
 	let db = library.db.clone();
 	let sync = &library.sync;
+	let _in_flight_scan = library.in_flight_updates.begin_scan(location_id);
 
-	let indexer_rules = location
-		.indexer_rules
-		.iter()
-		.map(|rule| IndexerRule::try_from(&rule.indexer_rule))
-		.collect::<Result<Vec<_>, _>>()
-		.map_err(IndexerError::from)?;
+	let indexer_rules = IndexerRule::for_location(location).map_err(IndexerError::from)?;
 
 	let (add_root, to_walk_path) = if sub_path != Path::new("") && sub_path != Path::new("/") {
 		let full_path = ensure_sub_path_is_in_location(&location_path, &sub_path)
@@ -76,17 +76,68 @@ pub async fn old_shallow(
 		(false, location_path.to_path_buf())
 	};
 
-	let (walked, to_update, to_remove, errors, _s) = {
-		walk_single_dir(
-			&to_walk_path,
-			&indexer_rules,
-			|_, _| {},
-			file_paths_db_fetcher_fn!(&db),
-			to_remove_db_fetcher_fn!(location_id, &db),
-			iso_file_path_factory(location_id, location_path),
-			add_root,
-		)
-		.await?
+	let discovery_seq = location
+		.track_discovery_order
+		.unwrap_or(false)
+		.then(AtomicU64::default);
+	let excluded_location_roots = nested_location_roots(location_id, location_path, &db).await?;
+	if excluded_location_roots
+		.iter()
+		.any(|root| to_walk_path.starts_with(root))
+	{
+		debug!(
+			"{} belongs to another location nested in this one, not scanning it",
+			to_walk_path.display()
+		);
+		return Ok(());
+	}
+
+	let (_, comparison_caps) = ComparisonCaps::detect(&to_walk_path);
+	let SingleDirWalk {
+		walked,
+		to_update,
+		to_remove,
+		to_remove_subtrees,
+		errors,
+		found_entries,
+		child_counts,
+		..
+	} = walk_single_dir(
+		&to_walk_path,
+		location_path,
+		&indexer_rules,
+		|_, _| {},
+		file_paths_db_fetcher_fn!(&db),
+		to_remove_db_fetcher_fn!(location_id, &db, comparison_caps.case_insensitive),
+		iso_file_path_factory(location_id, location_path),
+		add_root,
+		location.exclude_cloud_placeholders.unwrap_or(false),
+		location.cross_filesystems.unwrap_or(true),
+		&excluded_location_roots,
+		comparison_caps,
+		discovery_seq.as_ref(),
+	)
+	.await?;
+
+	// Like a full scan, nothing is removed from a root which looks like its drive isn't connected
+	let (to_remove, to_remove_subtrees) = if to_walk_path == location_path && found_entries == 0 {
+		let expected_entries =
+			count_file_paths_in_location(location_id, location_path, location_path, &db).await?;
+		if should_hold_back_removals(location.attention, 0, expected_entries) {
+			hold_back_removals(
+				location_id,
+				location.name.as_deref(),
+				expected_entries,
+				node,
+				library,
+			)
+			.await?;
+			(vec![], vec![])
+		} else {
+			(to_remove, to_remove_subtrees)
+		}
+	} else {
+		(to_remove, to_remove_subtrees)
 	};
 
 	let to_remove_count = to_remove.len();
@@ -101,10 +152,17 @@ pub async fn old_shallow(
 		)
 		.await;
 
+	warn_pinned_files_missing(&errors, node).await;
 	errors.into_iter().for_each(|e| error!("{e}"));
 
 	// TODO pass these uuids to sync system
 	remove_non_existing_file_paths(to_remove, &db, sync).await?;
+	let to_remove_count = to_remove_count
+		+ remove_subtrees(location_id, &to_remove_subtrees, &db, sync).await? as usize;
+
+	// Otherwise a full scan which started before this one would remove what it wrote to directories it
+	// already walked
+	let scan_generation = Some(current_scan_generation(location_id, &db).await?);
 
 	let mut new_directories_to_scan = HashSet::new();
 
@@ -118,8 +176,10 @@ pub async fn old_shallow(
 			let walked = chunk.collect::<Vec<_>>();
 			to_create_count += walked.len();
 
+			// The contents of other locations' roots are left to them
 			walked
 				.iter()
+				.filter(|walked_entry| !walked_entry.is_location_boundary)
 				.filter_map(|walked_entry| {
 					walked_entry.iso_file_path.materialized_path_for_children()
 				})
@@ -130,6 +190,7 @@ pub async fn old_shallow(
 			OldIndexerJobSaveStep {
 				chunk_idx: i,
 				walked,
+				scan_generation,
 			}
 		})
 		.collect::<Vec<_>>();
@@ -163,6 +224,7 @@ pub async fn old_shallow(
 			OldIndexerJobUpdateStep {
 				chunk_idx: i,
 				to_update,
+				scan_generation,
 			}
 		})
 		.collect::<Vec<_>>();
@@ -171,6 +233,10 @@ pub async fn old_shallow(
 		execute_indexer_update_step(&step, library).await?;
 	}
 
+	update_directories_child_counts(&child_counts, location_id, location_path, &db).await?;
+
+	library.indexing_updates.finish(location_id);
+
 	debug!(
 		"Walker at shallow indexer found: \
 		To create: {to_create_count}; To update: {to_update_count}; To remove: {to_remove_count};"
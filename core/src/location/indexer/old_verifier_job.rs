@@ -0,0 +1,648 @@
+use crate::{
+	invalidate_query,
+	library::Library,
+	old_job::{
+		CurrentStep, JobError, JobInitOutput, JobReportUpdate, JobResult, JobRunErrors,
+		JobRunMetadata, JobStepOutput, StatefulJob, WorkerContext,
+	},
+};
+
+use sd_file_path_helper::{
+	file_path_pub_and_cas_ids, file_path_walker, full_path_on_disk, path_is_placeholder,
+	FilePathMetadata, IsolatedFilePathData,
+};
+use sd_prisma::prisma::{file_path, location, PrismaClient, SortOrder};
+use sd_utils::{db::maybe_missing, error::FileIOError, from_bytes_to_uuid};
+
+use std::{
+	hash::{Hash, Hasher},
+	io,
+	path::{Path, PathBuf},
+};
+
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::fs;
+use tracing::info;
+
+use super::{
+	execute_indexer_update_step, hold_back_removals,
+	old_walk::{file_path_has_changed, ComparisonCaps, WalkedEntry},
+	remove_non_existing_file_paths, should_hold_back_removals, warn_pinned_files_missing,
+	IndexerError, OldIndexerJobUpdateStep,
+};
+
+/// How many file_paths are checked per step, the job can be paused between them
+const PAGE_SIZE: usize = 1000;
+/// How many file_paths are checked on disk at once
+const MAX_CONCURRENT_CHECKS: usize = 32;
+/// We keep the counts of everything found, but only this many of their paths for the report
+const MAX_REPORTED_PATHS: usize = 100;
+
+file_path::select!(file_path_for_verifier {
+	id
+	pub_id
+	cas_id
+	location_id
+	object_id
+	materialized_path
+	is_dir
+	name
+	extension
+	date_modified
+	inode
+	size_in_bytes_bytes
+	hidden
+	pinned
+	is_placeholder
+	is_location_boundary
+	name_bytes
+});
+
+/// `OldLocationVerifierJobInit` checks that the file_paths of a location are still on disk and unchanged,
+/// without walking the location or applying its indexer rules again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OldLocationVerifierJobInit {
+	pub location: location::Data,
+	/// Update changed file_paths and remove missing ones, like the indexer does, instead of only reporting them.
+	/// Missing pinned file_paths are only ever reported
+	#[serde(default)]
+	pub fix: bool,
+}
+
+impl Hash for OldLocationVerifierJobInit {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.location.id.hash(state);
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OldLocationVerifierJobData {
+	location_path: PathBuf,
+	#[serde(default)]
+	comparison_caps: ComparisonCaps,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct OldLocationVerifierJobRunMetadata {
+	cursor: file_path::id::Type,
+	total_file_paths: usize,
+	checked_count: usize,
+	missing_count: usize,
+	changed_count: usize,
+	permission_denied_count: usize,
+	updated_count: usize,
+	removed_count: usize,
+	missing_paths: Vec<PathBuf>,
+	changed_paths: Vec<PathBuf>,
+	permission_denied_paths: Vec<PathBuf>,
+}
+
+impl JobRunMetadata for OldLocationVerifierJobRunMetadata {
+	fn update(&mut self, new_data: Self) {
+		self.cursor = new_data.cursor;
+		self.total_file_paths += new_data.total_file_paths;
+		self.checked_count += new_data.checked_count;
+		self.missing_count += new_data.missing_count;
+		self.changed_count += new_data.changed_count;
+		self.permission_denied_count += new_data.permission_denied_count;
+		self.updated_count += new_data.updated_count;
+		self.removed_count += new_data.removed_count;
+
+		for (paths, new_paths) in [
+			(&mut self.missing_paths, new_data.missing_paths),
+			(&mut self.changed_paths, new_data.changed_paths),
+			(
+				&mut self.permission_denied_paths,
+				new_data.permission_denied_paths,
+			),
+		] {
+			let remaining = MAX_REPORTED_PATHS.saturating_sub(paths.len());
+			paths.extend(new_paths.into_iter().take(remaining));
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl StatefulJob for OldLocationVerifierJobInit {
+	type Data = OldLocationVerifierJobData;
+	type Step = ();
+	type RunMetadata = OldLocationVerifierJobRunMetadata;
+
+	const NAME: &'static str = "location_verifier";
+	const IS_BATCHED: bool = true;
+
+	fn target_location(&self) -> location::id::Type {
+		self.location.id
+	}
+
+	async fn init(
+		&self,
+		ctx: &WorkerContext,
+		data: &mut Option<Self::Data>,
+	) -> Result<JobInitOutput<Self::RunMetadata, Self::Step>, JobError> {
+		let init = self;
+		let Library { db, .. } = &*ctx.library;
+
+		let location_path =
+			maybe_missing(&init.location.path, "location.path").map(PathBuf::from)?;
+
+		let total_file_paths = db
+			.file_path()
+			.count(vec![file_path::location_id::equals(Some(init.location.id))])
+			.exec()
+			.await? as usize;
+
+		let (_, comparison_caps) = ComparisonCaps::detect(&location_path);
+		*data = Some(OldLocationVerifierJobData {
+			location_path,
+			comparison_caps,
+		});
+
+		if total_file_paths == 0 {
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "Location has no file paths to verify".to_string(),
+			});
+		}
+
+		// Every file_path of a location whose drive isn't mounted would be found missing
+		let mut root_entries = match fs::read_dir(&location_path).await {
+			Ok(root_entries) => root_entries,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				ctx.node
+					.locations
+					.remove_online(&from_bytes_to_uuid(&init.location.pub_id))
+					.await;
+
+				return Err(JobError::EarlyFinish {
+					name: <Self as StatefulJob>::NAME.to_string(),
+					reason: "Location root is missing, its drive may not be mounted".to_string(),
+				});
+			}
+			Err(e) => return Err(IndexerError::from(FileIOError::from((&location_path, e))).into()),
+		};
+		let root_is_empty = root_entries
+			.next_entry()
+			.await
+			.map_err(|e| IndexerError::from(FileIOError::from((&location_path, e))))?
+			.is_none();
+		if root_is_empty
+			&& should_hold_back_removals(init.location.attention, 0, total_file_paths as u64)
+		{
+			hold_back_removals(
+				init.location.id,
+				init.location.name.as_deref(),
+				total_file_paths as u64,
+				&ctx.node,
+				&ctx.library,
+			)
+			.await?;
+
+			return Err(JobError::EarlyFinish {
+				name: <Self as StatefulJob>::NAME.to_string(),
+				reason: "Location root is empty, its drive may not be connected".to_string(),
+			});
+		}
+
+		ctx.progress(vec![
+			JobReportUpdate::TaskCount(total_file_paths),
+			JobReportUpdate::Message(format!("Verifying {total_file_paths} file paths")),
+		]);
+
+		let task_count = (total_file_paths as f64 / PAGE_SIZE as f64).ceil() as usize;
+
+		Ok((
+			OldLocationVerifierJobRunMetadata {
+				total_file_paths,
+				..Default::default()
+			},
+			vec![(); task_count],
+		)
+			.into())
+	}
+
+	async fn execute_step(
+		&self,
+		ctx: &WorkerContext,
+		CurrentStep { step_number, .. }: CurrentStep<'_, Self::Step>,
+		data: &Self::Data,
+		run_metadata: &Self::RunMetadata,
+	) -> Result<JobStepOutput<Self::Step, Self::RunMetadata>, JobError> {
+		let init = self;
+		let library = &ctx.library;
+
+		let file_paths = file_paths_page(
+			init.location.id,
+			run_metadata.cursor,
+			PAGE_SIZE as i64,
+			&library.db,
+		)
+		.await?;
+
+		let Some(last_file_path) = file_paths.last() else {
+			// Rows were removed since we counted them, nothing left to verify
+			return Ok(None.into());
+		};
+
+		let mut new_metadata = Self::RunMetadata {
+			cursor: last_file_path.id,
+			checked_count: file_paths.len(),
+			..Default::default()
+		};
+
+		let Verification {
+			missing,
+			changed,
+			permission_denied,
+			errors,
+		} = verify_file_paths(&data.location_path, data.comparison_caps, file_paths).await;
+
+		new_metadata.missing_count = missing.len();
+		new_metadata.changed_count = changed.len();
+		new_metadata.permission_denied_count = permission_denied.len();
+		new_metadata.missing_paths = missing
+			.iter()
+			.map(|(path, _)| path.clone())
+			.take(MAX_REPORTED_PATHS)
+			.collect();
+		new_metadata.changed_paths = changed
+			.iter()
+			.map(|(path, _)| path.clone())
+			.take(MAX_REPORTED_PATHS)
+			.collect();
+		new_metadata.permission_denied_paths = permission_denied
+			.into_iter()
+			.take(MAX_REPORTED_PATHS)
+			.collect();
+
+		if init.fix {
+			if !changed.is_empty() {
+				new_metadata.updated_count = execute_indexer_update_step(
+					&OldIndexerJobUpdateStep {
+						chunk_idx: step_number,
+						to_update: changed.into_iter().map(|(_, entry)| entry).collect(),
+						scan_generation: None,
+					},
+					library,
+				)
+				.await? as usize;
+			}
+
+			if !missing.is_empty() {
+				let to_remove = missing
+					.into_iter()
+					.map(|(_, file_path)| file_path)
+					.collect::<Vec<_>>();
+
+				ctx.node
+					.thumbnailer
+					.remove_indexed_cas_ids(
+						to_remove
+							.iter()
+							.filter_map(|file_path| file_path.cas_id.clone())
+							.collect(),
+						library.id,
+					)
+					.await;
+
+				new_metadata.removed_count =
+					remove_non_existing_file_paths(to_remove, &library.db, &library.sync).await?
+						as usize;
+			}
+		}
+
+		warn_pinned_files_missing(&errors, &ctx.node).await;
+
+		ctx.progress(vec![
+			JobReportUpdate::CompletedTaskCount(
+				run_metadata.checked_count + new_metadata.checked_count,
+			),
+			JobReportUpdate::Message(format!(
+				"Verified {} of {} file paths",
+				run_metadata.checked_count + new_metadata.checked_count,
+				run_metadata.total_file_paths
+			)),
+		]);
+
+		Ok((
+			new_metadata,
+			JobRunErrors(errors.into_iter().map(|e| e.to_string()).collect()),
+		)
+			.into())
+	}
+
+	async fn finalize(
+		&self,
+		ctx: &WorkerContext,
+		_data: &Option<Self::Data>,
+		run_metadata: &Self::RunMetadata,
+	) -> JobResult {
+		let init = self;
+		info!(
+			"Verified {} file paths of location {}: {} missing, {} changed, {} without permission",
+			run_metadata.checked_count,
+			init.location.id,
+			run_metadata.missing_count,
+			run_metadata.changed_count,
+			run_metadata.permission_denied_count
+		);
+
+		if run_metadata.updated_count > 0 || run_metadata.removed_count > 0 {
+			invalidate_query!(ctx.library, "search.paths");
+		}
+
+		Ok(Some(json!({ "init": init, "run_metadata": run_metadata })))
+	}
+}
+
+/// A page of a location's file_paths, ordered by id and starting after `cursor`.
+async fn file_paths_page(
+	location_id: location::id::Type,
+	cursor: file_path::id::Type,
+	limit: i64,
+	db: &PrismaClient,
+) -> Result<Vec<file_path_for_verifier::Data>, IndexerError> {
+	db.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::id::gt(cursor),
+		])
+		.order_by(file_path::id::order(SortOrder::Asc))
+		.take(limit)
+		.select(file_path_for_verifier::select())
+		.exec()
+		.await
+		.map_err(Into::into)
+}
+
+#[derive(Debug, Default)]
+struct Verification {
+	missing: Vec<(PathBuf, file_path_pub_and_cas_ids::Data)>,
+	changed: Vec<(PathBuf, WalkedEntry)>,
+	permission_denied: Vec<PathBuf>,
+	errors: Vec<IndexerError>,
+}
+
+enum Verified {
+	Unchanged,
+	Missing(PathBuf, file_path_pub_and_cas_ids::Data),
+	Changed(PathBuf, WalkedEntry),
+	PermissionDenied(PathBuf),
+}
+
+async fn verify_file_paths(
+	location_path: &Path,
+	comparison_caps: ComparisonCaps,
+	file_paths: Vec<file_path_for_verifier::Data>,
+) -> Verification {
+	stream::iter(file_paths)
+		.map(|file_path| verify_file_path(location_path, comparison_caps, file_path))
+		.buffer_unordered(MAX_CONCURRENT_CHECKS)
+		.fold(
+			Verification::default(),
+			|mut verification, verified| async {
+				match verified {
+					Ok(Verified::Unchanged) => {}
+					Ok(Verified::Missing(path, file_path)) => {
+						verification.missing.push((path, file_path))
+					}
+					Ok(Verified::Changed(path, entry)) => verification.changed.push((path, entry)),
+					Ok(Verified::PermissionDenied(path)) => {
+						verification.permission_denied.push(path)
+					}
+					Err(e) => verification.errors.push(e),
+				}
+				verification
+			},
+		)
+		.await
+}
+
+async fn verify_file_path(
+	location_path: &Path,
+	comparison_caps: ComparisonCaps,
+	file_path: file_path_for_verifier::Data,
+) -> Result<Verified, IndexerError> {
+	let file_path_for_verifier::Data {
+		id,
+		pub_id,
+		cas_id,
+		location_id,
+		object_id,
+		materialized_path,
+		is_dir,
+		name,
+		extension,
+		date_modified,
+		inode,
+		size_in_bytes_bytes,
+		hidden,
+		pinned,
+		is_placeholder: was_placeholder,
+		is_location_boundary,
+		name_bytes,
+	} = file_path;
+
+	let walker_data = file_path_walker::Data {
+		pub_id: pub_id.clone(),
+		location_id,
+		object_id,
+		materialized_path,
+		is_dir,
+		name,
+		extension,
+		date_modified,
+		inode,
+		size_in_bytes_bytes,
+		hidden,
+		is_placeholder: was_placeholder,
+		is_location_boundary,
+	};
+
+	let iso_file_path = IsolatedFilePathData::try_from(walker_data.clone())?;
+	let path = full_path_on_disk(location_path, &iso_file_path, name_bytes.as_deref());
+
+	let metadata = match fs::symlink_metadata(&path).await {
+		Ok(metadata) => metadata,
+		// Reported as a warning instead, so it isn't removed even when fixing
+		Err(e) if e.kind() == io::ErrorKind::NotFound && pinned => {
+			return Err(IndexerError::PinnedFileMissing { path })
+		}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {
+			return Ok(Verified::Missing(
+				path,
+				file_path_pub_and_cas_ids::Data { id, pub_id, cas_id },
+			))
+		}
+		Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+			return Ok(Verified::PermissionDenied(path))
+		}
+		Err(e) => return Err(FileIOError::from((path, e)).into()),
+	};
+
+	let is_placeholder = path_is_placeholder(&path, &metadata);
+	let metadata = FilePathMetadata::from_path(&path, &metadata).await?;
+
+	if file_path_has_changed(
+		&walker_data,
+		&metadata,
+		iso_file_path.to_parts().is_dir,
+		comparison_caps,
+	) || was_placeholder.unwrap_or_default() != is_placeholder
+	{
+		Ok(Verified::Changed(
+			path,
+			WalkedEntry {
+				pub_id: from_bytes_to_uuid(&pub_id),
+				maybe_object_id: object_id,
+				iso_file_path,
+				metadata,
+				name_bytes,
+				is_location_boundary: is_location_boundary.unwrap_or_default(),
+				is_placeholder,
+				discovery_seq: None,
+				crossed_mount: false,
+			},
+		))
+	} else {
+		Ok(Verified::Unchanged)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sd_utils::db::inode_to_db;
+
+	use std::time::Duration;
+
+	use itertools::Itertools;
+	use tempfile::tempdir;
+
+	use super::*;
+
+	async fn indexed(
+		location_path: &Path,
+		id: file_path::id::Type,
+		path: &Path,
+	) -> file_path_for_verifier::Data {
+		let iso_file_path = IsolatedFilePathData::new(0, location_path, path, false).unwrap();
+		let parts = iso_file_path.to_parts();
+		let metadata = FilePathMetadata::from_path(path, &fs::metadata(path).await.unwrap())
+			.await
+			.unwrap();
+
+		file_path_for_verifier::Data {
+			id,
+			pub_id: sd_utils::uuid_to_bytes(uuid::Uuid::new_v4()),
+			cas_id: None,
+			location_id: Some(0),
+			object_id: None,
+			materialized_path: Some(parts.materialized_path.to_string()),
+			is_dir: Some(false),
+			name: Some(parts.name.to_string()),
+			extension: Some(parts.extension.to_string()),
+			date_modified: Some(metadata.modified_at.into()),
+			inode: Some(inode_to_db(metadata.inode)),
+			size_in_bytes_bytes: Some(metadata.size_in_bytes.to_be_bytes().to_vec()),
+			hidden: Some(metadata.hidden),
+			pinned: false,
+			is_placeholder: None,
+			is_location_boundary: None,
+			name_bytes: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn finds_missing_and_changed_files() {
+		let location = tempdir().unwrap();
+		let location_path = location.path();
+
+		let deleted = location_path.join("deleted.txt");
+		let touched = location_path.join("dir/touched.txt");
+		let resized = location_path.join("dir/resized.txt");
+		let untouched = location_path.join("dir/untouched.txt");
+
+		fs::create_dir(location_path.join("dir")).await.unwrap();
+		for path in [&deleted, &touched, &resized, &untouched] {
+			fs::write(path, b"hello").await.unwrap();
+		}
+
+		let mut file_paths = vec![];
+		for (id, path) in [&deleted, &touched, &resized, &untouched]
+			.into_iter()
+			.enumerate()
+		{
+			file_paths.push(indexed(location_path, id as i32, path).await);
+		}
+
+		fs::remove_file(&deleted).await.unwrap();
+		// Making sure the modification date moves past the precision we compare it with
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		fs::write(&touched, b"hello world").await.unwrap();
+
+		// Rewritten without its modification date moving
+		let modified_at = fs::metadata(&resized).await.unwrap().modified().unwrap();
+		fs::write(&resized, b"hello world").await.unwrap();
+		std::fs::File::options()
+			.write(true)
+			.open(&resized)
+			.unwrap()
+			.set_modified(modified_at)
+			.unwrap();
+
+		let Verification {
+			missing,
+			changed,
+			permission_denied,
+			errors,
+		} = verify_file_paths(location_path, ComparisonCaps::default(), file_paths).await;
+
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		assert!(permission_denied.is_empty());
+		assert_eq!(
+			missing
+				.into_iter()
+				.map(|(path, _)| path)
+				.collect::<Vec<_>>(),
+			vec![deleted]
+		);
+		assert_eq!(
+			changed
+				.into_iter()
+				.map(|(path, _)| path)
+				.sorted()
+				.collect::<Vec<_>>(),
+			vec![resized, touched]
+		);
+	}
+
+	#[tokio::test]
+	async fn missing_pinned_files_are_reported_instead_of_removed() {
+		let location = tempdir().unwrap();
+		let location_path = location.path();
+
+		let pinned = location_path.join("pinned.txt");
+		fs::write(&pinned, b"hello").await.unwrap();
+
+		let mut file_path = indexed(location_path, 0, &pinned).await;
+		file_path.pinned = true;
+
+		fs::remove_file(&pinned).await.unwrap();
+
+		let Verification {
+			missing,
+			changed,
+			errors,
+			..
+		} = verify_file_paths(location_path, ComparisonCaps::default(), vec![file_path]).await;
+
+		assert!(missing.is_empty());
+		assert!(changed.is_empty());
+		assert!(matches!(
+			errors.as_slice(),
+			[IndexerError::PinnedFileMissing { path }] if *path == pinned
+		));
+	}
+}
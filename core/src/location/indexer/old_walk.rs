@@ -1,31 +1,99 @@
+use crate::location::{RootClaim, RootDevice};
+
 use sd_file_path_helper::{
-	file_path_pub_and_cas_ids, file_path_walker, FilePathMetadata, IsolatedFilePathData,
+	file_path_pub_and_cas_ids, file_path_walker, get_device_from_path, get_filesystem_from_path,
+	get_inode_from_path, non_utf8_name_bytes, path_is_placeholder, FilePathError, FilePathMetadata,
+	FileSystem, IsolatedFilePathData,
 };
 use sd_prisma::prisma::file_path;
 use sd_utils::{db::inode_from_db, error::FileIOError};
 
 use std::{
 	collections::{HashMap, HashSet, VecDeque},
+	fs::Metadata,
 	future::Future,
 	hash::{Hash, Hasher},
+	io, mem,
 	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::{self, UNIX_EPOCH},
 };
 
-use chrono::{DateTime, Duration, FixedOffset};
+use chrono::{DateTime, FixedOffset};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
-use tracing::trace;
+use tracing::{span, trace, warn, Instrument, Level};
 use uuid::Uuid;
 
 use super::{
-	rules::{IndexerRule, RuleKind},
-	IndexerError,
+	rules::{IndexerRule, IndexerRuleError, RuleKind},
+	IndexerError, IoTokenBucket,
 };
 
 const TO_WALK_QUEUE_INITIAL_CAPACITY: usize = 32;
 const WALKER_PATHS_BUFFER_INITIAL_CAPACITY: usize = 256;
 const WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY: usize = 32;
 
+/// How much memory the entries found by a walk, which weren't written to the database yet, may take
+/// before it stops walking more directories in one go
+pub const DEFAULT_WALKER_MEMORY_BUDGET: u64 = 64 * 1024 * 1024;
+
+/// Approximate amount of memory held by the entries found by the walks of an indexer job and not
+/// written to the database yet. While over its budget, [`walk`] leaves the remaining directories
+/// to be walked by later steps, which only happen after the already found entries were written.
+///
+/// The estimate isn't exact, it only has to grow with the entries a walk holds.
+#[derive(Debug, Clone)]
+pub struct WalkerMemory {
+	used: Arc<AtomicU64>,
+	budget: u64,
+}
+
+impl Default for WalkerMemory {
+	fn default() -> Self {
+		Self::new(Arc::default(), DEFAULT_WALKER_MEMORY_BUDGET)
+	}
+}
+
+impl WalkerMemory {
+	pub fn new(used: Arc<AtomicU64>, budget: u64) -> Self {
+		Self { used, budget }
+	}
+
+	pub fn used(&self) -> u64 {
+		self.used.load(Ordering::Relaxed)
+	}
+
+	pub fn is_over_budget(&self) -> bool {
+		self.used() > self.budget
+	}
+
+	fn reserve(&self, bytes: u64) {
+		self.used.fetch_add(bytes, Ordering::Relaxed);
+	}
+
+	/// Gives back the memory of entries which were written to the database or dropped
+	pub fn release(&self, bytes: u64) {
+		self.used
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+				Some(used.saturating_sub(bytes))
+			})
+			.ok();
+	}
+}
+
+fn estimated_size(iso_file_path: &IsolatedFilePathData<'_>) -> u64 {
+	let parts = iso_file_path.to_parts();
+
+	(mem::size_of::<WalkedEntry>()
+		+ parts.materialized_path.len()
+		+ parts.name.len()
+		+ parts.extension.len()) as u64
+}
+
 /// `WalkEntry` represents a single path in the filesystem, for any comparison purposes, we only
 /// consider the path itself, not the metadata.
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,19 +102,103 @@ pub struct WalkedEntry {
 	pub maybe_object_id: file_path::object_id::Type,
 	pub iso_file_path: IsolatedFilePathData<'static>,
 	pub metadata: FilePathMetadata,
+	/// The original bytes of the entry's name, when it isn't valid UTF-8 and `iso_file_path` only has it lossily
+	#[serde(default)]
+	pub name_bytes: Option<Vec<u8>>,
+	/// The root of another location nested in the walked one, whose contents belong to that location
+	/// and weren't walked
+	#[serde(default)]
+	pub is_location_boundary: bool,
+	/// A file a cloud sync client only keeps online, see [`path_is_placeholder`]
+	#[serde(default)]
+	pub is_placeholder: bool,
+	/// Where this entry was found in its walk, in the order directories were read, when the walk numbers
+	/// the entries it creates. Sorting by it tells which files were added last where the filesystem doesn't
+	/// keep when they were created. Entries which are updated never get one.
+	#[serde(default)]
+	pub discovery_seq: Option<u64>,
+	/// A directory on another filesystem than the walk's root, eg. a drive mounted inside the location,
+	/// see [`ToWalkEntry::cross_filesystems`]
+	#[serde(default)]
+	pub crossed_mount: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl WalkedEntry {
+	/// Approximate memory taken by this entry, as accounted by [`WalkerMemory`]
+	pub fn estimated_size(&self) -> u64 {
+		estimated_size(&self.iso_file_path)
+	}
+}
+
+/// A `file_path` of a walked directory which the walk didn't keep, as `to_remove_db_fetcher` returns them
+#[derive(Debug, Clone)]
+pub struct ToRemoveEntry {
+	pub file_path: file_path_pub_and_cas_ids::Data,
+	/// Set when it's a directory, so everything below it can go too if it's gone from disk
+	pub dir: Option<IsolatedFilePathData<'static>>,
+	/// Set when it's pinned, so it's kept and reported with [`IndexerError::PinnedFileMissing`] instead
+	pub pinned: Option<IsolatedFilePathData<'static>>,
+}
+
+impl From<file_path_pub_and_cas_ids::Data> for ToRemoveEntry {
+	fn from(file_path: file_path_pub_and_cas_ids::Data) -> Self {
+		Self {
+			file_path,
+			dir: None,
+			pinned: None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToWalkEntry {
 	path: PathBuf,
 	parent_dir_accepted_by_its_children: Option<bool>,
 	maybe_parent: Option<PathBuf>,
+	/// The walk this directory was found by, so concurrent walks can be told apart in the logs
+	#[serde(default)]
+	walk_id: Uuid,
+	/// How many directories down from the location's root this one is, the root being 0
+	#[serde(default)]
+	depth: u32,
+	/// Directories this deep don't have their sub directories walked, see [`DEFAULT_MAX_WALK_DEPTH`]
+	#[serde(default = "default_max_depth")]
+	max_depth: u32,
+	/// The device id of the location's root, to tell the directories on other filesystems. Only known on unix
+	#[serde(default)]
+	root_device: Option<u64>,
+	/// Whether the directories on other filesystems than the root are walked into, like `find` does
+	/// without `-xdev`. They're kept as entries flagged with [`WalkedEntry::crossed_mount`] either way.
+	#[serde(default = "default_cross_filesystems")]
+	cross_filesystems: bool,
+	/// The root of the location, which the paths of the rules scoped to a sub path are relative to,
+	/// whichever of its directories the walk started from
+	#[serde(default)]
+	location_root: PathBuf,
+}
+
+/// A safety rail against trees nested deep enough to spawn walks until resources run out, eg. a symlink
+/// loop which wasn't detected. Well above how deep any real tree goes.
+pub const DEFAULT_MAX_WALK_DEPTH: u32 = 512;
+
+fn default_max_depth() -> u32 {
+	DEFAULT_MAX_WALK_DEPTH
+}
+
+fn default_cross_filesystems() -> bool {
+	true
 }
 
 #[derive(Debug)]
 struct WalkingEntry {
 	iso_file_path: IsolatedFilePathData<'static>,
 	maybe_metadata: Option<FilePathMetadata>,
+	name_bytes: Option<Vec<u8>>,
+	is_location_boundary: bool,
+	is_placeholder: bool,
+	crossed_mount: bool,
+	/// How many entries the walk found before this one
+	read_order: u64,
 }
 
 impl From<WalkingEntry> for WalkedEntry {
@@ -54,6 +206,11 @@ impl From<WalkingEntry> for WalkedEntry {
 		let WalkingEntry {
 			iso_file_path,
 			maybe_metadata,
+			name_bytes,
+			is_location_boundary,
+			is_placeholder,
+			crossed_mount,
+			..
 		} = walking_entry;
 
 		Self {
@@ -62,6 +219,11 @@ impl From<WalkingEntry> for WalkedEntry {
 			iso_file_path,
 			metadata: maybe_metadata
 				.expect("we always use Some in `the inner_walk_single_dir` function"),
+			name_bytes,
+			is_location_boundary,
+			is_placeholder,
+			discovery_seq: None,
+			crossed_mount,
 		}
 	}
 }
@@ -73,6 +235,11 @@ impl From<(Uuid, file_path::object_id::Type, WalkingEntry)> for WalkedEntry {
 		let WalkingEntry {
 			iso_file_path,
 			maybe_metadata,
+			name_bytes,
+			is_location_boundary,
+			is_placeholder,
+			crossed_mount,
+			..
 		} = walking_entry;
 
 		Self {
@@ -81,6 +248,11 @@ impl From<(Uuid, file_path::object_id::Type, WalkingEntry)> for WalkedEntry {
 			iso_file_path,
 			metadata: maybe_metadata
 				.expect("we always use Some in `the inner_walk_single_dir` function"),
+			name_bytes,
+			is_location_boundary,
+			is_placeholder,
+			discovery_seq: None,
+			crossed_mount,
 		}
 	}
 }
@@ -105,28 +277,233 @@ where
 	ToUpdate: Iterator<Item = WalkedEntry>,
 	ToRemove: Iterator<Item = file_path_pub_and_cas_ids::Data>,
 {
+	/// New entries, parents always come before their children so they can be inserted in this order
 	pub walked: Walked,
 	pub to_update: ToUpdate,
+	/// Entries which are already in the database and didn't change, so they only have to be marked as found
+	pub unchanged: Vec<file_path::pub_id::Type>,
 	pub to_walk: VecDeque<ToWalkEntry>,
 	pub to_remove: ToRemove,
+	/// Directories which are gone from disk, whose whole subtrees have to be removed. Their own
+	/// `file_path`s are in `to_remove`, but the ones below them aren't as they're never walked.
+	pub to_remove_subtrees: Vec<IsolatedFilePathData<'static>>,
 	pub errors: Vec<IndexerError>,
 	pub paths_and_sizes: HashMap<PathBuf, u64>,
+	pub walk_id: Uuid,
+	/// Entries deleted between their directory being read and being looked at, which isn't an error
+	pub vanished: u64,
+	/// Entries rejected by their name, whose metadata didn't have to be read
+	pub skipped_metadata_reads: u64,
+	/// Fingerprints of the directories which were walked and changed, to be stored for the next walk
+	pub fingerprints: Vec<DirectoryFingerprint>,
+	/// How many directories were left for later walks, the length of `to_walk`
+	pub spawned_children: usize,
+	/// How many entries were accepted, whether they're new, changed or already indexed
+	pub found_entries: usize,
+	/// Breakdown of the accepted entries, only when it was asked for
+	pub extension_statistics: Option<ExtensionStatistics>,
+	/// The filesystem of the walked root, only [`walk`] looks it up, when it could be told
+	pub filesystem: Option<FileSystem>,
+	/// How entries were compared with the database, to be used again by the walks continuing this one
+	pub comparison_caps: ComparisonCaps,
+	/// Files left out as they were modified too recently, probably still being written.
+	/// Their `file_path`s are kept as they are until they're walked again.
+	pub deferred_recent: Vec<PathBuf>,
+	/// Depth of the deepest directory walked, see [`ToWalkEntry::depth`]
+	pub max_depth_reached: u32,
+	/// Entries accepted directly inside each walked directory, only for the ones whose every entry
+	/// was looked at without errors
+	pub child_counts: HashMap<PathBuf, ChildCounts>,
+	/// Directories on other filesystems which weren't walked into, see [`ToWalkEntry::cross_filesystems`].
+	/// Like nested locations, their `file_path`s are kept as they are.
+	pub skipped_mounts: Vec<PathBuf>,
+}
+
+/// What [`walk_single_dir`] found in its directory
+pub struct SingleDirWalk<Walked, ToUpdate>
+where
+	Walked: Iterator<Item = WalkedEntry>,
+	ToUpdate: Iterator<Item = WalkedEntry>,
+{
+	/// New entries, the directory itself first when it was asked to be added
+	pub walked: Walked,
+	pub to_update: ToUpdate,
+	pub to_remove: Vec<file_path_pub_and_cas_ids::Data>,
+	/// Directories which are gone from disk, see [`WalkResult::to_remove_subtrees`]
+	pub to_remove_subtrees: Vec<IsolatedFilePathData<'static>>,
+	pub errors: Vec<IndexerError>,
+	pub root_size: u64,
+	/// How many entries were accepted, whether they're new, changed or already indexed
+	pub found_entries: usize,
+	/// See [`WalkResult::child_counts`], only the walked directory's
+	pub child_counts: HashMap<PathBuf, ChildCounts>,
+}
+
+/// How many files and directories the rules accepted directly inside a directory, whether they're
+/// new, changed or already indexed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChildCounts {
+	pub files: u32,
+	pub directories: u32,
+}
+
+/// Bucket of [`ExtensionStatistics`] for directories, no extension can contain a `/`
+pub const DIRECTORIES_BUCKET: &str = "/";
+/// Bucket of [`ExtensionStatistics`] for files without an extension
+pub const NO_EXTENSION_BUCKET: &str = "";
+
+/// How many entries have each extension, lowercased, and how many bytes they take.
+/// Directories and files without an extension are under [`DIRECTORIES_BUCKET`] and [`NO_EXTENSION_BUCKET`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionStatistics(pub HashMap<String, ExtensionBucket>);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionBucket {
+	pub count: u64,
+	/// Always 0 for directories, their contents are already counted in their own buckets
+	pub total_bytes: u64,
+}
+
+impl ExtensionStatistics {
+	fn from_entries<'a>(entries: impl IntoIterator<Item = &'a WalkingEntry>) -> Self {
+		let mut statistics = Self::default();
+		for entry in entries {
+			let parts = entry.iso_file_path.to_parts();
+			let (bucket, size_in_bytes) = if parts.is_dir {
+				(DIRECTORIES_BUCKET.to_string(), 0)
+			} else {
+				(
+					parts.extension.to_lowercase(),
+					entry
+						.maybe_metadata
+						.map_or(0, |metadata| metadata.size_in_bytes),
+				)
+			};
+
+			let bucket = statistics.0.entry(bucket).or_default();
+			bucket.count += 1;
+			bucket.total_bytes += size_in_bytes;
+		}
+
+		statistics
+	}
+
+	pub fn merge(&mut self, other: Self) {
+		for (extension, ExtensionBucket { count, total_bytes }) in other.0 {
+			let bucket = self.0.entry(extension).or_default();
+			bucket.count += count;
+			bucket.total_bytes += total_bytes;
+		}
+	}
+}
+
+/// Identifies the state of a directory's entries, so a later walk can skip all the database work for
+/// it when nothing changed, see [`walk`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirectoryFingerprint {
+	/// Materialized path of the directory's children
+	pub materialized_path: String,
+	pub fingerprint: Vec<u8>,
+}
+
+/// The options of a [`walk`], the defaults walk everything below the root with nothing else on
+pub(super) struct WalkOptions<'a> {
+	/// Nothing is walked if the root is on another device, as the drive holding the location probably
+	/// isn't mounted and everything would be removed, see [`check_root_device`]
+	pub expected_device: Option<RootDevice>,
+	/// The walk stops once it found this many entries, leaving the remaining directories for later
+	pub limit: u64,
+	/// Count the accepted entries in [`ExtensionStatistics`]
+	pub collect_statistics: bool,
+	/// The roots of other locations nested in this one, they're kept as entries flagged with
+	/// [`WalkedEntry::is_location_boundary`] but nothing below them is walked or removed, so their
+	/// contents aren't indexed twice
+	pub excluded_location_roots: &'a [PathBuf],
+	/// How entries are compared with the database, the [`ComparisonCaps`] of the filesystem the root
+	/// is on when `None`
+	pub comparison_caps: Option<ComparisonCaps>,
+	/// Claims the root for its location first, stopping with [`IndexerError::DuplicateLocation`] if
+	/// it's already the root of another location
+	pub root_claim: Option<RootClaim<'a>>,
+	/// Files modified less than that long ago are left out, see [`WalkResult::deferred_recent`]
+	pub defer_recently_modified: Option<time::Duration>,
+	/// Directories deeper than this below the location's root, or [`DEFAULT_MAX_WALK_DEPTH`] when it's
+	/// `None`, aren't walked and their parents get an [`IndexerError::MaxDepthExceeded`] instead
+	pub max_depth: Option<u32>,
+	/// Files cloud sync clients only keep online are left out instead of flagged with
+	/// [`WalkedEntry::is_placeholder`]
+	pub exclude_cloud_placeholders: bool,
+	/// Directories on another filesystem than the location's root are flagged with
+	/// [`WalkedEntry::crossed_mount`], and without this nothing below them is walked, see
+	/// [`WalkResult::skipped_mounts`]. A walked sub path on another filesystem is skipped entirely.
+	pub cross_filesystems: bool,
+	/// The entries to create are numbered from it, see [`WalkedEntry::discovery_seq`]. Steps
+	/// continuing the walk must be given the same counter.
+	pub discovery_seq: Option<&'a AtomicU64>,
+	/// The root of the walked location when only one of its sub directories is walked, the rules
+	/// scoped to a sub path are applied relative to it
+	pub location_root: Option<&'a Path>,
+}
+
+impl Default for WalkOptions<'_> {
+	fn default() -> Self {
+		Self {
+			expected_device: None,
+			limit: u64::MAX,
+			collect_statistics: false,
+			excluded_location_roots: &[],
+			comparison_caps: None,
+			root_claim: None,
+			defer_recently_modified: None,
+			max_depth: None,
+			exclude_cloud_placeholders: false,
+			cross_filesystems: true,
+			discovery_seq: None,
+			location_root: None,
+		}
+	}
 }
 
 /// This function walks through the filesystem, applying the rules to each entry and then returning
 /// a list of accepted entries. There are some useful comments in the implementation of this function
 /// in case of doubts.
-pub(super) async fn walk<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
+///
+/// `to_remove_db_fetcher` receives a walked directory and the entries found in it which must be
+/// kept, and returns the `file_path`s of that directory which aren't among them.
+///
+/// `fingerprint_db_fetcher` returns the stored [`DirectoryFingerprint`] of a directory. When it
+/// matches the directory's current one, its entries aren't compared with the database at all, so
+/// it should only return fingerprints if they can be trusted.
+///
+/// Everything else it does is up to its [`WalkOptions`].
+pub(super) async fn walk<FilePathDBFetcherFut, ToRemoveDbFetcherFut, FingerprintDbFetcherFut>(
 	root: impl AsRef<Path>,
+	walk_id: Uuid,
 	indexer_rules: &[IndexerRule],
 	mut update_notifier: impl FnMut(&Path, usize),
 	file_paths_db_fetcher: impl Fn(Vec<file_path::WhereParam>) -> FilePathDBFetcherFut,
 	to_remove_db_fetcher: impl Fn(
 		IsolatedFilePathData<'static>,
-		Vec<file_path::WhereParam>,
+		Vec<IsolatedFilePathData<'static>>,
 	) -> ToRemoveDbFetcherFut,
+	fingerprint_db_fetcher: impl Fn(IsolatedFilePathData<'static>) -> FingerprintDbFetcherFut,
 	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
-	limit: u64,
+	memory: &WalkerMemory,
+	throttle: &IoTokenBucket,
+	WalkOptions {
+		expected_device,
+		limit,
+		collect_statistics,
+		excluded_location_roots,
+		comparison_caps,
+		root_claim,
+		defer_recently_modified,
+		max_depth,
+		exclude_cloud_placeholders,
+		cross_filesystems,
+		discovery_seq,
+		location_root,
+	}: WalkOptions<'_>,
 ) -> Result<
 	WalkResult<
 		impl Iterator<Item = WalkedEntry>,
@@ -137,38 +514,104 @@ pub(super) async fn walk<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
 >
 where
 	FilePathDBFetcherFut: Future<Output = Result<Vec<file_path_walker::Data>, IndexerError>>,
-	ToRemoveDbFetcherFut:
-		Future<Output = Result<Vec<file_path_pub_and_cas_ids::Data>, IndexerError>>,
+	ToRemoveDbFetcherFut: Future<Output = Result<Vec<ToRemoveEntry>, IndexerError>>,
+	FingerprintDbFetcherFut: Future<Output = Result<Option<Vec<u8>>, IndexerError>>,
 {
 	let root = root.as_ref();
+	check_root_device(root, expected_device).await?;
+	if let Some(root_claim) = root_claim {
+		claim_root(root, root_claim).await?;
+	}
+	let (filesystem, detected_caps) = ComparisonCaps::detect(root);
+	let comparison_caps = comparison_caps.unwrap_or(detected_caps);
 
 	let mut to_walk = VecDeque::with_capacity(TO_WALK_QUEUE_INITIAL_CAPACITY);
-	to_walk.push_back(ToWalkEntry {
-		path: root.to_path_buf(),
-		parent_dir_accepted_by_its_children: None,
-		maybe_parent: None,
-	});
 	let mut indexed_paths = HashSet::with_capacity(WALKER_PATHS_BUFFER_INITIAL_CAPACITY);
+	let location_root = location_root.unwrap_or(root);
+	let root_device = location_root_device(location_root).await;
+	let mut skipped_mounts = vec![];
+	let root_metadata = fs::metadata(root).await;
+	match &root_metadata {
+		// A single file can be added as a location, there's no directory to read then and only the
+		// file itself is indexed, with nothing to remove
+		Ok(metadata) if !metadata.is_dir() => {
+			if let Some(entry) = single_file_root(
+				root,
+				metadata,
+				indexer_rules,
+				&iso_file_path_factory,
+				exclude_cloud_placeholders,
+			)
+			.await?
+			{
+				indexed_paths.insert(entry);
+			}
+		}
+		// A sub path on another filesystem than its location, like a directory below it would be
+		Ok(metadata) if !cross_filesystems && crosses_mount(root_device, device_of(metadata)) => {
+			trace!(
+				"{} is on another filesystem, not walking it",
+				root.display()
+			);
+			skipped_mounts.push(root.to_path_buf());
+		}
+		_ => to_walk.push_back(ToWalkEntry {
+			path: root.to_path_buf(),
+			parent_dir_accepted_by_its_children: None,
+			maybe_parent: None,
+			walk_id,
+			depth: depth_below(location_root, root),
+			max_depth: max_depth.unwrap_or(DEFAULT_MAX_WALK_DEPTH),
+			root_device,
+			cross_filesystems,
+			location_root: location_root.to_path_buf(),
+		}),
+	}
 	let mut errors = vec![];
+	let mut vanished = 0;
+	let mut skipped_metadata_reads = 0;
 	let mut paths_buffer = HashSet::with_capacity(WALKER_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut paths_and_sizes = HashMap::with_capacity(TO_WALK_QUEUE_INITIAL_CAPACITY);
 	let mut to_remove = vec![];
+	let mut to_remove_subtrees = vec![];
+	let mut fingerprints = vec![];
+	let mut deferred_recent = vec![];
+	let mut max_depth_reached = 0;
+	let mut child_counts = HashMap::with_capacity(TO_WALK_QUEUE_INITIAL_CAPACITY);
+	let mut unchanged_entries = 0;
 
 	while let Some(entry) = to_walk.pop_front() {
+		max_depth_reached = max_depth_reached.max(entry.depth);
 		let (entry_size, current_to_remove) = inner_walk_single_dir(
 			root,
 			&entry,
 			indexer_rules,
 			&mut update_notifier,
 			&to_remove_db_fetcher,
+			&fingerprint_db_fetcher,
 			&iso_file_path_factory,
 			WorkingTable {
 				indexed_paths: &mut indexed_paths,
 				paths_buffer: &mut paths_buffer,
 				maybe_to_walk: Some(&mut to_walk),
 				errors: &mut errors,
+				vanished: &mut vanished,
+				skipped_metadata_reads: &mut skipped_metadata_reads,
+				fingerprints: &mut fingerprints,
+				memory: Some(memory),
+				throttle: Some(throttle),
+				rejected: None,
+				excluded_location_roots,
+				defer_recently_modified,
+				deferred_recent: &mut deferred_recent,
+				exclude_cloud_placeholders,
+				to_remove_subtrees: &mut to_remove_subtrees,
+				child_counts: &mut child_counts,
+				skipped_mounts: &mut skipped_mounts,
+				unchanged_entries: &mut unchanged_entries,
 			},
 		)
+		.instrument(walker_span(&entry))
 		.await;
 		to_remove.push(current_to_remove);
 
@@ -183,30 +626,220 @@ where
 		if indexed_paths.len() >= limit as usize {
 			break;
 		}
+
+		// Leaving the rest of the directories to later steps, after what we found so far was written
+		if memory.is_over_budget() {
+			trace!(
+				%walk_id,
+				used = memory.used(),
+				"Walker is over its memory budget, pausing after {} directories left to walk",
+				to_walk.len()
+			);
+			break;
+		}
 	}
 
-	let (walked, to_update) = filter_existing_paths(indexed_paths, file_paths_db_fetcher).await?;
+	let found_entries = indexed_paths.len() + unchanged_entries;
+	let extension_statistics =
+		collect_statistics.then(|| ExtensionStatistics::from_entries(&indexed_paths));
+	let (walked, to_update, unchanged) = filter_existing_paths(
+		indexed_paths,
+		file_paths_db_fetcher,
+		Some(memory),
+		comparison_caps,
+		discovery_seq,
+	)
+	.await?;
 
 	Ok(WalkResult {
 		walked,
 		to_update,
+		unchanged,
+		spawned_children: to_walk.len(),
 		to_walk,
 		to_remove: to_remove.into_iter().flatten(),
+		to_remove_subtrees,
 		errors,
 		paths_and_sizes,
+		walk_id,
+		vanished,
+		skipped_metadata_reads,
+		fingerprints,
+		found_entries,
+		extension_statistics,
+		filesystem,
+		comparison_caps,
+		deferred_recent,
+		max_depth_reached,
+		child_counts,
+		skipped_mounts,
 	})
 }
 
-pub(super) async fn keep_walking<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
+/// The entry of a location which is a single file, if its rules accept it
+async fn single_file_root(
+	root: &Path,
+	metadata: &Metadata,
+	indexer_rules: &[IndexerRule],
+	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
+	exclude_cloud_placeholders: bool,
+) -> Result<Option<WalkingEntry>, IndexerError> {
+	let is_placeholder = path_is_placeholder(root, metadata);
+	if is_placeholder && exclude_cloud_placeholders {
+		return Ok(None);
+	}
+
+	let Some(iso_file_path) =
+		evaluate_single_path(root, root, indexer_rules, iso_file_path_factory).await?
+	else {
+		return Ok(None);
+	};
+
+	Ok(Some(WalkingEntry {
+		iso_file_path,
+		maybe_metadata: Some(FilePathMetadata::from_path(root, metadata).await?),
+		name_bytes: non_utf8_name_bytes(root),
+		is_location_boundary: false,
+		is_placeholder,
+		crossed_mount: false,
+		read_order: 0,
+	}))
+}
+
+/// Fails with [`IndexerError::WrongDevice`] when `root` isn't on the `expected` volume anymore, which
+/// happens when a drive fails to mount and an empty mountpoint or another volume sits at its path.
+pub(super) async fn check_root_device(
+	root: &Path,
+	expected: Option<RootDevice>,
+) -> Result<(), IndexerError> {
+	let Some(expected) = expected else {
+		return Ok(());
+	};
+
+	let found = get_device_from_path(root).await?;
+	if !expected.matches(root, found).await {
+		return Err(IndexerError::WrongDevice {
+			expected: expected.device,
+			found,
+		});
+	}
+
+	Ok(())
+}
+
+/// The same directory mounted at two paths has the same device and inode, so that's what is claimed
+pub(super) async fn claim_root(root: &Path, root_claim: RootClaim<'_>) -> Result<(), IndexerError> {
+	let identity = (
+		get_device_from_path(root).await?,
+		get_inode_from_path(root).await?,
+	);
+
+	root_claim
+		.claim(identity)
+		.map_err(|other_location_id| IndexerError::DuplicateLocation { other_location_id })
+}
+
+/// How entries of a filesystem can be compared with what the database has, as not all of them keep
+/// the same inode for a file or store modification dates as precisely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComparisonCaps {
+	/// Whether a file keeps its inode, FAT filesystems make them up from where the entry is on disk
+	pub stable_inodes: bool,
+	/// Modification dates closer than this to the stored one are considered the same
+	pub mtime_granularity: time::Duration,
+	/// Whether names differing only in case are the same file, like on Windows and macOS.
+	/// Extensions are matched with the database whatever their case then, other devices may have stored them otherwise.
+	#[serde(default)]
+	pub case_insensitive: bool,
+}
+
+impl Default for ComparisonCaps {
+	fn default() -> Self {
+		Self {
+			stable_inodes: true,
+			// Datetimes stored in DB lose a bit of precision
+			mtime_granularity: time::Duration::from_millis(1),
+			case_insensitive: false,
+		}
+	}
+}
+
+impl ComparisonCaps {
+	pub fn for_filesystem(filesystem: &FileSystem) -> Self {
+		match filesystem {
+			FileSystem::Fat => Self {
+				stable_inodes: false,
+				mtime_granularity: time::Duration::from_secs(2),
+				case_insensitive: true,
+			},
+			FileSystem::ExFat => Self {
+				stable_inodes: false,
+				mtime_granularity: time::Duration::from_millis(10),
+				case_insensitive: true,
+			},
+			// APFS and HFS+ can be formatted case sensitive, but the volumes macOS sets up aren't
+			FileSystem::Ntfs
+			| FileSystem::Refs
+			| FileSystem::Apfs
+			| FileSystem::Hfs
+			| FileSystem::Smb => Self {
+				case_insensitive: true,
+				..Self::default()
+			},
+			_ => Self::default(),
+		}
+	}
+
+	/// The filesystem `root` is on and how to compare its entries, the defaults when it can't be told
+	pub(super) fn detect(root: &Path) -> (Option<FileSystem>, Self) {
+		match get_filesystem_from_path(root) {
+			Ok(filesystem) => {
+				let caps = Self::for_filesystem(&filesystem);
+				trace!(
+					?filesystem,
+					?caps,
+					"Detected filesystem of {}",
+					root.display()
+				);
+				(Some(filesystem), caps)
+			}
+			Err(e) => {
+				warn!(
+					"Failed to detect the filesystem of {}: {e:#?}",
+					root.display()
+				);
+				(None, Self::default())
+			}
+		}
+	}
+}
+
+/// Walks a single directory left by [`walk`] or a previous call. While `memory` is over its budget
+/// the directory is handed back untouched in [`WalkResult::to_walk`], to be walked once the entries
+/// already found were written.
+pub(super) async fn keep_walking<
+	FilePathDBFetcherFut,
+	ToRemoveDbFetcherFut,
+	FingerprintDbFetcherFut,
+>(
 	to_walk_entry: &ToWalkEntry,
 	indexer_rules: &[IndexerRule],
 	mut update_notifier: impl FnMut(&Path, usize),
 	file_paths_db_fetcher: impl Fn(Vec<file_path::WhereParam>) -> FilePathDBFetcherFut,
 	to_remove_db_fetcher: impl Fn(
 		IsolatedFilePathData<'static>,
-		Vec<file_path::WhereParam>,
+		Vec<IsolatedFilePathData<'static>>,
 	) -> ToRemoveDbFetcherFut,
+	fingerprint_db_fetcher: impl Fn(IsolatedFilePathData<'static>) -> FingerprintDbFetcherFut,
 	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
+	memory: &WalkerMemory,
+	throttle: &IoTokenBucket,
+	collect_statistics: bool,
+	excluded_location_roots: &[PathBuf],
+	comparison_caps: ComparisonCaps,
+	defer_recently_modified: Option<time::Duration>,
+	exclude_cloud_placeholders: bool,
+	discovery_seq: Option<&AtomicU64>,
 ) -> Result<
 	WalkResult<
 		impl Iterator<Item = WalkedEntry>,
@@ -217,127 +850,389 @@ pub(super) async fn keep_walking<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
 >
 where
 	FilePathDBFetcherFut: Future<Output = Result<Vec<file_path_walker::Data>, IndexerError>>,
-	ToRemoveDbFetcherFut:
-		Future<Output = Result<Vec<file_path_pub_and_cas_ids::Data>, IndexerError>>,
+	ToRemoveDbFetcherFut: Future<Output = Result<Vec<ToRemoveEntry>, IndexerError>>,
+	FingerprintDbFetcherFut: Future<Output = Result<Option<Vec<u8>>, IndexerError>>,
 {
 	let mut to_keep_walking = VecDeque::with_capacity(TO_WALK_QUEUE_INITIAL_CAPACITY);
 	let mut indexed_paths = HashSet::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut paths_buffer = HashSet::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut errors = vec![];
+	let mut vanished = 0;
+	let mut skipped_metadata_reads = 0;
+	let mut fingerprints = vec![];
+	let mut deferred_recent = vec![];
+	let mut to_remove_subtrees = vec![];
+	let mut child_counts = HashMap::new();
+	let mut skipped_mounts = vec![];
+	let mut unchanged_entries = 0;
 
-	let (to_walk_entry_size, to_remove) = inner_walk_single_dir(
-		to_walk_entry.path.clone(),
-		to_walk_entry,
-		indexer_rules,
-		&mut update_notifier,
-		&to_remove_db_fetcher,
-		&iso_file_path_factory,
-		WorkingTable {
-			indexed_paths: &mut indexed_paths,
-			paths_buffer: &mut paths_buffer,
-			maybe_to_walk: Some(&mut to_keep_walking),
-			errors: &mut errors,
-		},
-	)
-	.await;
+	let walked_size = if memory.is_over_budget() {
+		trace!(
+			walk_id = %to_walk_entry.walk_id,
+			used = memory.used(),
+			"Walker is over its memory budget, leaving {} for later",
+			to_walk_entry.path.display()
+		);
+		to_keep_walking.push_back(to_walk_entry.clone());
+		None
+	} else {
+		Some(
+			inner_walk_single_dir(
+				to_walk_entry.path.clone(),
+				to_walk_entry,
+				indexer_rules,
+				&mut update_notifier,
+				&to_remove_db_fetcher,
+				&fingerprint_db_fetcher,
+				&iso_file_path_factory,
+				WorkingTable {
+					indexed_paths: &mut indexed_paths,
+					paths_buffer: &mut paths_buffer,
+					maybe_to_walk: Some(&mut to_keep_walking),
+					errors: &mut errors,
+					vanished: &mut vanished,
+					skipped_metadata_reads: &mut skipped_metadata_reads,
+					fingerprints: &mut fingerprints,
+					memory: Some(memory),
+					throttle: Some(throttle),
+					rejected: None,
+					excluded_location_roots,
+					defer_recently_modified,
+					deferred_recent: &mut deferred_recent,
+					exclude_cloud_placeholders,
+					to_remove_subtrees: &mut to_remove_subtrees,
+					child_counts: &mut child_counts,
+					skipped_mounts: &mut skipped_mounts,
+					unchanged_entries: &mut unchanged_entries,
+				},
+			)
+			.instrument(walker_span(to_walk_entry))
+			.await,
+		)
+	};
+	let (paths_and_sizes, to_remove) = match walked_size {
+		Some((to_walk_entry_size, to_remove)) => (
+			[
+				Some((to_walk_entry.path.clone(), to_walk_entry_size)),
+				to_walk_entry
+					.maybe_parent
+					.as_ref()
+					.map(|parent_path| (parent_path.clone(), to_walk_entry_size)),
+			]
+			.into_iter()
+			.flatten()
+			.collect(),
+			to_remove,
+		),
+		None => (HashMap::new(), vec![]),
+	};
 
-	let (walked, to_update) = filter_existing_paths(indexed_paths, file_paths_db_fetcher).await?;
+	let found_entries = indexed_paths.len() + unchanged_entries;
+	let extension_statistics =
+		collect_statistics.then(|| ExtensionStatistics::from_entries(&indexed_paths));
+	let (walked, to_update, unchanged) = filter_existing_paths(
+		indexed_paths,
+		file_paths_db_fetcher,
+		Some(memory),
+		comparison_caps,
+		discovery_seq,
+	)
+	.await?;
 
 	Ok(WalkResult {
 		walked,
 		to_update,
+		unchanged,
+		spawned_children: to_keep_walking.len(),
 		to_walk: to_keep_walking,
 		to_remove: to_remove.into_iter(),
+		to_remove_subtrees,
 		errors,
-		paths_and_sizes: [
-			Some((to_walk_entry.path.clone(), to_walk_entry_size)),
-			to_walk_entry
-				.maybe_parent
-				.as_ref()
-				.map(|parent_path| (parent_path.clone(), to_walk_entry_size)),
-		]
-		.into_iter()
-		.flatten()
-		.collect(),
+		paths_and_sizes,
+		walk_id: to_walk_entry.walk_id,
+		vanished,
+		skipped_metadata_reads,
+		fingerprints,
+		found_entries,
+		extension_statistics,
+		filesystem: None,
+		comparison_caps,
+		deferred_recent,
+		max_depth_reached: to_walk_entry.depth,
+		child_counts,
+		skipped_mounts,
 	})
 }
 
+/// What [`walk_to_memory`] found
+pub(super) struct MemoryWalk {
+	pub entries: Vec<WalkedEntry>,
+	/// Entries rejected by the rules, the contents of rejected directories aren't walked
+	pub rejected: Vec<PathBuf>,
+	pub errors: Vec<IndexerError>,
+}
+
+/// Walks everything below `root` like [`walk`] does, but without any database, for scans whose
+/// results are only kept in memory. All entries are new and nothing is left for later walks.
+pub(super) async fn walk_to_memory(
+	root: impl AsRef<Path>,
+	indexer_rules: &[IndexerRule],
+	mut update_notifier: impl FnMut(&Path, usize),
+	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
+	throttle: &IoTokenBucket,
+	cross_filesystems: bool,
+	excluded_location_roots: &[PathBuf],
+) -> MemoryWalk {
+	let root = root.as_ref();
+
+	let mut to_walk = VecDeque::with_capacity(TO_WALK_QUEUE_INITIAL_CAPACITY);
+	let mut indexed_paths = HashSet::with_capacity(WALKER_PATHS_BUFFER_INITIAL_CAPACITY);
+	let mut paths_buffer = HashSet::with_capacity(WALKER_PATHS_BUFFER_INITIAL_CAPACITY);
+	let mut errors = vec![];
+	let mut rejected = vec![];
+
+	match fs::metadata(root).await {
+		// Like with a full walk, a location which is a single file only has the file itself
+		Ok(metadata) if !metadata.is_dir() => {
+			match single_file_root(
+				root,
+				&metadata,
+				indexer_rules,
+				&iso_file_path_factory,
+				false,
+			)
+			.await
+			{
+				Ok(Some(entry)) => {
+					indexed_paths.insert(entry);
+				}
+				Ok(None) => rejected.push(root.to_path_buf()),
+				Err(e) => errors.push(e),
+			}
+		}
+		_ => to_walk.push_back(ToWalkEntry {
+			path: root.to_path_buf(),
+			parent_dir_accepted_by_its_children: None,
+			maybe_parent: None,
+			walk_id: Uuid::new_v4(),
+			depth: 0,
+			max_depth: DEFAULT_MAX_WALK_DEPTH,
+			root_device: location_root_device(root).await,
+			cross_filesystems,
+			location_root: root.to_path_buf(),
+		}),
+	}
+
+	while let Some(entry) = to_walk.pop_front() {
+		inner_walk_single_dir(
+			root,
+			&entry,
+			indexer_rules,
+			&mut update_notifier,
+			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
+			&iso_file_path_factory,
+			WorkingTable {
+				indexed_paths: &mut indexed_paths,
+				paths_buffer: &mut paths_buffer,
+				maybe_to_walk: Some(&mut to_walk),
+				errors: &mut errors,
+				vanished: &mut 0,
+				skipped_metadata_reads: &mut 0,
+				fingerprints: &mut vec![],
+				memory: None,
+				throttle: Some(throttle),
+				rejected: Some(&mut rejected),
+				excluded_location_roots,
+				defer_recently_modified: None,
+				deferred_recent: &mut vec![],
+				exclude_cloud_placeholders: false,
+				to_remove_subtrees: &mut vec![],
+				child_counts: &mut HashMap::new(),
+				skipped_mounts: &mut vec![],
+				unchanged_entries: &mut 0,
+			},
+		)
+		.instrument(walker_span(&entry))
+		.await;
+	}
+
+	MemoryWalk {
+		entries: indexed_paths.into_iter().map(WalkedEntry::from).collect(),
+		rejected,
+		errors,
+	}
+}
+
+/// Walks only the entries of `root`, a directory of the location at `location_root`, comparing them
+/// with the database.
+///
+/// Without `cross_filesystems`, nothing is walked when `root` is on another filesystem than its
+/// location, as a full walk wouldn't have gotten to it. Entries which are one of the
+/// `excluded_location_roots` are flagged like [`WalkOptions::excluded_location_roots`] are.
 pub(super) async fn walk_single_dir<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
 	root: impl AsRef<Path>,
+	location_root: &Path,
 	indexer_rules: &[IndexerRule],
 	mut update_notifier: impl FnMut(&Path, usize) + '_,
 	file_paths_db_fetcher: impl Fn(Vec<file_path::WhereParam>) -> FilePathDBFetcherFut,
 	to_remove_db_fetcher: impl Fn(
 		IsolatedFilePathData<'static>,
-		Vec<file_path::WhereParam>,
+		Vec<IsolatedFilePathData<'static>>,
 	) -> ToRemoveDbFetcherFut,
 	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
 	add_root: bool,
+	exclude_cloud_placeholders: bool,
+	cross_filesystems: bool,
+	excluded_location_roots: &[PathBuf],
+	comparison_caps: ComparisonCaps,
+	discovery_seq: Option<&AtomicU64>,
 ) -> Result<
-	(
-		impl Iterator<Item = WalkedEntry>,
-		impl Iterator<Item = WalkedEntry>,
-		Vec<file_path_pub_and_cas_ids::Data>,
-		Vec<IndexerError>,
-		u64,
-	),
+	SingleDirWalk<impl Iterator<Item = WalkedEntry>, impl Iterator<Item = WalkedEntry>>,
 	IndexerError,
 >
 where
 	FilePathDBFetcherFut: Future<Output = Result<Vec<file_path_walker::Data>, IndexerError>>,
-	ToRemoveDbFetcherFut:
-		Future<Output = Result<Vec<file_path_pub_and_cas_ids::Data>, IndexerError>>,
+	ToRemoveDbFetcherFut: Future<Output = Result<Vec<ToRemoveEntry>, IndexerError>>,
 {
 	let root = root.as_ref();
 
 	let mut indexed_paths = HashSet::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 
-	if add_root {
-		let metadata = fs::metadata(root)
-			.await
-			.map_err(|e| FileIOError::from((root, e)))?;
+	let root_metadata = match fs::metadata(root).await {
+		Ok(metadata) => Some(metadata),
+		Err(e) if add_root => return Err(FileIOError::from((root, e)).into()),
+		Err(_) => None,
+	};
 
+	// A location which is a single file has no directory to add, the file is its root
+	let add_root = add_root
+		&& root_metadata
+			.as_ref()
+			.is_some_and(|metadata| metadata.is_dir());
+	if let Some(metadata) = root_metadata.as_ref().filter(|_| add_root) {
 		indexed_paths.insert(WalkingEntry {
 			iso_file_path: iso_file_path_factory(root, true)?,
-			maybe_metadata: Some(FilePathMetadata::from_path(&root, &metadata).await?),
+			maybe_metadata: Some(FilePathMetadata::from_path(&root, metadata).await?),
+			name_bytes: non_utf8_name_bytes(root),
+			is_location_boundary: false,
+			is_placeholder: false,
+			crossed_mount: false,
+			read_order: 0,
 		});
 	}
 
 	let mut paths_buffer = HashSet::with_capacity(WALK_SINGLE_DIR_PATHS_BUFFER_INITIAL_CAPACITY);
 	let mut errors = vec![];
+	let mut vanished = 0;
+	let mut to_remove_subtrees = vec![];
+	let mut child_counts = HashMap::new();
 
-	let (root_size, to_remove) = inner_walk_single_dir(
-		root,
-		&ToWalkEntry {
-			path: root.to_path_buf(),
-			parent_dir_accepted_by_its_children: None,
-			maybe_parent: None,
-		},
-		indexer_rules,
-		&mut update_notifier,
-		&to_remove_db_fetcher,
-		&iso_file_path_factory,
-		WorkingTable {
-			indexed_paths: &mut indexed_paths,
-			paths_buffer: &mut paths_buffer,
-			maybe_to_walk: None,
-			errors: &mut errors,
-		},
-	)
-	.await;
+	let to_walk_entry = ToWalkEntry {
+		path: root.to_path_buf(),
+		parent_dir_accepted_by_its_children: None,
+		maybe_parent: None,
+		walk_id: Uuid::new_v4(),
+		depth: 0,
+		max_depth: DEFAULT_MAX_WALK_DEPTH,
+		root_device: location_root_device(location_root).await,
+		cross_filesystems,
+		location_root: location_root.to_path_buf(),
+	};
+
+	let crossed_mount = !cross_filesystems
+		&& root_metadata
+			.as_ref()
+			.is_some_and(|metadata| crosses_mount(to_walk_entry.root_device, device_of(metadata)));
+	let file_root = root_metadata.filter(|metadata| !metadata.is_dir());
+	let (root_size, to_remove) = if let Some(metadata) = file_root {
+		// Like with a full walk, a location which is a single file only has the file itself indexed
+		if let Some(entry) = single_file_root(
+			root,
+			&metadata,
+			indexer_rules,
+			&iso_file_path_factory,
+			exclude_cloud_placeholders,
+		)
+		.await?
+		{
+			indexed_paths.insert(entry);
+		}
+		(0, vec![])
+	} else if crossed_mount {
+		trace!(
+			"{} is on another filesystem, not walking it",
+			root.display()
+		);
+		(0, vec![])
+	} else {
+		inner_walk_single_dir(
+			root,
+			&to_walk_entry,
+			indexer_rules,
+			&mut update_notifier,
+			&to_remove_db_fetcher,
+			// Shallow walks always compare with the database, they're only for a single directory anyway
+			|_: IsolatedFilePathData<'static>| async { Ok(None) },
+			&iso_file_path_factory,
+			WorkingTable {
+				indexed_paths: &mut indexed_paths,
+				paths_buffer: &mut paths_buffer,
+				maybe_to_walk: None,
+				errors: &mut errors,
+				vanished: &mut vanished,
+				skipped_metadata_reads: &mut 0,
+				fingerprints: &mut vec![],
+				memory: None,
+				throttle: None,
+				rejected: None,
+				excluded_location_roots,
+				defer_recently_modified: None,
+				deferred_recent: &mut vec![],
+				exclude_cloud_placeholders,
+				to_remove_subtrees: &mut to_remove_subtrees,
+				child_counts: &mut child_counts,
+				skipped_mounts: &mut vec![],
+				unchanged_entries: &mut 0,
+			},
+		)
+		.instrument(walker_span(&to_walk_entry))
+		.await
+	};
 
-	let (walked, to_update) = filter_existing_paths(indexed_paths, file_paths_db_fetcher).await?;
+	let found_entries = indexed_paths.len() - usize::from(add_root);
+	let (walked, to_update, _) = filter_existing_paths(
+		indexed_paths,
+		file_paths_db_fetcher,
+		None,
+		comparison_caps,
+		discovery_seq,
+	)
+	.await?;
 
-	Ok((walked, to_update, to_remove, errors, root_size))
+	Ok(SingleDirWalk {
+		walked,
+		to_update,
+		to_remove,
+		to_remove_subtrees,
+		errors,
+		root_size,
+		found_entries,
+		child_counts,
+	})
 }
 
 async fn filter_existing_paths<F>(
 	indexed_paths: HashSet<WalkingEntry>,
 	file_paths_db_fetcher: impl Fn(Vec<file_path::WhereParam>) -> F,
+	memory: Option<&WalkerMemory>,
+	comparison_caps: ComparisonCaps,
+	discovery_seq: Option<&AtomicU64>,
 ) -> Result<
 	(
 		impl Iterator<Item = WalkedEntry>,
 		impl Iterator<Item = WalkedEntry>,
+		Vec<file_path::pub_id::Type>,
 	),
 	IndexerError,
 >
@@ -348,8 +1243,13 @@ where
 		file_paths_db_fetcher(
 			indexed_paths
 				.iter()
-				.map(|entry| &entry.iso_file_path)
-				.map(Into::into)
+				.map(|entry| {
+					if comparison_caps.case_insensitive {
+						entry.iso_file_path.any_extension_case_where_param()
+					} else {
+						file_path::WhereParam::from(&entry.iso_file_path)
+					}
+				})
 				.collect(),
 		)
 		.await
@@ -365,100 +1265,581 @@ where
 			})
 			.collect::<HashMap<_, _>>();
 
+		// Other devices may have stored the extension of an entry with another case, it's the same
+		// file on filesystems telling no difference between them
+		let normalized_paths_already_in_db = comparison_caps
+			.case_insensitive
+			.then(|| {
+				isolated_paths_already_in_db
+					.iter()
+					.map(|(iso_file_path, file_path)| {
+						(iso_file_path.with_normalized_extension(), file_path)
+					})
+					.collect::<HashMap<_, _>>()
+			})
+			.unwrap_or_default();
+
 		let mut to_update = vec![];
+		let mut unchanged = vec![];
 
-		let to_create = indexed_paths
+		let mut to_create = indexed_paths
 			.into_iter()
 			.filter_map(|entry| {
-				if let Some(file_path) = isolated_paths_already_in_db.get(&entry.iso_file_path) {
-					if let (Some(metadata), Some(inode), Some(date_modified)) = (
-						&entry.maybe_metadata,
-						&file_path.inode,
-						&file_path.date_modified,
-					) {
-						if (
-								inode_from_db(&inode[0..8]) != metadata.inode
-								// Datetimes stored in DB loses a bit of precision, so we need to check against a delta
-								// instead of using != operator
-								|| DateTime::<FixedOffset>::from(metadata.modified_at) - *date_modified
-									> Duration::milliseconds(1) || file_path.hidden.is_none() || metadata.hidden != file_path.hidden.unwrap_or_default()
-							)
-							// We ignore the size of directories because it is not reliable, we need to
-							// calculate it ourselves later
-							&& !(
-								entry.iso_file_path.to_parts().is_dir
-								&& metadata.size_in_bytes
-									!= file_path
-										.size_in_bytes_bytes
-										.as_ref()
-										.map(|size_in_bytes_bytes| {
-											u64::from_be_bytes([
-												size_in_bytes_bytes[0],
-												size_in_bytes_bytes[1],
-												size_in_bytes_bytes[2],
-												size_in_bytes_bytes[3],
-												size_in_bytes_bytes[4],
-												size_in_bytes_bytes[5],
-												size_in_bytes_bytes[6],
-												size_in_bytes_bytes[7],
-											])
-										})
-										.unwrap_or_default()
-								) {
+				let already_in_db = isolated_paths_already_in_db
+					.get(&entry.iso_file_path)
+					.or_else(|| {
+						normalized_paths_already_in_db
+							.get(&entry.iso_file_path.with_normalized_extension())
+							.copied()
+					});
+
+				if let Some(file_path) = already_in_db {
+					if let Some(metadata) = &entry.maybe_metadata {
+						// A placeholder which was downloaded, or evicted again, is updated even if nothing else changed,
+						// and so is a directory which became, or stopped being, the root of another location
+						if file_path_has_changed(
+							file_path,
+							metadata,
+							entry.iso_file_path.to_parts().is_dir,
+							comparison_caps,
+						) || file_path.is_placeholder.unwrap_or_default() != entry.is_placeholder
+							|| file_path.is_location_boundary.unwrap_or_default()
+								!= entry.is_location_boundary
+						{
 							to_update.push(
-								(sd_utils::from_bytes_to_uuid(&file_path.pub_id), file_path.object_id, entry).into(),
+								(
+									sd_utils::from_bytes_to_uuid(&file_path.pub_id),
+									file_path.object_id,
+									entry,
+								)
+									.into(),
 							);
+							return None;
 						}
 					}
 
+					// Only its pub_id is kept around from now on
+					if let Some(memory) = memory {
+						memory.release(estimated_size(&entry.iso_file_path));
+					}
+
+					unchanged.push(file_path.pub_id.clone());
 					None
 				} else {
-					Some(entry.into())
+					Some(entry)
 				}
 			})
 			.collect::<Vec<_>>();
 
-		(to_create.into_iter(), to_update.into_iter())
+		let mut to_create = match discovery_seq {
+			Some(discovery_seq) => {
+				// Numbered in the order they were found, before they're sorted to be inserted
+				to_create.sort_by_key(|entry| entry.read_order);
+				to_create
+					.into_iter()
+					.map(|entry| WalkedEntry {
+						discovery_seq: Some(discovery_seq.fetch_add(1, Ordering::Relaxed)),
+						..entry.into()
+					})
+					.collect::<Vec<_>>()
+			}
+			None => to_create.into_iter().map(WalkedEntry::from).collect(),
+		};
+		sort_in_insert_order(&mut to_create);
+
+		(to_create.into_iter(), to_update.into_iter(), unchanged)
 	})
 }
 
-struct WorkingTable<'a> {
-	indexed_paths: &'a mut HashSet<WalkingEntry>,
+/// `indexed_paths` is a [`HashSet`], so ancestors accepted because of a deep child can come out in any order.
+/// Sorting by depth makes sure every directory is inserted before its children.
+fn sort_in_insert_order(entries: &mut [WalkedEntry]) {
+	entries.sort_by_cached_key(|entry| {
+		let parts = entry.iso_file_path.to_parts();
+		(
+			parts.materialized_path.matches('/').count(),
+			parts.materialized_path.to_string(),
+			!parts.is_dir,
+			parts.name.to_string(),
+		)
+	});
+}
+
+fn walker_span(entry: &ToWalkEntry) -> tracing::Span {
+	span!(
+		Level::TRACE,
+		"walker",
+		walk_id = %entry.walk_id,
+		directory = %entry.path.display()
+	)
+}
+
+/// What the indexer rules decided about a single path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum RulesDecision {
+	/// Neither the path nor its children are indexed
+	Rejected,
+	/// The path itself isn't indexed but, as a directory, its children still have to be checked
+	WalkOnly {
+		accept_by_children_dir: Option<bool>,
+	},
+	/// The path is indexed, and walked into if it's a directory
+	Accepted {
+		accept_by_children_dir: Option<bool>,
+	},
+}
+
+/// Applies `indexer_rules` to `path` inside the location at `root`, `metadata` must not follow
+/// symlinks as we don't index them.
+pub(super) async fn evaluate_path(
+	root: &Path,
+	path: &Path,
+	metadata: &Metadata,
+	indexer_rules: &[IndexerRule],
+	parent_dir_accepted_by_its_children: Option<bool>,
+) -> Result<RulesDecision, IndexerError> {
+	// TODO: Hard ignoring symlinks for now, but this should be configurable
+	if metadata.is_symlink() {
+		return Ok(RulesDecision::Rejected);
+	}
+
+	let results = IndexerRule::apply_each_in_location(indexer_rules, root, path).await?;
+
+	let (decision, decided_by) = decide(
+		path,
+		&IndexerRule::results_per_kind(results.iter().flat_map(|(_, results)| results)),
+		metadata.is_dir(),
+		parent_dir_accepted_by_its_children,
+	);
+	if let Some(decided_by) = decided_by {
+		decided_by.credit(&results);
+	}
+
+	Ok(decision)
+}
+
+/// The rule result which made a [`RulesDecision`], so only the rule it came from is credited for it
+#[derive(Debug, Clone, Copy)]
+struct DecidedBy {
+	kind: RuleKind,
+	result: bool,
+	accepted: bool,
+	/// The decision came from no rule of the kind passing, so a rule is only credited when it's the
+	/// only one of its kind
+	by_absence: bool,
+}
+
+impl DecidedBy {
+	fn new(kind: RuleKind, result: bool, accepted: bool) -> Self {
+		Self {
+			kind,
+			result,
+			accepted,
+			by_absence: false,
+		}
+	}
+
+	fn by_absence(kind: RuleKind, accepted: bool) -> Self {
+		Self {
+			kind,
+			result: false,
+			accepted,
+			by_absence: true,
+		}
+	}
+
+	fn credit(&self, results: &[(&IndexerRule, Vec<(RuleKind, bool)>)]) {
+		let mut of_kind = results
+			.iter()
+			.filter(|(_, results)| results.iter().any(|(kind, _)| *kind == self.kind));
+
+		let rule = if self.by_absence {
+			match (of_kind.next(), of_kind.next()) {
+				(Some((rule, _)), None) => Some(*rule),
+				_ => None,
+			}
+		} else {
+			of_kind
+				.find(|(_, results)| results.contains(&(self.kind, self.result)))
+				.map(|(rule, _)| *rule)
+		};
+
+		if let Some(rule) = rule {
+			rule.credit(self.accepted);
+		}
+	}
+}
+
+fn decide(
+	path: &Path,
+	rules_per_kind: &HashMap<RuleKind, Vec<bool>>,
+	is_dir: bool,
+	parent_dir_accepted_by_its_children: Option<bool>,
+) -> (RulesDecision, Option<DecidedBy>) {
+	if rules_per_kind
+		.get(&RuleKind::RejectFilesByGlob)
+		.map_or(false, |reject_results| {
+			reject_results.iter().any(|reject| !reject)
+		}) {
+		trace!(
+			"Path {} rejected by `RuleKind::RejectFilesByGlob`",
+			path.display()
+		);
+		return (
+			RulesDecision::Rejected,
+			Some(DecidedBy::new(RuleKind::RejectFilesByGlob, false, false)),
+		);
+	}
+
+	// Accept by children has three states,
+	// None if we don't now yet or if this check doesn't apply
+	// Some(true) if this check applies and it passes
+	// Some(false) if this check applies and it was rejected
+	// and we pass the current parent state to its children
+	let mut accept_by_children_dir = parent_dir_accepted_by_its_children;
+	// Whether this directory's own rules, not its parent's, set `accept_by_children_dir`
+	let mut decided_by_children = None;
+
+	if is_dir {
+		// If it is a directory, first we check if we must reject it and its children entirely
+		if rules_per_kind
+			.get(&RuleKind::RejectIfChildrenDirectoriesArePresent)
+			.map_or(false, |reject_results| {
+				reject_results.iter().any(|reject| !reject)
+			}) {
+			trace!(
+				"Path {} rejected by rule `RuleKind::RejectIfChildrenDirectoriesArePresent`",
+				path.display(),
+			);
+			return (
+				RulesDecision::Rejected,
+				Some(DecidedBy::new(
+					RuleKind::RejectIfChildrenDirectoriesArePresent,
+					false,
+					false,
+				)),
+			);
+		}
+
+		// Then we check if we must accept it and its children
+		if let Some(accept_by_children_rules) =
+			rules_per_kind.get(&RuleKind::AcceptIfChildrenDirectoriesArePresent)
+		{
+			if accept_by_children_rules.iter().any(|accept| *accept) {
+				accept_by_children_dir = Some(true);
+				decided_by_children = Some(DecidedBy::new(
+					RuleKind::AcceptIfChildrenDirectoriesArePresent,
+					true,
+					true,
+				));
+			}
+
+			// If it wasn't accepted then we mark as rejected
+			if accept_by_children_dir.is_none() {
+				trace!(
+					"Path {} rejected because it didn't passed in any AcceptIfChildrenDirectoriesArePresent rule",
+					path.display()
+				);
+				accept_by_children_dir = Some(false);
+				decided_by_children = Some(DecidedBy::by_absence(
+					RuleKind::AcceptIfChildrenDirectoriesArePresent,
+					false,
+				));
+			}
+		}
+	}
+
+	let accept_globs = rules_per_kind.get(&RuleKind::AcceptFilesByGlob);
+	let (accepted, decided_by) = if accept_globs.map_or(false, |accept_rules| {
+		accept_rules.iter().all(|accept| !accept)
+	}) {
+		trace!(
+			"Path {} reject because it didn't passed in any AcceptFilesByGlob rules",
+			path.display()
+		);
+		(
+			false,
+			Some(DecidedBy::by_absence(RuleKind::AcceptFilesByGlob, false)),
+		)
+	} else {
+		let accepted = accept_by_children_dir.unwrap_or(true);
+		let decided_by = match (decided_by_children, accept_globs) {
+			(Some(decided_by), _) => Some(decided_by),
+			(None, Some(_)) if accepted => {
+				Some(DecidedBy::new(RuleKind::AcceptFilesByGlob, true, true))
+			}
+			// Accepted as no rule applies, or it follows the decision on its parent directory
+			(None, _) => None,
+		};
+		(accepted, decided_by)
+	};
+
+	let decision = match (accepted, is_dir) {
+		(true, _) => RulesDecision::Accepted {
+			accept_by_children_dir,
+		},
+		(false, true) => RulesDecision::WalkOnly {
+			accept_by_children_dir,
+		},
+		(false, false) => RulesDecision::Rejected,
+	};
+
+	(decision, decided_by)
+}
+
+/// Whether the walker would index `path`, for anything else which adds single paths to a location,
+/// like the location watcher, to agree with the indexer on what belongs to it.
+///
+/// The rules are evaluated for each directory between `location_path` and `path` too, as that's
+/// what the walker does on its way there. Returns the [`IsolatedFilePathData`] to index `path` with
+/// if it would be indexed.
+pub(crate) async fn evaluate_single_path(
+	location_path: impl AsRef<Path>,
+	path: impl AsRef<Path>,
+	indexer_rules: &[IndexerRule],
+	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
+) -> Result<Option<IsolatedFilePathData<'static>>, IndexerError> {
+	let path = path.as_ref();
+
+	match decide_single_path(location_path.as_ref(), path, indexer_rules).await? {
+		(RulesDecision::Accepted { .. }, metadata) => {
+			iso_file_path_factory(path, metadata.is_dir()).map(Some)
+		}
+		(RulesDecision::Rejected | RulesDecision::WalkOnly { .. }, _) => Ok(None),
+	}
+}
+
+/// Whether the walker would walk into the directory at `path` without indexing it, see
+/// [`RulesDecision::WalkOnly`]. It's only indexed along with the first entry found below it which is.
+pub(crate) async fn is_only_walked_through(
+	location_path: impl AsRef<Path>,
+	path: impl AsRef<Path>,
+	indexer_rules: &[IndexerRule],
+) -> Result<bool, IndexerError> {
+	decide_single_path(location_path.as_ref(), path.as_ref(), indexer_rules)
+		.await
+		.map(|(decision, _)| matches!(decision, RulesDecision::WalkOnly { .. }))
+}
+
+/// What the walker would decide about `path` on its way from `location_path`, along with its metadata
+async fn decide_single_path(
+	location_path: &Path,
+	path: &Path,
+	indexer_rules: &[IndexerRule],
+) -> Result<(RulesDecision, Metadata), IndexerError> {
+	// None when the location is `path` itself, a single file
+	let ancestors = path
+		.ancestors()
+		.skip(1)
+		.take_while(|&ancestor| ancestor != location_path && ancestor.starts_with(location_path))
+		.collect::<Vec<_>>();
+
+	let mut parent_dir_accepted_by_its_children = None;
+
+	for ancestor in ancestors.into_iter().rev() {
+		let metadata = fs::symlink_metadata(ancestor)
+			.await
+			.map_err(|e| FileIOError::from((ancestor, e)))?;
+
+		match evaluate_path(
+			location_path,
+			ancestor,
+			&metadata,
+			indexer_rules,
+			parent_dir_accepted_by_its_children,
+		)
+		.await?
+		{
+			// The walker never gets to `path`
+			RulesDecision::Rejected => return Ok((RulesDecision::Rejected, metadata)),
+			RulesDecision::WalkOnly {
+				accept_by_children_dir,
+			}
+			| RulesDecision::Accepted {
+				accept_by_children_dir,
+			} => parent_dir_accepted_by_its_children = accept_by_children_dir,
+		}
+	}
+
+	let metadata = fs::symlink_metadata(path)
+		.await
+		.map_err(|e| FileIOError::from((path, e)))?;
+
+	evaluate_path(
+		location_path,
+		path,
+		&metadata,
+		indexer_rules,
+		parent_dir_accepted_by_its_children,
+	)
+	.await
+	.map(|decision| (decision, metadata))
+}
+
+/// Whether `file_path` has to be updated to match `metadata`, freshly read from the filesystem.
+///
+/// Inodes are only compared when the filesystem keeps them, see [`ComparisonCaps`].
+pub(super) fn file_path_has_changed(
+	file_path: &file_path_walker::Data,
+	metadata: &FilePathMetadata,
+	is_dir: bool,
+	comparison_caps: ComparisonCaps,
+) -> bool {
+	let (Some(inode), Some(date_modified)) = (&file_path.inode, &file_path.date_modified) else {
+		return false;
+	};
+	let indexed_size = file_path
+		.size_in_bytes_bytes
+		.as_ref()
+		.map(|size_in_bytes_bytes| {
+			u64::from_be_bytes([
+				size_in_bytes_bytes[0],
+				size_in_bytes_bytes[1],
+				size_in_bytes_bytes[2],
+				size_in_bytes_bytes[3],
+				size_in_bytes_bytes[4],
+				size_in_bytes_bytes[5],
+				size_in_bytes_bytes[6],
+				size_in_bytes_bytes[7],
+			])
+		})
+		.unwrap_or_default();
+
+	(
+		(comparison_caps.stable_inodes && inode_from_db(&inode[0..8]) != metadata.inode)
+		// Datetimes aren't kept as precisely by every filesystem and by the DB, so we need to check
+		// against a delta instead of using != operator
+		|| (DateTime::<FixedOffset>::from(metadata.modified_at) - *date_modified)
+			.to_std()
+			.is_ok_and(|delta| delta > comparison_caps.mtime_granularity)
+		|| file_path.hidden.is_none() || metadata.hidden != file_path.hidden.unwrap_or_default()
+		// A file rewritten within the granularity of its modification date still has another size
+		|| (!is_dir && metadata.size_in_bytes != indexed_size)
+	)
+	// We ignore the size of directories because it is not reliable, we need to
+	// calculate it ourselves later
+	&& !(is_dir && metadata.size_in_bytes != indexed_size)
+}
+
+fn is_not_found(error: &IndexerError) -> bool {
+	match error {
+		IndexerError::FileIO(e)
+		| IndexerError::IndexerRules(
+			IndexerRuleError::AcceptByItsChildrenFileIO(e)
+			| IndexerRuleError::RejectByItsChildrenFileIO(e),
+		) => e.source.kind() == io::ErrorKind::NotFound,
+		_ => false,
+	}
+}
+
+/// Files modified within `window` are probably still being written, eg. a video export or a database.
+/// Directories are never deferred as their modification date changes with every new child.
+fn is_recently_modified(metadata: &Metadata, window: Option<time::Duration>) -> bool {
+	let Some(window) = window else {
+		return false;
+	};
+
+	!metadata.is_dir()
+		&& metadata
+			.modified()
+			.ok()
+			.and_then(|modified| modified.elapsed().ok())
+			.is_some_and(|age| age < window)
+}
+
+/// A rule can fail with a not found error about one of the children of `path` as well,
+/// so we make sure it's `path` itself which is gone
+async fn has_vanished(path: &Path) -> bool {
+	matches!(
+		fs::symlink_metadata(path).await,
+		Err(e) if e.kind() == io::ErrorKind::NotFound
+	)
+}
+
+struct WorkingTable<'a> {
+	indexed_paths: &'a mut HashSet<WalkingEntry>,
 	paths_buffer: &'a mut HashSet<WalkingEntry>,
 	maybe_to_walk: Option<&'a mut VecDeque<ToWalkEntry>>,
 	errors: &'a mut Vec<IndexerError>,
+	vanished: &'a mut u64,
+	skipped_metadata_reads: &'a mut u64,
+	fingerprints: &'a mut Vec<DirectoryFingerprint>,
+	memory: Option<&'a WalkerMemory>,
+	throttle: Option<&'a IoTokenBucket>,
+	/// Collects the entries rejected by the rules, instead of just dropping them
+	rejected: Option<&'a mut Vec<PathBuf>>,
+	/// Roots of other locations, which are never walked into
+	excluded_location_roots: &'a [PathBuf],
+	/// Files modified less than this long ago are left for a later walk, in `deferred_recent`
+	defer_recently_modified: Option<time::Duration>,
+	deferred_recent: &'a mut Vec<PathBuf>,
+	/// Files cloud sync clients only keep online are left out instead of being indexed as placeholders
+	exclude_cloud_placeholders: bool,
+	/// Removed directories which aren't on disk anymore, see [`WalkResult::to_remove_subtrees`]
+	to_remove_subtrees: &'a mut Vec<IsolatedFilePathData<'static>>,
+	/// See [`WalkResult::child_counts`]
+	child_counts: &'a mut HashMap<PathBuf, ChildCounts>,
+	/// See [`WalkResult::skipped_mounts`]
+	skipped_mounts: &'a mut Vec<PathBuf>,
+	/// Accepted entries of directories which didn't change since they were last indexed, they're
+	/// found too even though they're never compared with the database
+	unchanged_entries: &'a mut usize,
 }
 
-async fn inner_walk_single_dir<ToRemoveDbFetcherFut>(
+/// Walks the directory of `to_walk_entry`, `root` being the directory the whole walk started from,
+/// which the ancestors of accepted entries are indexed up to
+async fn inner_walk_single_dir<ToRemoveDbFetcherFut, FingerprintDbFetcherFut>(
 	root: impl AsRef<Path>,
 	ToWalkEntry {
 		path,
 		parent_dir_accepted_by_its_children,
+		walk_id,
+		depth,
+		max_depth,
+		root_device,
+		cross_filesystems,
+		location_root,
 		..
 	}: &ToWalkEntry,
 	indexer_rules: &[IndexerRule],
 	update_notifier: &mut impl FnMut(&Path, usize),
 	to_remove_db_fetcher: impl Fn(
 		IsolatedFilePathData<'static>,
-		Vec<file_path::WhereParam>,
+		Vec<IsolatedFilePathData<'static>>,
 	) -> ToRemoveDbFetcherFut,
+	fingerprint_db_fetcher: impl Fn(IsolatedFilePathData<'static>) -> FingerprintDbFetcherFut,
 	iso_file_path_factory: &impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
 	WorkingTable {
 		indexed_paths,
 		paths_buffer,
 		mut maybe_to_walk,
 		errors,
+		vanished,
+		skipped_metadata_reads,
+		fingerprints,
+		memory,
+		throttle,
+		mut rejected,
+		excluded_location_roots,
+		defer_recently_modified,
+		deferred_recent,
+		exclude_cloud_placeholders,
+		to_remove_subtrees,
+		child_counts,
+		skipped_mounts,
+		unchanged_entries,
 	}: WorkingTable<'_>,
 ) -> (u64, Vec<file_path_pub_and_cas_ids::Data>)
 where
-	ToRemoveDbFetcherFut:
-		Future<Output = Result<Vec<file_path_pub_and_cas_ids::Data>, IndexerError>>,
+	ToRemoveDbFetcherFut: Future<Output = Result<Vec<ToRemoveEntry>, IndexerError>>,
+	FingerprintDbFetcherFut: Future<Output = Result<Option<Vec<u8>>, IndexerError>>,
 {
 	let Ok(iso_file_path_to_walk) = iso_file_path_factory(path, true).map_err(|e| errors.push(e))
 	else {
 		return (0, vec![]);
 	};
 
+	io_permit(throttle).await;
 	let Ok(mut read_dir) = fs::read_dir(path)
 		.await
 		.map_err(|e| errors.push(FileIOError::from((path.clone(), e)).into()))
@@ -473,28 +1854,39 @@ where
 
 	let mut found_paths_counts = 0;
 
-	// Marking with a loop label here in case of rejection or errors, to continue with next entry
-	'entries: loop {
+	// Entries that still exist but were dropped because of a non-critical error or deferred, their
+	// `file_path`s must survive this walk so they don't lose their objects and tags
+	let mut kept_on_error = vec![];
+
+	let errors_before = errors.len();
+	// The directory's fingerprint can only be trusted if every entry was looked at
+	let mut complete = true;
+	// Names of everything in the directory, lossy like the stored ones, to tell removed directories
+	// which are gone from the ones which are still there but not indexed anymore
+	let mut on_disk = HashSet::new();
+	let mut listed_everything = true;
+	let mut depth_exceeded = false;
+	let mut entries = vec![];
+
+	// First we collect the metadata of every entry, to know if the directory changed at all
+	loop {
+		io_permit(throttle).await;
 		let entry = match read_dir.next_entry().await {
 			Ok(Some(entry)) => entry,
 			Ok(None) => break,
 			Err(e) => {
 				errors.push(FileIOError::from((path.clone(), e)).into());
+				complete = false;
+				listed_everything = false;
 				continue;
 			}
 		};
 
-		// Accept by children has three states,
-		// None if we don't now yet or if this check doesn't apply
-		// Some(true) if this check applies and it passes
-		// Some(false) if this check applies and it was rejected
-		// and we pass the current parent state to its children
-		let mut accept_by_children_dir = *parent_dir_accepted_by_its_children;
-
 		let current_path = entry.path();
+		on_disk.insert(entry.file_name().to_string_lossy().into_owned());
 
 		// Just sending updates if we found more paths since the last loop
-		let current_found_paths_count = paths_buffer.len();
+		let current_found_paths_count = entries.len();
 		if found_paths_counts != current_found_paths_count {
 			update_notifier(
 				&current_path,
@@ -503,120 +1895,230 @@ where
 			found_paths_counts = current_found_paths_count;
 		}
 
-		trace!(
-			"Current filesystem path: {}, accept_by_children_dir: {:#?}",
-			current_path.display(),
-			accept_by_children_dir
-		);
-
-		let Ok(rules_per_kind) = IndexerRule::apply_all(indexer_rules, &current_path)
-			.await
-			.map_err(|e| errors.push(e.into()))
-		else {
-			continue 'entries;
-		};
-
-		if rules_per_kind
-			.get(&RuleKind::RejectFilesByGlob)
-			.map_or(false, |reject_results| {
-				reject_results.iter().any(|reject| !reject)
-			}) {
+		// `evaluate_path` would reject it before anything else, no need to read its metadata
+		if let Some(rule) =
+			IndexerRule::rejecting_by_name(indexer_rules, location_root, &current_path)
+		{
 			trace!(
-				"Path {} rejected by `RuleKind::RejectFilesByGlob`",
-				current_path.display()
+				"Path {} rejected by its name by rule '{}'",
+				current_path.display(),
+				rule.name
 			);
-			continue 'entries;
+			rule.credit(false);
+			*skipped_metadata_reads += 1;
+			if let Some(rejected) = &mut rejected {
+				rejected.push(current_path);
+			}
+			continue;
 		}
 
-		let Ok(metadata) = entry
-			.metadata()
-			.await
-			.map_err(|e| errors.push(FileIOError::from((&current_path, e)).into()))
-		else {
-			continue 'entries;
-		};
-
-		// TODO: Hard ignoring symlinks for now, but this should be configurable
-		if metadata.is_symlink() {
-			continue 'entries;
+		io_permit(throttle).await;
+		match entry.metadata().await {
+			Ok(metadata) if is_recently_modified(&metadata, defer_recently_modified) => {
+				trace!(
+					"{} was modified too recently, leaving it for later",
+					current_path.display()
+				);
+				// Its `file_path` stays as it was, and so the directory can't be fingerprinted
+				if let Ok(iso_file_path) = iso_file_path_factory(&current_path, false) {
+					kept_on_error.push(iso_file_path);
+				}
+				deferred_recent.push(current_path);
+				complete = false;
+			}
+			Ok(metadata) => entries.push((current_path, metadata)),
+			// Happens all the time in caches and build outputs, the entry is just gone
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				trace!(
+					"{} vanished before its metadata was read",
+					current_path.display()
+				);
+				*vanished += 1;
+			}
+			Err(e) => {
+				errors.push(FileIOError::from((&current_path, e)).into());
+				// Without metadata we don't know if it was a file or a directory
+				kept_on_error.extend(
+					[false, true]
+						.into_iter()
+						.filter_map(|is_dir| iso_file_path_factory(&current_path, is_dir).ok()),
+				);
+				complete = false;
+			}
 		}
+	}
 
-		let is_dir = metadata.is_dir();
+	let fingerprint = complete.then(|| {
+		directory_fingerprint(
+			indexer_rules,
+			*parent_dir_accepted_by_its_children,
+			&entries,
+			exclude_cloud_placeholders,
+		)
+	});
+
+	// If nothing changed since the directory was last indexed, neither did its entries in the
+	// database, so we only have to find out which of its directories to walk into
+	let unchanged = match &fingerprint {
+		Some(fingerprint) => match fingerprint_db_fetcher(iso_file_path_to_walk.clone()).await {
+			Ok(stored) => stored.as_ref() == Some(fingerprint),
+			Err(e) => {
+				errors.push(e);
+				false
+			}
+		},
+		None => false,
+	};
+	let mut unchanged_size = 0;
+	let mut counts = ChildCounts::default();
+
+	// Marking with a loop label here in case of rejection or errors, to continue with next entry
+	'entries: for (current_path, metadata) in entries {
+		trace!(
+			"Current filesystem path: {}, parent_dir_accepted_by_its_children: {:#?}",
+			current_path.display(),
+			parent_dir_accepted_by_its_children
+		);
 
-		if is_dir {
-			// If it is a directory, first we check if we must reject it and its children entirely
-			if rules_per_kind
-				.get(&RuleKind::RejectIfChildrenDirectoriesArePresent)
-				.map_or(false, |reject_results| {
-					reject_results.iter().any(|reject| !reject)
-				}) {
+		let decision = match evaluate_path(
+			location_root,
+			&current_path,
+			&metadata,
+			indexer_rules,
+			*parent_dir_accepted_by_its_children,
+		)
+		.await
+		{
+			Ok(decision) => decision,
+			Err(e) if is_not_found(&e) && has_vanished(&current_path).await => {
 				trace!(
-					"Path {} rejected by rule `RuleKind::RejectIfChildrenDirectoriesArePresent`",
-					current_path.display(),
+					"{} vanished before the rules were applied",
+					current_path.display()
 				);
+				*vanished += 1;
 				continue 'entries;
 			}
-
-			// Then we check if we must accept it and its children
-			if let Some(accept_by_children_rules) =
-				rules_per_kind.get(&RuleKind::AcceptIfChildrenDirectoriesArePresent)
-			{
-				if accept_by_children_rules.iter().any(|accept| *accept) {
-					accept_by_children_dir = Some(true);
+			Err(e) => {
+				errors.push(e);
+				if let Ok(iso_file_path) = iso_file_path_factory(&current_path, metadata.is_dir()) {
+					kept_on_error.push(iso_file_path);
 				}
+				continue 'entries;
+			}
+		};
 
-				// If it wasn't accepted then we mark as rejected
-				if accept_by_children_dir.is_none() {
-					trace!(
-						"Path {} rejected because it didn't passed in any AcceptIfChildrenDirectoriesArePresent rule",
-						current_path.display()
-					);
-					accept_by_children_dir = Some(false);
+		let is_dir = metadata.is_dir();
+		let is_location_boundary = is_dir && excluded_location_roots.contains(&current_path);
+		let crossed_mount = is_dir && crosses_mount(*root_device, device_of(&metadata));
+
+		let accept_by_children_dir = match decision {
+			RulesDecision::Rejected => {
+				if let Some(rejected) = &mut rejected {
+					rejected.push(current_path);
 				}
+				continue 'entries;
 			}
-
-			// Then we mark this directory the be walked in too
-			if let Some(ref mut to_walk) = maybe_to_walk {
-				to_walk.push_back(ToWalkEntry {
-					path: current_path.clone(),
-					parent_dir_accepted_by_its_children: accept_by_children_dir,
-					maybe_parent: Some(path.clone()),
-				});
+			RulesDecision::WalkOnly {
+				accept_by_children_dir,
 			}
-		}
+			| RulesDecision::Accepted {
+				accept_by_children_dir,
+			} => accept_by_children_dir,
+		};
 
-		if rules_per_kind
-			.get(&RuleKind::AcceptFilesByGlob)
-			.map_or(false, |accept_rules| {
-				accept_rules.iter().all(|accept| !accept)
-			}) {
+		// Then we mark this directory the be walked in too, unless another location takes over from here
+		if is_location_boundary {
 			trace!(
-				"Path {} reject because it didn't passed in any AcceptFilesByGlob rules",
+				"{} is the root of another location, not walking into it",
 				current_path.display()
 			);
-			continue 'entries;
+		} else if crossed_mount && !*cross_filesystems {
+			if maybe_to_walk.is_some() {
+				trace!(
+					"{} is on another filesystem, not walking into it",
+					current_path.display()
+				);
+				skipped_mounts.push(current_path.clone());
+			}
+		} else if is_dir {
+			if let Some(ref mut to_walk) = maybe_to_walk {
+				if depth < max_depth {
+					to_walk.push_back(ToWalkEntry {
+						path: current_path.clone(),
+						parent_dir_accepted_by_its_children: accept_by_children_dir,
+						maybe_parent: Some(path.clone()),
+						walk_id: *walk_id,
+						depth: depth + 1,
+						max_depth: *max_depth,
+						root_device: *root_device,
+						cross_filesystems: *cross_filesystems,
+						location_root: location_root.clone(),
+					});
+				} else if !depth_exceeded {
+					// Once for the whole directory, however many sub directories it has
+					depth_exceeded = true;
+					errors.push(IndexerError::MaxDepthExceeded {
+						path: path.clone(),
+						depth: depth + 1,
+					});
+				}
+			}
 		}
 
-		if accept_by_children_dir.unwrap_or(true) {
+		if let RulesDecision::Accepted { .. } = decision {
+			let is_placeholder = !is_dir && path_is_placeholder(&current_path, &metadata);
+			if is_placeholder && exclude_cloud_placeholders {
+				trace!(
+					"{} is only kept online by a cloud sync client, leaving it out",
+					current_path.display()
+				);
+				continue 'entries;
+			}
+
+			if is_dir {
+				counts.directories += 1;
+			} else {
+				counts.files += 1;
+			}
+
+			if unchanged {
+				unchanged_size += metadata.len();
+				continue 'entries;
+			}
+
 			let Ok(iso_file_path) =
 				iso_file_path_factory(&current_path, is_dir).map_err(|e| errors.push(e))
 			else {
 				continue 'entries;
 			};
 
-			let Ok(metadata) = FilePathMetadata::from_path(&current_path, &metadata)
-				.await
-				.map_err(|e| errors.push(e.into()))
-			else {
-				continue;
+			let metadata = match file_path_metadata(&current_path, &metadata).await {
+				Ok(metadata) => metadata,
+				Err(e) => {
+					errors.push(e.into());
+					kept_on_error.push(iso_file_path);
+					continue 'entries;
+				}
 			};
 
-			paths_buffer.insert(WalkingEntry {
+			let entry_size = estimated_size(&iso_file_path);
+			let read_order = (indexed_paths.len() + paths_buffer.len()) as u64;
+			if paths_buffer.insert(WalkingEntry {
 				iso_file_path,
 				maybe_metadata: Some(metadata),
-			});
+				name_bytes: non_utf8_name_bytes(&current_path),
+				is_location_boundary,
+				is_placeholder,
+				crossed_mount,
+				read_order,
+			}) {
+				if let Some(memory) = memory {
+					memory.reserve(entry_size);
+				}
+			}
 
-			// If the ancestors directories wasn't indexed before, now we do
+			// If the ancestors directories wasn't indexed before, now we do. They were all walked to
+			// get here, so none of them was rejected, only left out by the rules for being `WalkOnly`
 			for ancestor in current_path
 				.ancestors()
 				.skip(1) // Skip the current directory as it was already indexed
@@ -632,9 +2134,15 @@ where
 				let mut ancestor_iso_walking_entry = WalkingEntry {
 					iso_file_path,
 					maybe_metadata: None,
+					name_bytes: non_utf8_name_bytes(ancestor),
+					is_location_boundary: false,
+					is_placeholder: false,
+					crossed_mount: false,
+					read_order: (indexed_paths.len() + paths_buffer.len()) as u64,
 				};
 				trace!("Indexing ancestor {}", ancestor.display());
 				if !indexed_paths.contains(&ancestor_iso_walking_entry) {
+					io_permit(throttle).await;
 					let Ok(metadata) = fs::metadata(ancestor)
 						.await
 						.map_err(|e| errors.push(FileIOError::from((&ancestor, e)).into()))
@@ -643,7 +2151,7 @@ where
 						continue;
 					};
 
-					let Ok(metadata) = FilePathMetadata::from_path(&ancestor, &metadata)
+					let Ok(metadata) = file_path_metadata(ancestor, &metadata)
 						.await
 						.map_err(|e| errors.push(e.into()))
 					else {
@@ -652,7 +2160,12 @@ where
 
 					ancestor_iso_walking_entry.maybe_metadata = Some(metadata);
 
-					paths_buffer.insert(ancestor_iso_walking_entry);
+					let entry_size = estimated_size(&ancestor_iso_walking_entry.iso_file_path);
+					if paths_buffer.insert(ancestor_iso_walking_entry) {
+						if let Some(memory) = memory {
+							memory.reserve(entry_size);
+						}
+					}
 				} else {
 					// If indexed_paths contains the current ancestors, then it will contain
 					// also all if its ancestors too, so we can stop here
@@ -662,15 +2175,25 @@ where
 		}
 	}
 
+	if complete && errors.len() == errors_before {
+		child_counts.insert(path.clone(), counts);
+	}
+
+	if unchanged {
+		trace!("{} didn't change since it was last indexed", path.display());
+		*unchanged_entries += (counts.files + counts.directories) as usize;
+		return (unchanged_size, vec![]);
+	}
+
 	// We continue the function even if we fail to fetch `file_path`s to remove,
 	// the DB will have old `file_path`s but at least this is better than
 	// don't adding the newly indexed paths
 	let to_remove = to_remove_db_fetcher(
-		iso_file_path_to_walk,
+		iso_file_path_to_walk.clone(),
 		paths_buffer
 			.iter()
-			.map(|entry| &entry.iso_file_path)
-			.map(Into::into)
+			.map(|entry| entry.iso_file_path.clone())
+			.chain(kept_on_error)
 			.collect(),
 	)
 	.await
@@ -679,6 +2202,45 @@ where
 		vec![]
 	});
 
+	// Whatever was below a directory which is gone went with it, and won't ever be walked to be removed
+	if listed_everything {
+		to_remove_subtrees.extend(
+			to_remove
+				.iter()
+				.filter_map(|entry| entry.dir.as_ref())
+				.filter(|dir| !on_disk.contains(&dir.full_name()))
+				.cloned(),
+		);
+	}
+
+	// Pinned file_paths are never removed, only reported when they're gone from disk
+	let (pinned, to_remove): (Vec<_>, Vec<_>) = to_remove
+		.into_iter()
+		.partition(|entry| entry.pinned.is_some());
+	if listed_everything {
+		errors.extend(
+			pinned
+				.into_iter()
+				.filter_map(|entry| entry.pinned)
+				.filter(|pinned| !on_disk.contains(&pinned.full_name()))
+				.map(|pinned| IndexerError::PinnedFileMissing {
+					path: location_root.join(&pinned),
+				}),
+		);
+	}
+	let to_remove = to_remove.into_iter().map(|entry| entry.file_path).collect();
+
+	// Only directories fully indexed without errors can be skipped next time
+	if let (Some(fingerprint), Some(materialized_path)) = (
+		fingerprint.filter(|_| errors.len() == errors_before),
+		iso_file_path_to_walk.materialized_path_for_children(),
+	) {
+		fingerprints.push(DirectoryFingerprint {
+			materialized_path,
+			fingerprint,
+		});
+	}
+
 	let mut to_walk_entry_size = 0;
 
 	// Just merging the `found_paths` with `indexed_paths` here in the end to avoid possibly
@@ -693,36 +2255,186 @@ where
 	(to_walk_entry_size, to_remove)
 }
 
-#[cfg(test)]
-#[allow(clippy::unwrap_used, clippy::panic)]
-mod tests {
-	use super::super::rules::RulePerKind;
-	use super::*;
-	use chrono::Utc;
-	use globset::{Glob, GlobSetBuilder};
-	use tempfile::{tempdir, TempDir};
-	// use tracing_test::traced_test;
+/// Hash of everything the walker's decisions about the entries of a directory depend on: their
+/// names, kinds, sizes and modification dates, the rules and if the directory was accepted by its
+/// children. A changed entry deeper down changes the modification date of its directory, so it
+/// shows up in the fingerprint of that directory instead.
+fn directory_fingerprint(
+	indexer_rules: &[IndexerRule],
+	parent_dir_accepted_by_its_children: Option<bool>,
+	entries: &[(PathBuf, Metadata)],
+	exclude_cloud_placeholders: bool,
+) -> Vec<u8> {
+	let mut hasher = blake3::Hasher::new();
 
-	impl PartialEq for WalkedEntry {
-		fn eq(&self, other: &Self) -> bool {
-			self.iso_file_path == other.iso_file_path
-		}
+	for rule in indexer_rules {
+		hasher.update(&rule.id.unwrap_or_default().to_le_bytes());
+		hasher.update(&rule.date_modified.timestamp_millis().to_le_bytes());
 	}
+	hasher.update(&[match parent_dir_accepted_by_its_children {
+		None => 0,
+		Some(false) => 1,
+		Some(true) => 2,
+	}]);
 
-	impl Eq for WalkedEntry {}
+	let mut entries = entries.iter().collect::<Vec<_>>();
+	entries.sort_by(|(a, _), (b, _)| a.cmp(b));
 
-	impl Hash for WalkedEntry {
-		fn hash<H: Hasher>(&self, state: &mut H) {
-			self.iso_file_path.hash(state);
+	for (path, metadata) in entries {
+		let modified = metadata
+			.modified()
+			.ok()
+			.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+			.unwrap_or_default();
+
+		hasher.update(
+			path.file_name()
+				.unwrap_or_default()
+				.to_string_lossy()
+				.as_bytes(),
+		);
+		hasher.update(&[0, u8::from(metadata.is_dir())]);
+		hasher.update(&metadata.len().to_le_bytes());
+		hasher.update(&modified.as_nanos().to_le_bytes());
+		// Only for placeholders, so the fingerprints of directories without any stay the same, but a placeholder
+		// being downloaded or the location's option changing has it walked again
+		if path_is_placeholder(path, metadata) {
+			hasher.update(&[1, u8::from(exclude_cloud_placeholders)]);
 		}
 	}
 
-	async fn prepare_location() -> TempDir {
-		let root = tempdir().unwrap();
-		let root_path = root.path();
-		let rust_project = root_path.join("rust_project");
-		let inner_project = root_path.join("inner");
-		let node_project = inner_project.join("node_project");
+	hasher.finalize().as_bytes().to_vec()
+}
+
+/// Waits for the walker's [`IoThrottle`](super::IoThrottle), if it has one
+async fn io_permit(throttle: Option<&IoTokenBucket>) {
+	if let Some(throttle) = throttle {
+		throttle.acquire().await;
+	}
+}
+
+/// The id of the device, so of the filesystem, an entry is on
+#[cfg(unix)]
+fn device_of(metadata: &Metadata) -> Option<u64> {
+	use std::os::unix::fs::MetadataExt;
+
+	Some(metadata.dev())
+}
+
+/// Windows only has it from an open handle, which we don't want for every directory walked
+#[cfg(not(unix))]
+fn device_of(_metadata: &Metadata) -> Option<u64> {
+	None
+}
+
+/// How many directories down from `location_root` its sub directory `path` is
+fn depth_below(location_root: &Path, path: &Path) -> u32 {
+	path.strip_prefix(location_root)
+		.map_or(0, |sub_path| sub_path.components().count() as u32)
+}
+
+/// The device of a location's root, which directories on other filesystems are told apart from
+/// whichever of its directories a walk starts from
+async fn location_root_device(location_root: &Path) -> Option<u64> {
+	fs::metadata(location_root)
+		.await
+		.ok()
+		.as_ref()
+		.and_then(device_of)
+}
+
+/// Whether an entry on `device` is on another filesystem than its location's root, when both are known
+fn crosses_mount(root_device: Option<u64>, device: Option<u64>) -> bool {
+	root_device
+		.zip(device)
+		.is_some_and(|(root_device, device)| root_device != device)
+}
+
+/// [`FilePathMetadata::from_path`], which can't fail on unix outside of tests
+async fn file_path_metadata(
+	path: &Path,
+	metadata: &Metadata,
+) -> Result<FilePathMetadata, FilePathError> {
+	#[cfg(test)]
+	if tests::FAILING_METADATA.with(|failing| failing.borrow().contains(path)) {
+		return Err(FileIOError::from((
+			path,
+			io::Error::new(io::ErrorKind::Other, "failing on purpose"),
+		))
+		.into());
+	}
+
+	FilePathMetadata::from_path(path, metadata).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic)]
+pub(super) mod tests {
+	use super::super::{
+		rules::{RuleHits, RulePerKind},
+		IoThrottle,
+	};
+	use super::*;
+	use chrono::Utc;
+	use globset::{Glob, GlobSetBuilder};
+	use std::cell::{Cell, RefCell};
+	use tempfile::{tempdir, TempDir};
+	// use tracing_test::traced_test;
+
+	thread_local! {
+		/// Paths for which [`file_path_metadata`] fails
+		pub(super) static FAILING_METADATA: RefCell<HashSet<PathBuf>> = RefCell::default();
+	}
+
+	impl PartialEq for WalkedEntry {
+		fn eq(&self, other: &Self) -> bool {
+			self.iso_file_path == other.iso_file_path
+		}
+	}
+
+	impl Eq for WalkedEntry {}
+
+	impl Hash for WalkedEntry {
+		fn hash<H: Hasher>(&self, state: &mut H) {
+			self.iso_file_path.hash(state);
+		}
+	}
+
+	/// [`walk`] of everything below `root`, as a location of its own, against an empty database
+	pub(crate) async fn walk_with(
+		root: &Path,
+		indexer_rules: &[IndexerRule],
+		options: WalkOptions<'_>,
+	) -> Result<
+		WalkResult<
+			impl Iterator<Item = WalkedEntry>,
+			impl Iterator<Item = WalkedEntry>,
+			impl Iterator<Item = file_path_pub_and_cas_ids::Data>,
+		>,
+		IndexerError,
+	> {
+		walk(
+			root,
+			Uuid::new_v4(),
+			indexer_rules,
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
+			|path, is_dir| IsolatedFilePathData::new(0, root, path, is_dir).map_err(Into::into),
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			options,
+		)
+		.await
+	}
+
+	pub(crate) async fn prepare_location() -> TempDir {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		let rust_project = root_path.join("rust_project");
+		let inner_project = root_path.join("inner");
+		let node_project = inner_project.join("node_project");
 		let photos = root_path.join("photos");
 
 		fs::create_dir(&rust_project).await.unwrap();
@@ -785,6 +2497,7 @@ mod tests {
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 			hidden: false,
+			created_at_is_fallback: false,
 		};
 
 		let f = |path, is_dir| IsolatedFilePathData::new(0, root_path, path, is_dir).unwrap();
@@ -793,54 +2506,298 @@ mod tests {
 
 		#[rustfmt::skip]
 		let expected = [
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/.git"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/Cargo.toml"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src/main.rs"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug/main"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/.git"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/package.json"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src/App.tsx"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react/package.json"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo1.png"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo2.jpg"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo3.jpeg"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/text.txt"), false), metadata },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/Cargo.toml"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src/main.rs"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug/main"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/package.json"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src/App.tsx"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react/package.json"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo1.png"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo2.jpg"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo3.jpeg"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/text.txt"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
 		]
 		.into_iter()
 		.collect::<HashSet<_>>();
 
-		let walk_result = walk(
-			root_path.to_path_buf(),
+		let walk_result = walk_with(root_path, &[], WalkOptions::default())
+			.await
+			.unwrap();
+
+		if !walk_result.errors.is_empty() {
+			panic!("errors: {:#?}", walk_result.errors);
+		}
+
+		let actual = walk_result.walked.collect::<HashSet<_>>();
+
+		if actual != expected {
+			panic!("difference: {:#?}", expected.difference(&actual));
+		}
+	}
+
+	#[tokio::test]
+	async fn extension_statistics_count_every_accepted_entry() {
+		let root = prepare_location().await;
+		let photos = root.path().join("photos");
+		fs::create_dir(photos.join("album")).await.unwrap();
+
+		let walk_result = walk_with(
+			&photos,
+			&[],
+			WalkOptions {
+				collect_statistics: true,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		assert!(walk_result.errors.is_empty());
+
+		let bucket = |count| ExtensionBucket {
+			count,
+			total_bytes: 0,
+		};
+		assert_eq!(
+			walk_result.extension_statistics.unwrap(),
+			ExtensionStatistics(HashMap::from([
+				("png".to_string(), bucket(1)),
+				("jpg".to_string(), bucket(1)),
+				("jpeg".to_string(), bucket(1)),
+				("txt".to_string(), bucket(1)),
+				(DIRECTORIES_BUCKET.to_string(), bucket(1)),
+			]))
+		);
+	}
+
+	#[tokio::test]
+	async fn child_counts_are_the_direct_entries_of_each_directory() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let walk_result = walk_with(root_path, &[], WalkOptions::default())
+			.await
+			.unwrap();
+		assert!(walk_result.errors.is_empty());
+
+		let counts = |path| walk_result.child_counts.get(&root_path.join(path)).copied();
+		assert_eq!(
+			counts("photos"),
+			Some(ChildCounts {
+				files: 4,
+				directories: 0
+			})
+		);
+		assert_eq!(
+			counts("rust_project"),
+			Some(ChildCounts {
+				files: 1,
+				directories: 3
+			})
+		);
+		assert_eq!(
+			walk_result.child_counts.get(root_path).copied(),
+			Some(ChildCounts {
+				files: 0,
+				directories: 3
+			})
+		);
+	}
+
+	#[test]
+	fn only_known_and_different_devices_cross_a_mount() {
+		assert!(crosses_mount(Some(1), Some(2)));
+		assert!(!crosses_mount(Some(1), Some(1)));
+		// Where device ids aren't known, nothing is ever told apart
+		assert!(!crosses_mount(None, Some(2)));
+		assert!(!crosses_mount(Some(1), None));
+	}
+
+	#[tokio::test]
+	async fn sub_path_walks_tell_mounts_apart_from_the_location_root() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let inner = root_path.join("inner");
+
+		let WalkResult { to_walk, .. } = walk(
+			&inner,
+			Uuid::new_v4(),
 			&[],
 			|_, _| {},
 			|_| async { Ok(vec![]) },
 			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
 			|path, is_dir| {
 				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
 			},
-			420,
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions {
+				limit: 1,
+				location_root: Some(root_path),
+				..Default::default()
+			},
 		)
 		.await
 		.unwrap();
 
-		if !walk_result.errors.is_empty() {
-			panic!("errors: {:#?}", walk_result.errors);
+		let location_device = device_of(&fs::metadata(root_path).await.unwrap());
+		assert!(!to_walk.is_empty());
+		assert!(to_walk.iter().all(|entry| {
+			entry.root_device == location_device && entry.location_root == root_path
+		}));
+	}
+
+	#[tokio::test]
+	async fn directories_on_other_devices_are_kept_but_only_walked_into_when_crossing() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let Some(device) = device_of(&fs::metadata(root_path).await.unwrap()) else {
+			// Only known on unix
+			return;
+		};
+
+		for cross_filesystems in [true, false] {
+			// As if the walk started on another device, so every directory of the location is a mount point
+			let to_walk_entry = ToWalkEntry {
+				path: root_path.to_path_buf(),
+				parent_dir_accepted_by_its_children: None,
+				maybe_parent: None,
+				walk_id: Uuid::new_v4(),
+				depth: 0,
+				max_depth: DEFAULT_MAX_WALK_DEPTH,
+				root_device: Some(device.wrapping_add(1)),
+				cross_filesystems,
+				location_root: root_path.to_path_buf(),
+			};
+
+			let WalkResult {
+				walked,
+				to_walk,
+				errors,
+				skipped_mounts,
+				..
+			} = keep_walking(
+				&to_walk_entry,
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				|path: &Path, is_dir| {
+					IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+				},
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				false,
+				&[],
+				ComparisonCaps::default(),
+				None,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+
+			let walked = walked.collect::<Vec<_>>();
+			assert_eq!(walked.len(), 3);
+			assert!(walked.iter().all(|entry| entry.crossed_mount));
+
+			if cross_filesystems {
+				assert_eq!(to_walk.len(), 3);
+				assert!(to_walk
+					.iter()
+					.all(|entry| entry.root_device == to_walk_entry.root_device));
+				assert!(skipped_mounts.is_empty());
+			} else {
+				assert!(to_walk.is_empty());
+				let mut skipped_mounts = skipped_mounts;
+				skipped_mounts.sort();
+				assert_eq!(
+					skipped_mounts,
+					["inner", "photos", "rust_project"].map(|dir| root_path.join(dir))
+				);
+			}
 		}
+	}
 
-		let actual = walk_result.walked.collect::<HashSet<_>>();
+	/// Needs to be run as root to mount anything, it's skipped otherwise
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn walks_without_cross_filesystems_stop_at_mount_points() {
+		use std::process::Command;
 
-		if actual != expected {
-			panic!("difference: {:#?}", expected.difference(&actual));
+		struct Unmount<'a>(&'a Path);
+
+		impl Drop for Unmount<'_> {
+			fn drop(&mut self) {
+				Command::new("umount").arg(self.0).status().ok();
+			}
+		}
+
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let mount_point = root_path.join("photos");
+
+		// A bind mount from the filesystem of the location would keep its device id, a tmpfs has its own
+		let mounted = Command::new("mount")
+			.args(["-t", "tmpfs", "tmpfs"])
+			.arg(&mount_point)
+			.status()
+			.is_ok_and(|status| status.success());
+		if !mounted {
+			return;
+		}
+		let _unmount = Unmount(&mount_point);
+		fs::File::create(mount_point.join("on_the_mount.txt"))
+			.await
+			.unwrap();
+
+		for cross_filesystems in [true, false] {
+			let walk_result = walk_with(
+				root_path,
+				&[],
+				WalkOptions {
+					cross_filesystems,
+					..Default::default()
+				},
+			)
+			.await
+			.unwrap();
+			assert!(walk_result.errors.is_empty());
+
+			let walked = walk_result.walked.collect::<Vec<_>>();
+			let crossed = walked
+				.iter()
+				.filter(|entry| entry.crossed_mount)
+				.map(|entry| root_path.join(&entry.iso_file_path))
+				.collect::<Vec<_>>();
+			assert_eq!(crossed, [mount_point.clone()]);
+
+			let on_the_mount = walked.iter().any(|entry| {
+				root_path.join(&entry.iso_file_path) == mount_point.join("on_the_mount.txt")
+			});
+			assert_eq!(on_the_mount, cross_filesystems);
+			assert_eq!(
+				walk_result.skipped_mounts,
+				if cross_filesystems {
+					vec![]
+				} else {
+					vec![mount_point.clone()]
+				}
+			);
 		}
 	}
 
@@ -856,6 +2813,7 @@ mod tests {
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 			hidden: false,
+			created_at_is_fallback: false,
 		};
 
 		let f = |path, is_dir| IsolatedFilePathData::new(0, root_path, path, is_dir).unwrap();
@@ -864,10 +2822,10 @@ mod tests {
 
 		#[rustfmt::skip]
 		let expected = [
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo1.png"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo2.jpg"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo3.jpeg"), false), metadata },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo1.png"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo2.jpg"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo3.jpeg"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
 		]
 		.into_iter()
 		.collect::<HashSet<_>>();
@@ -884,19 +2842,9 @@ mod tests {
 			)],
 		)];
 
-		let walk_result = walk(
-			root_path.to_path_buf(),
-			only_photos_rule,
-			|_, _| {},
-			|_| async { Ok(vec![]) },
-			|_, _| async { Ok(vec![]) },
-			|path, is_dir| {
-				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
-			},
-			420,
-		)
-		.await
-		.unwrap();
+		let walk_result = walk_with(root_path, only_photos_rule, WalkOptions::default())
+			.await
+			.unwrap();
 
 		if !walk_result.errors.is_empty() {
 			panic!("errors: {:#?}", walk_result.errors);
@@ -909,6 +2857,67 @@ mod tests {
 		}
 	}
 
+	#[tokio::test]
+	async fn name_rejections_skip_reading_metadata() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		// The paths walked with `rules`, and how many metadata reads they saved
+		async fn walk_with(
+			root_path: &Path,
+			rules: &[IndexerRule],
+		) -> (HashSet<IsolatedFilePathData<'static>>, u64) {
+			let walk_result = walk_with(root_path, rules, WalkOptions::default())
+				.await
+				.unwrap();
+			assert!(walk_result.errors.is_empty());
+
+			(
+				walk_result
+					.walked
+					.map(|entry| entry.iso_file_path)
+					.collect(),
+				walk_result.skipped_metadata_reads,
+			)
+		}
+
+		let no_text_rule = &[IndexerRule::new(
+			"no text".to_string(),
+			false,
+			vec![RulePerKind::RejectFilesByGlob(
+				vec![],
+				GlobSetBuilder::new()
+					.add(Glob::new("*.txt").unwrap())
+					.build()
+					.unwrap(),
+			)],
+		)];
+		assert!(no_text_rule[0].rules[0].is_name_only());
+
+		let (mut expected, skipped) = walk_with(root_path, &[]).await;
+		assert_eq!(skipped, 0);
+
+		let (without_text, skipped) = walk_with(root_path, no_text_rule).await;
+		assert_eq!(skipped, 1);
+
+		// The same entries as when the rule is applied once the metadata was read
+		let text =
+			IsolatedFilePathData::new(0, root_path, root_path.join("photos/text.txt"), false)
+				.unwrap();
+		assert!(expected.remove(&text));
+		assert_eq!(without_text, expected);
+
+		// Still credited to its rule
+		assert!(matches!(
+			IndexerRule::snapshot_and_reset(no_text_rule).as_slice(),
+			[RuleHits {
+				accepted: 0,
+				rejected: 1,
+				..
+			}]
+		));
+	}
+
 	#[tokio::test]
 	// #[traced_test]
 	async fn test_git_repos() {
@@ -921,6 +2930,7 @@ mod tests {
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 			hidden: false,
+			created_at_is_fallback: false,
 		};
 
 		let f = |path, is_dir| IsolatedFilePathData::new(0, root_path, path, is_dir).unwrap();
@@ -929,23 +2939,23 @@ mod tests {
 
 		#[rustfmt::skip]
 		let expected = [
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/.git"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/Cargo.toml"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src/main.rs"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug/main"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/.git"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/package.json"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src/App.tsx"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react/package.json"), false), metadata },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/Cargo.toml"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src/main.rs"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug/main"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/package.json"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src/App.tsx"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react/package.json"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
 		]
 		.into_iter()
 		.collect::<HashSet<_>>();
@@ -958,19 +2968,9 @@ mod tests {
 			)],
 		)];
 
-		let walk_result = walk(
-			root_path.to_path_buf(),
-			git_repos,
-			|_, _| {},
-			|_| async { Ok(vec![]) },
-			|_, _| async { Ok(vec![]) },
-			|path, is_dir| {
-				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
-			},
-			420,
-		)
-		.await
-		.unwrap();
+		let walk_result = walk_with(root_path, git_repos, WalkOptions::default())
+			.await
+			.unwrap();
 
 		if !walk_result.errors.is_empty() {
 			panic!("errors: {:#?}", walk_result.errors);
@@ -995,6 +2995,7 @@ mod tests {
 			created_at: Utc::now(),
 			modified_at: Utc::now(),
 			hidden: false,
+			created_at_is_fallback: false,
 		};
 
 		let f = |path, is_dir| IsolatedFilePathData::new(0, root_path, path, is_dir).unwrap();
@@ -1003,17 +3004,17 @@ mod tests {
 
 		#[rustfmt::skip]
 		let expected = [
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/.git"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/Cargo.toml"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src/main.rs"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/.git"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/package.json"), false), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src"), true), metadata },
-			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src/App.tsx"), false), metadata },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/Cargo.toml"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src/main.rs"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/package.json"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src/App.tsx"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
 		]
 		.into_iter()
 		.collect::<HashSet<_>>();
@@ -1050,16 +3051,10 @@ mod tests {
 			),
 		];
 
-		let walk_result = walk(
-			root_path.to_path_buf(),
+		let walk_result = walk_with(
+			root_path,
 			git_repos_no_deps_no_build_dirs,
-			|_, _| {},
-			|_| async { Ok(vec![]) },
-			|_, _| async { Ok(vec![]) },
-			|path, is_dir| {
-				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
-			},
-			420,
+			WalkOptions::default(),
 		)
 		.await
 		.unwrap();
@@ -1073,5 +3068,1929 @@ mod tests {
 		if actual != expected {
 			panic!("difference: {:#?}", expected.difference(&actual));
 		}
+
+		let hits = IndexerRule::snapshot_and_reset(git_repos_no_deps_no_build_dirs)
+			.into_iter()
+			.map(|hits| (hits.name, (hits.accepted, hits.rejected)))
+			.collect::<HashMap<_, _>>();
+		// Only the `node_modules` directory itself, its contents aren't walked
+		assert_eq!(hits["reject node_modules"], (0, 1));
+		assert_eq!(hits["reject rust build dir"], (0, 1));
+		// The git repositories, and the directories without one at the root of the location
+		assert_eq!(hits["git repos"], (2, 2));
+
+		assert!(
+			IndexerRule::snapshot_and_reset(git_repos_no_deps_no_build_dirs)
+				.iter()
+				.all(|hits| hits.accepted == 0 && hits.rejected == 0)
+		);
+	}
+
+	#[tokio::test]
+	async fn scoped_rules_only_apply_below_their_scope() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let metadata = FilePathMetadata {
+			inode: 0,
+			size_in_bytes: 0,
+			created_at: Utc::now(),
+			modified_at: Utc::now(),
+			hidden: false,
+			created_at_is_fallback: false,
+		};
+
+		let f = |path, is_dir| IsolatedFilePathData::new(0, root_path, path, is_dir).unwrap();
+		let pub_id = Uuid::new_v4();
+		let maybe_object_id = None;
+
+		#[rustfmt::skip]
+		let expected = [
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/Cargo.toml"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/src/main.rs"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("rust_project/target/debug/main"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/.git"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/package.json"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/src/App.tsx"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("inner/node_project/node_modules/react"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos"), true), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo1.png"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo2.jpg"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/photo3.jpeg"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+			WalkedEntry { pub_id, maybe_object_id, iso_file_path: f(root_path.join("photos/text.txt"), false), metadata, name_bytes: None, is_location_boundary: false, is_placeholder: false, discovery_seq: None, crossed_mount: false },
+		]
+		.into_iter()
+		.collect::<HashSet<_>>();
+
+		let scoped_rules = &[IndexerRule {
+			scope: Some("inner/node_project/node_modules".into()),
+			..IndexerRule::new(
+				"no package.json in dependencies".to_string(),
+				false,
+				vec![RulePerKind::RejectFilesByGlob(
+					vec![],
+					GlobSetBuilder::new()
+						.add(Glob::new("**/package.json").unwrap())
+						.build()
+						.unwrap(),
+				)],
+			)
+		}];
+
+		let walk_result = walk_with(root_path, scoped_rules, WalkOptions::default())
+			.await
+			.unwrap();
+
+		if !walk_result.errors.is_empty() {
+			panic!("errors: {:#?}", walk_result.errors);
+		}
+
+		let actual = walk_result.walked.collect::<HashSet<_>>();
+
+		if actual != expected {
+			panic!("difference: {:#?}", expected.symmetric_difference(&actual));
+		}
+
+		// Scopes are relative to the location's root, even when only one of its sub paths is walked
+		let inner = root_path.join("inner");
+		let walk_result = walk(
+			&inner,
+			Uuid::new_v4(),
+			scoped_rules,
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
+			|path, is_dir| {
+				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+			},
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions {
+				location_root: Some(root_path),
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		assert!(walk_result.errors.is_empty());
+
+		let actual = walk_result.walked.collect::<HashSet<_>>();
+		let expected = expected
+			.into_iter()
+			.filter(|entry| {
+				entry
+					.iso_file_path
+					.to_parts()
+					.materialized_path
+					.starts_with("/inner/")
+			})
+			.collect::<HashSet<_>>();
+		if actual != expected {
+			panic!("difference: {:#?}", expected.symmetric_difference(&actual));
+		}
+	}
+
+	#[tokio::test]
+	async fn deep_accepted_ancestors_come_before_their_children() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let only_app_rule = &[IndexerRule::new(
+			"only App.tsx".to_string(),
+			false,
+			vec![RulePerKind::AcceptFilesByGlob(
+				vec![],
+				GlobSetBuilder::new()
+					.add(Glob::new("**/App.tsx").unwrap())
+					.build()
+					.unwrap(),
+			)],
+		)];
+
+		let walk_result = walk_with(root_path, only_app_rule, WalkOptions::default())
+			.await
+			.unwrap();
+
+		if !walk_result.errors.is_empty() {
+			panic!("errors: {:#?}", walk_result.errors);
+		}
+
+		assert_eq!(
+			walk_result
+				.walked
+				.map(|entry| root_path.join(&entry.iso_file_path))
+				.collect::<Vec<_>>(),
+			vec![
+				root_path.join("inner"),
+				root_path.join("inner/node_project"),
+				root_path.join("inner/node_project/src"),
+				root_path.join("inner/node_project/src/App.tsx"),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn accepted_files_dont_bring_back_rejected_ancestors() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let rules = &[
+			IndexerRule::new(
+				"reject node_modules".to_string(),
+				false,
+				vec![RulePerKind::RejectFilesByGlob(
+					vec![],
+					GlobSetBuilder::new()
+						.add(Glob::new("**/node_modules").unwrap())
+						.build()
+						.unwrap(),
+				)],
+			),
+			IndexerRule::new(
+				"react package.json".to_string(),
+				false,
+				vec![RulePerKind::AcceptFilesByGlob(
+					vec![],
+					GlobSetBuilder::new()
+						.add(Glob::new("**/node_modules/react/package.json").unwrap())
+						.build()
+						.unwrap(),
+				)],
+			),
+		];
+
+		let walk_result = walk_with(root_path, rules, WalkOptions::default())
+			.await
+			.unwrap();
+
+		if !walk_result.errors.is_empty() {
+			panic!("errors: {:#?}", walk_result.errors);
+		}
+
+		let node_modules = root_path.join("inner/node_project/node_modules");
+		let under_node_modules = walk_result
+			.walked
+			.map(|entry| root_path.join(&entry.iso_file_path))
+			.filter(|path| path.starts_with(&node_modules))
+			.collect::<Vec<_>>();
+		assert!(
+			under_node_modules.is_empty(),
+			"rejected directory came back: {under_node_modules:#?}"
+		);
+
+		// The reject rule wins, nothing below `node_modules` is even looked at
+		let hits = IndexerRule::snapshot_and_reset(rules)
+			.into_iter()
+			.map(|hits| (hits.name, (hits.accepted, hits.rejected)))
+			.collect::<HashMap<_, _>>();
+		assert_eq!(hits["reject node_modules"], (0, 1));
+		assert_eq!(hits["react package.json"].0, 0);
+	}
+
+	#[tokio::test]
+	async fn single_paths_are_evaluated_like_the_walker_does() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let git_repos_no_deps_no_build_dirs = &[
+			IndexerRule::new(
+				"git repos".to_string(),
+				false,
+				vec![RulePerKind::AcceptIfChildrenDirectoriesArePresent(
+					[".git".to_string()].into_iter().collect(),
+				)],
+			),
+			IndexerRule::new(
+				"reject node_modules".to_string(),
+				false,
+				vec![RulePerKind::RejectFilesByGlob(
+					vec![],
+					GlobSetBuilder::new()
+						.add(Glob::new("{**/node_modules/*,**/node_modules}").unwrap())
+						.build()
+						.unwrap(),
+				)],
+			),
+			IndexerRule::new(
+				"reject rust build dir".to_string(),
+				false,
+				vec![RulePerKind::RejectFilesByGlob(
+					vec![],
+					GlobSetBuilder::new()
+						.add(Glob::new("{**/target/*,**/target}").unwrap())
+						.build()
+						.unwrap(),
+				)],
+			),
+		];
+
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+
+		let walk_result = walk(
+			root_path.to_path_buf(),
+			Uuid::new_v4(),
+			git_repos_no_deps_no_build_dirs,
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
+			iso_file_path_factory,
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions::default(),
+		)
+		.await
+		.unwrap();
+
+		if !walk_result.errors.is_empty() {
+			panic!("errors: {:#?}", walk_result.errors);
+		}
+
+		let walked = walk_result
+			.walked
+			.map(|entry| entry.iso_file_path)
+			.collect::<HashSet<_>>();
+
+		// As if the watcher got a modify event for a file the walker rejected
+		let rejected = root_path.join("rust_project/target/debug/main");
+		assert!(!walked.contains(&iso_file_path_factory(&rejected, false).unwrap()));
+		assert_eq!(
+			evaluate_single_path(
+				root_path,
+				&rejected,
+				git_repos_no_deps_no_build_dirs,
+				iso_file_path_factory
+			)
+			.await
+			.unwrap(),
+			None
+		);
+
+		// And both agree on every other file and directory too
+		let mut to_check = vec![root_path.to_path_buf()];
+		let mut files_checked = 0;
+		let mut walked_through_and_indexed = 0;
+		while let Some(dir) = to_check.pop() {
+			let mut read_dir = fs::read_dir(&dir).await.unwrap();
+			while let Some(entry) = read_dir.next_entry().await.unwrap() {
+				let path = entry.path();
+				if entry.file_type().await.unwrap().is_dir() {
+					let accepted = evaluate_single_path(
+						root_path,
+						&path,
+						git_repos_no_deps_no_build_dirs,
+						iso_file_path_factory,
+					)
+					.await
+					.unwrap()
+					.is_some();
+					let walked_through =
+						is_only_walked_through(root_path, &path, git_repos_no_deps_no_build_dirs)
+							.await
+							.unwrap();
+					let indexed = walked.contains(&iso_file_path_factory(&path, true).unwrap());
+					assert!(!(accepted && walked_through), "{path:?}");
+					// The ones only walked through are indexed when something within them is accepted,
+					// which is how the watcher gets to them too
+					if walked_through {
+						walked_through_and_indexed += usize::from(indexed);
+					} else {
+						assert_eq!(accepted, indexed, "{path:?}");
+					}
+
+					to_check.push(path);
+					continue;
+				}
+
+				files_checked += 1;
+				assert_eq!(
+					evaluate_single_path(
+						root_path,
+						&path,
+						git_repos_no_deps_no_build_dirs,
+						iso_file_path_factory
+					)
+					.await
+					.unwrap()
+					.is_some(),
+					walked.contains(&iso_file_path_factory(&path, false).unwrap()),
+					"{path:?}"
+				);
+			}
+		}
+		assert_eq!(files_checked, 10);
+		assert!(walked_through_and_indexed > 0);
+	}
+
+	#[tokio::test]
+	async fn concurrent_walks_keep_their_walk_ids() {
+		let (first, second) = (prepare_location().await, prepare_location().await);
+
+		async fn walk_ids(root: &Path, walk_id: Uuid) -> Vec<Uuid> {
+			let iso_file_path_factory = |path: &Path, is_dir| {
+				IsolatedFilePathData::new(0, root, path, is_dir).map_err(Into::into)
+			};
+
+			let WalkResult {
+				walk_id: result_walk_id,
+				mut to_walk,
+				..
+			} = walk(
+				root,
+				walk_id,
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				iso_file_path_factory,
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				WalkOptions {
+					limit: 1,
+					..Default::default()
+				},
+			)
+			.await
+			.unwrap();
+
+			let mut walk_ids = vec![result_walk_id];
+			while let Some(entry) = to_walk.pop_front() {
+				walk_ids.push(entry.walk_id);
+
+				let result = keep_walking(
+					&entry,
+					&[],
+					|_, _| {},
+					|_| async { Ok(vec![]) },
+					|_, _| async { Ok(vec![]) },
+					|_| async { Ok(None) },
+					iso_file_path_factory,
+					&WalkerMemory::default(),
+					&IoTokenBucket::default(),
+					false,
+					&[],
+					ComparisonCaps::default(),
+					None,
+					false,
+					None,
+				)
+				.await
+				.unwrap();
+				walk_ids.push(result.walk_id);
+				to_walk.extend(result.to_walk);
+			}
+
+			walk_ids
+		}
+
+		let (first_id, second_id) = (Uuid::new_v4(), Uuid::new_v4());
+		let (first_ids, second_ids) = tokio::join!(
+			walk_ids(first.path(), first_id),
+			walk_ids(second.path(), second_id)
+		);
+
+		assert!(first_ids.len() > 1);
+		assert!(first_ids.iter().all(|walk_id| *walk_id == first_id));
+		assert!(second_ids.len() > 1);
+		assert!(second_ids.iter().all(|walk_id| *walk_id == second_id));
+	}
+
+	#[tokio::test]
+	async fn vanished_entries_are_not_errors() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+
+		fs::File::create(root_path.join("first.tmp")).await.unwrap();
+		fs::File::create(root_path.join("second.tmp"))
+			.await
+			.unwrap();
+
+		// The notifier is called with the second entry, after the directory was read
+		// but before its metadata is, so it stands in for whatever deleted the file
+		let mut deleted = None;
+		let WalkResult {
+			walked,
+			errors,
+			vanished,
+			..
+		} = walk(
+			root_path.to_path_buf(),
+			Uuid::new_v4(),
+			&[],
+			|path, _| {
+				std::fs::remove_file(path).unwrap();
+				deleted = Some(path.to_path_buf());
+			},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
+			|path, is_dir| {
+				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+			},
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions::default(),
+		)
+		.await
+		.unwrap();
+
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		assert_eq!(vanished, 1);
+
+		let walked = walked.collect::<Vec<_>>();
+		assert_eq!(walked.len(), 1);
+		assert_ne!(Some(root_path.join(&walked[0].iso_file_path)), deleted,);
+	}
+
+	#[tokio::test]
+	async fn walking_pauses_while_over_the_memory_budget() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+
+		for dir in 0..10 {
+			let dir_path = root_path.join(format!("dir{dir}"));
+			fs::create_dir(&dir_path).await.unwrap();
+			for file in 0..100 {
+				fs::File::create(dir_path.join(format!("file{file}.txt")))
+					.await
+					.unwrap();
+			}
+		}
+
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+
+		let walk_with_budget = |memory: WalkerMemory| async move {
+			let WalkResult {
+				walked, to_walk, ..
+			} = walk(
+				root_path.to_path_buf(),
+				Uuid::new_v4(),
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				iso_file_path_factory,
+				&memory,
+				&IoTokenBucket::default(),
+				WalkOptions {
+					limit: u64::MAX,
+					..Default::default()
+				},
+			)
+			.await
+			.unwrap();
+
+			(walked.collect::<Vec<_>>(), to_walk, memory)
+		};
+		let keep_walking_with = |to_walk_entry, memory| async move {
+			let WalkResult {
+				walked, to_walk, ..
+			} = keep_walking(
+				to_walk_entry,
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				iso_file_path_factory,
+				memory,
+				&IoTokenBucket::default(),
+				false,
+				&[],
+				ComparisonCaps::default(),
+				None,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+
+			(walked.count(), to_walk)
+		};
+
+		let (walked, left_to_walk, memory) = walk_with_budget(WalkerMemory::default()).await;
+		assert_eq!(walked.len(), 10 * 100 + 10);
+		assert!(left_to_walk.is_empty());
+		assert_eq!(
+			memory.used(),
+			walked.iter().map(WalkedEntry::estimated_size).sum::<u64>()
+		);
+
+		// Stops right after the root, leaving every sub directory to later steps
+		let (walked, left_to_walk, memory) =
+			walk_with_budget(WalkerMemory::new(Arc::default(), 1)).await;
+		assert_eq!(walked.len(), 10);
+		assert_eq!(left_to_walk.len(), 10);
+
+		// Later steps hand their directory back while the found entries weren't written
+		assert!(memory.is_over_budget());
+		let (found, handed_back) = keep_walking_with(&left_to_walk[0], &memory).await;
+		assert_eq!(found, 0);
+		assert!(
+			matches!(&handed_back.iter().collect::<Vec<_>>()[..], [entry] if entry.path == left_to_walk[0].path)
+		);
+
+		// And carry on once they were
+		memory.release(walked.iter().map(WalkedEntry::estimated_size).sum());
+		assert!(!memory.is_over_budget());
+		let (found, to_walk) = keep_walking_with(&left_to_walk[0], &memory).await;
+		assert_eq!(found, 100);
+		assert!(to_walk.is_empty());
+	}
+
+	#[tokio::test]
+	async fn directories_are_persisted_before_their_children() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+
+		// Stands in for the database, the indexer job writes each walk's entries before walking deeper
+		let mut persisted = HashSet::new();
+		let mut peak_entries = 0;
+		let mut persist = |walked: Vec<WalkedEntry>| {
+			peak_entries = peak_entries.max(walked.len());
+			for entry in walked {
+				let path = root_path.join(&entry.iso_file_path);
+				let parent = path.parent().unwrap();
+				assert!(
+					parent == root_path || persisted.contains(parent),
+					"{path:?} was persisted before its parent"
+				);
+				persisted.insert(path);
+			}
+		};
+
+		let WalkResult {
+			walked,
+			mut to_walk,
+			errors,
+			..
+		} = walk(
+			root_path.to_path_buf(),
+			Uuid::new_v4(),
+			&[],
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
+			iso_file_path_factory,
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions {
+				limit: 1,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		persist(walked.collect());
+
+		// The rest is walked a directory at a time, like the job's walk steps
+		while let Some(entry) = to_walk.pop_front() {
+			let WalkResult {
+				walked,
+				to_walk: more_to_walk,
+				errors,
+				..
+			} = keep_walking(
+				&entry,
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				iso_file_path_factory,
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				false,
+				&[],
+				ComparisonCaps::default(),
+				None,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+			persist(walked.collect());
+			to_walk.extend(more_to_walk);
+		}
+
+		// Every entry made it in but no more than the largest directory was ever held at once
+		assert_eq!(persisted.len(), 22);
+		assert_eq!(peak_entries, 4);
+	}
+
+	#[tokio::test]
+	async fn entries_dropped_on_errors_are_not_removed() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+
+		for file in ["fine.txt", "flaky.txt"] {
+			fs::File::create(root_path.join(file)).await.unwrap();
+		}
+		FAILING_METADATA.with(|failing| failing.borrow_mut().insert(root_path.join("flaky.txt")));
+
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+
+		// Stands in for the database, which has a row for every file and one deleted since last time
+		let rows = ["fine.txt", "flaky.txt", "deleted.txt"]
+			.into_iter()
+			.zip(1..)
+			.map(|(file, id)| {
+				(
+					iso_file_path_factory(&root_path.join(file), false).unwrap(),
+					id,
+				)
+			})
+			.collect::<Vec<_>>();
+		let rows = &rows;
+
+		let WalkResult {
+			to_remove, errors, ..
+		} = walk(
+			root_path.to_path_buf(),
+			Uuid::new_v4(),
+			&[],
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, kept: Vec<IsolatedFilePathData<'static>>| async move {
+				Ok(rows
+					.iter()
+					.filter(|(iso_file_path, _)| !kept.contains(iso_file_path))
+					.map(|(_, id)| {
+						file_path_pub_and_cas_ids::Data {
+							id: *id,
+							pub_id: vec![],
+							cas_id: None,
+						}
+						.into()
+					})
+					.collect())
+			},
+			|_| async { Ok(None) },
+			iso_file_path_factory,
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions::default(),
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(errors.len(), 1, "errors: {errors:#?}");
+		assert_eq!(
+			to_remove.map(|file_path| file_path.id).collect::<Vec<_>>(),
+			[3]
+		);
+	}
+
+	#[tokio::test]
+	async fn only_directories_gone_from_disk_are_removed_with_their_subtrees() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		for dir in ["foobar", "ignored"] {
+			fs::create_dir(root_path.join(dir)).await.unwrap();
+		}
+		fs::File::create(root_path.join("foobar/photo.png"))
+			.await
+			.unwrap();
+
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+
+		// Stands in for the database as `to_remove_db_fetcher_fn!` does. `foo` was deleted with
+		// everything in it, `foobar` is still there and `ignored` is too but the rules reject it now.
+		let rows = [
+			(1, "foo", true),
+			(2, "foobar", true),
+			(3, "ignored", true),
+			(4, "notes", false),
+		]
+		.map(
+			|(id, name, is_dir)| super::super::file_path_to_remove::Data {
+				id,
+				pub_id: vec![],
+				cas_id: None,
+				pinned: false,
+				is_dir: Some(is_dir),
+				materialized_path: Some("/".to_string()),
+				name: Some(name.to_string()),
+				extension: Some(if is_dir { "" } else { "txt" }.to_string()),
+			},
+		)
+		.to_vec();
+		let rows = &rows;
+
+		let rules = &[IndexerRule::new(
+			"ignored".to_string(),
+			false,
+			vec![RulePerKind::RejectFilesByGlob(
+				vec![],
+				GlobSetBuilder::new()
+					.add(Glob::new("**/ignored").unwrap())
+					.build()
+					.unwrap(),
+			)],
+		)];
+
+		let WalkResult {
+			to_remove,
+			to_remove_subtrees,
+			errors,
+			..
+		} = walk(
+			root_path.to_path_buf(),
+			Uuid::new_v4(),
+			rules,
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|parent: IsolatedFilePathData<'static>, kept: Vec<IsolatedFilePathData<'static>>| async move {
+				// Only the root has rows
+				if !parent.is_root() {
+					return Ok(vec![]);
+				}
+
+				let not_kept = rows
+					.iter()
+					.filter(|row| {
+						let name = row.name.as_deref().unwrap();
+						!kept.iter().any(|kept| kept.to_parts().name == name)
+					})
+					.cloned()
+					.collect();
+				Ok(super::super::removable_file_paths(0, not_kept, &HashSet::new()).collect())
+			},
+			|_| async { Ok(None) },
+			iso_file_path_factory,
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions::default(),
+		)
+		.await
+		.unwrap();
+
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		assert_eq!(
+			to_remove.map(|file_path| file_path.id).collect::<Vec<_>>(),
+			[1, 3, 4]
+		);
+		// `ignored` may still have rows below it, but nothing took them away from the disk
+		assert_eq!(
+			to_remove_subtrees
+				.iter()
+				.filter_map(IsolatedFilePathData::materialized_path_for_children)
+				.collect::<Vec<_>>(),
+			["/foo/"]
+		);
+	}
+
+	#[tokio::test]
+	async fn pinned_file_paths_survive_a_walk_of_an_empty_directory() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+
+		// Stands in for the database as `to_remove_db_fetcher_fn!` does, with rows for files
+		// which aren't there anymore, as if the drive was briefly unmounted
+		let rows = [(1, false), (2, true), (3, false)]
+			.map(|(id, pinned)| super::super::file_path_to_remove::Data {
+				id,
+				pub_id: vec![],
+				cas_id: None,
+				pinned,
+				is_dir: Some(false),
+				materialized_path: Some("/".to_string()),
+				name: Some(format!("file_{id}")),
+				extension: Some("txt".to_string()),
+			})
+			.to_vec();
+		let rows = &rows;
+
+		let WalkResult {
+			to_remove, errors, ..
+		} = walk(
+			root_path.to_path_buf(),
+			Uuid::new_v4(),
+			&[],
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, kept: Vec<IsolatedFilePathData<'static>>| async move {
+				assert!(kept.is_empty());
+				Ok(super::super::removable_file_paths(0, rows.clone(), &HashSet::new()).collect())
+			},
+			|_| async { Ok(None) },
+			|path: &Path, is_dir| {
+				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+			},
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions::default(),
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(
+			to_remove.map(|file_path| file_path.id).collect::<Vec<_>>(),
+			[1, 3]
+		);
+		// Kept, but the missing file is reported
+		assert!(
+			matches!(
+				&errors[..],
+				[IndexerError::PinnedFileMissing { path }] if *path == root_path.join("file_2.txt")
+			),
+			"errors: {errors:#?}"
+		);
+	}
+
+	#[tokio::test]
+	async fn recently_modified_files_are_deferred_within_the_window() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+
+		let export = root_path.join("export.mov");
+		fs::write(&export, b"first frames").await.unwrap();
+		// Touched again, as an export in progress keeps doing
+		fs::write(&export, b"more frames").await.unwrap();
+		let export_iso = &IsolatedFilePathData::new(0, root_path, &export, false).unwrap();
+
+		for (window, deferred) in [
+			(time::Duration::from_secs(60 * 60), true),
+			(time::Duration::ZERO, false),
+		] {
+			let WalkResult {
+				walked,
+				to_remove,
+				errors,
+				deferred_recent,
+				..
+			} = walk(
+				root_path.to_path_buf(),
+				Uuid::new_v4(),
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				// Stands in for the database, which has a row for the export from a previous walk
+				|_, found: Vec<IsolatedFilePathData<'static>>| async move {
+					Ok(if found.contains(export_iso) {
+						vec![]
+					} else {
+						vec![file_path_pub_and_cas_ids::Data {
+							id: 1,
+							pub_id: vec![],
+							cas_id: None,
+						}
+						.into()]
+					})
+				},
+				|_| async { Ok(None) },
+				|path: &Path, is_dir| {
+					IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+				},
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				WalkOptions {
+					defer_recently_modified: Some(window),
+					..Default::default()
+				},
+			)
+			.await
+			.unwrap();
+
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+			assert_eq!(to_remove.count(), 0, "window: {window:?}");
+
+			let walked = walked
+				.map(|entry| entry.iso_file_path)
+				.filter(|iso_file_path| !iso_file_path.to_parts().is_dir)
+				.collect::<Vec<_>>();
+			if deferred {
+				assert_eq!(deferred_recent, [export.clone()]);
+				assert!(walked.is_empty(), "walked: {walked:#?}");
+			} else {
+				assert!(deferred_recent.is_empty());
+				assert_eq!(walked, [export_iso.clone()]);
+			}
+		}
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn non_utf8_names_are_indexed_and_stay_put() {
+		use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+
+		let invalid = root_path.join(OsStr::from_bytes(b"photo\xFF.png"));
+		for path in [&invalid, &root_path.join("fine.txt")] {
+			fs::File::create(path).await.unwrap();
+		}
+
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+
+		// Stands in for the database, with a row for everything the previous walk found
+		let rows = &RefCell::new(Vec::<IsolatedFilePathData<'static>>::new());
+
+		let mut walks = Vec::new();
+		for _ in 0..2 {
+			let WalkResult {
+				walked,
+				to_remove,
+				errors,
+				..
+			} = walk(
+				root_path.to_path_buf(),
+				Uuid::new_v4(),
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, found: Vec<IsolatedFilePathData<'static>>| async move {
+					Ok(rows
+						.borrow()
+						.iter()
+						.zip(1..)
+						.filter(|(iso_file_path, _)| !found.contains(iso_file_path))
+						.map(|(_, id)| {
+							file_path_pub_and_cas_ids::Data {
+								id,
+								pub_id: vec![],
+								cas_id: None,
+							}
+							.into()
+						})
+						.collect())
+				},
+				|_| async { Ok(None) },
+				iso_file_path_factory,
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				WalkOptions::default(),
+			)
+			.await
+			.unwrap();
+
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+			assert_eq!(to_remove.count(), 0);
+
+			let walked = walked.collect::<Vec<_>>();
+			*rows.borrow_mut() = walked
+				.iter()
+				.map(|entry| entry.iso_file_path.clone())
+				.collect();
+			walks.push(walked);
+		}
+
+		let entry = walks[0]
+			.iter()
+			.find(|entry| entry.name_bytes.is_some())
+			.unwrap();
+		assert_eq!(entry.iso_file_path.to_parts().name, "photo\u{FFFD}");
+		assert_eq!(entry.name_bytes.as_deref(), Some(&b"photo\xFF.png"[..]));
+
+		assert_eq!(
+			walks[0].iter().collect::<HashSet<_>>(),
+			walks[1].iter().collect::<HashSet<_>>()
+		);
+		assert_eq!(walks[0].len(), 2);
+	}
+
+	#[tokio::test]
+	async fn throttled_walks_take_longer_but_find_the_same() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let walk_with = |throttle: IoTokenBucket| async move {
+			let started_at = std::time::Instant::now();
+			let WalkResult { walked, errors, .. } = walk(
+				root_path.to_path_buf(),
+				Uuid::new_v4(),
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				|path, is_dir| {
+					IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+				},
+				&WalkerMemory::default(),
+				&throttle,
+				WalkOptions::default(),
+			)
+			.await
+			.unwrap();
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+
+			(walked.collect::<HashSet<_>>(), started_at.elapsed())
+		};
+
+		let (unthrottled, _) = walk_with(IoTokenBucket::default()).await;
+		// The fixture takes about 70 operations, reading its directories and their entries' metadata
+		let (throttled, elapsed) =
+			walk_with(IoTokenBucket::new(Some(IoThrottle { ops_per_sec: 100 }))).await;
+
+		assert_eq!(throttled, unthrottled);
+		assert!(
+			elapsed >= std::time::Duration::from_millis(500),
+			"throttled walk took {elapsed:?}"
+		);
+	}
+
+	#[tokio::test]
+	async fn unchanged_directories_skip_the_database() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		// Stand in for the stored fingerprints and count how often `file_path`s are fetched
+		let stored = &RefCell::new(HashMap::new());
+		let fetches = &Cell::new(0);
+
+		let walk_again = || async move {
+			let WalkResult {
+				walked,
+				errors,
+				paths_and_sizes,
+				fingerprints,
+				found_entries,
+				..
+			} = walk(
+				root_path.to_path_buf(),
+				Uuid::new_v4(),
+				&[],
+				|_, _| {},
+				|_| {
+					fetches.set(fetches.get() + 1);
+					async { Ok(vec![]) }
+				},
+				|_, _| async { Ok(vec![]) },
+				|iso_file_path: IsolatedFilePathData<'static>| {
+					let fingerprint = iso_file_path.materialized_path_for_children().and_then(
+						|materialized_path| stored.borrow().get(&materialized_path).cloned(),
+					);
+					async move { Ok(fingerprint) }
+				},
+				|path, is_dir| {
+					IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+				},
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				WalkOptions::default(),
+			)
+			.await
+			.unwrap();
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+
+			stored.borrow_mut().extend(fingerprints.into_iter().map(
+				|DirectoryFingerprint {
+				     materialized_path,
+				     fingerprint,
+				 }| (materialized_path, fingerprint),
+			));
+
+			(walked.count(), paths_and_sizes, found_entries)
+		};
+
+		let (first_walked, first_sizes, first_found) = walk_again().await;
+		assert_eq!(first_walked, 22);
+		assert_eq!(fetches.get(), 1);
+
+		// What wasn't compared with the database was still found
+		let (second_walked, second_sizes, second_found) = walk_again().await;
+		assert_eq!(second_walked, 0);
+		assert_eq!(fetches.get(), 1);
+		assert_eq!(second_sizes, first_sizes);
+		assert_eq!(second_found, first_found);
+
+		// A new file only brings back the database work for its directory and, as the directory's
+		// modification date changed, its parent. As the mock database is empty, all their entries
+		// look new: the 3 directories in the root and the 5 photos
+		fs::File::create(root_path.join("photos/photo4.png"))
+			.await
+			.unwrap();
+		let (third_walked, _, _) = walk_again().await;
+		assert_eq!(third_walked, 8);
+		assert_eq!(fetches.get(), 2);
+	}
+
+	#[tokio::test]
+	async fn walks_stop_when_the_root_is_on_another_device() {
+		use crate::location::metadata::SpacedriveLocationMetadataFile;
+
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let device = get_device_from_path(root_path).await.unwrap();
+		let location_pub_id = Uuid::new_v4();
+
+		let walk_on = |device| async move {
+			walk_with(
+				root_path,
+				&[],
+				WalkOptions {
+					expected_device: Some(RootDevice {
+						device,
+						location_pub_id,
+					}),
+					..Default::default()
+				},
+			)
+			.await
+			.map(|WalkResult { walked, .. }| walked.count())
+		};
+
+		assert!(walk_on(device).await.unwrap() > 0);
+
+		match walk_on(device.wrapping_add(1)).await {
+			Err(IndexerError::WrongDevice { expected, found }) => {
+				assert_eq!(expected, device.wrapping_add(1));
+				assert_eq!(found, device);
+			}
+			other => panic!("expected the walk to stop, got {:?}", other.map(|_| ())),
+		}
+
+		// The same volume can come back with another device id, the location's `.spacedrive` file
+		// at its root tells it's still the one it was added on
+		SpacedriveLocationMetadataFile::create_and_save(
+			Uuid::new_v4(),
+			location_pub_id,
+			root_path,
+			"test".to_string(),
+		)
+		.await
+		.unwrap();
+		assert!(walk_on(device.wrapping_add(1)).await.unwrap() > 0);
+	}
+
+	#[tokio::test]
+	async fn walks_stop_when_another_location_has_the_same_root() {
+		use crate::location::{ActiveLocationRoots, RootClaim};
+
+		let root = prepare_location().await;
+		let roots = ActiveLocationRoots::default();
+		let library_id = Uuid::new_v4();
+
+		let walk_as = |root_path: PathBuf, location_id| {
+			let roots = &roots;
+			async move {
+				walk_with(
+					&root_path,
+					&[],
+					WalkOptions {
+						root_claim: Some(RootClaim {
+							roots,
+							library_id,
+							location_id,
+						}),
+						..Default::default()
+					},
+				)
+				.await
+				.map(|WalkResult { walked, .. }| walked.count())
+			}
+		};
+
+		assert!(walk_as(root.path().to_path_buf(), 1).await.unwrap() > 0);
+		// Walking the first location again is fine
+		assert!(walk_as(root.path().to_path_buf(), 1).await.unwrap() > 0);
+
+		// Roots are told apart by their `(device, inode)`, so this is like the same directory mounted at another path
+		match walk_as(root.path().to_path_buf(), 2).await {
+			Err(IndexerError::DuplicateLocation { other_location_id }) => {
+				assert_eq!(other_location_id, 1);
+			}
+			other => panic!("expected the walk to stop, got {:?}", other.map(|_| ())),
+		}
+
+		// Until the first location is removed
+		roots.release(library_id, 1);
+		assert!(walk_as(root.path().to_path_buf(), 2).await.unwrap() > 0);
+	}
+
+	#[tokio::test]
+	async fn walks_stop_at_the_max_depth() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		let nested = |depth| (0..depth).fold(root_path.to_path_buf(), |path, _| path.join("d"));
+		fs::create_dir_all(nested(600)).await.unwrap();
+
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+		let memory = WalkerMemory::default();
+		let throttle = IoTokenBucket::default();
+
+		// Like the indexer job, a directory per walk once past the root
+		let WalkResult {
+			walked,
+			mut to_walk,
+			mut errors,
+			mut max_depth_reached,
+			..
+		} = walk(
+			root_path,
+			Uuid::new_v4(),
+			&[],
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			|_| async { Ok(None) },
+			iso_file_path_factory,
+			&memory,
+			&throttle,
+			WalkOptions {
+				limit: 1,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		let mut found = walked.count();
+
+		while let Some(to_walk_entry) = to_walk.pop_front() {
+			let result = keep_walking(
+				&to_walk_entry,
+				&[],
+				|_, _| {},
+				|_| async { Ok(vec![]) },
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				iso_file_path_factory,
+				&memory,
+				&throttle,
+				false,
+				&[],
+				ComparisonCaps::default(),
+				None,
+				false,
+				None,
+			)
+			.await
+			.unwrap();
+
+			found += result.walked.count();
+			to_walk.extend(result.to_walk);
+			errors.extend(result.errors);
+			max_depth_reached = max_depth_reached.max(result.max_depth_reached);
+		}
+
+		// The directory at the max depth is walked, its sub directory only indexed
+		let max_depth = DEFAULT_MAX_WALK_DEPTH;
+		assert_eq!(found, max_depth as usize + 1);
+		assert_eq!(max_depth_reached, max_depth);
+		assert!(
+			matches!(
+				&errors[..],
+				[IndexerError::MaxDepthExceeded { path, depth }]
+					if *path == nested(max_depth) && *depth == max_depth + 1
+			),
+			"{errors:#?}"
+		);
+	}
+
+	#[tokio::test]
+	async fn sub_path_walks_count_their_depth_from_the_location_root() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let inner = root_path.join("inner");
+		let node_project = inner.join("node_project");
+
+		let WalkResult {
+			walked,
+			to_walk,
+			errors,
+			max_depth_reached,
+			..
+		} = walk_with(
+			&inner,
+			&[],
+			WalkOptions {
+				max_depth: Some(1),
+				location_root: Some(root_path),
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+
+		// `inner` is already a directory down, so `node_project` is found but never walked
+		let walked = walked
+			.map(|entry| entry.iso_file_path)
+			.collect::<HashSet<_>>();
+		assert!(to_walk.is_empty());
+		assert_eq!(max_depth_reached, 1);
+		assert!(
+			walked.contains(&IsolatedFilePathData::new(0, root_path, &node_project, true).unwrap())
+		);
+		assert!(!walked.contains(
+			&IsolatedFilePathData::new(0, root_path, node_project.join("package.json"), false)
+				.unwrap()
+		));
+		assert!(
+			matches!(
+				&errors[..],
+				[IndexerError::MaxDepthExceeded { path, depth }]
+					if *path == inner && *depth == 2
+			),
+			"{errors:#?}"
+		);
+	}
+
+	#[tokio::test]
+	async fn nested_location_roots_are_kept_but_not_walked() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let inner_location = root_path.join("inner");
+
+		let asked_to_remove = RefCell::new(vec![]);
+		let WalkResult {
+			walked,
+			to_walk,
+			errors,
+			..
+		} = walk(
+			root_path.to_path_buf(),
+			Uuid::new_v4(),
+			&[],
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|parent: IsolatedFilePathData<'static>, _| {
+				asked_to_remove.borrow_mut().push(root_path.join(&parent));
+				async { Ok(vec![]) }
+			},
+			|_| async { Ok(None) },
+			|path, is_dir| {
+				IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+			},
+			&WalkerMemory::default(),
+			&IoTokenBucket::default(),
+			WalkOptions {
+				excluded_location_roots: &[inner_location.clone()],
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		assert!(to_walk.is_empty());
+
+		let walked = walked.collect::<Vec<_>>();
+		let boundaries = walked
+			.iter()
+			.filter(|entry| entry.is_location_boundary)
+			.map(|entry| root_path.join(&entry.iso_file_path))
+			.collect::<Vec<_>>();
+		assert_eq!(boundaries, [inner_location.clone()]);
+		assert!(walked.iter().all(|entry| {
+			let path = root_path.join(&entry.iso_file_path);
+			path == inner_location || !path.starts_with(&inner_location)
+		}));
+		assert!(walked.iter().any(
+			|entry| root_path.join(&entry.iso_file_path) == root_path.join("photos/photo1.png")
+		));
+
+		// Nothing below the other location is ever looked up for removal
+		let asked_to_remove = asked_to_remove.into_inner();
+		assert!(!asked_to_remove.is_empty());
+		assert!(asked_to_remove
+			.iter()
+			.all(|parent| !parent.starts_with(&inner_location)));
+	}
+
+	#[tokio::test]
+	async fn a_location_which_is_a_single_file_is_indexed_alone() {
+		let root = prepare_location().await;
+		let file = root.path().join("photos/photo1.png");
+
+		let WalkResult {
+			walked,
+			to_update,
+			to_walk,
+			to_remove,
+			to_remove_subtrees,
+			errors,
+			found_entries,
+			..
+		} = walk_with(&file, &[], WalkOptions::default()).await.unwrap();
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		assert!(to_walk.is_empty());
+		assert!(to_remove_subtrees.is_empty());
+		assert_eq!(to_remove.count(), 0);
+		assert_eq!(to_update.count(), 0);
+		assert_eq!(found_entries, 1);
+
+		let walked = walked.collect::<Vec<_>>();
+		let [entry] = &walked[..] else {
+			panic!("expected only the file, got {walked:#?}");
+		};
+		let parts = entry.iso_file_path.to_parts();
+		assert!(!parts.is_dir);
+		assert_eq!(parts.materialized_path, "/");
+		assert_eq!(parts.extension, "png");
+		// It's the location's root, like the root directory of any other location
+		assert_eq!(file.join(&entry.iso_file_path), file);
+		assert_eq!(
+			entry.metadata.size_in_bytes,
+			std::fs::metadata(&file).unwrap().len()
+		);
+	}
+
+	#[tokio::test]
+	async fn shallow_and_in_memory_walks_of_a_single_file_only_have_the_file() {
+		let root = prepare_location().await;
+		let file = root.path().join("photos/photo1.png");
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, &file, path, is_dir).map_err(Into::into)
+		};
+
+		let SingleDirWalk {
+			walked,
+			to_remove,
+			errors,
+			found_entries,
+			..
+		} = walk_single_dir(
+			&file,
+			&file,
+			&[],
+			|_, _| {},
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			iso_file_path_factory,
+			true,
+			false,
+			true,
+			&[],
+			ComparisonCaps::default(),
+			None,
+		)
+		.await
+		.unwrap();
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		assert!(to_remove.is_empty());
+		assert_eq!(found_entries, 1);
+
+		let walked = walked.collect::<Vec<_>>();
+		let [entry] = &walked[..] else {
+			panic!("expected only the file, got {walked:#?}");
+		};
+		let parts = entry.iso_file_path.to_parts();
+		assert!(!parts.is_dir);
+		assert_eq!(parts.extension, "png");
+
+		let MemoryWalk {
+			entries,
+			rejected,
+			errors,
+		} = walk_to_memory(
+			&file,
+			&[],
+			|_, _| {},
+			iso_file_path_factory,
+			&IoTokenBucket::default(),
+			true,
+			&[],
+		)
+		.await;
+		assert!(errors.is_empty(), "errors: {errors:#?}");
+		assert!(rejected.is_empty());
+		let [entry] = &entries[..] else {
+			panic!("expected only the file, got {entries:#?}");
+		};
+		assert!(!entry.iso_file_path.to_parts().is_dir);
+	}
+
+	#[cfg(not(target_family = "windows"))]
+	#[tokio::test]
+	async fn cloud_placeholders_are_flagged_or_left_out() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		let dropbox = root_path.join("Dropbox");
+		let online_only = dropbox.join("beach.jpg");
+		fs::create_dir(&dropbox).await.unwrap();
+		fs::write(&online_only, b"").await.unwrap();
+		fs::write(dropbox.join("notes.txt"), b"some notes")
+			.await
+			.unwrap();
+
+		let walk_with = |exclude_cloud_placeholders, rows: Vec<file_path_walker::Data>| async move {
+			let WalkResult {
+				walked,
+				to_update,
+				errors,
+				..
+			} = walk(
+				root_path.to_path_buf(),
+				Uuid::new_v4(),
+				&[],
+				|_, _| {},
+				move |_| {
+					let rows = rows.clone();
+					async move { Ok(rows) }
+				},
+				|_, _| async { Ok(vec![]) },
+				|_| async { Ok(None) },
+				|path, is_dir| {
+					IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+				},
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				WalkOptions {
+					exclude_cloud_placeholders,
+					..Default::default()
+				},
+			)
+			.await
+			.unwrap();
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+
+			(walked.collect::<Vec<_>>(), to_update.collect::<Vec<_>>())
+		};
+		let placeholders = |entries: &[WalkedEntry]| {
+			entries
+				.iter()
+				.filter(|entry| entry.is_placeholder)
+				.map(|entry| root_path.join(&entry.iso_file_path))
+				.collect::<Vec<_>>()
+		};
+
+		let (walked, _) = walk_with(false, vec![]).await;
+		assert_eq!(placeholders(&walked), [online_only.clone()]);
+		assert_eq!(walked.len(), 3);
+
+		let (excluded, _) = walk_with(true, vec![]).await;
+		assert_eq!(excluded.len(), 2);
+		assert!(placeholders(&excluded).is_empty());
+
+		// Indexed before it was told apart, so only its flag changed
+		let rows = walked
+			.iter()
+			.map(|entry| {
+				let parts = entry.iso_file_path.to_parts();
+				file_path_walker::Data {
+					pub_id: entry.pub_id.as_bytes().to_vec(),
+					location_id: Some(0),
+					object_id: None,
+					materialized_path: Some(parts.materialized_path.to_string()),
+					is_dir: Some(parts.is_dir),
+					name: Some(parts.name.to_string()),
+					extension: Some(parts.extension.to_string()),
+					date_modified: Some(entry.metadata.modified_at.into()),
+					inode: Some(sd_utils::db::inode_to_db(entry.metadata.inode)),
+					size_in_bytes_bytes: Some(entry.metadata.size_in_bytes.to_be_bytes().to_vec()),
+					hidden: Some(entry.metadata.hidden),
+					is_placeholder: None,
+					is_location_boundary: None,
+				}
+			})
+			.collect::<Vec<_>>();
+		let (walked, to_update) = walk_with(false, rows).await;
+		assert!(walked.is_empty());
+		assert_eq!(placeholders(&to_update), [online_only]);
+		assert_eq!(to_update.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn extensions_differing_in_case_are_updates_on_case_insensitive_filesystems() {
+		let root = tempdir().unwrap();
+		let root_path = root.path();
+		fs::write(root_path.join("PHOTO.JPG"), b"a photo")
+			.await
+			.unwrap();
+		fs::write(root_path.join("notes.txt"), b"some notes")
+			.await
+			.unwrap();
+
+		let walk_with = |comparison_caps: ComparisonCaps, rows: Vec<file_path_walker::Data>| async move {
+			let WalkResult {
+				walked,
+				to_update,
+				to_remove,
+				errors,
+				..
+			} = walk(
+				root_path.to_path_buf(),
+				Uuid::new_v4(),
+				&[],
+				|_, _| {},
+				{
+					let rows = rows.clone();
+					move |_| {
+						let rows = rows.clone();
+						async move { Ok(rows) }
+					}
+				},
+				// Like the database, everything below the walked directory which wasn't found is removed,
+				// telling extensions apart like `to_remove_db_fetcher_fn!` does
+				move |_, found: Vec<IsolatedFilePathData<'static>>| {
+					let rows = rows.clone();
+					async move {
+						Ok(rows
+							.iter()
+							.zip(1..)
+							.filter(|(row, _)| {
+								let row = IsolatedFilePathData::try_from((*row).clone()).unwrap();
+								!found.iter().any(|found| {
+									if comparison_caps.case_insensitive {
+										found.with_normalized_extension()
+											== row.with_normalized_extension()
+									} else {
+										*found == row
+									}
+								})
+							})
+							.map(|(_, id)| {
+								file_path_pub_and_cas_ids::Data {
+									id,
+									pub_id: vec![],
+									cas_id: None,
+								}
+								.into()
+							})
+							.collect())
+					}
+				},
+				|_| async { Ok(None) },
+				|path, is_dir| {
+					IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+				},
+				&WalkerMemory::default(),
+				&IoTokenBucket::default(),
+				WalkOptions {
+					comparison_caps: Some(comparison_caps),
+					..Default::default()
+				},
+			)
+			.await
+			.unwrap();
+			assert!(errors.is_empty(), "errors: {errors:#?}");
+
+			(
+				walked.collect::<Vec<_>>(),
+				to_update.collect::<Vec<_>>(),
+				to_remove.count(),
+			)
+		};
+
+		let (indexed, _, _) = walk_with(ComparisonCaps::default(), vec![]).await;
+		assert_eq!(indexed.len(), 2);
+
+		// What another device stored of the same files, with its own inodes and lowercased extensions
+		let rows = indexed
+			.iter()
+			.map(|entry| {
+				let parts = entry.iso_file_path.to_parts();
+				file_path_walker::Data {
+					pub_id: entry.pub_id.as_bytes().to_vec(),
+					location_id: Some(0),
+					object_id: None,
+					materialized_path: Some(parts.materialized_path.to_string()),
+					is_dir: Some(parts.is_dir),
+					name: Some(parts.name.to_string()),
+					extension: Some(parts.extension.to_lowercase()),
+					date_modified: Some(entry.metadata.modified_at.into()),
+					inode: Some(sd_utils::db::inode_to_db(entry.metadata.inode + 1)),
+					size_in_bytes_bytes: Some(entry.metadata.size_in_bytes.to_be_bytes().to_vec()),
+					hidden: Some(entry.metadata.hidden),
+					is_placeholder: None,
+					is_location_boundary: None,
+				}
+			})
+			.collect::<Vec<_>>();
+
+		let case_insensitive = ComparisonCaps {
+			case_insensitive: true,
+			..ComparisonCaps::default()
+		};
+		let (walked, to_update, removed) = walk_with(case_insensitive, rows.clone()).await;
+		assert!(walked.is_empty(), "created: {walked:#?}");
+		assert_eq!(removed, 0);
+		assert_eq!(
+			to_update
+				.iter()
+				.map(|entry| entry.pub_id)
+				.collect::<HashSet<_>>(),
+			indexed
+				.iter()
+				.map(|entry| entry.pub_id)
+				.collect::<HashSet<_>>()
+		);
+
+		// Where cases are told apart the photo is another file, its row is of one which is gone
+		let (walked, to_update, removed) = walk_with(ComparisonCaps::default(), rows).await;
+		assert_eq!(walked.len(), 1);
+		assert_eq!(walked[0].iso_file_path.extension(), "JPG");
+		assert_eq!(to_update.len(), 1);
+		assert_eq!(removed, 1);
+	}
+
+	#[tokio::test]
+	async fn only_created_entries_are_numbered_in_discovery_order() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let discovery_seq = AtomicU64::default();
+
+		let walk_with = |rows: Vec<file_path_walker::Data>| {
+			let discovery_seq = &discovery_seq;
+			async move {
+				let WalkResult {
+					walked,
+					to_update,
+					errors,
+					..
+				} = walk(
+					root_path.to_path_buf(),
+					Uuid::new_v4(),
+					&[],
+					|_, _| {},
+					move |_| {
+						let rows = rows.clone();
+						async move { Ok(rows) }
+					},
+					|_, _| async { Ok(vec![]) },
+					|_| async { Ok(None) },
+					|path, is_dir| {
+						IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+					},
+					&WalkerMemory::default(),
+					&IoTokenBucket::default(),
+					WalkOptions {
+						discovery_seq: Some(discovery_seq),
+						..Default::default()
+					},
+				)
+				.await
+				.unwrap();
+				assert!(errors.is_empty(), "errors: {errors:#?}");
+
+				(walked.collect::<Vec<_>>(), to_update.collect::<Vec<_>>())
+			}
+		};
+
+		let (created, to_update) = walk_with(vec![]).await;
+		assert!(to_update.is_empty());
+		let mut seqs = created
+			.iter()
+			.map(|entry| entry.discovery_seq.unwrap())
+			.collect::<Vec<_>>();
+		seqs.sort_unstable();
+		assert_eq!(seqs, (0..created.len() as u64).collect::<Vec<_>>());
+
+		// All of them were indexed before and changed since
+		let rows = created
+			.iter()
+			.map(|entry| {
+				let parts = entry.iso_file_path.to_parts();
+				file_path_walker::Data {
+					pub_id: entry.pub_id.as_bytes().to_vec(),
+					location_id: Some(0),
+					object_id: None,
+					materialized_path: Some(parts.materialized_path.to_string()),
+					is_dir: Some(parts.is_dir),
+					name: Some(parts.name.to_string()),
+					extension: Some(parts.extension.to_string()),
+					date_modified: Some(entry.metadata.modified_at.into()),
+					inode: Some(sd_utils::db::inode_to_db(entry.metadata.inode + 1)),
+					size_in_bytes_bytes: Some(entry.metadata.size_in_bytes.to_be_bytes().to_vec()),
+					hidden: Some(entry.metadata.hidden),
+					is_placeholder: None,
+					is_location_boundary: None,
+				}
+			})
+			.collect::<Vec<_>>();
+
+		let (walked, to_update) = walk_with(rows).await;
+		assert!(walked.is_empty(), "created: {walked:#?}");
+		assert_eq!(to_update.len(), created.len());
+		assert!(to_update.iter().all(|entry| entry.discovery_seq.is_none()));
+		// And they didn't use up any number
+		assert_eq!(discovery_seq.load(Ordering::Relaxed), created.len() as u64);
+	}
+
+	fn stored(metadata: &FilePathMetadata) -> file_path_walker::Data {
+		file_path_walker::Data {
+			pub_id: Uuid::new_v4().as_bytes().to_vec(),
+			location_id: Some(1),
+			object_id: None,
+			materialized_path: Some("/".to_string()),
+			is_dir: Some(false),
+			name: Some("photo".to_string()),
+			extension: Some("jpg".to_string()),
+			date_modified: Some(metadata.modified_at.into()),
+			inode: Some(sd_utils::db::inode_to_db(metadata.inode)),
+			size_in_bytes_bytes: Some(metadata.size_in_bytes.to_be_bytes().to_vec()),
+			hidden: Some(metadata.hidden),
+			is_placeholder: None,
+			is_location_boundary: None,
+		}
+	}
+
+	#[test]
+	fn entries_are_compared_following_the_caps() {
+		let now = Utc::now();
+		let metadata = FilePathMetadata {
+			inode: 42,
+			size_in_bytes: 10,
+			created_at: now,
+			modified_at: now,
+			hidden: false,
+			created_at_is_fallback: false,
+		};
+		let file_path = stored(&metadata);
+		let stable = ComparisonCaps::default();
+		let fat = ComparisonCaps::for_filesystem(&FileSystem::Fat);
+
+		assert!(!file_path_has_changed(&file_path, &metadata, false, stable));
+
+		// Mounting a FAT volume again can give its files other inodes
+		let remounted = FilePathMetadata {
+			inode: 1337,
+			..metadata
+		};
+		assert!(file_path_has_changed(&file_path, &remounted, false, stable));
+		assert!(!file_path_has_changed(&file_path, &remounted, false, fat));
+
+		// And its modification dates are only kept to the 2 seconds
+		let rounded = FilePathMetadata {
+			modified_at: now + chrono::Duration::milliseconds(1500),
+			..remounted
+		};
+		assert!(file_path_has_changed(&file_path, &rounded, false, stable));
+		assert!(!file_path_has_changed(&file_path, &rounded, false, fat));
+
+		// Unless it was rewritten within them, which still changes its size
+		let resized = FilePathMetadata {
+			size_in_bytes: 20,
+			..rounded
+		};
+		assert!(file_path_has_changed(&file_path, &resized, false, fat));
+
+		let modified = FilePathMetadata {
+			modified_at: now + chrono::Duration::seconds(3),
+			..remounted
+		};
+		assert!(file_path_has_changed(&file_path, &modified, false, fat));
 	}
 }
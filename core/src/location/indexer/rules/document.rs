@@ -0,0 +1,708 @@
+use crate::library::Library;
+
+use sd_prisma::prisma::indexer_rule;
+
+use std::{
+	collections::{HashMap, HashSet},
+	fmt,
+	path::PathBuf,
+};
+
+use chrono::Utc;
+use globset::Glob;
+use itertools::Itertools;
+use rspc::ErrorCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use specta::Type;
+use thiserror::Error;
+use tracing::debug;
+
+use super::{
+	generate_pub_id,
+	groups::{RuleGroup, RuleGroupCreateArgs, RuleGroupUpdateArgs},
+	normalize_scope, IndexerRule, IndexerRuleError, RuleKind, RulePerKind,
+};
+
+/// Version of the exported documents, older versions must stay importable
+pub const RULES_DOCUMENT_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum RuleImportError {
+	#[error("malformed indexer rules document: {0}")]
+	Malformed(#[from] serde_json::Error),
+	#[error(
+		"unsupported indexer rules document version {0}, expected at most {}",
+		RULES_DOCUMENT_VERSION
+	)]
+	UnsupportedVersion(u32),
+	#[error("invalid indexer rules document: {}", .0.iter().join("; "))]
+	InvalidRules(Vec<RuleImportIssue>),
+	#[error("invalid indexer rule groups in document: {}", .0.iter().join("; "))]
+	InvalidGroups(Vec<GroupImportIssue>),
+}
+
+impl From<RuleImportError> for rspc::Error {
+	fn from(err: RuleImportError) -> Self {
+		rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
+	}
+}
+
+/// Something wrong with a rule of an imported document, located by its indices in the document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleImportIssue {
+	pub rule: usize,
+	pub entry: Option<usize>,
+	pub parameter: Option<usize>,
+	pub message: String,
+}
+
+impl fmt::Display for RuleImportIssue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "rules[{}]", self.rule)?;
+		if let Some(entry) = self.entry {
+			write!(f, ".rules[{entry}]")?;
+		}
+		if let Some(parameter) = self.parameter {
+			write!(f, ".parameters[{parameter}]")?;
+		}
+		write!(f, ": {}", self.message)
+	}
+}
+
+/// Something wrong with a group of an imported document, `rule` being the index in its rules' names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupImportIssue {
+	pub group: usize,
+	pub rule: Option<usize>,
+	pub message: String,
+}
+
+impl fmt::Display for GroupImportIssue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "groups[{}]", self.group)?;
+		if let Some(rule) = self.rule {
+			write!(f, ".rules[{rule}]")?;
+		}
+		write!(f, ": {}", self.message)
+	}
+}
+
+/// What to do with an imported rule or group named like one already in the library
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Type)]
+pub enum RuleCollision {
+	/// Imports it under a free name, like `No Hidden (2)`
+	Rename,
+	Skip,
+	/// Overwrites the library's rule, system rules are never overwritten so they're renamed instead
+	Replace,
+}
+
+/// The groups are exported along with all their rules, even the ones missing from `rule_ids`
+#[derive(Type, Deserialize)]
+pub struct IndexerRulesExportArgs {
+	pub rule_ids: Vec<i32>,
+	#[serde(default)]
+	pub group_ids: Vec<i32>,
+}
+
+#[derive(Type, Deserialize)]
+pub struct IndexerRulesImportArgs {
+	pub document: Value,
+	pub on_collision: RuleCollision,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Document<Rule, Group> {
+	version: u32,
+	rules: Vec<Rule>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	groups: Vec<Group>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DocumentRule<Entry> {
+	name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	scope: Option<PathBuf>,
+	rules: Vec<Entry>,
+}
+
+/// Groups refer to their rules by name, which must be in the same document
+#[derive(Serialize, Deserialize)]
+struct DocumentGroup {
+	name: String,
+	rules: Vec<String>,
+}
+
+/// The rules and groups of an imported document, not saved to any library yet
+#[derive(Debug)]
+pub struct ImportedRules {
+	pub rules: Vec<IndexerRule>,
+	pub groups: Vec<ImportedRuleGroup>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImportedRuleGroup {
+	pub name: String,
+	/// Indices of the group's rules in [`ImportedRules::rules`]
+	pub rules: Vec<usize>,
+}
+
+/// Globs are written as their patterns and children directories as a sorted array of names
+#[derive(Serialize, Deserialize)]
+struct DocumentEntry {
+	kind: RuleKind,
+	parameters: Vec<String>,
+}
+
+impl From<&RulePerKind> for DocumentEntry {
+	fn from(rule: &RulePerKind) -> Self {
+		let globs = |globs: &[Glob]| globs.iter().map(|glob| glob.glob().to_string()).collect();
+		let children = |children: &HashSet<String>| children.iter().cloned().sorted().collect();
+
+		match rule {
+			RulePerKind::AcceptFilesByGlob(globs_list, _) => Self {
+				kind: RuleKind::AcceptFilesByGlob,
+				parameters: globs(globs_list),
+			},
+			RulePerKind::RejectFilesByGlob(globs_list, _) => Self {
+				kind: RuleKind::RejectFilesByGlob,
+				parameters: globs(globs_list),
+			},
+			RulePerKind::AcceptIfChildrenDirectoriesArePresent(children_set) => Self {
+				kind: RuleKind::AcceptIfChildrenDirectoriesArePresent,
+				parameters: children(children_set),
+			},
+			RulePerKind::RejectIfChildrenDirectoriesArePresent(children_set) => Self {
+				kind: RuleKind::RejectIfChildrenDirectoriesArePresent,
+				parameters: children(children_set),
+			},
+		}
+	}
+}
+
+impl IndexerRule {
+	/// Exports `rules` and `groups` as a JSON document which can be imported into any library.
+	/// Groups only keep the rules which are exported along with them.
+	pub fn export(rules: &[IndexerRule], groups: &[RuleGroup]) -> Value {
+		let names = rules
+			.iter()
+			.filter_map(|rule| rule.id.map(|id| (id, &rule.name)))
+			.collect::<HashMap<_, _>>();
+
+		json!(Document {
+			version: RULES_DOCUMENT_VERSION,
+			rules: rules
+				.iter()
+				.map(|rule| DocumentRule {
+					name: rule.name.clone(),
+					scope: rule.scope.clone(),
+					rules: rule.rules.iter().map(DocumentEntry::from).collect(),
+				})
+				.collect(),
+			groups: groups
+				.iter()
+				.map(|group| DocumentGroup {
+					name: group.name.clone(),
+					rules: group
+						.rule_ids
+						.iter()
+						.filter_map(|id| names.get(id).map(|name| name.to_string()))
+						.collect(),
+				})
+				.collect(),
+		})
+	}
+
+	/// Parses a document made by [`IndexerRule::export`], reporting every invalid rule at once,
+	/// then every invalid group. Nothing is saved to any library, see
+	/// [`IndexerRule::save_imported`].
+	pub fn import(document: &Value) -> Result<ImportedRules, RuleImportError> {
+		let Document {
+			version,
+			rules,
+			groups,
+		} = Document::<Value, Value>::deserialize(document)?;
+		if version == 0 || version > RULES_DOCUMENT_VERSION {
+			return Err(RuleImportError::UnsupportedVersion(version));
+		}
+
+		let mut issues = vec![];
+		let mut names = HashMap::with_capacity(rules.len());
+		let mut imported = Vec::with_capacity(rules.len());
+
+		for (rule_idx, rule) in rules.iter().enumerate() {
+			let mut issue = |entry, parameter, message: String| {
+				issues.push(RuleImportIssue {
+					rule: rule_idx,
+					entry,
+					parameter,
+					message,
+				})
+			};
+
+			let DocumentRule { name, scope, rules } = match DocumentRule::<Value>::deserialize(rule)
+			{
+				Ok(rule) => rule,
+				Err(e) => {
+					issue(None, None, e.to_string());
+					continue;
+				}
+			};
+
+			if name.trim().is_empty() {
+				issue(None, None, "name can't be empty".to_string());
+			} else if let Some(other_idx) = names.insert(name.clone(), rule_idx) {
+				issue(
+					None,
+					None,
+					format!("name is already used by rules[{other_idx}]"),
+				);
+			}
+
+			let scope = match scope.map(normalize_scope).transpose() {
+				Ok(scope) => scope.flatten().map(PathBuf::from),
+				Err(e) => {
+					issue(None, None, e.to_string());
+					None
+				}
+			};
+
+			if rules.is_empty() {
+				issue(None, None, "rule has nothing to apply".to_string());
+			}
+
+			let mut rules_per_kind = Vec::with_capacity(rules.len());
+			for (entry_idx, entry) in rules.iter().enumerate() {
+				match import_entry(entry) {
+					Ok(rule) => rules_per_kind.push(rule),
+					Err(entry_issues) => {
+						for (parameter, message) in entry_issues {
+							issue(Some(entry_idx), parameter, message);
+						}
+					}
+				}
+			}
+
+			imported.push(IndexerRule {
+				id: None,
+				name,
+				default: false,
+				rules: rules_per_kind,
+				scope,
+				date_created: Utc::now(),
+				date_modified: Utc::now(),
+				hits: Default::default(),
+			});
+		}
+
+		if !issues.is_empty() {
+			return Err(RuleImportError::InvalidRules(issues));
+		}
+
+		let mut issues = vec![];
+		let mut group_names = HashMap::with_capacity(groups.len());
+		let mut imported_groups = Vec::with_capacity(groups.len());
+
+		for (group_idx, group) in groups.iter().enumerate() {
+			let mut issue = |rule, message: String| {
+				issues.push(GroupImportIssue {
+					group: group_idx,
+					rule,
+					message,
+				})
+			};
+
+			let DocumentGroup { name, rules } = match DocumentGroup::deserialize(group) {
+				Ok(group) => group,
+				Err(e) => {
+					issue(None, e.to_string());
+					continue;
+				}
+			};
+
+			if name.trim().is_empty() {
+				issue(None, "name can't be empty".to_string());
+			} else if let Some(other_idx) = group_names.insert(name.clone(), group_idx) {
+				issue(None, format!("name is already used by groups[{other_idx}]"));
+			}
+
+			let mut rules_idx = Vec::with_capacity(rules.len());
+			for (rule_idx, rule_name) in rules.iter().enumerate() {
+				// Every rule was imported, so they're at the same index as in the document
+				match names.get(rule_name) {
+					Some(&idx) => rules_idx.push(idx),
+					None => issue(
+						Some(rule_idx),
+						format!("there's no rule named '{rule_name}' in the document"),
+					),
+				}
+			}
+
+			imported_groups.push(ImportedRuleGroup {
+				name,
+				rules: rules_idx,
+			});
+		}
+
+		if issues.is_empty() {
+			Ok(ImportedRules {
+				rules: imported,
+				groups: imported_groups,
+			})
+		} else {
+			Err(RuleImportError::InvalidGroups(issues))
+		}
+	}
+
+	/// Writes rules and groups parsed by [`IndexerRule::import`] to the library, returning how many
+	/// were written. Groups get the library's rule for the imported rules which were skipped.
+	pub async fn save_imported(
+		library: &Library,
+		ImportedRules { rules, groups }: ImportedRules,
+		on_collision: RuleCollision,
+	) -> Result<u32, IndexerRuleError> {
+		let mut existing = library
+			.db
+			.indexer_rule()
+			.find_many(vec![])
+			.exec()
+			.await?
+			.into_iter()
+			.filter_map(|rule| {
+				rule.name
+					.map(|name| (name, (rule.id, rule.default.unwrap_or_default())))
+			})
+			.collect::<HashMap<_, _>>();
+
+		let mut saved = 0;
+		let mut saved_ids = Vec::with_capacity(rules.len());
+
+		for IndexerRule {
+			name, rules, scope, ..
+		} in rules
+		{
+			let rules_data = rmp_serde::to_vec_named(&rules)?;
+			let scope = scope.map(|scope| scope.to_string_lossy().into_owned());
+			let now = Utc::now();
+
+			use indexer_rule::*;
+
+			let name = match (existing.get(&name), on_collision) {
+				(None, _) => name,
+				(Some(&(id, _)), RuleCollision::Skip) => {
+					debug!("Skipping imported indexer rule <name='{name}'>, it already exists");
+					saved_ids.push(id);
+					continue;
+				}
+				(Some(&(id, false)), RuleCollision::Replace) => {
+					library
+						.db
+						.indexer_rule()
+						.update(
+							id::equals(id),
+							vec![
+								rules_per_kind::set(Some(rules_data)),
+								scope::set(scope),
+								date_modified::set(Some(now.into())),
+							],
+						)
+						.exec()
+						.await?;
+
+					saved_ids.push(id);
+					saved += 1;
+					continue;
+				}
+				(Some(_), RuleCollision::Rename | RuleCollision::Replace) => {
+					unique_name(&name, |name| existing.contains_key(name))
+				}
+			};
+
+			let created = library
+				.db
+				.indexer_rule()
+				.create(
+					sd_utils::uuid_to_bytes(generate_pub_id()),
+					vec![
+						name::set(Some(name.clone())),
+						rules_per_kind::set(Some(rules_data)),
+						scope::set(scope),
+						date_created::set(Some(now.into())),
+						date_modified::set(Some(now.into())),
+					],
+				)
+				.exec()
+				.await?;
+
+			existing.insert(name, (created.id, false));
+			saved_ids.push(created.id);
+			saved += 1;
+		}
+
+		let mut existing_groups = RuleGroup::list(library)
+			.await?
+			.into_iter()
+			.map(|group| (group.name, group.id))
+			.collect::<HashMap<_, _>>();
+
+		for ImportedRuleGroup { name, rules } in groups {
+			let rule_ids = rules
+				.into_iter()
+				.filter_map(|idx| saved_ids.get(idx).copied())
+				.collect::<Vec<_>>();
+
+			let name = match (existing_groups.get(&name), on_collision) {
+				(None, _) => name,
+				(Some(_), RuleCollision::Skip) => {
+					debug!(
+						"Skipping imported indexer rule group <name='{name}'>, it already exists"
+					);
+					continue;
+				}
+				(Some(&id), RuleCollision::Replace) => {
+					RuleGroupUpdateArgs {
+						id,
+						name: None,
+						rule_ids: Some(rule_ids),
+					}
+					.update(library)
+					.await?;
+
+					saved += 1;
+					continue;
+				}
+				(Some(_), RuleCollision::Rename) => {
+					unique_name(&name, |name| existing_groups.contains_key(name))
+				}
+			};
+
+			let created = RuleGroupCreateArgs { name, rule_ids }
+				.create(library)
+				.await?;
+
+			existing_groups.insert(created.name, created.id);
+			saved += 1;
+		}
+
+		Ok(saved)
+	}
+}
+
+/// Parses a single entry of a rule, the issues found are located by their parameter's index
+fn import_entry(entry: &Value) -> Result<RulePerKind, Vec<(Option<usize>, String)>> {
+	let DocumentEntry { kind, parameters } =
+		DocumentEntry::deserialize(entry).map_err(|e| vec![(None, e.to_string())])?;
+
+	if parameters.is_empty() {
+		return Err(vec![(None, "parameters can't be empty".to_string())]);
+	}
+
+	let issues = parameters
+		.iter()
+		.enumerate()
+		.filter_map(|(parameter_idx, parameter)| {
+			match kind {
+				RuleKind::AcceptFilesByGlob | RuleKind::RejectFilesByGlob => {
+					Glob::new(parameter).err().map(|e| e.to_string())
+				}
+				RuleKind::AcceptIfChildrenDirectoriesArePresent
+				| RuleKind::RejectIfChildrenDirectoriesArePresent => (parameter.is_empty()
+					|| parameter.contains(['/', '\\']))
+				.then(|| "must be a single directory name".to_string()),
+			}
+			.map(|message| (Some(parameter_idx), message))
+		})
+		.collect::<Vec<_>>();
+
+	if !issues.is_empty() {
+		return Err(issues);
+	}
+
+	RulePerKind::new(kind, parameters).map_err(|e| vec![(None, e.to_string())])
+}
+
+/// `name` followed by the first free number, starting at 2
+fn unique_name(name: &str, is_taken: impl Fn(&str) -> bool) -> String {
+	let mut n = 2;
+	loop {
+		let candidate = format!("{name} ({n})");
+		if !is_taken(&candidate) {
+			return candidate;
+		}
+		n += 1;
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn every_rule_kind_round_trips() {
+		let rules = vec![
+			IndexerRule {
+				scope: Some("Photos/Exports".into()),
+				..IndexerRule::new(
+					"globs".to_string(),
+					false,
+					vec![
+						RulePerKind::new_accept_files_by_globs_str(["*.png", "**/*.{jpg,jpeg}"])
+							.unwrap(),
+						RulePerKind::new_reject_files_by_globs_str(["**/.*"]).unwrap(),
+					],
+				)
+			},
+			IndexerRule::new(
+				"children".to_string(),
+				true,
+				vec![
+					RulePerKind::AcceptIfChildrenDirectoriesArePresent(
+						[".git".to_string(), ".hg".to_string()]
+							.into_iter()
+							.collect(),
+					),
+					RulePerKind::RejectIfChildrenDirectoriesArePresent(
+						["node_modules".to_string()].into_iter().collect(),
+					),
+				],
+			),
+		];
+
+		let document = IndexerRule::export(&rules, &[]);
+		assert_eq!(document["version"], RULES_DOCUMENT_VERSION);
+		assert_eq!(
+			document["rules"][1]["rules"][0]["parameters"],
+			json!([".git", ".hg"])
+		);
+
+		let imported = IndexerRule::import(&document).unwrap().rules;
+		assert_eq!(imported.len(), rules.len());
+		for (imported, rule) in imported.iter().zip(&rules) {
+			assert_eq!(imported.name, rule.name);
+			assert_eq!(imported.scope, rule.scope);
+			assert_eq!(imported.rules, rule.rules);
+			// Whatever got imported is the library's own rule
+			assert!(!imported.default);
+		}
+
+		assert_eq!(IndexerRule::export(&imported, &[]), document);
+	}
+
+	#[test]
+	fn groups_round_trip_by_their_rules_names() {
+		let rules =
+			[(1, "No node_modules"), (2, "No target"), (3, "No Hidden")].map(|(id, name)| {
+				IndexerRule {
+					id: Some(id),
+					..IndexerRule::new(
+						name.to_string(),
+						false,
+						vec![RulePerKind::new_reject_files_by_globs_str(["**/.*"]).unwrap()],
+					)
+				}
+			});
+		let developer = RuleGroup {
+			id: 1,
+			name: "developer".to_string(),
+			// The 4th rule isn't exported so it's left out of the group
+			rule_ids: vec![1, 2, 4],
+		};
+
+		let document = IndexerRule::export(&rules, &[developer]);
+		assert_eq!(
+			document["groups"],
+			json!([{ "name": "developer", "rules": ["No node_modules", "No target"] }])
+		);
+
+		let imported = IndexerRule::import(&document).unwrap();
+		assert_eq!(imported.rules.len(), rules.len());
+		assert_eq!(
+			imported.groups,
+			[ImportedRuleGroup {
+				name: "developer".to_string(),
+				rules: vec![0, 1],
+			}]
+		);
+
+		let mut document = document;
+		document["groups"][0]["rules"][1] = json!("No build");
+		let Err(RuleImportError::InvalidGroups(issues)) = IndexerRule::import(&document) else {
+			panic!("a group of a rule which isn't in the document should be invalid");
+		};
+		assert_eq!(
+			issues[0].to_string(),
+			"groups[0].rules[1]: there's no rule named 'No build' in the document"
+		);
+	}
+
+	#[test]
+	fn malformed_documents_report_where_they_are_wrong() {
+		assert!(matches!(
+			IndexerRule::import(&json!({ "rules": [] })),
+			Err(RuleImportError::Malformed(_))
+		));
+		assert!(matches!(
+			IndexerRule::import(&json!({ "version": 2, "rules": [] })),
+			Err(RuleImportError::UnsupportedVersion(2))
+		));
+
+		let document = json!({
+			"version": 1,
+			"rules": [
+				{
+					"name": "fine",
+					"rules": [{ "kind": "RejectFilesByGlob", "parameters": ["*.tmp"] }],
+				},
+				{
+					"name": "bad parameters",
+					"rules": [
+						{ "kind": "AcceptFilesByGlob", "parameters": ["*.png", "a[b"] },
+						{ "kind": "RejectIfChildrenDirectoriesArePresent", "parameters": [] },
+					],
+				},
+				{
+					"name": "fine",
+					"scope": "../outside",
+					"rules": [{ "kind": "NotAKind", "parameters": ["x"] }],
+				},
+				{ "rules": [] },
+			],
+		});
+
+		let Err(RuleImportError::InvalidRules(issues)) = IndexerRule::import(&document) else {
+			panic!("document should be invalid");
+		};
+
+		let locations = issues
+			.iter()
+			.map(|issue| (issue.rule, issue.entry, issue.parameter))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			locations,
+			[
+				(1, Some(0), Some(1)),
+				(1, Some(1), None),
+				(2, None, None),
+				(2, None, None),
+				(2, Some(0), None),
+				(3, None, None),
+			]
+		);
+		assert_eq!(issues[2].message, "name is already used by rules[0]");
+		assert!(issues[0]
+			.to_string()
+			.starts_with("rules[1].rules[0].parameters[1]: "));
+	}
+
+	#[test]
+	fn renamed_rules_get_the_first_free_name() {
+		let taken = ["No Hidden", "No Hidden (2)"];
+		assert_eq!(
+			unique_name("No Hidden", |name| taken.contains(&name)),
+			"No Hidden (3)"
+		);
+	}
+}
@@ -0,0 +1,430 @@
+use crate::{library::Library, location::location_with_indexer_rules};
+
+use sd_prisma::prisma::{
+	indexer_rule, indexer_rule_group, indexer_rule_groups_in_location, indexer_rules_in_group,
+	location,
+};
+
+use std::collections::HashSet;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tracing::debug;
+
+use super::{generate_pub_id, IndexerRule, IndexerRuleError};
+
+indexer_rule_group::include!(indexer_rule_group_with_rules {
+	rules: select { indexer_rule_id }
+});
+
+/// A named set of indexer rules, like a preset, which is assigned to locations as a whole.
+/// Changing its rules changes them for every location it's assigned to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Type)]
+pub struct RuleGroup {
+	pub id: i32,
+	pub name: String,
+	pub rule_ids: Vec<i32>,
+}
+
+impl From<indexer_rule_group_with_rules::Data> for RuleGroup {
+	fn from(data: indexer_rule_group_with_rules::Data) -> Self {
+		Self {
+			id: data.id,
+			name: data.name,
+			rule_ids: data
+				.rules
+				.into_iter()
+				.map(|rule| rule.indexer_rule_id)
+				.collect(),
+		}
+	}
+}
+
+#[derive(Type, Deserialize)]
+pub struct RuleGroupCreateArgs {
+	pub name: String,
+	pub rule_ids: Vec<i32>,
+}
+
+impl RuleGroupCreateArgs {
+	pub async fn create(self, library: &Library) -> Result<RuleGroup, IndexerRuleError> {
+		debug!(
+			"Creating indexer rule group (name = {}, rules = {:?})",
+			self.name, self.rule_ids
+		);
+
+		let now = Utc::now();
+		let pub_id = sd_utils::uuid_to_bytes(generate_pub_id());
+
+		use indexer_rule_group::*;
+
+		// All in one go, so a failing rule doesn't leave the group behind without it
+		let (group, _) = library
+			.db
+			._batch((
+				library.db.indexer_rule_group().create(
+					pub_id.clone(),
+					self.name,
+					vec![
+						date_created::set(Some(now.into())),
+						date_modified::set(Some(now.into())),
+					],
+				),
+				self.rule_ids
+					.iter()
+					.map(|rule_id| {
+						library.db.indexer_rules_in_group().create(
+							indexer_rule_group::pub_id::equals(pub_id.clone()),
+							indexer_rule::id::equals(*rule_id),
+							vec![],
+						)
+					})
+					.collect::<Vec<_>>(),
+			))
+			.await?;
+
+		Ok(RuleGroup {
+			id: group.id,
+			name: group.name,
+			rule_ids: self.rule_ids,
+		})
+	}
+}
+
+/// Fields left out are kept, `rule_ids` replaces all the group's rules at once
+#[derive(Type, Deserialize)]
+pub struct RuleGroupUpdateArgs {
+	pub id: i32,
+	#[serde(default)]
+	pub name: Option<String>,
+	#[serde(default)]
+	pub rule_ids: Option<Vec<i32>>,
+}
+
+impl RuleGroupUpdateArgs {
+	pub async fn update(self, library: &Library) -> Result<(), IndexerRuleError> {
+		RuleGroup::find(library, self.id).await?;
+
+		use indexer_rule_group::*;
+
+		let mut params = vec![date_modified::set(Some(Utc::now().into()))];
+		if let Some(new_name) = self.name {
+			params.push(name::set(new_name));
+		}
+
+		let update = library
+			.db
+			.indexer_rule_group()
+			.update(id::equals(self.id), params);
+
+		match self.rule_ids {
+			Some(rule_ids) => {
+				// All in one go, so a scan never sees the group half updated
+				library
+					.db
+					._batch((
+						update,
+						library
+							.db
+							.indexer_rules_in_group()
+							.delete_many(vec![indexer_rules_in_group::group_id::equals(self.id)]),
+						library.db.indexer_rules_in_group().create_many(
+							rule_ids
+								.iter()
+								.map(|rule_id| {
+									indexer_rules_in_group::create_unchecked(
+										self.id,
+										*rule_id,
+										vec![],
+									)
+								})
+								.collect(),
+						),
+					))
+					.await?;
+			}
+			None => {
+				update.exec().await?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl RuleGroup {
+	pub async fn list(library: &Library) -> Result<Vec<Self>, IndexerRuleError> {
+		Ok(library
+			.db
+			.indexer_rule_group()
+			.find_many(vec![])
+			.include(indexer_rule_group_with_rules::include())
+			.exec()
+			.await?
+			.into_iter()
+			.map(Self::from)
+			.collect())
+	}
+
+	pub async fn list_for_location(
+		library: &Library,
+		location_id: location::id::Type,
+	) -> Result<Vec<Self>, IndexerRuleError> {
+		Ok(library
+			.db
+			.indexer_rule_group()
+			.find_many(vec![indexer_rule_group::locations::some(vec![
+				indexer_rule_groups_in_location::location_id::equals(location_id),
+			])])
+			.include(indexer_rule_group_with_rules::include())
+			.exec()
+			.await?
+			.into_iter()
+			.map(Self::from)
+			.collect())
+	}
+
+	/// Deleting a group leaves its rules alone, locations only stop getting them through the group
+	pub async fn delete(library: &Library, id: i32) -> Result<(), IndexerRuleError> {
+		Self::find(library, id).await?;
+
+		library
+			.db
+			.indexer_rule_group()
+			.delete(indexer_rule_group::id::equals(id))
+			.exec()
+			.await?;
+
+		Ok(())
+	}
+
+	/// Turns the group on or off for a location, taking effect from its next scan
+	pub async fn set_for_location(
+		library: &Library,
+		location_id: location::id::Type,
+		group_id: i32,
+		enabled: bool,
+	) -> Result<(), IndexerRuleError> {
+		Self::find(library, group_id).await?;
+
+		let unassign = library
+			.db
+			.indexer_rule_groups_in_location()
+			.delete_many(vec![
+				indexer_rule_groups_in_location::location_id::equals(location_id),
+				indexer_rule_groups_in_location::group_id::equals(group_id),
+			]);
+
+		if enabled {
+			// Turning on a group which already is on stays a no-op
+			library
+				.db
+				._batch((
+					unassign,
+					library
+						.db
+						.indexer_rule_groups_in_location()
+						.create_many(vec![indexer_rule_groups_in_location::create_unchecked(
+							location_id,
+							group_id,
+							vec![],
+						)]),
+				))
+				.await?;
+		} else {
+			unassign.exec().await?;
+		}
+
+		Ok(())
+	}
+
+	async fn find(library: &Library, id: i32) -> Result<(), IndexerRuleError> {
+		library
+			.db
+			.indexer_rule_group()
+			.find_unique(indexer_rule_group::id::equals(id))
+			.exec()
+			.await?
+			.map(|_| ())
+			.ok_or(IndexerRuleError::GroupNotFound(id))
+	}
+}
+
+impl IndexerRule {
+	/// The rules a location is indexed with, its own and the ones of its groups, each rule once
+	pub fn for_location(
+		location: &location_with_indexer_rules::Data,
+	) -> Result<Vec<Self>, IndexerRuleError> {
+		let own = location
+			.indexer_rules
+			.iter()
+			.map(|rule| Self::try_from(&rule.indexer_rule))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let groups = location
+			.indexer_rule_groups
+			.iter()
+			.map(|group| {
+				group
+					.group
+					.rules
+					.iter()
+					.map(|rule| Self::try_from(&rule.indexer_rule))
+					.collect::<Result<Vec<_>, _>>()
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		Ok(Self::flatten(own, groups))
+	}
+
+	/// The location's own rules then the ones of each group, leaving out the rules given earlier
+	/// as a rule can be both assigned directly and through any number of groups
+	pub fn flatten(
+		own: impl IntoIterator<Item = Self>,
+		groups: impl IntoIterator<Item = Vec<Self>>,
+	) -> Vec<Self> {
+		let mut seen = HashSet::new();
+
+		own.into_iter()
+			.chain(groups.into_iter().flatten())
+			.filter(|rule| rule.id.map_or(true, |id| seen.insert(id)))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+	use crate::location::{
+		create_location,
+		indexer::{
+			rules::{IndexerRuleCreateArgs, RuleKind, RulePerKind},
+			tests::{test_library, test_location},
+		},
+	};
+
+	use uuid::Uuid;
+
+	fn reject(id: i32, glob: &str) -> IndexerRule {
+		IndexerRule {
+			id: Some(id),
+			..IndexerRule::new(
+				glob.to_string(),
+				false,
+				vec![RulePerKind::new_reject_files_by_globs_str([glob]).unwrap()],
+			)
+		}
+	}
+
+	fn ids(rules: &[IndexerRule]) -> Vec<Option<i32>> {
+		rules.iter().map(|rule| rule.id).collect()
+	}
+
+	#[test]
+	fn a_group_flattens_like_its_rules_assigned_directly() {
+		let developer = || vec![reject(1, "**/node_modules"), reject(2, "**/target")];
+
+		let through_group = IndexerRule::flatten([], [developer()]);
+		let directly = IndexerRule::flatten(developer(), []);
+		assert_eq!(through_group, directly);
+		assert_eq!(ids(&through_group), [Some(1), Some(2)]);
+
+		// Assigned directly as well as through groups, a rule is still applied once
+		let both = IndexerRule::flatten([reject(2, "**/target")], [developer(), developer()]);
+		assert_eq!(ids(&both), [Some(2), Some(1)]);
+	}
+
+	async fn rules_of(library: &Library, location_id: location::id::Type) -> Vec<IndexerRule> {
+		let location = library
+			.db
+			.location()
+			.find_unique(location::id::equals(location_id))
+			.include(location_with_indexer_rules::include())
+			.exec()
+			.await
+			.unwrap()
+			.unwrap();
+
+		IndexerRule::for_location(&location).unwrap()
+	}
+
+	#[tokio::test]
+	async fn a_location_gets_the_rules_of_its_groups() {
+		let (_node, library, _dir) = test_library().await;
+
+		let mut rule_ids = vec![];
+		for (name, glob) in [
+			("No node_modules", "**/node_modules"),
+			("No target", "**/target"),
+		] {
+			let rule = IndexerRuleCreateArgs {
+				name: name.to_string(),
+				dry_run: false,
+				rules: vec![(RuleKind::RejectFilesByGlob, vec![glob.to_string()])],
+				scope: None,
+			}
+			.create(&library)
+			.await
+			.unwrap()
+			.unwrap();
+			rule_ids.push(rule.id);
+		}
+		let developer = RuleGroupCreateArgs {
+			name: "developer".to_string(),
+			rule_ids: rule_ids.clone(),
+		}
+		.create(&library)
+		.await
+		.unwrap();
+
+		let (group_root, direct_root) =
+			(tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+		let through_group = test_location(&library, group_root.path()).await;
+		let directly = create_location(
+			&library,
+			Uuid::new_v4(),
+			direct_root.path(),
+			&rule_ids,
+			false,
+		)
+		.await
+		.unwrap()
+		.unwrap()
+		.data;
+
+		RuleGroup::set_for_location(&library, through_group.id, developer.id, true)
+			.await
+			.unwrap();
+		let rules = rules_of(&library, through_group.id).await;
+		assert_eq!(rules, rules_of(&library, directly.id).await);
+		assert_eq!(
+			ids(&rules),
+			rule_ids.iter().copied().map(Some).collect::<Vec<_>>()
+		);
+
+		// Turning the group off takes its rules away from the location, but leaves them alone
+		RuleGroup::set_for_location(&library, through_group.id, developer.id, false)
+			.await
+			.unwrap();
+		assert!(rules_of(&library, through_group.id).await.is_empty());
+		assert_eq!(rules_of(&library, directly.id).await, rules);
+		assert_eq!(RuleGroup::list(&library).await.unwrap(), [developer]);
+	}
+
+	#[tokio::test]
+	async fn a_group_with_a_missing_rule_isnt_created() {
+		let (_node, library, _dir) = test_library().await;
+
+		let created = RuleGroupCreateArgs {
+			name: "broken".to_string(),
+			rule_ids: vec![i32::MAX],
+		}
+		.create(&library)
+		.await;
+
+		assert!(created.is_err());
+		assert!(RuleGroup::list(&library).await.unwrap().is_empty());
+	}
+}
@@ -9,7 +9,8 @@ use sd_utils::{
 use std::{
 	collections::{HashMap, HashSet},
 	marker::PhantomData,
-	path::Path,
+	path::{Component, Path, PathBuf},
+	sync::atomic::{AtomicU64, Ordering},
 };
 
 use chrono::{DateTime, Utc};
@@ -24,6 +25,9 @@ use tokio::fs;
 use tracing::debug;
 use uuid::Uuid;
 
+pub mod document;
+pub mod groups;
+pub mod preview;
 pub mod seed;
 
 #[derive(Error, Debug)]
@@ -35,6 +39,10 @@ pub enum IndexerRuleError {
 	Glob(#[from] globset::Error),
 	#[error(transparent)]
 	NonUtf8Path(#[from] NonUtf8PathError),
+	#[error("indexer rule scope must be a path inside the location: {}", .0.display())]
+	InvalidScope(PathBuf),
+	#[error("indexer rule group <id={0}> not found")]
+	GroupNotFound(i32),
 
 	// Internal Errors
 	#[error("indexer rule parameters encode error: {0}")]
@@ -56,9 +64,13 @@ impl From<IndexerRuleError> for rspc::Error {
 		match err {
 			IndexerRuleError::InvalidRuleKindInt(_)
 			| IndexerRuleError::Glob(_)
-			| IndexerRuleError::NonUtf8Path(_) => {
+			| IndexerRuleError::NonUtf8Path(_)
+			| IndexerRuleError::InvalidScope(_) => {
 				rspc::Error::with_cause(ErrorCode::BadRequest, err.to_string(), err)
 			}
+			IndexerRuleError::GroupNotFound(_) => {
+				rspc::Error::with_cause(ErrorCode::NotFound, err.to_string(), err)
+			}
 
 			_ => rspc::Error::with_cause(ErrorCode::InternalServerError, err.to_string(), err),
 		}
@@ -73,11 +85,15 @@ impl From<IndexerRuleError> for rspc::Error {
 ///
 /// In case of `RuleKind::AcceptIfChildrenDirectoriesArePresent` or `RuleKind::RejectIfChildrenDirectoriesArePresent` the
 /// `parameters` field must be a vector of strings containing the names of the directories.
+///
+/// `scope` limits the rule to a sub-path of the locations it's applied to, e.g. `Photos/Exports`.
 #[derive(Type, Deserialize)]
 pub struct IndexerRuleCreateArgs {
 	pub name: String,
 	pub dry_run: bool,
 	pub rules: Vec<(RuleKind, Vec<String>)>,
+	#[serde(default)]
+	pub scope: Option<PathBuf>,
 }
 
 impl IndexerRuleCreateArgs {
@@ -96,28 +112,13 @@ impl IndexerRuleCreateArgs {
 			self.rules
 		);
 
+		let scope = self.scope.map(normalize_scope).transpose()?.flatten();
+
 		let rules_data = rmp_serde::to_vec_named(
 			&self
 				.rules
 				.into_iter()
-				.map(|(kind, parameters)| match kind {
-					RuleKind::AcceptFilesByGlob => {
-						RulePerKind::new_accept_files_by_globs_str(parameters)
-					}
-					RuleKind::RejectFilesByGlob => {
-						RulePerKind::new_reject_files_by_globs_str(parameters)
-					}
-					RuleKind::AcceptIfChildrenDirectoriesArePresent => {
-						Ok(RulePerKind::AcceptIfChildrenDirectoriesArePresent(
-							parameters.into_iter().collect(),
-						))
-					}
-					RuleKind::RejectIfChildrenDirectoriesArePresent => {
-						Ok(RulePerKind::RejectIfChildrenDirectoriesArePresent(
-							parameters.into_iter().collect(),
-						))
-					}
-				})
+				.map(|(kind, parameters)| RulePerKind::new(kind, parameters))
 				.collect::<Result<Vec<_>, _>>()?,
 		)?;
 
@@ -138,6 +139,7 @@ impl IndexerRuleCreateArgs {
 					vec![
 						name::set(Some(self.name)),
 						rules_per_kind::set(Some(rules_data)),
+						scope::set(scope),
 						date_created::set(Some(date_created.into())),
 						date_modified::set(Some(date_created.into())),
 					],
@@ -184,6 +186,20 @@ pub enum RulePerKind {
 }
 
 impl RulePerKind {
+	/// Builds a rule from the parameters sent by the client, see [`IndexerRuleCreateArgs`]
+	pub fn new(kind: RuleKind, parameters: Vec<String>) -> Result<Self, IndexerRuleError> {
+		match kind {
+			RuleKind::AcceptFilesByGlob => Self::new_accept_files_by_globs_str(parameters),
+			RuleKind::RejectFilesByGlob => Self::new_reject_files_by_globs_str(parameters),
+			RuleKind::AcceptIfChildrenDirectoriesArePresent => Ok(
+				Self::AcceptIfChildrenDirectoriesArePresent(parameters.into_iter().collect()),
+			),
+			RuleKind::RejectIfChildrenDirectoriesArePresent => Ok(
+				Self::RejectIfChildrenDirectoriesArePresent(parameters.into_iter().collect()),
+			),
+		}
+	}
+
 	fn new_files_by_globs_str_and_kind(
 		globs_str: impl IntoIterator<Item = impl AsRef<str>>,
 		kind_fn: impl Fn(Vec<Glob>, GlobSet) -> Self,
@@ -427,6 +443,13 @@ impl<'de> Deserialize<'de> for RulePerKind {
 }
 
 impl RulePerKind {
+	/// Whether the rule can reject an entry by its path alone, so the walker applies it before reading
+	/// the entry's metadata. Accept globs only look at the path too, but an entry they don't accept
+	/// is still walked into if it's a directory.
+	pub fn is_name_only(&self) -> bool {
+		matches!(self, Self::RejectFilesByGlob(..))
+	}
+
 	async fn apply(&self, source: impl AsRef<Path>) -> Result<(RuleKind, bool), IndexerRuleError> {
 		match self {
 			RulePerKind::AcceptIfChildrenDirectoriesArePresent(children) => {
@@ -458,11 +481,59 @@ pub struct IndexerRule {
 	pub name: String,
 	pub default: bool,
 	pub rules: Vec<RulePerKind>,
+	/// Location relative path this rule is limited to, [`None`] for the whole location
+	#[serde(default)]
+	pub scope: Option<PathBuf>,
 	pub date_created: DateTime<Utc>,
 	pub date_modified: DateTime<Utc>,
+	/// How often this rule decided whether an entry was indexed, see [`IndexerRule::snapshot_and_reset`]
+	#[serde(skip)]
+	pub hits: RuleHitCounter,
+}
+
+/// Counters behind [`RuleHits`], only incremented for the rule which actually decided an entry's fate
+#[derive(Debug, Default)]
+pub struct RuleHitCounter {
+	accepted: AtomicU64,
+	rejected: AtomicU64,
+}
+
+/// How many entries a rule accepted and rejected, for users to check their rules do something
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleHits {
+	pub rule_id: Option<i32>,
+	pub name: String,
+	pub accepted: u64,
+	pub rejected: u64,
+}
+
+impl RuleHits {
+	/// Adds up the hits of the same rules, eg. from each walk of an indexer job
+	pub fn merge(into: &mut Vec<Self>, hits: Vec<Self>) {
+		for hit in hits {
+			match into
+				.iter_mut()
+				.find(|h| h.rule_id == hit.rule_id && h.name == hit.name)
+			{
+				Some(existing) => {
+					existing.accepted += hit.accepted;
+					existing.rejected += hit.rejected;
+				}
+				None => into.push(hit),
+			}
+		}
+	}
 }
 
 impl IndexerRule {
+	/// If this rule should be evaluated for a path, given relative to its location.
+	/// Scoped rules only apply to paths at or below their scope.
+	pub fn applies_to(&self, relative_path: impl AsRef<Path>) -> bool {
+		self.scope
+			.as_ref()
+			.map_or(true, |scope| relative_path.as_ref().starts_with(scope))
+	}
+
 	pub async fn apply(
 		&self,
 		source: impl AsRef<Path>,
@@ -470,22 +541,121 @@ impl IndexerRule {
 		try_join_all(self.rules.iter().map(|rule| rule.apply(source.as_ref()))).await
 	}
 
+	/// Credits this rule with deciding an entry was `accepted` or rejected
+	pub fn credit(&self, accepted: bool) {
+		let counter = if accepted {
+			&self.hits.accepted
+		} else {
+			&self.hits.rejected
+		};
+		counter.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// The hits of each rule since the last call, so every walk is counted on its own
+	pub fn snapshot_and_reset(rules: &[IndexerRule]) -> Vec<RuleHits> {
+		rules
+			.iter()
+			.map(|rule| RuleHits {
+				rule_id: rule.id,
+				name: rule.name.clone(),
+				accepted: rule.hits.accepted.swap(0, Ordering::Relaxed),
+				rejected: rule.hits.rejected.swap(0, Ordering::Relaxed),
+			})
+			.collect()
+	}
+
+	/// Applies the rules to a path which isn't part of a location, so scoped rules are skipped.
 	pub async fn apply_all(
 		rules: &[IndexerRule],
 		source: impl AsRef<Path>,
 	) -> Result<HashMap<RuleKind, Vec<bool>>, IndexerRuleError> {
-		try_join_all(rules.iter().map(|rule| rule.apply(source.as_ref())))
-			.await
-			.map(|results| {
-				results.into_iter().flatten().fold(
-					HashMap::<_, Vec<_>>::with_capacity(RuleKind::variant_count()),
-					|mut map, (kind, result)| {
-						map.entry(kind).or_default().push(result);
-						map
-					},
-				)
+		Self::apply_all_filtered(
+			rules.iter().filter(|rule| rule.scope.is_none()),
+			source.as_ref(),
+		)
+		.await
+	}
+
+	/// Applies the rules to a path inside the location at `location_path`, skipping the scoped
+	/// rules which don't cover it.
+	pub async fn apply_all_in_location(
+		rules: &[IndexerRule],
+		location_path: impl AsRef<Path>,
+		source: impl AsRef<Path>,
+	) -> Result<HashMap<RuleKind, Vec<bool>>, IndexerRuleError> {
+		let source = source.as_ref();
+		let relative_path = location_relative_path(location_path.as_ref(), source);
+
+		Self::apply_all_filtered(
+			rules.iter().filter(|rule| rule.applies_to(relative_path)),
+			source,
+		)
+		.await
+	}
+
+	/// Like [`IndexerRule::apply_all_in_location`], but keeping which rule every result came from
+	pub async fn apply_each_in_location<'r>(
+		rules: &'r [IndexerRule],
+		location_path: impl AsRef<Path>,
+		source: impl AsRef<Path>,
+	) -> Result<Vec<(&'r IndexerRule, Vec<(RuleKind, bool)>)>, IndexerRuleError> {
+		let source = source.as_ref();
+		let relative_path = location_relative_path(location_path.as_ref(), source);
+
+		let rules = rules
+			.iter()
+			.filter(|rule| rule.applies_to(relative_path))
+			.collect::<Vec<_>>();
+		let results = try_join_all(rules.iter().map(|rule| rule.apply(source))).await?;
+
+		Ok(rules.into_iter().zip(results).collect())
+	}
+
+	/// The first of `rules` rejecting `source`, inside the location at `location_path`, with one of its
+	/// name only rules (see [`RulePerKind::is_name_only`]). It's the rule applying all of them would have
+	/// credited for the rejection.
+	pub fn rejecting_by_name<'r>(
+		rules: &'r [IndexerRule],
+		location_path: impl AsRef<Path>,
+		source: impl AsRef<Path>,
+	) -> Option<&'r IndexerRule> {
+		let source = source.as_ref();
+		let relative_path = location_relative_path(location_path.as_ref(), source);
+
+		rules
+			.iter()
+			.filter(|rule| rule.applies_to(relative_path))
+			.find(|rule| {
+				rule.rules.iter().any(|rule| match rule {
+					RulePerKind::RejectFilesByGlob(_, reject_glob_set) if rule.is_name_only() => {
+						!reject_by_glob(source, reject_glob_set)
+					}
+					_ => false,
+				})
 			})
 	}
+
+	/// Groups the results of every rule by their kind
+	pub fn results_per_kind<'a>(
+		results: impl IntoIterator<Item = &'a (RuleKind, bool)>,
+	) -> HashMap<RuleKind, Vec<bool>> {
+		results.into_iter().fold(
+			HashMap::<_, Vec<_>>::with_capacity(RuleKind::variant_count()),
+			|mut map, (kind, result)| {
+				map.entry(*kind).or_default().push(*result);
+				map
+			},
+		)
+	}
+
+	async fn apply_all_filtered(
+		rules: impl Iterator<Item = &IndexerRule>,
+		source: &Path,
+	) -> Result<HashMap<RuleKind, Vec<bool>>, IndexerRuleError> {
+		try_join_all(rules.map(|rule| rule.apply(source)))
+			.await
+			.map(|results| Self::results_per_kind(results.iter().flatten()))
+	}
 }
 
 impl TryFrom<&indexer_rule::Data> for IndexerRule {
@@ -500,8 +670,10 @@ impl TryFrom<&indexer_rule::Data> for IndexerRule {
 				&data.rules_per_kind,
 				"indexer_rule.rules_per_kind",
 			)?)?,
+			scope: data.scope.as_ref().map(PathBuf::from),
 			date_created: maybe_missing(data.date_created, "indexer_rule.date_created")?.into(),
 			date_modified: maybe_missing(data.date_modified, "indexer_rule.date_modified")?.into(),
+			hits: Default::default(),
 		})
 	}
 }
@@ -514,6 +686,32 @@ impl TryFrom<indexer_rule::Data> for IndexerRule {
 	}
 }
 
+/// `path` relative to the root of its location, paths outside of it are returned unchanged
+pub fn location_relative_path<'p>(location_path: &Path, path: &'p Path) -> &'p Path {
+	path.strip_prefix(location_path).unwrap_or(path)
+}
+
+/// Scopes are stored as `/` separated location relative paths, an empty one meaning the whole location
+fn normalize_scope(scope: PathBuf) -> Result<Option<String>, IndexerRuleError> {
+	let mut normalized = vec![];
+
+	for component in scope.components() {
+		match component {
+			// Accepting materialized paths like `/Photos/Exports/` as well
+			Component::RootDir | Component::CurDir => {}
+			Component::Normal(part) => normalized.push(
+				part.to_str()
+					.ok_or_else(|| NonUtf8PathError(scope.as_path().into()))?,
+			),
+			Component::Prefix(_) | Component::ParentDir => {
+				return Err(IndexerRuleError::InvalidScope(scope.clone()));
+			}
+		}
+	}
+
+	Ok((!normalized.is_empty()).then(|| normalized.join("/")))
+}
+
 fn accept_by_glob(source: impl AsRef<Path>, accept_glob_set: &GlobSet) -> bool {
 	accept_glob_set.is_match(source.as_ref())
 }
@@ -630,8 +828,10 @@ mod tests {
 				name,
 				default,
 				rules,
+				scope: None,
 				date_created: Utc::now(),
 				date_modified: Utc::now(),
+				hits: Default::default(),
 			}
 		}
 	}
@@ -826,6 +1026,7 @@ mod tests {
 				&& self.name == other.name
 				&& self.default == other.default
 				&& self.rules == other.rules
+				&& self.scope == other.scope
 				&& self.date_created == other.date_created
 				&& self.date_modified == other.date_modified
 		}
@@ -833,6 +1034,19 @@ mod tests {
 
 	impl Eq for IndexerRule {}
 
+	#[test]
+	fn scopes_are_normalized_location_relative_paths() {
+		assert_eq!(
+			normalize_scope("/Photos/Exports/".into()).unwrap(),
+			Some("Photos/Exports".to_string())
+		);
+		assert_eq!(normalize_scope("/".into()).unwrap(), None);
+		assert!(matches!(
+			normalize_scope("Photos/../..".into()),
+			Err(IndexerRuleError::InvalidScope(_))
+		));
+	}
+
 	#[test]
 	fn serde_smoke_test() {
 		let actual = IndexerRule::new(
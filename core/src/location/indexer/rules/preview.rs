@@ -0,0 +1,154 @@
+use crate::location::indexer::old_walk::{evaluate_path, RulesDecision};
+
+use std::{path::PathBuf, time::Duration};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::fs;
+
+use super::{IndexerRule, IndexerRuleError, RuleKind, RulePerKind};
+
+/// At most this many entries are evaluated by a single preview
+pub const MAX_PREVIEW_ENTRIES: usize = 1000;
+/// Previews of directories on slow or stuck drives, of rules or of the next scan of a location, are given up after this long
+pub const PREVIEW_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An unsaved rule set to try on `path`, see [`super::IndexerRuleCreateArgs`] for `rules`.
+#[derive(Type, Deserialize)]
+pub struct IndexerRulesPreviewArgs {
+	pub rules: Vec<(RuleKind, Vec<String>)>,
+	pub path: PathBuf,
+	#[serde(default)]
+	pub limit: Option<u32>,
+}
+
+/// What the indexer would do with a previewed entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Type)]
+pub enum RuleDecision {
+	Accepted,
+	Rejected,
+	/// A directory which isn't indexed itself, but whose children would still be checked
+	WalkedInto,
+	/// The entry couldn't be read or evaluated
+	Error(String),
+}
+
+impl From<RulesDecision> for RuleDecision {
+	fn from(decision: RulesDecision) -> Self {
+		match decision {
+			RulesDecision::Accepted { .. } => Self::Accepted,
+			RulesDecision::Rejected => Self::Rejected,
+			RulesDecision::WalkOnly { .. } => Self::WalkedInto,
+		}
+	}
+}
+
+/// Evaluates `rules` against the first `limit` entries of the directory at `path`, the same way the
+/// walker would, without touching the database. Entries are sorted by path.
+pub async fn preview(
+	rules: Vec<RulePerKind>,
+	path: PathBuf,
+	limit: usize,
+) -> Vec<(PathBuf, RuleDecision)> {
+	let rules = [IndexerRule {
+		id: None,
+		name: "preview".to_string(),
+		default: false,
+		rules,
+		scope: None,
+		date_created: Utc::now(),
+		date_modified: Utc::now(),
+		hits: Default::default(),
+	}];
+
+	let mut read_dir = match fs::read_dir(&path).await {
+		Ok(read_dir) => read_dir,
+		Err(e) => return vec![(path, RuleDecision::Error(e.to_string()))],
+	};
+
+	let mut decisions = Vec::with_capacity(limit.min(MAX_PREVIEW_ENTRIES));
+
+	while decisions.len() < limit.min(MAX_PREVIEW_ENTRIES) {
+		let entry = match read_dir.next_entry().await {
+			Ok(Some(entry)) => entry,
+			Ok(None) => break,
+			Err(e) => {
+				decisions.push((path.clone(), RuleDecision::Error(e.to_string())));
+				break;
+			}
+		};
+
+		let entry_path = entry.path();
+
+		let decision = match entry.metadata().await {
+			Ok(metadata) => evaluate_path(&path, &entry_path, &metadata, &rules, None)
+				.await
+				.map_or_else(|e| RuleDecision::Error(e.to_string()), Into::into),
+			Err(e) => RuleDecision::Error(e.to_string()),
+		};
+
+		decisions.push((entry_path, decision));
+	}
+
+	decisions.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+	decisions
+}
+
+impl IndexerRulesPreviewArgs {
+	pub async fn preview(self) -> Result<Vec<(PathBuf, RuleDecision)>, IndexerRuleError> {
+		let rules = self
+			.rules
+			.into_iter()
+			.map(|(kind, parameters)| RulePerKind::new(kind, parameters))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		Ok(preview(
+			rules,
+			self.path,
+			self.limit
+				.map_or(MAX_PREVIEW_ENTRIES, |limit| limit as usize),
+		)
+		.await)
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	use tempfile::tempdir;
+
+	#[tokio::test]
+	async fn previews_decide_like_the_walker() {
+		let root = tempdir().unwrap();
+		let photos = root.path().join("photos");
+		fs::create_dir(&photos).await.unwrap();
+		for photo in ["photo1.png", "photo2.jpg", "photo3.jpeg", "text.txt"] {
+			fs::File::create(photos.join(photo)).await.unwrap();
+		}
+
+		let only_photos =
+			vec![RulePerKind::new_accept_files_by_globs_str(["{*.png,*.jpg,*.jpeg}"]).unwrap()];
+
+		assert_eq!(
+			preview(only_photos, photos.clone(), 10).await,
+			[
+				(photos.join("photo1.png"), RuleDecision::Accepted),
+				(photos.join("photo2.jpg"), RuleDecision::Accepted),
+				(photos.join("photo3.jpeg"), RuleDecision::Accepted),
+				(photos.join("text.txt"), RuleDecision::Rejected),
+			]
+		);
+
+		assert_eq!(preview(vec![], photos.clone(), 2).await.len(), 2);
+
+		let missing = root.path().join("missing");
+		assert!(matches!(
+			&preview(vec![], missing.clone(), 10).await[..],
+			[(path, RuleDecision::Error(_))] if *path == missing
+		));
+	}
+}
@@ -30,8 +30,10 @@ impl From<SystemIndexerRule> for IndexerRule {
 			name: rule.name.to_string(),
 			default: rule.default,
 			rules: rule.rules,
+			scope: None,
 			date_created: Utc::now(),
 			date_modified: Utc::now(),
+			hits: Default::default(),
 		}
 	}
 }
@@ -0,0 +1,346 @@
+use sd_file_path_helper::{FilePathMetadata, IsolatedFilePathData};
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use tokio::sync::mpsc;
+
+use super::{
+	old_walk::{walk_to_memory, MemoryWalk, WalkedEntry},
+	rules::IndexerRule,
+	IndexerError, IoThrottle, IoTokenBucket,
+};
+
+/// Sent by [`scan_to_memory`] for every directory it walks
+#[derive(Debug, Clone)]
+pub struct ScanUpdate {
+	pub directory: PathBuf,
+	/// Entries found so far
+	pub found_entries: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanOptions {
+	/// Limits the filesystem operations of the scan, unthrottled if not set
+	pub io_throttle: Option<IoThrottle>,
+	pub progress: Option<mpsc::UnboundedSender<ScanUpdate>>,
+	/// Like `find -xdev`, directories on other filesystems than the root are kept but not scanned
+	pub stay_on_filesystem: bool,
+	/// Roots of locations inside the scanned directory, they're kept but their contents are left out
+	pub excluded_roots: Vec<PathBuf>,
+}
+
+/// A file or directory found by [`scan_to_memory`]
+#[derive(Debug, Clone)]
+pub struct ScanNode {
+	pub path: PathBuf,
+	pub is_dir: bool,
+	pub metadata: FilePathMetadata,
+	/// The size of a file, or the sum of the sizes of every file below a directory
+	pub size_in_bytes: u64,
+	/// Sorted by path, empty for files
+	pub children: Vec<ScanNode>,
+	/// Entries of this directory which the rules rejected, their contents weren't scanned
+	pub rejected: Vec<PathBuf>,
+}
+
+/// Everything below a scanned directory, see [`scan_to_memory`]
+#[derive(Debug)]
+pub struct ScanTree {
+	pub root: PathBuf,
+	/// Sum of the sizes of every file found
+	pub size_in_bytes: u64,
+	/// Sorted by path
+	pub children: Vec<ScanNode>,
+	/// Entries of the root which the rules rejected
+	pub rejected: Vec<PathBuf>,
+	/// Non critical errors, the entries they happened on are missing from the tree
+	pub errors: Vec<IndexerError>,
+}
+
+/// Scans `root` applying `indexer_rules` like the indexer does, but without a location or the database,
+/// for tools which only want to look at what would be indexed, eg. the storage usage view.
+pub async fn scan_to_memory(
+	root: impl AsRef<Path>,
+	indexer_rules: &[IndexerRule],
+	ScanOptions {
+		io_throttle,
+		progress,
+		stay_on_filesystem,
+		excluded_roots,
+	}: ScanOptions,
+) -> ScanTree {
+	let root = root.as_ref();
+
+	let MemoryWalk {
+		entries,
+		rejected,
+		errors,
+	} = walk_to_memory(
+		root,
+		indexer_rules,
+		|directory, found_entries| {
+			if let Some(progress) = &progress {
+				progress
+					.send(ScanUpdate {
+						directory: directory.to_path_buf(),
+						found_entries,
+					})
+					.ok();
+			}
+		},
+		|path, is_dir| IsolatedFilePathData::new(0, root, path, is_dir).map_err(Into::into),
+		&IoTokenBucket::new(io_throttle),
+		!stay_on_filesystem,
+		&excluded_roots,
+	)
+	.await;
+
+	let mut entries_by_parent = HashMap::<_, Vec<_>>::new();
+	for entry in entries {
+		entries_by_parent
+			.entry(entry.iso_file_path.to_parts().materialized_path.to_string())
+			.or_default()
+			.push(entry);
+	}
+
+	let mut rejected_by_parent = HashMap::<_, Vec<_>>::new();
+	for path in rejected {
+		if let Some(parent) = path.parent() {
+			rejected_by_parent
+				.entry(parent.to_path_buf())
+				.or_default()
+				.push(path);
+		}
+	}
+
+	let (children, size_in_bytes) =
+		build_nodes(root, "/", &mut entries_by_parent, &mut rejected_by_parent);
+
+	ScanTree {
+		root: root.to_path_buf(),
+		size_in_bytes,
+		children,
+		rejected: sorted(rejected_by_parent.remove(root).unwrap_or_default()),
+		errors,
+	}
+}
+
+/// The nodes of the entries with `materialized_path` and the sum of their sizes
+fn build_nodes(
+	root: &Path,
+	materialized_path: &str,
+	entries_by_parent: &mut HashMap<String, Vec<WalkedEntry>>,
+	rejected_by_parent: &mut HashMap<PathBuf, Vec<PathBuf>>,
+) -> (Vec<ScanNode>, u64) {
+	let mut nodes = entries_by_parent
+		.remove(materialized_path)
+		.unwrap_or_default()
+		.into_iter()
+		.map(
+			|WalkedEntry {
+			     iso_file_path,
+			     metadata,
+			     ..
+			 }| {
+				let path = root.join(&iso_file_path);
+				let is_dir = iso_file_path.to_parts().is_dir;
+
+				let (children, size_in_bytes) = match iso_file_path.materialized_path_for_children()
+				{
+					Some(children_path) => {
+						build_nodes(root, &children_path, entries_by_parent, rejected_by_parent)
+					}
+					None => (vec![], metadata.size_in_bytes),
+				};
+
+				ScanNode {
+					rejected: sorted(rejected_by_parent.remove(&path).unwrap_or_default()),
+					path,
+					is_dir,
+					metadata,
+					size_in_bytes,
+					children,
+				}
+			},
+		)
+		.collect::<Vec<_>>();
+
+	nodes.sort_by(|a, b| a.path.cmp(&b.path));
+	let size_in_bytes = nodes.iter().map(|node| node.size_in_bytes).sum();
+
+	(nodes, size_in_bytes)
+}
+
+fn sorted(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+	paths.sort();
+	paths
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	use super::super::{old_walk::tests::prepare_location, rules::RulePerKind};
+
+	use globset::{Glob, GlobSetBuilder};
+	use tokio::fs;
+
+	fn shape(nodes: &[ScanNode], root: &Path, out: &mut Vec<String>) {
+		for node in nodes {
+			let relative = node.path.strip_prefix(root).unwrap().display().to_string();
+			out.push(if node.is_dir {
+				format!("{relative}/")
+			} else {
+				relative
+			});
+			shape(&node.children, root, out);
+		}
+	}
+
+	#[tokio::test]
+	async fn scans_the_whole_tree_with_directory_sizes() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+		fs::write(root_path.join("photos/photo1.png"), vec![0u8; 100])
+			.await
+			.unwrap();
+		fs::write(root_path.join("photos/text.txt"), vec![0u8; 20])
+			.await
+			.unwrap();
+		fs::write(root_path.join("rust_project/src/main.rs"), vec![0u8; 3])
+			.await
+			.unwrap();
+
+		let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+		let tree = scan_to_memory(
+			root_path,
+			&[],
+			ScanOptions {
+				io_throttle: None,
+				progress: Some(progress_tx),
+				..Default::default()
+			},
+		)
+		.await;
+		assert!(tree.errors.is_empty(), "errors: {:#?}", tree.errors);
+		assert!(tree.rejected.is_empty());
+
+		let mut actual = vec![];
+		shape(&tree.children, root_path, &mut actual);
+		assert_eq!(
+			actual,
+			[
+				"inner/",
+				"inner/node_project/",
+				"inner/node_project/.git/",
+				"inner/node_project/node_modules/",
+				"inner/node_project/node_modules/react/",
+				"inner/node_project/node_modules/react/package.json",
+				"inner/node_project/package.json",
+				"inner/node_project/src/",
+				"inner/node_project/src/App.tsx",
+				"photos/",
+				"photos/photo1.png",
+				"photos/photo2.jpg",
+				"photos/photo3.jpeg",
+				"photos/text.txt",
+				"rust_project/",
+				"rust_project/.git/",
+				"rust_project/Cargo.toml",
+				"rust_project/src/",
+				"rust_project/src/main.rs",
+				"rust_project/target/",
+				"rust_project/target/debug/",
+				"rust_project/target/debug/main",
+			]
+		);
+
+		let size_of = |relative: &str| {
+			fn find<'a>(nodes: &'a [ScanNode], path: &Path) -> Option<&'a ScanNode> {
+				nodes.iter().find_map(|node| {
+					if node.path == path {
+						Some(node)
+					} else {
+						find(&node.children, path)
+					}
+				})
+			}
+
+			find(&tree.children, &root_path.join(relative))
+				.unwrap()
+				.size_in_bytes
+		};
+		assert_eq!(size_of("photos"), 120);
+		assert_eq!(size_of("rust_project"), 3);
+		assert_eq!(size_of("rust_project/src"), 3);
+		assert_eq!(size_of("inner"), 0);
+		assert_eq!(tree.size_in_bytes, 123);
+
+		let mut updates = 0;
+		while progress_rx.try_recv().is_ok() {
+			updates += 1;
+		}
+		assert!(updates > 0);
+	}
+
+	#[tokio::test]
+	async fn rejected_entries_are_kept_apart() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let rules = [IndexerRule::new(
+			"reject node_modules".to_string(),
+			false,
+			vec![RulePerKind::RejectFilesByGlob(
+				vec![],
+				GlobSetBuilder::new()
+					.add(Glob::new("{**/node_modules/*,**/node_modules}").unwrap())
+					.build()
+					.unwrap(),
+			)],
+		)];
+
+		let tree = scan_to_memory(root_path, &rules, ScanOptions::default()).await;
+		assert!(tree.errors.is_empty(), "errors: {:#?}", tree.errors);
+
+		let node_project = &tree.children[0].children[0];
+		assert_eq!(node_project.path, root_path.join("inner/node_project"));
+		assert_eq!(
+			node_project.rejected,
+			[root_path.join("inner/node_project/node_modules")]
+		);
+		assert!(node_project
+			.children
+			.iter()
+			.all(|child| !child.path.ends_with("node_modules")));
+	}
+
+	#[tokio::test]
+	async fn excluded_roots_are_kept_without_their_contents() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+
+		let tree = scan_to_memory(
+			root_path,
+			&[],
+			ScanOptions {
+				excluded_roots: vec![root_path.join("rust_project")],
+				..Default::default()
+			},
+		)
+		.await;
+		assert!(tree.errors.is_empty(), "errors: {:#?}", tree.errors);
+
+		let rust_project = tree
+			.children
+			.iter()
+			.find(|node| node.path == root_path.join("rust_project"))
+			.unwrap();
+		assert!(rust_project.is_dir);
+		assert!(rust_project.children.is_empty());
+	}
+}
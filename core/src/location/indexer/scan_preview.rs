@@ -0,0 +1,406 @@
+use crate::{
+	file_paths_db_fetcher_fn,
+	location::{location_with_indexer_rules, RootDevice},
+	to_remove_db_fetcher_fn,
+};
+
+use sd_file_path_helper::{
+	ensure_sub_path_is_directory, ensure_sub_path_is_in_location, file_path_pub_and_cas_ids,
+	file_path_to_isolate, file_path_walker, IsolatedFilePathData,
+};
+use sd_prisma::prisma::{file_path, PrismaClient};
+use sd_utils::db::maybe_missing;
+
+use std::{
+	collections::VecDeque,
+	future::Future,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use serde::Serialize;
+use specta::Type;
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{
+	iso_file_path_factory, nested_location_roots,
+	old_walk::{
+		keep_walking, walk, ComparisonCaps, ToRemoveEntry, ToWalkEntry, WalkOptions, WalkResult,
+		WalkedEntry, WalkerMemory,
+	},
+	rules::IndexerRule,
+	IndexerError, IoTokenBucket,
+};
+
+/// The most paths a [`ScanPreview`] keeps of each kind of change, its counts are always complete
+pub const MAX_SCAN_PREVIEW_PATHS: usize = 100;
+
+/// How many entries the first walk of a preview looks at before it goes on one directory at a time
+const PREVIEW_WALK_LIMIT: u64 = 1000;
+
+/// What indexing a location again would change, see [`preview_scan`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Type)]
+pub struct ScanPreview {
+	pub to_create: PreviewedChanges,
+	pub to_update: PreviewedChanges,
+	pub to_remove: PreviewedChanges,
+	/// Non critical errors, the entries they happened on aren't counted
+	pub errors: u32,
+}
+
+/// The entries a [`ScanPreview`] found would change one way
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Type)]
+pub struct PreviewedChanges {
+	pub count: u32,
+	/// Relative to the location, at most [`MAX_SCAN_PREVIEW_PATHS`] of them
+	pub sample_paths: Vec<PathBuf>,
+}
+
+impl PreviewedChanges {
+	/// Count another entry, only building its path while there's room for it in the sample
+	fn add(&mut self, path: impl FnOnce() -> PathBuf) {
+		self.count = self.count.saturating_add(1);
+		if self.sample_paths.len() < MAX_SCAN_PREVIEW_PATHS {
+			self.sample_paths.push(path());
+		}
+	}
+}
+
+/// Counts what indexing `sub_path` of `location` again would create, update and remove, walking it
+/// like the indexer job does with the location's current rules, but without writing anything.
+///
+/// Useful after changing the rules of a location, to see what they would do before re-indexing it.
+pub async fn preview_scan(
+	location: &location_with_indexer_rules::Data,
+	sub_path: Option<&Path>,
+	db: &PrismaClient,
+) -> Result<ScanPreview, IndexerError> {
+	let location_id = location.id;
+	let location_path = maybe_missing(&location.path, "location.path").map(Path::new)?;
+
+	let indexer_rules = IndexerRule::for_location(location)?;
+
+	let (to_walk_path, expected_device) = match sub_path {
+		Some(sub_path) if sub_path != Path::new("") => {
+			let full_path = ensure_sub_path_is_in_location(location_path, sub_path).await?;
+			ensure_sub_path_is_directory(location_path, sub_path).await?;
+
+			(full_path, None)
+		}
+		_ => (
+			location_path.to_path_buf(),
+			RootDevice::from_db(location.root_device.as_deref(), &location.pub_id),
+		),
+	};
+
+	let excluded_location_roots = nested_location_roots(location_id, location_path, db).await?;
+
+	let (_, comparison_caps) = ComparisonCaps::detect(&to_walk_path);
+	let (mut preview, to_remove_ids) = preview_walk(
+		&to_walk_path,
+		location_path,
+		expected_device,
+		&indexer_rules,
+		file_paths_db_fetcher_fn!(db),
+		to_remove_db_fetcher_fn!(location_id, db, comparison_caps.case_insensitive),
+		iso_file_path_factory(location_id, location_path),
+		&excluded_location_roots,
+		location.exclude_cloud_placeholders.unwrap_or(false),
+		location.cross_filesystems.unwrap_or(true),
+		comparison_caps,
+	)
+	.await?;
+
+	// Only the ids of the removed file paths are known, their paths are in the database
+	preview.to_remove.sample_paths = db
+		.file_path()
+		.find_many(vec![file_path::id::in_vec(to_remove_ids)])
+		.select(file_path_to_isolate::select())
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|file_path| {
+			IsolatedFilePathData::try_from(file_path)
+				.map_err(|e| {
+					warn!("Failed to isolate a file path to remove in the preview: {e:#?}")
+				})
+				.ok()
+		})
+		.map(|iso_file_path| iso_file_path.as_ref().to_path_buf())
+		.collect();
+
+	Ok(preview)
+}
+
+/// Walks `root`, a directory of the location at `location_root`, entirely like the indexer job does,
+/// counting what it finds instead of writing it.
+///
+/// The paths sampled for removal are left empty, as only their ids are known to the walker; the ids are
+/// returned instead.
+pub(super) async fn preview_walk<FilePathDBFetcherFut, ToRemoveDbFetcherFut>(
+	root: &Path,
+	location_root: &Path,
+	expected_device: Option<RootDevice>,
+	indexer_rules: &[IndexerRule],
+	file_paths_db_fetcher: impl Fn(Vec<file_path::WhereParam>) -> FilePathDBFetcherFut,
+	to_remove_db_fetcher: impl Fn(
+		IsolatedFilePathData<'static>,
+		Vec<IsolatedFilePathData<'static>>,
+	) -> ToRemoveDbFetcherFut,
+	iso_file_path_factory: impl Fn(&Path, bool) -> Result<IsolatedFilePathData<'static>, IndexerError>,
+	excluded_location_roots: &[PathBuf],
+	exclude_cloud_placeholders: bool,
+	cross_filesystems: bool,
+	comparison_caps: ComparisonCaps,
+) -> Result<(ScanPreview, Vec<file_path::id::Type>), IndexerError>
+where
+	FilePathDBFetcherFut: Future<Output = Result<Vec<file_path_walker::Data>, IndexerError>>,
+	ToRemoveDbFetcherFut: Future<Output = Result<Vec<ToRemoveEntry>, IndexerError>>,
+{
+	// Entries are counted and dropped as soon as they're found, they never pile up
+	let memory = WalkerMemory::new(Arc::default(), u64::MAX);
+	let throttle = IoTokenBucket::default();
+
+	let mut preview = ScanPreview::default();
+	let mut to_remove_ids = vec![];
+
+	let first = walk(
+		root,
+		Uuid::new_v4(),
+		indexer_rules,
+		|_, _| {},
+		&file_paths_db_fetcher,
+		&to_remove_db_fetcher,
+		// Unchanged directories are never skipped, their entries may be rejected by the new rules
+		|_| async { Ok(None) },
+		&iso_file_path_factory,
+		&memory,
+		&throttle,
+		WalkOptions {
+			expected_device,
+			limit: PREVIEW_WALK_LIMIT,
+			excluded_location_roots,
+			exclude_cloud_placeholders,
+			cross_filesystems,
+			location_root: Some(location_root),
+			comparison_caps: Some(comparison_caps),
+			..Default::default()
+		},
+	)
+	.await?;
+
+	let mut to_walk = tally(first, &mut preview, &mut to_remove_ids);
+	while let Some(entry) = to_walk.pop_front() {
+		let next = keep_walking(
+			&entry,
+			indexer_rules,
+			|_, _| {},
+			&file_paths_db_fetcher,
+			&to_remove_db_fetcher,
+			|_| async { Ok(None) },
+			&iso_file_path_factory,
+			&memory,
+			&throttle,
+			false,
+			excluded_location_roots,
+			comparison_caps,
+			None,
+			exclude_cloud_placeholders,
+			None,
+		)
+		.await?;
+		to_walk.extend(tally(next, &mut preview, &mut to_remove_ids));
+	}
+
+	Ok((preview, to_remove_ids))
+}
+
+/// Count what a walk found into `preview`, returning the directories it left to walk
+fn tally(
+	WalkResult {
+		walked,
+		to_update,
+		to_walk,
+		to_remove,
+		errors,
+		..
+	}: WalkResult<
+		impl Iterator<Item = WalkedEntry>,
+		impl Iterator<Item = WalkedEntry>,
+		impl Iterator<Item = file_path_pub_and_cas_ids::Data>,
+	>,
+	preview: &mut ScanPreview,
+	to_remove_ids: &mut Vec<file_path::id::Type>,
+) -> VecDeque<ToWalkEntry> {
+	for entry in walked {
+		preview
+			.to_create
+			.add(|| entry.iso_file_path.as_ref().to_path_buf());
+	}
+	for entry in to_update {
+		preview
+			.to_update
+			.add(|| entry.iso_file_path.as_ref().to_path_buf());
+	}
+	for file_path in to_remove {
+		preview.to_remove.count = preview.to_remove.count.saturating_add(1);
+		if to_remove_ids.len() < MAX_SCAN_PREVIEW_PATHS {
+			to_remove_ids.push(file_path.id);
+		}
+	}
+	preview.errors = preview.errors.saturating_add(errors.len() as u32);
+
+	to_walk
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use sd_utils::db::inode_to_db;
+
+	use super::{
+		super::{
+			old_walk::{tests::prepare_location, walk_to_memory},
+			rules::RulePerKind,
+		},
+		*,
+	};
+
+	#[tokio::test]
+	async fn previews_count_what_new_rules_would_remove() {
+		let root = prepare_location().await;
+		let root_path = root.path();
+		let iso_file_path_factory = |path: &Path, is_dir| {
+			IsolatedFilePathData::new(0, root_path, path, is_dir).map_err(Into::into)
+		};
+
+		// Nothing is indexed yet, so everything would be created
+		let (preview, to_remove_ids) = preview_walk(
+			root_path,
+			root_path,
+			None,
+			&[],
+			|_| async { Ok(vec![]) },
+			|_, _| async { Ok(vec![]) },
+			iso_file_path_factory,
+			&[],
+			false,
+			true,
+			ComparisonCaps::default(),
+		)
+		.await
+		.unwrap();
+		assert_eq!(preview.to_create.count, 22);
+		assert_eq!(preview.to_create.sample_paths.len(), 22);
+		assert_eq!(preview.to_update, PreviewedChanges::default());
+		assert_eq!(preview.to_remove, PreviewedChanges::default());
+		assert!(to_remove_ids.is_empty());
+
+		// Stands in for the database once the fixture was indexed without rules
+		let rows = walk_to_memory(
+			root_path,
+			&[],
+			|_, _| {},
+			iso_file_path_factory,
+			&IoTokenBucket::default(),
+			true,
+			&[],
+		)
+		.await
+		.entries
+		.into_iter()
+		.zip(1..)
+		.map(|(entry, id)| {
+			let parts = entry.iso_file_path.to_parts();
+			let row = file_path_walker::Data {
+				pub_id: entry.pub_id.as_bytes().to_vec(),
+				location_id: Some(0),
+				object_id: None,
+				materialized_path: Some(parts.materialized_path.to_string()),
+				is_dir: Some(parts.is_dir),
+				name: Some(parts.name.to_string()),
+				extension: Some(parts.extension.to_string()),
+				date_modified: Some(entry.metadata.modified_at.into()),
+				inode: Some(inode_to_db(entry.metadata.inode)),
+				size_in_bytes_bytes: Some(entry.metadata.size_in_bytes.to_be_bytes().to_vec()),
+				hidden: Some(entry.metadata.hidden),
+				is_placeholder: None,
+				is_location_boundary: None,
+			};
+			(entry.iso_file_path, row, id)
+		})
+		.collect::<Vec<_>>();
+		let rows = &rows;
+
+		// Like the real fetchers, these can only read the database
+		let file_paths_db_fetcher =
+			|_| async move { Ok(rows.iter().map(|(_, row, _)| row.clone()).collect()) };
+		let to_remove_db_fetcher =
+			|parent: IsolatedFilePathData<'static>, kept: Vec<IsolatedFilePathData<'static>>| async move {
+				let children = parent.materialized_path_for_children();
+				Ok(rows
+					.iter()
+					.filter(|(iso_file_path, _, _)| {
+						Some(iso_file_path.to_parts().materialized_path.to_string()) == children
+							&& !kept.contains(iso_file_path)
+					})
+					.map(|(_, _, id)| {
+						file_path_pub_and_cas_ids::Data {
+							id: *id,
+							pub_id: vec![],
+							cas_id: None,
+						}
+						.into()
+					})
+					.collect())
+			};
+		let preview_with = |indexer_rules: Vec<IndexerRule>| async move {
+			preview_walk(
+				root_path,
+				root_path,
+				None,
+				&indexer_rules,
+				file_paths_db_fetcher,
+				to_remove_db_fetcher,
+				iso_file_path_factory,
+				&[],
+				false,
+				true,
+				ComparisonCaps::default(),
+			)
+			.await
+			.unwrap()
+		};
+
+		let (preview, _) = preview_with(vec![]).await;
+		assert_eq!(preview, ScanPreview::default());
+
+		let no_photos = IndexerRule::new(
+			"no photos".to_string(),
+			false,
+			vec![RulePerKind::new_reject_files_by_globs_str(["{*.png,*.jpg,*.jpeg}"]).unwrap()],
+		);
+		let (preview, to_remove_ids) = preview_with(vec![no_photos]).await;
+		assert_eq!(preview.to_create, PreviewedChanges::default());
+		assert_eq!(preview.to_update, PreviewedChanges::default());
+		assert_eq!(preview.errors, 0);
+
+		let mut photo_ids = rows
+			.iter()
+			.filter(|(iso_file_path, _, _)| {
+				["png", "jpg", "jpeg"].contains(&iso_file_path.to_parts().extension)
+			})
+			.map(|(_, _, id)| *id)
+			.collect::<Vec<_>>();
+		photo_ids.sort_unstable();
+		let mut to_remove_ids = to_remove_ids;
+		to_remove_ids.sort_unstable();
+		assert_eq!(preview.to_remove.count, 3);
+		assert_eq!(to_remove_ids, photo_ids);
+
+		// Nothing was applied, without the rule there is still nothing to do
+		let (preview, _) = preview_with(vec![]).await;
+		assert_eq!(preview, ScanPreview::default());
+	}
+}
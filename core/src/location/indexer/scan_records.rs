@@ -0,0 +1,168 @@
+use sd_prisma::prisma::{location, scan_record, PrismaClient, SortOrder};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use specta::Type;
+use tracing::warn;
+
+use super::{rules::RuleHits, AggregatedIndexerError, IndexerError};
+
+/// How many of a location's scans are kept, the oldest ones are removed first
+pub const MAX_SCAN_RECORDS: i32 = 10;
+
+/// One of a location's last scans, see [`ScanSummary`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Type)]
+pub struct ScanRecord {
+	/// Grows by one with every scan of the location
+	pub generation: i32,
+	pub summary: ScanSummary,
+}
+
+/// What's left of an indexer job once it's done, to tell how the location's last scans went
+/// without going through the job history
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct ScanSummary {
+	pub started_at: DateTime<Utc>,
+	pub finished_at: DateTime<Utc>,
+	/// The directory scanned relative to the location, `None` when the whole location was
+	pub sub_path: Option<String>,
+	pub found_entries: u32,
+	pub indexed: u32,
+	pub updated: u32,
+	pub removed: u32,
+	/// Entries deleted while they were being walked
+	pub vanished: u32,
+	/// How many directories down from the location's root the scan went
+	#[serde(default)]
+	pub max_depth_reached: u32,
+	/// Directories on other filesystems which weren't walked into, see [`super::OldIndexerJobInit::cross_filesystems`]
+	#[serde(default)]
+	pub skipped_mounts: u32,
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub bytes_indexed: u64,
+	pub scan_read_ms: u32,
+	pub db_write_ms: u32,
+	pub errors: Vec<ScanErrorCount>,
+	pub rule_hits: Vec<ScanRuleHits>,
+}
+
+/// How many non critical errors of this kind the scan ran into, across all directories
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct ScanErrorCount {
+	pub kind: String,
+	pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct ScanRuleHits {
+	pub name: String,
+	pub accepted: u32,
+	pub rejected: u32,
+}
+
+impl ScanSummary {
+	/// Adds up the errors of each kind, whichever directory they were in
+	pub(super) fn error_counts(errors: &[AggregatedIndexerError]) -> Vec<ScanErrorCount> {
+		let mut counts = Vec::<ScanErrorCount>::new();
+		for error in errors {
+			let count = saturating_u32(error.count);
+			match counts.iter_mut().find(|c| c.kind == error.kind) {
+				Some(c) => c.count = c.count.saturating_add(count),
+				None => counts.push(ScanErrorCount {
+					kind: error.kind.clone(),
+					count,
+				}),
+			}
+		}
+		counts
+	}
+
+	pub(super) fn rule_hits(hits: &[RuleHits]) -> Vec<ScanRuleHits> {
+		hits.iter()
+			.map(|hits| ScanRuleHits {
+				name: hits.name.clone(),
+				accepted: saturating_u32(hits.accepted),
+				rejected: saturating_u32(hits.rejected),
+			})
+			.collect()
+	}
+}
+
+/// The frontend can't take 64 bit integers, and no scan comes close to overflowing these counts
+pub(super) fn saturating_u32(n: impl TryInto<u32>) -> u32 {
+	n.try_into().unwrap_or(u32::MAX)
+}
+
+/// Keeps `summary` as the location's newest scan, removing the ones older than the last [`MAX_SCAN_RECORDS`].
+///
+/// `scan_generation` is the one the scan got from [`super::next_scan_generation`], so scans running at
+/// the same time never get the same one.
+pub(super) async fn record_scan(
+	location_id: location::id::Type,
+	scan_generation: i64,
+	summary: &ScanSummary,
+	db: &PrismaClient,
+) -> Result<(), IndexerError> {
+	db.scan_record()
+		.create(
+			location::id::equals(location_id),
+			// Billions of scans of a location are way out of reach
+			i32::try_from(scan_generation).unwrap_or(i32::MAX),
+			summary.finished_at.into(),
+			serde_json::to_vec(summary)?,
+			vec![],
+		)
+		.exec()
+		.await?;
+
+	// Failed scans leave no record, so the oldest ones kept can't be told from the generation alone
+	let older = db
+		.scan_record()
+		.find_many(vec![scan_record::location_id::equals(location_id)])
+		.order_by(scan_record::generation::order(SortOrder::Desc))
+		.skip(i64::from(MAX_SCAN_RECORDS))
+		.select(scan_record::select!({ id }))
+		.exec()
+		.await?;
+	if !older.is_empty() {
+		db.scan_record()
+			.delete_many(vec![scan_record::id::in_vec(
+				older.into_iter().map(|record| record.id).collect(),
+			)])
+			.exec()
+			.await?;
+	}
+
+	Ok(())
+}
+
+/// The location's last scans, newest first
+pub async fn scan_records(
+	location_id: location::id::Type,
+	db: &PrismaClient,
+) -> Result<Vec<ScanRecord>, IndexerError> {
+	Ok(db
+		.scan_record()
+		.find_many(vec![scan_record::location_id::equals(location_id)])
+		.order_by(scan_record::generation::order(SortOrder::Desc))
+		.exec()
+		.await?
+		.into_iter()
+		.filter_map(|record| match serde_json::from_slice(&record.summary) {
+			Ok(summary) => Some(ScanRecord {
+				generation: record.generation,
+				summary,
+			}),
+			Err(e) => {
+				warn!(
+					"Failed to parse scan record <location_id={location_id}, generation={}>: {e:#?}",
+					record.generation
+				);
+				None
+			}
+		})
+		.collect())
+}
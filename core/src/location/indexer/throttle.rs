@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+	sync::Mutex,
+	time::{sleep, Duration, Instant},
+};
+
+/// Filesystem operations per second allowed to locations indexed in the background
+pub const BACKGROUND_INDEXING_OPS_PER_SEC: u32 = 200;
+
+/// Limit on how many filesystem operations (reading a directory entry, fetching metadata) the
+/// walker does per second, so indexing a slow drive doesn't make the whole machine sluggish
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoThrottle {
+	pub ops_per_sec: u32,
+}
+
+impl IoThrottle {
+	/// The throttle of a location, depending on its `background_indexing` option
+	pub fn for_location(background_indexing: Option<bool>) -> Option<Self> {
+		background_indexing.unwrap_or(false).then_some(Self {
+			ops_per_sec: BACKGROUND_INDEXING_OPS_PER_SEC,
+		})
+	}
+}
+
+/// Token bucket enforcing an [`IoThrottle`], shared by all walks of a location's indexer job.
+///
+/// Clones share the same bucket, so changing its throttle takes effect on walks already running.
+#[derive(Debug, Clone, Default)]
+pub struct IoTokenBucket {
+	state: Arc<Mutex<BucketState>>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+	throttle: Option<IoThrottle>,
+	tokens: f64,
+	refilled_at: Instant,
+}
+
+impl Default for BucketState {
+	fn default() -> Self {
+		Self {
+			throttle: None,
+			tokens: 0.0,
+			refilled_at: Instant::now(),
+		}
+	}
+}
+
+impl IoTokenBucket {
+	pub fn new(throttle: Option<IoThrottle>) -> Self {
+		Self {
+			state: Arc::new(Mutex::new(BucketState {
+				throttle,
+				..Default::default()
+			})),
+		}
+	}
+
+	pub async fn set_throttle(&self, throttle: Option<IoThrottle>) {
+		self.state.lock().await.throttle = throttle;
+	}
+
+	/// Waits until another filesystem operation is allowed, returns right away when unthrottled
+	pub async fn acquire(&self) {
+		loop {
+			let wait = {
+				let mut state = self.state.lock().await;
+
+				let Some(IoThrottle { ops_per_sec }) = state.throttle else {
+					return;
+				};
+				let ops_per_sec = f64::from(ops_per_sec.max(1));
+
+				// At most a second worth of operations can be saved up
+				let now = Instant::now();
+				state.tokens = ops_per_sec.min(
+					state.tokens
+						+ now.duration_since(state.refilled_at).as_secs_f64() * ops_per_sec,
+				);
+				state.refilled_at = now;
+
+				if state.tokens >= 1.0 {
+					state.tokens -= 1.0;
+					return;
+				}
+
+				Duration::from_secs_f64((1.0 - state.tokens) / ops_per_sec)
+			};
+
+			// Sleeping without the lock and checking the throttle again, as it may have changed
+			sleep(wait).await;
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	use tokio::{spawn, time::timeout};
+
+	#[tokio::test]
+	async fn lifting_the_throttle_frees_waiting_walks() {
+		let bucket = IoTokenBucket::new(Some(IoThrottle { ops_per_sec: 1 }));
+
+		let waiting = spawn({
+			let bucket = bucket.clone();
+			async move {
+				for _ in 0..100 {
+					bucket.acquire().await;
+				}
+			}
+		});
+
+		sleep(Duration::from_millis(100)).await;
+		assert!(!waiting.is_finished());
+
+		bucket.set_throttle(None).await;
+		timeout(Duration::from_secs(2), waiting)
+			.await
+			.unwrap()
+			.unwrap();
+	}
+}
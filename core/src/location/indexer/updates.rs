@@ -0,0 +1,336 @@
+use sd_prisma::prisma::location;
+
+use std::{
+	collections::HashMap,
+	mem,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use specta::Type;
+use tokio::{
+	sync::broadcast::{self, error::RecvError},
+	time::sleep,
+};
+use uuid::Uuid;
+
+use super::old_walk::WalkedEntry;
+
+/// Updates which slow subscribers haven't received yet are dropped once there are this many, oldest first
+const CHANNEL_CAPACITY: usize = 64;
+/// Entries are sent as soon as this many are waiting, even within the throttle interval
+const MAX_BATCH_SIZE: usize = 1000;
+/// Entries of a location are sent at most this often, so the Explorer isn't re-rendered for every batch
+const THROTTLE_INTERVAL: Duration = Duration::from_millis(500);
+/// While a location is being indexed, subscribers are told to refetch at least this often
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Type)]
+pub enum IndexedEntryKind {
+	Created,
+	Updated,
+}
+
+/// A file_path which was just written by the indexer.
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct IndexedEntry {
+	pub pub_id: Uuid,
+	pub kind: IndexedEntryKind,
+	pub materialized_path: String,
+	pub name: String,
+	pub extension: String,
+	pub is_dir: bool,
+}
+
+impl IndexedEntry {
+	pub(super) fn new(entry: &WalkedEntry, kind: IndexedEntryKind) -> Self {
+		let parts = entry.iso_file_path.to_parts();
+
+		Self {
+			pub_id: entry.pub_id,
+			kind,
+			materialized_path: parts.materialized_path.to_string(),
+			name: parts.name.to_string(),
+			extension: parts.extension.to_string(),
+			is_dir: parts.is_dir,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+#[serde(tag = "type")]
+pub enum IndexingUpdate {
+	Entries {
+		location_id: location::id::Type,
+		entries: Vec<IndexedEntry>,
+	},
+	/// Some updates might have been missed, so the listing has to be refetched once
+	Resync { location_id: location::id::Type },
+}
+
+impl IndexingUpdate {
+	fn location_id(&self) -> location::id::Type {
+		match self {
+			Self::Entries { location_id, .. } | Self::Resync { location_id } => *location_id,
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Pending {
+	entries: Vec<IndexedEntry>,
+	last_sent: Instant,
+	last_resync: Instant,
+	last_pushed: Instant,
+}
+
+/// Streams the entries the indexer writes to the database, so the Explorer can show them while indexing.
+#[derive(Debug)]
+pub struct IndexingUpdates {
+	tx: broadcast::Sender<IndexingUpdate>,
+	pending: Arc<Mutex<HashMap<location::id::Type, Pending>>>,
+}
+
+impl Default for IndexingUpdates {
+	fn default() -> Self {
+		Self {
+			tx: broadcast::channel(CHANNEL_CAPACITY).0,
+			pending: Default::default(),
+		}
+	}
+}
+
+impl IndexingUpdates {
+	pub fn subscribe(&self, location_id: location::id::Type) -> IndexingUpdatesSubscriber {
+		IndexingUpdatesSubscriber {
+			rx: self.tx.subscribe(),
+			location_id,
+		}
+	}
+
+	pub(super) fn push(
+		&self,
+		location_id: location::id::Type,
+		entries: impl IntoIterator<Item = IndexedEntry>,
+	) {
+		let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+		let pending = pending.entry(location_id).or_insert_with(|| {
+			self.flush_periodically(location_id);
+
+			Pending {
+				entries: vec![],
+				last_sent: Instant::now(),
+				last_resync: Instant::now(),
+				last_pushed: Instant::now(),
+			}
+		});
+
+		pending.entries.extend(entries);
+		pending.last_pushed = Instant::now();
+
+		if pending.entries.len() >= MAX_BATCH_SIZE
+			|| pending.last_sent.elapsed() >= THROTTLE_INTERVAL
+		{
+			pending.last_sent = Instant::now();
+			self.send(IndexingUpdate::Entries {
+				location_id,
+				entries: mem::take(&mut pending.entries),
+			});
+		}
+
+		if pending.last_resync.elapsed() >= RESYNC_INTERVAL {
+			pending.last_resync = Instant::now();
+			self.send(IndexingUpdate::Resync { location_id });
+		}
+	}
+
+	/// Sends whatever is still waiting once the indexer is done with a location, followed by a resync.
+	pub(crate) fn finish(&self, location_id: location::id::Type) {
+		let pending = self
+			.pending
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&location_id);
+
+		if let Some(Pending { entries, .. }) = pending.filter(|pending| !pending.entries.is_empty())
+		{
+			self.send(IndexingUpdate::Entries {
+				location_id,
+				entries,
+			});
+		}
+
+		self.send(IndexingUpdate::Resync { location_id });
+	}
+
+	/// Sends the entries held back by the throttle once it's over, as no more may be pushed for a while.
+	/// The location is forgotten once nothing was pushed for a whole interval, eg. as its indexing was
+	/// cancelled without getting to [`Self::finish`].
+	fn flush_periodically(&self, location_id: location::id::Type) {
+		let (tx, pending) = (self.tx.clone(), Arc::clone(&self.pending));
+
+		tokio::spawn(async move {
+			loop {
+				sleep(THROTTLE_INTERVAL).await;
+
+				let mut pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+				let Some(location) = pending.get_mut(&location_id) else {
+					// Finished in the meantime
+					break;
+				};
+				if location.entries.is_empty() {
+					if location.last_pushed.elapsed() >= THROTTLE_INTERVAL {
+						pending.remove(&location_id);
+						break;
+					}
+					continue;
+				}
+
+				location.last_sent = Instant::now();
+				tx.send(IndexingUpdate::Entries {
+					location_id,
+					entries: mem::take(&mut location.entries),
+				})
+				.ok();
+			}
+		});
+	}
+
+	fn send(&self, update: IndexingUpdate) {
+		// It's fine if nobody is listening
+		self.tx.send(update).ok();
+	}
+}
+
+pub struct IndexingUpdatesSubscriber {
+	rx: broadcast::Receiver<IndexingUpdate>,
+	location_id: location::id::Type,
+}
+
+impl IndexingUpdatesSubscriber {
+	/// The next update of the subscribed location, [`None`] once the library is gone.
+	/// If this subscriber fell behind, the updates it missed are replaced by a single resync.
+	pub async fn next(&mut self) -> Option<IndexingUpdate> {
+		loop {
+			match self.rx.recv().await {
+				Ok(update) if update.location_id() == self.location_id => return Some(update),
+				Ok(_) => continue,
+				Err(RecvError::Lagged(_)) => {
+					return Some(IndexingUpdate::Resync {
+						location_id: self.location_id,
+					})
+				}
+				Err(RecvError::Closed) => return None,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use super::*;
+
+	fn entries(count: usize) -> Vec<IndexedEntry> {
+		(0..count)
+			.map(|i| IndexedEntry {
+				pub_id: Uuid::new_v4(),
+				kind: IndexedEntryKind::Created,
+				materialized_path: "/".to_string(),
+				name: format!("file{i}"),
+				extension: "txt".to_string(),
+				is_dir: false,
+			})
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn streamed_entries_match_the_written_ones() {
+		let updates = IndexingUpdates::default();
+		let mut subscriber = updates.subscribe(1);
+		let mut other_location = updates.subscribe(2);
+
+		let written = entries(2500);
+		for batch in written.chunks(100) {
+			updates.push(1, batch.iter().cloned());
+		}
+		updates.finish(1);
+
+		let mut streamed = HashSet::new();
+		loop {
+			match subscriber.next().await.unwrap() {
+				IndexingUpdate::Entries {
+					location_id,
+					entries,
+				} => {
+					assert_eq!(location_id, 1);
+					assert!(entries.len() <= MAX_BATCH_SIZE + 100);
+					streamed.extend(entries.into_iter().map(|entry| entry.pub_id));
+				}
+				IndexingUpdate::Resync { .. } => break,
+			}
+		}
+
+		assert_eq!(
+			streamed,
+			written
+				.iter()
+				.map(|entry| entry.pub_id)
+				.collect::<HashSet<_>>()
+		);
+
+		// Only told about its own location
+		updates.finish(2);
+		assert!(matches!(
+			other_location.next().await,
+			Some(IndexingUpdate::Resync { location_id: 2 })
+		));
+	}
+
+	#[tokio::test]
+	async fn held_back_entries_are_sent_without_more_being_pushed() {
+		let updates = IndexingUpdates::default();
+		let mut subscriber = updates.subscribe(1);
+
+		// Within the throttle interval, and the indexer never gets to finish
+		updates.push(1, entries(3));
+
+		let update = tokio::time::timeout(THROTTLE_INTERVAL * 4, subscriber.next())
+			.await
+			.unwrap();
+		assert!(matches!(
+			update,
+			Some(IndexingUpdate::Entries { location_id: 1, entries }) if entries.len() == 3
+		));
+
+		// Nothing came in since, so the location isn't kept around
+		sleep(THROTTLE_INTERVAL * 3).await;
+		assert!(updates
+			.pending
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.is_empty());
+	}
+
+	#[tokio::test]
+	async fn slow_subscribers_are_told_to_resync() {
+		let updates = IndexingUpdates::default();
+		let mut subscriber = updates.subscribe(1);
+
+		for _ in 0..CHANNEL_CAPACITY * 2 {
+			updates.push(1, entries(MAX_BATCH_SIZE));
+		}
+
+		assert!(matches!(
+			subscriber.next().await,
+			Some(IndexingUpdate::Resync { location_id: 1 })
+		));
+		// After which it's caught up with the newest updates
+		assert!(matches!(
+			subscriber.next().await,
+			Some(IndexingUpdate::Entries { location_id: 1, .. })
+		));
+	}
+}
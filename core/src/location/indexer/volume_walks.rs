@@ -0,0 +1,212 @@
+use crate::{
+	node::config::NodeConfig,
+	volume::{volume_for_path, DiskType, Volume},
+};
+
+use std::{
+	collections::{HashMap, VecDeque},
+	path::Path,
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use tokio::sync::oneshot;
+
+/// How many indexer walks run at once on a single spinning disk, the rest wait for their turn.
+pub(crate) fn walks_per_spinning_disk(config: &NodeConfig) -> usize {
+	config.walks_per_spinning_disk.unwrap_or(1).max(1) as usize
+}
+
+/// Whether walks of `path` seek on a spinning disk, so shouldn't run alongside other walks of it.
+/// Paths on unknown volumes are treated as solid state, so they're never held back.
+pub(crate) fn is_on_spinning_disk(volumes: &[Volume], path: &Path) -> bool {
+	volume_for_path(volumes, path).is_some_and(|volume| volume.disk_type == DiskType::HDD)
+}
+
+/// Schedules the indexer walks per device, so the walks of two disks run at the same time but a
+/// spinning disk isn't made to seek back and forth between two walks.
+#[derive(Debug, Default)]
+pub struct VolumeWalks {
+	devices: Mutex<HashMap<u64, DeviceWalks>>,
+}
+
+#[derive(Debug, Default)]
+struct DeviceWalks {
+	active: usize,
+	waiting: VecDeque<oneshot::Sender<VolumeWalkSlot>>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Enqueued {
+	Ready(VolumeWalkSlot),
+	Queued {
+		/// 1 is the next walk to start
+		position: u32,
+		/// Resolves once it's our turn
+		rx: oneshot::Receiver<VolumeWalkSlot>,
+	},
+}
+
+/// A walk running on a device. Dropping this hands the slot to the next walk waiting for the device.
+#[derive(Debug)]
+pub struct VolumeWalkSlot {
+	walks: Arc<VolumeWalks>,
+	device: Option<u64>,
+}
+
+impl Drop for VolumeWalkSlot {
+	fn drop(&mut self) {
+		if let Some(device) = self.device.take() {
+			self.walks.release(device);
+		}
+	}
+}
+
+impl VolumeWalks {
+	/// Queue a walk on `device`, walks of solid state volumes are never queued.
+	pub(crate) fn enqueue(
+		self: &Arc<Self>,
+		device: u64,
+		spinning: bool,
+		walks_per_disk: usize,
+	) -> Enqueued {
+		if !spinning {
+			return Enqueued::Ready(VolumeWalkSlot {
+				walks: self.clone(),
+				device: None,
+			});
+		}
+
+		let mut devices = self.devices.lock().unwrap_or_else(PoisonError::into_inner);
+		let walks = devices.entry(device).or_default();
+
+		if walks.active < walks_per_disk && walks.waiting.is_empty() {
+			walks.active += 1;
+			return Enqueued::Ready(VolumeWalkSlot {
+				walks: self.clone(),
+				device: Some(device),
+			});
+		}
+
+		let (tx, rx) = oneshot::channel();
+		walks.waiting.push_back(tx);
+		Enqueued::Queued {
+			position: walks.waiting.len() as u32,
+			rx,
+		}
+	}
+
+	fn release(self: &Arc<Self>, device: u64) {
+		let mut slot = VolumeWalkSlot {
+			walks: self.clone(),
+			device: None,
+		};
+
+		loop {
+			let next = {
+				let mut devices = self.devices.lock().unwrap_or_else(PoisonError::into_inner);
+				let Some(walks) = devices.get_mut(&device) else {
+					return;
+				};
+
+				match walks.waiting.pop_front() {
+					Some(tx) => tx,
+					None => {
+						walks.active = walks.active.saturating_sub(1);
+						if walks.active == 0 {
+							devices.remove(&device);
+						}
+						return;
+					}
+				}
+			};
+
+			// The slot moves straight to the next walk so `active` stays the same
+			slot.device = Some(device);
+			match next.send(slot) {
+				Ok(()) => return,
+				Err(mut unused) => {
+					// Its job went away while waiting, so try the one after it
+					unused.device = None;
+					slot = unused;
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic)]
+mod tests {
+	use super::*;
+
+	const DISK: u64 = 2049;
+	const OTHER_DISK: u64 = 2065;
+
+	#[test]
+	fn walks_of_the_same_disk_take_turns() {
+		let walks = Arc::new(VolumeWalks::default());
+
+		let Enqueued::Ready(first) = walks.enqueue(DISK, true, 1) else {
+			panic!("the first walk of a disk should start straight away");
+		};
+		let Enqueued::Queued { position, mut rx } = walks.enqueue(DISK, true, 1) else {
+			panic!("a second walk of the same disk should wait");
+		};
+		assert_eq!(position, 1);
+		assert!(rx.try_recv().is_err());
+
+		drop(first);
+		let second = rx.try_recv().unwrap();
+		assert_eq!(second.device, Some(DISK));
+
+		drop(second);
+		assert!(walks.devices.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn walks_of_different_disks_overlap() {
+		let walks = Arc::new(VolumeWalks::default());
+
+		let Enqueued::Ready(_first) = walks.enqueue(DISK, true, 1) else {
+			panic!("the first walk of a disk should start straight away");
+		};
+		assert!(matches!(
+			walks.enqueue(OTHER_DISK, true, 1),
+			Enqueued::Ready(_)
+		));
+	}
+
+	#[test]
+	fn solid_state_walks_are_never_queued() {
+		let walks = Arc::new(VolumeWalks::default());
+
+		let slots = (0..4)
+			.map(|_| match walks.enqueue(DISK, false, 1) {
+				Enqueued::Ready(slot) => slot,
+				Enqueued::Queued { .. } => panic!("walks of an SSD shouldn't wait"),
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(slots.len(), 4);
+		assert!(walks.devices.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn a_walk_which_went_away_is_skipped() {
+		let walks = Arc::new(VolumeWalks::default());
+
+		let Enqueued::Ready(first) = walks.enqueue(DISK, true, 1) else {
+			panic!("the first walk of a disk should start straight away");
+		};
+		let Enqueued::Queued { rx: gone, .. } = walks.enqueue(DISK, true, 1) else {
+			panic!("a second walk of the same disk should wait");
+		};
+		let Enqueued::Queued { mut rx, position } = walks.enqueue(DISK, true, 1) else {
+			panic!("a third walk of the same disk should wait");
+		};
+		assert_eq!(position, 2);
+
+		drop(gone);
+		drop(first);
+		assert!(rx.try_recv().is_ok());
+	}
+}
@@ -1,8 +1,10 @@
 use crate::{
 	library::{Library, LibraryId},
+	location::RootDevice,
 	Node,
 };
 
+use sd_file_path_helper::get_device_from_path;
 use sd_prisma::prisma::location;
 use sd_utils::db::maybe_missing;
 
@@ -35,6 +37,11 @@ pub(super) async fn check_online(
 	// TODO(N): This isn't gonna work with removable media and this will likely permanently break if the DB is restored from a backup.
 	if location.instance_id == Some(library.config().await.instance_id) {
 		match fs::metadata(&location_path).await {
+			// Another volume, or the empty mountpoint, sits at the path of a drive which isn't mounted
+			Ok(_) if !on_root_device(location, location_path).await => {
+				node.locations.remove_online(&pub_id).await;
+				Ok(false)
+			}
 			Ok(_) => {
 				node.locations.add_online(pub_id).await;
 				Ok(true)
@@ -55,6 +62,21 @@ pub(super) async fn check_online(
 	}
 }
 
+async fn on_root_device(location: &location::Data, location_path: &Path) -> bool {
+	let Some(expected) = RootDevice::from_db(location.root_device.as_deref(), &location.pub_id)
+	else {
+		return true;
+	};
+
+	match get_device_from_path(location_path).await {
+		Ok(found) => expected.matches(location_path, found).await,
+		Err(e) => {
+			error!("Failed to get the device of location root: {e:#?}");
+			true
+		}
+	}
+}
+
 pub(super) async fn location_check_sleep(
 	location_id: location::id::Type,
 	library: Arc<Library>,
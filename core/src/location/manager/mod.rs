@@ -1,15 +1,16 @@
 use crate::{
 	library::{Library, LibraryManagerEvent},
+	location::indexer::{IndexerError, IoThrottle, IoTokenBucket, VolumeWalks},
 	old_job::JobManagerError,
 	Node,
 };
 
-use sd_file_path_helper::FilePathError;
+use sd_file_path_helper::{get_device_from_path, get_inode_from_path, FilePathError};
 use sd_prisma::prisma::location;
 use sd_utils::{db::MissingFieldError, error::FileIOError};
 
 use std::{
-	collections::BTreeSet,
+	collections::{BTreeSet, HashMap},
 	path::{Path, PathBuf},
 	sync::Arc,
 };
@@ -20,7 +21,7 @@ use tokio::sync::{
 	broadcast::{self, Receiver},
 	oneshot, RwLock,
 };
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use tokio::sync::mpsc;
 use uuid::Uuid;
@@ -28,6 +29,9 @@ use uuid::Uuid;
 mod watcher;
 
 mod helpers;
+mod roots;
+
+pub use roots::{ActiveLocationRoots, RootClaim};
 
 #[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
@@ -103,6 +107,8 @@ pub enum LocationManagerError {
 	JobManager(#[from] JobManagerError),
 	#[error("missing-field")]
 	MissingField(#[from] MissingFieldError),
+	#[error("indexer error: {0}")]
+	Indexer(#[from] IndexerError),
 
 	#[error(transparent)]
 	FileIO(#[from] FileIOError),
@@ -187,6 +193,15 @@ pub struct Locations {
 
 	watcher_management_tx: mpsc::Sender<WatcherManagementMessage>,
 	stop_tx: Option<oneshot::Sender<()>>,
+
+	/// Shared by the indexer jobs of each location, keyed by library and location id
+	io_buckets: RwLock<HashMap<(Uuid, location::id::Type), IoTokenBucket>>,
+
+	/// The roots the indexer walked, so a directory isn't the root of two locations of a library
+	active_roots: ActiveLocationRoots,
+
+	/// Takes turns between the indexer walks of a spinning disk, shared by every library
+	volume_walks: Arc<VolumeWalks>,
 }
 
 impl Locations {
@@ -206,6 +221,9 @@ impl Locations {
 					location_management_tx,
 					watcher_management_tx,
 					stop_tx: Some(stop_tx),
+					io_buckets: Default::default(),
+					active_roots: Default::default(),
+					volume_walks: Default::default(),
 				},
 				LocationManagerActor {
 					location_management_rx,
@@ -272,15 +290,58 @@ impl Locations {
 		location_id: location::id::Type,
 		library: Arc<Library>,
 	) -> Result<(), LocationManagerError> {
+		self.claim_root(location_id, &library).await;
 		self.location_management_message(location_id, library, ManagementMessageAction::Add)
 			.await
 	}
 
+	/// Claim the root of a location as soon as it's managed, so a location walked for the first time
+	/// is told apart from every other location of the library and not only the ones walked since startup.
+	/// A location whose root is claimed already is left for its walk to reject, see [`ActiveLocationRoots`].
+	async fn claim_root(&self, location_id: location::id::Type, library: &Library) {
+		let path = match library
+			.db
+			.location()
+			.find_unique(location::id::equals(location_id))
+			.exec()
+			.await
+		{
+			Ok(Some(location::Data {
+				path: Some(path), ..
+			})) => path,
+			Ok(_) => return,
+			Err(e) => {
+				error!("Failed to fetch location <id='{location_id}'> to claim its root: {e:#?}");
+				return;
+			}
+		};
+
+		let identity = match (
+			get_device_from_path(&path).await,
+			get_inode_from_path(&path).await,
+		) {
+			(Ok(device), Ok(inode)) => (device, inode),
+			// Offline locations are claimed by their next walk
+			(Err(e), _) | (_, Err(e)) => {
+				debug!("Couldn't claim the root of location <id='{location_id}'>: {e}");
+				return;
+			}
+		};
+
+		if let Err(other_location_id) = self.active_roots.claim(library.id, location_id, identity) {
+			warn!(
+				"Location <id='{location_id}'> has the same root as location \
+				<id='{other_location_id}'>, it won't be indexed"
+			);
+		}
+	}
+
 	pub async fn remove(
 		&self,
 		location_id: location::id::Type,
 		library: Arc<Library>,
 	) -> Result<(), LocationManagerError> {
+		self.active_roots.release(library.id, location_id);
 		self.location_management_message(location_id, library, ManagementMessageAction::Remove)
 			.await
 	}
@@ -592,6 +653,53 @@ impl Locations {
 	pub fn online_rx(&self) -> Receiver<OnlineLocations> {
 		self.online_tx.subscribe()
 	}
+
+	/// The bucket throttling the walks of a location's indexer jobs, `initial` is only used if the
+	/// location has no bucket yet
+	pub async fn io_bucket(
+		&self,
+		library_id: Uuid,
+		location_id: location::id::Type,
+		initial: Option<IoThrottle>,
+	) -> IoTokenBucket {
+		if let Some(bucket) = self.io_buckets.read().await.get(&(library_id, location_id)) {
+			return bucket.clone();
+		}
+
+		self.io_buckets
+			.write()
+			.await
+			.entry((library_id, location_id))
+			.or_insert_with(|| IoTokenBucket::new(initial))
+			.clone()
+	}
+
+	/// What the indexer walking the location's root claims it with, see [`ActiveLocationRoots`]
+	pub fn root_claim(&self, library_id: Uuid, location_id: location::id::Type) -> RootClaim<'_> {
+		RootClaim {
+			roots: &self.active_roots,
+			library_id,
+			location_id,
+		}
+	}
+
+	/// Where the indexer's walks wait for their turn on a spinning disk
+	pub(crate) fn volume_walks(&self) -> &Arc<VolumeWalks> {
+		&self.volume_walks
+	}
+
+	/// Changes how the walks of a location are throttled, including walks already running
+	pub async fn set_io_throttle(
+		&self,
+		library_id: Uuid,
+		location_id: location::id::Type,
+		throttle: Option<IoThrottle>,
+	) {
+		self.io_bucket(library_id, location_id, throttle)
+			.await
+			.set_throttle(throttle)
+			.await;
+	}
 }
 
 impl Drop for Locations {
@@ -0,0 +1,102 @@
+use sd_prisma::prisma::location;
+
+use std::{
+	collections::HashMap,
+	sync::{Mutex, PoisonError},
+};
+
+use uuid::Uuid;
+
+/// The `(device, inode)` pair of a directory, the same whichever path it's mounted at
+pub type RootIdentity = (u64, u64);
+
+/// The roots of the locations of each library, so two locations of a library on the same directory
+/// mounted at different paths (eg. a network share mounted twice) don't both index the same data.
+///
+/// Locations claim their root when they're added to the location manager and again on each walk,
+/// as it may have been remounted since. A location keeps its root until it's removed from the location manager.
+#[derive(Debug, Default)]
+pub struct ActiveLocationRoots {
+	libraries: Mutex<HashMap<Uuid, HashMap<location::id::Type, RootIdentity>>>,
+}
+
+impl ActiveLocationRoots {
+	/// Register `identity` as the root of the location, unless another location of the library
+	/// already has it, whose id is returned instead
+	pub fn claim(
+		&self,
+		library_id: Uuid,
+		location_id: location::id::Type,
+		identity: RootIdentity,
+	) -> Result<(), location::id::Type> {
+		let mut libraries = self
+			.libraries
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		let roots = libraries.entry(library_id).or_default();
+
+		if let Some((&other_location_id, _)) = roots
+			.iter()
+			.find(|(&id, &other)| id != location_id && other == identity)
+		{
+			return Err(other_location_id);
+		}
+
+		// The location's own root may have been remounted since its last walk
+		roots.insert(location_id, identity);
+
+		Ok(())
+	}
+
+	pub fn release(&self, library_id: Uuid, location_id: location::id::Type) {
+		let mut libraries = self
+			.libraries
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+
+		if let Some(roots) = libraries.get_mut(&library_id) {
+			roots.remove(&location_id);
+			if roots.is_empty() {
+				libraries.remove(&library_id);
+			}
+		}
+	}
+}
+
+/// The location a walk is for, so it can claim its root in [`ActiveLocationRoots`] before walking
+#[derive(Debug, Clone, Copy)]
+pub struct RootClaim<'r> {
+	pub roots: &'r ActiveLocationRoots,
+	pub library_id: Uuid,
+	pub location_id: location::id::Type,
+}
+
+impl RootClaim<'_> {
+	pub fn claim(&self, identity: RootIdentity) -> Result<(), location::id::Type> {
+		self.roots
+			.claim(self.library_id, self.location_id, identity)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn locations_of_a_library_cant_share_a_root() {
+		let roots = ActiveLocationRoots::default();
+		let library_id = Uuid::new_v4();
+
+		assert_eq!(roots.claim(library_id, 1, (1, 42)), Ok(()));
+		// Walking the same location again is fine
+		assert_eq!(roots.claim(library_id, 1, (1, 42)), Ok(()));
+		assert_eq!(roots.claim(library_id, 2, (1, 42)), Err(1));
+		assert_eq!(roots.claim(library_id, 2, (2, 42)), Ok(()));
+
+		// Other libraries have their own locations
+		assert_eq!(roots.claim(Uuid::new_v4(), 1, (2, 42)), Ok(()));
+
+		roots.release(library_id, 1);
+		assert_eq!(roots.claim(library_id, 3, (1, 42)), Ok(()));
+	}
+}
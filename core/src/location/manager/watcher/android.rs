@@ -141,7 +141,7 @@ impl<'lib> EventHandler<'lib> for AndroidEventHandler<'lib> {
 					}
 				}
 
-				remove(self.location_id, &path, self.library).await?;
+				remove(self.location_id, &path, self.node, self.library).await?;
 			}
 			other_event_kind => {
 				trace!("Other Linux event that we don't handle for now: {other_event_kind:#?}");
@@ -249,7 +249,7 @@ impl AndroidEventHandler<'_> {
 							.insert(parent.to_path_buf(), Instant::now());
 					}
 				}
-				remove(self.location_id, &path, self.library).await?;
+				remove(self.location_id, &path, self.node, self.library).await?;
 				should_invalidate = true;
 				trace!("Removed file_path due timeout: {}", path.display());
 			} else {
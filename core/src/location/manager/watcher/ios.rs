@@ -130,7 +130,7 @@ impl<'lib> EventHandler<'lib> for IosEventHandler<'lib> {
 							.insert(parent.to_path_buf(), Instant::now());
 					}
 				}
-				remove(self.location_id, &path, self.library).await?; //FIXME: Find out why this freezes the watcher
+				remove(self.location_id, &path, self.node, self.library).await?; //FIXME: Find out why this freezes the watcher
 			}
 			other_event_kind => {
 				trace!("Other iOS event that we don't handle for now: {other_event_kind:#?}");
@@ -286,7 +286,7 @@ impl IosEventHandler<'_> {
 							.insert(parent.to_path_buf(), Instant::now());
 					}
 				}
-				remove(self.location_id, &path, self.library).await?;
+				remove(self.location_id, &path, self.node, self.library).await?;
 				trace!("Removed file_path due timeout: {}", path.display());
 				should_invalidate = true;
 			} else {
@@ -149,7 +149,7 @@ impl<'lib> EventHandler<'lib> for MacOsEventHandler<'lib> {
 							.insert(parent.to_path_buf(), Instant::now());
 					}
 				}
-				remove(self.location_id, &path, self.library).await?;
+				remove(self.location_id, &path, self.node, self.library).await?;
 			}
 			other_event_kind => {
 				trace!("Other MacOS event that we don't handle for now: {other_event_kind:#?}");
@@ -305,7 +305,7 @@ impl MacOsEventHandler<'_> {
 							.insert(parent.to_path_buf(), Instant::now());
 					}
 				}
-				remove(self.location_id, &path, self.library).await?;
+				remove(self.location_id, &path, self.node, self.library).await?;
 				trace!("Removed file_path due timeout: {}", path.display());
 				should_invalidate = true;
 			} else {
@@ -3,8 +3,14 @@ use crate::{
 	library::Library,
 	location::{
 		create_file_path, delete_directory, find_location,
-		indexer::reverse_update_directories_sizes, location_with_indexer_rules,
-		manager::LocationManagerError, scan_location_sub_path, update_location_size,
+		indexer::{
+			evaluate_single_path, forget_directory_fingerprints, is_only_walked_through,
+			nested_location_roots, reverse_update_directories_sizes, rules::IndexerRule,
+			warn_pinned_files_missing, IndexerError,
+		},
+		location_with_indexer_rules,
+		manager::LocationManagerError,
+		scan_location, scan_location_sub_path, update_location_size,
 	},
 	object::{
 		media::{
@@ -22,11 +28,11 @@ use sd_file_ext::{extensions::ImageExtension, kind::ObjectKind};
 use sd_file_path_helper::{
 	check_file_path_exists, file_path_with_object, filter_existing_file_path_params,
 	isolated_file_path_data::extract_normalized_materialized_path_str,
-	loose_find_existing_file_path_params, path_is_hidden, FilePathError, FilePathMetadata,
-	IsolatedFilePathData, MetadataExt,
+	loose_find_existing_file_path_params, non_utf8_name_bytes, path_is_hidden, path_is_placeholder,
+	FilePathError, FilePathMetadata, IsolatedFilePathData, MetadataExt,
 };
 use sd_prisma::{
-	prisma::{file_path, location, media_data, object},
+	prisma::{file_path, location, media_data, object, PrismaClient},
 	prisma_sync,
 };
 use sd_sync::OperationFactory;
@@ -47,6 +53,7 @@ use std::{
 	ffi::OsStr,
 	fs::Metadata,
 	path::{Path, PathBuf},
+	slice,
 	str::FromStr,
 	sync::Arc,
 };
@@ -59,7 +66,7 @@ use tokio::{
 	spawn,
 	time::Instant,
 };
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, trace};
 use uuid::Uuid;
 
 use super::{INode, HUNDRED_MILLIS};
@@ -97,18 +104,28 @@ pub(super) async fn create_dir(
 		path.display()
 	);
 
-	let iso_file_path = IsolatedFilePathData::new(location.id, location_path, path, true)?;
+	let indexer_rules = IndexerRule::for_location(&location).map_err(IndexerError::from)?;
+	if is_only_walked_through(location_path, path, &indexer_rules).await? {
+		// The walker only indexes it along with the first entry below it which the rules accept, so
+		// it's left to a scan of the nearest directory above it which is indexed
+		trace!(
+			"Directory only walked through by the indexer rules: {}",
+			path.display()
+		);
+		return scan_nearest_indexed_ancestor(location_path, path, node, library, location).await;
+	}
 
-	let parent_iso_file_path = iso_file_path.parent();
-	if !parent_iso_file_path.is_root()
-		&& !check_file_path_exists::<FilePathError>(&parent_iso_file_path, &library.db).await?
-	{
-		warn!(
-			"Watcher found a directory without parent: {}",
-			&iso_file_path
+	if !is_accepted_by_indexer_rules(location.id, location_path, path, library).await? {
+		trace!(
+			"Directory rejected by the indexer rules: {}",
+			path.display()
 		);
 		return Ok(());
-	};
+	}
+
+	let iso_file_path = IsolatedFilePathData::new(location.id, location_path, path, true)?;
+
+	create_missing_ancestors(location.id, location_path, path, library).await?;
 
 	let children_materialized_path = iso_file_path
 		.materialized_path_for_children()
@@ -121,9 +138,14 @@ pub(super) async fn create_dir(
 		iso_file_path.to_parts(),
 		None,
 		FilePathMetadata::from_path(&path, metadata).await?,
+		false,
 	)
 	.await?;
 
+	forget_parent_child_counts(&iso_file_path, &library.db).await?;
+	// It may be a directory restored as it was, whose entries are gone from the database
+	forget_directory_fingerprints(slice::from_ref(&iso_file_path), &library.db).await?;
+
 	// scan the new directory
 	scan_location_sub_path(node, library, location, &children_materialized_path).await?;
 
@@ -177,6 +199,7 @@ async fn inner_create_file(
 	let iso_file_path_parts = iso_file_path.to_parts();
 	let extension = iso_file_path_parts.extension.to_string();
 
+	let is_placeholder = path_is_placeholder(path, metadata);
 	let metadata = FilePathMetadata::from_path(&path, metadata).await?;
 
 	// First we check if already exist a file with this same inode number
@@ -222,25 +245,55 @@ async fn inner_create_file(
 		.await;
 	}
 
-	let parent_iso_file_path = iso_file_path.parent();
-	if !parent_iso_file_path.is_root()
-		&& !check_file_path_exists::<FilePathError>(&parent_iso_file_path, db).await?
-	{
-		warn!("Watcher found a file without parent: {}", &iso_file_path);
+	if !is_accepted_by_indexer_rules(location_id, location_path, path, library).await? {
+		trace!("File rejected by the indexer rules: {}", iso_file_path);
 		return Ok(());
-	};
+	}
+
+	if is_placeholder && excludes_cloud_placeholders(location_id, db).await? {
+		trace!("Leaving out cloud placeholder: {}", iso_file_path);
+		return Ok(());
+	}
+
+	create_missing_ancestors(location_id, location_path, path, library).await?;
+
+	// Reading it would have the cloud sync client download it, so like with the indexer it's only
+	// identified once it was downloaded
+	if is_placeholder {
+		debug!("Creating path of cloud placeholder: {}", iso_file_path);
+		create_file_path(library, iso_file_path_parts, None, metadata, true).await?;
+
+		forget_parent_child_counts(&iso_file_path, db).await?;
+		forget_directory_fingerprints(slice::from_ref(&iso_file_path), db).await?;
+
+		return Ok(());
+	}
 
 	// generate provisional object
 	let FileMetadata {
 		cas_id,
 		kind,
 		fs_metadata,
-	} = FileMetadata::new(&location_path, &iso_file_path).await?;
+	} = FileMetadata::new(
+		&location_path,
+		&iso_file_path,
+		non_utf8_name_bytes(path).as_deref(),
+	)
+	.await?;
 
 	debug!("Creating path: {}", iso_file_path);
 
-	let created_file =
-		create_file_path(library, iso_file_path_parts, cas_id.clone(), metadata).await?;
+	let created_file = create_file_path(
+		library,
+		iso_file_path_parts,
+		cas_id.clone(),
+		metadata,
+		false,
+	)
+	.await?;
+
+	forget_parent_child_counts(&iso_file_path, db).await?;
+	forget_directory_fingerprints(slice::from_ref(&iso_file_path), db).await?;
 
 	object::select!(object_ids { id pub_id });
 
@@ -376,6 +429,155 @@ async fn inner_create_file(
 	Ok(())
 }
 
+/// Instead of counting the entries of the directory `iso_file_path` was added to or removed from
+/// again, the watcher only forgets how many it had, until the next scan counts them
+async fn forget_parent_child_counts(
+	iso_file_path: &IsolatedFilePathData<'_>,
+	db: &PrismaClient,
+) -> Result<(), LocationManagerError> {
+	let parent_iso_file_path = iso_file_path.parent();
+	if parent_iso_file_path.is_root() {
+		return Ok(());
+	}
+
+	db.file_path()
+		.update_many(
+			vec![(&parent_iso_file_path).into()],
+			vec![
+				file_path::child_files::set(None),
+				file_path::child_dirs::set(None),
+			],
+		)
+		.exec()
+		.await?;
+
+	Ok(())
+}
+
+/// Like the walker, the directories `path` is in are indexed along with it, even the ones the rules
+/// only walk through, see [`is_only_walked_through`]
+async fn create_missing_ancestors(
+	location_id: location::id::Type,
+	location_path: impl AsRef<Path>,
+	path: impl AsRef<Path>,
+	library: &Library,
+) -> Result<(), LocationManagerError> {
+	let location_path = location_path.as_ref();
+
+	let mut missing = vec![];
+	for ancestor in
+		path.as_ref().ancestors().skip(1).take_while(|&ancestor| {
+			ancestor != location_path && ancestor.starts_with(location_path)
+		}) {
+		let iso_file_path = IsolatedFilePathData::new(location_id, location_path, ancestor, true)?;
+		if check_file_path_exists::<FilePathError>(&iso_file_path, &library.db).await? {
+			break;
+		}
+		missing.push((ancestor, iso_file_path));
+	}
+
+	for (ancestor, iso_file_path) in missing.into_iter().rev() {
+		debug!("Creating path of ancestor: {}", iso_file_path);
+
+		let metadata = fs::metadata(ancestor)
+			.await
+			.map_err(|e| FileIOError::from((ancestor, e)))?;
+		create_file_path(
+			library,
+			iso_file_path.to_parts(),
+			None,
+			FilePathMetadata::from_path(ancestor, &metadata).await?,
+			false,
+		)
+		.await?;
+
+		forget_parent_child_counts(&iso_file_path, &library.db).await?;
+		forget_directory_fingerprints(slice::from_ref(&iso_file_path), &library.db).await?;
+	}
+
+	Ok(())
+}
+
+/// Scans the nearest directory above `path` which is indexed, the whole location if there's none
+async fn scan_nearest_indexed_ancestor(
+	location_path: impl AsRef<Path>,
+	path: impl AsRef<Path>,
+	node: &Arc<Node>,
+	library: &Arc<Library>,
+	location: location_with_indexer_rules::Data,
+) -> Result<(), LocationManagerError> {
+	let location_path = location_path.as_ref();
+
+	for ancestor in
+		path.as_ref().ancestors().skip(1).take_while(|&ancestor| {
+			ancestor != location_path && ancestor.starts_with(location_path)
+		}) {
+		let iso_file_path = IsolatedFilePathData::new(location.id, location_path, ancestor, true)?;
+		if check_file_path_exists::<FilePathError>(&iso_file_path, &library.db).await? {
+			let sub_path = iso_file_path
+				.materialized_path_for_children()
+				.expect("the ancestors are all directories");
+			return scan_location_sub_path(node, library, location, sub_path)
+				.await
+				.map_err(Into::into);
+		}
+	}
+
+	scan_location(node, library, location)
+		.await
+		.map_err(Into::into)
+}
+
+/// See [`OldIndexerJobInit::exclude_cloud_placeholders`](crate::location::indexer::OldIndexerJobInit::exclude_cloud_placeholders)
+async fn excludes_cloud_placeholders(
+	location_id: location::id::Type,
+	db: &PrismaClient,
+) -> Result<bool, LocationManagerError> {
+	Ok(db
+		.location()
+		.find_unique(location::id::equals(location_id))
+		.select(location::select!({ exclude_cloud_placeholders }))
+		.exec()
+		.await?
+		.and_then(|location| location.exclude_cloud_placeholders)
+		.unwrap_or(false))
+}
+
+/// The watcher mustn't add anything the indexer itself wouldn't, so paths go through the same rules,
+/// and what's inside another location nested in this one is left to that location's watcher.
+async fn is_accepted_by_indexer_rules(
+	location_id: location::id::Type,
+	location_path: impl AsRef<Path>,
+	path: impl AsRef<Path>,
+	library: &Library,
+) -> Result<bool, LocationManagerError> {
+	let location_path = location_path.as_ref();
+	let path = path.as_ref();
+
+	if nested_location_roots(location_id, location_path, &library.db)
+		.await?
+		.iter()
+		.any(|root| path != root && path.starts_with(root))
+	{
+		return Ok(false);
+	}
+
+	let location = find_location(library, location_id)
+		.include(location_with_indexer_rules::include())
+		.exec()
+		.await?
+		.ok_or(LocationManagerError::MissingLocation(location_id))?;
+
+	let indexer_rules = IndexerRule::for_location(&location).map_err(IndexerError::from)?;
+
+	evaluate_single_path(location_path, path, &indexer_rules, |path, is_dir| {
+		IsolatedFilePathData::new(location_id, location_path, path, is_dir).map_err(Into::into)
+	})
+	.await
+	.map(|maybe_iso_file_path| maybe_iso_file_path.is_some())
+	.map_err(Into::into)
+}
+
 pub(super) async fn update_file(
 	location_id: location::id::Type,
 	full_path: impl AsRef<Path>,
@@ -446,11 +648,36 @@ async fn inner_update_file(
 
 	let iso_file_path = IsolatedFilePathData::try_from(file_path)?;
 
+	let is_placeholder = fs::metadata(full_path)
+		.await
+		.map(|metadata| path_is_placeholder(full_path, &metadata))
+		.map_err(|e| FileIOError::from((full_path, e)))?;
+	if is_placeholder != file_path.is_placeholder.unwrap_or_default() {
+		// Local only, like when the indexer sets it
+		db.file_path()
+			.update(
+				file_path::id::equals(file_path.id),
+				vec![file_path::is_placeholder::set(Some(is_placeholder))],
+			)
+			.exec()
+			.await?;
+	}
+	if is_placeholder {
+		// Evicted by the cloud sync client, what we know of its contents holds until it's downloaded again
+		trace!("Not reading cloud placeholder: {}", full_path.display());
+		return Ok(());
+	}
+
 	let FileMetadata {
 		cas_id,
 		fs_metadata,
 		kind,
-	} = FileMetadata::new(&location_path, &iso_file_path).await?;
+	} = FileMetadata::new(
+		&location_path,
+		&iso_file_path,
+		non_utf8_name_bytes(full_path).as_deref(),
+	)
+	.await?;
 
 	let inode = if let Some(inode) = maybe_new_inode {
 		inode
@@ -765,7 +992,8 @@ pub(super) async fn rename(
 		extract_normalized_materialized_path_str(location_id, &location_path, new_path)?;
 
 	// Renaming a file could potentially be a move to another directory, so we check if our parent changed
-	if old_path_materialized_str != new_path_materialized_str
+	let moved = old_path_materialized_str != new_path_materialized_str;
+	if moved
 		&& !check_file_path_exists::<FilePathError>(
 			&IsolatedFilePathData::new(location_id, &location_path, new_path, true)?.parent(),
 			db,
@@ -895,6 +1123,13 @@ pub(super) async fn rename(
 		)
 		.await?;
 
+		if moved {
+			let old = IsolatedFilePathData::new(location_id, &location_path, old_path, is_dir)?;
+			forget_parent_child_counts(&old, db).await?;
+			forget_parent_child_counts(&new, db).await?;
+			forget_directory_fingerprints(&[old, new], db).await?;
+		}
+
 		invalidate_query!(library, "search.paths");
 		invalidate_query!(library, "search.objects");
 	}
@@ -905,6 +1140,7 @@ pub(super) async fn rename(
 pub(super) async fn remove(
 	location_id: location::id::Type,
 	full_path: impl AsRef<Path>,
+	node: &Node,
 	library: &Library,
 ) -> Result<(), LocationManagerError> {
 	let full_path = full_path.as_ref();
@@ -925,13 +1161,14 @@ pub(super) async fn remove(
 		return Ok(());
 	};
 
-	remove_by_file_path(location_id, full_path, &file_path, library).await
+	remove_by_file_path(location_id, full_path, &file_path, node, library).await
 }
 
 pub(super) async fn remove_by_file_path(
 	location_id: location::id::Type,
 	path: impl AsRef<Path>,
 	file_path: &file_path::Data,
+	node: &Node,
 	library: &Library,
 ) -> Result<(), LocationManagerError> {
 	// check file still exists on disk
@@ -943,15 +1180,30 @@ pub(super) async fn remove_by_file_path(
 			let Library { sync, db, .. } = library;
 
 			let is_dir = maybe_missing(file_path.is_dir, "file_path.is_dir")?;
+			let iso_file_path = IsolatedFilePathData::try_from(file_path)?;
+
+			// Pinned file_paths are never removed, only reported when they're gone from disk
+			let pinned_missing = if file_path.pinned {
+				vec![path.as_ref().to_path_buf()]
+			} else if is_dir {
+				pinned_below(location_id, &iso_file_path, library).await?
+			} else {
+				vec![]
+			};
+			warn_pinned_files_missing(
+				&pinned_missing
+					.into_iter()
+					.map(|path| IndexerError::PinnedFileMissing { path })
+					.collect::<Vec<_>>(),
+				node,
+			)
+			.await;
 
 			// if is doesn't, we can remove it safely from our db
-			if is_dir {
-				delete_directory(
-					library,
-					location_id,
-					Some(&IsolatedFilePathData::try_from(file_path)?),
-				)
-				.await?;
+			if file_path.pinned {
+				return Ok(());
+			} else if is_dir {
+				delete_directory(library, location_id, Some(&iso_file_path)).await?;
 			} else {
 				sync.write_op(
 					db,
@@ -973,6 +1225,9 @@ pub(super) async fn remove_by_file_path(
 						.await?;
 				}
 			}
+
+			forget_parent_child_counts(&iso_file_path, db).await?;
+			forget_directory_fingerprints(slice::from_ref(&iso_file_path), db).await?;
 		}
 		Err(e) => return Err(FileIOError::from((path, e)).into()),
 	}
@@ -983,6 +1238,36 @@ pub(super) async fn remove_by_file_path(
 	Ok(())
 }
 
+/// Full paths of the pinned file_paths below the directory at `iso_file_path`
+async fn pinned_below(
+	location_id: location::id::Type,
+	iso_file_path: &IsolatedFilePathData<'_>,
+	library: &Library,
+) -> Result<Vec<PathBuf>, LocationManagerError> {
+	let Some(children_path) = iso_file_path.materialized_path_for_children() else {
+		return Ok(vec![]);
+	};
+	let location_path = extract_location_path(location_id, library).await?;
+
+	library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::location_id::equals(Some(location_id)),
+			file_path::pinned::equals(true),
+			file_path::materialized_path::starts_with(children_path),
+		])
+		.exec()
+		.await?
+		.iter()
+		.map(|file_path| {
+			IsolatedFilePathData::try_from(file_path)
+				.map(|iso_file_path| location_path.join(iso_file_path))
+				.map_err(Into::into)
+		})
+		.collect()
+}
+
 pub(super) async fn extract_inode_from_path(
 	location_id: location::id::Type,
 	path: impl AsRef<Path>,
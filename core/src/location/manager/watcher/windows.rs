@@ -332,7 +332,7 @@ impl WindowsEventHandler<'_> {
 							.insert(parent.to_path_buf(), Instant::now());
 					}
 				}
-				remove(self.location_id, &path, self.library).await?;
+				remove(self.location_id, &path, self.node, self.library).await?;
 				should_invalidate = true;
 				trace!("Removed file_path due timeout: {}", path.display());
 			} else {
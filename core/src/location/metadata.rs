@@ -193,6 +193,14 @@ impl SpacedriveLocationMetadataFile {
 			.map(|l| l.path.as_path())
 	}
 
+	/// Whether the location is in the file for any library, ie. it was added on the volume at this path
+	pub fn has_location(&self, location_pub_id: LocationPubId) -> bool {
+		self.metadata
+			.libraries
+			.values()
+			.any(|location| location.pub_id == location_pub_id)
+	}
+
 	pub fn is_empty(&self) -> bool {
 		self.metadata.libraries.is_empty()
 	}
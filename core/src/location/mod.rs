@@ -9,14 +9,16 @@ use crate::{
 	Node,
 };
 
-use sd_file_path_helper::{filter_existing_file_path_params, IsolatedFilePathData};
+use sd_file_path_helper::{
+	filter_existing_file_path_params, get_device_from_path, IsolatedFilePathData,
+};
 use sd_prisma::{
 	prisma::{file_path, indexer_rules_in_location, location, PrismaClient},
 	prisma_sync,
 };
 use sd_sync::*;
 use sd_utils::{
-	db::{maybe_missing, MissingFieldError},
+	db::{device_from_db, device_to_db, maybe_missing, MissingFieldError},
 	error::{FileIOError, NonUtf8PathError},
 	msgpack, uuid_to_bytes,
 };
@@ -40,15 +42,18 @@ use tokio::{fs, io, time::Instant};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
+mod attention;
 mod error;
 pub mod indexer;
 mod manager;
 pub mod metadata;
 pub mod non_indexed;
 
+pub(crate) use attention::set_location_attention;
+pub use attention::LocationAttention;
 pub use error::LocationError;
-use indexer::OldIndexerJobInit;
-pub use manager::{LocationManagerError, Locations};
+use indexer::{ErrorPolicy, OldIndexerJobInit};
+pub use manager::{ActiveLocationRoots, LocationManagerError, Locations, RootClaim};
 use metadata::SpacedriveLocationMetadataFile;
 
 pub type LocationPubId = Uuid;
@@ -56,6 +61,7 @@ pub type LocationPubId = Uuid;
 // Location includes!
 location::include!(location_with_indexer_rules {
 	indexer_rules: select { indexer_rule }
+	indexer_rule_groups: select { group: select { rules: select { indexer_rule } } }
 });
 
 /// `LocationCreateArgs` is the argument received from the client using `rspc` to create a new location.
@@ -92,11 +98,16 @@ impl LocationCreateArgs {
 			}
 		};
 
-		if !path_metadata.is_dir() {
-			return Err(LocationError::NotDirectory(self.path.into_boxed_path()));
-		}
+		// A single file can be a location too, it just has no room for a .spacedrive file
+		let is_dir = path_metadata.is_dir();
+
+		let maybe_metadata = if is_dir {
+			SpacedriveLocationMetadataFile::try_load(&self.path).await?
+		} else {
+			None
+		};
 
-		if let Some(mut metadata) = SpacedriveLocationMetadataFile::try_load(&self.path).await? {
+		if let Some(mut metadata) = maybe_metadata {
 			metadata
 				.clean_stale_libraries(
 					&node
@@ -161,12 +172,19 @@ impl LocationCreateArgs {
 
 		if let Some(location) = location {
 			// Write location metadata to a .spacedrive file
-			if let Err(err) = SpacedriveLocationMetadataFile::create_and_save(
+			let save_metadata = SpacedriveLocationMetadataFile::create_and_save(
 				library.id,
 				uuid,
 				&self.path,
 				location.name,
-			)
+			);
+			if let Err(err) = async {
+				if is_dir {
+					save_metadata.await
+				} else {
+					Ok(())
+				}
+			}
 			.err_into::<LocationError>()
 			.and_then(|()| async move {
 				node.locations
@@ -277,6 +295,33 @@ pub struct LocationUpdateArgs {
 	generate_preview_media: Option<bool>,
 	sync_preview_media: Option<bool>,
 	hidden: Option<bool>,
+	/// Throttles the filesystem operations of this location's indexing, see [`indexer::IoThrottle`]
+	#[serde(default)]
+	background_indexing: Option<bool>,
+	/// Leaves the files modified too recently for a later walk, see [`OldIndexerJobInit::defer_recently_modified`]
+	#[serde(default)]
+	defer_recently_modified: Option<bool>,
+	/// Leaves out the files cloud sync clients only keep online, see [`OldIndexerJobInit::exclude_cloud_placeholders`]
+	#[serde(default)]
+	exclude_cloud_placeholders: Option<bool>,
+	/// Numbers the files scans add in the order they're found, see [`OldIndexerJobInit::track_discovery_order`]
+	#[serde(default)]
+	track_discovery_order: Option<bool>,
+	/// Walks into the drives mounted inside the location, see [`OldIndexerJobInit::cross_filesystems`]
+	#[serde(default)]
+	cross_filesystems: Option<bool>,
+	/// How deep below the location's root scans go, see [`OldIndexerJobInit::max_walk_depth`]
+	#[serde(default)]
+	max_walk_depth: Option<u32>,
+	/// In MiB, how much memory scans hold before writing, see [`OldIndexerJobInit::walker_memory_budget`]
+	#[serde(default)]
+	walker_memory_budget: Option<u32>,
+	/// Errors per entry found before scans pause, see [`ErrorPolicy::max_noncritical_ratio`]
+	#[serde(default)]
+	max_noncritical_ratio: Option<f32>,
+	/// Errors before scans pause, see [`ErrorPolicy::max_noncritical_count`]
+	#[serde(default)]
+	max_noncritical_count: Option<u32>,
 	indexer_rules_ids: Vec<i32>,
 	path: Option<String>,
 }
@@ -291,6 +336,12 @@ impl LocationUpdateArgs {
 			.await?
 			.ok_or(LocationError::IdNotFound(self.id))?;
 
+		if let Some(depth) = self.max_walk_depth {
+			if depth > indexer::DEFAULT_MAX_WALK_DEPTH {
+				return Err(LocationError::InvalidMaxWalkDepth(depth));
+			}
+		}
+
 		let name = self.name.clone();
 
 		let (sync_params, db_params): (Vec<_>, Vec<_>) = [
@@ -320,6 +371,61 @@ impl LocationUpdateArgs {
 					location::hidden::set(Some(v)),
 				)
 			}),
+			self.background_indexing.map(|v| {
+				(
+					(location::background_indexing::NAME, msgpack!(v)),
+					location::background_indexing::set(Some(v)),
+				)
+			}),
+			self.defer_recently_modified.map(|v| {
+				(
+					(location::defer_recently_modified::NAME, msgpack!(v)),
+					location::defer_recently_modified::set(Some(v)),
+				)
+			}),
+			self.exclude_cloud_placeholders.map(|v| {
+				(
+					(location::exclude_cloud_placeholders::NAME, msgpack!(v)),
+					location::exclude_cloud_placeholders::set(Some(v)),
+				)
+			}),
+			self.track_discovery_order.map(|v| {
+				(
+					(location::track_discovery_order::NAME, msgpack!(v)),
+					location::track_discovery_order::set(Some(v)),
+				)
+			}),
+			self.cross_filesystems.map(|v| {
+				(
+					(location::cross_filesystems::NAME, msgpack!(v)),
+					location::cross_filesystems::set(Some(v)),
+				)
+			}),
+			self.max_walk_depth.map(|v| {
+				(
+					(location::max_walk_depth::NAME, msgpack!(v)),
+					// Fits as it's at most `DEFAULT_MAX_WALK_DEPTH`
+					location::max_walk_depth::set(Some(v as i32)),
+				)
+			}),
+			self.walker_memory_budget.map(|v| {
+				(
+					(location::walker_memory_budget::NAME, msgpack!(v)),
+					location::walker_memory_budget::set(Some(v as i32)),
+				)
+			}),
+			self.max_noncritical_ratio.map(|v| {
+				(
+					(location::max_noncritical_ratio::NAME, msgpack!(v)),
+					location::max_noncritical_ratio::set(Some(f64::from(v))),
+				)
+			}),
+			self.max_noncritical_count.map(|v| {
+				(
+					(location::max_noncritical_count::NAME, msgpack!(v)),
+					location::max_noncritical_count::set(Some(v as i32)),
+				)
+			}),
 			self.path.clone().map(|v| {
 				(
 					(location::path::NAME, msgpack!(v)),
@@ -370,6 +476,17 @@ impl LocationUpdateArgs {
 				node.locations.remove(self.id, library.clone()).await?;
 				node.locations.add(self.id, library.clone()).await?;
 			}
+
+			// Indexer jobs already running follow the new throttle right away
+			if let Some(background_indexing) = self.background_indexing {
+				node.locations
+					.set_io_throttle(
+						library.id,
+						self.id,
+						indexer::IoThrottle::for_location(Some(background_indexing)),
+					)
+					.await;
+			}
 		}
 
 		let current_rules_ids = location
@@ -452,10 +569,38 @@ pub async fn scan_location(
 	}
 
 	let location_base_data = location::Data::from(&location);
+	let defer_recently_modified = location
+		.defer_recently_modified
+		.unwrap_or(false)
+		.then_some(indexer::DEFAULT_RECENTLY_MODIFIED_WINDOW);
+	let exclude_cloud_placeholders = location.exclude_cloud_placeholders.unwrap_or(false);
+	let track_discovery_order = location.track_discovery_order.unwrap_or(false);
+	let cross_filesystems = location.cross_filesystems.unwrap_or(true);
+	let max_walk_depth = location
+		.max_walk_depth
+		.and_then(|depth| u32::try_from(depth).ok());
+	let walker_memory_budget = location
+		.walker_memory_budget
+		.and_then(|mib| u64::try_from(mib).ok())
+		.map(|mib| mib * 1024 * 1024);
+	let error_policy = ErrorPolicy::for_location(
+		location.max_noncritical_ratio,
+		location.max_noncritical_count,
+	);
 
 	JobBuilder::new(OldIndexerJobInit {
 		location,
 		sub_path: None,
+		verbose_errors: false,
+		walker_memory_budget,
+		error_policy,
+		trust_fingerprints: false,
+		extension_statistics: true,
+		defer_recently_modified,
+		max_walk_depth,
+		exclude_cloud_placeholders,
+		track_discovery_order,
+		cross_filesystems,
 	})
 	.with_action("scan_location")
 	.with_metadata(json!({"location": location_base_data.clone()}))
@@ -489,10 +634,39 @@ pub async fn scan_location_sub_path(
 	}
 
 	let location_base_data = location::Data::from(&location);
+	let defer_recently_modified = location
+		.defer_recently_modified
+		.unwrap_or(false)
+		.then_some(indexer::DEFAULT_RECENTLY_MODIFIED_WINDOW);
+	let exclude_cloud_placeholders = location.exclude_cloud_placeholders.unwrap_or(false);
+	let track_discovery_order = location.track_discovery_order.unwrap_or(false);
+	let cross_filesystems = location.cross_filesystems.unwrap_or(true);
+	let max_walk_depth = location
+		.max_walk_depth
+		.and_then(|depth| u32::try_from(depth).ok());
+	let walker_memory_budget = location
+		.walker_memory_budget
+		.and_then(|mib| u64::try_from(mib).ok())
+		.map(|mib| mib * 1024 * 1024);
+	let error_policy = ErrorPolicy::for_location(
+		location.max_noncritical_ratio,
+		location.max_noncritical_count,
+	);
 
 	JobBuilder::new(OldIndexerJobInit {
 		location,
 		sub_path: Some(sub_path.clone()),
+		verbose_errors: false,
+		walker_memory_budget,
+		error_policy,
+		// Only full scans go through directories which seem unchanged
+		trust_fingerprints: true,
+		extension_statistics: false,
+		defer_recently_modified,
+		max_walk_depth,
+		exclude_cloud_placeholders,
+		track_discovery_order,
+		cross_filesystems,
 	})
 	.with_action("scan_location_sub_path")
 	.with_metadata(json!({
@@ -561,6 +735,7 @@ pub async fn relink_location(
 		.to_str()
 		.map(str::to_string)
 		.ok_or_else(|| NonUtf8PathError(location_path.into()))?;
+	let root_device = root_device(location_path).await;
 
 	sync.write_op(
 		db,
@@ -573,7 +748,10 @@ pub async fn relink_location(
 		),
 		db.location().update(
 			location::pub_id::equals(pub_id.clone()),
-			vec![location::path::set(Some(path))],
+			vec![
+				location::path::set(Some(path)),
+				location::root_device::set(root_device),
+			],
 		),
 	)
 	.await?;
@@ -677,6 +855,7 @@ async fn create_location(
 	}
 
 	let date_created = Utc::now();
+	let root_device = root_device(location_path).await;
 
 	let location = sync
 		.write_ops(
@@ -706,6 +885,7 @@ async fn create_location(
 							location::path::set(Some(path)),
 							location::date_created::set(Some(date_created.into())),
 							location::instance_id::set(Some(library.config().await.instance_id)),
+							location::root_device::set(root_device),
 							// location::instance::connect(instance::id::equals(
 							// 	library.config.instance_id.as_bytes().to_vec(),
 							// )),
@@ -737,6 +917,47 @@ async fn create_location(
 	}))
 }
 
+/// The device the root of a location is on, stored so the indexer notices when another volume is
+/// mounted at the location's path. Locations whose device can't be read just aren't checked.
+async fn root_device(location_path: &Path) -> Option<Vec<u8>> {
+	get_device_from_path(location_path)
+		.await
+		.map_err(|e| warn!("Failed to get the device of location root: {e:#?}"))
+		.ok()
+		.map(device_to_db)
+}
+
+/// The device the root of a location was on when it was added, to notice when another volume is
+/// mounted at the location's path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootDevice {
+	pub device: u64,
+	pub location_pub_id: LocationPubId,
+}
+
+impl RootDevice {
+	/// From the `root_device` and `pub_id` of a location, [`None`] for locations added before it was stored
+	pub fn from_db(root_device: Option<&[u8]>, pub_id: &[u8]) -> Option<Self> {
+		Some(Self {
+			device: device_from_db(root_device?)?,
+			location_pub_id: Uuid::from_slice(pub_id).ok()?,
+		})
+	}
+
+	/// Whether `found`, the device the location's root is on now, is still the volume it was added on.
+	///
+	/// Device ids aren't stable, eg. an external drive can get another one when it's plugged back in
+	/// or after a reboot, so a root which still has the location's `.spacedrive` file is taken to be
+	/// on the same volume whatever its device.
+	pub async fn matches(&self, location_path: &Path, found: u64) -> bool {
+		found == self.device
+			|| matches!(
+				SpacedriveLocationMetadataFile::try_load(location_path).await,
+				Ok(Some(metadata)) if metadata.has_location(self.location_pub_id)
+			)
+	}
+}
+
 pub async fn delete_location(
 	node: &Node,
 	library: &Arc<Library>,
@@ -833,7 +1054,8 @@ pub async fn delete_location(
 }
 
 /// Will delete a directory recursively with Objects if left as orphans
-/// this function is used to delete a location and when ingesting directory deletion events
+/// this function is used to delete a location and when ingesting directory deletion events.
+/// The pinned file_paths below a directory are kept, only deleting the whole location removes them.
 pub async fn delete_directory(
 	library: &Library,
 	location_id: location::id::Type,
@@ -845,16 +1067,19 @@ pub async fn delete_directory(
 	// Sync requires having sync ids available.
 	let children_params = sd_utils::chain_optional_iter(
 		[file_path::location_id::equals(Some(location_id))],
-		[parent_iso_file_path.and_then(|parent| {
-			parent
-				.materialized_path_for_children()
-				.map(|materialized_path| {
-					or![
-						and(filter_existing_file_path_params(parent)),
-						file_path::materialized_path::starts_with(materialized_path),
-					]
-				})
-		})],
+		[
+			parent_iso_file_path.map(|_| file_path::pinned::equals(false)),
+			parent_iso_file_path.and_then(|parent| {
+				parent
+					.materialized_path_for_children()
+					.map(|materialized_path| {
+						or![
+							and(filter_existing_file_path_params(parent)),
+							file_path::materialized_path::starts_with(materialized_path),
+						]
+					})
+			}),
+		],
 	);
 
 	db.file_path().delete_many(children_params).exec().await?;
@@ -882,9 +1107,26 @@ impl From<location_with_indexer_rules::Data> for location::Data {
 			generate_preview_media: data.generate_preview_media,
 			sync_preview_media: data.sync_preview_media,
 			hidden: data.hidden,
+			background_indexing: data.background_indexing,
+			defer_recently_modified: data.defer_recently_modified,
+			exclude_cloud_placeholders: data.exclude_cloud_placeholders,
+			track_discovery_order: data.track_discovery_order,
+			cross_filesystems: data.cross_filesystems,
+			max_walk_depth: data.max_walk_depth,
+			walker_memory_budget: data.walker_memory_budget,
+			max_noncritical_ratio: data.max_noncritical_ratio,
+			max_noncritical_count: data.max_noncritical_count,
 			date_created: data.date_created,
+			root_device: data.root_device,
+			filesystem: data.filesystem,
+			attention: data.attention,
+			scan_generation: data.scan_generation,
 			file_paths: None,
 			indexer_rules: None,
+			indexer_rule_groups: None,
+			directory_fingerprints: None,
+			statistics: None,
+			scan_records: None,
 			instance: None,
 		}
 	}
@@ -905,9 +1147,26 @@ impl From<&location_with_indexer_rules::Data> for location::Data {
 			generate_preview_media: data.generate_preview_media,
 			sync_preview_media: data.sync_preview_media,
 			hidden: data.hidden,
+			background_indexing: data.background_indexing,
+			defer_recently_modified: data.defer_recently_modified,
+			exclude_cloud_placeholders: data.exclude_cloud_placeholders,
+			track_discovery_order: data.track_discovery_order,
+			cross_filesystems: data.cross_filesystems,
+			max_walk_depth: data.max_walk_depth,
+			walker_memory_budget: data.walker_memory_budget,
+			max_noncritical_ratio: data.max_noncritical_ratio,
+			max_noncritical_count: data.max_noncritical_count,
 			date_created: data.date_created,
+			root_device: data.root_device.clone(),
+			filesystem: data.filesystem.clone(),
+			attention: data.attention,
+			scan_generation: data.scan_generation,
 			file_paths: None,
 			indexer_rules: None,
+			indexer_rule_groups: None,
+			directory_fingerprints: None,
+			statistics: None,
+			scan_records: None,
 			instance: None,
 		}
 	}
@@ -1050,6 +1309,7 @@ pub async fn create_file_path(
 	}: IsolatedFilePathDataParts<'_>,
 	cas_id: Option<String>,
 	metadata: sd_file_path_helper::FilePathMetadata,
+	is_placeholder: bool,
 ) -> Result<file_path::Data, sd_file_path_helper::FilePathError> {
 	use sd_utils::db::inode_to_db;
 
@@ -1060,14 +1320,14 @@ pub async fn create_file_path(
 	let location = db
 		.location()
 		.find_unique(location::id::equals(location_id))
-		.select(location::select!({ id pub_id }))
+		.select(location::select!({ id pub_id scan_generation }))
 		.exec()
 		.await?
 		.ok_or(sd_file_path_helper::FilePathError::LocationNotFound(
 			location_id,
 		))?;
 
-	let (sync_params, db_params): (Vec<_>, Vec<_>) = {
+	let (sync_params, mut db_params): (Vec<_>, Vec<_>) = {
 		use file_path::*;
 
 		[
@@ -1122,6 +1382,12 @@ pub async fn create_file_path(
 		.into_iter()
 		.unzip()
 	};
+	// Created during a scan, it's as good as found by it, see `file_path::scan_generation`. Local only
+	db_params.push(file_path::scan_generation::set(Some(
+		location.scan_generation,
+	)));
+	// Like with the indexer, whether it's only kept online depends on this instance
+	db_params.push(file_path::is_placeholder::set(Some(is_placeholder)));
 
 	let pub_id = sd_utils::uuid_to_bytes(Uuid::new_v4());
 
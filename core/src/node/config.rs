@@ -1,17 +1,20 @@
 use crate::{
 	api::{notifications::Notification, BackendFeature},
 	object::media::old_thumbnail::preferences::ThumbnailerPreferences,
+	p2p::{OperationPolicy, StreamBudgets},
 	util::version_manager::{Kind, ManagedVersion, VersionManager, VersionManagerError},
 };
 
-use sd_p2p::Identity;
+use sd_p2p::{Identity, IdentitySuccession, RemoteIdentity};
 use sd_utils::error::FileIOError;
 
 use std::{
+	collections::{HashMap, HashSet},
 	path::{Path, PathBuf},
 	sync::Arc,
 };
 
+use chrono::{DateTime, Utc};
 use int_enum::IntEnum;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
@@ -36,6 +39,40 @@ pub enum P2PDiscoveryState {
 	Disabled,
 }
 
+/// Who can Spacedrop to this node. It's advertised to other nodes so they know before trying, but
+/// it's checked again when a Spacedrop comes in as what they saw may be out of date.
+#[derive(
+	Debug,
+	Default,
+	Clone,
+	Copy,
+	Eq,
+	PartialEq,
+	Serialize,
+	Deserialize,
+	Type,
+	strum::Display,
+	strum::EnumString,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum SpacedropMode {
+	Off,
+	/// Only the nodes we paired with
+	Contacts,
+	#[default]
+	Everyone,
+}
+
+impl SpacedropMode {
+	pub fn allows(self, is_contact: bool) -> bool {
+		match self {
+			Self::Off => false,
+			Self::Contacts => is_contact,
+			Self::Everyone => true,
+		}
+	}
+}
+
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Type)]
 #[serde(rename_all = "snake_case", untagged)]
 pub enum Port {
@@ -65,6 +102,9 @@ pub struct NodeConfig {
 	/// This keypair does effectively nothing except for provide libp2p with a stable peer_id.
 	#[serde(with = "identity_serde")]
 	pub identity: Identity,
+	/// Set when the identity was rotated, so nodes which knew the previous one can follow it to the current one.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub identity_rotation: Option<IdentityRotation>,
 	/// P2P config
 	#[serde(default, skip_serializing_if = "Port::is_random")]
 	pub p2p_ipv4_port: Port,
@@ -72,6 +112,66 @@ pub struct NodeConfig {
 	pub p2p_ipv6_port: Port,
 	#[serde(default)]
 	pub p2p_discovery: P2PDiscoveryState,
+	/// The nodes we paired with
+	#[serde(default, skip_serializing_if = "HashSet::is_empty")]
+	pub contacts: HashSet<RemoteIdentity>,
+	/// Who can Spacedrop to us, see [`SpacedropMode`]
+	#[serde(default)]
+	pub spacedrop_mode: SpacedropMode,
+	/// The names we gave peers, shown instead of the ones they advertise
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub peer_aliases: HashMap<RemoteIdentity, String>,
+	/// How long an incoming Spacedrop waits to be accepted before it's rejected. Uses the default when unset.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub spacedrop_timeout_secs: Option<u32>,
+	/// Hold the Spacedrops which arrive while no frontend is open until one is, instead of turning them down.
+	/// They still can't wait longer than the maximum timeout.
+	#[serde(default)]
+	pub queue_spacedrop_when_unattended: bool,
+	/// How many Spacedrops are sent to the same peer at once, the rest wait in a queue. Defaults to 1.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub spacedrop_parallelism: Option<u32>,
+	/// How many streams files are served to remote peers over at once, the rest wait. Defaults to 4.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub file_serve_concurrency: Option<u32>,
+	/// Limits how fast files are read from disk when serving them to remote peers. Unlimited when unset.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub file_serve_bytes_per_sec: Option<u32>,
+	/// Limits how fast Spacedrops and served files are sent to peers, shared between all transfers. Unlimited when unset.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub p2p_max_bytes_per_sec: Option<u32>,
+	/// Locations whose files are served to any peer which asks, not just instances of their library
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub public_locations: Vec<Uuid>,
+	/// How often connected peers are pinged to check they are still there. Defaults to every 30 seconds.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub keep_alive_interval_secs: Option<u32>,
+	/// How many pings in a row a peer can miss before its connections are closed. Defaults to 3.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub keep_alive_max_failures: Option<u32>,
+	/// How many seconds a peer's clock can be from ours before the user is warned about it. Defaults to 30.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub clock_skew_warning_secs: Option<u32>,
+	/// How many seconds a peer has to send the header of a stream it opened before it's closed. Defaults to 10.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub p2p_header_timeout_secs: Option<u32>,
+	/// How many seconds a peer has to set up the tunnel of a sync stream before it's closed. Defaults to 30.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub p2p_tunnel_timeout_secs: Option<u32>,
+	/// Which operations peers can start with us depending on how they reached us. Everything is allowed when unset.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub p2p_allowed_operations: Option<OperationPolicy>,
+	/// How many streams of each operation can be open at once, for both the ones we open and the ones peers open.
+	/// Defaults to 2 for sync, 3 for Spacedrop, 4 for files and as many pings as there are.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub p2p_stream_budgets: Option<StreamBudgets>,
+	/// Send details about the internals of mDNS and QUIC to the frontend, for debugging discovery issues
+	#[serde(default)]
+	pub p2p_diagnostics: bool,
+	/// How many indexer walks run at once on a single spinning disk, the rest wait for their turn.
+	/// Walks of solid state disks are never held back. Defaults to 1.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub walks_per_spinning_disk: Option<u32>,
 	/// Feature flags enabled on the node
 	#[serde(default)]
 	pub features: Vec<BackendFeature>,
@@ -88,6 +188,13 @@ pub struct NodeConfig {
 	version: NodeConfigVersion,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityRotation {
+	pub succession: IdentitySuccession,
+	/// The succession is advertised to other nodes until then, after which the previous identity is forgotten
+	pub retire_at: DateTime<Utc>,
+}
+
 mod identity_serde {
 	use sd_p2p::Identity;
 	use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -153,9 +260,29 @@ impl ManagedVersion<NodeConfigVersion> for NodeConfig {
 			id: Uuid::new_v4(),
 			name,
 			identity: Identity::default(),
+			identity_rotation: None,
 			p2p_ipv4_port: Port::Random,
 			p2p_ipv6_port: Port::Random,
 			p2p_discovery: P2PDiscoveryState::Everyone,
+			contacts: HashSet::new(),
+			spacedrop_mode: SpacedropMode::Everyone,
+			peer_aliases: HashMap::new(),
+			spacedrop_timeout_secs: None,
+			queue_spacedrop_when_unattended: false,
+			spacedrop_parallelism: None,
+			file_serve_concurrency: None,
+			file_serve_bytes_per_sec: None,
+			p2p_max_bytes_per_sec: None,
+			public_locations: vec![],
+			keep_alive_interval_secs: None,
+			keep_alive_max_failures: None,
+			clock_skew_warning_secs: None,
+			p2p_header_timeout_secs: None,
+			p2p_tunnel_timeout_secs: None,
+			p2p_allowed_operations: None,
+			p2p_stream_budgets: None,
+			p2p_diagnostics: false,
+			walks_per_spinning_disk: None,
 			version: Self::LATEST_VERSION,
 			features: vec![],
 			notifications: vec![],
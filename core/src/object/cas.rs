@@ -1,10 +1,10 @@
-use std::path::Path;
+use std::{collections::VecDeque, iter, ops::Range, path::Path};
 
 use blake3::Hasher;
 use static_assertions::const_assert;
 use tokio::{
 	fs::{self, File},
-	io::{self, AsyncReadExt, AsyncSeekExt, SeekFrom},
+	io::{self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, SeekFrom},
 };
 
 const SAMPLE_COUNT: u64 = 4;
@@ -60,3 +60,97 @@ pub async fn generate_cas_id(path: impl AsRef<Path>, size: u64) -> Result<String
 
 	Ok(hasher.finalize().to_hex()[..16].to_string())
 }
+
+/// Builds the `cas_id` of a file from its bytes as they're read, eg. while they're sent to a peer, so it's the one
+/// of exactly these bytes. Gives the same `cas_id` as [`generate_cas_id`].
+pub struct CasIdHasher {
+	hasher: Hasher,
+	/// The parts of the file which are hashed and weren't yet, in order
+	left: VecDeque<Range<u64>>,
+}
+
+impl CasIdHasher {
+	pub fn new(size: u64) -> Self {
+		let mut hasher = Hasher::new();
+		hasher.update(&size.to_le_bytes());
+
+		Self {
+			hasher,
+			left: sampled_ranges(size),
+		}
+	}
+
+	/// Hash the `bytes` at `offset` in the file. What's hashed before `offset` must have been passed
+	/// already, or read with [`Self::read_until`].
+	pub fn update(&mut self, offset: u64, bytes: &[u8]) {
+		let end = offset + bytes.len() as u64;
+		while let Some(range) = self.left.front_mut() {
+			if range.start >= end {
+				break;
+			}
+			debug_assert!(range.start >= offset, "bytes were skipped");
+
+			let until = range.end.min(end);
+			self.hasher
+				.update(&bytes[(range.start - offset) as usize..(until - offset) as usize]);
+			range.start = until;
+			if !range.is_empty() {
+				break;
+			}
+			self.left.pop_front();
+		}
+	}
+
+	/// Read what's hashed before `offset` in `file` and wasn't passed to [`Self::update`]
+	pub async fn read_until(
+		&mut self,
+		file: &mut (impl AsyncRead + AsyncSeek + Unpin),
+		offset: u64,
+	) -> Result<(), io::Error> {
+		let mut buf = Vec::new();
+		while let Some(range) = self.left.front_mut() {
+			if range.start >= offset {
+				break;
+			}
+
+			let until = range.end.min(offset);
+			buf.resize((until - range.start) as usize, 0);
+			file.seek(SeekFrom::Start(range.start)).await?;
+			file.read_exact(&mut buf).await?;
+			self.hasher.update(&buf);
+			range.start = until;
+			if !range.is_empty() {
+				break;
+			}
+			self.left.pop_front();
+		}
+
+		Ok(())
+	}
+
+	/// Read what's left to hash from `file`, as the `cas_id` of large files samples parts of it
+	pub async fn finish(
+		mut self,
+		file: &mut (impl AsyncRead + AsyncSeek + Unpin),
+	) -> Result<String, io::Error> {
+		self.read_until(file, u64::MAX).await?;
+
+		Ok(self.hasher.finalize().to_hex()[..16].to_string())
+	}
+}
+
+/// The parts of a file of `size` bytes its `cas_id` hashes, in the order [`generate_cas_id`] reads them
+fn sampled_ranges(size: u64) -> VecDeque<Range<u64>> {
+	if size <= MINIMUM_FILE_SIZE {
+		return iter::once(0..size).collect();
+	}
+
+	let seek_jump = (size - HEADER_OR_FOOTER_SIZE * 2) / SAMPLE_COUNT;
+	iter::once(0..HEADER_OR_FOOTER_SIZE)
+		.chain((0..SAMPLE_COUNT).map(|i| {
+			let start = HEADER_OR_FOOTER_SIZE + seek_jump * i;
+			start..start + SAMPLE_SIZE
+		}))
+		.chain(iter::once(size - HEADER_OR_FOOTER_SIZE..size))
+		.collect()
+}
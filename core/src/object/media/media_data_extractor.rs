@@ -1,7 +1,7 @@
 use crate::old_job::JobRunErrors;
 
 use sd_file_ext::extensions::{Extension, ImageExtension, ALL_IMAGE_EXTENSIONS};
-use sd_file_path_helper::{file_path_for_media_processor, IsolatedFilePathData};
+use sd_file_path_helper::{file_path_for_media_processor, full_path_on_disk, IsolatedFilePathData};
 use sd_media_metadata::ImageMetadata;
 use sd_prisma::prisma::{location, media_data, PrismaClient};
 use sd_utils::error::FileIOError;
@@ -115,7 +115,17 @@ pub async fn process(
 				IsolatedFilePathData::try_from((location_id, file_path))
 					.map_err(|e| error!("{e:#?}"))
 					.ok()
-					.map(|iso_file_path| (idx, location_path.join(iso_file_path), object_id))
+					.map(|iso_file_path| {
+						(
+							idx,
+							full_path_on_disk(
+								location_path,
+								&iso_file_path,
+								file_path.name_bytes.as_deref(),
+							),
+							object_id,
+						)
+					})
 			})
 			.map(|(idx, path, object_id)| async move {
 				let res = extract_media_data(&path).await;
@@ -14,7 +14,7 @@ use crate::old_job::JobRunErrors;
 use sd_file_ext::extensions::Extension;
 use sd_file_path_helper::{
 	ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
-	file_path_for_media_processor, IsolatedFilePathData,
+	file_path_for_media_processor, full_path_on_disk, IsolatedFilePathData,
 };
 use sd_prisma::prisma::{location, PrismaClient};
 use sd_utils::db::maybe_missing;
@@ -606,6 +606,8 @@ fn prepare_args(
 		return None;
 	};
 
+	let name_bytes = file_path.name_bytes.clone();
+
 	let Ok(iso_file_path) = IsolatedFilePathData::try_from((location_id, file_path)).map_err(|e| {
 		error!("Failed to extract isolated file path data from file path <id='{file_path_id}'>: {e:#?}");
 	}) else {
@@ -615,6 +617,6 @@ fn prepare_args(
 	Some(GenerateThumbnailArgs::new(
 		iso_file_path.extension().to_string(),
 		cas_id,
-		location_path.join(&iso_file_path),
+		full_path_on_disk(location_path, &iso_file_path, name_bytes.as_deref()),
 	))
 }
@@ -9,7 +9,7 @@ use crate::{
 use sd_file_ext::extensions::Extension;
 use sd_file_path_helper::{
 	ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
-	file_path_for_media_processor, IsolatedFilePathData,
+	file_path_for_media_processor, full_path_on_disk, IsolatedFilePathData,
 };
 use sd_prisma::prisma::{location, PrismaClient};
 use sd_utils::db::maybe_missing;
@@ -266,15 +266,16 @@ async fn dispatch_thumbnails_for_processing(
 		})
 		.filter_map(|(cas_id, file_path)| {
 			let file_path_id = file_path.id;
+			let name_bytes = file_path.name_bytes.clone();
 			IsolatedFilePathData::try_from((location_id, file_path))
 				.map_err(|e| {
 					error!("Failed to extract isolated file path data from file path <id='{file_path_id}'>: {e:#?}");
 				})
 				.ok()
-				.map(|iso_file_path| (cas_id, iso_file_path))
+				.map(|iso_file_path| (cas_id, iso_file_path, name_bytes))
 		})
-		.map(|(cas_id, iso_file_path)| {
-			let full_path = location_path.join(&iso_file_path);
+		.map(|(cas_id, iso_file_path, name_bytes)| {
+			let full_path = full_path_on_disk(location_path, &iso_file_path, name_bytes.as_deref());
 
 			GenerateThumbnailArgs::new(iso_file_path.extension().to_string(), cas_id, full_path)
 		})
@@ -65,7 +65,16 @@ pub fn get_indexed_thumbnail_path(node: &Node, cas_id: &str, library_id: Library
 
 /// This does not check if a thumbnail exists, it just returns the path that it would exist at
 fn get_thumbnail_path(node: &Node, cas_id: &str, kind: ThumbnailKind) -> PathBuf {
-	let mut thumb_path = node.config.data_directory();
+	get_thumbnail_path_in(node.config.data_directory(), cas_id, kind)
+}
+
+/// Same as [`get_thumbnail_path`] but within the given data directory
+pub(crate) fn get_thumbnail_path_in(
+	data_dir: impl AsRef<Path>,
+	cas_id: &str,
+	kind: ThumbnailKind,
+) -> PathBuf {
+	let mut thumb_path = data_dir.as_ref().to_path_buf();
 
 	thumb_path.push(THUMBNAIL_CACHE_DIR_NAME);
 	match kind {
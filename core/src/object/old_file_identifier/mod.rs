@@ -5,7 +5,9 @@ use crate::{
 };
 
 use sd_file_ext::{extensions::Extension, kind::ObjectKind};
-use sd_file_path_helper::{file_path_for_file_identifier, FilePathError, IsolatedFilePathData};
+use sd_file_path_helper::{
+	file_path_for_file_identifier, full_path_on_disk, FilePathError, IsolatedFilePathData,
+};
 use sd_prisma::{
 	prisma::{file_path, location, object, PrismaClient},
 	prisma_sync,
@@ -56,8 +58,9 @@ impl FileMetadata {
 	pub async fn new(
 		location_path: impl AsRef<Path>,
 		iso_file_path: &IsolatedFilePathData<'_>, // TODO: use dedicated CreateUnchecked type
+		name_bytes: Option<&[u8]>,
 	) -> Result<FileMetadata, FileIOError> {
-		let path = location_path.as_ref().join(iso_file_path);
+		let path = full_path_on_disk(location_path, iso_file_path, name_bytes);
 
 		let fs_metadata = fs::metadata(&path)
 			.await
@@ -111,7 +114,8 @@ async fn identifier_job_step(
 					.ok()
 			})
 			.map(|(iso_file_path, file_path)| async move {
-				FileMetadata::new(&location_path, &iso_file_path)
+				let name_bytes = file_path.name_bytes.as_deref();
+				FileMetadata::new(&location_path, &iso_file_path, name_bytes)
 					.await
 					.map(|metadata| {
 						(
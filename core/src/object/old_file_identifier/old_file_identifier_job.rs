@@ -262,6 +262,11 @@ fn orphan_path_filters(
 			file_path::is_dir::equals(Some(false)),
 			file_path::location_id::equals(Some(location_id)),
 			file_path::size_in_bytes_bytes::not(Some(0u64.to_be_bytes().to_vec())),
+			// Reading a cloud placeholder would have its sync client download it
+			or!(
+				file_path::is_placeholder::equals(None),
+				file_path::is_placeholder::equals(Some(false))
+			),
 		],
 		[
 			// this is a workaround for the cursor not working properly
@@ -136,6 +136,11 @@ fn orphan_path_filters(
 					.expect("sub path for shallow identifier must be a directory"),
 			)),
 			file_path::size_in_bytes_bytes::not(Some(0u64.to_be_bytes().to_vec())),
+			// Reading a cloud placeholder would have its sync client download it
+			or!(
+				file_path::is_placeholder::equals(None),
+				file_path::is_placeholder::equals(Some(false))
+			),
 		],
 		[file_path_id.map(file_path::id::gte)],
 	)
@@ -7,7 +7,7 @@ use crate::{
 
 use sd_file_path_helper::{
 	ensure_file_path_exists, ensure_sub_path_is_directory, ensure_sub_path_is_in_location,
-	file_path_for_object_validator, IsolatedFilePathData,
+	file_path_for_object_validator, full_path_on_disk, IsolatedFilePathData,
 };
 use sd_prisma::{
 	prisma::{file_path, location},
@@ -147,10 +147,11 @@ impl StatefulJob for OldObjectValidatorJobInit {
 		// we can also compare old and new checksums here
 		// This if is just to make sure, we already queried objects where integrity_checksum is null
 		if file_path.integrity_checksum.is_none() {
-			let full_path = data.location_path.join(IsolatedFilePathData::try_from((
-				init.location.id,
-				file_path,
-			))?);
+			let full_path = full_path_on_disk(
+				&data.location_path,
+				&IsolatedFilePathData::try_from((init.location.id, file_path))?,
+				file_path.name_bytes.as_deref(),
+			);
 			let checksum = file_checksum(&full_path)
 				.await
 				.map_err(|e| ValidatorError::FileIO(FileIOError::from((full_path, e))))?;
@@ -1,6 +1,8 @@
 use crate::{
 	library::Library,
-	location::indexer::old_indexer_job::OldIndexerJobInit,
+	location::indexer::{
+		old_indexer_job::OldIndexerJobInit, old_verifier_job::OldLocationVerifierJobInit,
+	},
 	object::{
 		fs::{
 			old_copy::OldFileCopierJobInit, old_cut::OldFileCutterJobInit,
@@ -400,6 +402,7 @@ fn initialize_resumable_job(
 			OldIndexerJobInit,
 			OldFileIdentifierJobInit,
 			OldObjectValidatorJobInit,
+			OldLocationVerifierJobInit,
 			OldFileCutterJobInit,
 			OldFileCopierJobInit,
 			OldFileDeleterJobInit,
@@ -347,6 +347,13 @@ impl<RunMetadata, Step> From<(RunMetadata, Vec<Step>, JobRunErrors)>
 	}
 }
 
+#[cfg(test)]
+impl<RunMetadata, Step> JobInitOutput<RunMetadata, Step> {
+	pub(crate) fn into_parts(self) -> (RunMetadata, VecDeque<Step>) {
+		(self.run_metadata, self.steps)
+	}
+}
+
 pub struct CurrentStep<'step, Step> {
 	pub step: &'step Step,
 	pub step_number: usize,
@@ -358,6 +365,16 @@ pub struct JobStepOutput<Step, RunMetadata> {
 	errors: JobRunErrors,
 }
 
+#[cfg(test)]
+impl<Step, RunMetadata> JobStepOutput<Step, RunMetadata> {
+	pub(crate) fn into_parts(self) -> (Vec<Step>, Option<RunMetadata>) {
+		(
+			self.maybe_more_steps.unwrap_or_default(),
+			self.maybe_more_metadata,
+		)
+	}
+}
+
 impl<Step, RunMetadata: JobRunMetadata> From<Vec<Step>> for JobStepOutput<Step, RunMetadata> {
 	fn from(more_steps: Vec<Step>) -> Self {
 		Self {
@@ -68,6 +68,7 @@ pub enum WorkerCommand {
 pub struct WorkerContext {
 	pub library: Arc<Library>,
 	pub node: Arc<Node>,
+	pub(super) worker_id: Uuid,
 	pub(super) events_tx: chan::Sender<WorkerEvent>,
 }
 
@@ -92,6 +93,13 @@ impl WorkerContext {
 		}
 	}
 
+	/// Pause the job from within, it waits for the user to resume or cancel it like any paused job
+	pub async fn pause_job(&self) {
+		if let Err(e) = self.node.old_jobs.pause(self.worker_id).await {
+			error!("Error pausing job from its worker context: {e:#?}");
+		}
+	}
+
 	pub fn progress_msg(&self, msg: String) {
 		self.progress(vec![JobReportUpdate::Message(msg)]);
 	}
@@ -109,6 +117,27 @@ impl WorkerContext {
 	}
 }
 
+#[cfg(test)]
+impl WorkerContext {
+	/// A context to run a job's steps by hand, its events go to the returned receiver
+	pub(crate) fn for_tests(
+		library: Arc<Library>,
+		node: Arc<Node>,
+	) -> (Self, chan::Receiver<WorkerEvent>) {
+		let (events_tx, events_rx) = chan::unbounded();
+
+		(
+			Self {
+				library,
+				node,
+				worker_id: Uuid::new_v4(),
+				events_tx,
+			},
+			events_rx,
+		)
+	}
+}
+
 // a worker is a dedicated task that runs a single job
 // once the job is complete the worker will exit
 pub struct Worker {
@@ -374,6 +403,7 @@ impl Worker {
 						WorkerContext {
 							library,
 							node,
+							worker_id,
 							events_tx,
 						},
 						commands_rx,
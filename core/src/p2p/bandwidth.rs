@@ -0,0 +1,240 @@
+use std::{
+	future::Future,
+	io,
+	pin::Pin,
+	sync::{Arc, Mutex, PoisonError},
+	task::{ready, Context, Poll},
+};
+
+use sd_p2p::UnicastStream;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	time::{sleep, Duration, Instant, Sleep},
+};
+
+use crate::node::config::NodeConfig;
+
+/// The most a single write can take from the bucket at once, so concurrent transfers take turns
+const MAX_GRANT: usize = 16 * 1024;
+
+/// Node wide limit on how fast we send Spacedrops and files to peers, see `p2p_max_bytes_per_sec`.
+///
+/// Every limited stream takes from the same token bucket so concurrent transfers share the bandwidth.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+	state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+	bytes_per_sec: Option<u32>,
+	tokens: f64,
+	refilled_at: Instant,
+}
+
+impl Default for BandwidthLimiter {
+	fn default() -> Self {
+		Self {
+			state: Mutex::new(BucketState {
+				bytes_per_sec: None,
+				tokens: 0.0,
+				refilled_at: Instant::now(),
+			}),
+		}
+	}
+}
+
+impl BandwidthLimiter {
+	/// Apply the limit from the node config, this takes effect for transfers which are already running too.
+	pub(crate) fn configure(&self, config: &NodeConfig) {
+		self.set_bytes_per_sec(config.p2p_max_bytes_per_sec);
+	}
+
+	pub(crate) fn set_bytes_per_sec(&self, bytes_per_sec: Option<u32>) {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		let bytes_per_sec = bytes_per_sec.filter(|b| *b > 0);
+		if state.bytes_per_sec.is_none() && bytes_per_sec.is_some() {
+			// Everything sent while unlimited doesn't count against the new limit
+			state.tokens = 0.0;
+			state.refilled_at = Instant::now();
+		}
+		state.bytes_per_sec = bytes_per_sec;
+	}
+
+	/// Limit how fast we write to `stream`, reading from it isn't limited.
+	pub(crate) fn limit(self: &Arc<Self>, stream: UnicastStream) -> UnicastStream {
+		let addr = stream.remote_addr();
+		UnicastStream::new(
+			stream.remote_identity(),
+			LimitedStream {
+				stream,
+				limiter: self.clone(),
+				sleep: None,
+			},
+		)
+		.with_remote_addr(addr)
+	}
+
+	/// How many of `wanted` bytes can be written right now, or how long to wait before asking again
+	fn take(&self, wanted: usize) -> Result<usize, Duration> {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		let Some(bytes_per_sec) = state.bytes_per_sec else {
+			return Ok(wanted);
+		};
+		let rate = f64::from(bytes_per_sec);
+
+		// At most a second worth of bytes can be saved up
+		let now = Instant::now();
+		state.tokens =
+			rate.min(state.tokens + now.duration_since(state.refilled_at).as_secs_f64() * rate);
+		state.refilled_at = now;
+
+		// Don't wake up for every byte on slow limits, wait for a reasonably sized chunk instead
+		let grant = wanted.min(MAX_GRANT).min(bytes_per_sec as usize);
+		if state.tokens >= grant as f64 {
+			state.tokens -= grant as f64;
+			Ok(grant)
+		} else {
+			Err(Duration::from_secs_f64(
+				(grant as f64 - state.tokens) / rate,
+			))
+		}
+	}
+
+	/// Give back bytes which were taken but couldn't be written
+	fn refund(&self, bytes: usize) {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		if let Some(bytes_per_sec) = state.bytes_per_sec {
+			state.tokens = f64::from(bytes_per_sec).min(state.tokens + bytes as f64);
+		}
+	}
+}
+
+struct LimitedStream {
+	stream: UnicastStream,
+	limiter: Arc<BandwidthLimiter>,
+	sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl AsyncRead for LimitedStream {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for LimitedStream {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		loop {
+			if let Some(sleep) = &mut self.sleep {
+				ready!(sleep.as_mut().poll(cx));
+				self.sleep = None;
+			}
+
+			match self.limiter.take(buf.len()) {
+				Ok(granted) => {
+					let result = Pin::new(&mut self.stream).poll_write(cx, &buf[..granted]);
+					let written = match &result {
+						Poll::Ready(Ok(written)) => *written,
+						_ => 0,
+					};
+					if written < granted {
+						self.limiter.refund(granted - written);
+					}
+					return result;
+				}
+				Err(wait) => self.sleep = Some(Box::pin(sleep(wait))),
+			}
+		}
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use sd_p2p::Identity;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+
+	const MB: usize = 1024 * 1024;
+
+	/// Send `len` bytes over a limited local stream, returning when the receiver got all of them
+	async fn transfer(limiter: &Arc<BandwidthLimiter>, len: usize) -> std::time::Instant {
+		let (stream, mut remote) = tokio::io::duplex(64 * 1024);
+		let mut stream = limiter.limit(UnicastStream::new(
+			Identity::new().to_remote_identity(),
+			stream,
+		));
+
+		let receiver = tokio::spawn(async move {
+			let mut buf = vec![0u8; 64 * 1024];
+			let mut received = 0;
+			while received < len {
+				received += remote.read(&mut buf).await.unwrap();
+			}
+			std::time::Instant::now()
+		});
+
+		stream.write_all(&vec![0u8; len]).await.unwrap();
+		stream.flush().await.unwrap();
+		receiver.await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn transfers_are_capped() {
+		let limiter = Arc::new(BandwidthLimiter::default());
+		limiter.set_bytes_per_sec(Some(MB as u32));
+
+		let start = std::time::Instant::now();
+		let done = transfer(&limiter, 3 * MB).await;
+		let elapsed = done - start;
+		assert!(
+			elapsed >= Duration::from_millis(2700) && elapsed <= Duration::from_millis(4000),
+			"took {elapsed:?}"
+		);
+	}
+
+	#[tokio::test]
+	async fn removing_the_cap_speeds_up_running_transfers() {
+		let limiter = Arc::new(BandwidthLimiter::default());
+		limiter.set_bytes_per_sec(Some(MB as u32));
+
+		let start = std::time::Instant::now();
+		let running = tokio::spawn({
+			let limiter = limiter.clone();
+			async move { transfer(&limiter, 8 * MB).await }
+		});
+
+		tokio::time::sleep(Duration::from_secs(1)).await;
+		limiter.set_bytes_per_sec(None);
+
+		// Capped all the way this would take about 8 seconds
+		let elapsed = running.await.unwrap() - start;
+		assert!(elapsed < Duration::from_millis(2500), "took {elapsed:?}");
+	}
+
+	#[tokio::test]
+	async fn no_delay_when_unset() {
+		let limiter = Arc::new(BandwidthLimiter::default());
+
+		let start = std::time::Instant::now();
+		let elapsed = transfer(&limiter, 8 * MB).await - start;
+		assert!(elapsed < Duration::from_secs(1), "took {elapsed:?}");
+	}
+}
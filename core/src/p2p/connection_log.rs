@@ -0,0 +1,164 @@
+use std::{
+	collections::VecDeque,
+	sync::{Mutex, PoisonError},
+};
+
+use chrono::{DateTime, Utc};
+use sd_p2p::RemoteIdentity;
+use serde::Serialize;
+use specta::Type;
+
+/// How many events the [`ConnectionLog`] keeps, older ones are dropped
+pub const CONNECTION_LOG_LEN: usize = 100;
+
+/// What happened to the connection with a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Type)]
+pub enum ConnectionLogEvent {
+	Connected,
+	Disconnected,
+	// We couldn't open a stream to the peer
+	ConnectFailed,
+	// A request to or from the peer had to wait for others to finish
+	Throttled,
+	// Which of the peer's addresses a stream we opened goes through
+	AddressChosen,
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct ConnectionLogEntry {
+	pub timestamp: DateTime<Utc>,
+	pub identity: RemoteIdentity,
+	pub event: ConnectionLogEvent,
+	// The path the connection takes, eg. `libp2p-quic-ipv4`, when it's known
+	pub listener: Option<String>,
+	pub detail: Option<String>,
+}
+
+/// The most recent connection events with peers, for debugging flaky connectivity.
+#[derive(Debug, Default)]
+pub struct ConnectionLog {
+	entries: Mutex<VecDeque<ConnectionLogEntry>>,
+}
+
+impl ConnectionLog {
+	pub(crate) fn record(
+		&self,
+		identity: RemoteIdentity,
+		event: ConnectionLogEvent,
+		listener: Option<String>,
+		detail: Option<String>,
+	) {
+		let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+		if entries.len() == CONNECTION_LOG_LEN {
+			entries.pop_front();
+		}
+		entries.push_back(ConnectionLogEntry {
+			timestamp: Utc::now(),
+			identity,
+			event,
+			listener,
+			detail,
+		});
+	}
+
+	/// The logged events, oldest first, optionally only the ones about `identity`
+	pub fn snapshot(&self, identity: Option<RemoteIdentity>) -> Vec<ConnectionLogEntry> {
+		self.entries
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.iter()
+			.filter(|entry| identity.map_or(true, |identity| entry.identity == identity))
+			.cloned()
+			.collect()
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::{collections::HashMap, sync::Arc, time::Duration};
+
+	use sd_p2p::{flume::bounded, Identity, UnicastStream, P2P};
+	use tokio::{sync::oneshot, time::timeout};
+
+	use crate::p2p::{KnownPeers, P2PEvents};
+
+	use super::*;
+
+	#[test]
+	fn oldest_entries_are_dropped() {
+		let log = ConnectionLog::default();
+		let identity = Identity::new().to_remote_identity();
+		for i in 0..CONNECTION_LOG_LEN + 10 {
+			log.record(
+				identity,
+				ConnectionLogEvent::ConnectFailed,
+				None,
+				Some(i.to_string()),
+			);
+		}
+
+		let entries = log.snapshot(None);
+		assert_eq!(entries.len(), CONNECTION_LOG_LEN);
+		assert_eq!(entries[0].detail.as_deref(), Some("10"));
+		assert!(log
+			.snapshot(Some(Identity::new().to_remote_identity()))
+			.is_empty());
+	}
+
+	#[tokio::test]
+	async fn connections_are_logged_in_order() {
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		let (hook_tx, _hook_rx) = bounded(15);
+		let listener = p2p.register_listener("test", hook_tx, |_, _, _| {});
+		let (libraries_tx, _libraries_rx) = bounded(15);
+		let data_dir = tempfile::tempdir().unwrap();
+		let events = P2PEvents::spawn(
+			p2p.clone(),
+			p2p.register_hook("test", libraries_tx),
+			Arc::new(KnownPeers::load(data_dir.path()).await),
+		);
+
+		let remote = Identity::new().to_remote_identity();
+		for _ in 0..2 {
+			let (stream, _remote_stream) = tokio::io::duplex(64);
+			let peer = p2p.clone().connected_to(
+				listener,
+				HashMap::new(),
+				UnicastStream::new(remote, stream),
+				None,
+				oneshot::channel().0,
+			);
+			peer.disconnected_from(listener);
+		}
+
+		let entries = timeout(Duration::from_secs(1), async {
+			loop {
+				let entries = events.connection_log().snapshot(Some(remote));
+				if entries.len() >= 4 {
+					break entries;
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		assert_eq!(
+			entries.iter().map(|entry| entry.event).collect::<Vec<_>>(),
+			[
+				ConnectionLogEvent::Connected,
+				ConnectionLogEvent::Disconnected,
+				ConnectionLogEvent::Connected,
+				ConnectionLogEvent::Disconnected,
+			]
+		);
+		assert!(entries
+			.windows(2)
+			.all(|pair| pair[0].timestamp <= pair[1].timestamp));
+		assert!(entries
+			.iter()
+			.all(|entry| entry.listener.as_deref() == Some("test")));
+	}
+}
@@ -0,0 +1,96 @@
+use std::{fmt, io, time::Duration};
+
+use rspc::ErrorCode;
+use thiserror::Error;
+
+use super::{operations::request_file::RequestFileError, HeaderError};
+
+/// Why a P2P operation failed.
+///
+/// The handlers of incoming streams return it instead of logging themselves, so it's logged once where the stream was accepted.
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("couldn't connect to the peer: {0}")]
+	Connection(String),
+	#[error("failed to read header: {0}")]
+	Header(#[from] HeaderError),
+	/// The peer sent something we didn't expect at this point
+	#[error("protocol error: {0}")]
+	Protocol(String),
+	#[error("tunnel error: {0}")]
+	Tunnel(String),
+	#[error("the library wasn't found")]
+	LibraryNotFound,
+	#[error("unauthorized: {0}")]
+	Unauthorized(String),
+	#[error("timed out after {0:?}")]
+	TimedOut(Duration),
+	#[error("error {context}: {source}")]
+	Io {
+		context: String,
+		#[source]
+		source: io::Error,
+	},
+	/// Anything the other variants don't cover, eg. reading the library's database
+	#[error("{0}")]
+	Other(String),
+}
+
+impl Error {
+	/// For `.map_err(Error::io("sending the response"))`, `context` says what we were doing
+	pub(crate) fn io(context: &'static str) -> impl FnOnce(io::Error) -> Self {
+		move |source| Self::Io {
+			context: context.to_string(),
+			source,
+		}
+	}
+
+	/// For `.map_err(Error::protocol("invalid sync message"))`, when what the peer sent doesn't parse
+	pub(crate) fn protocol<E: fmt::Display>(context: &'static str) -> impl FnOnce(E) -> Self {
+		move |err| Self::Protocol(format!("{context}: {err}"))
+	}
+}
+
+impl From<RequestFileError> for Error {
+	fn from(err: RequestFileError) -> Self {
+		match err {
+			RequestFileError::LibraryNotFound => Self::LibraryNotFound,
+			RequestFileError::Unauthorized | RequestFileError::PathOutsideLocation => {
+				Self::Unauthorized(err.to_string())
+			}
+			RequestFileError::PeerNotFound(_) | RequestFileError::Connecting(_) => {
+				Self::Connection(err.to_string())
+			}
+			RequestFileError::InvalidResponse(_) | RequestFileError::InvalidBatchResponse => {
+				Self::Protocol(err.to_string())
+			}
+			RequestFileError::Io(source) => Self::Io {
+				context: "requesting file".into(),
+				source,
+			},
+			RequestFileError::FileNotFound
+			| RequestFileError::RangeOutOfBounds { .. }
+			| RequestFileError::TooManyFiles(_)
+			| RequestFileError::Busy
+			| RequestFileError::ContentMismatch { .. } => Self::Other(err.to_string()),
+		}
+	}
+}
+
+impl From<Error> for rspc::Error {
+	fn from(err: Error) -> Self {
+		let code = match &err {
+			Error::LibraryNotFound => ErrorCode::NotFound,
+			Error::Unauthorized(_) => ErrorCode::Forbidden,
+			Error::TimedOut(_) => ErrorCode::Timeout,
+			Error::Connection(_)
+			| Error::Header(_)
+			| Error::Protocol(_)
+			| Error::Tunnel(_)
+			| Error::Io { .. }
+			| Error::Other(_) => ErrorCode::InternalServerError,
+		};
+
+		rspc::Error::with_cause(code, err.to_string(), err)
+	}
+}
@@ -1,12 +1,30 @@
-use std::sync::Arc;
+use std::{
+	collections::HashMap,
+	fmt,
+	net::SocketAddr,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+};
 
-use sd_p2p::{flume::bounded, HookEvent, HookId, PeerConnectionCandidate, RemoteIdentity, P2P};
+use chrono::{DateTime, Utc};
+use sd_p2p::{
+	flume::bounded, ConnectionAddr, Diagnostic, DiagnosticSeverity, HookEvent, HookId, ListenerId,
+	PeerConnectionCandidate, RemoteIdentity, P2P,
+};
 use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
 use specta::Type;
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::{
+	self,
+	error::{RecvError, TryRecvError},
+};
+use tracing::warn;
 use uuid::Uuid;
 
-use super::PeerMetadata;
+use super::{ConnectionLog, ConnectionLogEvent, KnownPeers, Listener2, OpId, PeerMetadata};
 
 /// The method used for the connection with this peer.
 /// *Technically* you can have multiple under the hood but this simplifies things for the UX.
@@ -30,9 +48,30 @@ pub enum DiscoveryMethod {
 	Local,
 }
 
-// This is used for synchronizing events between the backend and the frontend.
+/// What an incoming Spacedrop contains so the frontend can render the right prompt.
 #[derive(Debug, Clone, Serialize, Type)]
 #[serde(tag = "type")]
+pub enum SpacedropKind {
+	Files,
+	Text { preview: String, len: u32 },
+}
+
+/// What a sync session with a peer is currently doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Type)]
+pub enum SyncPhase {
+	// Working out if the peer has the library and what it supports
+	Handshake,
+	// Catching up on operations missed while offline
+	Backfill,
+	// Exchanging newly created operations
+	Live,
+	Done,
+}
+
+// This is used for synchronizing events between the backend and the frontend.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Type, strum::IntoStaticStr)]
+#[serde(tag = "type")]
 pub enum P2PEvent {
 	// An add or update event
 	PeerChange {
@@ -45,38 +84,394 @@ pub enum P2PEvent {
 	PeerDelete {
 		identity: RemoteIdentity,
 	},
+	// A connection with the peer was established, `via` names the path it takes, eg. `libp2p-quic-ipv4` or `libp2p-quic-relay`
+	ConnectedPeer {
+		identity: RemoteIdentity,
+		via: String,
+		#[specta(type = Option<String>)]
+		remote_addr: Option<SocketAddr>,
+	},
+	DisconnectedPeer {
+		identity: RemoteIdentity,
+		via: String,
+		#[specta(type = Option<String>)]
+		remote_addr: Option<SocketAddr>,
+	},
+	// `peer_name` is what the peer advertises, `display_name` what the UI should show, which is its alias if we gave it one
 	SpacedropRequest {
 		id: Uuid,
+		op_id: OpId,
 		identity: RemoteIdentity,
 		peer_name: String,
+		display_name: String,
+		kind: SpacedropKind,
 		files: Vec<String>,
 	},
+	// Waiting for other Spacedrops to the same peer to finish
+	SpacedropQueued {
+		id: Uuid,
+		op_id: OpId,
+		position: u32,
+	},
+	// The Spacedrop events carry the `op_id` both peers log the transfer with
 	SpacedropProgress {
 		id: Uuid,
+		op_id: OpId,
 		percent: u8,
 	},
+	// The terminal Spacedrop events carry who and what they were about so the frontend doesn't have to remember the request
 	SpacedropTimedOut {
 		id: Uuid,
+		op_id: OpId,
+		identity: RemoteIdentity,
+		files: Vec<String>,
+		#[specta(type = String)]
+		#[serde_as(as = "DisplayFromStr")]
+		total_bytes: u64,
 	},
 	SpacedropRejected {
 		id: Uuid,
+		op_id: OpId,
+		identity: RemoteIdentity,
+		files: Vec<String>,
+		#[specta(type = String)]
+		#[serde_as(as = "DisplayFromStr")]
+		total_bytes: u64,
+	},
+	// The Spacedrop couldn't be completed, eg. the destination ran out of space
+	SpacedropFailed {
+		id: Uuid,
+		op_id: OpId,
+		reason: String,
+	},
+	// Emitted on both sides once every file has been written.
+	// The receiver may have renamed files to avoid overwriting existing ones so these are the final paths on the receiving device.
+	SpacedropCompleted {
+		id: Uuid,
+		op_id: OpId,
+		identity: RemoteIdentity,
+		files: Vec<String>,
+		#[specta(type = String)]
+		#[serde_as(as = "DisplayFromStr")]
+		total_bytes: u64,
+		saved_paths: Vec<PathBuf>,
+	},
+	// Throttled progress of a sync session with a peer, reported separately by both sides
+	SyncProgress {
+		library_id: Uuid,
+		identity: RemoteIdentity,
+		op_id: OpId,
+		operations_sent: u32,
+		operations_received: u32,
+		phase: SyncPhase,
+	},
+	// What we advertise about ourselves changed, eg. the node was renamed or a listener moved to another port
+	SelfMetadataUpdated {
+		metadata: PeerMetadata,
+		listeners: Vec<Listener2>,
+	},
+	// The QUIC listener couldn't be started on the configured port. It's retried on the next config change.
+	ListenerError {
+		v6: bool,
+		port: u16,
+		error: String,
+	},
+	// Both users have to confirm they see the same code before the peers are added to each other's contacts
+	PairingRequest {
+		id: Uuid,
+		identity: RemoteIdentity,
+		code: String,
+	},
+	PairingCompleted {
+		id: Uuid,
+		identity: RemoteIdentity,
+	},
+	// Rejected, timed out or the exchange failed. Nothing was stored on either side.
+	PairingFailed {
+		id: Uuid,
+		identity: RemoteIdentity,
+		reason: String,
+	},
+	// A file fetched from a peer didn't match its `cas_id` so it should be re-identified
+	FileContentMismatch {
+		library_id: Uuid,
+		file_path_id: Uuid,
+		expected: String,
+		actual: String,
+	},
+	// The peer's clock is so far from ours that sync could order its operations wrong, one of them should be fixed.
+	// Positive when the peer's clock is ahead.
+	ClockSkewWarning {
+		identity: RemoteIdentity,
+		#[specta(type = String)]
+		#[serde_as(as = "DisplayFromStr")]
+		skew_ms: i64,
+	},
+	// Details about the internals of mDNS or QUIC, only sent with the `p2p_diagnostics` node option for debugging
+	Diagnostic {
+		severity: DiagnosticSeverity,
+		subsystem: String,
+		message: String,
+		timestamp: DateTime<Utc>,
 	},
 }
 
+impl P2PEvent {
+	/// The name of the variant, which is also the `type` the frontend gets
+	pub fn kind(&self) -> &'static str {
+		self.into()
+	}
+}
+
+/// Handed out at subscribe time, to tell the [`SubscriberStats`] of each subscriber apart
+pub type SubscriberId = u32;
+
+/// How the events got to one subscriber, to catch a frontend falling behind on them
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriberStats {
+	pub id: SubscriberId,
+	/// The kinds of events the subscriber takes, see [`P2PEvent::kind`]. `None` when it takes all of them.
+	pub kinds: Option<&'static [&'static str]>,
+	pub subscribed_at: DateTime<Utc>,
+	pub events_delivered: u64,
+	/// How many times the subscriber fell so far behind that events were dropped before it got them
+	pub lag_incidents: u64,
+	pub events_missed: u64,
+	pub last_delivery: Option<DateTime<Utc>>,
+}
+
+/// How many events a subscriber may be behind before it misses some
+const SUBSCRIBER_CAPACITY: usize = 15;
+
+#[derive(Default)]
+struct Subscribers {
+	next_id: AtomicU32,
+	stats: Mutex<HashMap<SubscriberId, SubscriberStats>>,
+	/// The channels of the subscribers taking only some kinds of events, so the others never fill them up
+	filtered: Mutex<HashMap<SubscriberId, (&'static [&'static str], broadcast::Sender<P2PEvent>)>>,
+}
+
+impl Subscribers {
+	fn update(&self, id: SubscriberId, f: impl FnOnce(&mut SubscriberStats)) {
+		if let Some(stats) = self
+			.stats
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get_mut(&id)
+		{
+			f(stats);
+		}
+	}
+}
+
+/// Sends the [`P2PEvent`]s to every subscriber, the filtered ones only getting the kinds they take
+#[derive(Clone)]
+pub struct P2PEventsSender {
+	all: broadcast::Sender<P2PEvent>,
+	subscribers: Arc<Subscribers>,
+}
+
+impl fmt::Debug for P2PEventsSender {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("P2PEventsSender").finish_non_exhaustive()
+	}
+}
+
+impl From<broadcast::Sender<P2PEvent>> for P2PEventsSender {
+	/// A sender for the subscribers of `all` only, eg. in tests
+	fn from(all: broadcast::Sender<P2PEvent>) -> Self {
+		Self {
+			all,
+			subscribers: Arc::default(),
+		}
+	}
+}
+
+impl P2PEventsSender {
+	/// Fails like [`broadcast::Sender::send`] when none of the subscribers taking all events are left
+	#[allow(clippy::result_large_err)]
+	pub fn send(&self, event: P2PEvent) -> Result<usize, broadcast::error::SendError<P2PEvent>> {
+		let kind = event.kind();
+		for (kinds, tx) in self
+			.subscribers
+			.filtered
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.values()
+		{
+			if kinds.contains(&kind) {
+				// Its receiver is removed from here along with it, so this can't fail
+				tx.send(event.clone()).ok();
+			}
+		}
+
+		self.all.send(event)
+	}
+}
+
+/// Receives the [`P2PEvent`]s like a [`broadcast::Receiver`], keeping the [`SubscriberStats`] of its subscriber.
+/// They're dropped along with it.
+pub struct P2PEventsReceiver {
+	id: SubscriberId,
+	rx: broadcast::Receiver<P2PEvent>,
+	subscribers: Arc<Subscribers>,
+}
+
+impl P2PEventsReceiver {
+	pub fn id(&self) -> SubscriberId {
+		self.id
+	}
+
+	pub async fn recv(&mut self) -> Result<P2PEvent, RecvError> {
+		match self.rx.recv().await {
+			Ok(event) => Ok(self.delivered(event)),
+			Err(RecvError::Lagged(missed)) => {
+				self.lagged(missed);
+				Err(RecvError::Lagged(missed))
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	pub fn try_recv(&mut self) -> Result<P2PEvent, TryRecvError> {
+		match self.rx.try_recv() {
+			Ok(event) => Ok(self.delivered(event)),
+			Err(TryRecvError::Lagged(missed)) => {
+				self.lagged(missed);
+				Err(TryRecvError::Lagged(missed))
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	fn delivered(&self, event: P2PEvent) -> P2PEvent {
+		self.subscribers.update(self.id, |stats| {
+			stats.events_delivered += 1;
+			stats.last_delivery = Some(Utc::now());
+		});
+		event
+	}
+
+	fn lagged(&self, missed: u64) {
+		warn!(
+			"P2P events subscriber {} lagged behind, missing {missed} events",
+			self.id
+		);
+		self.subscribers.update(self.id, |stats| {
+			stats.lag_incidents += 1;
+			stats.events_missed += missed;
+		});
+	}
+}
+
+impl Drop for P2PEventsReceiver {
+	fn drop(&mut self) {
+		self.subscribers
+			.stats
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&self.id);
+		self.subscribers
+			.filtered
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&self.id);
+	}
+}
+
+/// Names the path a connection through `listener` takes, so users know if they are on a direct LAN path or a relay.
+pub(crate) fn connection_via(listener: &str, addr: Option<ConnectionAddr>) -> String {
+	match addr {
+		Some(ConnectionAddr::Direct(SocketAddr::V4(_))) => format!("{listener}-ipv4"),
+		Some(ConnectionAddr::Direct(SocketAddr::V6(_))) => format!("{listener}-ipv6"),
+		Some(ConnectionAddr::Relay) => format!("{listener}-relay"),
+		None => listener.to_string(),
+	}
+}
+
+pub(crate) fn remote_addr(addr: Option<ConnectionAddr>) -> Option<SocketAddr> {
+	match addr {
+		Some(ConnectionAddr::Direct(addr)) => Some(addr),
+		Some(ConnectionAddr::Relay) | None => None,
+	}
+}
+
+fn listener_name(p2p: &P2P, listener_id: ListenerId) -> &'static str {
+	p2p.listeners()
+		.into_iter()
+		.find(|listener| listener.id == listener_id)
+		.map(|listener| listener.name)
+		.unwrap_or("unknown")
+}
+
 /// A P2P hook which listens for events and sends them over a channel which can be connected to the frontend.
 pub struct P2PEvents {
-	events: (broadcast::Sender<P2PEvent>, broadcast::Receiver<P2PEvent>),
+	events: (P2PEventsSender, broadcast::Receiver<P2PEvent>),
+	connection_log: Arc<ConnectionLog>,
 }
 
 impl P2PEvents {
-	pub fn spawn(p2p: Arc<P2P>, libraries_hook_id: HookId) -> Self {
-		let events = broadcast::channel(15);
+	/// Peers are remembered in `known_peers` as they come and go
+	pub fn spawn(p2p: Arc<P2P>, libraries_hook_id: HookId, known_peers: Arc<KnownPeers>) -> Self {
+		let (all, all_rx) = broadcast::channel(SUBSCRIBER_CAPACITY);
+		let events = (P2PEventsSender::from(all), all_rx);
 		let (tx, rx) = bounded(15);
 		let _ = p2p.register_hook("sd-frontend-events", tx);
 
 		let events_tx = events.0.clone();
+		let connection_log = Arc::new(ConnectionLog::default());
+		let log = connection_log.clone();
 		tokio::spawn(async move {
+			// The address is gone from the peer by the time we hear it disconnected, so we remember it
+			let mut connections =
+				HashMap::<(RemoteIdentity, ListenerId), (String, Option<SocketAddr>)>::new();
+
 			while let Ok(event) = rx.recv_async().await {
+				match &event {
+					HookEvent::PeerConnectedWith(listener_id, peer) => {
+						let addr = peer.connection_addr(*listener_id);
+						let via = connection_via(listener_name(&p2p, *listener_id), addr);
+						let remote_addr = remote_addr(addr);
+						connections
+							.insert((peer.identity(), *listener_id), (via.clone(), remote_addr));
+						log.record(
+							peer.identity(),
+							ConnectionLogEvent::Connected,
+							Some(via.clone()),
+							remote_addr.map(|addr| addr.to_string()),
+						);
+
+						let _ = events_tx.send(P2PEvent::ConnectedPeer {
+							identity: peer.identity(),
+							via,
+							remote_addr,
+						});
+					}
+					HookEvent::PeerDisconnectedWith(listener_id, identity) => {
+						let (via, remote_addr) = connections
+							.remove(&(*identity, *listener_id))
+							.unwrap_or_else(|| {
+								(
+									connection_via(listener_name(&p2p, *listener_id), None),
+									None,
+								)
+							});
+						log.record(
+							*identity,
+							ConnectionLogEvent::Disconnected,
+							Some(via.clone()),
+							remote_addr.map(|addr| addr.to_string()),
+						);
+
+						let _ = events_tx.send(P2PEvent::DisconnectedPeer {
+							identity: *identity,
+							via,
+							remote_addr,
+						});
+					}
+					_ => {}
+				}
+
 				let event = match event {
 					// We use `HookEvent::PeerUnavailable`/`HookEvent::PeerAvailable` over `HookEvent::PeerExpiredBy`/`HookEvent::PeerDiscoveredBy` so that having an active connection is treated as "discovered".
 					// It's possible to have an active connection without mDNS data (which is what Peer*By` are for)
@@ -86,16 +481,19 @@ impl P2PEvents {
 					| HookEvent::PeerDiscoveredBy(_, peer) => {
 						let metadata = match PeerMetadata::from_hashmap(&peer.metadata()) {
 							Ok(metadata) => metadata,
-							Err(e) => {
-								println!(
-									"Invalid metadata for peer '{}': {:?}",
-									peer.identity(),
-									e
-								);
-								continue;
+							Err(err) => {
+								warn!("Invalid metadata for peer '{}': {err}", peer.identity());
+								match err.into_partial() {
+									Some(metadata) => metadata,
+									None => continue,
+								}
 							}
 						};
 
+						known_peers
+							.seen(peer.identity(), metadata.clone(), peer.candidate_addrs())
+							.await;
+
 						P2PEvent::PeerChange {
 							identity: peer.identity(),
 							connection: if peer.is_connected_with_hook(libraries_hook_id) {
@@ -115,7 +513,10 @@ impl P2PEvents {
 							metadata,
 						}
 					}
-					HookEvent::PeerUnavailable(identity) => P2PEvent::PeerDelete { identity },
+					HookEvent::PeerUnavailable(identity) => {
+						known_peers.left(identity).await;
+						P2PEvent::PeerDelete { identity }
+					}
 					HookEvent::PeerDisconnectedWith(_, identity) => {
 						let peers = p2p.peers();
 						let Some(peer) = peers.get(&identity) else {
@@ -136,11 +537,119 @@ impl P2PEvents {
 			}
 		});
 
-		Self { events }
+		Self {
+			events,
+			connection_log,
+		}
+	}
+
+	/// Forward the diagnostics of the transports and discovery mechanisms as [`P2PEvent::Diagnostic`] while `enabled` is set.
+	pub(crate) fn spawn_diagnostics(&self, p2p: &P2P, enabled: Arc<AtomicBool>) -> HookId {
+		let (tx, rx) = bounded(15);
+		let hook_id = p2p.register_hook("sd-diagnostics", tx);
+
+		let events_tx = self.sender();
+		tokio::spawn(async move {
+			while let Ok(event) = rx.recv_async().await {
+				match event {
+					HookEvent::Diagnostic(Diagnostic {
+						severity,
+						subsystem,
+						message,
+					}) if enabled.load(Ordering::Relaxed) => {
+						let _ = events_tx.send(P2PEvent::Diagnostic {
+							severity,
+							subsystem: subsystem.to_string(),
+							message,
+							timestamp: Utc::now(),
+						});
+					}
+					HookEvent::Shutdown { _guard } => break,
+					_ => {}
+				}
+			}
+		});
+
+		hook_id
+	}
+
+	pub fn connection_log(&self) -> &Arc<ConnectionLog> {
+		&self.connection_log
+	}
+
+	pub fn subscribe(&self) -> P2PEventsReceiver {
+		self.subscribe_to(None)
+	}
+
+	/// Subscribe to only the events of these `kinds`, see [`P2PEvent::kind`]
+	pub fn subscribe_filtered(&self, kinds: &'static [&'static str]) -> P2PEventsReceiver {
+		self.subscribe_to(Some(kinds))
+	}
+
+	fn subscribe_to(&self, kinds: Option<&'static [&'static str]>) -> P2PEventsReceiver {
+		let subscribers = &self.events.0.subscribers;
+		let id = subscribers.next_id.fetch_add(1, Ordering::Relaxed);
+		subscribers
+			.stats
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(
+				id,
+				SubscriberStats {
+					id,
+					kinds,
+					subscribed_at: Utc::now(),
+					events_delivered: 0,
+					lag_incidents: 0,
+					events_missed: 0,
+					last_delivery: None,
+				},
+			);
+
+		let rx = match kinds {
+			Some(kinds) => {
+				let (tx, rx) = broadcast::channel(SUBSCRIBER_CAPACITY);
+				subscribers
+					.filtered
+					.lock()
+					.unwrap_or_else(PoisonError::into_inner)
+					.insert(id, (kinds, tx));
+				rx
+			}
+			None => self.events.0.all.subscribe(),
+		};
+
+		P2PEventsReceiver {
+			id,
+			rx,
+			subscribers: subscribers.clone(),
+		}
+	}
+
+	/// How the events are getting to each current subscriber
+	pub fn stats(&self) -> Vec<SubscriberStats> {
+		let mut stats = self
+			.events
+			.0
+			.subscribers
+			.stats
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.values()
+			.cloned()
+			.collect::<Vec<_>>();
+		stats.sort_by_key(|stats| stats.id);
+		stats
 	}
 
-	pub fn subscribe(&self) -> broadcast::Receiver<P2PEvent> {
-		self.events.0.subscribe()
+	/// Whether anything is listening for all the events, eg. a frontend. The receiver kept here only so
+	/// sending never fails isn't counted, neither are the subscribers taking only some kinds of events.
+	pub(crate) fn has_subscribers(&self) -> bool {
+		self.events.0.all.receiver_count() > 1
+	}
+
+	pub(crate) fn sender(&self) -> P2PEventsSender {
+		self.events.0.clone()
 	}
 
 	#[allow(clippy::result_large_err)]
@@ -148,3 +657,186 @@ impl P2PEvents {
 		self.events.0.send(event)
 	}
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic)]
+mod tests {
+	use std::{
+		net::{Ipv4Addr, Ipv6Addr},
+		time::Duration,
+	};
+
+	use sd_p2p::Identity;
+	use tokio::time::timeout;
+
+	use super::*;
+
+	#[test]
+	fn via_names_the_listener_and_path() {
+		let local = ConnectionAddr::Direct((Ipv4Addr::LOCALHOST, 7373).into());
+
+		assert_eq!(
+			connection_via("libp2p-quic", Some(local)),
+			"libp2p-quic-ipv4"
+		);
+		assert_eq!(
+			remote_addr(Some(local)).map(|addr| addr.ip().is_loopback()),
+			Some(true)
+		);
+
+		assert_eq!(
+			connection_via(
+				"libp2p-quic",
+				Some(ConnectionAddr::Direct((Ipv6Addr::LOCALHOST, 7373).into()))
+			),
+			"libp2p-quic-ipv6"
+		);
+
+		assert_eq!(
+			connection_via("libp2p-quic", Some(ConnectionAddr::Relay)),
+			"libp2p-quic-relay"
+		);
+		assert_eq!(remote_addr(Some(ConnectionAddr::Relay)), None);
+
+		// The listener didn't know where the connection comes from
+		assert_eq!(connection_via("libp2p-quic", None), "libp2p-quic");
+	}
+
+	#[tokio::test]
+	async fn diagnostics_are_only_sent_when_enabled() {
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		let (libraries_tx, _libraries_rx) = bounded(15);
+		let data_dir = tempfile::tempdir().unwrap();
+		let events = P2PEvents::spawn(
+			p2p.clone(),
+			p2p.register_hook("test", libraries_tx),
+			Arc::new(KnownPeers::load(data_dir.path()).await),
+		);
+		let enabled = Arc::new(AtomicBool::new(false));
+		events.spawn_diagnostics(&p2p, enabled.clone());
+		let mut rx = events.subscribe();
+
+		p2p.diagnostic(DiagnosticSeverity::Warning, "mdns", "failed to announce");
+		// Let the hook go through the diagnostic before it's enabled
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		enabled.store(true, Ordering::Relaxed);
+		p2p.diagnostic(DiagnosticSeverity::Error, "quic", "failed to listen");
+
+		let event = timeout(Duration::from_secs(1), rx.recv())
+			.await
+			.unwrap()
+			.unwrap();
+		let P2PEvent::Diagnostic {
+			severity,
+			subsystem,
+			message,
+			..
+		} = event
+		else {
+			panic!("expected a diagnostic, got {event:?}");
+		};
+		assert_eq!(severity, DiagnosticSeverity::Error);
+		assert_eq!(subsystem, "quic");
+		assert_eq!(message, "failed to listen");
+		assert!(rx.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn only_the_subscriber_which_lagged_has_it_in_its_stats() {
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		let (libraries_tx, _libraries_rx) = bounded(15);
+		let data_dir = tempfile::tempdir().unwrap();
+		let events = P2PEvents::spawn(
+			p2p.clone(),
+			p2p.register_hook("test", libraries_tx),
+			Arc::new(KnownPeers::load(data_dir.path()).await),
+		);
+		let mut keeping_up = events.subscribe();
+		let mut lagging = events.subscribe();
+		let mut filtered = events.subscribe_filtered(&["PeerChange"]);
+
+		let identity = Identity::new().to_remote_identity();
+		for _ in 0..20 {
+			events.send(P2PEvent::PeerDelete { identity }).unwrap();
+			keeping_up.try_recv().unwrap();
+		}
+		// The channel only keeps the last 15 events
+		assert!(matches!(lagging.recv().await, Err(RecvError::Lagged(5))));
+		lagging.recv().await.unwrap();
+		// The events of other kinds never got to the filtered one, so it didn't fall behind
+		assert!(matches!(filtered.try_recv(), Err(TryRecvError::Empty)));
+
+		let metadata = PeerMetadata {
+			name: "peer".to_string(),
+			operating_system: None,
+			device_model: None,
+			version: None,
+			succession: None,
+			spacedrop_mode: None,
+		};
+		for _ in 0..20 {
+			events
+				.send(P2PEvent::PeerChange {
+					identity,
+					connection: ConnectionMethod::Local,
+					discovery: DiscoveryMethod::Local,
+					metadata: metadata.clone(),
+				})
+				.unwrap();
+			assert!(matches!(
+				filtered.try_recv(),
+				Ok(P2PEvent::PeerChange { .. })
+			));
+		}
+
+		let stats = events.stats();
+		let stats_of = |rx: &P2PEventsReceiver| stats.iter().find(|s| s.id == rx.id()).unwrap();
+		assert_eq!(stats.len(), 3);
+
+		let keeping_up_stats = stats_of(&keeping_up);
+		assert_eq!(keeping_up_stats.events_delivered, 20);
+		assert_eq!(keeping_up_stats.lag_incidents, 0);
+		assert!(keeping_up_stats.last_delivery.is_some());
+
+		let lagging_stats = stats_of(&lagging);
+		assert_eq!(lagging_stats.events_delivered, 1);
+		assert_eq!(lagging_stats.lag_incidents, 1);
+		assert_eq!(lagging_stats.events_missed, 5);
+
+		// Only its kind of events is delivered to it
+		let filtered_stats = stats_of(&filtered);
+		assert_eq!(filtered_stats.kinds, Some(&["PeerChange"][..]));
+		assert_eq!(filtered_stats.events_delivered, 20);
+		assert_eq!(filtered_stats.lag_incidents, 0);
+
+		drop(lagging);
+		assert_eq!(events.stats().len(), 2);
+	}
+
+	#[tokio::test]
+	async fn only_subscribers_taking_every_event_count() {
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		let (libraries_tx, _libraries_rx) = bounded(15);
+		let data_dir = tempfile::tempdir().unwrap();
+		let events = P2PEvents::spawn(
+			p2p.clone(),
+			p2p.register_hook("test", libraries_tx),
+			Arc::new(KnownPeers::load(data_dir.path()).await),
+		);
+		assert!(!events.has_subscribers());
+
+		// Like the diagnostics stream of the frontend, which doesn't answer Spacedrops
+		let diagnostics = events.subscribe_filtered(&["Diagnostic"]);
+		assert!(!events.has_subscribers());
+
+		let frontend = events.subscribe();
+		assert!(events.has_subscribers());
+
+		drop(frontend);
+		assert!(!events.has_subscribers());
+		drop(diagnostics);
+	}
+}
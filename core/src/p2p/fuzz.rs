@@ -0,0 +1,302 @@
+//! Entry points for the fuzz targets in `core/fuzz`, which feed the parsers of the listening side
+//! whatever bytes they come up with.
+//!
+//! Whatever a peer sends has to end in a value or an error which maps to a P2P [`Error`], never in
+//! a panic. Length fields over the caps of the protocol have to be refused before allocating for
+//! them, which the targets catch when run with a malloc limit, eg. from `core`:
+//! `cargo +nightly fuzz run header -- -malloc_limit_mb=64`
+
+use super::{
+	operations::spacedrop::SpacedropPayload,
+	sync::{OperationsFrame, SyncFrame, SyncMessage, SyncResponse},
+	Header, HeaderError, OpId,
+};
+
+use sd_p2p_block::{
+	BlockSize, Compression, Range, SpaceblockRequest, SpaceblockRequestError, SpaceblockRequests,
+	SpaceblockRequestsError, MAX_SPACEDROP_FILES,
+};
+use sd_p2p_proto::decode;
+
+use std::{
+	io::{self, Cursor},
+	time::{Duration, UNIX_EPOCH},
+};
+
+use arbitrary::Unstructured;
+use futures::executor::block_on;
+use uuid::Uuid;
+
+/// A [`Header`] read from `data`
+pub fn header(data: &[u8]) {
+	let bytes = stream_bytes(data, |u| {
+		let op_id = match u.arbitrary()? {
+			true => Some(OpId::from_uuid(Uuid::from_bytes(u.arbitrary()?))),
+			false => None,
+		};
+		let discriminator = u.int_in_range(0..=11)?;
+		// Spacedrop requests are in the format of the nodes from before op ids when there isn't one
+		if discriminator == 0 {
+			let header = Header::Spacedrop(SpacedropPayload::Files(arbitrary_requests(u)?));
+			return Ok(match op_id {
+				Some(op_id) => header.to_bytes_with_op_id(op_id),
+				None => header.to_bytes(),
+			});
+		}
+
+		let mut bytes = vec![];
+		if let Some(op_id) = op_id {
+			bytes.push(10);
+			bytes.extend(op_id.as_uuid().as_bytes());
+		}
+		bytes.push(discriminator);
+		Ok(bytes)
+	});
+
+	// A cursor never has to wait for more bytes, so the parsers run to completion straight away
+	if let Err(err) = block_on(Header::from_stream_with_op_id(&mut Cursor::new(bytes))) {
+		assert_header_error(&err);
+	}
+}
+
+/// The [`SpaceblockRequests`] of a Spacedrop read from `data`
+pub fn spaceblock_requests(data: &[u8]) {
+	let bytes = stream_bytes(data, |u| Ok(arbitrary_requests(u)?.to_bytes()));
+
+	match block_on(SpaceblockRequests::from_stream(&mut Cursor::new(&bytes))) {
+		Ok(requests) => {
+			let again = block_on(SpaceblockRequests::from_stream(&mut Cursor::new(
+				requests.to_bytes(),
+			)));
+			assert_eq!(again.ok(), Some(requests));
+		}
+		Err(err) => assert_requests_error(&err),
+	}
+
+	// As sent by nodes from before compression and file metadata
+	match block_on(SpaceblockRequests::from_stream_legacy(&mut Cursor::new(
+		&bytes,
+	))) {
+		Ok(requests) => {
+			let again = block_on(SpaceblockRequests::from_stream_legacy(&mut Cursor::new(
+				requests.to_bytes_legacy(),
+			)));
+			assert_eq!(again.ok(), Some(requests));
+		}
+		Err(err) => assert_requests_error(&err),
+	}
+}
+
+/// Each of the messages of a sync stream read from `data`
+pub fn sync(data: &[u8]) {
+	let bytes = stream_bytes(data, |u| {
+		let message = match u.int_in_range(0..=2)? {
+			0 => SyncMessage::NewOperations,
+			1 => SyncMessage::RequestOperationsSince {
+				timestamp: crate::sync::NTP64(u.arbitrary()?),
+			},
+			_ => SyncMessage::Multiplexed,
+		};
+		let mut bytes = message.to_bytes();
+		bytes.extend(
+			SyncFrame::Library {
+				library_id: Uuid::from_bytes(u.arbitrary()?),
+				op_id: OpId::from_uuid(Uuid::from_bytes(u.arbitrary()?)),
+				message,
+			}
+			.to_bytes(),
+		);
+		Ok(bytes)
+	});
+
+	// The same bytes are read as each message, as any of them can come first on a stream
+	block_on(async {
+		if let Err(err) = SyncMessage::from_stream(&mut Cursor::new(&bytes)).await {
+			assert_decode_error(&err);
+		}
+		if let Err(err) = SyncFrame::from_stream(&mut Cursor::new(&bytes)).await {
+			assert_decode_error(&err);
+		}
+		if let Err(err) = OperationsFrame::from_stream(&mut Cursor::new(&bytes)).await {
+			assert_decode_error(&err);
+		}
+		if let Err(err) = SyncResponse::from_stream(&mut Cursor::new(&bytes)).await {
+			assert_decode_error(&err);
+		}
+	});
+}
+
+/// Bytes from a peer can only run out or be invalid, anything else is a bug in the parser
+fn assert_io_error(err: &io::Error) {
+	assert!(
+		matches!(
+			err.kind(),
+			io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData
+		),
+		"unexpected io error: {err:?}"
+	);
+}
+
+fn assert_decode_error(err: &decode::Error) {
+	match err {
+		decode::Error::IoError(err) => assert_io_error(err),
+		decode::Error::TooLong { len, max } => assert!(len > max, "{len} isn't over {max}"),
+		decode::Error::UuidFormatError(_)
+		| decode::Error::NameFormatError(_)
+		| decode::Error::InvalidRemoteIdentity(_) => {}
+	}
+}
+
+fn assert_requests_error(err: &SpaceblockRequestsError) {
+	match err {
+		SpaceblockRequestsError::Id(err) => assert_decode_error(err),
+		SpaceblockRequestsError::InvalidLen(err)
+		| SpaceblockRequestsError::BlockSize(err)
+		| SpaceblockRequestsError::Compression(err) => assert_io_error(err),
+		SpaceblockRequestsError::TooManyRequests(len) => assert!(*len > MAX_SPACEDROP_FILES),
+		SpaceblockRequestsError::SpaceblockRequest(err) => match err {
+			SpaceblockRequestError::Name(err) => assert_decode_error(err),
+			SpaceblockRequestError::Size(err)
+			| SpaceblockRequestError::RangeError(err)
+			| SpaceblockRequestError::Metadata(err) => assert_io_error(err),
+		},
+	}
+}
+
+fn assert_header_error(err: &HeaderError) {
+	match err {
+		HeaderError::DiscriminatorIo(err) => assert_io_error(err),
+		HeaderError::DiscriminatorInvalid(_) => {}
+		HeaderError::SpacedropRequest(err) => assert_requests_error(err),
+		HeaderError::SpacedropTextRequest(err)
+		| HeaderError::SyncRequest(err)
+		| HeaderError::FileRequest(err)
+		| HeaderError::ThumbnailRequest(err)
+		| HeaderError::PairRequest(err)
+		| HeaderError::IdentifyRequest(err)
+		| HeaderError::OpId(err) => assert_decode_error(err),
+	}
+}
+
+/// The bytes of the stream `data` stands for. Its first byte picks whether the rest is used as is,
+/// or to build a well formed encoding with `encode` which is then cut short or has a byte changed,
+/// to get further into the parsers than random bytes do.
+fn stream_bytes(
+	data: &[u8],
+	encode: impl FnOnce(&mut Unstructured<'_>) -> arbitrary::Result<Vec<u8>>,
+) -> Vec<u8> {
+	let Some((mode, data)) = data.split_first() else {
+		return vec![];
+	};
+	if mode % 2 == 0 {
+		return data.to_vec();
+	}
+
+	let mut u = Unstructured::new(data);
+	let Ok(mut bytes) = encode(&mut u) else {
+		return data.to_vec();
+	};
+	if !bytes.is_empty() {
+		let at = u.choose_index(bytes.len()).unwrap_or_default();
+		match u.int_in_range(0..=2).unwrap_or_default() {
+			0 => {}
+			1 => bytes.truncate(at),
+			_ => bytes[at] ^= u.arbitrary::<u8>().unwrap_or(0xff).max(1),
+		}
+	}
+	bytes.extend(u.take_rest());
+
+	bytes
+}
+
+fn arbitrary_requests(u: &mut Unstructured<'_>) -> arbitrary::Result<SpaceblockRequests> {
+	let len = u.int_in_range(0..=8)?;
+	Ok(SpaceblockRequests {
+		id: Uuid::from_bytes(u.arbitrary()?),
+		block_size: BlockSize::from_size(u.arbitrary()?),
+		compression: match u.arbitrary()? {
+			true => Compression::Zstd,
+			false => Compression::None,
+		},
+		requests: (0..len)
+			.map(|_| arbitrary_request(u))
+			.collect::<arbitrary::Result<_>>()?,
+	})
+}
+
+fn arbitrary_request(u: &mut Unstructured<'_>) -> arbitrary::Result<SpaceblockRequest> {
+	Ok(SpaceblockRequest {
+		// Names longer than a `u16` can't be encoded at all
+		name: u.arbitrary::<&str>()?.chars().take(255).collect(),
+		size: u.arbitrary()?,
+		range: match u.arbitrary()? {
+			true => Range::Partial(u.arbitrary()?..u.arbitrary()?),
+			false => Range::Full,
+		},
+		modified_at: u
+			.arbitrary::<Option<u64>>()?
+			.map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos)),
+		created_at: u
+			.arbitrary::<Option<u64>>()?
+			.map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos)),
+		mode: u.arbitrary()?,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A corpus small enough for `cargo test`, the fuzz targets run the same checks for as long as
+	/// they're given
+	fn corpus() -> Vec<Vec<u8>> {
+		// An xorshift keeps the corpus the same from run to run
+		let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+		let mut next = move || {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			state
+		};
+
+		let mut corpus = (0..2000)
+			.map(|_| {
+				let len = (next() % 512) as usize;
+				(0..len).map(|_| next() as u8).collect()
+			})
+			.collect::<Vec<Vec<u8>>>();
+		corpus.push(vec![]);
+		// Every discriminator on its own, then followed by lengths as long as they get
+		corpus.extend((0..=u8::MAX).map(|d| vec![0, d]));
+		corpus.extend((0..=u8::MAX).map(|d| [&[0, d][..], &[0xff; 64]].concat()));
+
+		corpus
+	}
+
+	#[test]
+	fn parsers_survive_a_bounded_corpus() {
+		for data in corpus() {
+			header(&data);
+			spaceblock_requests(&data);
+			sync(&data);
+		}
+	}
+
+	#[test]
+	fn lengths_over_the_caps_are_refused_before_reading_on() {
+		let mut bytes = vec![10];
+		bytes.extend(Uuid::new_v4().as_bytes());
+		bytes.push(0);
+		bytes.extend(Uuid::new_v4().as_bytes());
+		bytes.extend(BlockSize::from_size(0).to_bytes());
+		bytes.push(Compression::None.to_byte());
+		bytes.extend((MAX_SPACEDROP_FILES + 1).to_le_bytes());
+
+		assert!(matches!(
+			block_on(Header::from_stream(&mut Cursor::new(bytes))),
+			Err(HeaderError::SpacedropRequest(
+				sd_p2p_block::SpaceblockRequestsError::TooManyRequests(_)
+			))
+		));
+	}
+}
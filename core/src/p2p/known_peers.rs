@@ -0,0 +1,333 @@
+use crate::util::write_atomic;
+
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use sd_p2p::RemoteIdentity;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{fs, sync::Mutex};
+use tracing::warn;
+
+use super::PeerMetadata;
+
+/// How long a peer which isn't seen again is remembered for, it's dropped the next time the node starts
+pub const KNOWN_PEER_RETENTION_DAYS: i64 = 90;
+
+/// How stale the `last_seen` of a peer which is still around may get before it's refreshed and saved,
+/// so a peer which never goes offline isn't dropped after a crash
+const SAVE_SEEN_EVERY_HOURS: i64 = 1;
+
+/// The file within the node's data directory the known peers are stored in
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+
+/// What we last knew about a peer, kept once it goes offline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RememberedPeer {
+	metadata: PeerMetadata,
+	last_seen: DateTime<Utc>,
+	addrs: Vec<SocketAddr>,
+}
+
+/// A peer which is online or was seen before, so the device list can show it either way
+#[derive(Debug, Clone, PartialEq, Serialize, Type)]
+pub struct KnownPeer {
+	pub identity: RemoteIdentity,
+	pub metadata: PeerMetadata,
+	pub online: bool,
+	// Now for the peers which are online
+	pub last_seen: DateTime<Utc>,
+	// Where it was last found, the peers which are offline may not be there anymore
+	#[specta(type = Vec<String>)]
+	pub addrs: Vec<SocketAddr>,
+}
+
+/// Every peer this node saw, kept in the node's data directory so they're still listed once they went offline.
+#[derive(Debug)]
+pub struct KnownPeers {
+	path: PathBuf,
+	peers: Mutex<HashMap<RemoteIdentity, RememberedPeer>>,
+}
+
+impl KnownPeers {
+	/// Load the peers stored in `data_dir`, leaving out the ones which weren't seen for [`KNOWN_PEER_RETENTION_DAYS`].
+	/// Starts over if they're missing or can't be read.
+	pub(crate) async fn load(data_dir: impl AsRef<Path>) -> Self {
+		let path = data_dir.as_ref().join(KNOWN_PEERS_FILE);
+
+		let mut peers: HashMap<RemoteIdentity, RememberedPeer> = match fs::read(&path).await {
+			Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+				warn!(
+					"Failed to parse the known peers at '{}': {err}",
+					path.display()
+				);
+				HashMap::new()
+			}),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+			Err(err) => {
+				warn!(
+					"Failed to read the known peers at '{}': {err}",
+					path.display()
+				);
+				HashMap::new()
+			}
+		};
+
+		let retained_since = Utc::now() - Duration::days(KNOWN_PEER_RETENTION_DAYS);
+		peers.retain(|_, peer| peer.last_seen >= retained_since);
+
+		Self {
+			path,
+			peers: Mutex::new(peers),
+		}
+	}
+
+	/// The peer is online with this metadata and these addresses, the ones it was found at before are kept
+	/// when it has none, eg. over a relay. Only saved when something changed or every
+	/// [`SAVE_SEEN_EVERY_HOURS`], as peers are seen again every time mDNS announces them.
+	pub(crate) async fn seen(
+		&self,
+		identity: RemoteIdentity,
+		metadata: PeerMetadata,
+		mut addrs: Vec<SocketAddr>,
+	) {
+		let now = Utc::now();
+		let mut peers = self.peers.lock().await;
+		if let Some(peer) = peers.get_mut(&identity) {
+			if addrs.is_empty() {
+				addrs = peer.addrs.clone();
+			}
+			if peer.metadata == metadata
+				&& peer.addrs == addrs
+				&& now - peer.last_seen < Duration::hours(SAVE_SEEN_EVERY_HOURS)
+			{
+				return;
+			}
+		}
+
+		peers.insert(
+			identity,
+			RememberedPeer {
+				metadata,
+				last_seen: now,
+				addrs,
+			},
+		);
+
+		self.save(&peers).await;
+	}
+
+	/// The peer went offline, so it was last seen now
+	pub(crate) async fn left(&self, identity: RemoteIdentity) {
+		let mut peers = self.peers.lock().await;
+		let Some(peer) = peers.get_mut(&identity) else {
+			return;
+		};
+		peer.last_seen = Utc::now();
+
+		self.save(&peers).await;
+	}
+
+	/// Stop remembering the peer, it's still listed while it's online and remembered again the next time it's seen.
+	/// Returns whether it was remembered at all.
+	pub async fn forget(&self, identity: RemoteIdentity) -> bool {
+		let mut peers = self.peers.lock().await;
+		if peers.remove(&identity).is_none() {
+			return false;
+		}
+
+		self.save(&peers).await;
+		true
+	}
+
+	/// The `live` peers with their current metadata along with the remembered ones which aren't among them,
+	/// online peers first and then the most recently seen
+	pub(crate) async fn merged(
+		&self,
+		live: impl IntoIterator<Item = (RemoteIdentity, PeerMetadata, Vec<SocketAddr>)>,
+	) -> Vec<KnownPeer> {
+		let now = Utc::now();
+		let mut known = live
+			.into_iter()
+			.map(|(identity, metadata, addrs)| {
+				(
+					identity,
+					KnownPeer {
+						identity,
+						metadata,
+						online: true,
+						last_seen: now,
+						addrs,
+					},
+				)
+			})
+			.collect::<HashMap<_, _>>();
+
+		for (identity, peer) in self.peers.lock().await.iter() {
+			known.entry(*identity).or_insert_with(|| KnownPeer {
+				identity: *identity,
+				metadata: peer.metadata.clone(),
+				online: false,
+				last_seen: peer.last_seen,
+				addrs: peer.addrs.clone(),
+			});
+		}
+
+		let mut known = known.into_values().collect::<Vec<_>>();
+		known.sort_by(|a, b| {
+			b.online
+				.cmp(&a.online)
+				.then_with(|| b.last_seen.cmp(&a.last_seen))
+		});
+		known
+	}
+
+	// Called with the lock held so writes can't overtake each other
+	async fn save(&self, peers: &HashMap<RemoteIdentity, RememberedPeer>) {
+		let result = match serde_json::to_vec(peers) {
+			Ok(bytes) => write_atomic(&self.path, bytes)
+				.await
+				.map_err(|err| err.to_string()),
+			Err(err) => Err(err.to_string()),
+		};
+
+		if let Err(err) = result {
+			warn!(
+				"Failed to save the known peers to '{}': {err}",
+				self.path.display()
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::{collections::BTreeSet, net::Ipv4Addr, sync::Arc, time::Duration as StdDuration};
+
+	use sd_p2p::{flume::bounded, Identity, PeerConnectionCandidate, P2P};
+	use tempfile::tempdir;
+	use tokio::time::timeout;
+
+	use super::*;
+	use crate::p2p::{P2PEvent, P2PEvents, P2PEventsReceiver, SPACEDRIVE_APP_ID};
+
+	fn metadata(name: &str) -> PeerMetadata {
+		PeerMetadata {
+			name: name.to_string(),
+			operating_system: None,
+			device_model: None,
+			version: None,
+			succession: None,
+			spacedrop_mode: None,
+		}
+	}
+
+	async fn next_event(rx: &mut P2PEventsReceiver) -> P2PEvent {
+		timeout(StdDuration::from_secs(1), rx.recv())
+			.await
+			.unwrap()
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn peers_which_went_offline_are_still_known_after_a_restart() {
+		let dir = tempdir().unwrap();
+
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new(SPACEDRIVE_APP_ID, Identity::new(), handler_tx);
+		let (libraries_tx, _libraries_rx) = bounded(15);
+		let known_peers = Arc::new(KnownPeers::load(dir.path()).await);
+		let events = P2PEvents::spawn(
+			p2p.clone(),
+			p2p.register_hook("test", libraries_tx),
+			known_peers.clone(),
+		);
+		let mut rx = events.subscribe();
+
+		// A fake discovery mechanism finds the peer and then loses it again
+		let (discovery_tx, _discovery_rx) = bounded(15);
+		let discovery = p2p.register_hook("test-discovery", discovery_tx);
+		let identity = Identity::new().to_remote_identity();
+		let addr = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 20), 7373));
+		let mut advertised = HashMap::new();
+		metadata("MacBook").update(&mut advertised);
+		let peer = p2p.clone().discover_peer(
+			discovery,
+			identity,
+			advertised,
+			BTreeSet::from([PeerConnectionCandidate::SocketAddr(addr)]),
+		);
+
+		// The peer is stored before the frontend hears about it
+		while !matches!(next_event(&mut rx).await, P2PEvent::PeerChange { .. }) {}
+		peer.undiscover_peer(discovery);
+		while !matches!(next_event(&mut rx).await, P2PEvent::PeerDelete { .. }) {}
+		drop(events);
+		drop(known_peers);
+
+		let known_peers = KnownPeers::load(dir.path()).await;
+		let known = known_peers.merged([]).await;
+		assert_eq!(known.len(), 1, "{known:#?}");
+		assert_eq!(known[0].identity, identity);
+		assert_eq!(known[0].metadata.name, "MacBook");
+		assert!(!known[0].online);
+		assert_eq!(known[0].addrs, [addr]);
+
+		// Back online under a new name
+		let known = known_peers
+			.merged([(identity, metadata("Work MacBook"), vec![])])
+			.await;
+		assert_eq!(known.len(), 1);
+		assert!(known[0].online);
+		assert_eq!(known[0].metadata.name, "Work MacBook");
+	}
+
+	#[tokio::test]
+	async fn forgotten_and_expired_peers_are_dropped() {
+		let dir = tempdir().unwrap();
+		let forgotten = Identity::new().to_remote_identity();
+		let expired = Identity::new().to_remote_identity();
+		let kept = Identity::new().to_remote_identity();
+
+		let known_peers = KnownPeers::load(dir.path()).await;
+		for identity in [forgotten, expired, kept] {
+			known_peers.seen(identity, metadata("Laptop"), vec![]).await;
+		}
+		known_peers
+			.peers
+			.lock()
+			.await
+			.get_mut(&expired)
+			.unwrap()
+			.last_seen = Utc::now() - Duration::days(KNOWN_PEER_RETENTION_DAYS + 1);
+		assert!(known_peers.forget(forgotten).await);
+		assert!(!known_peers.forget(forgotten).await);
+
+		// Still around long after it was first seen, so it's kept
+		known_peers
+			.peers
+			.lock()
+			.await
+			.get_mut(&kept)
+			.unwrap()
+			.last_seen = Utc::now() - Duration::days(KNOWN_PEER_RETENTION_DAYS + 1);
+		known_peers.seen(kept, metadata("Laptop"), vec![]).await;
+		drop(known_peers);
+
+		let known_peers = KnownPeers::load(dir.path()).await;
+		assert_eq!(
+			known_peers
+				.merged([])
+				.await
+				.into_iter()
+				.map(|peer| peer.identity)
+				.collect::<Vec<_>>(),
+			[kept]
+		);
+	}
+}
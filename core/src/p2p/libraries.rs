@@ -1,80 +1,257 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::{HashMap, HashSet},
+	sync::{Arc, Mutex, PoisonError},
+};
 
-use sd_p2p::{flume::bounded, HookEvent, HookId, PeerConnectionCandidate, RemoteIdentity, P2P};
-use tracing::error;
+use sd_p2p::{
+	flume::bounded, HookEvent, HookId, IdentitySuccession, PeerConnectionCandidate, RemoteIdentity,
+	P2P,
+};
+use sd_prisma::prisma::instance;
+use tracing::{debug, error};
+use uuid::Uuid;
 
-use crate::library::{Libraries, LibraryManagerEvent};
+use crate::{
+	library::{Libraries, Library, LibraryManagerEvent},
+	node::config,
+};
+
+use super::PeerMetadata;
+
+/// The libraries which the user has excluded from P2P.
+///
+/// They aren't advertised to other nodes and any requests for them are rejected, even if the remote node's metadata says it's an instance.
+#[derive(Debug, Default)]
+pub struct DisabledLibraries(Mutex<HashSet<Uuid>>);
+
+impl DisabledLibraries {
+	pub fn contains(&self, library_id: &Uuid) -> bool {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.contains(library_id)
+	}
+
+	/// Returns `true` if this changed whether the library is disabled.
+	fn set(&self, library_id: Uuid, enabled: bool) -> bool {
+		let mut disabled = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+		match enabled {
+			true => disabled.remove(&library_id),
+			false => disabled.insert(library_id),
+		}
+	}
+}
+
+/// Add or remove the library from our metadata so other nodes know whether we participate in it.
+/// Returns `true` if this changed whether the library is enabled.
+fn advertise(
+	p2p: &P2P,
+	disabled: &DisabledLibraries,
+	library_id: Uuid,
+	identity: RemoteIdentity,
+	enabled: bool,
+) -> bool {
+	let changed = disabled.set(library_id, enabled);
+	match enabled {
+		true => {
+			p2p.metadata_mut()
+				.insert(library_id.to_string(), identity.to_string());
+		}
+		false => {
+			p2p.metadata_mut().remove(&library_id.to_string());
+		}
+	}
+	changed
+}
+
+async fn discover_instances(p2p: &Arc<P2P>, hook_id: HookId, library: &Library) {
+	let Ok(instances) = library.db.instance().find_many(vec![]).exec().await else {
+		return;
+	};
+
+	for i in instances.iter() {
+		let identity =
+			RemoteIdentity::from_bytes(&i.remote_identity).expect("lol: invalid DB entry");
+
+		// Skip self
+		if identity == library.identity.to_remote_identity() {
+			continue;
+		}
+
+		p2p.clone().discover_peer(
+			hook_id,
+			identity,
+			HashMap::new(), // TODO: We should probs cache this so we have something
+			[PeerConnectionCandidate::Relay].into_iter().collect(),
+		);
+	}
+}
+
+async fn undiscover_instances(p2p: &P2P, hook_id: HookId, library: &Library) {
+	let Ok(instances) = library.db.instance().find_many(vec![]).exec().await else {
+		return;
+	};
+
+	for i in instances.iter() {
+		let identity =
+			RemoteIdentity::from_bytes(&i.remote_identity).expect("lol: invalid DB entry");
+
+		let peers = p2p.peers();
+		let Some(peer) = peers.get(&identity) else {
+			continue;
+		};
+		peer.undiscover_peer(hook_id);
+	}
+}
+
+/// The succession a peer advertises, once it's valid and the peer is using the identity it rotated to.
+fn followed_succession(
+	identity: RemoteIdentity,
+	metadata: &HashMap<String, String>,
+) -> Option<IdentitySuccession> {
+	PeerMetadata::from_hashmap(metadata)
+		.or_else(|err| err.into_partial().ok_or(()))
+		.ok()?
+		.succession
+		.filter(|succession| succession.next() == identity)
+}
+
+/// Move the instances paired with the previous identity of a peer over to the one it rotated to.
+async fn follow_succession(libraries: &Libraries, succession: &IdentitySuccession) {
+	for library in libraries.get_all().await {
+		match library
+			.db
+			.instance()
+			.update_many(
+				vec![instance::remote_identity::equals(
+					succession.previous().get_bytes().to_vec(),
+				)],
+				vec![instance::remote_identity::set(
+					succession.next().get_bytes().to_vec(),
+				)],
+			)
+			.exec()
+			.await
+		{
+			Ok(0) => {}
+			Ok(count) => {
+				debug!(
+					"Moved {count} instances of library '{}' from RemoteIdentity('{}') to RemoteIdentity('{}')",
+					library.id,
+					succession.previous(),
+					succession.next()
+				);
+				libraries.update_instances(library).await;
+			}
+			Err(err) => error!(
+				"Failed to follow the identity succession of RemoteIdentity('{}') in library '{}': {err:?}",
+				succession.previous(),
+				library.id
+			),
+		}
+	}
+}
+
+/// Move our contact and alias for the previous identity of a peer over to the one it rotated to, so it's still trusted.
+async fn follow_succession_in_config(
+	node_config: &config::Manager,
+	succession: &IdentitySuccession,
+) {
+	let (previous, next) = (succession.previous(), succession.next());
+	let config = node_config.get().await;
+	if !config.contacts.contains(&previous) && !config.peer_aliases.contains_key(&previous) {
+		return;
+	}
+
+	let result = node_config
+		.write(|config| {
+			if config.contacts.remove(&previous) {
+				config.contacts.insert(next);
+			}
+			if let Some(alias) = config.peer_aliases.remove(&previous) {
+				config.peer_aliases.entry(next).or_insert(alias);
+			}
+		})
+		.await;
+	match result {
+		Ok(_) => debug!(
+			"Moved our contact with RemoteIdentity('{previous}') to RemoteIdentity('{next}')"
+		),
+		Err(err) => error!(
+			"Failed to follow the identity succession of RemoteIdentity('{previous}') in the node config: {err:?}"
+		),
+	}
+}
 
 /// A P2P hook which integrates P2P into Spacedrive's library system.
 ///
 /// This hooks is responsible for:
 ///  - injecting library peers into the P2P system so we can connect to them over internet.
+///  - keeping libraries with P2P disabled in their config out of our metadata.
+///  - moving instances and contacts over to the new identity of peers which rotated theirs.
 ///
-pub fn libraries_hook(p2p: Arc<P2P>, libraries: Arc<Libraries>) -> HookId {
+pub fn libraries_hook(
+	p2p: Arc<P2P>,
+	libraries: Arc<Libraries>,
+	disabled: Arc<DisabledLibraries>,
+	node_config: Arc<config::Manager>,
+) -> HookId {
 	let (tx, rx) = bounded(15);
 	let hook_id = p2p.register_hook("sd-libraries-hook", tx);
 
+	let libraries_rx = libraries.rx.clone();
 	let handle = tokio::spawn(async move {
-		if let Err(err) = libraries
-			.rx
-			.clone()
+		if let Err(err) = libraries_rx
 			.subscribe(|msg| {
 				let p2p = p2p.clone();
+				let disabled = disabled.clone();
 				async move {
 					match msg {
 						LibraryManagerEvent::InstancesModified(library)
 						| LibraryManagerEvent::Load(library) => {
-							p2p.metadata_mut().insert(
-								library.id.to_string(),
-								library.identity.to_remote_identity().to_string(),
+							let enabled = library.config().await.p2p_enabled;
+							advertise(
+								&p2p,
+								&disabled,
+								library.id,
+								library.identity.to_remote_identity(),
+								enabled,
 							);
 
-							let Ok(instances) =
-								library.db.instance().find_many(vec![]).exec().await
-							else {
-								return;
-							};
-
-							for i in instances.iter() {
-								let identity = RemoteIdentity::from_bytes(&i.remote_identity)
-									.expect("lol: invalid DB entry");
-
-								// Skip self
-								if identity == library.identity.to_remote_identity() {
-									continue;
-								}
-
-								p2p.clone().discover_peer(
-									hook_id,
-									identity,
-									HashMap::new(), // TODO: We should probs cache this so we have something
-									[PeerConnectionCandidate::Relay].into_iter().collect(),
-								);
+							if enabled {
+								discover_instances(&p2p, hook_id, &library).await;
 							}
 						}
-						LibraryManagerEvent::Edit(_library) => {
+						LibraryManagerEvent::Edit(library) => {
 							// TODO: Send changes to all connected nodes or queue sending for when they are online!
-						}
-						LibraryManagerEvent::Delete(library) => {
-							p2p.metadata_mut().remove(&library.id.to_string());
 
-							let Ok(instances) =
-								library.db.instance().find_many(vec![]).exec().await
-							else {
+							let enabled = library.config().await.p2p_enabled;
+							if !advertise(
+								&p2p,
+								&disabled,
+								library.id,
+								library.identity.to_remote_identity(),
+								enabled,
+							) {
 								return;
-							};
-
-							for i in instances.iter() {
-								let identity = RemoteIdentity::from_bytes(&i.remote_identity)
-									.expect("lol: invalid DB entry");
+							}
 
-								let peers = p2p.peers();
-								let Some(peer) = peers.get(&identity) else {
-									continue;
-								};
-								peer.undiscover_peer(hook_id);
+							debug!(
+								"P2P has been {} for library '{}'",
+								if enabled { "enabled" } else { "disabled" },
+								library.id
+							);
+							match enabled {
+								true => discover_instances(&p2p, hook_id, &library).await,
+								false => undiscover_instances(&p2p, hook_id, &library).await,
 							}
 						}
+						LibraryManagerEvent::Delete(library) => {
+							p2p.metadata_mut().remove(&library.id.to_string());
+							disabled.set(library.id, true);
+
+							undiscover_instances(&p2p, hook_id, &library).await;
+						}
 					}
 				}
 			})
@@ -91,6 +268,13 @@ pub fn libraries_hook(p2p: Arc<P2P>, libraries: Arc<Libraries>) -> HookId {
 					handle.abort();
 					break;
 				}
+				HookEvent::PeerAvailable(peer) | HookEvent::PeerDiscoveredBy(_, peer) => {
+					let succession = followed_succession(peer.identity(), &peer.metadata());
+					if let Some(succession) = succession {
+						follow_succession(&libraries, &succession).await;
+						follow_succession_in_config(&node_config, &succession).await;
+					}
+				}
 				_ => continue,
 			}
 		}
@@ -98,3 +282,174 @@ pub fn libraries_hook(p2p: Arc<P2P>, libraries: Arc<Libraries>) -> HookId {
 
 	hook_id
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::time::Duration;
+
+	use sd_p2p::{Identity, MemoryNetwork};
+	use tempfile::tempdir;
+	use tokio::time::{sleep, timeout};
+
+	use crate::{
+		node::config::SpacedropMode,
+		p2p::{operations, P2PEvent, P2PTransport},
+		Env, Node,
+	};
+
+	use super::*;
+
+	#[test]
+	fn disabled_libraries_are_not_advertised() {
+		let (tx, _rx) = bounded(1);
+		let p2p = P2P::new("sd-test", Identity::default(), tx);
+		let disabled = DisabledLibraries::default();
+		let identity = Identity::default().to_remote_identity();
+		let (library_id, other_library_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+		// Libraries start out enabled so loading them isn't a change
+		assert!(!advertise(&p2p, &disabled, library_id, identity, true));
+		assert!(!advertise(
+			&p2p,
+			&disabled,
+			other_library_id,
+			identity,
+			true
+		));
+		assert!(p2p.metadata().contains_key(&library_id.to_string()));
+
+		assert!(advertise(&p2p, &disabled, library_id, identity, false));
+		assert!(!advertise(&p2p, &disabled, library_id, identity, false));
+		assert!(disabled.contains(&library_id));
+		assert!(!p2p.metadata().contains_key(&library_id.to_string()));
+
+		// Other libraries are unaffected
+		assert!(!disabled.contains(&other_library_id));
+		assert_eq!(
+			p2p.metadata().get(&other_library_id.to_string()),
+			Some(&identity.to_string())
+		);
+
+		assert!(advertise(&p2p, &disabled, library_id, identity, true));
+		assert!(!disabled.contains(&library_id));
+		assert!(p2p.metadata().contains_key(&library_id.to_string()));
+	}
+
+	#[test]
+	fn successions_are_followed_once_in_use() {
+		let previous = Identity::default();
+		let next = Identity::default();
+
+		let mut metadata = HashMap::new();
+		PeerMetadata {
+			name: "peer".into(),
+			operating_system: None,
+			device_model: None,
+			version: None,
+			succession: Some(IdentitySuccession::new(&previous, &next)),
+			spacedrop_mode: None,
+		}
+		.update(&mut metadata);
+
+		// Still running under the previous identity, so there is nothing to connect to yet
+		assert!(followed_succession(previous.to_remote_identity(), &metadata).is_none());
+
+		let succession = followed_succession(next.to_remote_identity(), &metadata).unwrap();
+		assert_eq!(succession.previous(), previous.to_remote_identity());
+		assert_eq!(succession.next(), next.to_remote_identity());
+
+		// Another peer can't claim it
+		assert!(followed_succession(Identity::default().to_remote_identity(), &metadata).is_none());
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn paired_nodes_still_trust_a_rotated_identity() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+
+		// Paired, with Spacedrops only trusted from contacts
+		let previous = sender.p2p.p2p.remote_identity();
+		let receiver_identity = receiver.p2p.p2p.remote_identity();
+		sender
+			.config
+			.write(|config| {
+				config.contacts.insert(receiver_identity);
+			})
+			.await
+			.unwrap();
+		receiver
+			.config
+			.write(|config| {
+				config.contacts.insert(previous);
+				config.spacedrop_mode = SpacedropMode::Contacts;
+			})
+			.await
+			.unwrap();
+		receiver.p2p.on_node_config_change().await;
+
+		// The new identity is used once the node restarts
+		let next = sender.p2p.rotate_identity().await.unwrap();
+		sender.shutdown().await;
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		assert_eq!(sender.p2p.p2p.remote_identity(), next);
+
+		timeout(Duration::from_secs(5), async {
+			while !receiver.config.get().await.contacts.contains(&next) {
+				sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+		assert!(!receiver.config.get().await.contacts.contains(&previous));
+
+		// Still a contact, so the Spacedrop is put to the user instead of being rejected
+		let mut receiver_events = receiver.p2p.events.subscribe();
+		let sent = tempdir().unwrap();
+		let path = sent.path().join("IMG_0001.jpg");
+		tokio::fs::write(&path, b"Spacedrive").await.unwrap();
+		let id = operations::spacedrop(sender.p2p.clone(), receiver_identity, vec![path])
+			.await
+			.unwrap();
+		let from = timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropRequest {
+					id: requested,
+					identity,
+					..
+				} = receiver_events.recv().await.unwrap()
+				{
+					if requested == id {
+						break identity;
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(from, next);
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+}
@@ -1,21 +1,39 @@
 use crate::{
 	node::{
-		config::{self, P2PDiscoveryState, Port},
+		config::{self, IdentityRotation, NodeConfig, NodeConfigError, P2PDiscoveryState, Port},
 		get_hardware_model_name, HardwareModel,
 	},
 	p2p::{
-		libraries::libraries_hook, operations, sync::SyncMessage, Header, OperatingSystem,
-		SPACEDRIVE_APP_ID,
+		libraries::{libraries_hook, DisabledLibraries},
+		operations::{
+			self,
+			identify::IdentifyCache,
+			ping::{ClockSkews, KeepAlive},
+			request_file::{FileServeLimiter, RequestFileError},
+			spacedrop::{
+				peer_name, SpacedropAccept, SpacedropQueue, SpacedropTransfers, RECEIVER_BUSY,
+			},
+			thumbnail::{ThumbnailStats, RESPONSE_NOT_FOUND},
+		},
+		sync::{
+			NonParticipants, SharedTunnel, SyncFrame, SyncMessage, SyncProgress, SyncResponse,
+			SyncSessions, SyncTunnels,
+		},
+		BandwidthLimiter, ConnectionLog, ConnectionLogEntry, ConnectionLogEvent, Header, KnownPeer,
+		KnownPeers, OpId, OperatingSystem, P2PMetrics, P2POperation, SpacedropHistory,
+		StreamDirection, StreamLimiter, StreamSlot, SPACEDRIVE_APP_ID,
 	},
 	Node,
 };
 
 use axum::routing::IntoMakeService;
+use chrono::Utc;
 
 use sd_p2p::{
 	flume::{bounded, Receiver},
-	HookId, Libp2pPeerId, Listener, Mdns, Peer, QuicTransport, RelayServerEntry, RemoteIdentity,
-	UnicastStream, P2P,
+	ConnectionAddr, HookId, Identity, IdentitySuccession, Libp2pPeerId, Listener, Mdns,
+	MemoryNetwork, MemoryTransport, NewStreamError, Peer, ProbedAddr, QuicTransport,
+	RelayServerEntry, RemoteIdentity, UnicastStream, P2P,
 };
 use sd_p2p_tunnel::Tunnel;
 use serde::Serialize;
@@ -24,36 +42,147 @@ use specta::Type;
 use std::{
 	collections::{HashMap, HashSet},
 	convert::Infallible,
-	net::SocketAddr,
-	sync::{atomic::AtomicBool, Arc, Mutex, PoisonError},
-	time::Duration,
+	io,
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+	sync::{
+		atomic::{AtomicBool, AtomicU32, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+	time::{Duration, Instant},
 };
 use tower_service::Service;
 use tracing::error;
 
-use tokio::sync::oneshot;
-use tracing::info;
+use tokio::{
+	io::AsyncWriteExt,
+	sync::{oneshot, Notify},
+	time::timeout,
+};
+use tracing::{debug, info, warn, Instrument, Span};
 use uuid::Uuid;
 
-use super::{P2PEvents, PeerMetadata};
+use super::{
+	enforce,
+	events::{connection_via, remote_addr},
+	self_test::{check_listeners, check_mdns, check_ping, check_udp},
+	Error, P2PEvent, P2PEvents, P2PSelfTestReport, PeerMetadata, SelfTestStatus,
+};
+
+/// How many days the succession of a rotated identity is advertised for, nodes which don't see us within them treat us as a new node
+const IDENTITY_RETIREMENT_GRACE_DAYS: i64 = 30;
+
+/// How long each address of a peer gets to answer a reachability probe
+const REACHABILITY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How a [`P2PManager`] reaches other nodes
+#[derive(Debug, Clone)]
+pub enum P2PTransport {
+	/// QUIC over the network, finding the nodes on the LAN with mDNS
+	Quic,
+	/// Within this process, so tests can run more than one node without opening a socket
+	Memory(MemoryNetwork),
+}
+
+/// The transport a [`P2PManager`] was started with, see [`P2PTransport`]
+#[derive(Debug)]
+enum Transport {
+	Quic(QuicTransport),
+	Memory(MemoryTransport),
+}
+
+impl Transport {
+	/// The name its listener is registered with
+	fn name(&self) -> &'static str {
+		match self {
+			Self::Quic(_) => "libp2p-quic",
+			Self::Memory(_) => "memory",
+		}
+	}
+
+	async fn set_relay_config(&self, relays: Vec<RelayServerEntry>) {
+		match self {
+			Self::Quic(quic) => quic.set_relay_config(relays).await,
+			Self::Memory(memory) => memory.set_relay_config(relays).await,
+		}
+	}
+
+	fn get_relay_config(&self) -> Vec<RelayServerEntry> {
+		match self {
+			Self::Quic(quic) => quic.get_relay_config(),
+			Self::Memory(memory) => memory.get_relay_config(),
+		}
+	}
+
+	async fn set_ipv4_enabled(&self, port: Option<u16>) -> Result<(), String> {
+		match self {
+			Self::Quic(quic) => quic.set_ipv4_enabled(port).await,
+			Self::Memory(memory) => memory.set_ipv4_enabled(port).await,
+		}
+	}
+
+	async fn set_ipv6_enabled(&self, port: Option<u16>) -> Result<(), String> {
+		match self {
+			Self::Quic(quic) => quic.set_ipv6_enabled(port).await,
+			Self::Memory(memory) => memory.set_ipv6_enabled(port).await,
+		}
+	}
+
+	async fn probe(&self, identity: RemoteIdentity, addr: SocketAddr) -> Result<(), String> {
+		match self {
+			Self::Quic(quic) => quic.probe(identity, addr).await,
+			Self::Memory(memory) => memory.probe(identity, addr).await,
+		}
+	}
+}
 
 pub struct P2PManager {
 	pub(crate) p2p: Arc<P2P>,
 	mdns: Mutex<Option<Mdns>>,
-	quic: QuicTransport,
+	transport: Transport,
 	// The `libp2p::PeerId`. This is for debugging only, use `RemoteIdentity` instead.
 	lp2p_peer_id: Libp2pPeerId,
 	pub(crate) events: P2PEvents,
-	pub(super) spacedrop_pairing_reqs: Arc<Mutex<HashMap<Uuid, oneshot::Sender<Option<String>>>>>,
+	pub(super) spacedrop_pairing_reqs:
+		Arc<Mutex<HashMap<Uuid, oneshot::Sender<Option<SpacedropAccept>>>>>,
 	pub(super) spacedrop_cancellations: Arc<Mutex<HashMap<Uuid, Arc<AtomicBool>>>>,
+	pub(super) spacedrop_keep_alives: Arc<Mutex<HashMap<Uuid, Arc<Notify>>>>,
+	// The requests held for the next frontend, see `NodeConfig::queue_spacedrop_when_unattended`
+	pub(super) spacedrop_unattended: Arc<Mutex<HashMap<Uuid, P2PEvent>>>,
+	pub(super) spacedrop_queue: Arc<SpacedropQueue>,
+	pub(super) spacedrop_transfers: Arc<SpacedropTransfers>,
+	pub(crate) spacedrop_history: SpacedropHistory,
+	pub(crate) known_peers: Arc<KnownPeers>,
+	pub(crate) clock_skews: ClockSkews,
+	pub(super) identified: IdentifyCache,
+	pub(super) pairing_reqs: Arc<Mutex<HashMap<Uuid, oneshot::Sender<bool>>>>,
+	pub(super) sync_non_participants: Arc<NonParticipants>,
+	pub(super) sync_sessions: Arc<SyncSessions>,
+	pub(super) sync_tunnels: Arc<SyncTunnels<SharedTunnel>>,
+	pub(super) file_serve_limiter: Arc<FileServeLimiter>,
+	pub(super) bandwidth_limiter: Arc<BandwidthLimiter>,
+	pub(super) stream_limiter: Arc<StreamLimiter>,
+	stream_timeouts: Arc<StreamTimeouts>,
+	// The peers a reachability probe is running for, so the streams opened meanwhile don't start another
+	probing: Arc<Mutex<HashSet<RemoteIdentity>>>,
+	pub(super) thumbnail_stats: Arc<ThumbnailStats>,
+	pub(crate) metrics: Arc<P2PMetrics>,
+	pub(super) disabled_libraries: Arc<DisabledLibraries>,
+	// Whether `P2PEvent::Diagnostic`s are sent, follows the `p2p_diagnostics` node option
+	diagnostics: Arc<AtomicBool>,
+	// When P2P was started, peers which ask us to identify ourselves are told how long ago
+	pub(super) started_at: Instant,
+	// The report of the last self-test, it's part of `state` for bug reports
+	last_self_test: Mutex<Option<P2PSelfTestReport>>,
 	pub(crate) node_config: Arc<config::Manager>,
 	pub libraries_hook_id: HookId,
 }
 
 impl P2PManager {
-	pub async fn new(
+	/// Start the P2P system of the node, reaching other nodes through `transport`
+	pub async fn new_with_transport(
 		node_config: Arc<config::Manager>,
 		libraries: Arc<crate::library::Libraries>,
+		transport: P2PTransport,
 	) -> Result<
 		(
 			Arc<P2PManager>,
@@ -63,16 +192,58 @@ impl P2PManager {
 	> {
 		let (tx, rx) = bounded(25);
 		let p2p = P2P::new(SPACEDRIVE_APP_ID, node_config.get().await.identity, tx);
-		let (quic, lp2p_peer_id) = QuicTransport::spawn(p2p.clone())?;
-		let libraries_hook_id = libraries_hook(p2p.clone(), libraries);
+		let (transport, lp2p_peer_id) = match transport {
+			P2PTransport::Quic => {
+				let (quic, lp2p_peer_id) = QuicTransport::spawn(p2p.clone())?;
+				(Transport::Quic(quic), lp2p_peer_id)
+			}
+			P2PTransport::Memory(network) => {
+				let (memory, lp2p_peer_id) = MemoryTransport::spawn(p2p.clone(), &network);
+				(Transport::Memory(memory), lp2p_peer_id)
+			}
+		};
+		let disabled_libraries = Arc::new(DisabledLibraries::default());
+		let libraries_hook_id = libraries_hook(
+			p2p.clone(),
+			libraries,
+			disabled_libraries.clone(),
+			node_config.clone(),
+		);
+		let known_peers = Arc::new(KnownPeers::load(node_config.data_directory()).await);
+		let events = P2PEvents::spawn(p2p.clone(), libraries_hook_id, known_peers.clone());
+		let diagnostics = Arc::new(AtomicBool::new(false));
+		events.spawn_diagnostics(&p2p, diagnostics.clone());
 		let this = Arc::new(Self {
 			p2p: p2p.clone(),
 			lp2p_peer_id,
 			mdns: Mutex::new(None),
-			quic,
-			events: P2PEvents::spawn(p2p.clone(), libraries_hook_id),
+			transport,
+			events,
 			spacedrop_pairing_reqs: Default::default(),
 			spacedrop_cancellations: Default::default(),
+			spacedrop_keep_alives: Default::default(),
+			spacedrop_unattended: Default::default(),
+			spacedrop_queue: Default::default(),
+			spacedrop_transfers: Default::default(),
+			spacedrop_history: SpacedropHistory::load(node_config.data_directory()).await,
+			known_peers,
+			clock_skews: Default::default(),
+			identified: Default::default(),
+			pairing_reqs: Default::default(),
+			sync_non_participants: Default::default(),
+			sync_sessions: Default::default(),
+			sync_tunnels: Default::default(),
+			file_serve_limiter: Default::default(),
+			bandwidth_limiter: Default::default(),
+			stream_limiter: Default::default(),
+			stream_timeouts: Default::default(),
+			probing: Default::default(),
+			thumbnail_stats: Default::default(),
+			metrics: Default::default(),
+			disabled_libraries,
+			diagnostics,
+			started_at: Instant::now(),
+			last_self_test: Default::default(),
 			node_config,
 			libraries_hook_id,
 		});
@@ -88,6 +259,40 @@ impl P2PManager {
 		Ok((this.clone(), |node: Arc<Node>, router| {
 			tokio::spawn(start(this.clone(), node.clone(), rx, router));
 
+			tokio::spawn({
+				let this = Arc::downgrade(&this);
+				async move {
+					let mut keep_alive = KeepAlive::default();
+					loop {
+						let Some(this) = this.upgrade() else {
+							break;
+						};
+						keep_alive.configure(&this.node_config.get().await);
+						drop(this);
+
+						tokio::time::sleep(keep_alive.interval).await;
+
+						let Some(this) = this.upgrade() else {
+							break;
+						};
+						keep_alive
+							.probe(
+								&this.p2p,
+								&this.metrics,
+								&this.identified,
+								&this.clock_skews,
+								&this.events,
+							)
+							.await;
+					}
+				}
+			});
+
+			// Relays are only of use over the network
+			if let Transport::Memory(_) = this.transport {
+				return;
+			}
+
 			// TODO: Cleanup this thread on p2p shutdown.
 			tokio::spawn(async move {
 				let client = reqwest::Client::new();
@@ -107,7 +312,7 @@ impl P2PManager {
 							} else {
 								match resp.json::<Vec<RelayServerEntry>>().await {
 									Ok(config) => {
-										this.quic.set_relay_config(config).await;
+										this.transport.set_relay_config(config).await;
 										info!("Updated p2p relay configuration successfully.")
 									}
 									Err(err) => {
@@ -129,17 +334,74 @@ impl P2PManager {
 		self.p2p.metadata().clone()
 	}
 
+	/// The alias we gave `identity`, if any
+	pub async fn peer_alias(&self, identity: RemoteIdentity) -> Option<String> {
+		self.node_config
+			.get()
+			.await
+			.peer_aliases
+			.get(&identity)
+			.cloned()
+	}
+
+	/// See [`set_peer_alias`]
+	pub async fn set_peer_alias(
+		&self,
+		identity: RemoteIdentity,
+		alias: Option<String>,
+	) -> Result<(), NodeConfigError> {
+		set_peer_alias(&self.node_config, identity, alias).await
+	}
+
+	/// Replace the identity of this node with a new one, which the current one signs so paired nodes can follow it.
+	///
+	/// The new identity is used once P2P is restarted. Until the grace period is over its succession is advertised in our [`PeerMetadata`].
+	pub async fn rotate_identity(&self) -> Result<RemoteIdentity, NodeConfigError> {
+		let next = Identity::new();
+		let remote_identity = next.to_remote_identity();
+
+		self.node_config
+			.write(|config| {
+				config.identity_rotation = Some(IdentityRotation {
+					succession: IdentitySuccession::new(&config.identity, &next),
+					retire_at: Utc::now() + chrono::Duration::days(IDENTITY_RETIREMENT_GRACE_DAYS),
+				});
+				config.identity = next;
+			})
+			.await?;
+		self.on_node_config_change().await;
+
+		info!("Rotated node identity to RemoteIdentity('{remote_identity}'), it will be used once P2P restarts");
+
+		Ok(remote_identity)
+	}
+
 	// TODO: Remove this and add a subscription system to `config::Manager`
 	pub async fn on_node_config_change(&self) {
-		let config = self.node_config.get().await;
+		let mut config = self.node_config.get().await;
 
-		PeerMetadata {
-			name: config.name.clone(),
-			operating_system: Some(OperatingSystem::get_os()),
-			device_model: Some(get_hardware_model_name().unwrap_or(HardwareModel::Other)),
-			version: Some(env!("CARGO_PKG_VERSION").to_string()),
+		if config
+			.identity_rotation
+			.as_ref()
+			.is_some_and(|rotation| rotation.retire_at <= Utc::now())
+		{
+			debug!("Retiring the previous node identity, its grace period is over");
+			config.identity_rotation = None;
+			if let Err(err) = self.node_config.write(|c| c.identity_rotation = None).await {
+				error!("Failed to retire the previous node identity: {err}");
+			}
 		}
-		.update(&mut self.p2p.metadata_mut());
+
+		let advertised_before = advertised(&self.p2p);
+		let metadata = own_metadata(&config);
+		metadata.clone().update(&mut self.p2p.metadata_mut());
+
+		self.file_serve_limiter.configure(&config);
+		self.bandwidth_limiter.configure(&config);
+		self.stream_limiter.configure(&config);
+		self.stream_timeouts.configure(&config);
+		self.diagnostics
+			.store(config.p2p_diagnostics, Ordering::Relaxed);
 
 		let port = match config.p2p_ipv4_port {
 			Port::Disabled => None,
@@ -147,11 +409,15 @@ impl P2PManager {
 			Port::Discrete(port) => Some(port),
 		};
 		info!("Setting quic ipv4 listener to: {port:?}");
-		if let Err(err) = self.quic.set_ipv4_enabled(port).await {
+		if let Err(err) = self.transport.set_ipv4_enabled(port).await {
+			// We keep the configured port, instead of disabling the listener, so it's retried on the next config change
 			error!("Failed to enabled quic ipv4 listener: {err}");
-			self.node_config
-				.write(|c| c.p2p_ipv4_port = Port::Disabled)
-				.await
+			self.events
+				.send(P2PEvent::ListenerError {
+					v6: false,
+					port: port.unwrap_or_default(),
+					error: err.to_string(),
+				})
 				.ok();
 		}
 
@@ -160,36 +426,26 @@ impl P2PManager {
 			Port::Random => Some(0),
 			Port::Discrete(port) => Some(port),
 		};
-		info!("Setting quic ipv4 listener to: {port:?}");
-		if let Err(err) = self.quic.set_ipv6_enabled(port).await {
+		info!("Setting quic ipv6 listener to: {port:?}");
+		if let Err(err) = self.transport.set_ipv6_enabled(port).await {
 			error!("Failed to enabled quic ipv6 listener: {err}");
-			self.node_config
-				.write(|c| c.p2p_ipv6_port = Port::Disabled)
-				.await
+			self.events
+				.send(P2PEvent::ListenerError {
+					v6: true,
+					port: port.unwrap_or_default(),
+					error: err.to_string(),
+				})
+				.ok();
+		}
+
+		// Most settings, eg. the bandwidth limits, don't change what we advertise
+		if advertised(&self.p2p) != advertised_before {
+			self.events
+				.send(self_metadata_updated(&self.p2p, metadata))
 				.ok();
 		}
 
 		let should_revert = match config.p2p_discovery {
-			P2PDiscoveryState::Everyone
-			// TODO: Make `ContactsOnly` work
-			| P2PDiscoveryState::ContactsOnly => {
-				let mut mdns = self.mdns.lock().unwrap_or_else(PoisonError::into_inner);
-				if mdns.is_none() {
-					match Mdns::spawn(self.p2p.clone()) {
-						Ok(m) => {
-							info!("mDNS started successfully.");
-							*mdns = Some(m);
-							false
-						}
-						Err(err) => {
-							error!("Failed to start mDNS: {err}");
-							true
-						}
-					}
-				} else {
-					false
-				}
-			}
 			P2PDiscoveryState::Disabled => {
 				let mdns = {
 					let mut mdns = self.mdns.lock().unwrap_or_else(PoisonError::into_inner);
@@ -201,6 +457,17 @@ impl P2PManager {
 				}
 
 				false
+			}
+			// The nodes on a memory network already discover each other
+			_ if matches!(self.transport, Transport::Memory(_)) => false,
+			P2PDiscoveryState::Everyone
+			// TODO: Make `ContactsOnly` work
+			| P2PDiscoveryState::ContactsOnly => match self.start_mdns() {
+				Ok(()) => false,
+				Err(err) => {
+					error!("Failed to start mDNS: {err}");
+					true
+				}
 			},
 		};
 
@@ -215,7 +482,89 @@ impl P2PManager {
 		}
 	}
 
+	/// With [`P2PDiscoveryState::ContactsOnly`] only the nodes we paired with can reach us.
+	pub(crate) async fn is_reachable_by(&self, identity: RemoteIdentity) -> bool {
+		let config = self.node_config.get().await;
+		config.p2p_discovery != P2PDiscoveryState::ContactsOnly
+			|| config.contacts.contains(&identity)
+	}
+
+	/// Check if the QUIC listener could use `port` by binding to it and releasing it straight away.
+	/// A port we are already listening on is reported as [`PortStatus::InUse`].
+	pub fn test_port(port: u16, v6: bool) -> PortStatus {
+		let addr: SocketAddr = match v6 {
+			true => (Ipv6Addr::UNSPECIFIED, port).into(),
+			false => (Ipv4Addr::UNSPECIFIED, port).into(),
+		};
+
+		match UdpSocket::bind(addr) {
+			Ok(_) => PortStatus::Available,
+			Err(err) if err.kind() == io::ErrorKind::AddrInUse => PortStatus::InUse,
+			Err(err) => PortStatus::Unavailable {
+				error: err.to_string(),
+			},
+		}
+	}
+
+	/// Start advertising and browsing over mDNS, unless it's already running
+	pub(super) fn start_mdns(&self) -> Result<(), String> {
+		let mut mdns = self.mdns.lock().unwrap_or_else(PoisonError::into_inner);
+		if mdns.is_none() {
+			*mdns = Some(Mdns::spawn(self.p2p.clone()).map_err(|err| err.to_string())?);
+			info!("mDNS started successfully.");
+		}
+
+		Ok(())
+	}
+
+	/// Check, one after the other, that our listener is bound, that mDNS answers for us, that UDP gets through
+	/// and that a known peer answers a ping. The report is kept for [`Self::state`], so it ends up in bug reports.
+	pub async fn self_test(&self) -> P2PSelfTestReport {
+		let ran_at = Utc::now();
+		let config = self.node_config.get().await;
+		let memory = matches!(self.transport, Transport::Memory(_));
+		let mdns_running = self
+			.mdns
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.is_some();
+
+		let mut checks = vec![check_listeners(
+			&self.p2p.listeners(),
+			self.transport.name(),
+			!memory,
+		)];
+
+		// Always ask the network, the config only helps explaining why nobody answered
+		let mut mdns = check_mdns(&self.p2p).await;
+		if mdns.status == SelfTestStatus::Failed {
+			let why = match config.p2p_discovery {
+				P2PDiscoveryState::Disabled => Some("discovery is disabled in the settings"),
+				_ if memory && !mdns_running => Some("mDNS isn't started for a memory network"),
+				_ if !mdns_running => Some("mDNS isn't running, it failed to start"),
+				_ => None,
+			};
+			if let Some(why) = why {
+				mdns.detail = format!("{}, {why}", mdns.detail);
+			}
+		}
+		checks.push(mdns);
+		checks.push(check_udp().await);
+		checks.push(check_ping(&self.p2p, &self.metrics, &self.identified).await);
+
+		let report = P2PSelfTestReport { ran_at, checks };
+		*self
+			.last_self_test
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner) = Some(report.clone());
+		report
+	}
+
 	pub fn get_library_instances(&self, library: &Uuid) -> Vec<(RemoteIdentity, Arc<Peer>)> {
+		if self.disabled_libraries.contains(library) {
+			return vec![];
+		}
+
 		let library_id = library.to_string();
 		self.p2p
 			.peers()
@@ -226,6 +575,10 @@ impl P2PManager {
 	}
 
 	pub fn get_instance(&self, library: &Uuid, identity: RemoteIdentity) -> Option<Arc<Peer>> {
+		if self.disabled_libraries.contains(library) {
+			return None;
+		}
+
 		let library_id = library.to_string();
 		self.p2p
 			.peers()
@@ -244,11 +597,22 @@ impl P2PManager {
 			"peers": self.p2p.peers().iter().map(|(identity, p)| json!({
 				"identity": identity.to_string(),
 				"metadata": p.metadata().clone(),
+				"display_name": display_name(&node_config, &self.p2p, *identity),
 				"can_connect": p.can_connect(),
 				"is_connected": p.is_connected(),
 				"active_connections": p.active_connections(),
+				"connections": p.connections().into_iter().map(|(listener_id, addr)| json!({
+					"via": connection_via(
+						listeners.iter().find(|l| l.id == listener_id).map(|l| l.name).unwrap_or("unknown"),
+						addr,
+					),
+					"remote_addr": remote_addr(addr),
+				})).collect::<Vec<_>>(),
 				"connection_methods": p.connection_methods().iter().map(|id| format!("{:?}", id)).collect::<Vec<_>>(),
 				"discovered_by": p.discovered_by().iter().map(|id| format!("{:?}", id)).collect::<Vec<_>>(),
+				"candidate_addrs": p.candidate_addrs(),
+				"fastest_addr": p.fastest_addr(),
+				"clock_skew": self.clock_skews.get(*identity),
 			})).collect::<Vec<_>>(),
 			"hooks": self.p2p.hooks().iter().map(|(id, name)| json!({
 				"id": format!("{:?}", id),
@@ -259,23 +623,282 @@ impl P2PManager {
 				"p2p_ipv4_port": node_config.p2p_ipv4_port,
 				"p2p_ipv6_port": node_config.p2p_ipv6_port,
 				"p2p_discovery": node_config.p2p_discovery,
+				"file_serve_concurrency": node_config.file_serve_concurrency,
+				"file_serve_bytes_per_sec": node_config.file_serve_bytes_per_sec,
+				"p2p_max_bytes_per_sec": node_config.p2p_max_bytes_per_sec,
+				"identity_rotation": node_config.identity_rotation,
+				"contacts": node_config.contacts,
+				"peer_aliases": node_config.peer_aliases,
 			}),
-			"relay_config": self.quic.get_relay_config(),
+			"event_subscribers": self.events.stats(),
+			"file_serving": json!({
+				"active": self.file_serve_limiter.active(),
+				"queued": self.file_serve_limiter.queued(),
+			}),
+			"stream_budgets": json!({
+				"budgets": node_config.p2p_stream_budgets.unwrap_or_default(),
+				"streams": P2POperation::ALL.into_iter().map(|operation| json!({
+					"operation": operation,
+					"inbound_active": self.stream_limiter.active(operation, StreamDirection::Inbound),
+					"inbound_queued": self.stream_limiter.queued(operation, StreamDirection::Inbound),
+					"outbound_active": self.stream_limiter.active(operation, StreamDirection::Outbound),
+					"outbound_queued": self.stream_limiter.queued(operation, StreamDirection::Outbound),
+				})).collect::<Vec<_>>(),
+			}),
+			"metrics": self.metrics.snapshot(),
+			"thumbnails": json!({
+				"hits": self.thumbnail_stats.hits(),
+				"misses": self.thumbnail_stats.misses(),
+			}),
+			"relay_config": self.transport.get_relay_config(),
+			"connection_log": self.connection_log(None),
+			"last_self_test": self.last_self_test.lock().unwrap_or_else(PoisonError::into_inner).clone(),
 		})
 	}
 
+	/// The peers which are online along with the ones seen before, which are offline, see [`KnownPeers`]
+	pub async fn known_peers(&self) -> Vec<KnownPeer> {
+		let live = self
+			.p2p
+			.peers()
+			.values()
+			.filter_map(|peer| {
+				PeerMetadata::from_hashmap(&peer.metadata())
+					.or_else(|err| err.into_partial().ok_or(()))
+					.ok()
+					.map(|metadata| (peer.identity(), metadata, peer.candidate_addrs()))
+			})
+			.collect::<Vec<_>>();
+
+		self.known_peers.merged(live).await
+	}
+
+	/// The most recent connection events, optionally only the ones with `identity`
+	pub fn connection_log(&self, identity: Option<RemoteIdentity>) -> Vec<ConnectionLogEntry> {
+		self.events.connection_log().snapshot(identity)
+	}
+
+	pub(crate) fn log(&self) -> &ConnectionLog {
+		self.events.connection_log()
+	}
+
+	/// Race connection attempts to every address `peer` was discovered at, see [`Peer::probe_reachability`]
+	pub(crate) async fn probe_reachability(&self, peer: &Peer) -> Option<ProbedAddr> {
+		let identity = peer.identity();
+		let fastest = peer
+			.probe_reachability(REACHABILITY_PROBE_TIMEOUT, |addr| {
+				self.transport.probe(identity, addr)
+			})
+			.await;
+		debug!("Reachability probe of '{identity}' found {fastest:?}");
+
+		fastest
+	}
+
+	/// Probe the addresses of `peer` in the background, unless a probe of it is running already
+	fn probe_in_background(self: &Arc<Self>, peer: &Arc<Peer>) {
+		let identity = peer.identity();
+		if !self
+			.probing
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(identity)
+		{
+			return;
+		}
+
+		let this = self.clone();
+		let peer = peer.clone();
+		tokio::spawn(async move {
+			this.probe_reachability(&peer).await;
+			this.probing
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.remove(&identity);
+		});
+	}
+
+	/// Open a stream to `peer` for `operation`, trying its LAN addresses first and the one which answered
+	/// a reachability probe before everything else, instead of whichever path the transport picks.
+	///
+	/// The first stream to a peer with more than one address doesn't wait for the probe, it starts it for the next ones.
+	/// When the peer has more than one address, the one the stream goes through is kept in the connection log.
+	/// Waits for a slot in the budget of `operation` first, which the stream keeps until it's dropped.
+	pub(crate) async fn new_stream(
+		self: &Arc<Self>,
+		peer: &Arc<Peer>,
+		operation: P2POperation,
+	) -> Result<UnicastStream, NewStreamError> {
+		let slot = self.stream_slot(operation, StreamDirection::Outbound).await;
+		let stream = self.open_stream(peer, operation).await?;
+
+		Ok(slot.hold(stream))
+	}
+
+	/// Like [`Self::new_stream`], for a stream which stays open for as long as the peer is connected, eg. a multiplexed sync tunnel.
+	/// It doesn't count towards the budget of `operation`, it would hold one of its slots forever.
+	pub(crate) async fn new_shared_stream(
+		self: &Arc<Self>,
+		peer: &Arc<Peer>,
+		operation: P2POperation,
+	) -> Result<UnicastStream, NewStreamError> {
+		self.open_stream(peer, operation).await
+	}
+
+	async fn open_stream(
+		self: &Arc<Self>,
+		peer: &Arc<Peer>,
+		operation: P2POperation,
+	) -> Result<UnicastStream, NewStreamError> {
+		let preference = peer.candidate_addrs();
+		if preference.len() > 1 && peer.fastest_addr().is_none() {
+			self.probe_in_background(peer);
+		}
+
+		let stream = peer.new_stream_preferring(preference.clone()).await?;
+
+		if preference.len() > 1 {
+			let addr = stream.remote_addr();
+			self.log().record(
+				peer.identity(),
+				ConnectionLogEvent::AddressChosen,
+				addr.map(|addr| connection_via(self.transport.name(), Some(addr))),
+				Some(match addr {
+					Some(ConnectionAddr::Direct(addr)) => format!("{operation:?} through {addr}"),
+					Some(ConnectionAddr::Relay) => format!("{operation:?} through a relay"),
+					None => format!("{operation:?} through an unknown address"),
+				}),
+			);
+		}
+
+		Ok(stream)
+	}
+
+	/// Wait for one of the streams `operation` is allowed, see [`super::StreamBudgets`]
+	async fn stream_slot(&self, operation: P2POperation, direction: StreamDirection) -> StreamSlot {
+		let (slot, waited) = self.stream_limiter.acquire(operation, direction).await;
+		if let Some(waited) = waited {
+			debug!("{operation:?} stream waited {waited:?} for its budget");
+			self.metrics.queued(operation, waited);
+		}
+
+		slot
+	}
+
+	/// Count a stream for `operation` which couldn't be opened to `identity`, keeping why in the connection log
+	pub(crate) fn connect_failed(
+		&self,
+		identity: RemoteIdentity,
+		operation: P2POperation,
+		err: impl std::fmt::Display,
+	) {
+		self.metrics.failed(operation);
+		self.log().record(
+			identity,
+			ConnectionLogEvent::ConnectFailed,
+			None,
+			Some(format!("{operation:?}: {err}")),
+		);
+	}
+
 	pub async fn shutdown(&self) {
-		// `self.p2p` will automatically take care of shutting down all the hooks. Eg. `self.quic`, `self.mdns`, etc.
+		// `self.p2p` will automatically take care of shutting down all the hooks. Eg. `self.transport`, `self.mdns`, etc.
 		self.p2p.shutdown().await;
 	}
 }
 
+/// Respond to `msg` for the library, rejecting it with a [`SyncResponse`] if we can't.
+/// Only fails when the tunnel can't be used anymore, a rejection leaves it ready for the next message.
+///
+/// `legacy` is for originators from before [`PROTOCOL_VERSION`](operations::identify::PROTOCOL_VERSION), see [`super::sync::responder`].
+#[allow(clippy::too_many_arguments)]
+async fn respond_to_sync(
+	this: &Arc<P2PManager>,
+	node: &Arc<Node>,
+	tunnel: &mut Tunnel,
+	remote: RemoteIdentity,
+	library_id: Uuid,
+	msg: SyncMessage,
+	op_id: OpId,
+	legacy: bool,
+) -> Result<(), Error> {
+	// A library with P2P disabled is treated as missing so we don't reveal that we have it
+	let library = match this.disabled_libraries.contains(&library_id) {
+		true => None,
+		false => node.libraries.get_library(&library_id).await,
+	};
+	let Some(library) = library else {
+		warn!("Rejecting sync for unknown or P2P disabled library '{library_id}'");
+		return super::sync::reject_sync(tunnel, SyncResponse::LibraryNotFound, legacy).await;
+	};
+
+	// Held until we are done responding, including on errors
+	let Some(_session) = this.sync_sessions.try_start(remote, library_id) else {
+		debug!("Rejecting sync as '{remote}' already has a session for library '{library_id}'");
+		return super::sync::reject_sync(tunnel, SyncResponse::Busy, legacy).await;
+	};
+
+	let mut progress = SyncProgress::new(this.events.sender(), library_id, remote, op_id);
+	let result = match msg {
+		SyncMessage::NewOperations => {
+			super::sync::responder(tunnel, library, legacy, &mut progress).await
+		}
+		SyncMessage::RequestOperationsSince { timestamp } => {
+			super::sync::send_operations_since(tunnel, library, timestamp, &mut progress).await
+		}
+		SyncMessage::Multiplexed => Err(Error::Protocol(
+			"sync sessions can't be multiplexed within one another".into(),
+		)),
+	};
+	if result.is_err() {
+		this.metrics.failed(P2POperation::Sync);
+	}
+
+	result
+}
+
+/// Answer a stream which is over the budget of its operation the way the operation says it's busy,
+/// instead of leaving it unread until one of the operation's streams is closed
+async fn reject_busy(this: &P2PManager, header: &Header, legacy: bool, mut stream: UnicastStream) {
+	let remote = stream.remote_identity();
+	let response = match header {
+		// Nodes from before the busy replies see them as the request being declined
+		Header::Spacedrop(_) if legacy => vec![0],
+		Header::Spacedrop(_) => vec![RECEIVER_BUSY],
+		Header::File(_) | Header::FileBatch(_) if legacy => {
+			RequestFileError::FileNotFound.to_bytes()
+		}
+		Header::File(_) | Header::FileBatch(_) => RequestFileError::Busy.to_bytes(),
+		Header::Thumbnail(_) => vec![RESPONSE_NOT_FOUND],
+		// Pings are just closed, which their senders already handle. Sync streams are answered once their tunnel is up.
+		Header::Ping | Header::Sync(_) | Header::Http | Header::Pair(_) | Header::Identify(_) => {
+			vec![]
+		}
+	};
+
+	this.log().record(
+		remote,
+		ConnectionLogEvent::Throttled,
+		None,
+		Some("stream is over the budget of its operation, answered busy".into()),
+	);
+	if response.is_empty() {
+		return;
+	}
+
+	if let Err(err) = stream.write_all(&response).await {
+		debug!("Failed to tell '{remote}' we are busy: {err}");
+		return;
+	}
+	stream.flush().await.ok();
+}
+
 async fn start(
 	this: Arc<P2PManager>,
 	node: Arc<Node>,
 	rx: Receiver<UnicastStream>,
 	mut service: IntoMakeService<axum::Router<()>>,
-) -> Result<(), ()> {
+) -> Result<(), Error> {
 	while let Ok(mut stream) = rx.recv_async().await {
 		let this = this.clone();
 		let node = node.clone();
@@ -284,73 +907,272 @@ async fn start(
 		tokio::spawn(async move {
 			println!("APPLICATION GOT STREAM: {:?}", stream); // TODO
 
-			let Ok(header) = Header::from_stream(&mut stream).await.map_err(|err| {
-				error!("Failed to read header from stream: {}", err);
-			}) else {
-				return;
+			let (header, op_id) =
+				match read_header(&mut stream, &this.stream_timeouts, &this.metrics).await {
+					Ok(read) => read,
+					Err(err @ Error::TimedOut(_)) => {
+						debug!(
+							"Closing stream from '{}' which didn't send a header: {err}",
+							stream.remote_identity()
+						);
+						return;
+					}
+					Err(err) => {
+						error!("Failed to read header from stream: {err}");
+						return;
+					}
+				};
+
+			// Only peers which predate op ids don't send one, so that's what they understand too. We pick an id for them.
+			let legacy = op_id.is_none();
+			let op_id = op_id.unwrap_or_else(OpId::new);
+
+			// Everything after the header is counted towards the operation it's for
+			let operation = match &header {
+				Header::Ping => Some(P2POperation::Ping),
+				Header::Spacedrop(_) => Some(P2POperation::Spacedrop),
+				Header::Sync(_) => Some(P2POperation::Sync),
+				Header::File(_) | Header::FileBatch(_) | Header::Thumbnail(_) => {
+					Some(P2POperation::File)
+				}
+				Header::Http | Header::Pair(_) | Header::Identify(_) => None,
+			};
+			// Both sides handle the operation in a span with the id the peer picked, so their logs can be matched up
+			let span = match operation {
+				Some(operation) => op_id.span(stream.remote_identity(), operation),
+				None => Span::none(),
 			};
 
-			match header {
-				Header::Ping => operations::ping::receiver(stream).await,
-				Header::Spacedrop(req) => {
-					let Err(()) = operations::spacedrop::receiver(&this, req, stream).await else {
+			async move {
+				// Held until we are done with the stream
+				let mut _slot = None;
+				if let Some(operation) = operation {
+					let policy = this.node_config.get().await.p2p_allowed_operations;
+					let Some(allowed) =
+						enforce(&this.p2p, policy.as_ref(), &header, operation, stream).await
+					else {
 						return;
 					};
+					stream = this.metrics.instrument(operation, allowed);
 
-					error!("Failed to handle Spacedrop request");
+					// Sync streams take theirs once we know they aren't a multiplexed session, which stays open for as long as the peer is connected
+					if !matches!(operation, P2POperation::Sync) {
+						let Some(slot) = this
+							.stream_limiter
+							.try_take(operation, StreamDirection::Inbound)
+						else {
+							reject_busy(&this, &header, legacy, stream).await;
+							return;
+						};
+						_slot = Some(slot);
+					}
 				}
-				Header::Sync(library_id) => {
-					let Ok(mut tunnel) = Tunnel::responder(stream).await.map_err(|err| {
-						error!("Failed `Tunnel::responder`: {}", err);
-					}) else {
-						return;
-					};
 
-					let Ok(msg) = SyncMessage::from_stream(&mut tunnel).await.map_err(|err| {
-						error!("Failed `SyncMessage::from_stream`: {}", err);
-					}) else {
-						return;
-					};
+				// We only limit what we send back for file requests, it's bulk data unlike the other operations
+				if let Header::File(_) | Header::FileBatch(_) = header {
+					stream = this.bandwidth_limiter.limit(stream);
+				}
 
-					let Ok(library) =
-						node.libraries
-							.get_library(&library_id)
-							.await
-							.ok_or_else(|| {
-								error!("Failed to get library '{library_id}'");
+				match header {
+					Header::Ping => {
+						let remote = stream.remote_identity();
+						if let Err(err) = operations::ping::receiver(stream).await {
+							debug!("Failed to answer ping from peer '{remote}': {err}");
+						}
+					}
+					Header::Spacedrop(req) => {
+						let remote = stream.remote_identity();
+						let id = req.id();
+						if let Err(err) =
+							operations::spacedrop::receiver(&this, req, op_id, legacy, stream).await
+						{
+							error!("({id}): failed to handle Spacedrop from '{remote}': {err}");
+							this.metrics.failed(P2POperation::Spacedrop);
+						}
+					}
+					Header::Sync(library_id) => {
+						let remote = stream.remote_identity();
+						let budget = this.stream_timeouts.tunnel();
+						let setup = timeout(budget, async {
+							let mut tunnel = Tunnel::responder(stream)
+								.await
+								.map_err(|err| Error::Tunnel(err.into()))?;
 
-								// TODO: Respond to remote client with warning!
-							})
-					else {
-						return;
-					};
+							let msg = SyncMessage::from_stream(&mut tunnel)
+								.await
+								.map_err(Error::protocol("invalid sync message"))?;
 
-					match msg {
-						SyncMessage::NewOperations => {
-							let Err(()) = super::sync::responder(&mut tunnel, library).await else {
+							Ok::<_, Error>((tunnel, msg))
+						})
+						.await
+						.unwrap_or_else(|_| Err(Error::TimedOut(budget)));
+						let (mut tunnel, msg) = match setup {
+							Ok(setup) => setup,
+							Err(err @ Error::TimedOut(_)) => {
+								debug!("Closing sync stream from '{remote}' which didn't set up its tunnel: {err}");
+								this.metrics.timed_out(P2POperation::Sync);
+								return;
+							}
+							Err(err) => {
+								error!("Failed to set up sync tunnel with '{remote}': {err}");
+								this.metrics.failed(P2POperation::Sync);
+								return;
+							}
+						};
+
+						let SyncMessage::Multiplexed = msg else {
+							let Some(_slot) = this
+								.stream_limiter
+								.try_take(P2POperation::Sync, StreamDirection::Inbound)
+							else {
+								this.log().record(
+									remote,
+									ConnectionLogEvent::Throttled,
+									None,
+									Some("sync stream is over the budget, answered busy".into()),
+								);
+								if let Err(err) = super::sync::reject_sync(
+									&mut tunnel,
+									SyncResponse::Busy,
+									legacy,
+								)
+								.await
+								{
+									log_sync_failure(remote, &err);
+								}
 								return;
 							};
 
-							error!("Failed to handle sync responder request");
+							if let Err(err) = respond_to_sync(
+								&this,
+								&node,
+								&mut tunnel,
+								remote,
+								library_id,
+								msg,
+								op_id,
+								legacy,
+							)
+							.await
+							{
+								log_sync_failure(remote, &err);
+							}
+							return;
+						};
+
+						if let Err(err) = tunnel.write_all(&SyncResponse::Ok.to_bytes()).await {
+							error!("Failed to accept multiplexed sync session: {err}");
+							return;
 						}
-					};
-				}
-				Header::Http => {
-					let remote = stream.remote_identity();
-					let Err(err) = operations::rspc::receiver(stream, &mut service).await else {
-						return;
-					};
+						tunnel.flush().await.ok();
 
-					error!("Failed to handling rspc request with '{remote}': {err:?}");
-				}
-			};
+						// The libraries take turns until the originator is done with the tunnel
+						loop {
+							match SyncFrame::from_stream(&mut tunnel).await {
+								Ok(SyncFrame::Library {
+									library_id,
+									op_id,
+									message,
+								}) => {
+									if let Err(err) = respond_to_sync(
+										&this,
+										&node,
+										&mut tunnel,
+										remote,
+										library_id,
+										message,
+										op_id,
+										false,
+									)
+									.instrument(op_id.span(remote, P2POperation::Sync))
+									.await
+									{
+										log_sync_failure(remote, &err);
+										return;
+									}
+								}
+								Ok(SyncFrame::End) => return,
+								Err(err) => {
+									debug!(
+										"Ending multiplexed sync session with '{remote}': {err}"
+									);
+									return;
+								}
+							}
+						}
+					}
+					Header::File(req) => {
+						let remote = stream.remote_identity();
+						if let Err(err) =
+							operations::request_file::receiver(&node, &this, req, stream).await
+						{
+							error!("Failed to handle file request from '{remote}': {err}");
+							this.metrics.failed(P2POperation::File);
+						}
+					}
+					Header::FileBatch(req) => {
+						let remote = stream.remote_identity();
+						if let Err(err) =
+							operations::request_file::batch_receiver(&node, &this, req, stream)
+								.await
+						{
+							error!("Failed to handle file batch request from '{remote}': {err}");
+							this.metrics.failed(P2POperation::File);
+						}
+					}
+					Header::Thumbnail(req) => {
+						let remote = stream.remote_identity();
+						if let Err(err) =
+							operations::thumbnail::receiver(&node, &this, req, stream).await
+						{
+							error!("Failed to handle thumbnail request from '{remote}': {err}");
+							this.metrics.failed(P2POperation::File);
+						}
+					}
+					Header::Http => {
+						let remote = stream.remote_identity();
+						let Err(err) = operations::rspc::receiver(stream, &mut service).await
+						else {
+							return;
+						};
+
+						error!("Failed to handling rspc request with '{remote}': {err:?}");
+					}
+					Header::Pair(req) => operations::pair::receiver(&this, req, stream).await,
+					Header::Identify(req) => {
+						operations::identify::receiver(&this, &node, req, stream).await
+					}
+				};
+			}
+			.instrument(span)
+			.await;
 		});
 	}
 
-	Ok::<_, ()>(())
+	Ok(())
+}
+
+/// The originator going quiet happens every now and then, eg. when its network changes, so it isn't logged as an error
+fn log_sync_failure(remote: RemoteIdentity, err: &Error) {
+	match err {
+		Error::TimedOut(_) => {
+			warn!("Ending sync session as '{remote}' stopped responding: {err}")
+		}
+		_ => error!("Failed to respond to sync from '{remote}': {err}"),
+	}
+}
+
+/// If a port can be used for the P2P listener.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Type)]
+#[serde(tag = "type")]
+pub enum PortStatus {
+	Available,
+	InUse,
+	// Eg. a privileged port or the address family isn't supported
+	Unavailable { error: String },
 }
 
-#[derive(Debug, Serialize, Type)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Type)]
 pub struct Listener2 {
 	pub id: String,
 	pub name: &'static str,
@@ -367,9 +1189,340 @@ pub fn into_listener2(l: &[Listener]) -> Vec<Listener2> {
 		.collect()
 }
 
+/// What we advertise to other nodes about ourselves
+pub(crate) fn own_metadata(config: &NodeConfig) -> PeerMetadata {
+	PeerMetadata {
+		name: config.name.clone(),
+		operating_system: Some(OperatingSystem::get_os()),
+		device_model: Some(get_hardware_model_name().unwrap_or(HardwareModel::Other)),
+		version: Some(env!("CARGO_PKG_VERSION").to_string()),
+		succession: config
+			.identity_rotation
+			.as_ref()
+			.map(|rotation| rotation.succession.clone()),
+		spacedrop_mode: Some(config.spacedrop_mode),
+	}
+}
+
+/// What the frontend calls `identity`, the alias we gave it or else the name it advertises
+pub(crate) fn display_name(
+	config: &NodeConfig,
+	p2p: &P2P,
+	identity: RemoteIdentity,
+) -> Option<String> {
+	config
+		.peer_aliases
+		.get(&identity)
+		.cloned()
+		.or_else(|| peer_name(p2p, identity))
+}
+
+/// Give `identity` an alias, `None` or a blank one removes it so the peer shows with its advertised name again
+pub(crate) async fn set_peer_alias(
+	node_config: &config::Manager,
+	identity: RemoteIdentity,
+	alias: Option<String>,
+) -> Result<(), NodeConfigError> {
+	let alias = alias
+		.map(|alias| alias.trim().to_string())
+		.filter(|alias| !alias.is_empty());
+
+	node_config
+		.write(|config| match alias {
+			Some(alias) => {
+				config.peer_aliases.insert(identity, alias);
+			}
+			None => {
+				config.peer_aliases.remove(&identity);
+			}
+		})
+		.await
+		.map(|_| ())
+}
+
+/// What other nodes see of us, the metadata and where we listen
+fn advertised(p2p: &P2P) -> (HashMap<String, String>, Vec<Listener2>) {
+	(p2p.metadata().clone(), into_listener2(&p2p.listeners()))
+}
+
+/// Tells the frontend what we now advertise, with the listeners as they are once the config was applied
+fn self_metadata_updated(p2p: &P2P, metadata: PeerMetadata) -> P2PEvent {
+	P2PEvent::SelfMetadataUpdated {
+		metadata,
+		listeners: into_listener2(&p2p.listeners()),
+	}
+}
+
+/// Used when `p2p_header_timeout_secs` isn't set
+const DEFAULT_HEADER_TIMEOUT_SECS: u32 = 10;
+/// Used when `p2p_tunnel_timeout_secs` isn't set
+const DEFAULT_TUNNEL_TIMEOUT_SECS: u32 = 30;
+
+/// How long a peer has for each stage of setting up a stream it opened with us, so streams which went
+/// half-open (eg. behind a NAT or from a crashed client) don't hold on to their resources forever.
+#[derive(Debug)]
+struct StreamTimeouts {
+	header_secs: AtomicU32,
+	tunnel_secs: AtomicU32,
+}
+
+impl Default for StreamTimeouts {
+	fn default() -> Self {
+		Self {
+			header_secs: AtomicU32::new(DEFAULT_HEADER_TIMEOUT_SECS),
+			tunnel_secs: AtomicU32::new(DEFAULT_TUNNEL_TIMEOUT_SECS),
+		}
+	}
+}
+
+impl StreamTimeouts {
+	/// Apply the budgets from the node config, this takes effect for new streams.
+	fn configure(&self, config: &NodeConfig) {
+		self.header_secs.store(
+			config
+				.p2p_header_timeout_secs
+				.unwrap_or(DEFAULT_HEADER_TIMEOUT_SECS)
+				.max(1),
+			Ordering::Relaxed,
+		);
+		self.tunnel_secs.store(
+			config
+				.p2p_tunnel_timeout_secs
+				.unwrap_or(DEFAULT_TUNNEL_TIMEOUT_SECS)
+				.max(1),
+			Ordering::Relaxed,
+		);
+	}
+
+	fn header(&self) -> Duration {
+		Duration::from_secs(self.header_secs.load(Ordering::Relaxed).into())
+	}
+
+	fn tunnel(&self) -> Duration {
+		Duration::from_secs(self.tunnel_secs.load(Ordering::Relaxed).into())
+	}
+}
+
+/// Reads the header of a stream a peer opened, `None` when it's invalid or wasn't sent in time.
+/// The caller dropping the stream then closes it.
+///
+/// Peers from before operations had ids, [`PROTOCOL_VERSION`](operations::identify::PROTOCOL_VERSION), don't send one.
+async fn read_header(
+	stream: &mut UnicastStream,
+	timeouts: &StreamTimeouts,
+	metrics: &P2PMetrics,
+) -> Result<(Header, Option<OpId>), Error> {
+	let budget = timeouts.header();
+	match timeout(budget, Header::from_stream_with_op_id(stream)).await {
+		Ok(Ok(read)) => Ok(read),
+		Ok(Err(err)) => Err(err.into()),
+		Err(_) => {
+			metrics.header_timed_out();
+			Err(Error::TimedOut(budget))
+		}
+	}
+}
+
 fn unwrap_infallible<T>(result: Result<T, Infallible>) -> T {
 	match result {
 		Ok(value) => value,
 		Err(err) => match err {},
 	}
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use tokio::io::AsyncReadExt;
+
+	use super::*;
+
+	#[test]
+	fn test_port_detects_ports_in_use() {
+		let listener = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		assert_eq!(P2PManager::test_port(port, false), PortStatus::InUse);
+
+		drop(listener);
+		assert_eq!(P2PManager::test_port(port, false), PortStatus::Available);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn streams_over_the_budget_are_answered_busy() {
+		use crate::{
+			p2p::{operations::thumbnail, P2PTransport},
+			Env, Node,
+		};
+
+		let network = sd_p2p::MemoryNetwork::default();
+		let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+		let (node, _) = Node::new_with_p2p_transport(
+			dirs[0].path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (other, _) = Node::new_with_p2p_transport(
+			dirs[1].path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let remote = other.p2p.p2p.remote_identity();
+		let peer = timeout(Duration::from_secs(5), async {
+			loop {
+				if let Some(peer) = node.p2p.p2p.peers().get(&remote) {
+					break peer.clone();
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		// Every file stream the other node allows is in use
+		let held = std::iter::from_fn(|| {
+			other
+				.p2p
+				.stream_limiter
+				.try_take(P2POperation::File, StreamDirection::Inbound)
+		})
+		.collect::<Vec<_>>();
+		assert!(!held.is_empty());
+
+		// Answered straight away instead of waiting for one of them to be closed
+		let thumbnail = timeout(
+			Duration::from_secs(5),
+			thumbnail::request(
+				&peer,
+				Uuid::new_v4(),
+				"cas".into(),
+				thumbnail::ThumbSize::Standard,
+				&node.p2p.metrics,
+			),
+		)
+		.await
+		.unwrap()
+		.unwrap();
+		assert_eq!(thumbnail, None);
+		assert!(other
+			.p2p
+			.connection_log(Some(node.p2p.p2p.remote_identity()))
+			.iter()
+			.any(|entry| matches!(entry.event, ConnectionLogEvent::Throttled)));
+		assert_eq!(
+			other
+				.p2p
+				.stream_limiter
+				.queued(P2POperation::File, StreamDirection::Inbound),
+			0
+		);
+
+		drop(held);
+		node.shutdown().await;
+		other.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn renaming_the_node_is_sent_to_the_frontend() {
+		use crate::{p2p::P2PTransport, Env, Node};
+
+		let dir = tempfile::tempdir().unwrap();
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(sd_p2p::MemoryNetwork::default()),
+		)
+		.await
+		.unwrap();
+		let mut rx = node.p2p.events.subscribe();
+
+		// Nothing we advertise depends on diagnostics, so the frontend has nothing to refresh
+		node.config
+			.write(|c| c.p2p_diagnostics = true)
+			.await
+			.unwrap();
+		node.p2p.on_node_config_change().await;
+
+		node.config
+			.write(|c| c.name = "Renamed".to_string())
+			.await
+			.unwrap();
+		node.p2p.on_node_config_change().await;
+
+		let metadata = loop {
+			match timeout(Duration::from_secs(1), rx.recv())
+				.await
+				.unwrap()
+				.unwrap()
+			{
+				P2PEvent::SelfMetadataUpdated { metadata, .. } => break metadata,
+				_ => continue,
+			}
+		};
+		assert_eq!(metadata.name, "Renamed");
+		assert_eq!(
+			PeerMetadata::from_hashmap(&node.p2p.p2p.metadata())
+				.unwrap()
+				.name,
+			"Renamed"
+		);
+
+		node.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn silent_streams_are_closed_without_holding_up_others() {
+		let timeouts = Arc::new(StreamTimeouts::default());
+		timeouts.header_secs.store(1, Ordering::Relaxed);
+		let metrics = Arc::new(P2PMetrics::default());
+		let identity = Identity::new().to_remote_identity();
+
+		// Opened but never written to, like a half-open connection
+		let (silent, mut silent_remote) = tokio::io::duplex(64);
+		let silent = tokio::spawn({
+			let (timeouts, metrics) = (timeouts.clone(), metrics.clone());
+			async move {
+				let mut stream = UnicastStream::new(identity, silent);
+				read_header(&mut stream, &timeouts, &metrics).await
+			}
+		});
+
+		let (ping, mut ping_remote) = tokio::io::duplex(64);
+		tokio::spawn({
+			let (timeouts, metrics) = (timeouts.clone(), metrics.clone());
+			async move {
+				let mut stream = UnicastStream::new(identity, ping);
+				if let Ok((Header::Ping, _)) = read_header(&mut stream, &timeouts, &metrics).await {
+					operations::ping::receiver(stream).await.unwrap();
+				}
+			}
+		});
+		ping_remote
+			.write_all(&Header::Ping.to_bytes())
+			.await
+			.unwrap();
+		let mut pong = [0; 9];
+		timeout(
+			Duration::from_millis(500),
+			ping_remote.read_exact(&mut pong),
+		)
+		.await
+		.unwrap()
+		.unwrap();
+		assert!(!silent.is_finished());
+
+		let header = timeout(Duration::from_secs(2), silent)
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(matches!(header, Err(Error::TimedOut(_))), "{header:?}");
+		// The stream was dropped, so the peer sees it closed
+		let mut buf = [0; 1];
+		assert_eq!(silent_remote.read(&mut buf).await.unwrap(), 0);
+		assert_eq!(metrics.snapshot().header_timeouts, 1);
+	}
+}
@@ -1,16 +1,24 @@
-use crate::node::{HardwareModel, Platform};
+use crate::node::{config::SpacedropMode, HardwareModel, Platform};
+
+use sd_p2p::IdentitySuccession;
 
 use std::{collections::HashMap, env, fmt::Display, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use thiserror::Error;
 
-#[derive(Debug, Clone, Type, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Type, Serialize, Deserialize)]
 pub struct PeerMetadata {
 	pub name: String,
 	pub operating_system: Option<OperatingSystem>,
 	pub device_model: Option<HardwareModel>,
 	pub version: Option<String>,
+	/// Advertised for a while after the node rotated its identity, see [`IdentitySuccession`].
+	#[specta(type = Option<String>)]
+	pub succession: Option<IdentitySuccession>,
+	/// Who the peer takes Spacedrops from, older versions don't advertise it and take them from everyone
+	pub spacedrop_mode: Option<SpacedropMode>,
 }
 
 impl PeerMetadata {
@@ -25,34 +33,146 @@ impl PeerMetadata {
 		if let Some(device_model) = self.device_model {
 			map.insert("device_model".to_owned(), device_model.to_string());
 		}
+		// Unlike the other fields it goes away while we are running, once the previous identity is retired
+		match self.succession {
+			Some(succession) => {
+				map.insert("succession".to_owned(), succession.to_string());
+			}
+			None => {
+				map.remove("succession");
+			}
+		}
+		if let Some(spacedrop_mode) = self.spacedrop_mode {
+			map.insert("spacedrop".to_owned(), spacedrop_mode.to_string());
+		}
 	}
 
-	pub fn from_hashmap(data: &HashMap<String, String>) -> Result<Self, String> {
-		Ok(Self {
-			name: data
-				.get("name")
-				.ok_or_else(|| {
-					"DNS record for field 'name' missing. Unable to decode 'PeerMetadata'!"
-						.to_owned()
-				})?
-				.to_owned(),
-			operating_system: data
-				.get("os")
-				.map(|os| os.parse().map_err(|_| "Unable to parse 'OperationSystem'!"))
-				.transpose()?,
-			device_model: Some(HardwareModel::from_display_name(
-				data.get("device_model")
-					.map(|s| s.as_str())
-					.unwrap_or("Other"),
-			)),
-			version: data.get("version").map(|v| v.to_owned()),
-		})
+	/// Whether the peer says it takes our Spacedrops, it's only a hint as the metadata may be out of date
+	pub fn accepts_spacedrops(&self, is_contact: bool) -> bool {
+		self.spacedrop_mode.unwrap_or_default().allows(is_contact)
+	}
+
+	/// Decode the metadata a peer advertises.
+	/// Only `name` is required, unknown keys are ignored so newer clients can add fields without breaking older ones.
+	pub fn from_hashmap(data: &HashMap<String, String>) -> Result<Self, PeerMetadataError> {
+		let name = data
+			.get("name")
+			.filter(|name| !name.is_empty())
+			.ok_or(PeerMetadataError::MissingName)?
+			.to_owned();
+
+		let mut errors = Vec::new();
+		let operating_system = optional_field(data, "os", &mut errors).and_then(|os| {
+			os.parse()
+				.map_err(|()| {
+					errors.push(FieldError {
+						field: "os",
+						reason: format!("unknown operating system '{os}'"),
+					})
+				})
+				.ok()
+		});
+		let device_model = optional_field(data, "device_model", &mut errors).map(|model| {
+			let device_model = HardwareModel::from_display_name(model);
+			if device_model == HardwareModel::Other && !model.eq_ignore_ascii_case("other") {
+				// Still usable, it's probably a model this version doesn't know about
+				errors.push(FieldError {
+					field: "device_model",
+					reason: format!("unknown model '{model}'"),
+				});
+			}
+			device_model
+		});
+		let version = optional_field(data, "version", &mut errors).map(|v| v.to_owned());
+		let succession = optional_field(data, "succession", &mut errors).and_then(|succession| {
+			succession
+				.parse()
+				.map_err(|err: sd_p2p::IdentityErr| {
+					errors.push(FieldError {
+						field: "succession",
+						reason: err.to_string(),
+					})
+				})
+				.ok()
+		});
+		let spacedrop_mode = optional_field(data, "spacedrop", &mut errors).and_then(|mode| {
+			mode.parse()
+				.map_err(|_| {
+					errors.push(FieldError {
+						field: "spacedrop",
+						reason: format!("unknown mode '{mode}'"),
+					})
+				})
+				.ok()
+		});
+
+		let metadata = Self {
+			name,
+			operating_system,
+			device_model,
+			version,
+			succession,
+			spacedrop_mode,
+		};
+		match errors.is_empty() {
+			true => Ok(metadata),
+			false => Err(PeerMetadataError::InvalidFields {
+				partial: metadata,
+				errors,
+			}),
+		}
+	}
+}
+
+fn optional_field<'a>(
+	data: &'a HashMap<String, String>,
+	field: &'static str,
+	errors: &mut Vec<FieldError>,
+) -> Option<&'a String> {
+	match data.get(field) {
+		Some(value) if value.is_empty() => {
+			errors.push(FieldError {
+				field,
+				reason: "value is empty".into(),
+			});
+			None
+		}
+		value => value,
+	}
+}
+
+/// A field of [`PeerMetadata`] which couldn't be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+	pub field: &'static str,
+	pub reason: String,
+}
+
+#[derive(Debug, Error)]
+pub enum PeerMetadataError {
+	#[error("field 'name' is missing")]
+	MissingName,
+	/// The `partial` metadata has the fields which failed set to `None`, or the closest match, and is still usable.
+	#[error("invalid fields: {errors:?}")]
+	InvalidFields {
+		partial: PeerMetadata,
+		errors: Vec<FieldError>,
+	},
+}
+
+impl PeerMetadataError {
+	/// The metadata we could decode, if it's usable
+	pub fn into_partial(self) -> Option<PeerMetadata> {
+		match self {
+			Self::MissingName => None,
+			Self::InvalidFields { partial, .. } => Some(partial),
+		}
 	}
 }
 
 /// Represents the operating system which the remote peer is running.
 /// This is not used internally and predominantly is designed to be used for display purposes by the embedding application.
-#[derive(Debug, Clone, Type, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
 pub enum OperatingSystem {
 	Windows,
 	Linux,
@@ -123,3 +243,119 @@ impl FromStr for OperatingSystem {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use sd_p2p::Identity;
+
+	use base64::prelude::*;
+
+	use super::*;
+
+	fn map(entries: &[(&str, &str)]) -> HashMap<String, String> {
+		entries
+			.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn roundtrip() {
+		let metadata = PeerMetadata {
+			name: "Oscar's MacBook".into(),
+			operating_system: Some(OperatingSystem::MacOS),
+			device_model: Some(HardwareModel::MacBookPro),
+			version: Some("0.2.4".into()),
+			succession: Some(IdentitySuccession::new(
+				&Identity::default(),
+				&Identity::default(),
+			)),
+			spacedrop_mode: Some(SpacedropMode::Contacts),
+		};
+		let mut data = HashMap::new();
+		metadata.clone().update(&mut data);
+
+		assert_eq!(PeerMetadata::from_hashmap(&data).unwrap(), metadata);
+	}
+
+	#[test]
+	fn optional_fields_can_be_missing() {
+		let metadata = PeerMetadata::from_hashmap(&map(&[("name", "peer")])).unwrap();
+		assert_eq!(metadata.name, "peer");
+		assert!(metadata.operating_system.is_none());
+		assert!(metadata.device_model.is_none());
+		assert!(metadata.version.is_none());
+		assert!(metadata.accepts_spacedrops(false));
+	}
+
+	#[test]
+	fn unknown_keys_are_ignored() {
+		let metadata =
+			PeerMetadata::from_hashmap(&map(&[("name", "peer"), ("from_the_future", "yes")]))
+				.unwrap();
+		assert_eq!(metadata.name, "peer");
+	}
+
+	#[test]
+	fn invalid_optional_fields_are_partial() {
+		let Err(PeerMetadataError::InvalidFields { partial, errors }) =
+			PeerMetadata::from_hashmap(&map(&[
+				("name", "peer"),
+				("os", ""),
+				("device_model", "Toaster 9000"),
+				("version", "0.2.4"),
+			]))
+		else {
+			unreachable!();
+		};
+
+		assert_eq!(partial.name, "peer");
+		assert!(partial.operating_system.is_none());
+		assert_eq!(partial.device_model, Some(HardwareModel::Other));
+		assert_eq!(partial.version.as_deref(), Some("0.2.4"));
+		assert_eq!(
+			errors.iter().map(|e| e.field).collect::<Vec<_>>(),
+			vec!["os", "device_model"]
+		);
+	}
+
+	#[test]
+	fn name_is_required() {
+		for data in [map(&[("version", "0.2.4")]), map(&[("name", "")])] {
+			let err = PeerMetadata::from_hashmap(&data).unwrap_err();
+			assert!(matches!(err, PeerMetadataError::MissingName));
+			assert!(err.into_partial().is_none());
+		}
+	}
+
+	#[test]
+	fn forged_successions_are_dropped() {
+		let previous = Identity::default();
+		let next = Identity::default();
+
+		// Claims to succeed `previous` but is signed by someone else
+		let mut forged = IdentitySuccession::new(&Identity::default(), &next).to_bytes();
+		forged[..32].copy_from_slice(&previous.to_remote_identity().get_bytes());
+		let forged = BASE64_STANDARD_NO_PAD.encode(forged);
+
+		let Err(PeerMetadataError::InvalidFields { partial, errors }) =
+			PeerMetadata::from_hashmap(&map(&[("name", "peer"), ("succession", &forged)]))
+		else {
+			unreachable!();
+		};
+
+		assert!(partial.succession.is_none());
+		assert_eq!(
+			errors.iter().map(|e| e.field).collect::<Vec<_>>(),
+			vec!["succession"]
+		);
+
+		let succession = IdentitySuccession::new(&previous, &next);
+		let metadata = PeerMetadata::from_hashmap(&map(&[
+			("name", "peer"),
+			("succession", &succession.to_string()),
+		]))
+		.unwrap();
+		assert_eq!(metadata.succession, Some(succession));
+	}
+}
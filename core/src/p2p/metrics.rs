@@ -0,0 +1,316 @@
+use std::{
+	io,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use sd_p2p::UnicastStream;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use specta::Type;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The operations we keep [`P2PMetrics`] for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum P2POperation {
+	Ping,
+	Spacedrop,
+	Sync,
+	// Includes batches and thumbnails
+	File,
+}
+
+impl P2POperation {
+	pub(crate) const ALL: [Self; 4] = [Self::Ping, Self::Spacedrop, Self::Sync, Self::File];
+
+	pub(super) fn index(self) -> usize {
+		self as usize
+	}
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+	bytes_sent: AtomicU64,
+	bytes_received: AtomicU64,
+	streams: AtomicU64,
+	failures: AtomicU64,
+	timeouts: AtomicU64,
+	queued: AtomicU64,
+	queue_wait_ms: AtomicU64,
+}
+
+/// How much each P2P operation has sent and received, in both directions, since the metrics were last reset.
+#[derive(Debug)]
+pub struct P2PMetrics {
+	counters: [Counters; 4],
+	// Streams which were dropped before sending a header, so they aren't tied to an operation
+	header_timeouts: AtomicU64,
+	spacedrop_file_bytes: AtomicU64,
+	since: Mutex<DateTime<Utc>>,
+}
+
+impl Default for P2PMetrics {
+	fn default() -> Self {
+		Self {
+			counters: Default::default(),
+			header_timeouts: Default::default(),
+			spacedrop_file_bytes: Default::default(),
+			since: Mutex::new(Utc::now()),
+		}
+	}
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct OperationMetrics {
+	pub operation: P2POperation,
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub bytes_sent: u64,
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub bytes_received: u64,
+	pub streams: u32,
+	pub failures: u32,
+	// Streams which were dropped as the peer went silent while they were being set up
+	pub timeouts: u32,
+	/// Streams which had to wait for one of the operation's streams to be closed, see [`super::StreamBudgets`]
+	pub queued: u32,
+	/// How long they waited altogether
+	pub queue_wait_ms: u32,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct P2PMetricsSnapshot {
+	pub since: DateTime<Utc>,
+	pub operations: Vec<OperationMetrics>,
+	pub header_timeouts: u32,
+	/// The size of the files Spacedrops sent and received, compared with the bytes of the Spacedrop
+	/// operation it tells how much compression saved
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub spacedrop_file_bytes: u64,
+}
+
+impl P2PMetrics {
+	/// Count everything sent and received over `stream` towards `operation`.
+	pub(crate) fn instrument(
+		self: &Arc<Self>,
+		operation: P2POperation,
+		stream: UnicastStream,
+	) -> UnicastStream {
+		self.counters[operation.index()]
+			.streams
+			.fetch_add(1, Ordering::Relaxed);
+
+		let addr = stream.remote_addr();
+		UnicastStream::new(
+			stream.remote_identity(),
+			MeteredStream {
+				stream,
+				metrics: self.clone(),
+				operation,
+			},
+		)
+		.with_remote_addr(addr)
+	}
+
+	pub(crate) fn failed(&self, operation: P2POperation) {
+		self.counters[operation.index()]
+			.failures
+			.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn timed_out(&self, operation: P2POperation) {
+		self.counters[operation.index()]
+			.timeouts
+			.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count a stream for `operation` which waited `waited` for its budget
+	pub(crate) fn queued(&self, operation: P2POperation, waited: Duration) {
+		let counters = &self.counters[operation.index()];
+		counters.queued.fetch_add(1, Ordering::Relaxed);
+		counters.queue_wait_ms.fetch_add(
+			u64::try_from(waited.as_millis()).unwrap_or(u64::MAX),
+			Ordering::Relaxed,
+		);
+	}
+
+	pub(crate) fn header_timed_out(&self) {
+		self.header_timeouts.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count `bytes` of files sent or received by a Spacedrop, before they were compressed
+	pub(crate) fn spacedrop_files(&self, bytes: u64) {
+		self.spacedrop_file_bytes
+			.fetch_add(bytes, Ordering::Relaxed);
+	}
+
+	pub fn snapshot(&self) -> P2PMetricsSnapshot {
+		P2PMetricsSnapshot {
+			since: *self.since.lock().unwrap_or_else(PoisonError::into_inner),
+			operations: P2POperation::ALL
+				.into_iter()
+				.map(|operation| {
+					let counters = &self.counters[operation.index()];
+					OperationMetrics {
+						operation,
+						bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+						bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+						streams: u32::try_from(counters.streams.load(Ordering::Relaxed))
+							.unwrap_or(u32::MAX),
+						failures: u32::try_from(counters.failures.load(Ordering::Relaxed))
+							.unwrap_or(u32::MAX),
+						timeouts: u32::try_from(counters.timeouts.load(Ordering::Relaxed))
+							.unwrap_or(u32::MAX),
+						queued: u32::try_from(counters.queued.load(Ordering::Relaxed))
+							.unwrap_or(u32::MAX),
+						queue_wait_ms: u32::try_from(
+							counters.queue_wait_ms.load(Ordering::Relaxed),
+						)
+						.unwrap_or(u32::MAX),
+					}
+				})
+				.collect(),
+			header_timeouts: u32::try_from(self.header_timeouts.load(Ordering::Relaxed))
+				.unwrap_or(u32::MAX),
+			spacedrop_file_bytes: self.spacedrop_file_bytes.load(Ordering::Relaxed),
+		}
+	}
+
+	pub fn reset(&self) {
+		let mut since = self.since.lock().unwrap_or_else(PoisonError::into_inner);
+		for counters in &self.counters {
+			counters.bytes_sent.store(0, Ordering::Relaxed);
+			counters.bytes_received.store(0, Ordering::Relaxed);
+			counters.streams.store(0, Ordering::Relaxed);
+			counters.failures.store(0, Ordering::Relaxed);
+			counters.timeouts.store(0, Ordering::Relaxed);
+			counters.queued.store(0, Ordering::Relaxed);
+			counters.queue_wait_ms.store(0, Ordering::Relaxed);
+		}
+		self.header_timeouts.store(0, Ordering::Relaxed);
+		self.spacedrop_file_bytes.store(0, Ordering::Relaxed);
+		*since = Utc::now();
+	}
+}
+
+struct MeteredStream {
+	stream: UnicastStream,
+	metrics: Arc<P2PMetrics>,
+	operation: P2POperation,
+}
+
+impl AsyncRead for MeteredStream {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let before = buf.filled().len();
+		let result = Pin::new(&mut self.stream).poll_read(cx, buf);
+		if let Poll::Ready(Ok(())) = result {
+			self.metrics.counters[self.operation.index()]
+				.bytes_received
+				.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+		}
+		result
+	}
+}
+
+impl AsyncWrite for MeteredStream {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+		if let Poll::Ready(Ok(n)) = result {
+			self.metrics.counters[self.operation.index()]
+				.bytes_sent
+				.fetch_add(n as u64, Ordering::Relaxed);
+		}
+		result
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sd_p2p::Identity;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use crate::p2p::Header;
+
+	use super::*;
+
+	fn metrics_for(snapshot: &P2PMetricsSnapshot, operation: P2POperation) -> &OperationMetrics {
+		snapshot
+			.operations
+			.iter()
+			.find(|m| m.operation == operation)
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn counts_are_attributed_to_the_operation() {
+		let metrics = Arc::new(P2PMetrics::default());
+		let identity = Identity::default().to_remote_identity();
+
+		// A ping goes out...
+		let (ping, mut ping_remote) = tokio::io::duplex(64);
+		let mut ping = metrics.instrument(P2POperation::Ping, UnicastStream::new(identity, ping));
+		ping.write_all(&Header::Ping.to_bytes()).await.unwrap();
+
+		// ...and a small Spacedrop comes in
+		let (spacedrop, mut spacedrop_remote) = tokio::io::duplex(64);
+		let mut spacedrop = metrics.instrument(
+			P2POperation::Spacedrop,
+			UnicastStream::new(identity, spacedrop),
+		);
+		spacedrop_remote.write_all(b"hello world").await.unwrap();
+		let mut buf = [0; 11];
+		spacedrop.read_exact(&mut buf).await.unwrap();
+		metrics.failed(P2POperation::Spacedrop);
+
+		let mut buf = [0; 1];
+		ping_remote.read_exact(&mut buf).await.unwrap();
+
+		let snapshot = metrics.snapshot();
+		let ping = metrics_for(&snapshot, P2POperation::Ping);
+		assert_eq!((ping.bytes_sent, ping.bytes_received), (1, 0));
+		assert_eq!((ping.streams, ping.failures), (1, 0));
+
+		let spacedrop = metrics_for(&snapshot, P2POperation::Spacedrop);
+		assert_eq!((spacedrop.bytes_sent, spacedrop.bytes_received), (0, 11));
+		assert_eq!((spacedrop.streams, spacedrop.failures), (1, 1));
+
+		for operation in [P2POperation::Sync, P2POperation::File] {
+			let m = metrics_for(&snapshot, operation);
+			assert_eq!((m.bytes_sent, m.bytes_received, m.streams), (0, 0, 0));
+		}
+
+		metrics.reset();
+		let reset = metrics.snapshot();
+		assert!(reset.since >= snapshot.since);
+		assert!(reset
+			.operations
+			.iter()
+			.all(|m| m.bytes_sent == 0 && m.bytes_received == 0 && m.streams == 0));
+	}
+}
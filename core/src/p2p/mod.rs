@@ -1,17 +1,37 @@
 #![warn(clippy::all, clippy::unwrap_used, clippy::panic)]
 #![allow(clippy::unnecessary_cast)] // Yeah they aren't necessary on this arch, but they are on others
 
+mod bandwidth;
+mod connection_log;
+mod error;
 mod events;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+mod known_peers;
 pub(super) mod libraries;
 mod manager;
 mod metadata;
+mod metrics;
 pub mod operations;
+mod policy;
 mod protocol;
+mod self_test;
+mod spacedrop_history;
+mod stream_budgets;
 pub mod sync;
 
+pub use bandwidth::*;
+pub use connection_log::*;
+pub use error::*;
 pub use events::*;
+pub use known_peers::*;
 pub use manager::*;
 pub use metadata::*;
+pub use metrics::*;
+pub use policy::*;
 pub use protocol::*;
+pub use self_test::*;
+pub use spacedrop_history::*;
+pub use stream_budgets::*;
 
 pub(super) const SPACEDRIVE_APP_ID: &str = "sd";
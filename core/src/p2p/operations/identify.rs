@@ -0,0 +1,666 @@
+use std::{
+	collections::HashMap,
+	io,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+
+use crate::{
+	p2p::{own_metadata, Header, P2PManager, PeerMetadata},
+	Node,
+};
+
+use sd_p2p::{Identity, Peer, RemoteIdentity, UnicastStream, P2P};
+use sd_p2p_proto::{decode, encode};
+use sd_prisma::prisma::instance;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use thiserror::Error;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	time::timeout,
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Bumped whenever a change to the protocol means older nodes can't talk to us anymore
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The operations we answer, so peers know what they can ask of us without trying it
+const CAPABILITIES: &[&str] = &[
+	"ping",
+	"spacedrop",
+	"spacedrop-zstd",
+	"sync",
+	"sync-multiplexed",
+	"file",
+	"file-batch",
+	"file-by-path",
+	"thumbnail",
+	"http",
+	"pair",
+	"identify",
+];
+
+/// Prefixed to what's signed, so the signature of an answer can't be passed off as anything else
+const IDENTIFY_CONTEXT: &[u8] = b"sd-p2p-identify";
+
+/// How long the answer of a peer is reused for before it's asked again
+const IDENTIFY_TTL: Duration = Duration::from_secs(60);
+
+/// How long a peer has to answer
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most we read of an answer, it's only some metadata and library ids
+const MAX_PAYLOAD_LEN: u32 = 64 * 1024;
+
+/// An ed25519 signature, anything longer can't be one
+const MAX_SIGNATURE_LEN: usize = 64;
+
+/// Ask the remote node what it is, it answers with a signed [`IdentifyPayload`].
+///
+/// The nonce is signed with the answer, so an answer can't be replayed to us later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderIdentify {
+	pub nonce: Uuid,
+}
+
+impl HeaderIdentify {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
+		Ok(Self {
+			nonce: decode::uuid(stream).await?,
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		encode::uuid(&mut bytes, &self.nonce);
+		bytes
+	}
+}
+
+/// What a node says about itself when asked, unlike its mDNS metadata it's always up to date.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Type)]
+pub struct IdentifyPayload {
+	pub metadata: PeerMetadata,
+	/// The libraries the node shares with whoever asked, leaving out the ones it excluded from P2P
+	pub libraries: Vec<Uuid>,
+	pub protocol_version: u32,
+	pub capabilities: Vec<String>,
+	/// How long P2P has been running on the node
+	pub uptime_secs: u32,
+}
+
+#[derive(Debug, Error)]
+pub enum IdentifyError {
+	#[error("peer not found")]
+	PeerNotFound,
+	#[error("error connecting to peer: {0}")]
+	Connecting(String),
+	#[error("the remote node didn't answer in time")]
+	TimedOut,
+	#[error("the answer of the remote node is {0} bytes, more than we take")]
+	TooLarge(u32),
+	#[error("the answer isn't signed by the remote node")]
+	InvalidSignature,
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("error decoding: {0}")]
+	Decode(#[from] decode::Error),
+	#[error("error encoding the answer: {0}")]
+	Encode(#[from] rmp_serde::encode::Error),
+	#[error("error decoding the answer: {0}")]
+	Payload(#[from] rmp_serde::decode::Error),
+}
+
+/// What the responder signs, which binds the answer to the request and to who asked for it
+fn signed_message(requester: RemoteIdentity, nonce: &Uuid, payload: &[u8]) -> Vec<u8> {
+	let mut message = IDENTIFY_CONTEXT.to_vec();
+	message.extend_from_slice(&requester.get_bytes());
+	message.extend_from_slice(nonce.as_bytes());
+	message.extend_from_slice(payload);
+	message
+}
+
+/// Ask the node at the other end of `stream` to identify itself, only returning its answer if `responder` signed it.
+/// Nodes from before they could be asked close the stream, which is returned as an [`IdentifyError::Io`].
+pub(crate) async fn request(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	requester: RemoteIdentity,
+	responder: RemoteIdentity,
+) -> Result<IdentifyPayload, IdentifyError> {
+	let nonce = Uuid::new_v4();
+	stream
+		.write_all(&Header::Identify(HeaderIdentify { nonce }).to_bytes())
+		.await?;
+	stream.flush().await?;
+
+	let len = stream.read_u32_le().await?;
+	if len > MAX_PAYLOAD_LEN {
+		return Err(IdentifyError::TooLarge(len));
+	}
+	let mut payload = vec![0; len as usize];
+	stream.read_exact(&mut payload).await?;
+	let signature = decode::buf_max(stream, MAX_SIGNATURE_LEN).await?;
+
+	if !responder.verify(&signed_message(requester, &nonce, &payload), &signature) {
+		return Err(IdentifyError::InvalidSignature);
+	}
+
+	Ok(rmp_serde::from_slice(&payload)?)
+}
+
+/// Answer `requester` with `payload`, signed by our `identity`.
+pub(crate) async fn respond(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	identity: &Identity,
+	requester: RemoteIdentity,
+	header: &HeaderIdentify,
+	payload: &IdentifyPayload,
+) -> Result<(), IdentifyError> {
+	let payload = rmp_serde::to_vec_named(payload)?;
+
+	let mut bytes = Vec::new();
+	encode::buf(&mut bytes, &payload);
+	encode::buf(
+		&mut bytes,
+		&identity.sign(&signed_message(requester, &header.nonce, &payload)),
+	);
+	stream.write_all(&bytes).await?;
+	stream.flush().await?;
+
+	Ok(())
+}
+
+pub(crate) async fn receiver(
+	p2p: &Arc<P2PManager>,
+	node: &Arc<Node>,
+	header: HeaderIdentify,
+	mut stream: UnicastStream,
+) {
+	let requester = stream.remote_identity();
+	debug!("Received identify request from peer '{requester}'");
+
+	let payload = IdentifyPayload {
+		metadata: own_metadata(&p2p.node_config.get().await),
+		libraries: shared_libraries(p2p, node, requester).await,
+		protocol_version: PROTOCOL_VERSION,
+		capabilities: CAPABILITIES.iter().map(ToString::to_string).collect(),
+		uptime_secs: p2p
+			.started_at
+			.elapsed()
+			.as_secs()
+			.try_into()
+			.unwrap_or(u32::MAX),
+	};
+
+	if let Err(err) = respond(
+		&mut stream,
+		p2p.p2p.identity(),
+		requester,
+		&header,
+		&payload,
+	)
+	.await
+	{
+		debug!("Failed to answer identify request from peer '{requester}': {err}");
+	}
+}
+
+/// The libraries `requester` is an instance of, which the user didn't exclude from P2P
+async fn shared_libraries(p2p: &P2PManager, node: &Node, requester: RemoteIdentity) -> Vec<Uuid> {
+	let mut libraries = vec![];
+	for library in node.libraries.get_all().await {
+		if p2p.disabled_libraries.contains(&library.id) {
+			continue;
+		}
+
+		match library
+			.db
+			.instance()
+			.count(vec![instance::remote_identity::equals(
+				requester.get_bytes().to_vec(),
+			)])
+			.exec()
+			.await
+		{
+			Ok(0) => {}
+			Ok(_) => libraries.push(library.id),
+			Err(err) => warn!(
+				"Failed to check if peer '{requester}' is an instance of library '{}': {err:?}",
+				library.id
+			),
+		}
+	}
+	libraries
+}
+
+/// What a peer told us it supports, so we only send it what it understands. See [`P2PManager::peer_protocol`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerProtocol {
+	pub version: u32,
+	pub capabilities: Vec<String>,
+}
+
+impl PeerProtocol {
+	/// What nodes from before they could be asked to identify themselves understand
+	pub fn legacy() -> Self {
+		Self {
+			version: 0,
+			capabilities: vec![],
+		}
+	}
+
+	/// Whether the peer understands everything [`PROTOCOL_VERSION`] changed
+	pub fn is_current(&self) -> bool {
+		self.version >= PROTOCOL_VERSION
+	}
+
+	pub fn supports(&self, capability: &str) -> bool {
+		self.is_current() && self.capabilities.iter().any(|c| c == capability)
+	}
+}
+
+impl From<&IdentifyPayload> for PeerProtocol {
+	fn from(payload: &IdentifyPayload) -> Self {
+		Self {
+			version: payload.protocol_version,
+			capabilities: payload.capabilities.clone(),
+		}
+	}
+}
+
+/// The latest answer of every peer we asked to identify itself, see [`P2PManager::identify`].
+#[derive(Debug, Default)]
+pub struct IdentifyCache {
+	answers: Mutex<HashMap<RemoteIdentity, (Instant, IdentifyPayload)>>,
+	/// Peers which hung up on us when asked, they predate being asked
+	legacy: Mutex<HashMap<RemoteIdentity, Instant>>,
+}
+
+impl IdentifyCache {
+	/// The answer of the peer, unless it's older than the TTL
+	fn get(&self, identity: RemoteIdentity) -> Option<IdentifyPayload> {
+		self.answers
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(&identity)
+			.filter(|(answered_at, _)| answered_at.elapsed() < IDENTIFY_TTL)
+			.map(|(_, payload)| payload.clone())
+	}
+
+	fn insert(&self, identity: RemoteIdentity, payload: IdentifyPayload) {
+		let mut answers = self.answers.lock().unwrap_or_else(PoisonError::into_inner);
+		answers.retain(|_, (answered_at, _)| answered_at.elapsed() < IDENTIFY_TTL);
+		answers.insert(identity, (Instant::now(), payload));
+	}
+
+	/// What `identity` supports as far as we know without asking it, [`PeerProtocol::legacy`] if it never told us
+	pub(crate) fn known_protocol(&self, identity: RemoteIdentity) -> PeerProtocol {
+		self.get(identity)
+			.map_or_else(PeerProtocol::legacy, |payload| PeerProtocol::from(&payload))
+	}
+
+	fn is_legacy(&self, identity: RemoteIdentity) -> bool {
+		self.legacy
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(&identity)
+			.is_some_and(|asked_at| asked_at.elapsed() < IDENTIFY_TTL)
+	}
+
+	fn insert_legacy(&self, identity: RemoteIdentity) {
+		let mut legacy = self.legacy.lock().unwrap_or_else(PoisonError::into_inner);
+		legacy.retain(|_, asked_at| asked_at.elapsed() < IDENTIFY_TTL);
+		legacy.insert(identity, Instant::now());
+	}
+}
+
+/// Ask `identity` what it is, or reuse its answer if it's recent enough.
+/// The metadata it answers with replaces what we know of it from mDNS.
+async fn identify(
+	p2p: &P2P,
+	cache: &IdentifyCache,
+	identity: RemoteIdentity,
+) -> Result<IdentifyPayload, IdentifyError> {
+	if let Some(payload) = cache.get(identity) {
+		return Ok(payload);
+	}
+
+	let peer = p2p
+		.peers()
+		.get(&identity)
+		.cloned()
+		.ok_or(IdentifyError::PeerNotFound)?;
+
+	let mut stream = peer
+		.new_stream()
+		.await
+		.map_err(|err| IdentifyError::Connecting(err.to_string()))?;
+	let payload = timeout(
+		IDENTIFY_TIMEOUT,
+		request(&mut stream, p2p.remote_identity(), identity),
+	)
+	.await
+	.map_err(|_| IdentifyError::TimedOut)??;
+
+	if refresh_metadata(&peer, &payload.metadata) {
+		debug!("Refreshed the metadata of peer '{identity}' from its identify answer");
+	}
+	cache.insert(identity, payload.clone());
+
+	Ok(payload)
+}
+
+/// What `identity` supports, from its answer to [`identify`].
+/// Peers which can't be asked, for whatever reason, are sent what every node understands.
+async fn peer_protocol(p2p: &P2P, cache: &IdentifyCache, identity: RemoteIdentity) -> PeerProtocol {
+	if cache.is_legacy(identity) {
+		return PeerProtocol::legacy();
+	}
+
+	match identify(p2p, cache, identity).await {
+		Ok(payload) => PeerProtocol::from(&payload),
+		// Nodes from before identify close the stream on the header they don't know
+		Err(IdentifyError::Io(err)) => {
+			debug!("Peer '{identity}' can't identify itself, using the legacy protocol: {err}");
+			cache.insert_legacy(identity);
+			PeerProtocol::legacy()
+		}
+		Err(err) => {
+			debug!("Failed to identify peer '{identity}', using the legacy protocol: {err}");
+			PeerProtocol::legacy()
+		}
+	}
+}
+
+/// Replace what `peer` advertised with `metadata` if they differ, returning whether they did.
+/// The library ids it advertises are kept, they aren't part of [`PeerMetadata`].
+fn refresh_metadata(peer: &Peer, metadata: &PeerMetadata) -> bool {
+	if PeerMetadata::from_hashmap(&peer.metadata()).is_ok_and(|advertised| advertised == *metadata)
+	{
+		return false;
+	}
+
+	metadata.clone().update(&mut peer.metadata_mut());
+	true
+}
+
+impl P2PManager {
+	/// Ask a connected peer for its details, which it signs so they can't have been made up on the way.
+	///
+	/// Answers are reused for a minute, so this can be called whenever the details are shown.
+	pub async fn identify(
+		&self,
+		identity: RemoteIdentity,
+	) -> Result<IdentifyPayload, IdentifyError> {
+		identify(&self.p2p, &self.identified, identity).await
+	}
+
+	/// What `identity` supports, so changes to the protocol are only sent to peers which understand them.
+	///
+	/// Asks the peer to identify itself, unless it did so recently.
+	pub async fn peer_protocol(&self, identity: RemoteIdentity) -> PeerProtocol {
+		peer_protocol(&self.p2p, &self.identified, identity).await
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use crate::node::config::SpacedropMode;
+
+	use sd_p2p::{flume::bounded, MemoryNetwork, MemoryTransport};
+
+	use super::*;
+
+	fn payload(name: &str) -> IdentifyPayload {
+		IdentifyPayload {
+			metadata: PeerMetadata {
+				name: name.to_string(),
+				operating_system: None,
+				device_model: None,
+				version: Some("0.2.0".to_string()),
+				succession: None,
+				spacedrop_mode: Some(SpacedropMode::Contacts),
+			},
+			libraries: vec![Uuid::new_v4()],
+			protocol_version: PROTOCOL_VERSION,
+			capabilities: CAPABILITIES.iter().map(ToString::to_string).collect(),
+			uptime_secs: 42,
+		}
+	}
+
+	/// Put a node on `network` which answers identify requests with `payload`, signed by `signer`, and have it connect with `p2p`
+	async fn identified_node(
+		network: &MemoryNetwork,
+		p2p: &P2P,
+		payload: IdentifyPayload,
+		signer: Option<Identity>,
+	) -> RemoteIdentity {
+		let (handler_tx, handler_rx) = bounded(5);
+		let identity = Identity::new();
+		let signer = signer.unwrap_or_else(|| identity.clone());
+		let remote = P2P::new("test", identity, handler_tx);
+		MemoryTransport::spawn(remote.clone(), network);
+
+		tokio::spawn(async move {
+			while let Ok(mut stream) = handler_rx.recv_async().await {
+				let Ok(Header::Identify(header)) = Header::from_stream(&mut stream).await else {
+					continue;
+				};
+				let requester = stream.remote_identity();
+				respond(&mut stream, &signer, requester, &header, &payload)
+					.await
+					.unwrap();
+			}
+		});
+
+		// We're only connected with the node once it opened a stream to us
+		let us = remote.peers().get(&p2p.remote_identity()).cloned().unwrap();
+		us.new_stream().await.unwrap();
+
+		remote.remote_identity()
+	}
+
+	/// Put a node on `network` from before nodes could be asked to identify themselves, and have it connect with `p2p`
+	async fn legacy_node(network: &MemoryNetwork, p2p: &P2P) -> RemoteIdentity {
+		let (handler_tx, handler_rx) = bounded(5);
+		let remote = P2P::new("test", Identity::new(), handler_tx);
+		MemoryTransport::spawn(remote.clone(), network);
+
+		tokio::spawn(async move {
+			// Like the nodes of back then, streams with a discriminator it doesn't know are dropped
+			while let Ok(mut stream) = handler_rx.recv_async().await {
+				stream.read_u8().await.ok();
+			}
+		});
+
+		let us = remote.peers().get(&p2p.remote_identity()).cloned().unwrap();
+		us.new_stream().await.unwrap();
+
+		remote.remote_identity()
+	}
+
+	#[tokio::test]
+	async fn the_protocol_of_peers_is_negotiated() {
+		let network = MemoryNetwork::default();
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		MemoryTransport::spawn(p2p.clone(), &network);
+		let cache = IdentifyCache::default();
+
+		let current = identified_node(&network, &p2p, payload("Laptop"), None).await;
+		let protocol = peer_protocol(&p2p, &cache, current).await;
+		assert!(protocol.is_current());
+		assert!(protocol.supports("spacedrop-zstd"));
+		assert!(!protocol.supports("something-from-the-future"));
+
+		let legacy = legacy_node(&network, &p2p).await;
+		let protocol = peer_protocol(&p2p, &cache, legacy).await;
+		assert_eq!(protocol, PeerProtocol::legacy());
+		assert!(!protocol.supports("spacedrop-zstd"));
+		// It isn't asked again every time something is sent to it
+		assert!(cache.is_legacy(legacy));
+		assert!(!cache.is_legacy(current));
+
+		// A node which says it's from before the protocol changed doesn't get what it can't understand
+		let mut outdated = payload("Desktop");
+		outdated.protocol_version = 0;
+		let outdated = identified_node(&network, &p2p, outdated, None).await;
+		assert!(!peer_protocol(&p2p, &cache, outdated)
+			.await
+			.supports("spacedrop-zstd"));
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn nodes_answer_with_the_libraries_they_share() {
+		use crate::{library::LibraryName, p2p::P2PTransport, util::MaybeUndefined, Env};
+		use chrono::Utc;
+		use tempfile::tempdir;
+
+		let network = MemoryNetwork::default();
+		let (dir, other_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (other, _) = Node::new_with_p2p_transport(
+			other_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let identity = node.p2p.p2p.remote_identity();
+
+		// We're an instance of two of the other node's libraries, one of which it excluded from P2P
+		let mut libraries = vec![];
+		for name in ["Shared", "Excluded", "Not shared"] {
+			libraries.push(
+				other
+					.libraries
+					.create(LibraryName::new(name).unwrap(), None, &other)
+					.await
+					.unwrap(),
+			);
+		}
+		let [shared, excluded, _] = &libraries[..] else {
+			unreachable!();
+		};
+		for library in [shared, excluded] {
+			let now = Utc::now().fixed_offset();
+			library
+				.db
+				.instance()
+				.create(
+					Uuid::new_v4().as_bytes().to_vec(),
+					identity.get_bytes().to_vec(),
+					node.config.get().await.id.as_bytes().to_vec(),
+					now,
+					now,
+					vec![],
+				)
+				.exec()
+				.await
+				.unwrap();
+		}
+		other
+			.libraries
+			.edit(
+				excluded.id,
+				None,
+				MaybeUndefined::Undefined,
+				MaybeUndefined::Undefined,
+				None,
+				Some(false),
+			)
+			.await
+			.unwrap();
+
+		let remote = other.p2p.p2p.remote_identity();
+		let answer = tokio::time::timeout(Duration::from_secs(5), async {
+			loop {
+				// Until the other node knows about us and handled the library being excluded
+				if other.p2p.disabled_libraries.contains(&excluded.id) {
+					if let Ok(answer) = node.p2p.identify(remote).await {
+						break answer;
+					}
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		// Answered through the dispatch of the other node, like from another device
+		assert_eq!(answer.libraries, vec![shared.id]);
+		assert_eq!(answer.protocol_version, PROTOCOL_VERSION);
+		assert_eq!(answer.metadata, own_metadata(&other.config.get().await));
+		assert_eq!(
+			node.p2p.peer_protocol(remote).await,
+			PeerProtocol::from(&answer)
+		);
+
+		node.shutdown().await;
+		other.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn nodes_identify_themselves_consistently() {
+		let network = MemoryNetwork::default();
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		MemoryTransport::spawn(p2p.clone(), &network);
+
+		let expected = payload("Laptop");
+		let remote = identified_node(&network, &p2p, expected.clone(), None).await;
+		// What mDNS last saw, from before the node was renamed
+		let peer = p2p.peers().get(&remote).cloned().unwrap();
+		payload("Old Laptop")
+			.metadata
+			.update(&mut peer.metadata_mut());
+
+		let cache = IdentifyCache::default();
+		assert_eq!(identify(&p2p, &cache, remote).await.unwrap(), expected);
+		assert_eq!(
+			PeerMetadata::from_hashmap(&peer.metadata()).unwrap(),
+			expected.metadata
+		);
+
+		// Asking again gets the same answer, from the cache or the node
+		assert_eq!(identify(&p2p, &cache, remote).await.unwrap(), expected);
+		let mut stream = peer.new_stream().await.unwrap();
+		assert_eq!(
+			request(&mut stream, p2p.remote_identity(), remote)
+				.await
+				.unwrap(),
+			expected
+		);
+	}
+
+	#[tokio::test]
+	async fn answers_signed_by_another_key_are_rejected() {
+		let network = MemoryNetwork::default();
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		MemoryTransport::spawn(p2p.clone(), &network);
+
+		let remote =
+			identified_node(&network, &p2p, payload("Laptop"), Some(Identity::new())).await;
+		let peer = p2p.peers().get(&remote).cloned().unwrap();
+		let advertised = peer.metadata().clone();
+
+		let cache = IdentifyCache::default();
+		assert!(matches!(
+			identify(&p2p, &cache, remote).await,
+			Err(IdentifyError::InvalidSignature)
+		));
+		// Nothing it said was taken
+		assert!(cache.get(remote).is_none());
+		assert_eq!(*peer.metadata(), advertised);
+	}
+}
@@ -1,6 +1,15 @@
+pub mod identify;
+pub mod pair;
 pub mod ping;
+pub mod request_file;
 pub mod rspc;
 pub mod spacedrop;
+pub mod thumbnail;
 
+pub use identify::{IdentifyError, IdentifyPayload, PeerProtocol};
+pub use pair::pair;
+pub use request_file::{download_file, request_file, request_file_by_path, request_files};
 pub use rspc::remote_rspc;
-pub use spacedrop::spacedrop;
+pub use spacedrop::{
+	spacedrop, spacedrop_objects, spacedrop_text, SpacedropError, SPACEDROP_MIN_TIMEOUT,
+};
@@ -0,0 +1,397 @@
+use std::{
+	io,
+	sync::{Arc, PoisonError},
+	time::Duration,
+};
+
+use crate::{
+	node::config::{self, NodeConfigError},
+	p2p::{Header, P2PEvent, P2PManager},
+};
+use sd_p2p::{RemoteIdentity, UnicastStream};
+use sd_p2p_proto::{decode, encode};
+use thiserror::Error;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	sync::oneshot,
+	time::timeout,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// How long each user has to confirm the pairing code before the pairing is aborted
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+const CONFIRMED: u8 = 1;
+const REJECTED: u8 = 0;
+
+/// Start pairing with a remote node.
+///
+/// The initiator only commits to its nonce here and reveals it once it has the responder's,
+/// so neither side can pick a nonce which makes the codes match after seeing the other one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderPair {
+	pub id: Uuid,
+	pub commitment: [u8; blake3::OUT_LEN],
+}
+
+impl HeaderPair {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
+		let id = decode::uuid(stream).await?;
+		let mut commitment = [0; blake3::OUT_LEN];
+		stream.read_exact(&mut commitment).await?;
+
+		Ok(Self { id, commitment })
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		encode::uuid(&mut bytes, &self.id);
+		bytes.extend_from_slice(&self.commitment);
+		bytes
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum PairingError {
+	#[error("peer not found")]
+	PeerNotFound,
+	#[error("error connecting to peer: {0}")]
+	Connecting(String),
+	#[error("the pairing was rejected")]
+	Rejected,
+	#[error("the pairing wasn't confirmed in time")]
+	TimedOut,
+	#[error("the remote node's nonce doesn't match its commitment")]
+	InvalidCommitment,
+	#[error("invalid response '{0}' from the remote node")]
+	InvalidResponse(u8),
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("error decoding: {0}")]
+	Decode(#[from] decode::Error),
+	#[error("failed to save the contact: {0}")]
+	Config(#[from] NodeConfigError),
+}
+
+fn commitment(nonce: &Uuid) -> [u8; blake3::OUT_LEN] {
+	*blake3::hash(nonce.as_bytes()).as_bytes()
+}
+
+/// The 6-digit code shown to both users, derived from both identities and nonces.
+/// If they see the same one nobody is sitting in the middle of the exchange.
+pub fn pairing_code(
+	initiator: RemoteIdentity,
+	responder: RemoteIdentity,
+	initiator_nonce: &Uuid,
+	responder_nonce: &Uuid,
+) -> String {
+	let mut hasher = blake3::Hasher::new();
+	hasher.update(b"sd-p2p-pairing");
+	hasher.update(&initiator.get_bytes());
+	hasher.update(&responder.get_bytes());
+	hasher.update(initiator_nonce.as_bytes());
+	hasher.update(responder_nonce.as_bytes());
+
+	let [a, b, c, d, ..] = *hasher.finalize().as_bytes();
+	format!("{:06}", u32::from_le_bytes([a, b, c, d]) % 1_000_000)
+}
+
+/// The initiator's side of the exchange, returns the code to show to the user.
+pub(crate) async fn initiate(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	id: Uuid,
+	initiator: RemoteIdentity,
+	responder: RemoteIdentity,
+) -> Result<String, PairingError> {
+	let nonce = Uuid::new_v4();
+	stream
+		.write_all(
+			&Header::Pair(HeaderPair {
+				id,
+				commitment: commitment(&nonce),
+			})
+			.to_bytes(),
+		)
+		.await?;
+	stream.flush().await?;
+
+	let responder_nonce = decode::uuid(stream).await?;
+
+	let mut bytes = Vec::new();
+	encode::uuid(&mut bytes, &nonce);
+	stream.write_all(&bytes).await?;
+	stream.flush().await?;
+
+	Ok(pairing_code(initiator, responder, &nonce, &responder_nonce))
+}
+
+/// The responder's side of the exchange, returns the code to show to the user.
+pub(crate) async fn respond(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	header: &HeaderPair,
+	initiator: RemoteIdentity,
+	responder: RemoteIdentity,
+) -> Result<String, PairingError> {
+	let nonce = Uuid::new_v4();
+
+	let mut bytes = Vec::new();
+	encode::uuid(&mut bytes, &nonce);
+	stream.write_all(&bytes).await?;
+	stream.flush().await?;
+
+	let initiator_nonce = decode::uuid(stream).await?;
+	if commitment(&initiator_nonce) != header.commitment {
+		return Err(PairingError::InvalidCommitment);
+	}
+
+	Ok(pairing_code(initiator, responder, &initiator_nonce, &nonce))
+}
+
+/// Tell the remote node whether our user confirmed the code, and wait for theirs to do the same.
+pub(crate) async fn confirm(
+	stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	confirmed: bool,
+) -> Result<(), PairingError> {
+	stream
+		.write_all(&[if confirmed { CONFIRMED } else { REJECTED }])
+		.await?;
+	stream.flush().await?;
+
+	if !confirmed {
+		return Err(PairingError::Rejected);
+	}
+
+	match timeout(PAIRING_TIMEOUT, stream.read_u8()).await {
+		Ok(Ok(CONFIRMED)) => Ok(()),
+		Ok(Ok(REJECTED)) => Err(PairingError::Rejected),
+		Ok(Ok(d)) => Err(PairingError::InvalidResponse(d)),
+		Ok(Err(err)) => Err(err.into()),
+		Err(_) => Err(PairingError::TimedOut),
+	}
+}
+
+pub(crate) async fn add_contact(
+	node_config: &config::Manager,
+	identity: RemoteIdentity,
+) -> Result<(), NodeConfigError> {
+	node_config
+		.write(|config| {
+			config.contacts.insert(identity);
+		})
+		.await
+		.map(|_| ())
+}
+
+/// Pair with a remote node. Progress is reported through [`P2PEvent::PairingRequest`] and the events which follow it.
+pub async fn pair(p2p: Arc<P2PManager>, identity: RemoteIdentity) -> Result<Uuid, PairingError> {
+	let peer = p2p
+		.p2p
+		.peers()
+		.get(&identity)
+		.ok_or(PairingError::PeerNotFound)?
+		.clone();
+
+	let id = Uuid::new_v4();
+	debug!("({id}): starting pairing with peer '{identity}'");
+
+	tokio::spawn(async move {
+		let result = async {
+			let mut stream = peer
+				.new_stream()
+				.await
+				.map_err(|err| PairingError::Connecting(err.to_string()))?;
+
+			let code = initiate(&mut stream, id, p2p.p2p.remote_identity(), identity).await?;
+			run(&p2p, id, identity, code, &mut stream).await
+		}
+		.await;
+
+		finish(&p2p, id, identity, result);
+	});
+
+	Ok(id)
+}
+
+pub(crate) async fn receiver(p2p: &Arc<P2PManager>, header: HeaderPair, mut stream: UnicastStream) {
+	let id = header.id;
+	let identity = stream.remote_identity();
+	debug!("({id}): received pairing request from peer '{identity}'");
+
+	let result = async {
+		let code = respond(&mut stream, &header, identity, p2p.p2p.remote_identity()).await?;
+		run(p2p, id, identity, code, &mut stream).await
+	}
+	.await;
+
+	finish(p2p, id, identity, result);
+}
+
+/// Show the code to our user, and once both users confirmed it, add the remote node to our contacts.
+async fn run(
+	p2p: &Arc<P2PManager>,
+	id: Uuid,
+	identity: RemoteIdentity,
+	code: String,
+	stream: &mut UnicastStream,
+) -> Result<(), PairingError> {
+	let (tx, rx) = oneshot::channel();
+	p2p.pairing_reqs
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.insert(id, tx);
+
+	p2p.events
+		.send(P2PEvent::PairingRequest { id, identity, code })
+		.ok();
+
+	let confirmed = timeout(PAIRING_TIMEOUT, rx).await;
+	p2p.pairing_reqs
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.remove(&id);
+
+	match confirmed {
+		// The request is dropped if the manager forgets it, which we treat as a rejection
+		Ok(confirmed) => confirm(stream, confirmed.unwrap_or(false)).await?,
+		Err(_) => {
+			stream.write_all(&[REJECTED]).await?;
+			stream.flush().await?;
+			return Err(PairingError::TimedOut);
+		}
+	}
+
+	add_contact(&p2p.node_config, identity).await?;
+	Ok(())
+}
+
+fn finish(p2p: &P2PManager, id: Uuid, identity: RemoteIdentity, result: Result<(), PairingError>) {
+	let event = match result {
+		Ok(()) => {
+			info!("({id}): paired with peer '{identity}'");
+			P2PEvent::PairingCompleted { id, identity }
+		}
+		Err(err) => {
+			warn!("({id}): pairing with peer '{identity}' failed: {err}");
+			P2PEvent::PairingFailed {
+				id,
+				identity,
+				reason: err.to_string(),
+			}
+		}
+	};
+
+	p2p.events.send(event).ok();
+}
+
+impl P2PManager {
+	/// Called once the user compared the code of a [`P2PEvent::PairingRequest`] with the one shown on the other device.
+	pub fn confirm_pairing(&self, id: Uuid, confirmed: bool) {
+		if let Some(chan) = self
+			.pairing_reqs
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&id)
+		{
+			chan.send(confirmed)
+				.map_err(|err| {
+					warn!("error confirming pairing '{id:?}': '{err:?}'");
+				})
+				.ok();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sd_p2p::Identity;
+
+	use super::*;
+
+	async fn exchange(
+		initiator: &mut (impl AsyncRead + AsyncWrite + Unpin),
+		responder: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	) -> (String, String) {
+		let (initiator_identity, responder_identity) = (
+			Identity::default().to_remote_identity(),
+			Identity::default().to_remote_identity(),
+		);
+		let id = Uuid::new_v4();
+
+		let (initiator_code, responder_code) = tokio::join!(
+			initiate(initiator, id, initiator_identity, responder_identity),
+			async {
+				let Header::Pair(header) = Header::from_stream(responder).await.unwrap() else {
+					unreachable!();
+				};
+				assert_eq!(header.id, id);
+				respond(responder, &header, initiator_identity, responder_identity).await
+			}
+		);
+
+		(initiator_code.unwrap(), responder_code.unwrap())
+	}
+
+	#[tokio::test]
+	async fn both_sides_show_the_same_code() {
+		let (mut initiator, mut responder) = tokio::io::duplex(64);
+
+		let (initiator_code, responder_code) = exchange(&mut initiator, &mut responder).await;
+		assert_eq!(initiator_code, responder_code);
+		assert_eq!(initiator_code.len(), 6);
+		assert!(initiator_code.chars().all(|c| c.is_ascii_digit()));
+
+		let (initiator, responder) =
+			tokio::join!(confirm(&mut initiator, true), confirm(&mut responder, true));
+		assert!(initiator.is_ok());
+		assert!(responder.is_ok());
+	}
+
+	#[tokio::test]
+	async fn a_rejection_aborts_both_sides() {
+		let (mut initiator, mut responder) = tokio::io::duplex(64);
+		exchange(&mut initiator, &mut responder).await;
+
+		let (initiator, responder) = tokio::join!(
+			confirm(&mut initiator, true),
+			confirm(&mut responder, false)
+		);
+		assert!(matches!(initiator, Err(PairingError::Rejected)));
+		assert!(matches!(responder, Err(PairingError::Rejected)));
+	}
+
+	#[tokio::test]
+	async fn nonces_must_match_the_commitment() {
+		let (mut initiator, mut responder) = tokio::io::duplex(64);
+		let identity = Identity::default().to_remote_identity();
+		let header = HeaderPair {
+			id: Uuid::new_v4(),
+			commitment: commitment(&Uuid::new_v4()),
+		};
+
+		let (_, result) = tokio::join!(
+			async {
+				decode::uuid(&mut initiator).await.unwrap();
+				// Not the nonce it committed to
+				let mut bytes = Vec::new();
+				encode::uuid(&mut bytes, &Uuid::new_v4());
+				initiator.write_all(&bytes).await.unwrap();
+			},
+			respond(&mut responder, &header, identity, identity)
+		);
+		assert!(matches!(result, Err(PairingError::InvalidCommitment)));
+	}
+
+	#[tokio::test]
+	async fn contacts_persist_across_restarts() {
+		let data_dir = tempfile::tempdir().unwrap();
+		let identity = Identity::default().to_remote_identity();
+
+		let node_config = config::Manager::new(data_dir.path()).await.unwrap();
+		add_contact(&node_config, identity).await.unwrap();
+		drop(node_config);
+
+		let node_config = config::Manager::new(data_dir.path()).await.unwrap();
+		assert!(node_config.get().await.contacts.contains(&identity));
+	}
+}
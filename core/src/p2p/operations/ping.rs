@@ -1,12 +1,521 @@
-use sd_p2p::UnicastStream;
-use tracing::debug;
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex, PoisonError},
+	time::Duration,
+};
 
-/// Send a ping to all peers we are connected to
-#[allow(unused)]
-pub async fn ping() {
-	todo!();
+use crate::{
+	node::config::NodeConfig,
+	p2p::{Error as P2PError, Header, OpId, P2PEvent, P2PEvents, P2PMetrics, P2POperation},
+};
+
+use super::identify::{IdentifyCache, PeerProtocol};
+
+use chrono::Utc;
+use sd_p2p::{Peer, RemoteIdentity, UnicastStream, P2P};
+use serde::Serialize;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	time::{timeout, Instant},
+};
+use tracing::{debug, warn, Instrument};
+
+/// How often connected peers are probed by default
+const DEFAULT_KEEP_ALIVE_INTERVAL_SECS: u32 = 30;
+/// How many probes in a row a peer can miss by default before its connections are closed
+const DEFAULT_KEEP_ALIVE_MAX_FAILURES: u32 = 3;
+
+/// The longest a single probe waits for the peer, probes are given up earlier when the interval is shorter
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far a peer's clock can be from ours by default before the user is warned, sync orders operations by their timestamps
+const DEFAULT_CLOCK_SKEW_WARNING_SECS: u32 = 30;
+
+/// Sent back by the receiver of a ping, followed by its wall-clock time in milliseconds
+const PONG: u8 = b'P';
+
+/// What a peer's answer to a ping told us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pong {
+	/// How many milliseconds the peer's clock is ahead of ours, accounting for half the round trip.
+	/// `None` for nodes from before pongs carried their clock.
+	pub clock_skew_ms: Option<i64>,
+}
+
+/// Send a ping to `peer`, which speaks `protocol`, returning its answer if it came within `wait`.
+/// Nodes from before pings were answered close the stream instead, which is just as good a sign of life.
+pub async fn ping(
+	peer: &Peer,
+	wait: Duration,
+	protocol: &PeerProtocol,
+	metrics: &Arc<P2PMetrics>,
+) -> Result<Pong, P2PError> {
+	let op_id = OpId::new();
+	let probe = async {
+		let stream = peer
+			.new_stream()
+			.await
+			.map_err(|err| P2PError::Connection(err.to_string()))?;
+		let mut stream = metrics.instrument(P2POperation::Ping, stream);
+		let sent_at = Utc::now().timestamp_millis();
+		let start = Instant::now();
+		stream
+			.write_all(&Header::Ping.to_bytes_for(op_id, protocol))
+			.await
+			.map_err(P2PError::io("sending ping"))?;
+
+		let mut buf = [0; 1];
+		match stream.read(&mut buf).await {
+			Ok(0) => Ok(Pong {
+				clock_skew_ms: None,
+			}),
+			Ok(_) if buf[0] == PONG => {
+				let mut remote_now = [0; 8];
+				let clock_skew_ms = match stream.read_exact(&mut remote_now).await {
+					Ok(_) => {
+						let half_rtt = i64::try_from(start.elapsed().as_millis() / 2).unwrap_or(0);
+						Some(i64::from_be_bytes(remote_now) - (sent_at + half_rtt))
+					}
+					// Nodes from before the clock was sent close the stream after the pong
+					Err(_) => None,
+				};
+
+				Ok(Pong { clock_skew_ms })
+			}
+			Ok(_) => Err(P2PError::Protocol(format!("invalid pong '{}'", buf[0]))),
+			Err(err) => Err(P2PError::io("receiving pong")(err)),
+		}
+	};
+
+	async {
+		let result = timeout(wait, probe)
+			.await
+			.unwrap_or_else(|_| Err(P2PError::TimedOut(wait)));
+		if let Err(err) = &result {
+			debug!("Ping to peer '{}' failed: {err}", peer.identity());
+		}
+		result
+	}
+	.instrument(op_id.span(peer.identity(), P2POperation::Ping))
+	.await
 }
 
-pub(crate) async fn receiver(stream: UnicastStream) {
+pub(crate) async fn receiver(stream: UnicastStream) -> Result<(), P2PError> {
 	debug!("Received ping from peer '{}'", stream.remote_identity());
+
+	answer(stream, Utc::now().timestamp_millis())
+		.await
+		.map_err(P2PError::io("answering ping"))
+}
+
+async fn answer(
+	mut stream: impl AsyncRead + AsyncWrite + Unpin,
+	now_ms: i64,
+) -> std::io::Result<()> {
+	let mut pong = vec![PONG];
+	pong.extend_from_slice(&now_ms.to_be_bytes());
+	stream.write_all(&pong).await?;
+	stream.flush().await
+}
+
+/// How far a peer's clock is from ours, as of its last answered ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ClockSkew {
+	/// Positive when the peer's clock is ahead of ours
+	pub skew_ms: i64,
+	/// Beyond the node's warning threshold, the user was sent a [`P2PEvent::ClockSkewWarning`]
+	pub exceeds_threshold: bool,
+}
+
+/// The latest [`ClockSkew`] of every connected peer which reports its clock.
+#[derive(Debug, Default)]
+pub struct ClockSkews {
+	skews: Mutex<HashMap<RemoteIdentity, ClockSkew>>,
+}
+
+impl ClockSkews {
+	pub fn get(&self, identity: RemoteIdentity) -> Option<ClockSkew> {
+		self.skews
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(&identity)
+			.copied()
+	}
+
+	/// Store a new estimate, returning whether the peer just went beyond `threshold`.
+	/// Peers are only warned about again once they got back within it.
+	fn record(&self, identity: RemoteIdentity, skew_ms: i64, threshold: Duration) -> bool {
+		let exceeds_threshold = skew_ms.unsigned_abs() > threshold.as_millis() as u64;
+		let previous = self
+			.skews
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(
+				identity,
+				ClockSkew {
+					skew_ms,
+					exceeds_threshold,
+				},
+			);
+
+		exceeds_threshold && !previous.is_some_and(|previous| previous.exceeds_threshold)
+	}
+
+	fn retain(&self, mut f: impl FnMut(&RemoteIdentity) -> bool) {
+		self.skews
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.retain(|identity, _| f(identity));
+	}
+}
+
+/// Detects peers which went away without closing their connections, eg. a laptop with its lid closed.
+///
+/// Every connected peer is pinged each interval and the connections of those which miss too many pings in a row are closed,
+/// so they stop being shown as connected straight away instead of once a transfer to them fails.
+#[derive(Debug)]
+pub(crate) struct KeepAlive {
+	pub(crate) interval: Duration,
+	max_failures: u32,
+	clock_skew_warning: Duration,
+	failures: HashMap<RemoteIdentity, u32>,
+}
+
+impl Default for KeepAlive {
+	fn default() -> Self {
+		Self {
+			interval: Duration::from_secs(DEFAULT_KEEP_ALIVE_INTERVAL_SECS.into()),
+			max_failures: DEFAULT_KEEP_ALIVE_MAX_FAILURES,
+			clock_skew_warning: Duration::from_secs(DEFAULT_CLOCK_SKEW_WARNING_SECS.into()),
+			failures: HashMap::new(),
+		}
+	}
+}
+
+impl KeepAlive {
+	/// Apply the intervals from the node config, this takes effect from the next probe.
+	pub(crate) fn configure(&mut self, config: &NodeConfig) {
+		self.interval = Duration::from_secs(
+			config
+				.keep_alive_interval_secs
+				.unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL_SECS)
+				.max(1)
+				.into(),
+		);
+		self.max_failures = config
+			.keep_alive_max_failures
+			.unwrap_or(DEFAULT_KEEP_ALIVE_MAX_FAILURES)
+			.max(1);
+		self.clock_skew_warning = Duration::from_secs(
+			config
+				.clock_skew_warning_secs
+				.unwrap_or(DEFAULT_CLOCK_SKEW_WARNING_SECS)
+				.into(),
+		);
+	}
+
+	/// Ping every connected peer at once and close the connections of the ones which failed too often.
+	/// The clocks the peers answer with are kept in `clock_skews`, warning the user through `events` about the ones which are off.
+	///
+	/// Peers aren't asked what they support just to be pinged, only those `identified` knows to be current are sent an op id.
+	pub(crate) async fn probe(
+		&mut self,
+		p2p: &P2P,
+		metrics: &Arc<P2PMetrics>,
+		identified: &IdentifyCache,
+		clock_skews: &ClockSkews,
+		events: &P2PEvents,
+	) {
+		let peers = p2p
+			.peers()
+			.values()
+			.filter(|peer| peer.is_connected())
+			.cloned()
+			.collect::<Vec<_>>();
+
+		// Peers which disconnected on their own start over if they come back
+		self.failures
+			.retain(|identity, _| peers.iter().any(|peer| peer.identity() == *identity));
+		clock_skews.retain(|identity| peers.iter().any(|peer| peer.identity() == *identity));
+
+		let wait = self.interval.min(PROBE_TIMEOUT);
+		let results = futures::future::join_all(peers.iter().map(|peer| async {
+			ping(
+				peer,
+				wait,
+				&identified.known_protocol(peer.identity()),
+				metrics,
+			)
+			.await
+		}))
+		.await;
+
+		for (peer, pong) in peers.into_iter().zip(results) {
+			if let Ok(pong) = pong {
+				self.failures.remove(&peer.identity());
+
+				if let Some(skew_ms) = pong.clock_skew_ms {
+					if clock_skews.record(peer.identity(), skew_ms, self.clock_skew_warning) {
+						warn!(
+							"Peer '{}' clock is {skew_ms}ms off ours, sync with it may order operations wrong",
+							peer.identity()
+						);
+						events
+							.send(P2PEvent::ClockSkewWarning {
+								identity: peer.identity(),
+								skew_ms,
+							})
+							.ok();
+					}
+				}
+				continue;
+			}
+
+			let failures = self.failures.entry(peer.identity()).or_default();
+			*failures += 1;
+			if *failures < self.max_failures {
+				continue;
+			}
+
+			warn!(
+				"Peer '{}' missed {failures} pings in a row, closing its connections",
+				peer.identity()
+			);
+			self.failures.remove(&peer.identity());
+			peer.disconnect();
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::{
+		future::Future,
+		sync::{
+			atomic::{AtomicI64, Ordering},
+			Arc,
+		},
+	};
+
+	use sd_p2p::{flume::bounded, Identity, LinkConditions, MemoryNetwork, MemoryTransport};
+	use tokio::{sync::broadcast::error::TryRecvError, time::sleep};
+
+	use crate::p2p::KnownPeers;
+
+	use super::*;
+
+	/// Put a node on `network` which hands the pings it gets to `answer`, and have it connect with `p2p`
+	async fn pinged_node<Fut>(
+		network: &MemoryNetwork,
+		p2p: &P2P,
+		answer: impl Fn(UnicastStream) -> Fut + Send + Sync + 'static,
+	) -> Arc<Peer>
+	where
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		let (handler_tx, handler_rx) = bounded(5);
+		let remote = P2P::new("test", Identity::new(), handler_tx);
+		MemoryTransport::spawn(remote.clone(), network);
+
+		let answer = Arc::new(answer);
+		tokio::spawn(async move {
+			while let Ok(mut stream) = handler_rx.recv_async().await {
+				let answer = answer.clone();
+				tokio::spawn(async move {
+					Header::from_stream(&mut stream).await.unwrap();
+					answer(stream).await;
+				});
+			}
+		});
+
+		// We're only connected with the node once it opened a stream to us
+		let us = remote.peers().get(&p2p.remote_identity()).cloned().unwrap();
+		us.new_stream().await.unwrap();
+
+		p2p.peers().get(&remote.remote_identity()).cloned().unwrap()
+	}
+
+	#[tokio::test]
+	async fn unresponsive_peers_are_disconnected() {
+		let network = MemoryNetwork::default();
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		MemoryTransport::spawn(p2p.clone(), &network);
+		let (libraries_tx, _libraries_rx) = bounded(15);
+		let data_dir = tempfile::tempdir().unwrap();
+		let events = P2PEvents::spawn(
+			p2p.clone(),
+			p2p.register_hook("test", libraries_tx),
+			Arc::new(KnownPeers::load(data_dir.path()).await),
+		);
+		let mut events_rx = events.subscribe();
+
+		let peer = pinged_node(&network, &p2p, receiver).await;
+		let remote = peer.identity();
+		assert!(peer.is_connected());
+
+		let mut keep_alive = KeepAlive {
+			interval: Duration::from_millis(50),
+			max_failures: 2,
+			clock_skew_warning: Duration::from_secs(30),
+			failures: HashMap::new(),
+		};
+		let clock_skews = ClockSkews::default();
+
+		for _ in 0..3 {
+			keep_alive
+				.probe(
+					&p2p,
+					&Default::default(),
+					&IdentifyCache::default(),
+					&clock_skews,
+					&events,
+				)
+				.await;
+		}
+		assert!(peer.is_connected());
+
+		// Nothing gets through in time anymore, like once the lid of the remote was closed
+		network.set_link(LinkConditions {
+			latency: Duration::from_secs(10),
+			bytes_per_sec: None,
+		});
+
+		let probing = tokio::spawn(async move {
+			loop {
+				keep_alive
+					.probe(
+						&p2p,
+						&Default::default(),
+						&IdentifyCache::default(),
+						&clock_skews,
+						&events,
+					)
+					.await;
+				sleep(keep_alive.interval).await;
+			}
+		});
+
+		timeout(Duration::from_secs(1), async {
+			loop {
+				if let P2PEvent::DisconnectedPeer { identity, .. } = events_rx.recv().await.unwrap()
+				{
+					assert_eq!(identity, remote);
+					break;
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert!(!peer.is_connected());
+
+		probing.abort();
+	}
+
+	#[tokio::test]
+	async fn peers_with_a_skewed_clock_are_warned_about_once() {
+		let network = MemoryNetwork::default();
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new("test", Identity::new(), handler_tx);
+		MemoryTransport::spawn(p2p.clone(), &network);
+		let (libraries_tx, _libraries_rx) = bounded(15);
+		let data_dir = tempfile::tempdir().unwrap();
+		let events = P2PEvents::spawn(
+			p2p.clone(),
+			p2p.register_hook("test", libraries_tx),
+			Arc::new(KnownPeers::load(data_dir.path()).await),
+		);
+		let mut events_rx = events.subscribe();
+
+		// The remote answers pings with its clock `offset` milliseconds ahead of ours
+		let offset = Arc::new(AtomicI64::new(5_000));
+		let peer = pinged_node(&network, &p2p, {
+			let offset = offset.clone();
+			move |stream| {
+				let now_ms = Utc::now().timestamp_millis() + offset.load(Ordering::Relaxed);
+				async move { answer(stream, now_ms).await.unwrap() }
+			}
+		})
+		.await;
+		let remote = peer.identity();
+
+		let mut keep_alive = KeepAlive {
+			interval: Duration::from_millis(50),
+			max_failures: 2,
+			clock_skew_warning: Duration::from_secs(30),
+			failures: HashMap::new(),
+		};
+		let clock_skews = ClockSkews::default();
+		let mut warnings = || {
+			let mut warnings = vec![];
+			loop {
+				match events_rx.try_recv() {
+					Ok(P2PEvent::ClockSkewWarning { identity, skew_ms }) => {
+						warnings.push((identity, skew_ms))
+					}
+					Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+					Err(_) => break warnings,
+				}
+			}
+		};
+		let skew_is_about =
+			|skew: ClockSkew, expected: i64| (skew.skew_ms - expected).abs() < 1_000;
+
+		let metrics = Arc::new(P2PMetrics::default());
+		keep_alive
+			.probe(
+				&p2p,
+				&metrics,
+				&IdentifyCache::default(),
+				&clock_skews,
+				&events,
+			)
+			.await;
+		let skew = clock_skews.get(remote).unwrap();
+		assert!(skew_is_about(skew, 5_000), "{skew:?}");
+		assert!(!skew.exceeds_threshold);
+		assert!(warnings().is_empty());
+
+		// The ping we sent is counted, along with the pong and clock it was answered with
+		let snapshot = metrics.snapshot();
+		let pings = snapshot
+			.operations
+			.iter()
+			.find(|metrics| metrics.operation == P2POperation::Ping)
+			.unwrap();
+		assert_eq!((pings.streams, pings.bytes_received), (1, 9));
+		assert!(pings.bytes_sent > 0);
+
+		offset.store(-60_000, Ordering::Relaxed);
+		keep_alive
+			.probe(
+				&p2p,
+				&Default::default(),
+				&IdentifyCache::default(),
+				&clock_skews,
+				&events,
+			)
+			.await;
+		let skew = clock_skews.get(remote).unwrap();
+		assert!(skew_is_about(skew, -60_000), "{skew:?}");
+		assert!(skew.exceeds_threshold);
+		let warnings_sent = warnings();
+		assert_eq!(warnings_sent.len(), 1);
+		assert_eq!(warnings_sent[0].0, remote);
+		assert_eq!(warnings_sent[0].1, skew.skew_ms);
+
+		// Still off, which the user already knows about
+		keep_alive
+			.probe(
+				&p2p,
+				&Default::default(),
+				&IdentifyCache::default(),
+				&clock_skews,
+				&events,
+			)
+			.await;
+		assert!(clock_skews.get(remote).unwrap().exceeds_threshold);
+		assert!(warnings().is_empty());
+	}
 }
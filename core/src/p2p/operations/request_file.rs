@@ -0,0 +1,1707 @@
+use std::{
+	collections::VecDeque,
+	fmt,
+	future::Future,
+	io,
+	ops::Range,
+	path::{Component, Path, PathBuf},
+	pin::Pin,
+	sync::{Arc, Mutex, PoisonError},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use crate::{
+	library::Library,
+	node::config::NodeConfig,
+	object::cas::CasIdHasher,
+	p2p::{
+		ConnectionLogEvent, Error as P2PError, Header, OpId, P2PEvent, P2PEventsSender, P2PManager,
+		P2POperation,
+	},
+	Node,
+};
+use sd_file_path_helper::{
+	file_path_to_handle_p2p_serve_file, filter_existing_file_path_params, full_path_on_disk,
+	IsolatedFilePathData,
+};
+use sd_p2p::{RemoteIdentity, UnicastStream};
+use sd_p2p_proto::{decode, encode};
+use sd_prisma::prisma::{file_path, location};
+use sd_utils::db::maybe_missing;
+use thiserror::Error;
+use tokio::{
+	fs::File,
+	io::{
+		AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf, Take,
+	},
+	sync::oneshot,
+};
+use tracing::{debug, error, Instrument};
+use uuid::Uuid;
+
+/// Sent before the file's bytes when the request can be served
+const RESPONSE_OK: u8 = b'O';
+/// Sent in place of [`RESPONSE_OK`], followed by a [`RequestFileError`] code
+const RESPONSE_ERR: u8 = b'E';
+
+/// The most files a single batch request can ask for
+pub const MAX_BATCH_FILES: usize = 256;
+
+/// Files which would take a batch response over this many bytes are skipped, they can be requested individually instead
+pub const MAX_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How many streams we serve files over at once by default, the rest wait for a slot
+const DEFAULT_FILE_SERVE_CONCURRENCY: usize = 4;
+
+/// The size of each read when copying a file to the requester, the throttle is applied between them
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Per-file status in a batch response
+const BATCH_FOUND: u8 = b'F';
+const BATCH_NOT_FOUND: u8 = b'N';
+const BATCH_SKIPPED: u8 = b'S';
+
+/// The longest [`FileTarget::Path`] a node serves, as long as paths get on most platforms
+pub const MAX_RELATIVE_PATH_LEN: usize = 4096;
+
+/// The longest `cas_id` read from a peer, well above the 16 characters they have
+const MAX_CAS_ID_LEN: usize = 64;
+
+/// Which file a [`HeaderFile`] asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileTarget {
+	/// The `pub_id` of the file path
+	Id(Uuid),
+	/// A path within a location, for requesters which only know where the file is, eg. when browsing a remote location.
+	/// The serving node only accepts paths which stay within the location.
+	Path {
+		location_pub_id: Uuid,
+		relative_path: String,
+	},
+}
+
+impl fmt::Display for FileTarget {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Id(file_path_id) => write!(f, "'{file_path_id}'"),
+			Self::Path {
+				location_pub_id,
+				relative_path,
+			} => write!(f, "'{relative_path}' in location '{location_pub_id}'"),
+		}
+	}
+}
+
+/// Ask a remote node for (part of) a file in a library we share with it.
+///
+/// Requests for a [`FileTarget::Path`] have a [`Header`] discriminator of their own, so the requests of nodes
+/// which only know ids are unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderFile {
+	pub library_id: Uuid,
+	pub target: FileTarget,
+	/// The bytes to send, or the whole file if [`None`]
+	pub range: Option<Range<u64>>,
+	/// The `cas_id` we expect the file to have. If set the response ends with the file's actual `cas_id`.
+	/// [`None`] is a "best effort" request which skips verification.
+	pub cas_id: Option<String>,
+}
+
+impl HeaderFile {
+	/// Read the header of a file requested by [`FileTarget::Id`], or by [`FileTarget::Path`] with `by_path`
+	pub async fn from_stream(
+		stream: &mut (impl AsyncRead + Unpin),
+		by_path: bool,
+	) -> Result<Self, decode::Error> {
+		let library_id = decode::uuid(stream).await?;
+		let target = if by_path {
+			FileTarget::Path {
+				location_pub_id: decode::uuid(stream).await?,
+				relative_path: decode::string_max(stream, MAX_RELATIVE_PATH_LEN).await?,
+			}
+		} else {
+			FileTarget::Id(decode::uuid(stream).await?)
+		};
+		let range = match stream.read_u8().await? {
+			0 => None,
+			_ => Some(stream.read_u64_le().await?..stream.read_u64_le().await?),
+		};
+		let cas_id = match stream.read_u8().await? {
+			0 => None,
+			_ => Some(decode::string_max(stream, MAX_CAS_ID_LEN).await?),
+		};
+
+		Ok(Self {
+			library_id,
+			target,
+			range,
+			cas_id,
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		encode::uuid(&mut bytes, &self.library_id);
+		match &self.target {
+			FileTarget::Id(file_path_id) => encode::uuid(&mut bytes, file_path_id),
+			FileTarget::Path {
+				location_pub_id,
+				relative_path,
+			} => {
+				encode::uuid(&mut bytes, location_pub_id);
+				encode::string(&mut bytes, relative_path);
+			}
+		}
+		match &self.range {
+			Some(range) => {
+				bytes.push(1);
+				bytes.extend_from_slice(&range.start.to_le_bytes());
+				bytes.extend_from_slice(&range.end.to_le_bytes());
+			}
+			None => bytes.push(0),
+		}
+		match &self.cas_id {
+			Some(cas_id) => {
+				bytes.push(1);
+				encode::string(&mut bytes, cas_id);
+			}
+			None => bytes.push(0),
+		}
+		bytes
+	}
+}
+
+/// Ask a remote node for many whole files over a single stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderFileBatch {
+	pub library_id: Uuid,
+	pub file_path_ids: Vec<Uuid>,
+}
+
+impl HeaderFileBatch {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
+		let library_id = decode::uuid(stream).await?;
+		let len = stream.read_u32_le().await? as usize;
+		if len > MAX_BATCH_FILES {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("batch of {len} files is over the limit of {MAX_BATCH_FILES}"),
+			)
+			.into());
+		}
+
+		let mut file_path_ids = Vec::with_capacity(len);
+		for _ in 0..len {
+			file_path_ids.push(decode::uuid(stream).await?);
+		}
+
+		Ok(Self {
+			library_id,
+			file_path_ids,
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		encode::uuid(&mut bytes, &self.library_id);
+		bytes.extend_from_slice(&(self.file_path_ids.len() as u32).to_le_bytes());
+		for id in &self.file_path_ids {
+			encode::uuid(&mut bytes, id);
+		}
+		bytes
+	}
+}
+
+/// A single file from a batch response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchFile {
+	Found(Vec<u8>),
+	NotFound,
+	/// The file didn't fit within [`MAX_BATCH_BYTES`], request it on its own
+	Skipped,
+}
+
+#[derive(Debug, Error)]
+pub enum RequestFileError {
+	#[error("peer '{0}' isn't an instance of the library")]
+	PeerNotFound(RemoteIdentity),
+	#[error("the remote node doesn't have the library")]
+	LibraryNotFound,
+	#[error("the remote node only shares the library's files with its instances")]
+	Unauthorized,
+	#[error("the remote node doesn't have the file")]
+	FileNotFound,
+	#[error("the requested path isn't within the location")]
+	PathOutsideLocation,
+	#[error("the remote node is serving as many files as it allows")]
+	Busy,
+	#[error("requested range is outside of the file which is {total_size} bytes")]
+	RangeOutOfBounds { total_size: u64 },
+	#[error("invalid response '{0}' from the remote node")]
+	InvalidResponse(u8),
+	#[error("batch of {0} files is over the limit")]
+	TooManyFiles(usize),
+	#[error("batch response doesn't match the request")]
+	InvalidBatchResponse,
+	#[error("file content has changed, expected cas_id '{expected}' but got '{actual}'")]
+	ContentMismatch { expected: String, actual: String },
+	#[error("error connecting to peer: {0}")]
+	Connecting(String),
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+}
+
+impl RequestFileError {
+	/// Encode the errors the serving side can send back
+	pub(crate) fn to_bytes(&self) -> Vec<u8> {
+		match self {
+			Self::LibraryNotFound => vec![RESPONSE_ERR, b'L'],
+			Self::Unauthorized => vec![RESPONSE_ERR, b'U'],
+			Self::PathOutsideLocation => vec![RESPONSE_ERR, b'P'],
+			Self::Busy => vec![RESPONSE_ERR, b'B'],
+			Self::RangeOutOfBounds { total_size } => {
+				let mut bytes = vec![RESPONSE_ERR, b'R'];
+				bytes.extend_from_slice(&total_size.to_le_bytes());
+				bytes
+			}
+			// Everything else is reported to the requester as the file being unavailable
+			_ => vec![RESPONSE_ERR, b'F'],
+		}
+	}
+
+	async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Self {
+		match stream.read_u8().await {
+			Ok(b'L') => Self::LibraryNotFound,
+			Ok(b'U') => Self::Unauthorized,
+			Ok(b'F') => Self::FileNotFound,
+			Ok(b'P') => Self::PathOutsideLocation,
+			Ok(b'B') => Self::Busy,
+			Ok(b'R') => match stream.read_u64_le().await {
+				Ok(total_size) => Self::RangeOutOfBounds { total_size },
+				Err(err) => err.into(),
+			},
+			Ok(d) => Self::InvalidResponse(d),
+			Err(err) => err.into(),
+		}
+	}
+}
+
+/// Where to report a [`RequestFileError::ContentMismatch`] so the library can re-identify the file.
+#[derive(Debug)]
+struct MismatchReport {
+	events: P2PEventsSender,
+	library_id: Uuid,
+	file_path_id: Uuid,
+}
+
+/// The requested bytes of a remote file.
+#[derive(Debug)]
+pub struct RemoteFile<S> {
+	/// The size of the whole file, so follow-up ranges can be requested
+	pub total_size: u64,
+	/// How many bytes will be read from this [`RemoteFile`]
+	pub len: u64,
+	reader: Take<S>,
+	expected_cas_id: Option<String>,
+	report: Option<MismatchReport>,
+}
+
+impl<S: AsyncRead + Unpin> RemoteFile<S> {
+	/// Read the response preamble from the serving node.
+	pub(crate) async fn from_stream(
+		mut stream: S,
+		expected_cas_id: Option<String>,
+	) -> Result<Self, RequestFileError> {
+		match stream.read_u8().await? {
+			RESPONSE_OK => {
+				let total_size = stream.read_u64_le().await?;
+				let len = stream.read_u64_le().await?;
+
+				Ok(Self {
+					total_size,
+					len,
+					reader: stream.take(len),
+					expected_cas_id,
+					report: None,
+				})
+			}
+			RESPONSE_ERR => Err(RequestFileError::from_stream(&mut stream).await),
+			d => Err(RequestFileError::InvalidResponse(d)),
+		}
+	}
+
+	/// Verify the file against the `cas_id` it was requested with. This must be called after reading the file as the bytes aren't trustworthy until it returns `Ok`.
+	/// Anything which hasn't been read yet is discarded.
+	pub async fn finish(mut self) -> Result<(), RequestFileError> {
+		let Some(expected) = self.expected_cas_id.take() else {
+			return Ok(());
+		};
+
+		tokio::io::copy(&mut self.reader, &mut tokio::io::sink()).await?;
+		let actual = decode::string_max(self.reader.get_mut(), MAX_CAS_ID_LEN)
+			.await
+			.map_err(|_| RequestFileError::InvalidResponse(RESPONSE_OK))?;
+		if actual == expected {
+			return Ok(());
+		}
+
+		if let Some(report) = self.report {
+			report
+				.events
+				.send(P2PEvent::FileContentMismatch {
+					library_id: report.library_id,
+					file_path_id: report.file_path_id,
+					expected: expected.clone(),
+					actual: actual.clone(),
+				})
+				.ok();
+		}
+
+		Err(RequestFileError::ContentMismatch { expected, actual })
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RemoteFile<S> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.reader).poll_read(cx, buf)
+	}
+}
+
+/// Limits how many streams we serve files over at once and, optionally, how fast we read them from disk.
+/// This stops remote peers from starving the local UI of disk bandwidth. Requests over the limit wait instead of failing.
+#[derive(Debug)]
+pub(crate) struct FileServeLimiter {
+	state: Mutex<LimiterState>,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+	concurrency: usize,
+	bytes_per_sec: Option<u32>,
+	active: usize,
+	waiting: VecDeque<oneshot::Sender<FileServeSlot>>,
+}
+
+impl Default for FileServeLimiter {
+	fn default() -> Self {
+		Self {
+			state: Mutex::new(LimiterState {
+				concurrency: DEFAULT_FILE_SERVE_CONCURRENCY,
+				bytes_per_sec: None,
+				active: 0,
+				waiting: VecDeque::new(),
+			}),
+		}
+	}
+}
+
+/// A stream we are serving files over. Dropping this hands the slot to the next waiting request.
+#[derive(Debug)]
+pub(crate) struct FileServeSlot {
+	limiter: Arc<FileServeLimiter>,
+}
+
+impl Drop for FileServeSlot {
+	fn drop(&mut self) {
+		self.limiter
+			.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.active -= 1;
+		self.limiter.wake();
+	}
+}
+
+impl FileServeLimiter {
+	/// Hand free slots to waiting requests
+	fn wake(self: &Arc<Self>) {
+		let ready = {
+			let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+			let mut ready = Vec::new();
+			while state.active < state.concurrency {
+				let Some(tx) = state.waiting.pop_front() else {
+					break;
+				};
+				state.active += 1;
+				ready.push(tx);
+			}
+			ready
+		};
+
+		for tx in ready {
+			// If the request gave up waiting, eg. the peer went away, the slot is dropped which passes it on to the next one
+			tx.send(FileServeSlot {
+				limiter: self.clone(),
+			})
+			.ok();
+		}
+	}
+
+	/// Apply the limits from the node config, this takes effect for requests which are already being served too.
+	pub(crate) fn configure(self: &Arc<Self>, config: &NodeConfig) {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		state.concurrency = config
+			.file_serve_concurrency
+			.map(|c| c.max(1) as usize)
+			.unwrap_or(DEFAULT_FILE_SERVE_CONCURRENCY);
+		state.bytes_per_sec = config.file_serve_bytes_per_sec.filter(|b| *b > 0);
+		drop(state);
+		self.wake();
+	}
+
+	/// Wait for a free slot to serve a file over.
+	pub(crate) async fn acquire(self: &Arc<Self>) -> FileServeSlot {
+		let rx = {
+			let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+			if state.active < state.concurrency && state.waiting.is_empty() {
+				state.active += 1;
+				return FileServeSlot {
+					limiter: self.clone(),
+				};
+			}
+
+			let (tx, rx) = oneshot::channel();
+			state.waiting.push_back(tx);
+			rx
+		};
+
+		match rx.await {
+			Ok(slot) => slot,
+			// Senders are only dropped after sending as we hold the limiter, but to be safe we take a slot anyway
+			Err(_) => {
+				self.state
+					.lock()
+					.unwrap_or_else(PoisonError::into_inner)
+					.active += 1;
+				FileServeSlot {
+					limiter: self.clone(),
+				}
+			}
+		}
+	}
+
+	/// How many streams are currently being served
+	pub(crate) fn active(&self) -> usize {
+		self.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.active
+	}
+
+	/// Whether a new request would have to wait for a slot
+	pub(crate) fn is_saturated(&self) -> bool {
+		let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		state.active >= state.concurrency || !state.waiting.is_empty()
+	}
+
+	/// How many requests are waiting for a slot
+	pub(crate) fn queued(&self) -> usize {
+		self.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.waiting
+			.len()
+	}
+
+	/// The throttle is split between the streams being served so it applies to the node as a whole
+	fn bytes_per_sec_per_stream(&self) -> Option<u32> {
+		let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		state
+			.bytes_per_sec
+			.map(|b| (b / state.active.max(1) as u32).max(1))
+	}
+
+	/// Copy `reader` to `writer`, throttled to the configured rate. The rate is checked after every chunk so config changes apply straight away.
+	/// Each chunk is passed to `on_chunk` before it's written.
+	async fn copy(
+		&self,
+		reader: &mut (impl AsyncRead + Unpin),
+		writer: &mut (impl AsyncWrite + Unpin),
+		mut on_chunk: impl FnMut(&[u8]),
+	) -> io::Result<u64> {
+		let mut buf = vec![0; COPY_CHUNK_SIZE];
+		let mut copied = 0u64;
+		loop {
+			let n = reader.read(&mut buf).await?;
+			if n == 0 {
+				return Ok(copied);
+			}
+
+			on_chunk(&buf[..n]);
+			writer.write_all(&buf[..n]).await?;
+			copied += n as u64;
+
+			if let Some(rate) = self.bytes_per_sec_per_stream() {
+				tokio::time::sleep(Duration::from_secs_f64(n as f64 / f64::from(rate))).await;
+			}
+		}
+	}
+}
+
+/// Request a file, or the `range` of it, from a remote node.
+/// If `cas_id` is set [`RemoteFile::finish`] checks the remote file still has it.
+pub async fn request_file(
+	p2p: &Arc<P2PManager>,
+	identity: RemoteIdentity,
+	library_id: Uuid,
+	file_path_id: Uuid,
+	range: Option<Range<u64>>,
+	cas_id: Option<String>,
+) -> Result<RemoteFile<UnicastStream>, RequestFileError> {
+	let mut file = request(
+		p2p,
+		identity,
+		library_id,
+		FileTarget::Id(file_path_id),
+		range,
+		cas_id,
+	)
+	.await?;
+	file.report = Some(MismatchReport {
+		events: p2p.events.sender(),
+		library_id,
+		file_path_id,
+	});
+	Ok(file)
+}
+
+/// Save a file of a remote node to `to`, checking it against `cas_id` when it's known. Returns how many bytes were saved.
+///
+/// Nothing is left at `to` when the request fails or the file doesn't match.
+pub async fn download_file(
+	p2p: &Arc<P2PManager>,
+	identity: RemoteIdentity,
+	library_id: Uuid,
+	file_path_id: Uuid,
+	cas_id: Option<String>,
+	to: &Path,
+) -> Result<u64, P2PError> {
+	let mut file = request_file(p2p, identity, library_id, file_path_id, None, cas_id).await?;
+
+	let saved = async {
+		let mut out = File::create(to)
+			.await
+			.map_err(P2PError::io("creating the downloaded file"))?;
+		let len = tokio::io::copy(&mut file, &mut out)
+			.await
+			.map_err(P2PError::io("downloading file"))?;
+		out.flush()
+			.await
+			.map_err(P2PError::io("saving the downloaded file"))?;
+		file.finish().await?;
+		Ok::<_, P2PError>(len)
+	}
+	.await;
+	if saved.is_err() {
+		tokio::fs::remove_file(to).await.ok();
+	}
+
+	saved
+}
+
+/// Like [`request_file`] for a file we only know the path of within one of the remote node's locations.
+///
+/// Not knowing its id, a [`RequestFileError::ContentMismatch`] isn't reported to the library.
+pub async fn request_file_by_path(
+	p2p: &Arc<P2PManager>,
+	identity: RemoteIdentity,
+	library_id: Uuid,
+	location_pub_id: Uuid,
+	relative_path: String,
+	range: Option<Range<u64>>,
+	cas_id: Option<String>,
+) -> Result<RemoteFile<UnicastStream>, RequestFileError> {
+	request(
+		p2p,
+		identity,
+		library_id,
+		FileTarget::Path {
+			location_pub_id,
+			relative_path,
+		},
+		range,
+		cas_id,
+	)
+	.await
+}
+
+async fn request(
+	p2p: &Arc<P2PManager>,
+	identity: RemoteIdentity,
+	library_id: Uuid,
+	target: FileTarget,
+	range: Option<Range<u64>>,
+	cas_id: Option<String>,
+) -> Result<RemoteFile<UnicastStream>, RequestFileError> {
+	let peer = p2p
+		.get_instance(&library_id, identity)
+		.ok_or(RequestFileError::PeerNotFound(identity))?;
+
+	let op_id = OpId::new();
+	async {
+		let protocol = p2p.peer_protocol(identity).await;
+		let stream = p2p
+			.new_stream(&peer, P2POperation::File)
+			.await
+			.map_err(|err| {
+				p2p.connect_failed(identity, P2POperation::File, &err);
+				RequestFileError::Connecting(err.to_string())
+			})?;
+		let mut stream = p2p.metrics.instrument(P2POperation::File, stream);
+		stream
+			.write_all(
+				&Header::File(HeaderFile {
+					library_id,
+					target,
+					range,
+					cas_id: cas_id.clone(),
+				})
+				.to_bytes_for(op_id, &protocol),
+			)
+			.await?;
+
+		RemoteFile::from_stream(stream, cas_id).await
+	}
+	.instrument(op_id.span(identity, P2POperation::File))
+	.await
+}
+
+/// Request many whole files from a remote node over a single stream.
+/// Files which are missing on the remote node don't fail the batch, they are returned as [`BatchFile::NotFound`].
+pub async fn request_files(
+	p2p: &Arc<P2PManager>,
+	identity: RemoteIdentity,
+	library_id: Uuid,
+	file_path_ids: Vec<Uuid>,
+) -> Result<Vec<(Uuid, BatchFile)>, RequestFileError> {
+	if file_path_ids.len() > MAX_BATCH_FILES {
+		return Err(RequestFileError::TooManyFiles(file_path_ids.len()));
+	}
+
+	let peer = p2p
+		.get_instance(&library_id, identity)
+		.ok_or(RequestFileError::PeerNotFound(identity))?;
+
+	let op_id = OpId::new();
+	async {
+		let protocol = p2p.peer_protocol(identity).await;
+		let stream = p2p
+			.new_stream(&peer, P2POperation::File)
+			.await
+			.map_err(|err| {
+				p2p.connect_failed(identity, P2POperation::File, &err);
+				RequestFileError::Connecting(err.to_string())
+			})?;
+		let mut stream = p2p.metrics.instrument(P2POperation::File, stream);
+		let header = HeaderFileBatch {
+			library_id,
+			file_path_ids,
+		};
+		stream
+			.write_all(&Header::FileBatch(header.clone()).to_bytes_for(op_id, &protocol))
+			.await?;
+
+		receive_batch(&mut stream, &header.file_path_ids).await
+	}
+	.instrument(op_id.span(identity, P2POperation::File))
+	.await
+}
+
+/// Split a batch response back into the files that were requested.
+pub(crate) async fn receive_batch(
+	stream: &mut (impl AsyncRead + Unpin),
+	file_path_ids: &[Uuid],
+) -> Result<Vec<(Uuid, BatchFile)>, RequestFileError> {
+	match stream.read_u8().await? {
+		RESPONSE_OK => {}
+		RESPONSE_ERR => return Err(RequestFileError::from_stream(stream).await),
+		d => return Err(RequestFileError::InvalidResponse(d)),
+	}
+
+	let mut total = 0u64;
+	let mut files = Vec::with_capacity(file_path_ids.len());
+	for expected in file_path_ids {
+		let id = decode::uuid(stream)
+			.await
+			.map_err(|_| RequestFileError::InvalidBatchResponse)?;
+		if id != *expected {
+			return Err(RequestFileError::InvalidBatchResponse);
+		}
+
+		let file = match stream.read_u8().await? {
+			BATCH_FOUND => {
+				let size = stream.read_u64_le().await?;
+				// Checked before adding, as the size comes from the peer
+				if size > MAX_BATCH_BYTES - total {
+					return Err(RequestFileError::InvalidBatchResponse);
+				}
+				total += size;
+
+				let mut buf = vec![0; size as usize];
+				stream.read_exact(&mut buf).await?;
+				BatchFile::Found(buf)
+			}
+			BATCH_NOT_FOUND => BatchFile::NotFound,
+			BATCH_SKIPPED => BatchFile::Skipped,
+			d => return Err(RequestFileError::InvalidResponse(d)),
+		};
+		files.push((id, file));
+	}
+
+	Ok(files)
+}
+
+pub(crate) async fn receiver(
+	node: &Arc<Node>,
+	p2p: &Arc<P2PManager>,
+	header: HeaderFile,
+	mut stream: UnicastStream,
+) -> Result<(), P2PError> {
+	let remote = stream.remote_identity();
+	debug!(
+		"Received file request for {} from peer '{remote}'",
+		header.target
+	);
+
+	let library = get_library(node, p2p, header.library_id, remote).await;
+
+	log_if_throttled(p2p, remote);
+	// Held until we are done sending the file
+	let _slot = p2p.file_serve_limiter.acquire().await;
+	let file = match library {
+		Ok((library, access)) => match &header.target {
+			FileTarget::Id(file_path_id) => open_file(&library, &access, *file_path_id).await,
+			FileTarget::Path {
+				location_pub_id,
+				relative_path,
+			} => open_file_by_path(&library, &access, *location_pub_id, relative_path).await,
+		},
+		Err(err) => Err(err),
+	};
+
+	let file = match file {
+		Ok(file) => file,
+		Err(err) => {
+			debug!("Rejecting file request from '{remote}': {err}");
+
+			stream
+				.write_all(&err.to_bytes())
+				.await
+				.map_err(P2PError::io("rejecting file request"))?;
+			return stream
+				.flush()
+				.await
+				.map_err(P2PError::io("rejecting file request"));
+		}
+	};
+
+	serve_file(
+		file,
+		header.range,
+		header.cas_id.is_some(),
+		&p2p.file_serve_limiter,
+		&mut stream,
+	)
+	.await
+	.map_err(P2PError::io("sending file"))
+}
+
+pub(crate) async fn batch_receiver(
+	node: &Arc<Node>,
+	p2p: &Arc<P2PManager>,
+	header: HeaderFileBatch,
+	mut stream: UnicastStream,
+) -> Result<(), P2PError> {
+	let remote = stream.remote_identity();
+	debug!(
+		"Received request for {} files from peer '{remote}'",
+		header.file_path_ids.len()
+	);
+
+	let (library, access) = match get_library(node, p2p, header.library_id, remote).await {
+		Ok(found) => found,
+		Err(err) => {
+			debug!("Rejecting file batch request from '{remote}': {err}");
+
+			stream
+				.write_all(&err.to_bytes())
+				.await
+				.map_err(P2PError::io("rejecting file batch request"))?;
+			return stream
+				.flush()
+				.await
+				.map_err(P2PError::io("rejecting file batch request"));
+		}
+	};
+
+	log_if_throttled(p2p, remote);
+	// Held until we are done sending every file
+	let _slot = p2p.file_serve_limiter.acquire().await;
+	serve_batch(
+		&header.file_path_ids,
+		&p2p.file_serve_limiter,
+		&mut stream,
+		|id| {
+			let (library, access) = (library.clone(), access.clone());
+			async move { open_file(&library, &access, id).await.ok() }
+		},
+	)
+	.await
+	.map_err(P2PError::io("sending file batch"))
+}
+
+/// Which of a library's files a peer is allowed to request
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FileAccess {
+	/// The peer is an instance of the library so it can read any of its files
+	Instance,
+	/// Only the files in these locations, which the node config shares with everyone
+	Public(Vec<Uuid>),
+}
+
+impl FileAccess {
+	fn new(is_instance: bool, public_locations: &[Uuid]) -> Result<Self, RequestFileError> {
+		if is_instance {
+			Ok(Self::Instance)
+		} else if public_locations.is_empty() {
+			Err(RequestFileError::Unauthorized)
+		} else {
+			Ok(Self::Public(public_locations.to_vec()))
+		}
+	}
+
+	fn allows(&self, location_pub_id: Uuid) -> bool {
+		match self {
+			Self::Instance => true,
+			Self::Public(locations) => locations.contains(&location_pub_id),
+		}
+	}
+}
+
+fn log_if_throttled(p2p: &P2PManager, remote: RemoteIdentity) {
+	if p2p.file_serve_limiter.is_saturated() {
+		p2p.log().record(
+			remote,
+			ConnectionLogEvent::Throttled,
+			None,
+			Some("file request is waiting for other files to be served".into()),
+		);
+	}
+}
+
+async fn get_library(
+	node: &Arc<Node>,
+	p2p: &Arc<P2PManager>,
+	library_id: Uuid,
+	remote: RemoteIdentity,
+) -> Result<(Arc<Library>, FileAccess), RequestFileError> {
+	let library = node
+		.libraries
+		.get_library(&library_id)
+		.await
+		.ok_or(RequestFileError::LibraryNotFound)?;
+
+	// We only serve files to other instances of the library, unless their location is public
+	let access = FileAccess::new(
+		p2p.get_instance(&library_id, remote).is_some(),
+		&node.config.get().await.public_locations,
+	)?;
+
+	Ok((library, access))
+}
+
+async fn open_file(
+	library: &Library,
+	access: &FileAccess,
+	file_path_id: Uuid,
+) -> Result<File, RequestFileError> {
+	let file_path = library
+		.db
+		.file_path()
+		.find_unique(file_path::pub_id::equals(sd_utils::uuid_to_bytes(
+			file_path_id,
+		)))
+		.select(file_path_to_handle_p2p_serve_file::select())
+		.exec()
+		.await
+		.map_err(|err| {
+			error!("Failed to find file_path '{file_path_id}': {err}");
+			RequestFileError::FileNotFound
+		})?
+		.ok_or(RequestFileError::FileNotFound)?;
+
+	let location = maybe_missing(&file_path.location, "file_path.location")
+		.map_err(|_| RequestFileError::FileNotFound)?;
+	if !access.allows(sd_utils::from_bytes_to_uuid(&location.pub_id)) {
+		return Err(RequestFileError::Unauthorized);
+	}
+
+	let location_path = maybe_missing(&location.path, "file_path.location.path")
+		.map_err(|_| RequestFileError::FileNotFound)?;
+	let path = full_path_on_disk(
+		location_path,
+		&IsolatedFilePathData::try_from((location.id, &file_path))
+			.map_err(|_| RequestFileError::FileNotFound)?,
+		file_path.name_bytes.as_deref(),
+	);
+
+	File::open(&path).await.map_err(|err| {
+		debug!("Failed to open {path:?}: {err}");
+		RequestFileError::FileNotFound
+	})
+}
+
+async fn open_file_by_path(
+	library: &Library,
+	access: &FileAccess,
+	location_pub_id: Uuid,
+	relative_path: &str,
+) -> Result<File, RequestFileError> {
+	// Before looking it up so peers can't find out which locations exist
+	if !access.allows(location_pub_id) {
+		return Err(RequestFileError::Unauthorized);
+	}
+
+	let location = library
+		.db
+		.location()
+		.find_unique(location::pub_id::equals(sd_utils::uuid_to_bytes(
+			location_pub_id,
+		)))
+		.select(location::select!({ id path }))
+		.exec()
+		.await
+		.map_err(|err| {
+			error!("Failed to find location '{location_pub_id}': {err}");
+			RequestFileError::FileNotFound
+		})?
+		.ok_or(RequestFileError::FileNotFound)?;
+
+	let location_path = maybe_missing(&location.path, "location.path")
+		.map_err(|_| RequestFileError::FileNotFound)?;
+	let (path, canonical_path) =
+		resolve_in_location(Path::new(location_path), relative_path).await?;
+
+	// Only what the location indexed is served, not whatever else is in its directory
+	let iso_file_path = IsolatedFilePathData::new(location.id, location_path, &path, false)
+		.map_err(|_| RequestFileError::FileNotFound)?;
+	library
+		.db
+		.file_path()
+		.find_first(filter_existing_file_path_params(&iso_file_path))
+		.select(file_path::select!({ id }))
+		.exec()
+		.await
+		.map_err(|err| {
+			error!("Failed to find file_path for {path:?}: {err}");
+			RequestFileError::FileNotFound
+		})?
+		.ok_or(RequestFileError::FileNotFound)?;
+
+	// What was checked is opened, a symlink swapped in since can't lead it out of the location
+	File::open(&canonical_path).await.map_err(|err| {
+		debug!("Failed to open {canonical_path:?}: {err}");
+		RequestFileError::FileNotFound
+	})
+}
+
+/// The file at `relative_path` in the location, refusing paths which would leave it through `..` or a symlink.
+///
+/// Gives back the path within the location, to look the file up with, and its canonical path, which is the one to open.
+async fn resolve_in_location(
+	location_path: &Path,
+	relative_path: &str,
+) -> Result<(PathBuf, PathBuf), RequestFileError> {
+	// Materialized paths start with a separator but are still relative to the location
+	let mut path = location_path.to_path_buf();
+	for component in Path::new(relative_path.trim_start_matches(['/', '\\'])).components() {
+		match component {
+			Component::Normal(name) => path.push(name),
+			Component::CurDir => {}
+			Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+				return Err(RequestFileError::PathOutsideLocation)
+			}
+		}
+	}
+	if path == location_path {
+		return Err(RequestFileError::FileNotFound);
+	}
+
+	let canonicalize = |path: PathBuf| async move {
+		tokio::fs::canonicalize(&path).await.map_err(|err| {
+			debug!("Failed to resolve {path:?}: {err}");
+			RequestFileError::FileNotFound
+		})
+	};
+	let canonical_path = canonicalize(path.clone()).await?;
+	if !canonical_path.starts_with(canonicalize(location_path.to_path_buf()).await?) {
+		return Err(RequestFileError::PathOutsideLocation);
+	}
+
+	Ok((path, canonical_path))
+}
+
+/// Send the response preamble followed by the requested bytes of `file`, or the error frame if `range` doesn't fit in it.
+/// With `verify` the file's current `cas_id` is sent after the bytes.
+pub(crate) async fn serve_file(
+	mut file: impl AsyncRead + AsyncSeek + Unpin,
+	range: Option<Range<u64>>,
+	verify: bool,
+	limiter: &FileServeLimiter,
+	stream: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<()> {
+	let total_size = file.seek(io::SeekFrom::End(0)).await?;
+	let range = range.unwrap_or(0..total_size);
+
+	if range.start > range.end || range.end > total_size {
+		stream
+			.write_all(&RequestFileError::RangeOutOfBounds { total_size }.to_bytes())
+			.await?;
+		return stream.flush().await;
+	}
+
+	let len = range.end - range.start;
+	let mut preamble = vec![RESPONSE_OK];
+	preamble.extend_from_slice(&total_size.to_le_bytes());
+	preamble.extend_from_slice(&len.to_le_bytes());
+	stream.write_all(&preamble).await?;
+
+	// Hashed while sending so the `cas_id` is the one of the bytes the requester got.
+	// It covers the whole file and not just the range, so the parts it samples outside of it are read from the file.
+	let mut cas_id = verify.then(|| CasIdHasher::new(total_size));
+	if let Some(cas_id) = &mut cas_id {
+		cas_id.read_until(&mut file, range.start).await?;
+	}
+
+	file.seek(io::SeekFrom::Start(range.start)).await?;
+	let mut offset = range.start;
+	let sent = limiter
+		.copy(&mut (&mut file).take(len), stream, |chunk| {
+			if let Some(cas_id) = &mut cas_id {
+				cas_id.update(offset, chunk);
+			}
+			offset += chunk.len() as u64;
+		})
+		.await?;
+	if sent != len {
+		return Err(io::Error::new(
+			io::ErrorKind::UnexpectedEof,
+			format!("file shrunk while sending it, sent {sent} of {len} bytes"),
+		));
+	}
+
+	if let Some(cas_id) = cas_id {
+		let mut trailer = Vec::new();
+		encode::string(&mut trailer, &cas_id.finish(&mut file).await?);
+		stream.write_all(&trailer).await?;
+	}
+
+	stream.flush().await
+}
+
+/// Send each of `file_path_ids` in order, each with a preamble so the requester can tell them apart.
+pub(crate) async fn serve_batch<F, Fut>(
+	file_path_ids: &[Uuid],
+	limiter: &FileServeLimiter,
+	stream: &mut (impl AsyncWrite + Unpin),
+	mut open: F,
+) -> io::Result<()>
+where
+	F: FnMut(Uuid) -> Fut,
+	Fut: Future<Output = Option<File>>,
+{
+	stream.write_all(&[RESPONSE_OK]).await?;
+
+	let mut total = 0u64;
+	for id in file_path_ids {
+		let mut preamble = Vec::new();
+		encode::uuid(&mut preamble, id);
+
+		let Some(mut file) = open(*id).await else {
+			preamble.push(BATCH_NOT_FOUND);
+			stream.write_all(&preamble).await?;
+			continue;
+		};
+
+		let size = file.seek(io::SeekFrom::End(0)).await?;
+		if total + size > MAX_BATCH_BYTES {
+			preamble.push(BATCH_SKIPPED);
+			stream.write_all(&preamble).await?;
+			continue;
+		}
+		total += size;
+
+		preamble.push(BATCH_FOUND);
+		preamble.extend_from_slice(&size.to_le_bytes());
+		stream.write_all(&preamble).await?;
+
+		file.seek(io::SeekFrom::Start(0)).await?;
+		let sent = limiter.copy(&mut file.take(size), stream, |_| {}).await?;
+		if sent != size {
+			// The requester can't recover from a short file, so we give up on the whole batch
+			return Err(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				format!("file '{id}' shrunk while sending it, sent {sent} of {size} bytes"),
+			));
+		}
+	}
+
+	stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use crate::object::cas::generate_cas_id;
+
+	use super::*;
+
+	fn contents() -> Vec<u8> {
+		(0..1024).map(|i| (i % 251) as u8).collect()
+	}
+
+	async fn fetch(range: Option<Range<u64>>) -> Result<(u64, Vec<u8>), RequestFileError> {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("file.bin");
+		tokio::fs::write(&path, contents()).await.unwrap();
+
+		fetch_path(&path, range, None).await
+	}
+
+	async fn fetch_path(
+		path: &Path,
+		range: Option<Range<u64>>,
+		cas_id: Option<String>,
+	) -> Result<(u64, Vec<u8>), RequestFileError> {
+		let (mut server, client) = tokio::io::duplex(64);
+		let file = File::open(path).await.unwrap();
+		let verify = cas_id.is_some();
+		let serving = tokio::spawn(async move {
+			serve_file(
+				file,
+				range,
+				verify,
+				&FileServeLimiter::default(),
+				&mut server,
+			)
+			.await
+		});
+
+		let result = match RemoteFile::from_stream(client, cas_id).await {
+			Ok(mut remote) => {
+				let mut buf = Vec::new();
+				remote.read_to_end(&mut buf).await.unwrap();
+				assert_eq!(remote.len, buf.len() as u64);
+				let total_size = remote.total_size;
+				remote.finish().await.map(|()| (total_size, buf))
+			}
+			Err(err) => Err(err),
+		};
+		serving.await.unwrap().unwrap();
+		result
+	}
+
+	#[tokio::test]
+	async fn header_roundtrip() {
+		let by_path = FileTarget::Path {
+			location_pub_id: Uuid::new_v4(),
+			relative_path: "/photos/photo.jpg".to_string(),
+		};
+		for (target, range, cas_id) in [
+			(FileTarget::Id(Uuid::new_v4()), None, None),
+			(
+				FileTarget::Id(Uuid::new_v4()),
+				Some(100..200),
+				Some("abc".to_string()),
+			),
+			(by_path.clone(), None, None),
+			(by_path, Some(100..200), Some("abc".to_string())),
+		] {
+			let header = HeaderFile {
+				library_id: Uuid::new_v4(),
+				target,
+				range,
+				cas_id,
+			};
+			let bytes = Header::File(header.clone()).to_bytes();
+			let result = Header::from_stream(&mut bytes.as_slice()).await.unwrap();
+			assert_eq!(result, Header::File(header));
+		}
+	}
+
+	#[tokio::test]
+	async fn fetches_a_range() {
+		let (total_size, bytes) = fetch(Some(100..200)).await.unwrap();
+		assert_eq!(total_size, 1024);
+		assert_eq!(bytes, &contents()[100..200]);
+	}
+
+	#[tokio::test]
+	async fn fetches_the_whole_file() {
+		let (total_size, bytes) = fetch(None).await.unwrap();
+		assert_eq!(total_size, 1024);
+		assert_eq!(bytes, contents());
+	}
+
+	#[tokio::test]
+	async fn verifies_cas_id() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("file.bin");
+		tokio::fs::write(&path, contents()).await.unwrap();
+		let cas_id = generate_cas_id(&path, 1024).await.unwrap();
+
+		let (_, bytes) = fetch_path(&path, Some(100..200), Some(cas_id.clone()))
+			.await
+			.unwrap();
+		assert_eq!(bytes, &contents()[100..200]);
+
+		// The file changes after it was indexed
+		tokio::fs::write(&path, vec![0; 1024]).await.unwrap();
+
+		let Err(RequestFileError::ContentMismatch { expected, actual }) =
+			fetch_path(&path, None, Some(cas_id.clone())).await
+		else {
+			unreachable!();
+		};
+		assert_eq!(expected, cas_id);
+		assert_eq!(actual, generate_cas_id(&path, 1024).await.unwrap());
+
+		// Best effort fetches don't care
+		let (_, bytes) = fetch_path(&path, None, None).await.unwrap();
+		assert_eq!(bytes, vec![0; 1024]);
+	}
+
+	#[tokio::test]
+	async fn verifies_the_cas_id_of_large_files_from_any_range() {
+		// Large enough for the `cas_id` to only sample it
+		let contents = (0..300 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let size = contents.len() as u64;
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("file.bin");
+		tokio::fs::write(&path, &contents).await.unwrap();
+		let cas_id = generate_cas_id(&path, size).await.unwrap();
+
+		for range in [
+			None,
+			Some(0..10),
+			Some(5000..200_000),
+			Some(size - 10..size),
+		] {
+			let (_, bytes) = fetch_path(&path, range.clone(), Some(cas_id.clone()))
+				.await
+				.unwrap();
+			let range = range.unwrap_or(0..size);
+			assert_eq!(bytes, &contents[range.start as usize..range.end as usize]);
+		}
+	}
+
+	#[tokio::test]
+	async fn long_cas_ids_are_refused() {
+		let mut response = vec![RESPONSE_OK];
+		response.extend_from_slice(&0u64.to_le_bytes());
+		response.extend_from_slice(&0u64.to_le_bytes());
+		encode::string(&mut response, &"a".repeat(MAX_CAS_ID_LEN + 1));
+
+		let remote = RemoteFile::from_stream(response.as_slice(), Some("abc".to_string()))
+			.await
+			.unwrap();
+		assert!(matches!(
+			remote.finish().await,
+			Err(RequestFileError::InvalidResponse(RESPONSE_OK))
+		));
+	}
+
+	#[tokio::test]
+	async fn batch_reports_missing_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let ids = (0..5).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+		let missing = ids[2];
+		for (i, id) in ids.iter().enumerate() {
+			if *id != missing {
+				tokio::fs::write(dir.path().join(id.to_string()), vec![i as u8; 100 * i])
+					.await
+					.unwrap();
+			}
+		}
+
+		let (mut server, mut client) = tokio::io::duplex(64);
+		let path = dir.path().to_path_buf();
+		let requested = ids.clone();
+		let serving = tokio::spawn(async move {
+			serve_batch(
+				&requested,
+				&FileServeLimiter::default(),
+				&mut server,
+				|id| {
+					let path = path.join(id.to_string());
+					async move { File::open(path).await.ok() }
+				},
+			)
+			.await
+		});
+
+		let files = receive_batch(&mut client, &ids).await.unwrap();
+		serving.await.unwrap().unwrap();
+
+		assert_eq!(files.len(), 5);
+		for (i, (id, file)) in files.into_iter().enumerate() {
+			assert_eq!(id, ids[i]);
+			if id == missing {
+				assert_eq!(file, BatchFile::NotFound);
+			} else {
+				assert_eq!(file, BatchFile::Found(vec![i as u8; 100 * i]));
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn limits_concurrent_streams() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("file.bin");
+		tokio::fs::write(&path, contents()).await.unwrap();
+
+		let limiter = Arc::new(FileServeLimiter::default());
+		let max_active = Arc::new(AtomicUsize::new(0));
+
+		// Every slot is taken, so the requests all wait for one
+		let mut held = Vec::new();
+		for _ in 0..DEFAULT_FILE_SERVE_CONCURRENCY {
+			held.push(limiter.acquire().await);
+		}
+
+		let requests = (0..10)
+			.map(|_| {
+				let (limiter, max_active, path) =
+					(limiter.clone(), max_active.clone(), path.clone());
+				tokio::spawn(async move {
+					let _slot = limiter.acquire().await;
+					max_active.fetch_max(limiter.active(), Ordering::SeqCst);
+
+					// A small pipe so each request is streaming for a while
+					let (mut server, mut client) = tokio::io::duplex(16);
+					let file = File::open(&path).await.unwrap();
+					let reading = tokio::spawn(async move {
+						let mut buf = Vec::new();
+						client.read_to_end(&mut buf).await.unwrap();
+						buf
+					});
+					serve_file(file, None, false, &limiter, &mut server)
+						.await
+						.unwrap();
+					drop(server);
+					reading.await.unwrap()
+				})
+			})
+			.collect::<Vec<_>>();
+
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while limiter.queued() < 10 {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(limiter.active(), DEFAULT_FILE_SERVE_CONCURRENCY);
+		drop(held);
+
+		for request in futures::future::join_all(requests).await {
+			assert_eq!(request.unwrap(), contents());
+		}
+		assert!(max_active.load(Ordering::SeqCst) <= DEFAULT_FILE_SERVE_CONCURRENCY);
+		assert_eq!(limiter.active(), 0);
+		assert_eq!(limiter.queued(), 0);
+	}
+
+	#[tokio::test]
+	async fn throttles_reads() {
+		let limiter = FileServeLimiter::default();
+		limiter
+			.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.bytes_per_sec = Some(10 * 1024);
+
+		let start = std::time::Instant::now();
+		let mut out = Vec::new();
+		limiter
+			.copy(&mut vec![0u8; 5 * 1024].as_slice(), &mut out, |_| {})
+			.await
+			.unwrap();
+		assert_eq!(out.len(), 5 * 1024);
+		assert!(start.elapsed() >= Duration::from_millis(400));
+	}
+
+	#[tokio::test]
+	async fn batch_header_is_capped() {
+		let header = HeaderFileBatch {
+			library_id: Uuid::new_v4(),
+			file_path_ids: (0..MAX_BATCH_FILES + 1).map(|_| Uuid::new_v4()).collect(),
+		};
+		let bytes = header.to_bytes();
+		assert!(HeaderFileBatch::from_stream(&mut bytes.as_slice())
+			.await
+			.is_err());
+	}
+
+	#[tokio::test]
+	async fn only_instances_can_request_private_files() {
+		let public = Uuid::new_v4();
+
+		// A peer which shares no library with us and nothing is public
+		assert!(matches!(
+			FileAccess::new(false, &[]),
+			Err(RequestFileError::Unauthorized)
+		));
+
+		let stranger = FileAccess::new(false, &[public]).unwrap();
+		assert!(stranger.allows(public));
+		assert!(!stranger.allows(Uuid::new_v4()));
+
+		let instance = FileAccess::new(true, &[]).unwrap();
+		assert!(instance.allows(Uuid::new_v4()));
+	}
+
+	#[tokio::test]
+	async fn batch_overflowing_the_cap_is_refused() {
+		let ids = [Uuid::new_v4(), Uuid::new_v4()];
+
+		// A responder claiming a huge second file, which would wrap around the total
+		let mut response = vec![RESPONSE_OK];
+		encode::uuid(&mut response, &ids[0]);
+		response.push(BATCH_FOUND);
+		response.extend_from_slice(&4u64.to_le_bytes());
+		response.extend_from_slice(b"test");
+		encode::uuid(&mut response, &ids[1]);
+		response.push(BATCH_FOUND);
+		response.extend_from_slice(&u64::MAX.to_le_bytes());
+
+		assert!(matches!(
+			receive_batch(&mut response.as_slice(), &ids).await,
+			Err(RequestFileError::InvalidBatchResponse)
+		));
+	}
+
+	#[tokio::test]
+	async fn unauthorized_is_reported_to_the_requester() {
+		let bytes = RequestFileError::Unauthorized.to_bytes();
+		assert!(matches!(
+			RemoteFile::from_stream(bytes.as_slice(), None).await,
+			Err(RequestFileError::Unauthorized)
+		));
+
+		let mut batch = bytes.as_slice();
+		assert!(matches!(
+			receive_batch(&mut batch, &[Uuid::new_v4()]).await,
+			Err(RequestFileError::Unauthorized)
+		));
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn fetches_by_path_within_an_indexed_location() {
+		use crate::{
+			library::LibraryName,
+			location::{light_scan_location, LocationCreateArgs},
+			p2p::P2PTransport,
+			Env,
+		};
+
+		let dir = tempfile::tempdir().unwrap();
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(Default::default()),
+		)
+		.await
+		.unwrap();
+		let library = node
+			.libraries
+			.create(LibraryName::new("Test").unwrap(), None, &node)
+			.await
+			.unwrap();
+
+		let root = tempfile::tempdir().unwrap();
+		tokio::fs::create_dir(root.path().join("photos"))
+			.await
+			.unwrap();
+		tokio::fs::write(root.path().join("photos/photo.jpg"), contents())
+			.await
+			.unwrap();
+		let location = LocationCreateArgs {
+			path: root.path().to_path_buf(),
+			dry_run: false,
+			indexer_rules_ids: vec![],
+		}
+		.create(&node, &library)
+		.await
+		.unwrap()
+		.unwrap();
+		for sub_path in ["", "photos"] {
+			light_scan_location(node.clone(), library.clone(), location.clone(), sub_path)
+				.await
+				.unwrap();
+		}
+		let location_pub_id = sd_utils::from_bytes_to_uuid(&location.pub_id);
+		let open = |access, relative_path| {
+			let library = &library;
+			async move { open_file_by_path(library, &access, location_pub_id, relative_path).await }
+		};
+
+		for relative_path in ["/photos/photo.jpg", "photos/./photo.jpg"] {
+			let file = open(FileAccess::Instance, relative_path).await.unwrap();
+			let (mut server, mut client) = tokio::io::duplex(64);
+			let limiter = FileServeLimiter::default();
+			let serving = async {
+				serve_file(file, None, false, &limiter, &mut server)
+					.await
+					.unwrap();
+				drop(server);
+			};
+			let mut response = vec![];
+			tokio::join!(serving, async {
+				client.read_to_end(&mut response).await.unwrap();
+			});
+			assert!(response.ends_with(&contents()), "{relative_path}");
+		}
+
+		// Only what the location indexed is served
+		tokio::fs::write(root.path().join("unindexed.txt"), b"hello")
+			.await
+			.unwrap();
+		assert!(matches!(
+			open(FileAccess::Instance, "/unindexed.txt").await,
+			Err(RequestFileError::FileNotFound)
+		));
+		assert!(matches!(
+			open(FileAccess::Instance, "/photos/missing.jpg").await,
+			Err(RequestFileError::FileNotFound)
+		));
+
+		// Any `..` is refused, even if it would end up in the location again
+		let outside = tempfile::tempdir().unwrap();
+		tokio::fs::write(outside.path().join("secret"), b"secret")
+			.await
+			.unwrap();
+		for relative_path in [
+			"../secret",
+			"/photos/../../secret",
+			"photos/../photos/photo.jpg",
+		] {
+			assert!(
+				matches!(
+					open(FileAccess::Instance, relative_path).await,
+					Err(RequestFileError::PathOutsideLocation)
+				),
+				"{relative_path}"
+			);
+		}
+
+		// A symlink in the location doesn't give access to what it points to
+		#[cfg(unix)]
+		{
+			std::os::unix::fs::symlink(outside.path().join("secret"), root.path().join("link"))
+				.unwrap();
+			assert!(matches!(
+				open(FileAccess::Instance, "link").await,
+				Err(RequestFileError::PathOutsideLocation)
+			));
+		}
+
+		// Peers which aren't instances only reach the locations shared with them
+		assert!(matches!(
+			open(
+				FileAccess::Public(vec![Uuid::new_v4()]),
+				"/photos/photo.jpg"
+			)
+			.await,
+			Err(RequestFileError::Unauthorized)
+		));
+
+		// The requester can tell it apart from the other rejections
+		let bytes = RequestFileError::PathOutsideLocation.to_bytes();
+		assert!(matches!(
+			RemoteFile::from_stream(bytes.as_slice(), None).await,
+			Err(RequestFileError::PathOutsideLocation)
+		));
+
+		node.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn overlong_paths_are_refused_before_being_read() {
+		let header = HeaderFile {
+			library_id: Uuid::new_v4(),
+			target: FileTarget::Path {
+				location_pub_id: Uuid::new_v4(),
+				relative_path: "a/".repeat(MAX_RELATIVE_PATH_LEN),
+			},
+			range: None,
+			cas_id: None,
+		};
+		let bytes = header.to_bytes();
+		assert!(matches!(
+			HeaderFile::from_stream(&mut bytes.as_slice(), true).await,
+			Err(decode::Error::TooLong { .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn rejects_range_past_eof() {
+		let Err(RequestFileError::RangeOutOfBounds { total_size }) = fetch(Some(1000..2000)).await
+		else {
+			unreachable!();
+		};
+		assert_eq!(total_size, 1024);
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn a_missing_library_is_reported_to_the_requester() {
+		use crate::{p2p::P2PTransport, Env};
+		use sd_p2p::MemoryNetwork;
+
+		let network = MemoryNetwork::default();
+		let (dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (other, _) = Node::new_with_p2p_transport(
+			other_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+
+		// The other node advertises the library, eg. it was deleted since, but doesn't have it
+		let library_id = Uuid::new_v4();
+		let identity = other.p2p.p2p.remote_identity();
+		other
+			.p2p
+			.p2p
+			.metadata_mut()
+			.insert(library_id.to_string(), Uuid::new_v4().to_string());
+		tokio::time::timeout(Duration::from_secs(5), async {
+			while node.p2p.get_instance(&library_id, identity).is_none() {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		let to = dir.path().join("downloaded");
+		let err = download_file(&node.p2p, identity, library_id, Uuid::new_v4(), None, &to)
+			.await
+			.unwrap_err();
+		assert!(matches!(err, P2PError::LibraryNotFound), "{err:?}");
+		assert!(!to.exists());
+	}
+}
@@ -1,344 +1,3378 @@
 use std::{
 	borrow::Cow,
-	path::PathBuf,
+	cmp::Reverse,
+	collections::{HashMap, VecDeque},
+	ffi::OsString,
+	io,
+	path::{Path, PathBuf},
 	sync::{
 		atomic::{AtomicBool, Ordering},
-		Arc, PoisonError,
+		Arc, Mutex, PoisonError,
 	},
 	time::Duration,
 };
 
-use crate::p2p::{Header, P2PEvent, P2PManager};
+use crate::{
+	library::Library,
+	node::config::NodeConfig,
+	p2p::{
+		display_name, ConnectionLogEvent, Error as P2PError, Header, OpId, P2PEvent,
+		P2PEventsReceiver, P2PManager, P2POperation, PeerMetadata, SpacedropDirection,
+		SpacedropHistoryEntry, SpacedropKind, SpacedropOutcome,
+	},
+	volume::available_space_at,
+	Node,
+};
+use chrono::{DateTime, Utc};
 use futures::future::join_all;
-use sd_p2p::{RemoteIdentity, UnicastStream};
-use sd_p2p_block::{BlockSize, Range, SpaceblockRequest, SpaceblockRequests, Transfer};
+use sd_file_path_helper::{file_path_to_spacedrop, full_path_on_disk, IsolatedFilePathData};
+use sd_p2p::{Peer, RemoteIdentity, UnicastStream, P2P};
+use sd_p2p_block::{
+	is_precompressed, BlockSize, Compression, Range, SpaceblockRequest, SpaceblockRequests,
+	Transfer, TransferUnit, COMPRESSION_SAMPLE_SIZE, MAX_LEGACY_SPACEDROP_FILES,
+	MAX_SPACEDROP_FILES,
+};
+use sd_p2p_proto::{decode, encode};
+use sd_prisma::prisma::{file_path, location, object};
+use serde::Serialize;
+use specta::Type;
+use thiserror::Error;
 use tokio::{
-	fs::{create_dir_all, File},
-	io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-	sync::oneshot,
-	time::{sleep, Instant},
+	fs::{self, create_dir_all, File, OpenOptions},
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+	sync::{oneshot, Notify},
+	time::{sleep_until, Instant},
 };
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
 use uuid::Uuid;
 
 /// The amount of time to wait for a Spacedrop request to be accepted or rejected before it's automatically rejected
 pub(crate) const SPACEDROP_TIMEOUT: Duration = Duration::from_secs(60);
 
-// TODO: Proper error handling
-pub async fn spacedrop(
-	p2p: Arc<P2PManager>,
-	identity: RemoteIdentity,
-	paths: Vec<PathBuf>,
-) -> Result<Uuid, ()> {
-	if paths.is_empty() {
-		return Err(());
+/// Keep-alives from the receiver can extend the deadline of a Spacedrop request but never past this
+pub(crate) const SPACEDROP_MAX_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The shortest timeout which can be configured, so the user has time to answer and the prompt to keep it alive
+pub const SPACEDROP_MIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sent by the receiver in place of the accept/reject byte while the prompt is still open
+const KEEP_ALIVE: u8 = 2;
+
+/// Sent by the receiver in place of the accept/reject byte when no frontend is open to accept the Spacedrop
+const RECEIVER_UNAVAILABLE: u8 = 3;
+
+/// Sent by the receiver in place of the accept byte when it takes up the compression the sender proposed
+const ACCEPTED_COMPRESSED: u8 = 4;
+
+/// Sent by the receiver in place of the accept/reject byte when it's receiving as many Spacedrops as it allows, see `p2p_stream_budgets`
+pub(crate) const RECEIVER_BUSY: u8 = 5;
+
+/// Why the Spacedrop failed when the receiver had no frontend open
+pub(crate) const RECEIVER_UNAVAILABLE_REASON: &str = "the receiving device has no one to accept it";
+
+/// Why the Spacedrop failed when the other device cancelled it
+const CANCELLED_BY_PEER_REASON: &str = "the other device cancelled the Spacedrop";
+
+/// Why the Spacedrop failed when the receiver was busy with others
+pub(crate) const RECEIVER_BUSY_REASON: &str = "the receiving device is busy with other Spacedrops";
+
+/// How often the sender is told a request held for a frontend is still there, well within its timeout
+const UNATTENDED_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Text larger than this is refused by both the sender and the receiver
+pub(crate) const MAX_TEXT_LEN: u64 = 1024 * 1024;
+
+/// Text up to this size is handed straight back to the frontend (for the clipboard), anything larger is saved as a `.txt` file
+const TEXT_INLINE_THRESHOLD: u64 = 64 * 1024;
+
+/// How much of the text is shown in the accept prompt
+const TEXT_PREVIEW_CHARS: usize = 100;
+
+/// The longest preview in bytes the receiver reads, as a character takes up to 4 bytes in UTF-8
+pub(crate) const MAX_TEXT_PREVIEW_LEN: usize = TEXT_PREVIEW_CHARS * 4;
+
+/// The highest suffix we will try (`name (N).ext`) before giving up on finding a free filename
+const MAX_COLLISION_SUFFIX: usize = 1000;
+
+/// What is being Spacedropped.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpacedropPayload {
+	Files(SpaceblockRequests),
+	Text { id: Uuid, preview: String, len: u64 },
+}
+
+impl SpacedropPayload {
+	pub fn id(&self) -> Uuid {
+		match self {
+			Self::Files(req) => req.id,
+			Self::Text { id, .. } => *id,
+		}
 	}
+}
 
-	let (files, requests): (Vec<_>, Vec<_>) = join_all(paths.into_iter().map(|path| async move {
-		let file = File::open(&path).await?;
-		let metadata = file.metadata().await?;
-		let name = path
-			.file_name()
-			.map(|v| v.to_string_lossy())
-			.unwrap_or(Cow::Borrowed(""))
-			.to_string();
+/// The options the user picked when accepting a Spacedrop.
+#[derive(Debug)]
+pub(crate) struct SpacedropAccept {
+	pub(crate) path: PathBuf,
+	/// Replace existing files instead of picking a free `name (N).ext` filename
+	pub(crate) overwrite: bool,
+	/// Apply the sender's Unix permissions. This is opt-in as they don't always make sense on another machine.
+	pub(crate) apply_permissions: bool,
+	/// Reports back to the caller whether the Spacedrop could be accepted.
+	/// Small text payloads are returned through this instead of being saved to disk.
+	pub(crate) result_tx: oneshot::Sender<Result<Option<String>, SpacedropAcceptError>>,
+}
 
-		Ok((
-			(path, file),
-			SpaceblockRequest {
-				name,
-				size: metadata.len(),
-				range: Range::Full,
-			},
-		))
-	}))
-	.await
-	.into_iter()
-	.collect::<Result<Vec<_>, std::io::Error>>()
-	.map_err(|err| {
-		warn!("error opening file: '{err:?}'");
-		// TODO: Proper error type
-	})?
-	.into_iter()
-	.unzip();
+#[derive(Debug, Error)]
+pub enum SpacedropAcceptError {
+	#[error("destination {0:?} doesn't exist")]
+	DestinationNotFound(PathBuf),
+	#[error("destination {0:?} isn't writable")]
+	DestinationNotWritable(PathBuf),
+	#[error("not enough free space, '{required}' bytes are required but only '{available}' are available")]
+	InsufficientSpace { required: u64, available: u64 },
+}
 
-	let total_length: u64 = requests.iter().map(|req| req.size).sum();
+/// Why a Spacedrop couldn't be started
+#[derive(Debug, Error)]
+pub enum SpacedropError {
+	#[error("'{0}' isn't receiving Spacedrops from us")]
+	ReceivingDisabled(RemoteIdentity),
+	#[error(transparent)]
+	P2P(#[from] P2PError),
+	#[error("can't Spacedrop {0} files at once, the most is {MAX_SPACEDROP_FILES}")]
+	TooManyFiles(usize),
+	#[error("there is nothing to Spacedrop")]
+	Empty,
+	#[error("can't Spacedrop text of {0} bytes, the most is {MAX_TEXT_LEN}")]
+	TextTooLong(u64),
+}
 
-	let id = Uuid::new_v4();
-	debug!("({id}): starting Spacedrop with peer '{identity}");
-	let peer = p2p
-		.p2p
-		.peers()
-		.get(&identity)
-		.ok_or_else(|| {
-			debug!("({id}): failed to find connection method with '{identity}'");
-			// TODO: Proper error
-		})?
-		.clone();
+/// The directory the files of a Spacedrop are going to be saved into.
+fn destination_dir(path: &Path, path_is_dir: bool) -> PathBuf {
+	if path_is_dir {
+		path.to_path_buf()
+	} else {
+		path.parent().map(Path::to_path_buf).unwrap_or_default()
+	}
+}
 
-	let mut stream = peer.new_stream().await.map_err(|err| {
-		debug!("({id}): failed to connect to '{identity}': {err:?}");
-		// TODO: Proper error
-	})?;
+/// Check we can actually save `required` bytes into `dir` before telling the sender to start.
+///
+/// `available` is the free space on the volume `dir` is on. When it's unknown we skip that check.
+pub(crate) async fn preflight(
+	dir: &Path,
+	required: u64,
+	available: Option<u64>,
+) -> Result<(), SpacedropAcceptError> {
+	match fs::metadata(dir).await {
+		Ok(metadata) if metadata.is_dir() => {}
+		_ => return Err(SpacedropAcceptError::DestinationNotFound(dir.to_path_buf())),
+	}
 
-	tokio::spawn(async move {
-		debug!("({id}): connected, sending header");
-		let header = Header::Spacedrop(SpaceblockRequests {
-			id,
-			block_size: BlockSize::from_size(total_length),
-			requests,
-		});
-		if let Err(err) = stream.write_all(&header.to_bytes()).await {
-			debug!("({id}): failed to send header: {err}");
-			return;
+	// Permission bits don't tell the full story (ACLs, read-only mounts, etc) so just try it
+	let probe = dir.join(format!(".spacedrop-{}", Uuid::new_v4()));
+	match OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.open(&probe)
+		.await
+	{
+		Ok(_) => {
+			fs::remove_file(&probe).await.ok();
 		}
-		let Header::Spacedrop(requests) = header else {
-			unreachable!();
-		};
+		Err(_) => {
+			return Err(SpacedropAcceptError::DestinationNotWritable(
+				dir.to_path_buf(),
+			))
+		}
+	}
 
-		debug!("({id}): waiting for response");
-		let result = tokio::select! {
-		  result = stream.read_u8() => result,
-		  // Add 5 seconds incase the user responded on the deadline and slow network
-		   _ = sleep(SPACEDROP_TIMEOUT + Duration::from_secs(5)) => {
-				debug!("({id}): timed out, cancelling");
-				p2p.events.send(P2PEvent::SpacedropTimedOut { id }).ok();
-				return;
-			},
-		};
+	match available {
+		Some(available) if required > available => Err(SpacedropAcceptError::InsufficientSpace {
+			required,
+			available,
+		}),
+		_ => Ok(()),
+	}
+}
 
-		match result {
-			Ok(0) => {
-				debug!("({id}): Spacedrop was rejected from peer '{identity}'");
-				p2p.events.send(P2PEvent::SpacedropRejected { id }).ok();
-				return;
+/// Sent by the receiver once all files have been written so the sender knows where everything ended up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SpacedropCompletion {
+	pub(crate) saved_paths: Vec<PathBuf>,
+}
+
+impl SpacedropCompletion {
+	pub(crate) async fn from_stream(
+		stream: &mut (impl AsyncRead + Unpin),
+	) -> Result<Self, decode::Error> {
+		let len = stream.read_u32_le().await?;
+		if len > MAX_SPACEDROP_FILES {
+			return Err(decode::Error::TooLong {
+				len: len as usize,
+				max: MAX_SPACEDROP_FILES as usize,
+			});
+		}
+
+		let mut saved_paths = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			saved_paths.push(PathBuf::from(decode::string(stream).await?));
+		}
+
+		Ok(Self { saved_paths })
+	}
+
+	pub(crate) fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = (self.saved_paths.len() as u32).to_le_bytes().to_vec();
+		for path in &self.saved_paths {
+			encode::string(&mut buf, &path.to_string_lossy());
+		}
+		buf
+	}
+}
+
+/// How many Spacedrops we send to a single peer at once, the rest are queued.
+pub(crate) fn spacedrop_parallelism(config: &NodeConfig) -> usize {
+	config.spacedrop_parallelism.unwrap_or(1).max(1) as usize
+}
+
+/// Queues outgoing Spacedrops so only a limited amount are sent to a single peer at once.
+#[derive(Debug, Default)]
+pub(crate) struct SpacedropQueue {
+	peers: Mutex<HashMap<RemoteIdentity, PeerQueue>>,
+}
+
+#[derive(Debug, Default)]
+struct PeerQueue {
+	active: usize,
+	waiting: VecDeque<(Uuid, oneshot::Sender<SpacedropSlot>)>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Enqueued {
+	Ready(SpacedropSlot),
+	Queued {
+		/// 1 is the next Spacedrop to start
+		position: u32,
+		/// Resolves once it's our turn, or errors if we were cancelled while waiting
+		rx: oneshot::Receiver<SpacedropSlot>,
+	},
+}
+
+/// A running Spacedrop to a peer. Dropping this hands the slot to the next queued Spacedrop.
+#[derive(Debug)]
+pub(crate) struct SpacedropSlot {
+	queue: Arc<SpacedropQueue>,
+	identity: Option<RemoteIdentity>,
+}
+
+impl Drop for SpacedropSlot {
+	fn drop(&mut self) {
+		if let Some(identity) = self.identity.take() {
+			self.queue.release(identity);
+		}
+	}
+}
+
+impl SpacedropQueue {
+	pub(crate) fn enqueue(
+		self: &Arc<Self>,
+		identity: RemoteIdentity,
+		id: Uuid,
+		parallelism: usize,
+	) -> Enqueued {
+		let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+		let peer = peers.entry(identity).or_default();
+
+		if peer.active < parallelism && peer.waiting.is_empty() {
+			peer.active += 1;
+			return Enqueued::Ready(SpacedropSlot {
+				queue: self.clone(),
+				identity: Some(identity),
+			});
+		}
+
+		let (tx, rx) = oneshot::channel();
+		peer.waiting.push_back((id, tx));
+		Enqueued::Queued {
+			position: peer.waiting.len() as u32,
+			rx,
+		}
+	}
+
+	/// Remove a Spacedrop which hasn't started yet. Returns `false` if it wasn't queued.
+	pub(crate) fn cancel(&self, id: Uuid) -> bool {
+		let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+		for peer in peers.values_mut() {
+			if let Some(i) = peer
+				.waiting
+				.iter()
+				.position(|(queued_id, _)| *queued_id == id)
+			{
+				// Dropping the sender wakes the waiting task up with an error
+				peer.waiting.remove(i);
+				return true;
 			}
-			Ok(1) => {}                     // Okay
-			Ok(_) => todo!(),               // TODO: Proper error
-			Err(err) => todo!("{:?}", err), // TODO: Proper error
 		}
 
-		let cancelled = Arc::new(AtomicBool::new(false));
-		p2p.spacedrop_cancellations
-			.lock()
-			.unwrap_or_else(PoisonError::into_inner)
-			.insert(id, cancelled.clone());
+		false
+	}
 
-		debug!("({id}): starting transfer");
-		let i = Instant::now();
+	fn release(self: &Arc<Self>, identity: RemoteIdentity) {
+		let mut slot = SpacedropSlot {
+			queue: self.clone(),
+			identity: None,
+		};
 
-		let mut transfer = Transfer::new(
-			&requests,
-			|percent| {
-				p2p.events
-					.send(P2PEvent::SpacedropProgress { id, percent })
-					.ok();
-			},
-			&cancelled,
-		);
+		loop {
+			let next = {
+				let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+				let Some(peer) = peers.get_mut(&identity) else {
+					return;
+				};
 
-		for (file_id, (path, file)) in files.into_iter().enumerate() {
-			debug!("({id}): transmitting '{file_id}' from '{path:?}'");
-			let file = BufReader::new(file);
-			if let Err(err) = transfer.send(&mut stream, file).await {
-				debug!("({id}): failed to send file '{file_id}': {err}");
-				// TODO: Error to frontend
-				// p2p.events
-				// 	.send(P2PEvent::SpacedropFailed { id, file_id })
-				// 	.ok();
-				return;
+				match peer.waiting.pop_front() {
+					Some((_, tx)) => tx,
+					None => {
+						peer.active = peer.active.saturating_sub(1);
+						if peer.active == 0 {
+							peers.remove(&identity);
+						}
+						return;
+					}
+				}
+			};
+
+			// The slot moves straight to the next Spacedrop so `active` stays the same
+			slot.identity = Some(identity);
+			match next.send(slot) {
+				Ok(()) => return,
+				Err(mut unused) => {
+					// They went away while we were handing it over, so try the one after them
+					unused.identity = None;
+					slot = unused;
+				}
 			}
 		}
+	}
+}
 
-		debug!("({id}): finished; took '{:?}", i.elapsed());
-	});
+/// Who and what a Spacedrop is about, attached to its terminal events and kept in the history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SpacedropTransfer {
+	pub(crate) op_id: OpId,
+	pub(crate) direction: SpacedropDirection,
+	pub(crate) identity: RemoteIdentity,
+	pub(crate) peer_name: Option<String>,
+	pub(crate) files: Vec<String>,
+	pub(crate) total_bytes: u64,
+	pub(crate) started_at: DateTime<Utc>,
+}
 
-	Ok(id)
+/// How a Spacedrop ended.
+#[derive(Debug, Clone)]
+pub(crate) enum SpacedropEnd {
+	// With where the receiver saved the files
+	Completed(Vec<PathBuf>),
+	Rejected,
+	TimedOut,
+	Failed(String),
+	// By the user of either device
+	Cancelled,
 }
 
-// TODO: Move these off the manager
-impl P2PManager {
-	pub async fn accept_spacedrop(&self, id: Uuid, path: String) {
-		if let Some(chan) = self
-			.spacedrop_pairing_reqs
-			.lock()
-			.unwrap_or_else(PoisonError::into_inner)
-			.remove(&id)
-		{
-			chan.send(Some(path))
-				.map_err(|err| {
-					warn!("error accepting Spacedrop '{id:?}': '{err:?}'");
-				})
-				.ok();
+impl SpacedropEnd {
+	/// No frontend was open on the receiving device to accept the Spacedrop
+	pub(crate) fn unavailable() -> Self {
+		Self::Failed(RECEIVER_UNAVAILABLE_REASON.to_string())
+	}
+
+	/// The receiving device was receiving as many Spacedrops as it allows
+	pub(crate) fn busy() -> Self {
+		Self::Failed(RECEIVER_BUSY_REASON.to_string())
+	}
+}
+
+/// The name `identity` currently advertises, if it's known
+pub(crate) fn peer_name(p2p: &P2P, identity: RemoteIdentity) -> Option<String> {
+	p2p.peers()
+		.get(&identity)
+		.and_then(|peer| {
+			PeerMetadata::from_hashmap(&peer.metadata())
+				.or_else(|err| err.into_partial().ok_or(()))
+				.ok()
+		})
+		.map(|metadata| metadata.name)
+}
+
+/// Asks the frontend whether to accept a Spacedrop from `identity`
+fn spacedrop_request(
+	config: &NodeConfig,
+	p2p: &P2P,
+	id: Uuid,
+	op_id: OpId,
+	identity: RemoteIdentity,
+	kind: SpacedropKind,
+	files: Vec<String>,
+) -> P2PEvent {
+	P2PEvent::SpacedropRequest {
+		id,
+		op_id,
+		identity,
+		peer_name: peer_name(p2p, identity).unwrap_or_else(|| "Unknown".into()),
+		display_name: display_name(config, p2p, identity).unwrap_or_else(|| "Unknown".into()),
+		kind,
+		files,
+	}
+}
+
+impl SpacedropTransfer {
+	pub(crate) fn rejected(self, id: Uuid) -> P2PEvent {
+		P2PEvent::SpacedropRejected {
+			id,
+			op_id: self.op_id,
+			identity: self.identity,
+			files: self.files,
+			total_bytes: self.total_bytes,
 		}
 	}
 
-	pub async fn reject_spacedrop(&self, id: Uuid) {
-		if let Some(chan) = self
-			.spacedrop_pairing_reqs
-			.lock()
-			.unwrap_or_else(PoisonError::into_inner)
-			.remove(&id)
-		{
-			chan.send(None)
-				.map_err(|err| {
-					warn!("error rejecting Spacedrop '{id:?}': '{err:?}'");
-				})
-				.ok();
+	pub(crate) fn timed_out(self, id: Uuid) -> P2PEvent {
+		P2PEvent::SpacedropTimedOut {
+			id,
+			op_id: self.op_id,
+			identity: self.identity,
+			files: self.files,
+			total_bytes: self.total_bytes,
 		}
 	}
 
-	pub async fn cancel_spacedrop(&self, id: Uuid) {
-		if let Some(cancelled) = self
-			.spacedrop_cancellations
+	pub(crate) fn completed(self, id: Uuid, saved_paths: Vec<PathBuf>) -> P2PEvent {
+		P2PEvent::SpacedropCompleted {
+			id,
+			op_id: self.op_id,
+			identity: self.identity,
+			files: self.files,
+			total_bytes: self.total_bytes,
+			saved_paths,
+		}
+	}
+
+	fn history_entry(self, id: Uuid, end: SpacedropEnd) -> SpacedropHistoryEntry {
+		let (outcome, reason, saved_paths) = match end {
+			SpacedropEnd::Completed(saved_paths) => {
+				(SpacedropOutcome::Completed, None, saved_paths)
+			}
+			SpacedropEnd::Rejected => (SpacedropOutcome::Rejected, None, vec![]),
+			SpacedropEnd::TimedOut => (SpacedropOutcome::TimedOut, None, vec![]),
+			SpacedropEnd::Failed(reason) => (SpacedropOutcome::Failed, Some(reason), vec![]),
+			SpacedropEnd::Cancelled => (SpacedropOutcome::Cancelled, None, vec![]),
+		};
+
+		SpacedropHistoryEntry {
+			id,
+			direction: self.direction,
+			identity: self.identity,
+			peer_name: self.peer_name,
+			files: self.files,
+			total_bytes: self.total_bytes,
+			outcome,
+			reason,
+			saved_paths,
+			started_at: self.started_at,
+			finished_at: Utc::now(),
+		}
+	}
+}
+
+/// The outgoing Spacedrops which haven't finished yet.
+#[derive(Debug, Default)]
+pub(crate) struct SpacedropTransfers {
+	transfers: Mutex<HashMap<Uuid, SpacedropTransfer>>,
+}
+
+/// Removes the transfer from [`SpacedropTransfers`] when dropped so every way a Spacedrop can end cleans it up.
+#[derive(Debug)]
+pub(crate) struct SpacedropTransferGuard {
+	transfers: Arc<SpacedropTransfers>,
+	id: Uuid,
+}
+
+impl Drop for SpacedropTransferGuard {
+	fn drop(&mut self) {
+		self.transfers
+			.transfers
 			.lock()
 			.unwrap_or_else(PoisonError::into_inner)
-			.remove(&id)
-		{
-			cancelled.store(true, Ordering::Relaxed);
-		}
+			.remove(&self.id);
 	}
 }
 
-pub(crate) async fn receiver(
-	this: &Arc<P2PManager>,
-	req: SpaceblockRequests,
-	mut stream: UnicastStream,
-) -> Result<(), ()> {
-	let id = req.id;
-	let (tx, rx) = oneshot::channel();
+impl SpacedropTransferGuard {
+	pub(crate) fn transfer(&self) -> Option<SpacedropTransfer> {
+		self.transfers.get(self.id)
+	}
+}
 
-	info!(
-		"({id}): received '{}' files from peer '{}' with block size '{:?}'",
-		req.requests.len(),
-		stream.remote_identity(),
-		req.block_size
-	);
-	this.spacedrop_pairing_reqs
-		.lock()
-		.unwrap_or_else(PoisonError::into_inner)
-		.insert(id, tx);
+impl SpacedropTransfers {
+	pub(crate) fn register(
+		self: &Arc<Self>,
+		id: Uuid,
+		transfer: SpacedropTransfer,
+	) -> SpacedropTransferGuard {
+		self.transfers
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(id, transfer);
 
-	if this
-		.events
-		.send(P2PEvent::SpacedropRequest {
+		SpacedropTransferGuard {
+			transfers: self.clone(),
 			id,
-			identity: stream.remote_identity(),
-			peer_name: "Unknown".into(),
-			// TODO: A better solution to this
-			// manager
-			// 	.get_discovered_peers()
-			// 	.await
-			// 	.into_iter()
-			// 	.find(|p| p.peer_id == event.peer_id)
-			// 	.map(|p| p.metadata.name)
-			// 	.unwrap_or_else(|| "Unknown".to_string()),
-			files: req
-				.requests
-				.iter()
-				.map(|req| req.name.clone())
-				.collect::<Vec<_>>(),
-		})
-		.is_err()
-	{
-		// No frontend's are active
+		}
+	}
 
-		// TODO: Implement this
-		error!("TODO: Outright reject Spacedrop");
+	pub(crate) fn get(&self, id: Uuid) -> Option<SpacedropTransfer> {
+		self.transfers
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(&id)
+			.cloned()
 	}
+}
 
-	tokio::select! {
-		_ = sleep(SPACEDROP_TIMEOUT) => {
-			info!("({id}): timeout, rejecting!");
+/// The timeout for Spacedrop requests configured by the user, bounded by [`SPACEDROP_MIN_TIMEOUT`] and [`SPACEDROP_MAX_TIMEOUT`].
+pub(crate) fn spacedrop_timeout(config: &NodeConfig) -> Duration {
+	config
+		.spacedrop_timeout_secs
+		.map(|secs| Duration::from_secs(secs.into()))
+		.unwrap_or(SPACEDROP_TIMEOUT)
+		.clamp(SPACEDROP_MIN_TIMEOUT, SPACEDROP_MAX_TIMEOUT)
+}
 
-			stream.write_all(&[0]).await.map_err(|err| {
-				error!("({id}): error reject bit: '{err:?}'");
-			})?;
-			stream.flush().await.map_err(|err| {
-				error!("({id}): error flushing reject bit: '{err:?}'");
-			})?;
-		}
-		file_path = rx => {
-			match file_path {
-				Ok(Some(file_path)) => {
-					info!("({id}): accepted saving to '{:?}'", file_path);
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SpacedropResponse {
+	Accepted,
+	/// Accepted, with the file data compressed as the sender proposed
+	AcceptedCompressed,
+	Rejected,
+	TimedOut,
+	Unavailable,
+	Busy,
+}
 
-					let cancelled = Arc::new(AtomicBool::new(false));
-					this.spacedrop_cancellations
-						.lock()
-						.unwrap_or_else(PoisonError::into_inner)
-						.insert(id, cancelled.clone());
+/// Wait for the receiver to accept or reject the Spacedrop.
+///
+/// Every keep-alive pushes the deadline out by `timeout` again, up until `hard_cap` has passed since we started waiting.
+pub(crate) async fn wait_for_response(
+	stream: &mut (impl AsyncRead + Unpin),
+	timeout: Duration,
+	hard_cap: Duration,
+) -> Result<SpacedropResponse, io::Error> {
+	let start = Instant::now();
+	let mut deadline = start + timeout;
 
-					stream.write_all(&[1]).await.map_err(|err| {
-						error!("({id}): error sending continuation bit: '{err:?}'");
+	loop {
+		let result = tokio::select! {
+			result = stream.read_u8() => result?,
+			_ = sleep_until(deadline) => return Ok(SpacedropResponse::TimedOut),
+		};
 
-						// TODO: Send error to the frontend
+		match result {
+			0 => return Ok(SpacedropResponse::Rejected),
+			1 => return Ok(SpacedropResponse::Accepted),
+			ACCEPTED_COMPRESSED => return Ok(SpacedropResponse::AcceptedCompressed),
+			RECEIVER_UNAVAILABLE => return Ok(SpacedropResponse::Unavailable),
+			RECEIVER_BUSY => return Ok(SpacedropResponse::Busy),
+			KEEP_ALIVE => deadline = (Instant::now() + timeout).min(start + hard_cap),
+			v => {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("invalid Spacedrop response '{v}'"),
+				))
+			}
+		}
+	}
+}
 
-						// TODO: make sure the other peer times out or we retry???
-					})?;
+/// Create the file we are going to write an incoming Spacedrop file into.
+///
+/// If `path` is already taken (and we aren't overwriting) we try `name (1).ext`, `name (2).ext`, etc.
+/// The `create_new` flag makes the existence check and the creation atomic so we can never clobber a file which appeared in the meantime.
+pub(crate) async fn create_unique_file(
+	path: &Path,
+	overwrite: bool,
+) -> Result<(PathBuf, File), io::Error> {
+	if overwrite {
+		return Ok((path.to_path_buf(), File::create(path).await?));
+	}
 
-					let names = req.requests.iter().map(|req| req.name.clone()).collect::<Vec<_>>();
-					let mut transfer = Transfer::new(&req, |percent| {
-						this.events.send(P2PEvent::SpacedropProgress { id, percent }).ok();
-					}, &cancelled);
-
-					let file_path = PathBuf::from(file_path);
-					let names_len = names.len();
-					for file_name in names {
-						 // When transferring more than 1 file we wanna join the incoming file name to the directory provided by the user
-						 let mut path = file_path.clone();
-						 if names_len != 1 {
-							// We know the `file_path` will be a directory so we can just push the file name to it
-							path.push(&file_name);
-						}
+	for i in 0..=MAX_COLLISION_SUFFIX {
+		let candidate = if i == 0 {
+			path.to_path_buf()
+		} else {
+			suffixed_path(path, i)
+		};
 
-						debug!("({id}): accepting '{file_name}' and saving to '{:?}'", path);
+		match OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(&candidate)
+			.await
+		{
+			Ok(file) => return Ok((candidate, file)),
+			Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+			Err(err) => return Err(err),
+		}
+	}
 
-						if let Some(parent) = path.parent() {
-						  create_dir_all(&parent).await.map_err(|err| {
-								error!("({id}): error creating parent directory '{parent:?}': '{err:?}'");
+	Err(io::Error::new(
+		io::ErrorKind::AlreadyExists,
+		format!(
+			"no free filename found for '{}' after {MAX_COLLISION_SUFFIX} attempts",
+			path.display()
+		),
+	))
+}
 
-								// TODO: Send error to the frontend
+/// The manifest entry for a file we are about to send.
+pub(crate) fn file_request(name: String, metadata: &std::fs::Metadata) -> SpaceblockRequest {
+	SpaceblockRequest {
+		name,
+		size: metadata.len(),
+		range: Range::Full,
+		modified_at: metadata.modified().ok(),
+		created_at: metadata.created().ok(),
+		#[cfg(unix)]
+		mode: Some(std::os::unix::fs::PermissionsExt::mode(
+			&metadata.permissions(),
+		)),
+		#[cfg(not(unix))]
+		mode: None,
+	}
+}
 
-								// TODO: Send error to remote peer
-							})?;
-						}
+/// The bits of a sender's file mode we apply, ie. read, write and execute for the owner, group and others.
+#[cfg(unix)]
+const PERMISSION_BITS: u32 = 0o777;
 
-						let f = File::create(&path).await.map_err(|err| {
-							error!("({id}): error creating file at '{path:?}': '{err:?}'");
+/// Restore the sender's timestamps (and optionally permissions) onto a file we received.
+///
+/// This only logs on failure as not every platform or filesystem supports all of them.
+pub(crate) async fn apply_file_metadata(
+	path: &Path,
+	req: &SpaceblockRequest,
+	apply_permissions: bool,
+) {
+	let path = path.to_path_buf();
+	let (modified_at, created_at, mode) = (req.modified_at, req.created_at, req.mode);
 
-							// TODO: Send error to the frontend
+	let result = tokio::task::spawn_blocking(move || {
+		let mut times = std::fs::FileTimes::new();
+		if let Some(modified_at) = modified_at {
+			times = times.set_modified(modified_at);
+		}
+		#[cfg(any(target_os = "macos", target_os = "windows"))]
+		{
+			#[cfg(target_os = "macos")]
+			use std::os::macos::fs::FileTimesExt;
+			#[cfg(target_os = "windows")]
+			use std::os::windows::fs::FileTimesExt;
 
-							// TODO: Send error to remote peer
-						})?;
-						let f = BufWriter::new(f);
-						if let Err(err) = transfer.receive(&mut stream, f).await {
-							error!("({id}): error receiving file '{file_name}': '{err:?}'");
+			if let Some(created_at) = created_at {
+				times = times.set_created(created_at);
+			}
+		}
+		#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+		let _ = created_at;
 
-							// TODO: Send error to frontend
+		if let Err(err) = std::fs::File::options()
+			.write(true)
+			.open(&path)
+			.and_then(|file| file.set_times(times))
+		{
+			warn!("failed to set timestamps on '{}': {err}", path.display());
+		}
 
-							break;
-						}
-					}
+		if !apply_permissions {
+			return;
+		}
 
-					info!("({id}): complete");
-				}
-				Ok(None) => {
-					info!("({id}): rejected");
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
 
-					stream.write_all(&[0]).await.map_err(|err| {
-					   error!("({id}): error sending rejection: '{err:?}'");
-					})?;
-					stream.flush().await.map_err(|err| {
-					   error!("({id}): error flushing rejection: '{err:?}'");
-					})?;
-				}
-				Err(_) => {
-					warn!("({id}): error with Spacedrop pairing request receiver!");
-				}
+			let Some(mode) = mode else {
+				return;
+			};
+			// Only the permission bits, a peer must not be able to hand us setuid, setgid or sticky files
+			let mode = mode & PERMISSION_BITS;
+			if let Err(err) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+			{
+				warn!("failed to set permissions on '{}': {err}", path.display());
 			}
 		}
-	};
+		#[cfg(not(unix))]
+		let _ = mode;
+	})
+	.await;
 
-	Ok(())
+	if let Err(err) = result {
+		error!("task applying file metadata panicked: {err:?}");
+	}
+}
+
+/// `IMG_0001.jpg` -> `IMG_0001 (i).jpg`
+fn suffixed_path(path: &Path, i: usize) -> PathBuf {
+	let mut name = OsString::from(path.file_stem().unwrap_or_default());
+	name.push(format!(" ({i})"));
+	if let Some(extension) = path.extension() {
+		name.push(".");
+		name.push(extension);
+	}
+
+	path.with_file_name(name)
+}
+
+/// The peer to Spacedrop to, without going any further when the mode it advertises leaves us out.
+/// The receiver checks again as its mode may have changed since, see [`crate::node::config::SpacedropMode`].
+async fn spacedrop_peer(
+	p2p: &P2PManager,
+	id: Uuid,
+	identity: RemoteIdentity,
+) -> Result<Arc<Peer>, SpacedropError> {
+	let peer = p2p
+		.p2p
+		.peers()
+		.get(&identity)
+		.ok_or_else(|| {
+			debug!("({id}): failed to find connection method with '{identity}'");
+			P2PError::Connection(format!("no connection method to '{identity}'"))
+		})?
+		.clone();
+
+	let is_contact = p2p.node_config.get().await.contacts.contains(&identity);
+	let accepts = PeerMetadata::from_hashmap(&peer.metadata())
+		.or_else(|err| err.into_partial().ok_or(()))
+		.map_or(true, |metadata| metadata.accepts_spacedrops(is_contact));
+	if !accepts {
+		debug!("({id}): '{identity}' isn't receiving Spacedrops from us");
+		return Err(SpacedropError::ReceivingDisabled(identity));
+	}
+
+	Ok(peer)
+}
+
+pub async fn spacedrop(
+	p2p: Arc<P2PManager>,
+	identity: RemoteIdentity,
+	paths: Vec<PathBuf>,
+) -> Result<Uuid, SpacedropError> {
+	if paths.is_empty() {
+		return Err(SpacedropError::Empty);
+	}
+	if paths.len() > MAX_SPACEDROP_FILES as usize {
+		return Err(SpacedropError::TooManyFiles(paths.len()));
+	}
+
+	// Each file is only opened once it's sent, so a large Spacedrop doesn't hold a descriptor for all of them
+	let (files, requests): (Vec<_>, Vec<_>) = join_all(paths.into_iter().map(|path| async move {
+		let metadata = fs::metadata(&path).await.map_err(|source| P2PError::Io {
+			context: format!("reading the metadata of {}", path.display()),
+			source,
+		})?;
+		let name = path
+			.file_name()
+			.map(|v| v.to_string_lossy())
+			.unwrap_or(Cow::Borrowed(""))
+			.to_string();
+
+		Ok((path, file_request(name, &metadata)))
+	}))
+	.await
+	.into_iter()
+	.collect::<Result<Vec<_>, P2PError>>()?
+	.into_iter()
+	.unzip();
+
+	let total_length: u64 = requests.iter().map(|req| req.size).sum();
+
+	let id = Uuid::new_v4();
+	debug!("({id}): starting Spacedrop with peer '{identity}");
+	let peer = spacedrop_peer(&p2p, id, identity).await?;
+
+	let op_id = OpId::new();
+	let registered = p2p.spacedrop_transfers.register(
+		id,
+		SpacedropTransfer {
+			op_id,
+			direction: SpacedropDirection::Sent,
+			identity,
+			peer_name: peer_name(&p2p.p2p, identity),
+			files: requests.iter().map(|req| req.name.clone()).collect(),
+			total_bytes: total_length,
+			started_at: Utc::now(),
+		},
+	);
+
+	let parallelism = spacedrop_parallelism(&p2p.node_config.get().await);
+	let enqueued = p2p.spacedrop_queue.enqueue(identity, id, parallelism);
+
+	tokio::spawn(
+		async move {
+			// Held until the transfer is over so the next Spacedrop to this peer can start
+			let _slot = match enqueued {
+				Enqueued::Ready(slot) => slot,
+				Enqueued::Queued { position, rx } => {
+					debug!("({id}): queued at position '{position}' behind other Spacedrops to '{identity}'");
+					p2p.events
+						.send(P2PEvent::SpacedropQueued {
+							id,
+							op_id,
+							position,
+						})
+						.ok();
+					p2p.log().record(
+						identity,
+						ConnectionLogEvent::Throttled,
+						None,
+						Some(format!("Spacedrop '{id}' is queued at position {position}")),
+					);
+
+					let Ok(slot) = rx.await else {
+						debug!("({id}): cancelled while queued");
+						if let Some(transfer) = registered.transfer() {
+							p2p.record_spacedrop(id, transfer, SpacedropEnd::Cancelled)
+								.await;
+						}
+						return;
+					};
+					slot
+				}
+			};
+
+			// Nodes from before segments and completions are sent each file on its own, with as little as they understood
+			let protocol = p2p.peer_protocol(identity).await;
+			let legacy = !protocol.is_current();
+			if legacy && requests.len() > MAX_LEGACY_SPACEDROP_FILES as usize {
+				let reason = format!(
+					"'{identity}' can't receive more than {MAX_LEGACY_SPACEDROP_FILES} files at once"
+				);
+				debug!("({id}): {reason}");
+				if let Some(transfer) = registered.transfer() {
+					p2p.finish_spacedrop(id, transfer, SpacedropEnd::Failed(reason))
+						.await;
+				}
+				return;
+			}
+
+			let mut stream = match p2p.new_stream(&peer, P2POperation::Spacedrop).await {
+				Ok(stream) => p2p
+					.bandwidth_limiter
+					.limit(p2p.metrics.instrument(P2POperation::Spacedrop, stream)),
+				Err(err) => {
+					debug!("({id}): failed to connect to '{identity}': {err:?}");
+					p2p.connect_failed(identity, P2POperation::Spacedrop, &err);
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(
+							id,
+							transfer,
+							SpacedropEnd::Failed(format!("failed to connect to '{identity}'")),
+						)
+						.await;
+					}
+					return;
+				}
+			};
+
+			// Only peers which told us they can decompress are offered compression, the rest are sent the files as they are
+			let proposed = match protocol.supports("spacedrop-zstd") {
+				true => propose_compression(&files, &requests).await,
+				false => Compression::None,
+			};
+			debug!("({id}): connected, sending header proposing '{proposed:?}' compression");
+			let header = Header::Spacedrop(SpacedropPayload::Files(SpaceblockRequests {
+				id,
+				block_size: BlockSize::from_size(total_length),
+				compression: proposed,
+				requests,
+			}));
+			if let Err(err) = stream
+				.write_all(&header.to_bytes_for(op_id, &protocol))
+				.await
+			{
+				debug!("({id}): failed to send header: {err}");
+				if let Some(transfer) = registered.transfer() {
+					p2p.finish_spacedrop(
+						id,
+						transfer,
+						SpacedropEnd::Failed(format!("failed to send the request: {err}")),
+					)
+					.await;
+				}
+				return;
+			}
+			let Header::Spacedrop(SpacedropPayload::Files(requests)) = header else {
+				unreachable!();
+			};
+
+			debug!("({id}): waiting for response");
+			let timeout = spacedrop_timeout(&p2p.node_config.get().await);
+			// Add 5 seconds incase the user responded on the deadline and slow network
+			let compression = match wait_for_response(
+				&mut stream,
+				timeout + Duration::from_secs(5),
+				SPACEDROP_MAX_TIMEOUT + Duration::from_secs(5),
+			)
+			.await
+			{
+				Ok(SpacedropResponse::Accepted) => Compression::None,
+				Ok(SpacedropResponse::AcceptedCompressed) => requests.compression,
+				Ok(SpacedropResponse::Rejected) => {
+					debug!("({id}): Spacedrop was rejected from peer '{identity}'");
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::Rejected)
+							.await;
+					}
+					return;
+				}
+				Ok(SpacedropResponse::TimedOut) => {
+					debug!("({id}): timed out, cancelling");
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::TimedOut)
+							.await;
+					}
+					return;
+				}
+				Ok(SpacedropResponse::Unavailable) => {
+					debug!("({id}): no one is there to accept it on '{identity}'");
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::unavailable())
+							.await;
+					}
+					return;
+				}
+				Ok(SpacedropResponse::Busy) => {
+					debug!("({id}): '{identity}' is busy with other Spacedrops");
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::busy())
+							.await;
+					}
+					return;
+				}
+				Err(err) => {
+					debug!("({id}): error waiting for response from '{identity}': {err:?}");
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(
+							id,
+							transfer,
+							SpacedropEnd::Failed(format!("failed to get a response: {err}")),
+						)
+						.await;
+					}
+					return;
+				}
+			};
+
+			let cancelled = Arc::new(AtomicBool::new(false));
+			p2p.spacedrop_cancellations
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.insert(id, cancelled.clone());
+
+			debug!("({id}): starting transfer with '{compression:?}' compression");
+			let i = Instant::now();
+
+			let mut transfer = Transfer::new(
+				&requests,
+				|percent| {
+					p2p.events
+						.send(P2PEvent::SpacedropProgress { id, op_id, percent })
+						.ok();
+				},
+				&cancelled,
+			)
+			.with_compression(compression);
+
+			async {
+				let units = match legacy {
+					true => TransferUnit::streamed(&requests.requests),
+					false => TransferUnit::plan(&requests.requests),
+				};
+				for unit in units {
+					let result = match unit {
+						TransferUnit::Streamed(file_id) => {
+							let path = &files[file_id];
+							debug!("({id}): transmitting '{file_id}' from '{path:?}'");
+							match File::open(path).await {
+								Ok(file) => transfer
+									.send(&mut stream, BufReader::new(file))
+									.await
+									.map_err(|err| {
+										format!("failed to send '{}': {err}", path.display())
+									}),
+								Err(err) => {
+									Err(format!("failed to open '{}': {err}", path.display()))
+								}
+							}
+						}
+						TransferUnit::Packed(file_ids) => {
+							debug!("({id}): transmitting '{file_ids:?}' in a segment");
+							let count = file_ids.len();
+							match read_packed(&files[file_ids]).await {
+								Ok(contents) => transfer
+									.send_segment(&mut stream, &contents)
+									.await
+									.map_err(|err| format!("failed to send {count} files: {err}")),
+								Err(reason) => Err(reason),
+							}
+						}
+					};
+
+					if let Err(reason) = result {
+						debug!("({id}): {reason}");
+						if let Some(transfer) = registered.transfer() {
+							p2p.finish_spacedrop(id, transfer, SpacedropEnd::Failed(reason))
+								.await;
+						}
+						return;
+					}
+				}
+
+				if cancelled.load(Ordering::Relaxed) {
+					debug!("({id}): cancelled; took '{:?}", i.elapsed());
+					if let Some(transfer) = registered.transfer() {
+						p2p.record_spacedrop(id, transfer, SpacedropEnd::Cancelled)
+							.await;
+					}
+					return;
+				}
+
+				// They never tell us where they saved the files
+				if legacy {
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::Completed(Vec::new()))
+							.await;
+					}
+					return;
+				}
+
+				match SpacedropCompletion::from_stream(&mut stream).await {
+					Ok(SpacedropCompletion { saved_paths }) => {
+						debug!("({id}): remote saved files to '{saved_paths:?}'");
+						if let Some(transfer) = registered.transfer() {
+							p2p.finish_spacedrop(
+								id,
+								transfer,
+								SpacedropEnd::Completed(saved_paths),
+							)
+							.await;
+						}
+					}
+					Err(err) => {
+						debug!("({id}): failed to read completion from remote: {err}");
+						if let Some(transfer) = registered.transfer() {
+							p2p.finish_spacedrop(
+								id,
+								transfer,
+								SpacedropEnd::Failed(format!(
+									"the receiving device didn't confirm it saved the files: {err}"
+								)),
+							)
+							.await;
+						}
+					}
+				}
+			}
+			.await;
+
+			debug!(
+				"({id}): sent {} bytes of files in {} bytes",
+				transfer.file_bytes(),
+				transfer.wire_bytes()
+			);
+			p2p.metrics.spacedrop_files(transfer.file_bytes());
+
+			p2p.spacedrop_cancellations
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.remove(&id);
+
+			debug!("({id}): finished; took '{:?}", i.elapsed());
+		}
+		.instrument(op_id.span(identity, P2POperation::Spacedrop)),
+	);
+
+	Ok(id)
+}
+
+/// The compression worth proposing for a Spacedrop, from a sample of its first file which isn't
+/// compressed already
+async fn propose_compression(files: &[PathBuf], requests: &[SpaceblockRequest]) -> Compression {
+	let Some((path, _)) = files
+		.iter()
+		.zip(requests)
+		.find(|(_, req)| !is_precompressed(&req.name))
+	else {
+		return Compression::None;
+	};
+
+	let mut sample = Vec::with_capacity(COMPRESSION_SAMPLE_SIZE);
+	let read = match File::open(path).await {
+		Ok(file) => {
+			file.take(COMPRESSION_SAMPLE_SIZE as u64)
+				.read_to_end(&mut sample)
+				.await
+		}
+		Err(err) => Err(err),
+	};
+	if let Err(err) = read {
+		debug!(
+			"failed to sample '{}' for compression: {err}",
+			path.display()
+		);
+		return Compression::None;
+	}
+
+	Compression::estimate(&sample)
+}
+
+/// The whole content of each of the small files packed in a segment, opening them one at a time
+async fn read_packed(files: &[PathBuf]) -> Result<Vec<Vec<u8>>, String> {
+	let mut contents = Vec::with_capacity(files.len());
+	for path in files {
+		contents.push(
+			fs::read(path)
+				.await
+				.map_err(|err| format!("failed to read '{}': {err}", path.display()))?,
+		);
+	}
+
+	Ok(contents)
+}
+
+/// Why an object was left out of a Spacedrop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Type)]
+pub enum SpacedropSkipReason {
+	/// None of the object's files are in a location on this node
+	NoFilePath,
+	/// The object's files are only in locations which are offline
+	LocationOffline,
+	/// The object's file was indexed but is no longer on disk
+	FileMissing,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Type)]
+pub struct SkippedObject {
+	pub object_id: object::id::Type,
+	pub reason: SpacedropSkipReason,
+}
+
+/// The outcome of [`spacedrop_objects`]
+#[derive(Debug, Serialize, Type)]
+pub struct SpacedropObjects {
+	/// The Spacedrop of the objects which could be found, [`None`] if none of them could be
+	pub id: Option<Uuid>,
+	pub skipped: Vec<SkippedObject>,
+}
+
+/// A local file an object could be Spacedropped from
+#[derive(Debug, Clone)]
+struct ObjectFile {
+	object_id: object::id::Type,
+	path: PathBuf,
+	online: bool,
+	hidden: bool,
+}
+
+/// Pick the file each object is sent from, preferring ones in online locations which aren't hidden.
+/// When a file is gone from disk the object's other copies are tried before giving up on it.
+/// The result is in the order of `object_ids`.
+async fn pick_object_files(
+	object_ids: &[object::id::Type],
+	files: Vec<ObjectFile>,
+) -> (Vec<(object::id::Type, PathBuf)>, Vec<SkippedObject>) {
+	let mut copies = HashMap::<_, Vec<ObjectFile>>::new();
+	for file in files {
+		copies.entry(file.object_id).or_default().push(file);
+	}
+
+	let mut picked = Vec::with_capacity(object_ids.len());
+	let mut skipped = Vec::new();
+	'objects: for object_id in object_ids {
+		let mut files = copies.remove(object_id).unwrap_or_default();
+		files.sort_by_key(|file| Reverse((file.online, !file.hidden)));
+
+		let reason = match files.first() {
+			None => SpacedropSkipReason::NoFilePath,
+			Some(file) if !file.online => SpacedropSkipReason::LocationOffline,
+			Some(_) => SpacedropSkipReason::FileMissing,
+		};
+		for file in files.into_iter().take_while(|file| file.online) {
+			if matches!(fs::metadata(&file.path).await, Ok(metadata) if metadata.is_file()) {
+				picked.push((*object_id, file.path));
+				continue 'objects;
+			}
+		}
+
+		skipped.push(SkippedObject {
+			object_id: *object_id,
+			reason,
+		});
+	}
+
+	(picked, skipped)
+}
+
+/// Spacedrop the objects of a library, each is sent from one of its files on this node.
+/// Objects with no file we can send are skipped instead of failing the whole Spacedrop.
+pub async fn spacedrop_objects(
+	node: &Node,
+	library: &Library,
+	identity: RemoteIdentity,
+	object_ids: Vec<object::id::Type>,
+) -> Result<SpacedropObjects, SpacedropError> {
+	let file_paths = library
+		.db
+		.file_path()
+		.find_many(vec![
+			file_path::object_id::in_vec(object_ids.clone()),
+			file_path::is_dir::equals(Some(false)),
+			file_path::location::is(vec![location::instance_id::equals(Some(
+				library.config().await.instance_id,
+			))]),
+		])
+		.select(file_path_to_spacedrop::select())
+		.exec()
+		.await
+		.map_err(|err| {
+			P2PError::Other(format!(
+				"failed to find the files of the objects to Spacedrop: {err}"
+			))
+		})?;
+
+	let online = node.locations.get_online().await;
+	let files = file_paths
+		.into_iter()
+		.filter_map(|file_path| {
+			let object_id = file_path.object_id?;
+			let location = file_path.location.as_ref()?;
+			let path = full_path_on_disk(
+				location.path.as_ref()?,
+				&IsolatedFilePathData::try_from((location.id, &file_path)).ok()?,
+				file_path.name_bytes.as_deref(),
+			);
+
+			Some(ObjectFile {
+				object_id,
+				path,
+				online: online.contains(&location.pub_id),
+				hidden: file_path.hidden.unwrap_or(false),
+			})
+		})
+		.collect();
+
+	let (picked, skipped) = pick_object_files(&object_ids, files).await;
+	if !skipped.is_empty() {
+		debug!(
+			"skipping {} objects which can't be Spacedropped",
+			skipped.len()
+		);
+	}
+
+	let id = if picked.is_empty() {
+		None
+	} else {
+		Some(
+			spacedrop(
+				node.p2p.clone(),
+				identity,
+				picked.into_iter().map(|(_, path)| path).collect(),
+			)
+			.await?,
+		)
+	};
+
+	Ok(SpacedropObjects { id, skipped })
+}
+
+pub async fn spacedrop_text(
+	p2p: Arc<P2PManager>,
+	identity: RemoteIdentity,
+	text: String,
+) -> Result<Uuid, SpacedropError> {
+	let len = text.len() as u64;
+	if text.is_empty() {
+		return Err(SpacedropError::Empty);
+	}
+	if len > MAX_TEXT_LEN {
+		return Err(SpacedropError::TextTooLong(len));
+	}
+
+	let id = Uuid::new_v4();
+	debug!("({id}): starting text Spacedrop with peer '{identity}");
+	let peer = spacedrop_peer(&p2p, id, identity).await?;
+
+	let op_id = OpId::new();
+	let registered = p2p.spacedrop_transfers.register(
+		id,
+		SpacedropTransfer {
+			op_id,
+			direction: SpacedropDirection::Sent,
+			identity,
+			peer_name: peer_name(&p2p.p2p, identity),
+			files: vec![],
+			total_bytes: len,
+			started_at: Utc::now(),
+		},
+	);
+
+	let protocol = p2p.peer_protocol(identity).await;
+	let stream = p2p
+		.new_stream(&peer, P2POperation::Spacedrop)
+		.await
+		.map_err(|err| {
+			debug!("({id}): failed to connect to '{identity}': {err:?}");
+			p2p.connect_failed(identity, P2POperation::Spacedrop, &err);
+			P2PError::Connection(err.to_string())
+		})?;
+	let mut stream = p2p
+		.bandwidth_limiter
+		.limit(p2p.metrics.instrument(P2POperation::Spacedrop, stream));
+
+	tokio::spawn(
+		async move {
+			let header = Header::Spacedrop(SpacedropPayload::Text {
+				id,
+				preview: text.chars().take(TEXT_PREVIEW_CHARS).collect(),
+				len,
+			});
+			if let Err(err) = stream
+				.write_all(&header.to_bytes_for(op_id, &protocol))
+				.await
+			{
+				debug!("({id}): failed to send header: {err}");
+				if let Some(transfer) = registered.transfer() {
+					p2p.finish_spacedrop(
+						id,
+						transfer,
+						SpacedropEnd::Failed(format!("failed to send the request: {err}")),
+					)
+					.await;
+				}
+				return;
+			}
+
+			let timeout = spacedrop_timeout(&p2p.node_config.get().await);
+			match wait_for_response(
+				&mut stream,
+				timeout + Duration::from_secs(5),
+				SPACEDROP_MAX_TIMEOUT + Duration::from_secs(5),
+			)
+			.await
+			{
+				// Text is sent as it is, whatever the receiver answers
+				Ok(SpacedropResponse::Accepted | SpacedropResponse::AcceptedCompressed) => {}
+				Ok(SpacedropResponse::Rejected) => {
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::Rejected)
+							.await;
+					}
+					return;
+				}
+				Ok(SpacedropResponse::TimedOut) => {
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::TimedOut)
+							.await;
+					}
+					return;
+				}
+				Ok(SpacedropResponse::Unavailable) => {
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::unavailable())
+							.await;
+					}
+					return;
+				}
+				Ok(SpacedropResponse::Busy) => {
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(id, transfer, SpacedropEnd::busy())
+							.await;
+					}
+					return;
+				}
+				Err(err) => {
+					debug!("({id}): error waiting for response from '{identity}': {err:?}");
+					if let Some(transfer) = registered.transfer() {
+						p2p.finish_spacedrop(
+							id,
+							transfer,
+							SpacedropEnd::Failed(format!("failed to get a response: {err}")),
+						)
+						.await;
+					}
+					return;
+				}
+			}
+
+			if let Err(err) = send_text(&mut stream, &text).await {
+				debug!("({id}): failed to send text: {err}");
+				if let Some(transfer) = registered.transfer() {
+					p2p.finish_spacedrop(
+						id,
+						transfer,
+						SpacedropEnd::Failed(format!("failed to send the text: {err}")),
+					)
+					.await;
+				}
+				return;
+			}
+
+			// The receiver doesn't report back where large text was saved
+			if let Some(transfer) = registered.transfer() {
+				p2p.finish_spacedrop(id, transfer, SpacedropEnd::Completed(vec![]))
+					.await;
+			}
+
+			debug!("({id}): finished");
+		}
+		.instrument(op_id.span(identity, P2POperation::Spacedrop)),
+	);
+
+	Ok(id)
+}
+
+pub(crate) async fn send_text(
+	stream: &mut (impl AsyncWrite + Unpin),
+	text: &str,
+) -> Result<(), io::Error> {
+	stream.write_all(text.as_bytes()).await?;
+	stream.flush().await
+}
+
+pub(crate) async fn receive_text(
+	stream: &mut (impl AsyncRead + Unpin),
+	len: u64,
+) -> Result<String, io::Error> {
+	if len > MAX_TEXT_LEN {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("text of '{len}' bytes is over the limit"),
+		));
+	}
+
+	let mut buf = vec![0u8; len as usize];
+	stream.read_exact(&mut buf).await?;
+	String::from_utf8(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// TODO: Move these off the manager
+impl P2PManager {
+	/// Subscribe to the events like a frontend, along with the Spacedrop requests held while none was open.
+	/// Their timeout starts over now that someone can answer them.
+	pub(crate) fn subscribe_frontend(&self) -> (P2PEventsReceiver, Vec<P2PEvent>) {
+		let rx = self.events.subscribe();
+		let held = std::mem::take(
+			&mut *self
+				.spacedrop_unattended
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner),
+		);
+
+		let keep_alives = self
+			.spacedrop_keep_alives
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		for id in held.keys() {
+			if let Some(keep_alive) = keep_alives.get(id) {
+				keep_alive.notify_one();
+			}
+		}
+
+		(rx, held.into_values().collect())
+	}
+
+	fn hold_unattended_spacedrop(&self, id: Uuid, request: P2PEvent) {
+		let mut held = self
+			.spacedrop_unattended
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		held.insert(id, request);
+
+		// A frontend which subscribed since we checked would never get it otherwise
+		if self.events.has_subscribers() {
+			if let Some(request) = held.remove(&id) {
+				drop(held);
+				self.events.send(request).ok();
+				if let Some(keep_alive) = self
+					.spacedrop_keep_alives
+					.lock()
+					.unwrap_or_else(PoisonError::into_inner)
+					.get(&id)
+				{
+					keep_alive.notify_one();
+				}
+			}
+		}
+	}
+
+	/// Send the terminal event of a Spacedrop and keep it in the history.
+	pub(crate) async fn finish_spacedrop(
+		&self,
+		id: Uuid,
+		transfer: SpacedropTransfer,
+		end: SpacedropEnd,
+	) {
+		let event = match &end {
+			SpacedropEnd::Completed(saved_paths) => {
+				transfer.clone().completed(id, saved_paths.clone())
+			}
+			SpacedropEnd::Rejected => transfer.clone().rejected(id),
+			SpacedropEnd::TimedOut => transfer.clone().timed_out(id),
+			SpacedropEnd::Failed(reason) => P2PEvent::SpacedropFailed {
+				id,
+				op_id: transfer.op_id,
+				reason: reason.clone(),
+			},
+			// Only ever sent when the other device cancelled it, our user knows when they did
+			SpacedropEnd::Cancelled => P2PEvent::SpacedropFailed {
+				id,
+				op_id: transfer.op_id,
+				reason: CANCELLED_BY_PEER_REASON.to_string(),
+			},
+		};
+		self.events.send(event).ok();
+
+		self.record_spacedrop(id, transfer, end).await;
+	}
+
+	pub(crate) async fn record_spacedrop(
+		&self,
+		id: Uuid,
+		transfer: SpacedropTransfer,
+		end: SpacedropEnd,
+	) {
+		self.spacedrop_history
+			.record(transfer.history_entry(id, end))
+			.await;
+	}
+
+	/// Accept a Spacedrop. For small text payloads the text is returned instead of being saved to `path`.
+	pub async fn accept_spacedrop(
+		&self,
+		id: Uuid,
+		path: PathBuf,
+		overwrite: bool,
+		apply_permissions: bool,
+	) -> Result<Option<String>, SpacedropAcceptError> {
+		let Some(chan) = self
+			.spacedrop_pairing_reqs
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&id)
+		else {
+			return Ok(None);
+		};
+
+		let (result_tx, result_rx) = oneshot::channel();
+		if let Err(err) = chan.send(Some(SpacedropAccept {
+			path,
+			overwrite,
+			apply_permissions,
+			result_tx,
+		})) {
+			warn!("error accepting Spacedrop '{id:?}': '{err:?}'");
+			return Ok(None);
+		}
+
+		// This will only error if the Spacedrop failed after we accepted it, which is reported through events
+		result_rx.await.unwrap_or(Ok(None))
+	}
+
+	pub async fn reject_spacedrop(&self, id: Uuid) {
+		if let Some(chan) = self
+			.spacedrop_pairing_reqs
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&id)
+		{
+			chan.send(None)
+				.map_err(|err| {
+					warn!("error rejecting Spacedrop '{id:?}': '{err:?}'");
+				})
+				.ok();
+		}
+	}
+
+	/// Called while the accept prompt is still visible so the Spacedrop doesn't time out on either side.
+	pub async fn keep_alive_spacedrop(&self, id: Uuid) {
+		if let Some(keep_alive) = self
+			.spacedrop_keep_alives
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.get(&id)
+		{
+			keep_alive.notify_one();
+		}
+	}
+
+	pub async fn cancel_spacedrop(&self, id: Uuid) {
+		if self.spacedrop_queue.cancel(id) {
+			return;
+		}
+
+		if let Some(cancelled) = self
+			.spacedrop_cancellations
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&id)
+		{
+			cancelled.store(true, Ordering::Relaxed);
+		}
+	}
+}
+
+/// `legacy` is for senders from before segments and completions, which stream each file on its own and don't wait to hear where it was saved
+pub(crate) async fn receiver(
+	this: &Arc<P2PManager>,
+	payload: SpacedropPayload,
+	op_id: OpId,
+	legacy: bool,
+	mut stream: UnicastStream,
+) -> Result<(), P2PError> {
+	let id = payload.id();
+	let (tx, rx) = oneshot::channel();
+
+	// The sender checked the mode we advertise, but it may not have seen it change yet
+	let remote = stream.remote_identity();
+	let config = this.node_config.get().await;
+	let rejection = if !this.is_reachable_by(remote).await {
+		Some("it isn't one of our contacts".to_string())
+	} else if !config
+		.spacedrop_mode
+		.allows(config.contacts.contains(&remote))
+	{
+		Some(format!("Spacedrops are '{}'", config.spacedrop_mode))
+	} else {
+		None
+	};
+	if let Some(reason) = rejection {
+		info!("({id}): rejecting Spacedrop from '{remote}' as {reason}");
+		stream
+			.write_all(&[0])
+			.await
+			.map_err(P2PError::io("sending rejection"))?;
+		return stream
+			.flush()
+			.await
+			.map_err(P2PError::io("flushing rejection"));
+	}
+
+	let (kind, files) = match &payload {
+		SpacedropPayload::Files(req) => {
+			info!(
+				"({id}): received '{}' files from peer '{}' with block size '{:?}'",
+				req.requests.len(),
+				stream.remote_identity(),
+				req.block_size
+			);
+
+			(
+				SpacedropKind::Files,
+				req.requests
+					.iter()
+					.map(|req| req.name.clone())
+					.collect::<Vec<_>>(),
+			)
+		}
+		SpacedropPayload::Text { preview, len, .. } => {
+			info!(
+				"({id}): received '{len}' bytes of text from peer '{}'",
+				stream.remote_identity()
+			);
+
+			if *len > MAX_TEXT_LEN {
+				warn!("({id}): text is over the limit, rejecting!");
+				stream
+					.write_all(&[0])
+					.await
+					.map_err(P2PError::io("sending rejection"))?;
+				return stream
+					.flush()
+					.await
+					.map_err(P2PError::io("flushing rejection"));
+			}
+
+			(
+				SpacedropKind::Text {
+					preview: preview.clone(),
+					// We checked against `MAX_TEXT_LEN` above so this fits
+					len: *len as u32,
+				},
+				// Large text is saved as a file so we present it to the frontend as one
+				if *len > TEXT_INLINE_THRESHOLD {
+					vec!["Spacedrop.txt".to_string()]
+				} else {
+					vec![]
+				},
+			)
+		}
+	};
+
+	let transfer = SpacedropTransfer {
+		op_id,
+		direction: SpacedropDirection::Received,
+		identity: stream.remote_identity(),
+		peer_name: peer_name(&this.p2p, stream.remote_identity()),
+		files: files.clone(),
+		total_bytes: match &payload {
+			SpacedropPayload::Files(req) => req.requests.iter().map(|req| req.size).sum(),
+			SpacedropPayload::Text { len, .. } => *len,
+		},
+		started_at: Utc::now(),
+	};
+
+	let unattended = !this.events.has_subscribers();
+	if unattended && !config.queue_spacedrop_when_unattended {
+		info!("({id}): no frontend is open to accept it, rejecting!");
+		this.finish_spacedrop(id, transfer, SpacedropEnd::unavailable())
+			.await;
+
+		stream
+			.write_all(&[RECEIVER_UNAVAILABLE])
+			.await
+			.map_err(P2PError::io("sending rejection"))?;
+		return stream
+			.flush()
+			.await
+			.map_err(P2PError::io("flushing rejection"));
+	}
+
+	this.spacedrop_pairing_reqs
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.insert(id, tx);
+	let keep_alive = Arc::new(Notify::new());
+	this.spacedrop_keep_alives
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.insert(id, keep_alive.clone());
+
+	let request = spacedrop_request(
+		&config,
+		&this.p2p,
+		id,
+		op_id,
+		stream.remote_identity(),
+		kind,
+		files,
+	);
+	let timeout = spacedrop_timeout(&config);
+	let hard_deadline = Instant::now() + SPACEDROP_MAX_TIMEOUT;
+	let mut deadline = Instant::now() + timeout;
+	// While the request is held for a frontend the sender is kept waiting, up until the hard deadline
+	let mut unattended_keep_alive = None;
+	if unattended {
+		info!("({id}): no frontend is open, holding the request until one is");
+		this.hold_unattended_spacedrop(id, request);
+		deadline = hard_deadline;
+		unattended_keep_alive = Some(Instant::now() + UNATTENDED_KEEP_ALIVE_INTERVAL);
+	} else {
+		this.events.send(request).ok();
+	}
+
+	let mut rx = rx;
+	let accept = loop {
+		tokio::select! {
+			_ = sleep_until(deadline) => break None,
+			_ = sleep_until(unattended_keep_alive.unwrap_or(deadline)),
+				if unattended_keep_alive.is_some() =>
+			{
+				unattended_keep_alive = Some(Instant::now() + UNATTENDED_KEEP_ALIVE_INTERVAL);
+
+				stream.write_all(&[KEEP_ALIVE]).await.map_err(P2PError::io("sending keep-alive"))?;
+				stream.flush().await.map_err(P2PError::io("flushing keep-alive"))?;
+			}
+			_ = keep_alive.notified() => {
+				// The request reached a frontend, so it's prompted for like any other from now on
+				unattended_keep_alive = None;
+				deadline = (Instant::now() + timeout).min(hard_deadline);
+				debug!("({id}): prompt still open, extending deadline");
+
+				stream.write_all(&[KEEP_ALIVE]).await.map_err(P2PError::io("sending keep-alive"))?;
+				stream.flush().await.map_err(P2PError::io("flushing keep-alive"))?;
+			}
+			accept = &mut rx => break Some(accept),
+		}
+	};
+	this.spacedrop_keep_alives
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.remove(&id);
+
+	match accept {
+		None => {
+			info!("({id}): timeout, rejecting!");
+			this.spacedrop_pairing_reqs
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.remove(&id);
+			this.spacedrop_unattended
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.remove(&id);
+			this.finish_spacedrop(id, transfer, SpacedropEnd::TimedOut)
+				.await;
+
+			stream
+				.write_all(&[0])
+				.await
+				.map_err(P2PError::io("sending reject bit"))?;
+			stream
+				.flush()
+				.await
+				.map_err(P2PError::io("flushing reject bit"))?;
+		}
+		Some(Ok(Some(SpacedropAccept {
+			path: file_path,
+			overwrite,
+			apply_permissions,
+			result_tx,
+		}))) => {
+			info!("({id}): accepted saving to '{file_path:?}' (overwrite: {overwrite})");
+
+			let destination = match &payload {
+				SpacedropPayload::Files(req) => Some((
+					// A single file is saved to the path given, multiple files are saved into it
+					destination_dir(&file_path, req.requests.len() != 1),
+					req.requests.iter().map(|req| req.size).sum(),
+				)),
+				SpacedropPayload::Text { len, .. } if *len > TEXT_INLINE_THRESHOLD => {
+					let is_dir = fs::metadata(&file_path)
+						.await
+						.map(|metadata| metadata.is_dir())
+						.unwrap_or(false);
+					Some((destination_dir(&file_path, is_dir), *len))
+				}
+				SpacedropPayload::Text { .. } => None,
+			};
+
+			if let Some((dir, required)) = destination {
+				let available = available_space_at(&dir).await;
+				if let Err(err) = preflight(&dir, required, available).await {
+					warn!("({id}): can't save to '{dir:?}': {err}");
+					this.finish_spacedrop(id, transfer, SpacedropEnd::Failed(err.to_string()))
+						.await;
+					result_tx.send(Err(err)).ok();
+
+					stream
+						.write_all(&[0])
+						.await
+						.map_err(P2PError::io("sending rejection"))?;
+					return stream
+						.flush()
+						.await
+						.map_err(P2PError::io("flushing rejection"));
+				}
+			}
+
+			let req = match payload {
+				SpacedropPayload::Files(req) => req,
+				SpacedropPayload::Text { len, .. } => {
+					let saved_paths =
+						receive_text_payload(&mut stream, len, file_path, result_tx).await?;
+
+					info!("({id}): complete");
+					this.finish_spacedrop(id, transfer, SpacedropEnd::Completed(saved_paths))
+						.await;
+					return Ok(());
+				}
+			};
+			result_tx.send(Ok(None)).ok();
+
+			let cancelled = Arc::new(AtomicBool::new(false));
+			this.spacedrop_cancellations
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.insert(id, cancelled.clone());
+
+			let result = receive_files(
+				this,
+				&req,
+				op_id,
+				legacy,
+				&mut stream,
+				file_path,
+				overwrite,
+				apply_permissions,
+				&cancelled,
+			)
+			.await;
+
+			this.spacedrop_cancellations
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner)
+				.remove(&id);
+
+			match result {
+				Ok(SpacedropEnd::Completed(saved_paths)) => {
+					info!("({id}): complete");
+					this.finish_spacedrop(id, transfer, SpacedropEnd::Completed(saved_paths))
+						.await;
+				}
+				// The user cancelled it themselves so the frontend isn't told again
+				Ok(SpacedropEnd::Cancelled) if cancelled.load(Ordering::Relaxed) => {
+					info!("({id}): cancelled");
+					this.record_spacedrop(id, transfer, SpacedropEnd::Cancelled)
+						.await;
+				}
+				Ok(end) => this.finish_spacedrop(id, transfer, end).await,
+				Err(err) => {
+					this.finish_spacedrop(id, transfer, SpacedropEnd::Failed(err.to_string()))
+						.await;
+					return Err(err);
+				}
+			}
+		}
+		Some(Ok(None)) => {
+			info!("({id}): rejected");
+			// The user rejected it themselves so the frontend isn't told again
+			this.record_spacedrop(id, transfer, SpacedropEnd::Rejected)
+				.await;
+
+			stream
+				.write_all(&[0])
+				.await
+				.map_err(P2PError::io("sending rejection"))?;
+			stream
+				.flush()
+				.await
+				.map_err(P2PError::io("flushing rejection"))?;
+		}
+		Some(Err(_)) => {
+			warn!("({id}): error with Spacedrop pairing request receiver!");
+		}
+	}
+
+	Ok(())
+}
+
+/// Returns where the text was saved, which is nowhere if it was small enough to hand back to the frontend.
+async fn receive_text_payload(
+	stream: &mut UnicastStream,
+	len: u64,
+	file_path: PathBuf,
+	result_tx: oneshot::Sender<Result<Option<String>, SpacedropAcceptError>>,
+) -> Result<Vec<PathBuf>, P2PError> {
+	stream
+		.write_all(&[1])
+		.await
+		.map_err(P2PError::io("sending continuation bit"))?;
+	stream
+		.flush()
+		.await
+		.map_err(P2PError::io("flushing continuation bit"))?;
+
+	let text = receive_text(stream, len)
+		.await
+		.map_err(P2PError::io("receiving text"))?;
+
+	Ok(if len > TEXT_INLINE_THRESHOLD {
+		let is_dir = fs::metadata(&file_path)
+			.await
+			.map(|metadata| metadata.is_dir())
+			.unwrap_or(false);
+		let path = if is_dir {
+			file_path.join("Spacedrop.txt")
+		} else {
+			file_path
+		};
+
+		let (path, mut f) =
+			create_unique_file(&path, false)
+				.await
+				.map_err(|source| P2PError::Io {
+					context: format!("creating file at '{path:?}'"),
+					source,
+				})?;
+		f.write_all(text.as_bytes())
+			.await
+			.map_err(|source| P2PError::Io {
+				context: format!("writing text to '{path:?}'"),
+				source,
+			})?;
+		f.flush().await.map_err(|source| P2PError::Io {
+			context: format!("flushing text to '{path:?}'"),
+			source,
+		})?;
+
+		result_tx.send(Ok(None)).ok();
+		vec![path]
+	} else {
+		result_tx.send(Ok(Some(text))).ok();
+		vec![]
+	})
+}
+
+/// Returns how the transfer ended, with where the files were saved once it completed.
+#[allow(clippy::too_many_arguments)]
+async fn receive_files(
+	this: &Arc<P2PManager>,
+	req: &SpaceblockRequests,
+	op_id: OpId,
+	legacy: bool,
+	stream: &mut UnicastStream,
+	file_path: PathBuf,
+	overwrite: bool,
+	apply_permissions: bool,
+	cancelled: &AtomicBool,
+) -> Result<SpacedropEnd, P2PError> {
+	let id = req.id;
+
+	// We take up whatever compression the sender proposed, as we can decompress anything it can send
+	stream
+		.write_all(&[match req.compression {
+			Compression::None => 1,
+			Compression::Zstd => ACCEPTED_COMPRESSED,
+		}])
+		.await
+		.map_err(P2PError::io("sending continuation bit"))?;
+
+	let mut transfer = Transfer::new(
+		req,
+		|percent| {
+			this.events
+				.send(P2PEvent::SpacedropProgress { id, op_id, percent })
+				.ok();
+		},
+		cancelled,
+	)
+	.with_compression(req.compression);
+
+	let units = match legacy {
+		true => TransferUnit::streamed(&req.requests),
+		false => TransferUnit::plan(&req.requests),
+	};
+	let result = receive_units(
+		id,
+		req,
+		units,
+		&mut transfer,
+		stream,
+		&file_path,
+		overwrite,
+		apply_permissions,
+	)
+	.await;
+	debug!(
+		"({id}): received {} bytes of files in {} bytes",
+		transfer.file_bytes(),
+		transfer.wire_bytes()
+	);
+	this.metrics.spacedrop_files(transfer.file_bytes());
+	let saved_paths = match result? {
+		SpacedropEnd::Completed(saved_paths) => saved_paths,
+		end => return Ok(end),
+	};
+
+	if cancelled.load(Ordering::Relaxed) {
+		return Ok(SpacedropEnd::Cancelled);
+	}
+
+	if legacy {
+		return Ok(SpacedropEnd::Completed(saved_paths));
+	}
+
+	let completion = SpacedropCompletion { saved_paths };
+	stream
+		.write_all(&completion.to_bytes())
+		.await
+		.map_err(P2PError::io("sending completion"))?;
+	stream
+		.flush()
+		.await
+		.map_err(P2PError::io("flushing completion"))?;
+
+	Ok(SpacedropEnd::Completed(completion.saved_paths))
+}
+
+/// Receive and save every file of `req`, sent as `units`, returns how the transfer ended
+#[allow(clippy::too_many_arguments)]
+async fn receive_units<'a, F: Fn(u8) + 'a>(
+	id: Uuid,
+	req: &SpaceblockRequests,
+	units: Vec<TransferUnit>,
+	transfer: &mut Transfer<'a, F>,
+	stream: &mut UnicastStream,
+	file_path: &Path,
+	overwrite: bool,
+	apply_permissions: bool,
+) -> Result<SpacedropEnd, P2PError> {
+	let names_len = req.requests.len();
+	let mut saved_paths = Vec::with_capacity(names_len);
+	for unit in units {
+		match unit {
+			TransferUnit::Streamed(file_id) => {
+				let file_req = &req.requests[file_id];
+				let file_name = &file_req.name;
+				let (path, f) =
+					create_received_file(id, file_path, file_req, names_len, overwrite).await?;
+
+				let f = BufWriter::new(f);
+				if let Err(err) = transfer.receive(stream, f).await {
+					error!("({id}): error receiving file '{file_name}': '{err:?}'");
+
+					return Ok(SpacedropEnd::Failed(format!(
+						"failed to receive '{file_name}': {err}"
+					)));
+				}
+
+				apply_file_metadata(&path, file_req, apply_permissions).await;
+				saved_paths.push(path);
+			}
+			TransferUnit::Packed(file_ids) => {
+				let segment = match transfer.receive_segment(stream).await {
+					Ok(Some(segment)) => segment,
+					// By either end
+					Ok(None) => return Ok(SpacedropEnd::Cancelled),
+					Err(err) => {
+						error!("({id}): error receiving files '{file_ids:?}': '{err:?}'");
+
+						return Ok(SpacedropEnd::Failed(format!(
+							"failed to receive {} files: {err}",
+							file_ids.len()
+						)));
+					}
+				};
+
+				for ((entry, data), file_req) in segment.files().zip(&req.requests[file_ids]) {
+					let (path, mut f) =
+						create_received_file(id, file_path, file_req, names_len, overwrite).await?;
+
+					f.write_all(data).await.map_err(|source| P2PError::Io {
+						context: format!("writing '{}' to '{path:?}'", entry.name),
+						source,
+					})?;
+					f.flush().await.map_err(|source| P2PError::Io {
+						context: format!("flushing '{}' to '{path:?}'", entry.name),
+						source,
+					})?;
+
+					apply_file_metadata(&path, file_req, apply_permissions).await;
+					transfer.unpacked(entry);
+					saved_paths.push(path);
+				}
+			}
+		}
+	}
+
+	Ok(SpacedropEnd::Completed(saved_paths))
+}
+
+/// Creates the file `file_req` is saved to.
+/// Files are saved to the destination itself, unless there are many of them which go in it.
+async fn create_received_file(
+	id: Uuid,
+	file_path: &Path,
+	file_req: &SpaceblockRequest,
+	names_len: usize,
+	overwrite: bool,
+) -> Result<(PathBuf, File), P2PError> {
+	let file_name = &file_req.name;
+	// When transferring more than 1 file we wanna join the incoming file name to the directory provided by the user
+	let mut path = file_path.to_path_buf();
+	if names_len != 1 {
+		// We know the `file_path` will be a directory so we can just push the file name to it
+		path.push(file_name);
+	}
+
+	debug!("({id}): accepting '{file_name}' and saving to '{:?}'", path);
+
+	if let Some(parent) = path.parent() {
+		// TODO: Send error to the frontend and the remote peer
+		create_dir_all(&parent)
+			.await
+			.map_err(|source| P2PError::Io {
+				context: format!("creating parent directory '{parent:?}'"),
+				source,
+			})?;
+	}
+
+	let (path, f) = create_unique_file(&path, overwrite)
+		.await
+		.map_err(|source| P2PError::Io {
+			context: format!("creating file at '{path:?}'"),
+			source,
+		})?;
+	debug!("({id}): writing '{file_name}' to '{:?}'", path);
+
+	Ok((path, f))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{io::Cursor, time::SystemTime};
+
+	use crate::{
+		node::config,
+		p2p::{set_peer_alias, P2PTransport, SPACEDRIVE_APP_ID},
+		Env,
+	};
+
+	use sd_p2p::{flume::bounded, Identity, MemoryNetwork};
+	use tempfile::tempdir;
+	use tokio::{
+		fs,
+		time::{sleep, timeout},
+	};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn objects_are_sent_from_their_best_file() {
+		let dir = tempdir().unwrap();
+		let visible = dir.path().join("photo.jpg");
+		let hidden = dir.path().join(".photo.jpg");
+		for path in [&visible, &hidden] {
+			fs::write(path, b"photo").await.unwrap();
+		}
+
+		let file = |object_id, path: &Path, online, hidden| ObjectFile {
+			object_id,
+			path: path.to_path_buf(),
+			online,
+			hidden,
+		};
+
+		let (picked, skipped) = pick_object_files(
+			&[1, 2, 3, 4, 5],
+			vec![
+				file(1, &hidden, true, true),
+				file(1, &visible, true, false),
+				file(1, &dir.path().join("offline.jpg"), false, false),
+				// The file of this object was deleted after it was indexed
+				file(2, &dir.path().join("deleted.jpg"), true, false),
+				file(2, &visible, false, false),
+				file(3, &visible, false, false),
+				// The best copy is gone, so the hidden one is sent instead
+				file(5, &dir.path().join("deleted.jpg"), true, false),
+				file(5, &hidden, true, true),
+			],
+		)
+		.await;
+
+		assert_eq!(picked, [(1, visible), (5, hidden)]);
+		assert_eq!(
+			skipped,
+			[
+				SkippedObject {
+					object_id: 2,
+					reason: SpacedropSkipReason::FileMissing,
+				},
+				SkippedObject {
+					object_id: 3,
+					reason: SpacedropSkipReason::LocationOffline,
+				},
+				SkippedObject {
+					object_id: 4,
+					reason: SpacedropSkipReason::NoFilePath,
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn suffixed_path_keeps_extension() {
+		assert_eq!(
+			suffixed_path(Path::new("/photos/IMG_0001.jpg"), 1),
+			PathBuf::from("/photos/IMG_0001 (1).jpg")
+		);
+		assert_eq!(
+			suffixed_path(Path::new("/docs/README"), 2),
+			PathBuf::from("/docs/README (2)")
+		);
+	}
+
+	#[tokio::test]
+	async fn received_file_does_not_clobber_existing_one() {
+		let dir = tempdir().unwrap();
+		let target = dir.path().join("IMG_0001.jpg");
+		fs::write(&target, b"original").await.unwrap();
+
+		let data = b"Spacedrive".to_vec();
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(data.len() as u64),
+			compression: Compression::None,
+			requests: vec![SpaceblockRequest {
+				name: "IMG_0001.jpg".into(),
+				size: data.len() as u64,
+				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
+			}],
+		};
+
+		let (mut client, mut server) = tokio::io::duplex(64);
+		tokio::spawn({
+			let req = req.clone();
+			let data = data.clone();
+			async move {
+				Transfer::new(&req, |_| {}, &Default::default())
+					.send(&mut client, BufReader::new(Cursor::new(data)))
+					.await
+					.unwrap();
+			}
+		});
+
+		let (saved_path, file) = create_unique_file(&target, false).await.unwrap();
+		assert_eq!(saved_path, dir.path().join("IMG_0001 (1).jpg"));
+
+		Transfer::new(&req, |_| {}, &Default::default())
+			.receive(&mut server, BufWriter::new(file))
+			.await
+			.unwrap();
+
+		assert_eq!(fs::read(&saved_path).await.unwrap(), data);
+		assert_eq!(fs::read(&target).await.unwrap(), b"original");
+	}
+
+	#[tokio::test]
+	async fn overwrite_replaces_existing_file() {
+		let dir = tempdir().unwrap();
+		let target = dir.path().join("IMG_0001.jpg");
+		fs::write(&target, b"original").await.unwrap();
+
+		let (saved_path, mut file) = create_unique_file(&target, true).await.unwrap();
+		file.write_all(b"new").await.unwrap();
+		file.flush().await.unwrap();
+
+		assert_eq!(saved_path, target);
+		assert_eq!(fs::read(&target).await.unwrap(), b"new");
+	}
+
+	#[test]
+	fn timeout_is_clamped() {
+		use crate::util::version_manager::ManagedVersion;
+
+		let mut config = NodeConfig::from_latest_version().unwrap();
+		assert_eq!(spacedrop_timeout(&config), SPACEDROP_TIMEOUT);
+
+		// Eg. a config written before the API refused short timeouts
+		config.spacedrop_timeout_secs = Some(0);
+		assert_eq!(spacedrop_timeout(&config), SPACEDROP_MIN_TIMEOUT);
+
+		config.spacedrop_timeout_secs = Some(u32::MAX);
+		assert_eq!(spacedrop_timeout(&config), SPACEDROP_MAX_TIMEOUT);
+	}
+
+	#[tokio::test]
+	async fn completion_roundtrip() {
+		let completion = SpacedropCompletion {
+			saved_paths: vec![PathBuf::from("/a/b (1).txt"), PathBuf::from("/a/c")],
+		};
+
+		let bytes = completion.to_bytes();
+		let result = SpacedropCompletion::from_stream(&mut Cursor::new(bytes))
+			.await
+			.unwrap();
+		assert_eq!(result, completion);
+
+		// More files than fit in a `u8`
+		let completion = SpacedropCompletion {
+			saved_paths: (0..300).map(|i| PathBuf::from(format!("/a/{i}"))).collect(),
+		};
+		let result = SpacedropCompletion::from_stream(&mut Cursor::new(completion.to_bytes()))
+			.await
+			.unwrap();
+		assert_eq!(result, completion);
+
+		let too_many = (MAX_SPACEDROP_FILES + 1).to_le_bytes();
+		assert!(matches!(
+			SpacedropCompletion::from_stream(&mut Cursor::new(too_many)).await,
+			Err(decode::Error::TooLong { .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn slow_accept_with_keep_alives_proceeds() {
+		let data = b"Spacedrive".to_vec();
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(data.len() as u64),
+			compression: Compression::None,
+			requests: vec![SpaceblockRequest {
+				name: "Demo".into(),
+				size: data.len() as u64,
+				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
+			}],
+		};
+
+		let (mut client, mut server) = tokio::io::duplex(64);
+		let receiver = tokio::spawn({
+			let req = req.clone();
+			async move {
+				// The user takes well over the timeout to respond but the prompt keeps pinging
+				for _ in 0..5 {
+					sleep(Duration::from_millis(50)).await;
+					server.write_all(&[KEEP_ALIVE]).await.unwrap();
+				}
+				server.write_all(&[1]).await.unwrap();
+
+				let mut result = Vec::new();
+				Transfer::new(&req, |_| {}, &Default::default())
+					.receive(&mut server, &mut result)
+					.await
+					.unwrap();
+				result
+			}
+		});
+
+		let response = wait_for_response(
+			&mut client,
+			Duration::from_millis(100),
+			Duration::from_secs(10),
+		)
+		.await
+		.unwrap();
+		assert_eq!(response, SpacedropResponse::Accepted);
+
+		Transfer::new(&req, |_| {}, &Default::default())
+			.send(&mut client, BufReader::new(Cursor::new(data.clone())))
+			.await
+			.unwrap();
+		assert_eq!(receiver.await.unwrap(), data);
+	}
+
+	#[tokio::test]
+	async fn keep_alives_are_capped() {
+		let (mut client, mut server) = tokio::io::duplex(64);
+		tokio::spawn(async move {
+			loop {
+				sleep(Duration::from_millis(20)).await;
+				if server.write_all(&[KEEP_ALIVE]).await.is_err() {
+					break;
+				}
+			}
+		});
+
+		let response = wait_for_response(
+			&mut client,
+			Duration::from_millis(50),
+			Duration::from_millis(200),
+		)
+		.await
+		.unwrap();
+		assert_eq!(response, SpacedropResponse::TimedOut);
+	}
+
+	#[tokio::test]
+	async fn text_roundtrip() {
+		let text = "Hello from Spacedrive! 👋 こんにちは Ünïcödé";
+		let header = Header::Spacedrop(SpacedropPayload::Text {
+			id: Uuid::new_v4(),
+			preview: text.chars().take(TEXT_PREVIEW_CHARS).collect(),
+			len: text.len() as u64,
+		});
+
+		let (mut client, mut server) = tokio::io::duplex(64);
+		tokio::spawn({
+			let bytes = header.to_bytes();
+			async move {
+				client.write_all(&bytes).await.unwrap();
+				send_text(&mut client, text).await.unwrap();
+			}
+		});
+
+		let received = Header::from_stream(&mut server).await.unwrap();
+		assert_eq!(received, header);
+		let Header::Spacedrop(SpacedropPayload::Text { len, .. }) = received else {
+			unreachable!("expected a text Spacedrop");
+		};
+		assert_eq!(receive_text(&mut server, len).await.unwrap(), text);
+	}
+
+	#[tokio::test]
+	async fn text_with_a_long_preview_is_refused() {
+		let header = Header::Spacedrop(SpacedropPayload::Text {
+			id: Uuid::new_v4(),
+			preview: "a".repeat(MAX_TEXT_PREVIEW_LEN + 1),
+			len: 1,
+		});
+
+		assert!(matches!(
+			Header::from_stream(&mut header.to_bytes().as_slice()).await,
+			Err(crate::p2p::HeaderError::SpacedropTextRequest(
+				decode::Error::TooLong { .. }
+			))
+		));
+	}
+
+	#[tokio::test]
+	async fn text_over_limit_is_refused() {
+		let (_client, mut server) = tokio::io::duplex(64);
+		assert!(receive_text(&mut server, MAX_TEXT_LEN + 1).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn second_spacedrop_to_peer_is_queued() {
+		let queue = Arc::new(SpacedropQueue::default());
+		let peer = Identity::default().to_remote_identity();
+
+		let Enqueued::Ready(first) = queue.enqueue(peer, Uuid::new_v4(), 1) else {
+			unreachable!("nothing else is running so it should start straight away");
+		};
+		let Enqueued::Queued { position, mut rx } = queue.enqueue(peer, Uuid::new_v4(), 1) else {
+			unreachable!("the first Spacedrop is still running");
+		};
+		assert_eq!(position, 1);
+
+		// Other peers have their own queue
+		let other_peer = Identity::default().to_remote_identity();
+		assert!(matches!(
+			queue.enqueue(other_peer, Uuid::new_v4(), 1),
+			Enqueued::Ready(_)
+		));
+
+		assert!(rx.try_recv().is_err());
+		drop(first);
+		let _second = rx.await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn queued_spacedrop_can_be_cancelled() {
+		let queue = Arc::new(SpacedropQueue::default());
+		let peer = Identity::default().to_remote_identity();
+
+		let Enqueued::Ready(first) = queue.enqueue(peer, Uuid::new_v4(), 1) else {
+			unreachable!();
+		};
+		let id = Uuid::new_v4();
+		let Enqueued::Queued { rx, .. } = queue.enqueue(peer, id, 1) else {
+			unreachable!();
+		};
+
+		assert!(queue.cancel(id));
+		assert!(rx.await.is_err());
+
+		drop(first);
+		assert!(queue
+			.peers
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.is_empty());
+	}
+
+	#[tokio::test]
+	async fn received_file_keeps_modification_time() {
+		let dir = tempdir().unwrap();
+		let source = dir.path().join("source.jpg");
+		let target = dir.path().join("target.jpg");
+		fs::write(&source, b"Spacedrive").await.unwrap();
+
+		let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+		std::fs::File::options()
+			.write(true)
+			.open(&source)
+			.unwrap()
+			.set_modified(mtime)
+			.unwrap();
+
+		let metadata = fs::metadata(&source).await.unwrap();
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(metadata.len()),
+			compression: Compression::None,
+			requests: vec![file_request("source.jpg".into(), &metadata)],
+		};
+		// The manifest goes over the wire so make sure the timestamps survive that too
+		let Header::Spacedrop(SpacedropPayload::Files(req)) =
+			Header::from_stream(&mut Cursor::new(
+				Header::Spacedrop(SpacedropPayload::Files(req)).to_bytes_with_op_id(OpId::new()),
+			))
+			.await
+			.unwrap()
+		else {
+			unreachable!();
+		};
+
+		let (mut client, mut server) = tokio::io::duplex(64);
+		tokio::spawn({
+			let req = req.clone();
+			let file = File::open(&source).await.unwrap();
+			async move {
+				Transfer::new(&req, |_| {}, &Default::default())
+					.send(&mut client, BufReader::new(file))
+					.await
+					.unwrap();
+			}
+		});
+
+		let (saved_path, file) = create_unique_file(&target, false).await.unwrap();
+		Transfer::new(&req, |_| {}, &Default::default())
+			.receive(&mut server, BufWriter::new(file))
+			.await
+			.unwrap();
+		apply_file_metadata(&saved_path, &req.requests[0], false).await;
+
+		let received = fs::metadata(&saved_path).await.unwrap().modified().unwrap();
+		let difference = received
+			.duration_since(mtime)
+			.unwrap_or_else(|err| err.duration());
+		// Some filesystems only store timestamps with a 2 second granularity
+		assert!(difference <= Duration::from_secs(2), "{difference:?}");
+		assert_eq!(fs::read(&saved_path).await.unwrap(), b"Spacedrive");
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn received_file_permissions_drop_special_bits() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("script.sh");
+		fs::write(&path, b"#!/bin/sh").await.unwrap();
+
+		let req = SpaceblockRequest {
+			name: "script.sh".into(),
+			size: 9,
+			range: Range::Full,
+			modified_at: None,
+			created_at: None,
+			mode: Some(0o4755),
+		};
+		apply_file_metadata(&path, &req, true).await;
+
+		let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+		assert_eq!(mode & 0o7777, 0o755);
+	}
+
+	#[tokio::test]
+	async fn preflight_accepts_writable_destination() {
+		let dir = tempdir().unwrap();
+		preflight(dir.path(), 1024, Some(4096)).await.unwrap();
+		preflight(dir.path(), 1024, None).await.unwrap();
+
+		// The writability probe shouldn't be left behind
+		let mut entries = fs::read_dir(dir.path()).await.unwrap();
+		assert!(entries.next_entry().await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn preflight_rejects_insufficient_space() {
+		let dir = tempdir().unwrap();
+		match preflight(dir.path(), 4096, Some(1024)).await {
+			Err(SpacedropAcceptError::InsufficientSpace {
+				required,
+				available,
+			}) => {
+				assert_eq!(required, 4096);
+				assert_eq!(available, 1024);
+			}
+			result => unreachable!("unexpected preflight result: {result:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn preflight_rejects_missing_destination() {
+		let dir = tempdir().unwrap();
+		let missing = dir.path().join("missing");
+		assert!(matches!(
+			preflight(&missing, 0, None).await,
+			Err(SpacedropAcceptError::DestinationNotFound(path)) if path == missing
+		));
+	}
+
+	#[tokio::test]
+	async fn spacedrop_requests_carry_the_peer_alias() {
+		let data_dir = tempdir().unwrap();
+		let node_config = config::Manager::new(data_dir.path()).await.unwrap();
+		let (handler_tx, _handler_rx) = bounded(5);
+		let p2p = P2P::new(SPACEDRIVE_APP_ID, Identity::default(), handler_tx);
+		let peer = Identity::default().to_remote_identity();
+
+		let request = |config: &NodeConfig| {
+			spacedrop_request(
+				config,
+				&p2p,
+				Uuid::new_v4(),
+				OpId::new(),
+				peer,
+				SpacedropKind::Files,
+				vec!["IMG_0001.jpg".into()],
+			)
+		};
+
+		set_peer_alias(&node_config, peer, Some(" Work laptop ".into()))
+			.await
+			.unwrap();
+		assert!(matches!(
+			request(&node_config.get().await),
+			P2PEvent::SpacedropRequest { identity, peer_name, display_name, .. }
+				if identity == peer && peer_name == "Unknown" && display_name == "Work laptop"
+		));
+		assert_eq!(
+			display_name(&node_config.get().await, &p2p, peer).as_deref(),
+			Some("Work laptop")
+		);
+
+		// Removing the alias goes back to the name the peer advertises, which we don't know here
+		set_peer_alias(&node_config, peer, None).await.unwrap();
+		assert!(matches!(
+			request(&node_config.get().await),
+			P2PEvent::SpacedropRequest { display_name, .. } if display_name == "Unknown"
+		));
+		assert!(node_config.get().await.peer_aliases.is_empty());
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn rejection_names_peer_and_files() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let mut sender_events = sender.p2p.events.subscribe();
+		let mut receiver_events = receiver.p2p.events.subscribe();
+
+		let sent = tempdir().unwrap();
+		let mut paths = vec![];
+		for name in ["IMG_0001.jpg", "IMG_0002.jpg"] {
+			let path = sent.path().join(name);
+			fs::write(&path, vec![0; 2048]).await.unwrap();
+			paths.push(path);
+		}
+		let id = spacedrop(
+			sender.p2p.clone(),
+			receiver.p2p.p2p.remote_identity(),
+			paths,
+		)
+		.await
+		.unwrap();
+
+		timeout(Duration::from_secs(5), async {
+			while !matches!(
+				receiver_events.recv().await.unwrap(),
+				P2PEvent::SpacedropRequest { id: requested, .. } if requested == id
+			) {}
+		})
+		.await
+		.unwrap();
+
+		// The receiving node rejects the Spacedrop, like from its prompt
+		receiver.p2p.reject_spacedrop(id).await;
+
+		let (identity, files, total_bytes) = timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropRejected {
+					id: rejected,
+					identity,
+					files,
+					total_bytes,
+					..
+				} = sender_events.recv().await.unwrap()
+				{
+					if rejected == id {
+						break (identity, files, total_bytes);
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(identity, receiver.p2p.p2p.remote_identity());
+		assert_eq!(files, ["IMG_0001.jpg", "IMG_0002.jpg"]);
+		assert_eq!(total_bytes, 4096);
+
+		// Reaching a terminal state cleans up the registry
+		timeout(Duration::from_secs(5), async {
+			while sender.p2p.spacedrop_transfers.get(id).is_some() {
+				sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn files_are_spacedropped_between_nodes() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let mut sender_events = sender.p2p.events.subscribe();
+		let mut receiver_events = receiver.p2p.events.subscribe();
+
+		let sent = tempdir().unwrap();
+		let path = sent.path().join("IMG_0001.jpg");
+		fs::write(&path, b"Spacedrive").await.unwrap();
+		let id = spacedrop(
+			sender.p2p.clone(),
+			receiver.p2p.p2p.remote_identity(),
+			vec![path],
+		)
+		.await
+		.unwrap();
+
+		// Through the header and the dispatch of the receiving node, like from another device
+		let (requested, from) = timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropRequest {
+					id,
+					identity,
+					files,
+					..
+				} = receiver_events.recv().await.unwrap()
+				{
+					assert_eq!(files, ["IMG_0001.jpg"]);
+					break (id, identity);
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(requested, id);
+		assert_eq!(from, sender.p2p.p2p.remote_identity());
+
+		let destination = tempdir().unwrap();
+		receiver
+			.p2p
+			.accept_spacedrop(id, destination.path().to_path_buf(), false, false)
+			.await
+			.unwrap();
+
+		let saved_paths = timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropCompleted {
+					id: completed,
+					saved_paths,
+					..
+				} = sender_events.recv().await.unwrap()
+				{
+					if completed == id {
+						break saved_paths;
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(saved_paths, [destination.path().join("IMG_0001.jpg")]);
+		assert_eq!(fs::read(&saved_paths[0]).await.unwrap(), b"Spacedrive");
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn text_is_spacedropped_between_nodes() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let mut receiver_events = receiver.p2p.events.subscribe();
+
+		// Longer than the preview, with characters taking up more than a byte
+		let text = "Hello from Spacedrive! 👋 こんにちは ".repeat(10);
+		let id = spacedrop_text(
+			sender.p2p.clone(),
+			receiver.p2p.p2p.remote_identity(),
+			text.clone(),
+		)
+		.await
+		.unwrap();
+
+		let kind = timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropRequest {
+					id: requested,
+					kind,
+					files,
+					..
+				} = receiver_events.recv().await.unwrap()
+				{
+					assert_eq!(requested, id);
+					assert!(files.is_empty());
+					break kind;
+				}
+			}
+		})
+		.await
+		.unwrap();
+		let SpacedropKind::Text { preview, len } = kind else {
+			unreachable!("expected a text Spacedrop");
+		};
+		assert_eq!(
+			preview,
+			text.chars().take(TEXT_PREVIEW_CHARS).collect::<String>()
+		);
+		assert_eq!(len as usize, text.len());
+
+		// Small enough to be handed straight back to the frontend
+		let destination = tempdir().unwrap();
+		assert_eq!(
+			receiver
+				.p2p
+				.accept_spacedrop(id, destination.path().to_path_buf(), false, false)
+				.await
+				.unwrap(),
+			Some(text)
+		);
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn both_nodes_report_a_spacedrop_with_the_same_op_id() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let mut sender_events = sender.p2p.events.subscribe();
+		let mut receiver_events = receiver.p2p.events.subscribe();
+
+		let sent = tempdir().unwrap();
+		let path = sent.path().join("IMG_0001.jpg");
+		fs::write(&path, vec![0; 4096]).await.unwrap();
+		let id = spacedrop(
+			sender.p2p.clone(),
+			receiver.p2p.p2p.remote_identity(),
+			vec![path],
+		)
+		.await
+		.unwrap();
+
+		// The op ids of the events about this Spacedrop, up to the first one which matches `until`
+		async fn op_ids(
+			events: &mut P2PEventsReceiver,
+			id: Uuid,
+			until: fn(&P2PEvent) -> bool,
+		) -> Vec<OpId> {
+			let mut op_ids = vec![];
+			loop {
+				let event = events.recv().await.unwrap();
+				match &event {
+					P2PEvent::SpacedropRequest {
+						id: event_id,
+						op_id,
+						..
+					}
+					| P2PEvent::SpacedropProgress {
+						id: event_id,
+						op_id,
+						..
+					}
+					| P2PEvent::SpacedropCompleted {
+						id: event_id,
+						op_id,
+						..
+					} if *event_id == id => op_ids.push(*op_id),
+					_ => continue,
+				}
+				if until(&event) {
+					break op_ids;
+				}
+			}
+		}
+		let requested = |event: &P2PEvent| matches!(event, P2PEvent::SpacedropRequest { .. });
+		let completed = |event: &P2PEvent| matches!(event, P2PEvent::SpacedropCompleted { .. });
+
+		let (received_op_ids, sent_op_ids) = timeout(Duration::from_secs(5), async {
+			let mut received_op_ids = op_ids(&mut receiver_events, id, requested).await;
+
+			let destination = tempdir().unwrap();
+			receiver
+				.p2p
+				.accept_spacedrop(id, destination.path().to_path_buf(), false, false)
+				.await
+				.unwrap();
+
+			received_op_ids.extend(op_ids(&mut receiver_events, id, completed).await);
+			(
+				received_op_ids,
+				op_ids(&mut sender_events, id, completed).await,
+			)
+		})
+		.await
+		.unwrap();
+
+		// At least the request and the completion on the receiving node
+		assert!(received_op_ids.len() >= 2);
+		let op_id = sent_op_ids[0];
+		assert!(
+			sent_op_ids
+				.iter()
+				.chain(&received_op_ids)
+				.all(|event_op_id| *event_op_id == op_id),
+			"sent: {sent_op_ids:?}, received: {received_op_ids:?}"
+		);
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn spacedrops_fail_straight_away_while_the_receiver_is_not_taking_them() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let mut sender_events = sender.p2p.events.subscribe();
+		let mut receiver_events = receiver.p2p.events.subscribe();
+		let identity = receiver.p2p.p2p.remote_identity();
+
+		let sent = tempdir().unwrap();
+		let path = sent.path().join("IMG_0001.jpg");
+		fs::write(&path, b"Spacedrive").await.unwrap();
+
+		// Like `nodes.edit`, which advertises the new mode right away
+		async fn set_mode(receiver: &Node, sender: &Node, mode: config::SpacedropMode) {
+			receiver
+				.config
+				.write(|config| config.spacedrop_mode = mode)
+				.await
+				.unwrap();
+			receiver.p2p.on_node_config_change().await;
+
+			let identity = receiver.p2p.p2p.remote_identity();
+			timeout(Duration::from_secs(5), async {
+				loop {
+					let advertised = sender.p2p.p2p.peers().get(&identity).and_then(|peer| {
+						PeerMetadata::from_hashmap(&peer.metadata())
+							.ok()?
+							.spacedrop_mode
+					});
+					if advertised == Some(mode) {
+						break;
+					}
+					sleep(Duration::from_millis(10)).await;
+				}
+			})
+			.await
+			.unwrap();
+		}
+
+		set_mode(&receiver, &sender, config::SpacedropMode::Off).await;
+		let err = spacedrop(sender.p2p.clone(), identity, vec![path.clone()])
+			.await
+			.unwrap_err();
+		assert!(
+			matches!(err, SpacedropError::ReceivingDisabled(peer) if peer == identity),
+			"{err:?}"
+		);
+
+		set_mode(&receiver, &sender, config::SpacedropMode::Everyone).await;
+		let id = spacedrop(sender.p2p.clone(), identity, vec![path.clone()])
+			.await
+			.unwrap();
+		timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropRequest { id: requested, .. } =
+					receiver_events.recv().await.unwrap()
+				{
+					if requested == id {
+						break;
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+		receiver.p2p.reject_spacedrop(id).await;
+
+		// Changed without being advertised yet, the receiver still turns the Spacedrop down
+		receiver
+			.config
+			.write(|config| config.spacedrop_mode = config::SpacedropMode::Contacts)
+			.await
+			.unwrap();
+		let id = spacedrop(sender.p2p.clone(), identity, vec![path])
+			.await
+			.unwrap();
+		timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropRejected { id: rejected, .. } =
+					sender_events.recv().await.unwrap()
+				{
+					if rejected == id {
+						break;
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn spacedrops_from_strangers_are_rejected_when_only_contacts_can_reach_us() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		let mut sender_events = sender.p2p.events.subscribe();
+		receiver
+			.config
+			.write(|config| config.p2p_discovery = config::P2PDiscoveryState::ContactsOnly)
+			.await
+			.unwrap();
+
+		let sent = tempdir().unwrap();
+		let path = sent.path().join("IMG_0001.jpg");
+		fs::write(&path, b"Spacedrive").await.unwrap();
+		let id = spacedrop(
+			sender.p2p.clone(),
+			receiver.p2p.p2p.remote_identity(),
+			vec![path],
+		)
+		.await
+		.unwrap();
+
+		// Told straight away instead of waiting out the timeout
+		timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropRejected { id: rejected, .. } =
+					sender_events.recv().await.unwrap()
+				{
+					if rejected == id {
+						break;
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn spacedrops_are_turned_down_while_no_frontend_is_open() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		// Nothing subscribes to the events of the receiving node
+		let mut sender_events = sender.p2p.events.subscribe();
+
+		let sent = tempdir().unwrap();
+		let path = sent.path().join("IMG_0001.jpg");
+		fs::write(&path, b"Spacedrive").await.unwrap();
+		let id = spacedrop(
+			sender.p2p.clone(),
+			receiver.p2p.p2p.remote_identity(),
+			vec![path],
+		)
+		.await
+		.unwrap();
+
+		// Well before the Spacedrop would have timed out
+		let reason = timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropFailed {
+					id: failed, reason, ..
+				} = sender_events.recv().await.unwrap()
+				{
+					if failed == id {
+						break reason;
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(reason, RECEIVER_UNAVAILABLE_REASON);
+		assert!(receiver
+			.p2p
+			.spacedrop_pairing_reqs
+			.lock()
+			.unwrap()
+			.is_empty());
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn spacedrops_are_held_for_the_next_frontend_when_queueing() {
+		let network = MemoryNetwork::default();
+		let (sender_dir, receiver_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (sender, _) = Node::new_with_p2p_transport(
+			sender_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (receiver, _) = Node::new_with_p2p_transport(
+			receiver_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+		receiver
+			.config
+			.write(|config| config.queue_spacedrop_when_unattended = true)
+			.await
+			.unwrap();
+		let mut sender_events = sender.p2p.events.subscribe();
+
+		let sent = tempdir().unwrap();
+		let path = sent.path().join("IMG_0001.jpg");
+		fs::write(&path, b"Spacedrive").await.unwrap();
+		let id = spacedrop(
+			sender.p2p.clone(),
+			receiver.p2p.p2p.remote_identity(),
+			vec![path],
+		)
+		.await
+		.unwrap();
+
+		timeout(Duration::from_secs(5), async {
+			while !receiver
+				.p2p
+				.spacedrop_unattended
+				.lock()
+				.unwrap()
+				.contains_key(&id)
+			{
+				sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		// A frontend opens and is handed the request
+		let (_rx, held) = receiver.p2p.subscribe_frontend();
+		let requested = |event: &P2PEvent| match event {
+			P2PEvent::SpacedropRequest { id: requested, .. } => *requested == id,
+			_ => false,
+		};
+		assert!(held.iter().any(requested), "{held:?}");
+		assert!(receiver.p2p.spacedrop_unattended.lock().unwrap().is_empty());
+
+		let destination = tempdir().unwrap();
+		receiver
+			.p2p
+			.accept_spacedrop(id, destination.path().to_path_buf(), false, false)
+			.await
+			.unwrap();
+
+		let saved_paths = timeout(Duration::from_secs(5), async {
+			loop {
+				if let P2PEvent::SpacedropCompleted {
+					id: completed,
+					saved_paths,
+					..
+				} = sender_events.recv().await.unwrap()
+				{
+					if completed == id {
+						break saved_paths;
+					}
+				}
+			}
+		})
+		.await
+		.unwrap();
+		assert_eq!(fs::read(&saved_paths[0]).await.unwrap(), b"Spacedrive");
+
+		sender.shutdown().await;
+		receiver.shutdown().await;
+	}
 }
@@ -0,0 +1,293 @@
+use std::{
+	io,
+	path::Path,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
+
+use crate::{
+	object::media::old_thumbnail::{get_thumbnail_path_in, ThumbnailKind},
+	p2p::{Error as P2PError, Header, P2PManager, P2PMetrics, P2POperation},
+	Node,
+};
+use sd_p2p::{Peer, UnicastStream};
+use sd_p2p_proto::{decode, encode};
+use thiserror::Error;
+use tokio::{
+	fs,
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// Thumbnails are small so anything larger than this is refused by both sides
+const MAX_THUMBNAIL_BYTES: u32 = 10 * 1024 * 1024;
+
+const RESPONSE_FOUND: u8 = b'F';
+pub(crate) const RESPONSE_NOT_FOUND: u8 = b'N';
+
+/// The thumbnail sizes a peer can ask for.
+/// We only generate a single size currently but it's part of the header so more can be added without a new protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbSize {
+	Standard,
+}
+
+/// Ask a remote node for a thumbnail it has already generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderThumbnail {
+	pub library_id: Uuid,
+	pub cas_id: String,
+	pub size: ThumbSize,
+}
+
+impl HeaderThumbnail {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
+		let library_id = decode::uuid(stream).await?;
+		let cas_id = decode::string(stream).await?;
+		let size = match stream.read_u8().await? {
+			0 => ThumbSize::Standard,
+			d => {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("invalid thumbnail size '{d}'"),
+				)
+				.into())
+			}
+		};
+
+		Ok(Self {
+			library_id,
+			cas_id,
+			size,
+		})
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		encode::uuid(&mut bytes, &self.library_id);
+		encode::string(&mut bytes, &self.cas_id);
+		bytes.push(match self.size {
+			ThumbSize::Standard => 0,
+		});
+		bytes
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum RequestThumbnailError {
+	#[error("error connecting to peer: {0}")]
+	Connecting(String),
+	#[error("thumbnail of {0} bytes is too large")]
+	TooLarge(u32),
+	#[error("invalid response '{0}' from the remote node")]
+	InvalidResponse(u8),
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+}
+
+/// How often thumbnails requested by remote peers were found, for debugging.
+#[derive(Debug, Default)]
+pub(crate) struct ThumbnailStats {
+	hits: AtomicU64,
+	misses: AtomicU64,
+}
+
+impl ThumbnailStats {
+	pub(crate) fn hits(&self) -> u64 {
+		self.hits.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn misses(&self) -> u64 {
+		self.misses.load(Ordering::Relaxed)
+	}
+}
+
+/// Request a thumbnail from a remote node. Returns [`None`] if it hasn't generated one, so the caller can fall back to requesting the file or showing a placeholder.
+pub async fn request(
+	peer: &Peer,
+	library_id: Uuid,
+	cas_id: String,
+	size: ThumbSize,
+	metrics: &Arc<P2PMetrics>,
+) -> Result<Option<Vec<u8>>, RequestThumbnailError> {
+	let stream = peer
+		.new_stream()
+		.await
+		.map_err(|err| RequestThumbnailError::Connecting(err.to_string()))?;
+	let mut stream = metrics.instrument(P2POperation::File, stream);
+	stream
+		.write_all(
+			&Header::Thumbnail(HeaderThumbnail {
+				library_id,
+				cas_id,
+				size,
+			})
+			.to_bytes(),
+		)
+		.await?;
+
+	receive(&mut stream).await
+}
+
+pub(crate) async fn receive(
+	stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<Vec<u8>>, RequestThumbnailError> {
+	match stream.read_u8().await? {
+		RESPONSE_FOUND => {
+			let len = stream.read_u32_le().await?;
+			if len > MAX_THUMBNAIL_BYTES {
+				return Err(RequestThumbnailError::TooLarge(len));
+			}
+
+			let mut buf = vec![0; len as usize];
+			stream.read_exact(&mut buf).await?;
+			Ok(Some(buf))
+		}
+		RESPONSE_NOT_FOUND => Ok(None),
+		d => Err(RequestThumbnailError::InvalidResponse(d)),
+	}
+}
+
+pub(crate) async fn receiver(
+	node: &Arc<Node>,
+	p2p: &Arc<P2PManager>,
+	header: HeaderThumbnail,
+	mut stream: UnicastStream,
+) -> Result<(), P2PError> {
+	let remote = stream.remote_identity();
+	debug!(
+		"Received thumbnail request for '{}' from peer '{remote}'",
+		header.cas_id
+	);
+
+	// We only share thumbnails with other instances of the library
+	let data_dir = p2p
+		.get_instance(&header.library_id, remote)
+		.map(|_| node.config.data_directory());
+
+	serve(
+		data_dir.as_deref(),
+		&header,
+		&p2p.thumbnail_stats,
+		&mut stream,
+	)
+	.await
+	.map_err(P2PError::io("sending thumbnail"))
+}
+
+/// Send the thumbnail from the thumbnail store within `data_dir`, or the not-found frame if it doesn't exist.
+pub(crate) async fn serve(
+	data_dir: Option<&Path>,
+	header: &HeaderThumbnail,
+	stats: &ThumbnailStats,
+	stream: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<()> {
+	let thumbnail = match data_dir {
+		// The `cas_id` ends up in a path so anything which could escape the thumbnail directory is refused
+		Some(data_dir)
+			if header.cas_id.len() >= 3
+				&& header.cas_id.chars().all(|c| c.is_ascii_alphanumeric()) =>
+		{
+			let path = get_thumbnail_path_in(
+				data_dir,
+				&header.cas_id,
+				ThumbnailKind::Indexed(header.library_id),
+			);
+			fs::read(&path)
+				.await
+				.map_err(|err| {
+					if err.kind() != io::ErrorKind::NotFound {
+						error!("Failed to read thumbnail {path:?}: {err}");
+					}
+				})
+				.ok()
+				.filter(|bytes| bytes.len() <= MAX_THUMBNAIL_BYTES as usize)
+		}
+		_ => None,
+	};
+
+	match thumbnail {
+		Some(bytes) => {
+			stats.hits.fetch_add(1, Ordering::Relaxed);
+
+			let mut buf = Vec::with_capacity(bytes.len() + 5);
+			buf.push(RESPONSE_FOUND);
+			buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+			buf.extend_from_slice(&bytes);
+			stream.write_all(&buf).await?;
+		}
+		None => {
+			stats.misses.fetch_add(1, Ordering::Relaxed);
+			stream.write_all(&[RESPONSE_NOT_FOUND]).await?;
+		}
+	}
+
+	stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	async fn fetch(
+		data_dir: Option<&Path>,
+		header: HeaderThumbnail,
+		stats: &ThumbnailStats,
+	) -> Option<Vec<u8>> {
+		let mut stream = Vec::new();
+		serve(data_dir, &header, stats, &mut stream).await.unwrap();
+		receive(&mut stream.as_slice()).await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn header_roundtrip() {
+		let header = HeaderThumbnail {
+			library_id: Uuid::new_v4(),
+			cas_id: "abcdef0123456789".to_string(),
+			size: ThumbSize::Standard,
+		};
+		let bytes = Header::Thumbnail(header.clone()).to_bytes();
+		let result = Header::from_stream(&mut bytes.as_slice()).await.unwrap();
+		assert_eq!(result, Header::Thumbnail(header));
+	}
+
+	#[tokio::test]
+	async fn serves_thumbnails() {
+		let data_dir = tempfile::tempdir().unwrap();
+		let library_id = Uuid::new_v4();
+		let cas_id = "abcdef0123456789".to_string();
+
+		let path =
+			get_thumbnail_path_in(data_dir.path(), &cas_id, ThumbnailKind::Indexed(library_id));
+		fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+		fs::write(&path, b"not really a webp").await.unwrap();
+
+		let stats = ThumbnailStats::default();
+		let header = |cas_id: &str| HeaderThumbnail {
+			library_id,
+			cas_id: cas_id.to_string(),
+			size: ThumbSize::Standard,
+		};
+
+		assert_eq!(
+			fetch(Some(data_dir.path()), header(&cas_id), &stats).await,
+			Some(b"not really a webp".to_vec())
+		);
+		assert_eq!(
+			fetch(Some(data_dir.path()), header("0000000000000000"), &stats).await,
+			None
+		);
+		assert_eq!(
+			fetch(Some(data_dir.path()), header("../../etc/passwd"), &stats).await,
+			None
+		);
+		// Not an instance of the library
+		assert_eq!(fetch(None, header(&cas_id), &stats).await, None);
+
+		assert_eq!(stats.hits(), 1);
+		assert_eq!(stats.misses(), 3);
+	}
+}
@@ -0,0 +1,231 @@
+use sd_p2p::{ConnectionAddr, PeerConnectionCandidate, UnicastStream, P2P};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+use super::{
+	operations::{request_file::RequestFileError, thumbnail},
+	Header, P2POperation,
+};
+
+/// How a stream reached us, which decides the [`OperationPolicy`] it's checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum PeerOrigin {
+	/// From a peer mDNS discovered on the local network, at an address it was discovered at
+	Lan,
+	/// Directly from a peer which wasn't discovered there, eg. through a manually entered address
+	Manual,
+	/// Through a relay
+	Relay,
+}
+
+impl PeerOrigin {
+	/// Classify `stream` by the connection it came over, as a peer can be connected to us both over the
+	/// local network and through a relay. Streams the transport doesn't know the address of go by how we discovered their peer.
+	pub fn of(p2p: &P2P, stream: &UnicastStream) -> Self {
+		let addr = match stream.remote_addr() {
+			Some(ConnectionAddr::Relay) => return Self::Relay,
+			Some(ConnectionAddr::Direct(addr)) => Some(addr),
+			None => None,
+		};
+
+		let Some(peer) = p2p.peers().get(&stream.remote_identity()).cloned() else {
+			return Self::Manual;
+		};
+		let Some(mdns) = p2p
+			.hooks()
+			.into_iter()
+			.find_map(|(id, name)| (name == "mdns").then_some(id))
+		else {
+			return Self::Manual;
+		};
+
+		let discovered = peer.candidates_discovered_by(mdns);
+		let on_lan = match addr {
+			// The port of a connection isn't always the one the peer listens on
+			Some(addr) => discovered.iter().any(|candidate| {
+				matches!(candidate, PeerConnectionCandidate::SocketAddr(found) if found.ip() == addr.ip())
+			}),
+			None => !discovered.is_empty(),
+		};
+
+		if on_lan {
+			Self::Lan
+		} else {
+			Self::Manual
+		}
+	}
+}
+
+/// The operations peers are allowed to start with us, for each [`PeerOrigin`].
+/// Everything is allowed by default.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct OperationPolicy {
+	#[serde(default = "all_operations")]
+	pub lan: Vec<P2POperation>,
+	#[serde(default = "all_operations")]
+	pub manual: Vec<P2POperation>,
+	#[serde(default = "all_operations")]
+	pub relay: Vec<P2POperation>,
+}
+
+fn all_operations() -> Vec<P2POperation> {
+	P2POperation::ALL.to_vec()
+}
+
+impl Default for OperationPolicy {
+	fn default() -> Self {
+		Self {
+			lan: all_operations(),
+			manual: all_operations(),
+			relay: all_operations(),
+		}
+	}
+}
+
+impl OperationPolicy {
+	pub fn allows(&self, origin: PeerOrigin, operation: P2POperation) -> bool {
+		match origin {
+			PeerOrigin::Lan => &self.lan,
+			PeerOrigin::Manual => &self.manual,
+			PeerOrigin::Relay => &self.relay,
+		}
+		.contains(&operation)
+	}
+}
+
+/// Check an incoming stream against `policy`, giving it back if it can be handled.
+/// Refused streams are answered the way their operation refuses requests, so the remote sees a regular rejection.
+pub(crate) async fn enforce(
+	p2p: &P2P,
+	policy: Option<&OperationPolicy>,
+	header: &Header,
+	operation: P2POperation,
+	mut stream: UnicastStream,
+) -> Option<UnicastStream> {
+	let Some(policy) = policy else {
+		return Some(stream);
+	};
+
+	let remote = stream.remote_identity();
+	let origin = PeerOrigin::of(p2p, &stream);
+	if policy.allows(origin, operation) {
+		return Some(stream);
+	}
+
+	warn!("Refusing {operation:?} from peer '{remote}' connected over {origin:?}");
+
+	let response = match header {
+		// The same as the user declining the Spacedrop
+		Header::Spacedrop(_) => vec![0],
+		Header::File(_) | Header::FileBatch(_) => RequestFileError::Unauthorized.to_bytes(),
+		Header::Thumbnail(_) => vec![thumbnail::RESPONSE_NOT_FOUND],
+		// Pings and sync requests are just closed, which their senders already handle
+		Header::Ping | Header::Sync(_) | Header::Http | Header::Pair(_) | Header::Identify(_) => {
+			return None
+		}
+	};
+
+	if let Err(err) = stream.write_all(&response).await {
+		debug!("Failed to refuse {operation:?} from peer '{remote}': {err}");
+		return None;
+	}
+	stream.flush().await.ok();
+
+	None
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::{collections::HashMap, time::Duration};
+
+	use sd_p2p::{flume::bounded, Identity, MemoryNetwork};
+	use sd_p2p_tunnel::Tunnel;
+	use tempfile::tempdir;
+	use tokio::{
+		io::{duplex, AsyncReadExt},
+		sync::oneshot,
+		time::timeout,
+	};
+	use uuid::Uuid;
+
+	use crate::{
+		p2p::{operations::spacedrop::SpacedropPayload, OpId, P2PTransport},
+		Env, Node,
+	};
+
+	use super::*;
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn streams_over_a_relay_are_held_to_its_policy() {
+		let dir = tempdir().unwrap();
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(MemoryNetwork::default()),
+		)
+		.await
+		.unwrap();
+		node.config
+			.write(|config| {
+				config.p2p_allowed_operations = Some(OperationPolicy {
+					relay: vec![P2POperation::Sync],
+					..Default::default()
+				})
+			})
+			.await
+			.unwrap();
+
+		// Streams from the peer reach the node like they do from a transport, over a relayed connection
+		let (hook_tx, _hook_rx) = bounded(15);
+		let listener = node
+			.p2p
+			.p2p
+			.register_listener("relay", hook_tx, |_, _, _| {});
+		let remote = Identity::new().to_remote_identity();
+		let identity = node.p2p.p2p.remote_identity();
+		let open_stream = |header: Header| {
+			let (stream, remote_stream) = duplex(1024);
+			node.p2p.p2p.clone().connected_to(
+				listener,
+				HashMap::new(),
+				UnicastStream::new(remote, stream).with_remote_addr(Some(ConnectionAddr::Relay)),
+				Some(ConnectionAddr::Relay),
+				oneshot::channel().0,
+			);
+			let mut remote_stream = UnicastStream::new(identity, remote_stream);
+			async move {
+				remote_stream
+					.write_all(&header.to_bytes_with_op_id(OpId::new()))
+					.await
+					.unwrap();
+				remote_stream
+			}
+		};
+
+		// Declined like the user would, instead of being put to them
+		let mut stream = open_stream(Header::Spacedrop(SpacedropPayload::Text {
+			id: Uuid::new_v4(),
+			preview: "hello".into(),
+			len: 5,
+		}))
+		.await;
+		let mut response = [0xff];
+		timeout(Duration::from_secs(5), stream.read_exact(&mut response))
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(response, [0]);
+
+		// The sync responder sets up its tunnel
+		let stream = open_stream(Header::Sync(Uuid::new_v4())).await;
+		timeout(Duration::from_secs(5), Tunnel::initiator(stream))
+			.await
+			.unwrap()
+			.unwrap();
+
+		node.shutdown().await;
+	}
+}
@@ -1,18 +1,81 @@
+use std::fmt;
+
+use sd_p2p::RemoteIdentity;
 use sd_p2p_block::{SpaceblockRequests, SpaceblockRequestsError};
 use sd_p2p_proto::{decode, encode};
+use serde::Serialize;
+use specta::Type;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::info_span;
 use uuid::Uuid;
 
+use super::{
+	operations::{
+		identify::{HeaderIdentify, PeerProtocol},
+		pair::HeaderPair,
+		request_file::{FileTarget, HeaderFile, HeaderFileBatch},
+		spacedrop::{SpacedropPayload, MAX_TEXT_PREVIEW_LEN},
+		thumbnail::HeaderThumbnail,
+	},
+	P2POperation,
+};
+
+/// Sent ahead of the discriminator of a [`Header`] when it's followed by the [`OpId`] of the operation
+const OP_ID_DISCRIMINATOR: u8 = 10;
+
+/// Identifies a single operation on both peers, so the logs and events of each side can be matched up.
+///
+/// It's picked by the peer starting the operation and sent with its [`Header`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Type)]
+#[serde(transparent)]
+pub struct OpId(Uuid);
+
+impl OpId {
+	#[allow(clippy::new_without_default)]
+	pub fn new() -> Self {
+		Self(Uuid::new_v4())
+	}
+
+	pub(crate) fn from_uuid(uuid: Uuid) -> Self {
+		Self(uuid)
+	}
+
+	pub(crate) fn as_uuid(&self) -> &Uuid {
+		&self.0
+	}
+
+	/// The span both peers handle the operation with `peer` in
+	pub(crate) fn span(self, peer: RemoteIdentity, operation: P2POperation) -> tracing::Span {
+		info_span!("p2p_operation", op_id = %self, %peer, ?operation)
+	}
+}
+
+impl fmt::Display for OpId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
 /// TODO
 #[derive(Debug, PartialEq, Eq)]
 pub enum Header {
 	// TODO: Split out cause this is a broadcast
 	Ping,
-	Spacedrop(SpaceblockRequests),
+	Spacedrop(SpacedropPayload),
 	Sync(Uuid),
+	// Request (part of) a file from a library we share with the remote node, by id or by its path in a location
+	File(HeaderFile),
+	// Request many whole files over a single stream
+	FileBatch(HeaderFileBatch),
+	// Request a thumbnail the remote node has already generated
+	Thumbnail(HeaderThumbnail),
 	// A HTTP server used for rspc requests and streaming files
 	Http,
+	// Pair with the remote node so it's added to our contacts
+	Pair(HeaderPair),
+	// Ask the remote node for its details, which it answers signed
+	Identify(HeaderIdentify),
 }
 
 #[derive(Debug, Error)]
@@ -23,37 +86,153 @@ pub enum HeaderError {
 	DiscriminatorInvalid(u8),
 	#[error("error reading spacedrop request: {0}")]
 	SpacedropRequest(#[from] SpaceblockRequestsError),
+	#[error("error reading spacedrop text request: {0}")]
+	SpacedropTextRequest(decode::Error),
 	#[error("error reading sync request: {0}")]
 	SyncRequest(decode::Error),
+	#[error("error reading file request: {0}")]
+	FileRequest(decode::Error),
+	#[error("error reading thumbnail request: {0}")]
+	ThumbnailRequest(decode::Error),
+	#[error("error reading pairing request: {0}")]
+	PairRequest(decode::Error),
+	#[error("error reading identify request: {0}")]
+	IdentifyRequest(decode::Error),
+	#[error("error reading operation id: {0}")]
+	OpId(decode::Error),
 }
 
 impl Header {
 	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, HeaderError> {
-		let discriminator = stream
+		Self::from_stream_with_op_id(stream)
+			.await
+			.map(|(header, _)| header)
+	}
+
+	/// Reads the header along with the [`OpId`] it was sent with, [`None`] for nodes from before they were sent
+	pub async fn from_stream_with_op_id(
+		stream: &mut (impl AsyncRead + Unpin),
+	) -> Result<(Self, Option<OpId>), HeaderError> {
+		let mut discriminator = stream
 			.read_u8()
 			.await
 			.map_err(HeaderError::DiscriminatorIo)?;
 
+		let mut op_id = None;
+		if discriminator == OP_ID_DISCRIMINATOR {
+			op_id = Some(OpId(decode::uuid(stream).await.map_err(HeaderError::OpId)?));
+			discriminator = stream
+				.read_u8()
+				.await
+				.map_err(HeaderError::DiscriminatorIo)?;
+		}
+
+		Self::from_discriminator(discriminator, op_id.is_none(), stream)
+			.await
+			.map(|header| (header, op_id))
+	}
+
+	/// `legacy` is for headers sent without an [`OpId`], whose requests are in the format of the nodes from before them
+	async fn from_discriminator(
+		discriminator: u8,
+		legacy: bool,
+		stream: &mut (impl AsyncRead + Unpin),
+	) -> Result<Self, HeaderError> {
 		match discriminator {
-			0 => Ok(Self::Spacedrop(
-				SpaceblockRequests::from_stream(stream).await?,
-			)),
+			0 => Ok(Self::Spacedrop(SpacedropPayload::Files(match legacy {
+				true => SpaceblockRequests::from_stream_legacy(stream).await?,
+				false => SpaceblockRequests::from_stream(stream).await?,
+			}))),
 			1 => Ok(Self::Ping),
+			2 => Ok(Self::File(
+				HeaderFile::from_stream(stream, false)
+					.await
+					.map_err(HeaderError::FileRequest)?,
+			)),
 			3 => Ok(Self::Sync(
 				decode::uuid(stream)
 					.await
 					.map_err(HeaderError::SyncRequest)?,
 			)),
+			4 => Ok(Self::FileBatch(
+				HeaderFileBatch::from_stream(stream)
+					.await
+					.map_err(HeaderError::FileRequest)?,
+			)),
 			5 => Ok(Self::Http),
+			6 => Ok(Self::Spacedrop(SpacedropPayload::Text {
+				id: decode::uuid(stream)
+					.await
+					.map_err(HeaderError::SpacedropTextRequest)?,
+				preview: decode::string_max(stream, MAX_TEXT_PREVIEW_LEN)
+					.await
+					.map_err(HeaderError::SpacedropTextRequest)?,
+				len: stream
+					.read_u64_le()
+					.await
+					.map_err(|err| HeaderError::SpacedropTextRequest(err.into()))?,
+			})),
+			7 => Ok(Self::Thumbnail(
+				HeaderThumbnail::from_stream(stream)
+					.await
+					.map_err(HeaderError::ThumbnailRequest)?,
+			)),
+			8 => Ok(Self::Pair(
+				HeaderPair::from_stream(stream)
+					.await
+					.map_err(HeaderError::PairRequest)?,
+			)),
+			9 => Ok(Self::File(
+				HeaderFile::from_stream(stream, true)
+					.await
+					.map_err(HeaderError::FileRequest)?,
+			)),
+			11 => Ok(Self::Identify(
+				HeaderIdentify::from_stream(stream)
+					.await
+					.map_err(HeaderError::IdentifyRequest)?,
+			)),
 			d => Err(HeaderError::DiscriminatorInvalid(d)),
 		}
 	}
 
+	/// The header of the operation `op_id`, as a peer speaking `protocol` understands it.
+	/// Peers from before operations had ids would refuse the header if it was sent with one.
+	pub fn to_bytes_for(&self, op_id: OpId, protocol: &PeerProtocol) -> Vec<u8> {
+		match protocol.is_current() {
+			true => self.to_bytes_with_op_id(op_id),
+			false => self.to_bytes(),
+		}
+	}
+
+	/// The header of the operation `op_id`
+	pub fn to_bytes_with_op_id(&self, op_id: OpId) -> Vec<u8> {
+		let mut bytes = vec![OP_ID_DISCRIMINATOR];
+		encode::uuid(&mut bytes, &op_id.0);
+		bytes.extend_from_slice(&self.encode(false));
+		bytes
+	}
+
+	/// The header without an [`OpId`], so Spacedrop requests are in the format of the nodes from before them
 	pub fn to_bytes(&self) -> Vec<u8> {
+		self.encode(true)
+	}
+
+	fn encode(&self, legacy: bool) -> Vec<u8> {
 		match self {
-			Self::Spacedrop(transfer_request) => {
+			Self::Spacedrop(SpacedropPayload::Files(transfer_request)) => {
 				let mut bytes = vec![0];
-				bytes.extend_from_slice(&transfer_request.to_bytes());
+				bytes.extend_from_slice(&match legacy {
+					true => transfer_request.to_bytes_legacy(),
+					false => transfer_request.to_bytes(),
+				});
+				bytes
+			}
+			Self::Spacedrop(SpacedropPayload::Text { id, preview, len }) => {
+				let mut bytes = vec![6];
+				encode::uuid(&mut bytes, id);
+				encode::string(&mut bytes, preview);
+				bytes.extend_from_slice(&len.to_le_bytes());
 				bytes
 			}
 			Self::Ping => vec![1],
@@ -62,14 +241,47 @@ impl Header {
 				encode::uuid(&mut bytes, uuid);
 				bytes
 			}
+			Self::File(header) => {
+				let mut bytes = match header.target {
+					FileTarget::Id(_) => vec![2],
+					FileTarget::Path { .. } => vec![9],
+				};
+				bytes.extend_from_slice(&header.to_bytes());
+				bytes
+			}
+			Self::FileBatch(header) => {
+				let mut bytes = vec![4];
+				bytes.extend_from_slice(&header.to_bytes());
+				bytes
+			}
+			Self::Thumbnail(header) => {
+				let mut bytes = vec![7];
+				bytes.extend_from_slice(&header.to_bytes());
+				bytes
+			}
 			Self::Http => vec![5],
+			Self::Pair(header) => {
+				let mut bytes = vec![8];
+				bytes.extend_from_slice(&header.to_bytes());
+				bytes
+			}
+			Self::Identify(header) => {
+				let mut bytes = vec![11];
+				bytes.extend_from_slice(&header.to_bytes());
+				bytes
+			}
 		}
 	}
 }
 
 #[cfg(test)]
+#[allow(clippy::unwrap_used)]
 mod tests {
-	// use super::*;
+	use std::time::{Duration, UNIX_EPOCH};
+
+	use sd_p2p_block::{BlockSize, Compression, Range, SpaceblockRequest};
+
+	use super::*;
 
 	#[test]
 	fn test_header() {
@@ -91,4 +303,81 @@ mod tests {
 		// 		Ok(Header::Sync(uuid))
 		// 	);
 	}
+
+	#[tokio::test]
+	async fn op_ids_are_read_with_the_header() {
+		let op_id = OpId::new();
+		let library_id = Uuid::new_v4();
+
+		let bytes = Header::Sync(library_id).to_bytes_with_op_id(op_id);
+		assert_eq!(
+			Header::from_stream_with_op_id(&mut bytes.as_slice())
+				.await
+				.unwrap(),
+			(Header::Sync(library_id), Some(op_id))
+		);
+		// Receivers which don't care about it still get the header
+		assert_eq!(
+			Header::from_stream(&mut bytes.as_slice()).await.unwrap(),
+			Header::Sync(library_id)
+		);
+
+		// Nodes from before operations had ids aren't sent one
+		assert_eq!(
+			Header::Sync(library_id).to_bytes_for(op_id, &PeerProtocol::legacy()),
+			Header::Sync(library_id).to_bytes()
+		);
+
+		// Sent by nodes from before operations had ids
+		let bytes = Header::Ping.to_bytes();
+		assert_eq!(
+			Header::from_stream_with_op_id(&mut bytes.as_slice())
+				.await
+				.unwrap(),
+			(Header::Ping, None)
+		);
+	}
+
+	#[tokio::test]
+	async fn spacedrops_to_legacy_peers_use_their_requests() {
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(42),
+			compression: Compression::Zstd,
+			requests: vec![SpaceblockRequest {
+				name: "Demo".into(),
+				size: 42,
+				range: Range::Full,
+				modified_at: Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+				created_at: None,
+				mode: Some(0o644),
+			}],
+		};
+		let header = Header::Spacedrop(SpacedropPayload::Files(req.clone()));
+
+		let mut legacy = vec![0];
+		legacy.extend_from_slice(&req.to_bytes_legacy());
+		assert_eq!(
+			header.to_bytes_for(OpId::new(), &PeerProtocol::legacy()),
+			legacy
+		);
+
+		// Without an op id, the requests are read like nodes from before them sent them
+		let Header::Spacedrop(SpacedropPayload::Files(received)) =
+			Header::from_stream(&mut legacy.as_slice()).await.unwrap()
+		else {
+			unreachable!();
+		};
+		assert_eq!(received.compression, Compression::None);
+		assert_eq!(received.requests[0].modified_at, None);
+
+		let op_id = OpId::new();
+		let bytes = header.to_bytes_with_op_id(op_id);
+		assert_eq!(
+			Header::from_stream_with_op_id(&mut bytes.as_slice())
+				.await
+				.unwrap(),
+			(header, Some(op_id))
+		);
+	}
 }
@@ -0,0 +1,339 @@
+use std::{
+	io,
+	net::{Ipv4Addr, UdpSocket},
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use sd_p2p::{Listener, Mdns, P2P};
+use serde::Serialize;
+use specta::Type;
+
+use super::{
+	operations::{identify::IdentifyCache, ping::ping},
+	P2PMetrics,
+};
+
+/// How long mDNS gets to answer for our own service
+const MDNS_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the UDP probe gets to come back over loopback
+const UDP_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a peer gets to answer the ping
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+const UDP_PROBE: &[u8] = b"sd-p2p-self-test";
+
+/// The outcome of [`P2PManager::self_test`](super::P2PManager::self_test), for the settings page to
+/// tell why this node can't see or reach other nodes
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct P2PSelfTestReport {
+	pub ran_at: DateTime<Utc>,
+	/// In the order they ran
+	pub checks: Vec<SelfTestCheck>,
+}
+
+impl P2PSelfTestReport {
+	/// Whether none of the checks failed, skipped ones don't count
+	pub fn passed(&self) -> bool {
+		self.checks
+			.iter()
+			.all(|check| check.status != SelfTestStatus::Failed)
+	}
+
+	pub fn check(&self, kind: SelfTestCheckKind) -> Option<&SelfTestCheck> {
+		self.checks.iter().find(|check| check.kind == kind)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct SelfTestCheck {
+	pub kind: SelfTestCheckKind,
+	pub status: SelfTestStatus,
+	/// What was found, to be shown as is
+	pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Type)]
+pub enum SelfTestCheckKind {
+	/// The transport's listener is bound
+	Listeners,
+	/// The mDNS responder answers for our own service
+	Mdns,
+	/// A UDP datagram can be sent to a probe on loopback and back
+	Udp,
+	/// One of the known peers answers a ping
+	Ping,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Type)]
+pub enum SelfTestStatus {
+	Passed,
+	Failed,
+	/// There was nothing to check, eg. pinging without any known peer
+	Skipped,
+}
+
+impl SelfTestCheck {
+	fn passed(kind: SelfTestCheckKind, detail: impl Into<String>) -> Self {
+		Self {
+			kind,
+			status: SelfTestStatus::Passed,
+			detail: detail.into(),
+		}
+	}
+
+	fn failed(kind: SelfTestCheckKind, detail: impl Into<String>) -> Self {
+		Self {
+			kind,
+			status: SelfTestStatus::Failed,
+			detail: detail.into(),
+		}
+	}
+
+	fn skipped(kind: SelfTestCheckKind, detail: impl Into<String>) -> Self {
+		Self {
+			kind,
+			status: SelfTestStatus::Skipped,
+			detail: detail.into(),
+		}
+	}
+}
+
+/// Whether the listener named `transport` is registered, and bound to an address unless the transport has none
+pub(super) fn check_listeners(
+	listeners: &[Listener],
+	transport: &str,
+	needs_addrs: bool,
+) -> SelfTestCheck {
+	let kind = SelfTestCheckKind::Listeners;
+	let Some(listener) = listeners.iter().find(|l| l.name == transport) else {
+		return SelfTestCheck::failed(kind, format!("no {transport} listener is registered"));
+	};
+
+	if needs_addrs && listener.addrs.is_empty() {
+		return SelfTestCheck::failed(
+			kind,
+			format!(
+				"the {transport} listener isn't bound to any address, are both ports disabled in the settings?"
+			),
+		);
+	}
+
+	let bound = listeners
+		.iter()
+		.map(|l| {
+			let mut addrs = l.addrs.iter().map(ToString::to_string).collect::<Vec<_>>();
+			addrs.sort();
+			match addrs.is_empty() {
+				true => format!("{} without an address", l.name),
+				false => format!("{} on {}", l.name, addrs.join(", ")),
+			}
+		})
+		.collect::<Vec<_>>();
+
+	SelfTestCheck::passed(kind, format!("listening with {}", bound.join("; ")))
+}
+
+/// Whether the running mDNS responder answers a query for our own service
+pub(super) async fn check_mdns(p2p: &P2P) -> SelfTestCheck {
+	let kind = SelfTestCheckKind::Mdns;
+	match Mdns::probe(p2p, MDNS_PROBE_TIMEOUT).await {
+		Ok(took) => SelfTestCheck::passed(
+			kind,
+			format!("our service was found in {}ms", took.as_millis()),
+		),
+		Err(err) => SelfTestCheck::failed(kind, err),
+	}
+}
+
+/// Send a datagram to an echo socket on loopback and wait for it to come back.
+/// If this fails the OS or a firewall is getting in the way of any UDP traffic, which QUIC runs over.
+pub(super) async fn check_udp() -> SelfTestCheck {
+	let kind = SelfTestCheckKind::Udp;
+	let result = tokio::task::spawn_blocking(|| -> io::Result<Duration> {
+		let echo = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+		let probe = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+		echo.set_read_timeout(Some(UDP_PROBE_TIMEOUT))?;
+		probe.set_read_timeout(Some(UDP_PROBE_TIMEOUT))?;
+
+		let start = Instant::now();
+		probe.send_to(UDP_PROBE, echo.local_addr()?)?;
+		let mut buf = [0; UDP_PROBE.len()];
+		let (len, from) = echo.recv_from(&mut buf)?;
+		echo.send_to(&buf[..len], from)?;
+		let len = probe.recv(&mut buf)?;
+		if &buf[..len] != UDP_PROBE {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"the probe came back altered",
+			));
+		}
+
+		Ok(start.elapsed())
+	})
+	.await;
+
+	match result {
+		Ok(Ok(took)) => SelfTestCheck::passed(
+			kind,
+			format!("the loopback probe answered in {}µs", took.as_micros()),
+		),
+		Ok(Err(err))
+			if matches!(
+				err.kind(),
+				io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+			) =>
+		{
+			SelfTestCheck::failed(
+				kind,
+				format!("the loopback probe didn't answer within {UDP_PROBE_TIMEOUT:?}"),
+			)
+		}
+		Ok(Err(err)) => SelfTestCheck::failed(kind, format!("the loopback probe failed: {err}")),
+		Err(err) => SelfTestCheck::failed(kind, format!("the loopback probe didn't run: {err}")),
+	}
+}
+
+/// Ping a peer, preferring one we're already connected to, skipped when no peer is known
+pub(super) async fn check_ping(
+	p2p: &P2P,
+	metrics: &Arc<P2PMetrics>,
+	identified: &IdentifyCache,
+) -> SelfTestCheck {
+	let kind = SelfTestCheckKind::Ping;
+	let peer = {
+		let peers = p2p.peers();
+		peers
+			.values()
+			.find(|peer| peer.is_connected())
+			.or_else(|| peers.values().find(|peer| peer.can_connect()))
+			.cloned()
+	};
+	let Some(peer) = peer else {
+		return SelfTestCheck::skipped(kind, "no peer is known to ping");
+	};
+
+	let start = Instant::now();
+	match ping(
+		&peer,
+		PING_TIMEOUT,
+		&identified.known_protocol(peer.identity()),
+		metrics,
+	)
+	.await
+	{
+		Ok(_) => SelfTestCheck::passed(
+			kind,
+			format!(
+				"{} answered in {}ms",
+				peer.identity(),
+				start.elapsed().as_millis()
+			),
+		),
+		Err(err) => {
+			SelfTestCheck::failed(kind, format!("{} didn't answer: {err}", peer.identity()))
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use crate::{node::config::P2PDiscoveryState, p2p::P2PTransport, Env, Node};
+
+	use sd_p2p::{flume::bounded, MemoryNetwork};
+	use tempfile::tempdir;
+
+	use super::*;
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn a_healthy_node_passes_its_local_checks() {
+		let network = MemoryNetwork::default();
+		let dir = tempdir().unwrap();
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+
+		// Memory nodes don't need mDNS to find each other, so start it by hand with an address to announce
+		let (hook_tx, _hook_rx) = bounded(15);
+		let listener = node
+			.p2p
+			.p2p
+			.register_listener("mdns-test", hook_tx, |_, _, _| {});
+		node.p2p
+			.p2p
+			.register_listener_addr(listener, (Ipv4Addr::LOCALHOST, 7374).into());
+		node.p2p.start_mdns().unwrap();
+
+		let report = node.p2p.self_test().await;
+		assert!(report.passed(), "{report:#?}");
+		let kinds = report.checks.iter().map(|c| c.kind).collect::<Vec<_>>();
+		assert_eq!(
+			kinds,
+			[
+				SelfTestCheckKind::Listeners,
+				SelfTestCheckKind::Mdns,
+				SelfTestCheckKind::Udp,
+				SelfTestCheckKind::Ping
+			]
+		);
+		// Alone on its network there's nobody to ping
+		assert_eq!(
+			report.check(SelfTestCheckKind::Ping).unwrap().status,
+			SelfTestStatus::Skipped
+		);
+
+		node.config
+			.write(|c| c.p2p_discovery = P2PDiscoveryState::Disabled)
+			.await
+			.unwrap();
+		node.p2p.on_node_config_change().await;
+
+		// mDNS is stopped, so our service goes unanswered
+		let report = node.p2p.self_test().await;
+		assert!(!report.passed());
+		let mdns = report.check(SelfTestCheckKind::Mdns).unwrap();
+		assert!(
+			mdns.detail
+				.ends_with("discovery is disabled in the settings"),
+			"{mdns:#?}"
+		);
+		for check in &report.checks {
+			let expected = match check.kind {
+				SelfTestCheckKind::Mdns => SelfTestStatus::Failed,
+				SelfTestCheckKind::Ping => SelfTestStatus::Skipped,
+				_ => SelfTestStatus::Passed,
+			};
+			assert_eq!(check.status, expected, "{check:#?}");
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn a_peer_on_the_network_is_pinged() {
+		let network = MemoryNetwork::default();
+		let (dir, other_dir) = (tempdir().unwrap(), tempdir().unwrap());
+		let (node, _) = Node::new_with_p2p_transport(
+			dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (_other, _) = Node::new_with_p2p_transport(
+			other_dir.path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+
+		let ping = node.p2p.self_test().await;
+		let ping = ping.check(SelfTestCheckKind::Ping).unwrap();
+		assert_eq!(ping.status, SelfTestStatus::Passed, "{ping:#?}");
+	}
+}
@@ -0,0 +1,256 @@
+use crate::util::write_atomic;
+
+use std::{
+	collections::VecDeque,
+	path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use sd_p2p::RemoteIdentity;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use specta::Type;
+use tokio::{fs, sync::Mutex};
+use tracing::warn;
+use uuid::Uuid;
+
+/// How many finished Spacedrops the [`SpacedropHistory`] keeps, older ones are dropped
+pub const SPACEDROP_HISTORY_LEN: usize = 500;
+
+/// The file within the node's data directory the history is stored in
+const SPACEDROP_HISTORY_FILE: &str = "spacedrop_history.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum SpacedropDirection {
+	Sent,
+	Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub enum SpacedropOutcome {
+	Completed,
+	Rejected,
+	TimedOut,
+	Failed,
+	Cancelled,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Type)]
+pub struct SpacedropHistoryEntry {
+	pub id: Uuid,
+	pub direction: SpacedropDirection,
+	pub identity: RemoteIdentity,
+	// The peer's name when the Spacedrop happened, if it was known
+	pub peer_name: Option<String>,
+	pub files: Vec<String>,
+	#[specta(type = String)]
+	#[serde_as(as = "DisplayFromStr")]
+	pub total_bytes: u64,
+	pub outcome: SpacedropOutcome,
+	// Why it failed, for `SpacedropOutcome::Failed`
+	pub reason: Option<String>,
+	// Where the receiver saved the files, only known once it completed
+	pub saved_paths: Vec<PathBuf>,
+	pub started_at: DateTime<Utc>,
+	pub finished_at: DateTime<Utc>,
+}
+
+/// Which entries of the [`SpacedropHistory`] to list, everything when empty.
+#[derive(Debug, Clone, Default, Deserialize, Type)]
+pub struct SpacedropHistoryFilter {
+	pub direction: Option<SpacedropDirection>,
+	pub identity: Option<RemoteIdentity>,
+	pub outcome: Option<SpacedropOutcome>,
+	// Only transfers with a file whose name contains this, ignoring case
+	pub file_name: Option<String>,
+}
+
+impl SpacedropHistoryFilter {
+	fn matches(&self, entry: &SpacedropHistoryEntry) -> bool {
+		self.direction.map_or(true, |d| entry.direction == d)
+			&& self.identity.map_or(true, |i| entry.identity == i)
+			&& self.outcome.map_or(true, |o| entry.outcome == o)
+			&& self.file_name.as_ref().map_or(true, |name| {
+				let name = name.to_lowercase();
+				entry
+					.files
+					.iter()
+					.any(|file| file.to_lowercase().contains(&name))
+			})
+	}
+}
+
+/// The most recent finished Spacedrops, kept in the node's data directory so they can be looked up later.
+#[derive(Debug)]
+pub struct SpacedropHistory {
+	path: PathBuf,
+	entries: Mutex<VecDeque<SpacedropHistoryEntry>>,
+}
+
+impl SpacedropHistory {
+	/// Load the history stored in `data_dir`, starting over if it's missing or can't be read
+	pub(crate) async fn load(data_dir: impl AsRef<Path>) -> Self {
+		let path = data_dir.as_ref().join(SPACEDROP_HISTORY_FILE);
+
+		let entries = match fs::read(&path).await {
+			Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+				warn!(
+					"Failed to parse the Spacedrop history at '{}': {err}",
+					path.display()
+				);
+				VecDeque::new()
+			}),
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+			Err(err) => {
+				warn!(
+					"Failed to read the Spacedrop history at '{}': {err}",
+					path.display()
+				);
+				VecDeque::new()
+			}
+		};
+
+		Self {
+			path,
+			entries: Mutex::new(entries),
+		}
+	}
+
+	pub(crate) async fn record(&self, entry: SpacedropHistoryEntry) {
+		let mut entries = self.entries.lock().await;
+		while entries.len() >= SPACEDROP_HISTORY_LEN {
+			entries.pop_front();
+		}
+		entries.push_back(entry);
+
+		self.save(&entries).await;
+	}
+
+	/// The entries matching `filter`, newest first
+	pub async fn list(&self, filter: &SpacedropHistoryFilter) -> Vec<SpacedropHistoryEntry> {
+		self.entries
+			.lock()
+			.await
+			.iter()
+			.rev()
+			.filter(|entry| filter.matches(entry))
+			.cloned()
+			.collect()
+	}
+
+	pub async fn clear(&self) {
+		let mut entries = self.entries.lock().await;
+		entries.clear();
+
+		self.save(&entries).await;
+	}
+
+	// Called with the lock held so writes can't overtake each other
+	async fn save(&self, entries: &VecDeque<SpacedropHistoryEntry>) {
+		let result = match serde_json::to_vec(entries) {
+			Ok(bytes) => write_atomic(&self.path, bytes)
+				.await
+				.map_err(|err| err.to_string()),
+			Err(err) => Err(err.to_string()),
+		};
+
+		if let Err(err) = result {
+			warn!(
+				"Failed to save the Spacedrop history to '{}': {err}",
+				self.path.display()
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use sd_p2p::Identity;
+	use tempfile::tempdir;
+
+	use super::*;
+
+	fn entry(outcome: SpacedropOutcome, file: &str) -> SpacedropHistoryEntry {
+		SpacedropHistoryEntry {
+			id: Uuid::new_v4(),
+			direction: SpacedropDirection::Received,
+			identity: Identity::new().to_remote_identity(),
+			peer_name: Some("Laptop".into()),
+			files: vec![file.into()],
+			total_bytes: 42,
+			outcome,
+			reason: None,
+			saved_paths: vec![],
+			started_at: Utc::now(),
+			finished_at: Utc::now(),
+		}
+	}
+
+	#[tokio::test]
+	async fn history_survives_a_restart() {
+		let dir = tempdir().unwrap();
+
+		let history = SpacedropHistory::load(dir.path()).await;
+		let completed = entry(SpacedropOutcome::Completed, "IMG_0001.jpg");
+		let rejected = entry(SpacedropOutcome::Rejected, "notes.txt");
+		history.record(completed.clone()).await;
+		history.record(rejected.clone()).await;
+		drop(history);
+
+		// Written in place, without leaving behind the file it was written to first
+		let files = std::fs::read_dir(dir.path())
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.collect::<Vec<_>>();
+		assert_eq!(files, [SPACEDROP_HISTORY_FILE]);
+
+		let history = SpacedropHistory::load(dir.path()).await;
+		assert_eq!(
+			history.list(&SpacedropHistoryFilter::default()).await,
+			[rejected.clone(), completed.clone()]
+		);
+		assert_eq!(
+			history
+				.list(&SpacedropHistoryFilter {
+					outcome: Some(SpacedropOutcome::Completed),
+					..Default::default()
+				})
+				.await,
+			[completed.clone()]
+		);
+		assert_eq!(
+			history
+				.list(&SpacedropHistoryFilter {
+					file_name: Some("img_".into()),
+					..Default::default()
+				})
+				.await,
+			[completed]
+		);
+
+		history.clear().await;
+		let history = SpacedropHistory::load(dir.path()).await;
+		assert!(history
+			.list(&SpacedropHistoryFilter::default())
+			.await
+			.is_empty());
+	}
+
+	#[tokio::test]
+	async fn oldest_entries_are_dropped() {
+		let dir = tempdir().unwrap();
+		let history = SpacedropHistory::load(dir.path()).await;
+		for _ in 0..SPACEDROP_HISTORY_LEN + 5 {
+			history
+				.record(entry(SpacedropOutcome::Completed, "file"))
+				.await;
+		}
+
+		assert_eq!(
+			history.list(&SpacedropHistoryFilter::default()).await.len(),
+			SPACEDROP_HISTORY_LEN
+		);
+	}
+}
@@ -0,0 +1,401 @@
+use std::{
+	collections::VecDeque,
+	io,
+	pin::Pin,
+	sync::{Arc, Mutex, PoisonError},
+	task::{Context, Poll},
+	time::{Duration, Instant},
+};
+
+use sd_p2p::UnicastStream;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	sync::oneshot,
+};
+
+use crate::node::config::NodeConfig;
+
+use super::P2POperation;
+
+/// How many streams of each operation can be open at once, the rest wait for one of them to be closed.
+/// Without them a sync backfill, which opens streams one after the other, can use up all the streams
+/// the transport allows so a Spacedrop times out before it even starts. Unlimited when `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[serde(default)]
+pub struct StreamBudgets {
+	pub ping: Option<u32>,
+	pub spacedrop: Option<u32>,
+	pub sync: Option<u32>,
+	// Includes batches and thumbnails
+	pub file: Option<u32>,
+}
+
+impl Default for StreamBudgets {
+	fn default() -> Self {
+		Self {
+			ping: None,
+			spacedrop: Some(3),
+			sync: Some(2),
+			file: Some(4),
+		}
+	}
+}
+
+impl StreamBudgets {
+	fn of(&self, operation: P2POperation) -> Option<usize> {
+		match operation {
+			P2POperation::Ping => self.ping,
+			P2POperation::Spacedrop => self.spacedrop,
+			P2POperation::Sync => self.sync,
+			P2POperation::File => self.file,
+		}
+		.map(|budget| budget.max(1) as usize)
+	}
+}
+
+/// Which side opened a stream. Both have a budget of their own, otherwise two nodes syncing with each
+/// other could each use theirs up on the streams they opened, and wait forever to accept the other's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamDirection {
+	Inbound,
+	Outbound,
+}
+
+/// Hands out the [`StreamBudgets`] of the node, see `p2p_stream_budgets`
+#[derive(Debug, Default)]
+pub(crate) struct StreamLimiter {
+	state: Mutex<LimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+	budgets: StreamBudgets,
+	// Indexed by operation, then by direction
+	classes: [[Class; 2]; 4],
+}
+
+impl LimiterState {
+	fn class(&mut self, operation: P2POperation, direction: StreamDirection) -> &mut Class {
+		&mut self.classes[operation.index()][direction as usize]
+	}
+}
+
+#[derive(Debug, Default)]
+struct Class {
+	active: usize,
+	waiting: VecDeque<oneshot::Sender<StreamSlot>>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Acquired {
+	Ready(StreamSlot),
+	/// Resolves once one of the operation's streams is closed
+	Queued(oneshot::Receiver<StreamSlot>),
+}
+
+/// One of the streams of an operation's budget. Dropping this hands it to the next stream waiting for one.
+#[derive(Debug)]
+pub(crate) struct StreamSlot {
+	limiter: Arc<StreamLimiter>,
+	operation: P2POperation,
+	direction: StreamDirection,
+}
+
+impl Drop for StreamSlot {
+	fn drop(&mut self) {
+		let mut state = self
+			.limiter
+			.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		let class = state.class(self.operation, self.direction);
+		class.active = class.active.saturating_sub(1);
+		drop(state);
+
+		self.limiter.wake(self.operation, self.direction);
+	}
+}
+
+impl StreamSlot {
+	/// Keep the slot for as long as `stream` is open
+	pub(crate) fn hold(self, stream: UnicastStream) -> UnicastStream {
+		let addr = stream.remote_addr();
+		UnicastStream::new(
+			stream.remote_identity(),
+			HeldStream {
+				stream,
+				_slot: self,
+			},
+		)
+		.with_remote_addr(addr)
+	}
+}
+
+impl StreamLimiter {
+	/// Apply the budgets from the node config, this takes effect for the streams which are waiting too.
+	/// Lowering a budget doesn't close any stream, new ones wait until enough of them are closed.
+	pub(crate) fn configure(self: &Arc<Self>, config: &NodeConfig) {
+		self.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.budgets = config.p2p_stream_budgets.unwrap_or_default();
+
+		for operation in P2POperation::ALL {
+			for direction in [StreamDirection::Inbound, StreamDirection::Outbound] {
+				self.wake(operation, direction);
+			}
+		}
+	}
+
+	/// Take a slot of `operation` if there is one free, or queue for the next one
+	pub(crate) fn try_acquire(
+		self: &Arc<Self>,
+		operation: P2POperation,
+		direction: StreamDirection,
+	) -> Acquired {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		let budget = state.budgets.of(operation);
+		let class = state.class(operation, direction);
+
+		if budget.map_or(true, |budget| class.active < budget) && class.waiting.is_empty() {
+			class.active += 1;
+			return Acquired::Ready(self.slot(operation, direction));
+		}
+
+		let (tx, rx) = oneshot::channel();
+		class.waiting.push_back(tx);
+		Acquired::Queued(rx)
+	}
+
+	/// Take a slot of `operation` only if there is one free, without queueing for the next one
+	pub(crate) fn try_take(
+		self: &Arc<Self>,
+		operation: P2POperation,
+		direction: StreamDirection,
+	) -> Option<StreamSlot> {
+		let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+		let budget = state.budgets.of(operation);
+		let class = state.class(operation, direction);
+
+		if budget.map_or(true, |budget| class.active < budget) && class.waiting.is_empty() {
+			class.active += 1;
+			return Some(self.slot(operation, direction));
+		}
+
+		None
+	}
+
+	/// Wait for a slot of `operation`, along with how long it was waited for when it had to be
+	pub(crate) async fn acquire(
+		self: &Arc<Self>,
+		operation: P2POperation,
+		direction: StreamDirection,
+	) -> (StreamSlot, Option<Duration>) {
+		let rx = match self.try_acquire(operation, direction) {
+			Acquired::Ready(slot) => return (slot, None),
+			Acquired::Queued(rx) => rx,
+		};
+
+		let queued_at = Instant::now();
+		let slot = match rx.await {
+			Ok(slot) => slot,
+			// Senders are only dropped after sending as we hold the limiter, but to be safe we take a slot anyway
+			Err(_) => {
+				self.state
+					.lock()
+					.unwrap_or_else(PoisonError::into_inner)
+					.class(operation, direction)
+					.active += 1;
+				self.slot(operation, direction)
+			}
+		};
+
+		(slot, Some(queued_at.elapsed()))
+	}
+
+	pub(crate) fn active(&self, operation: P2POperation, direction: StreamDirection) -> usize {
+		self.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.class(operation, direction)
+			.active
+	}
+
+	pub(crate) fn queued(&self, operation: P2POperation, direction: StreamDirection) -> usize {
+		self.state
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.class(operation, direction)
+			.waiting
+			.len()
+	}
+
+	fn slot(self: &Arc<Self>, operation: P2POperation, direction: StreamDirection) -> StreamSlot {
+		StreamSlot {
+			limiter: self.clone(),
+			operation,
+			direction,
+		}
+	}
+
+	/// Hand free slots of `operation` to the streams waiting for them
+	fn wake(self: &Arc<Self>, operation: P2POperation, direction: StreamDirection) {
+		let ready = {
+			let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+			let budget = state.budgets.of(operation);
+			let class = state.class(operation, direction);
+			let mut ready = Vec::new();
+			while budget.map_or(true, |budget| class.active < budget) {
+				let Some(tx) = class.waiting.pop_front() else {
+					break;
+				};
+				class.active += 1;
+				ready.push(tx);
+			}
+			ready
+		};
+
+		for tx in ready {
+			// If the stream gave up waiting, eg. its peer went away, the slot is dropped which passes it on to the next one
+			tx.send(self.slot(operation, direction)).ok();
+		}
+	}
+}
+
+struct HeldStream {
+	stream: UnicastStream,
+	_slot: StreamSlot,
+}
+
+impl AsyncRead for HeldStream {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for HeldStream {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.stream).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::panic)]
+mod tests {
+	use sd_p2p::Identity;
+
+	use super::*;
+
+	fn limiter(budgets: StreamBudgets) -> Arc<StreamLimiter> {
+		let limiter = Arc::new(StreamLimiter::default());
+		limiter.state.lock().unwrap().budgets = budgets;
+		limiter
+	}
+
+	#[tokio::test]
+	async fn a_saturated_sync_budget_leaves_spacedrop_alone() {
+		let limiter = limiter(StreamBudgets::default());
+
+		// A backfill holding every sync stream it's allowed, with more sessions waiting behind it
+		let sessions = (0..2)
+			.map(
+				|_| match limiter.try_acquire(P2POperation::Sync, StreamDirection::Outbound) {
+					Acquired::Ready(slot) => slot,
+					Acquired::Queued(_) => panic!("the sync budget isn't used up yet"),
+				},
+			)
+			.collect::<Vec<_>>();
+		let Acquired::Queued(mut waiting) =
+			limiter.try_acquire(P2POperation::Sync, StreamDirection::Outbound)
+		else {
+			panic!("a third sync session should wait");
+		};
+		assert_eq!(
+			limiter.queued(P2POperation::Sync, StreamDirection::Outbound),
+			1
+		);
+
+		let (spacedrop, waited) = limiter
+			.acquire(P2POperation::Spacedrop, StreamDirection::Outbound)
+			.await;
+		assert_eq!(waited, None);
+		drop(spacedrop);
+
+		// Peers opening sync streams to us have a budget of their own
+		assert!(matches!(
+			limiter.try_acquire(P2POperation::Sync, StreamDirection::Inbound),
+			Acquired::Ready(_)
+		));
+
+		assert!(waiting.try_recv().is_err());
+		drop(sessions);
+		let _next = waiting.try_recv().unwrap();
+		assert_eq!(
+			limiter.active(P2POperation::Sync, StreamDirection::Outbound),
+			1
+		);
+	}
+
+	#[test]
+	fn raising_a_budget_lets_waiting_streams_through() {
+		let limiter = limiter(StreamBudgets {
+			file: Some(1),
+			..Default::default()
+		});
+
+		let Acquired::Ready(_first) =
+			limiter.try_acquire(P2POperation::File, StreamDirection::Inbound)
+		else {
+			panic!("the first file stream should start straight away");
+		};
+		let Acquired::Queued(mut second) =
+			limiter.try_acquire(P2POperation::File, StreamDirection::Inbound)
+		else {
+			panic!("a second file stream should wait");
+		};
+
+		limiter.state.lock().unwrap().budgets.file = Some(2);
+		limiter.wake(P2POperation::File, StreamDirection::Inbound);
+		assert!(second.try_recv().is_ok());
+	}
+
+	#[tokio::test]
+	async fn a_held_stream_gives_its_slot_back_when_closed() {
+		let limiter = limiter(StreamBudgets::default());
+		let identity = Identity::default().to_remote_identity();
+
+		let (slot, _) = limiter
+			.acquire(P2POperation::Spacedrop, StreamDirection::Outbound)
+			.await;
+		let (stream, _remote) = tokio::io::duplex(64);
+		let stream = slot.hold(UnicastStream::new(identity, stream));
+		assert_eq!(
+			limiter.active(P2POperation::Spacedrop, StreamDirection::Outbound),
+			1
+		);
+
+		drop(stream);
+		assert_eq!(
+			limiter.active(P2POperation::Spacedrop, StreamDirection::Outbound),
+			0
+		);
+	}
+}
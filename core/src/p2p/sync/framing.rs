@@ -0,0 +1,266 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The largest frame we will write by default. Larger payloads are split across multiple frames.
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+/// Payloads smaller than this aren't worth compressing.
+const COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+/// We refuse to reassemble or decompress a message larger than this.
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The payload of the message this frame is part of is zstd compressed.
+const FLAG_COMPRESSED: u8 = 1 << 0;
+/// More frames follow which are part of the same message.
+const FLAG_MORE: u8 = 1 << 1;
+/// The sender can decompress messages so we are free to compress what we send to it.
+const FLAG_SUPPORTS_COMPRESSION: u8 = 1 << 2;
+
+/// Frames sync payloads over the tunnel.
+///
+/// Each frame is a flags byte followed by a `u32` length prefixed chunk of the message.
+/// Messages are only compressed once the remote has told us it supports it, so peers which don't still work, just uncompressed.
+#[derive(Debug, Clone)]
+pub struct Framing {
+	max_frame_bytes: usize,
+	compression: bool,
+	remote_compression: bool,
+	legacy: bool,
+}
+
+impl Default for Framing {
+	fn default() -> Self {
+		Self {
+			max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+			compression: true,
+			remote_compression: false,
+			legacy: false,
+		}
+	}
+}
+
+impl Framing {
+	/// How payloads were sent before they were framed, each message is a `u32` length prefix and the message as is.
+	/// Peers from before [`PROTOCOL_VERSION`](crate::p2p::operations::identify::PROTOCOL_VERSION) understand nothing else.
+	pub fn legacy() -> Self {
+		Self {
+			compression: false,
+			legacy: true,
+			..Default::default()
+		}
+	}
+
+	pub fn with_max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+		self.max_frame_bytes = max_frame_bytes.max(1);
+		self
+	}
+
+	pub fn without_compression(mut self) -> Self {
+		self.compression = false;
+		self
+	}
+
+	/// Write `payload` as one or more frames. Returns the number of bytes written to the stream.
+	pub async fn write(
+		&mut self,
+		stream: &mut (impl AsyncWrite + Unpin),
+		payload: &[u8],
+	) -> io::Result<usize> {
+		if self.legacy {
+			let len = u32::try_from(payload.len()).map_err(|_| {
+				io::Error::new(io::ErrorKind::InvalidInput, "sync message is too large")
+			})?;
+			stream.write_u32_le(len).await?;
+			stream.write_all(payload).await?;
+			stream.flush().await?;
+			return Ok(4 + payload.len());
+		}
+
+		let compressed;
+		let mut flags = 0;
+		let payload =
+			if self.compression && self.remote_compression && payload.len() > COMPRESSION_THRESHOLD
+			{
+				compressed = zstd::bulk::compress(payload, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+				flags |= FLAG_COMPRESSED;
+				&compressed[..]
+			} else {
+				payload
+			};
+		if self.compression {
+			flags |= FLAG_SUPPORTS_COMPRESSION;
+		}
+
+		let mut written = 0;
+		let mut offset = 0;
+		loop {
+			let end = (offset + self.max_frame_bytes).min(payload.len());
+			let more = end < payload.len();
+
+			stream
+				.write_u8(if more { flags | FLAG_MORE } else { flags })
+				.await?;
+			stream.write_u32_le((end - offset) as u32).await?;
+			stream.write_all(&payload[offset..end]).await?;
+
+			written += 5 + end - offset;
+			offset = end;
+			if !more {
+				break;
+			}
+		}
+		stream.flush().await?;
+
+		Ok(written)
+	}
+
+	/// Read the frames of a single message and return its payload.
+	pub async fn read(&mut self, stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+		if self.legacy {
+			let len = stream.read_u32_le().await? as usize;
+			if len > MAX_MESSAGE_BYTES {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"sync message is too large",
+				));
+			}
+
+			let mut message = vec![0; len];
+			stream.read_exact(&mut message).await?;
+			return Ok(message);
+		}
+
+		let mut message = Vec::new();
+		let compressed = loop {
+			let flags = stream.read_u8().await?;
+			let len = stream.read_u32_le().await? as usize;
+			if message.len() + len > MAX_MESSAGE_BYTES {
+				return Err(io::Error::new(
+					io::ErrorKind::InvalidData,
+					"sync message is too large",
+				));
+			}
+
+			if flags & FLAG_SUPPORTS_COMPRESSION != 0 {
+				self.remote_compression = true;
+			}
+
+			let start = message.len();
+			message.resize(start + len, 0);
+			stream.read_exact(&mut message[start..]).await?;
+
+			if flags & FLAG_MORE == 0 {
+				break flags & FLAG_COMPRESSED != 0;
+			}
+		};
+
+		if compressed {
+			zstd::bulk::decompress(&message, MAX_MESSAGE_BYTES)
+		} else {
+			Ok(message)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	async fn roundtrip(framing: &mut Framing, payload: &[u8]) -> (usize, Vec<u8>) {
+		let mut buf = Vec::new();
+		let written = framing.write(&mut buf, payload).await.unwrap();
+		assert_eq!(written, buf.len());
+
+		let result = Framing::default()
+			.read(&mut Cursor::new(buf))
+			.await
+			.unwrap();
+		(written, result)
+	}
+
+	#[tokio::test]
+	async fn test_framing() {
+		let mut framing = Framing::default().with_max_frame_bytes(16);
+
+		for payload in [
+			vec![],
+			vec![1; 16],
+			vec![2; 17],
+			(0..=255).collect::<Vec<u8>>(),
+		] {
+			let (written, result) = roundtrip(&mut framing, &payload).await;
+			assert_eq!(result, payload);
+
+			// Each frame has a 5 byte header
+			assert_eq!(
+				written,
+				payload.len() + 5 * payload.chunks(16).count().max(1)
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn compresses_only_once_negotiated() {
+		let payload = b"Spacedrive".repeat(1024);
+
+		// We don't know if the remote can decompress yet
+		let mut framing = Framing::default();
+		let (uncompressed, result) = roundtrip(&mut framing, &payload).await;
+		assert_eq!(result, payload);
+		assert_eq!(uncompressed, payload.len() + 5);
+
+		// The remote tells us it supports compression in its first frame
+		let mut buf = Vec::new();
+		Framing::default().write(&mut buf, &[]).await.unwrap();
+		framing.read(&mut Cursor::new(buf)).await.unwrap();
+
+		let (compressed, result) = roundtrip(&mut framing, &payload).await;
+		assert_eq!(result, payload);
+		assert!(compressed < uncompressed);
+
+		// A remote which doesn't support compression never gets compressed frames
+		let mut buf = Vec::new();
+		Framing::default()
+			.without_compression()
+			.write(&mut buf, &[])
+			.await
+			.unwrap();
+		let mut framing = Framing::default();
+		framing.read(&mut Cursor::new(buf)).await.unwrap();
+		let (written, _) = roundtrip(&mut framing, &payload).await;
+		assert_eq!(written, uncompressed);
+	}
+
+	#[tokio::test]
+	async fn legacy_messages_are_length_prefixed() {
+		let payload = b"Spacedrive".repeat(1024);
+
+		let mut buf = Vec::new();
+		let written = Framing::legacy().write(&mut buf, &payload).await.unwrap();
+		assert_eq!(written, buf.len());
+
+		// What peers from before framing send and expect
+		let mut expected = Vec::new();
+		sd_p2p_proto::encode::buf(&mut expected, &payload);
+		assert_eq!(buf, expected);
+
+		let result = Framing::legacy().read(&mut Cursor::new(buf)).await.unwrap();
+		assert_eq!(result, payload);
+	}
+
+	#[tokio::test]
+	async fn rejects_oversized_messages() {
+		let mut buf = vec![0];
+		buf.extend_from_slice(&(MAX_MESSAGE_BYTES as u32 + 1).to_le_bytes());
+
+		let err = Framing::default()
+			.read(&mut Cursor::new(buf))
+			.await
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+}
@@ -2,29 +2,402 @@
 
 use crate::{
 	library::Library,
+	p2p::SyncPhase,
 	sync::{self, GetOpsArgs},
 };
 
-use sd_p2p_proto::{decode, encode};
+use sd_p2p::RemoteIdentity;
+use sd_p2p_tunnel::PeerStoppedResponding;
 use sd_sync::CRDTOperation;
 
-use std::sync::Arc;
+use std::{
+	collections::{HashMap, HashSet},
+	future::Future,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+	time::{Duration, SystemTime},
+};
 
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use futures::future::BoxFuture;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+	sync::{broadcast::error::RecvError, mpsc, oneshot},
+	time::timeout,
+};
 use tracing::*;
 use uuid::Uuid;
 
-use super::P2PManager;
+use super::{Error, P2PEvent, P2PManager};
 
+mod framing;
+mod priority;
+mod progress;
 mod proto;
+
+pub use framing::*;
+pub use priority::*;
+pub use progress::*;
 pub use proto::*;
 
-pub use originator::run as originator;
+/// Instances which told us they don't have a library, even though their metadata says they do.
+///
+/// We stop syncing with them until the metadata they advertise for the library changes.
+#[derive(Debug, Default)]
+pub struct NonParticipants(Mutex<HashMap<(Uuid, RemoteIdentity), Option<String>>>);
+
+impl NonParticipants {
+	/// `metadata` is what `identity` was advertising for the library when it rejected us.
+	pub fn insert(&self, library_id: Uuid, identity: RemoteIdentity, metadata: Option<String>) {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert((library_id, identity), metadata);
+	}
+
+	/// Whether we should skip syncing with `identity`, given what it currently advertises for the library.
+	pub fn should_skip(
+		&self,
+		library_id: Uuid,
+		identity: RemoteIdentity,
+		metadata: Option<&String>,
+	) -> bool {
+		let mut non_participants = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+		match non_participants.get(&(library_id, identity)) {
+			Some(rejected) if rejected.as_ref() == metadata => true,
+			Some(_) => {
+				// Their metadata changed so they may have the library now
+				non_participants.remove(&(library_id, identity));
+				false
+			}
+			None => false,
+		}
+	}
+}
+
+/// A dead tunnel is reported as the peer timing out, which is worth retrying as the link may come back
+fn tunnel_failed(context: &'static str) -> impl FnOnce(std::io::Error) -> Error {
+	move |err| match PeerStoppedResponding::from_io(&err) {
+		Some(stopped) => Error::TimedOut(stopped.silent_for),
+		None => Error::io(context)(err),
+	}
+}
+
+/// A message which doesn't decode is the peer's fault, unless the tunnel died while reading it
+fn decode_failed(context: &'static str) -> impl FnOnce(sd_p2p_proto::decode::Error) -> Error {
+	move |err| match err {
+		sd_p2p_proto::decode::Error::IoError(err) => tunnel_failed(context)(err),
+		err => Error::protocol(context)(err),
+	}
+}
+
+/// How many times a sync session is started when the tunnel to the peer keeps dying
+const SESSION_ATTEMPTS: u32 = 3;
+
+/// How long to wait before starting a sync session again after its tunnel died, doubled every time
+const SESSION_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Run `session` until it doesn't end because the tunnel to `remote_identity` died, up to [`SESSION_ATTEMPTS`] times.
+async fn retry_on_tunnel_death<Fut>(
+	remote_identity: RemoteIdentity,
+	mut backoff: Duration,
+	mut session: impl FnMut() -> Fut,
+) -> Result<(), Error>
+where
+	Fut: Future<Output = Result<(), Error>>,
+{
+	let mut attempt = 1;
+	loop {
+		match session().await {
+			Err(err @ Error::TimedOut(_)) if attempt < SESSION_ATTEMPTS => {
+				warn!(
+					"Sync session with '{remote_identity:?}' died, retrying in {backoff:?}: {err}"
+				);
+				tokio::time::sleep(backoff).await;
+				backoff *= 2;
+				attempt += 1;
+			}
+			result => return result,
+		}
+	}
+}
+
+/// The sync sessions we are responding to, so a peer can only run one per library at a time.
+#[derive(Debug, Default)]
+pub struct SyncSessions(Mutex<HashSet<(RemoteIdentity, Uuid)>>);
+
+/// An active sync session, dropping this allows the peer to start another one.
+#[derive(Debug)]
+pub struct SessionGuard {
+	sessions: Arc<SyncSessions>,
+	key: (RemoteIdentity, Uuid),
+}
+
+impl Drop for SessionGuard {
+	fn drop(&mut self) {
+		self.sessions
+			.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.remove(&self.key);
+	}
+}
+
+impl SyncSessions {
+	/// Returns `None` if `identity` already has a session open for the library.
+	pub fn try_start(
+		self: &Arc<Self>,
+		identity: RemoteIdentity,
+		library_id: Uuid,
+	) -> Option<SessionGuard> {
+		let key = (identity, library_id);
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(key)
+			.then(|| SessionGuard {
+				sessions: self.clone(),
+				key,
+			})
+	}
+}
+
+/// How long the tunnel to a peer is kept open once no library has anything left to sync with it
+const SHARED_TUNNEL_IDLE: Duration = Duration::from_secs(30);
+
+/// What [`SyncTunnels`] run sessions on
+pub trait SessionTunnel: Send + 'static {
+	/// Where [`SyncFrame::End`] is sent when the tunnel is closed
+	fn writer(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send);
+}
+
+/// A sync session waiting for its turn on the tunnel, it's handed the tunnel once the ones before it are done.
+pub type QueuedSession<T> =
+	Box<dyn for<'a> FnOnce(&'a mut T) -> BoxFuture<'a, Result<(), Error>> + Send>;
+
+/// So the closure is inferred to take a tunnel of any lifetime
+pub fn queued_session<T, F>(session: F) -> QueuedSession<T>
+where
+	F: for<'a> FnOnce(&'a mut T) -> BoxFuture<'a, Result<(), Error>> + Send + 'static,
+{
+	Box::new(session)
+}
+
+/// A queued session along with where to tell whoever queued it how it went
+type Queued<T> = (QueuedSession<T>, oneshot::Sender<Result<(), Error>>);
+
+/// The tunnel we originate syncs on for each peer, so every library we share with a peer is synced over one tunnel
+/// instead of each paying for a handshake and keep-alives of its own.
+///
+/// Sessions are queued per peer and run one after the other, the tunnel is opened for the first one and closed once
+/// the queue was empty for [`SHARED_TUNNEL_IDLE`].
+#[derive(Debug)]
+pub struct SyncTunnels<T> {
+	queues: Mutex<HashMap<RemoteIdentity, mpsc::UnboundedSender<Queued<T>>>>,
+	opened: AtomicUsize,
+}
+
+impl<T> Default for SyncTunnels<T> {
+	fn default() -> Self {
+		Self {
+			queues: Default::default(),
+			opened: AtomicUsize::new(0),
+		}
+	}
+}
+
+impl<T: SessionTunnel> SyncTunnels<T> {
+	/// Run `session` on the tunnel to `remote_identity` once it's this session's turn, opening the tunnel with `open` if there is none.
+	///
+	/// The tunnel is closed when a session fails, as it may have been left in the middle of an exchange, and opened again for the next one.
+	pub async fn run<Open, Fut>(
+		self: &Arc<Self>,
+		remote_identity: RemoteIdentity,
+		open: Open,
+		session: QueuedSession<T>,
+	) -> Result<(), Error>
+	where
+		Open: Fn() -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = Result<T, Error>> + Send,
+	{
+		let (done_tx, done_rx) = oneshot::channel();
+		{
+			let mut queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+			let queued = match queues.get(&remote_identity) {
+				Some(queue) => queue.send((session, done_tx)).err().map(|err| err.0),
+				None => Some((session, done_tx)),
+			};
+
+			if let Some(queued) = queued {
+				let (queue, rx) = mpsc::unbounded_channel();
+				queue.send(queued).ok();
+				queues.insert(remote_identity, queue);
+				tokio::spawn(self.clone().carry(remote_identity, open, rx));
+			}
+		}
+
+		done_rx.await.unwrap_or_else(|_| {
+			Err(Error::Tunnel(
+				"the tunnel was dropped before the session ran".into(),
+			))
+		})
+	}
+
+	async fn carry<Open, Fut>(
+		self: Arc<Self>,
+		remote_identity: RemoteIdentity,
+		open: Open,
+		mut rx: mpsc::UnboundedReceiver<Queued<T>>,
+	) where
+		Open: Fn() -> Fut + Send + Sync,
+		Fut: Future<Output = Result<T, Error>> + Send,
+	{
+		let mut tunnel = None;
+		loop {
+			let queued = match timeout(SHARED_TUNNEL_IDLE, rx.recv()).await {
+				Ok(queued) => queued,
+				Err(_) => {
+					// Sessions are only queued with the lock held, so none can be lost once we're out of the map
+					let mut queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+					let queued = rx.try_recv().ok();
+					if queued.is_none() {
+						queues.remove(&remote_identity);
+					}
+					queued
+				}
+			};
+			let Some((session, done)) = queued else {
+				if let Some(mut tunnel) = tunnel {
+					debug!("Closing the sync tunnel to '{remote_identity:?}' which has been idle for {SHARED_TUNNEL_IDLE:?}");
+					let writer = tunnel.writer();
+					writer.write_all(&SyncFrame::End.to_bytes()).await.ok();
+					writer.flush().await.ok();
+				}
+				return;
+			};
+
+			let result = match &mut tunnel {
+				Some(tunnel) => session(tunnel).await,
+				None => match open().await {
+					Ok(opened) => {
+						self.opened.fetch_add(1, Ordering::Relaxed);
+						session(tunnel.insert(opened)).await
+					}
+					Err(err) => Err(err),
+				},
+			};
+			if result.is_err() {
+				tunnel = None;
+			}
+
+			done.send(result).ok();
+		}
+	}
+
+	/// How many tunnels were opened so far
+	pub fn opened(&self) -> usize {
+		self.opened.load(Ordering::Relaxed)
+	}
+}
+
+/// How far behind another instance we can be before we explicitly ask for its operations when the library loads.
+const BACKFILL_GAP: Duration = Duration::from_secs(60 * 60);
+
+/// Called when a library is loaded to catch up on the operations we missed while we were offline.
+///
+/// Libraries are loaded before peers get a chance to connect, so if none of its instances are connected yet
+/// we backfill from the first one which connects.
+pub async fn backfill_on_load(library: Arc<Library>, p2p: Arc<P2PManager>) {
+	// Before trying the peers which are connected, so we can't miss one connecting in the meantime
+	let mut connected = p2p.events.subscribe_filtered(&["ConnectedPeer"]);
+
+	let Some(since) = backfill_since(&library).await else {
+		return;
+	};
+	for (remote_identity, peer) in p2p.get_library_instances(&library.id) {
+		if peer.is_connected() && backfill_from(&library, &p2p, remote_identity, since).await {
+			return;
+		}
+	}
+
+	debug!(
+		"No peers available to backfill library '{}', waiting for one to connect",
+		library.id
+	);
+	loop {
+		let remote_identity = match connected.recv().await {
+			Ok(P2PEvent::ConnectedPeer { identity, .. }) => identity,
+			Ok(_) | Err(RecvError::Lagged(_)) => continue,
+			Err(RecvError::Closed) => return,
+		};
+		if !p2p
+			.get_library_instances(&library.id)
+			.iter()
+			.any(|(identity, _)| *identity == remote_identity)
+		{
+			continue;
+		}
+
+		// Live sync with another peer may have caught us up in the meantime
+		let Some(since) = backfill_since(&library).await else {
+			return;
+		};
+		if backfill_from(&library, &p2p, remote_identity, since).await {
+			return;
+		}
+	}
+}
+
+/// The oldest timestamp of the instances we are more than [`BACKFILL_GAP`] behind on, if any
+async fn backfill_since(library: &Library) -> Option<sync::NTP64> {
+	let now = SystemTime::now();
+	library
+		.sync
+		.timestamps
+		.read()
+		.await
+		.iter()
+		.filter(|(instance, _)| **instance != library.sync.instance)
+		.map(|(_, timestamp)| *timestamp)
+		.filter(|timestamp| {
+			now.duration_since(timestamp.to_system_time())
+				.map(|gap| gap > BACKFILL_GAP)
+				.unwrap_or(false)
+		})
+		.min()
+}
+
+/// Returns whether the backfill from `remote_identity` succeeded
+async fn backfill_from(
+	library: &Library,
+	p2p: &Arc<P2PManager>,
+	remote_identity: RemoteIdentity,
+	since: sync::NTP64,
+) -> bool {
+	debug!(
+		"Backfilling library '{}' from peer '{remote_identity:?}' since '{since:?}'",
+		library.id
+	);
+	match request_operations_since(library.id, &library.sync, p2p, remote_identity, since).await {
+		Ok(()) => true,
+		Err(err) => {
+			warn!(
+				"Failed to backfill library '{}' from peer '{remote_identity:?}': {err}",
+				library.id
+			);
+			false
+		}
+	}
+}
+
+pub use originator::{request_operations_since, run as originator, SharedTunnel};
 mod originator {
-	use crate::p2p::Header;
+	use crate::p2p::{operations::PeerProtocol, Header, OpId, P2POperation};
 
 	use super::*;
 	use responder::tx as rx;
+	use sd_p2p::{Peer, UnicastStream};
 	use sd_p2p_tunnel::Tunnel;
 
 	pub mod tx {
@@ -37,19 +410,25 @@ mod originator {
 			// TODO: Per field errors for better error handling
 			pub async fn from_stream(
 				stream: &mut (impl AsyncRead + Unpin),
+				framing: &mut Framing,
 			) -> std::io::Result<Self> {
 				Ok(Self(
-					rmp_serde::from_slice(&decode::buf(stream).await.unwrap()).unwrap(),
+					rmp_serde::from_slice(&framing.read(stream).await?)
+						.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?,
 				))
 			}
 
-			pub fn to_bytes(&self) -> Vec<u8> {
+			pub async fn write(
+				&self,
+				stream: &mut (impl AsyncWrite + Unpin),
+				framing: &mut Framing,
+			) -> std::io::Result<usize> {
 				let Self(args) = self;
-				let mut buf = vec![];
 
 				// TODO: Error handling
-				encode::buf(&mut buf, &rmp_serde::to_vec_named(&args).unwrap());
-				buf
+				framing
+					.write(stream, &rmp_serde::to_vec_named(&args).unwrap())
+					.await
 			}
 		}
 
@@ -59,8 +438,15 @@ mod originator {
 			{
 				let original = Operations(vec![]);
 
-				let mut cursor = std::io::Cursor::new(original.to_bytes());
-				let result = Operations::from_stream(&mut cursor).await.unwrap();
+				let mut buf = vec![];
+				original
+					.write(&mut buf, &mut Framing::default())
+					.await
+					.unwrap();
+				let mut cursor = std::io::Cursor::new(buf);
+				let result = Operations::from_stream(&mut cursor, &mut Framing::default())
+					.await
+					.unwrap();
 				assert_eq!(original, result);
 			}
 
@@ -73,8 +459,15 @@ mod originator {
 					data: sd_sync::CRDTOperationData::Create,
 				}]);
 
-				let mut cursor = std::io::Cursor::new(original.to_bytes());
-				let result = Operations::from_stream(&mut cursor).await.unwrap();
+				let mut buf = vec![];
+				original
+					.write(&mut buf, &mut Framing::default())
+					.await
+					.unwrap();
+				let mut cursor = std::io::Cursor::new(buf);
+				let result = Operations::from_stream(&mut cursor, &mut Framing::default())
+					.await
+					.unwrap();
 				assert_eq!(original, result);
 			}
 		}
@@ -87,45 +480,488 @@ mod originator {
 				continue;
 			};
 
+			let metadata = peer.metadata().get(&library_id.to_string()).cloned();
+			if p2p
+				.sync_non_participants
+				.should_skip(library_id, remote_identity, metadata.as_ref())
+			{
+				debug!("Skipping peer '{remote_identity:?}' as it doesn't have library '{library_id:?}'");
+				continue;
+			}
+
 			let sync = sync.clone();
+			let p2p = p2p.clone();
 
 			tokio::spawn(async move {
 				debug!(
 					"Alerting peer '{remote_identity:?}' of new sync events for library '{library_id:?}'"
 				);
+				match p2p.clock_skews.get(remote_identity) {
+					Some(skew) if skew.exceeds_threshold => warn!(
+						"Peer '{remote_identity:?}' clock is {}ms off ours, operations synced with it may be ordered wrong",
+						skew.skew_ms
+					),
+					Some(skew) => debug!(
+						"Peer '{remote_identity:?}' clock is {}ms off ours",
+						skew.skew_ms
+					),
+					None => {}
+				}
 
-				let mut stream = peer.new_stream().await.unwrap();
-
-				stream
-					.write_all(&Header::Sync(library_id).to_bytes())
+				// Peers which can't multiplex get a tunnel per library like they used to
+				let multiplexed = p2p
+					.peer_protocol(remote_identity)
 					.await
-					.unwrap();
+					.supports("sync-multiplexed");
+				let result = if multiplexed {
+					retry_on_tunnel_death(remote_identity, SESSION_RETRY_BACKOFF, || {
+						alert_over_shared_tunnel(
+							library_id,
+							sync.clone(),
+							p2p.clone(),
+							remote_identity,
+							peer.clone(),
+							metadata.clone(),
+						)
+					})
+					.await
+				} else {
+					retry_on_tunnel_death(remote_identity, SESSION_RETRY_BACKOFF, || {
+						// Each attempt is a new stream, so a new operation for the remote too
+						let op_id = OpId::new();
+						alert_new_operations(
+							library_id,
+							&sync,
+							&p2p,
+							remote_identity,
+							&peer,
+							&metadata,
+							op_id,
+						)
+						.instrument(op_id.span(remote_identity, P2POperation::Sync))
+					})
+					.await
+				};
+
+				if let Err(err) = result {
+					debug!("Sync session with '{remote_identity:?}' for library '{library_id:?}' ended early: {err}");
+				}
+			});
+		}
+	}
 
-				let mut tunnel = Tunnel::initiator(stream).await.unwrap();
+	/// Peers from before tunnels were framed would take a framed one for garbage
+	async fn open_tunnel(
+		stream: UnicastStream,
+		protocol: &PeerProtocol,
+	) -> Result<Tunnel, &'static str> {
+		if protocol.is_current() {
+			Tunnel::initiator(stream).await
+		} else {
+			Tunnel::unframed_initiator(stream).await
+		}
+	}
 
-				tunnel
-					.write_all(&SyncMessage::NewOperations.to_bytes())
-					.await
-					.unwrap();
-				tunnel.flush().await.unwrap();
+	/// The tunnel every library we share with a peer is synced over, see [`SyncTunnels`]
+	#[derive(Debug)]
+	pub struct SharedTunnel {
+		tunnel: Tunnel,
+		op_id: OpId,
+	}
 
-				while let Ok(rx::MainRequest::GetOperations(args)) =
-					rx::MainRequest::from_stream(&mut tunnel).await
-				{
-					let ops = sync.get_ops(args).await.unwrap();
+	impl SessionTunnel for SharedTunnel {
+		fn writer(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send) {
+			&mut self.tunnel
+		}
+	}
 
-					tunnel
-						.write_all(&tx::Operations(ops).to_bytes())
-						.await
-						.unwrap();
-					tunnel.flush().await.unwrap();
+	/// Queue alerting `peer` of the library's new operations onto the tunnel we share with it
+	pub(super) async fn alert_over_shared_tunnel(
+		library_id: Uuid,
+		sync: Arc<sync::Manager>,
+		p2p: Arc<super::P2PManager>,
+		remote_identity: RemoteIdentity,
+		peer: Arc<Peer>,
+		metadata: Option<String>,
+	) -> Result<(), Error> {
+		let tunnels = p2p.sync_tunnels.clone();
+		let open = {
+			let p2p = p2p.clone();
+			move || open_shared_tunnel(library_id, p2p.clone(), remote_identity, peer.clone())
+		};
+		let session = queued_session(move |shared: &mut SharedTunnel| {
+			// Each library's session is an operation of its own, the tunnel's is only its setup
+			let op_id = OpId::new();
+			debug!(
+				"Syncing library '{library_id:?}' on the tunnel opened by operation '{}'",
+				shared.op_id
+			);
+			Box::pin(
+				async move {
+					let mut progress =
+						SyncProgress::new(p2p.events.sender(), library_id, remote_identity, op_id);
+					let message = SyncFrame::Library {
+						library_id,
+						op_id,
+						message: SyncMessage::NewOperations,
+					};
+					let response = offer_operations(
+						&mut shared.tunnel,
+						&message.to_bytes(),
+						false,
+						|args| sync.get_ops(args),
+						&mut progress,
+					)
+					.await?;
+					note_rejection(&p2p, library_id, remote_identity, &metadata, response);
+					Ok(())
 				}
-			});
+				.instrument(op_id.span(remote_identity, P2POperation::Sync)),
+			)
+		});
+
+		tunnels.run(remote_identity, open, session).await
+	}
+
+	/// Open a tunnel to `peer` and start a [`SyncMessage::Multiplexed`] session on it.
+	/// Only for peers which told us they support `sync-multiplexed`.
+	async fn open_shared_tunnel(
+		library_id: Uuid,
+		p2p: Arc<super::P2PManager>,
+		remote_identity: RemoteIdentity,
+		peer: Arc<Peer>,
+	) -> Result<SharedTunnel, Error> {
+		let op_id = OpId::new();
+		let protocol = p2p.peer_protocol(remote_identity).await;
+		let stream = p2p
+			.new_shared_stream(&peer, P2POperation::Sync)
+			.await
+			.map_err(|err| Error::Connection(err.to_string()))?;
+		let mut stream = p2p.metrics.instrument(P2POperation::Sync, stream);
+
+		// `library_id` is the library which needed the tunnel first, the responder ignores it
+		stream
+			.write_all(&Header::Sync(library_id).to_bytes_for(op_id, &protocol))
+			.await
+			.map_err(tunnel_failed("sending sync header"))?;
+
+		let mut tunnel = open_tunnel(stream, &protocol)
+			.await
+			.map_err(|err| Error::Tunnel(err.to_string()))?;
+		tunnel
+			.write_all(&SyncMessage::Multiplexed.to_bytes())
+			.await
+			.map_err(tunnel_failed("starting multiplexed sync"))?;
+		tunnel
+			.flush()
+			.await
+			.map_err(tunnel_failed("starting multiplexed sync"))?;
+
+		match SyncResponse::from_stream(&mut tunnel)
+			.await
+			.map_err(decode_failed("reading sync response"))?
+		{
+			SyncResponse::Ok => Ok(SharedTunnel { tunnel, op_id }),
+			response => Err(Error::Protocol(format!(
+				"refused to multiplex sync sessions: {response:?}"
+			))),
+		}
+	}
+
+	/// Stop alerting peers which don't have the library, or are busy syncing it
+	fn note_rejection(
+		p2p: &super::P2PManager,
+		library_id: Uuid,
+		remote_identity: RemoteIdentity,
+		metadata: &Option<String>,
+		response: SyncResponse,
+	) {
+		match response {
+			SyncResponse::Ok => {}
+			SyncResponse::LibraryNotFound => {
+				warn!("Peer '{remote_identity:?}' doesn't have library '{library_id:?}', not syncing with it until its metadata changes");
+				p2p.sync_non_participants
+					.insert(library_id, remote_identity, metadata.clone());
+			}
+			SyncResponse::Busy => {
+				debug!("Peer '{remote_identity:?}' is busy syncing library '{library_id:?}'")
+			}
+		}
+	}
+
+	/// Send `message` telling the remote we have new operations and send it those it asks for, until it's done.
+	///
+	/// Returns how the remote responded, the operations are only sent if it was [`SyncResponse::Ok`].
+	/// A `legacy` remote never responds, it goes straight to asking for operations in [`Framing::legacy`] messages.
+	pub(super) async fn offer_operations<Fut, E>(
+		stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+		message: &[u8],
+		legacy: bool,
+		mut get_ops: impl FnMut(GetOpsArgs) -> Fut,
+		progress: &mut SyncProgress,
+	) -> Result<SyncResponse, Error>
+	where
+		Fut: Future<Output = Result<Vec<CRDTOperation>, E>>,
+		E: std::fmt::Display,
+	{
+		stream
+			.write_all(message)
+			.await
+			.map_err(tunnel_failed("offering operations"))?;
+		stream
+			.flush()
+			.await
+			.map_err(tunnel_failed("offering operations"))?;
+
+		let mut framing = if legacy {
+			Framing::legacy()
+		} else {
+			match SyncResponse::from_stream(stream)
+				.await
+				.map_err(decode_failed("reading sync response"))?
+			{
+				SyncResponse::Ok => {}
+				response => return Ok(response),
+			}
+			Framing::default()
+		};
+		progress.phase(SyncPhase::Live);
+
+		loop {
+			let args = match rx::MainRequest::from_stream(stream, &mut framing)
+				.await
+				.map_err(tunnel_failed("reading operations request"))?
+			{
+				rx::MainRequest::GetOperations(args) => args,
+				rx::MainRequest::Done => return Ok(SyncResponse::Ok),
+			};
+
+			let ops = get_ops(args)
+				.await
+				.map_err(|err| Error::Other(format!("failed to get operations: {err}")))?;
+			let count = ops.len();
+
+			tx::Operations(ops)
+				.write(stream, &mut framing)
+				.await
+				.map_err(tunnel_failed("sending operations"))?;
+			progress.sent(count);
+		}
+	}
+
+	/// Tell `peer` we have new operations and send it those it asks for, until it's done
+	async fn alert_new_operations(
+		library_id: Uuid,
+		sync: &sync::Manager,
+		p2p: &Arc<super::P2PManager>,
+		remote_identity: RemoteIdentity,
+		peer: &Peer,
+		metadata: &Option<String>,
+		op_id: OpId,
+	) -> Result<(), Error> {
+		let mut progress =
+			SyncProgress::new(p2p.events.sender(), library_id, remote_identity, op_id);
+		let protocol = p2p.peer_protocol(remote_identity).await;
+		let stream = p2p
+			.new_stream(peer, P2POperation::Sync)
+			.await
+			.map_err(|err| Error::Connection(err.to_string()))?;
+		let mut stream = p2p.metrics.instrument(P2POperation::Sync, stream);
+
+		stream
+			.write_all(&Header::Sync(library_id).to_bytes_for(op_id, &protocol))
+			.await
+			.map_err(tunnel_failed("sending sync header"))?;
+
+		let mut tunnel = open_tunnel(stream, &protocol)
+			.await
+			.map_err(|err| Error::Tunnel(err.to_string()))?;
+
+		let response = offer_operations(
+			&mut tunnel,
+			&SyncMessage::NewOperations.to_bytes(),
+			!protocol.is_current(),
+			|args| sync.get_ops(args),
+			&mut progress,
+		)
+		.await?;
+		note_rejection(p2p, library_id, remote_identity, metadata, response);
+
+		Ok(())
+	}
+
+	/// Ask `identity` for every operation it has since `timestamp` and ingest them.
+	/// Unlike [`run`] this pulls operations, so it works even if we were offline when they were created.
+	///
+	/// The request is made again if the tunnel to `identity` dies before it's done.
+	pub async fn request_operations_since(
+		library_id: Uuid,
+		sync: &Arc<sync::Manager>,
+		p2p: &Arc<super::P2PManager>,
+		remote_identity: RemoteIdentity,
+		timestamp: sync::NTP64,
+	) -> Result<(), Error> {
+		retry_on_tunnel_death(remote_identity, SESSION_RETRY_BACKOFF, || {
+			let op_id = OpId::new();
+			request_operations_since_once(library_id, sync, p2p, remote_identity, timestamp, op_id)
+				.instrument(op_id.span(remote_identity, P2POperation::Sync))
+		})
+		.await
+	}
+
+	async fn request_operations_since_once(
+		library_id: Uuid,
+		sync: &Arc<sync::Manager>,
+		p2p: &Arc<super::P2PManager>,
+		remote_identity: RemoteIdentity,
+		timestamp: sync::NTP64,
+		op_id: OpId,
+	) -> Result<(), Error> {
+		let peer = p2p
+			.get_instance(&library_id, remote_identity)
+			.ok_or_else(|| {
+				Error::Connection(format!(
+					"'{remote_identity}' isn't an instance of library '{library_id}'"
+				))
+			})?;
+
+		// Peers from before it can't be asked for operations, they only offer theirs when they have new ones
+		let protocol = p2p.peer_protocol(remote_identity).await;
+		if !protocol.is_current() {
+			return Err(Error::Protocol(
+				"the peer doesn't support requesting operations".into(),
+			));
+		}
+
+		let mut progress =
+			SyncProgress::new(p2p.events.sender(), library_id, remote_identity, op_id);
+		let stream = p2p
+			.new_stream(&peer, P2POperation::Sync)
+			.await
+			.map_err(|err| {
+				p2p.connect_failed(remote_identity, P2POperation::Sync, &err);
+				Error::Connection(err.to_string())
+			})?;
+		let mut stream = p2p.metrics.instrument(P2POperation::Sync, stream);
+		stream
+			.write_all(&Header::Sync(library_id).to_bytes_for(op_id, &protocol))
+			.await
+			.map_err(tunnel_failed("sending sync header"))?;
+
+		let mut tunnel = open_tunnel(stream, &protocol)
+			.await
+			.map_err(|err| Error::Tunnel(err.to_string()))?;
+		tunnel
+			.write_all(&SyncMessage::RequestOperationsSince { timestamp }.to_bytes())
+			.await
+			.map_err(tunnel_failed("requesting operations"))?;
+		tunnel
+			.flush()
+			.await
+			.map_err(tunnel_failed("requesting operations"))?;
+
+		match SyncResponse::from_stream(&mut tunnel)
+			.await
+			.map_err(decode_failed("reading sync response"))?
+		{
+			SyncResponse::Ok => progress.phase(SyncPhase::Backfill),
+			SyncResponse::LibraryNotFound => {
+				p2p.sync_non_participants.insert(
+					library_id,
+					remote_identity,
+					peer.metadata().get(&library_id.to_string()).cloned(),
+				);
+				return Err(Error::LibraryNotFound);
+			}
+			SyncResponse::Busy => {
+				return Err(Error::Other(format!(
+					"the peer is busy syncing library '{library_id}'"
+				)));
+			}
+		}
+
+		ingest_frames(&mut tunnel, sync, &mut progress).await
+	}
+
+	/// Read a single [`OperationsFrame`], an empty one marks the end of the stream.
+	pub(super) async fn receive_operations(
+		stream: &mut (impl AsyncRead + Unpin),
+		progress: &mut SyncProgress,
+	) -> Result<OperationsFrame, sd_p2p_proto::decode::Error> {
+		let frame = OperationsFrame::from_stream(stream).await?;
+		progress.received(frame.ops.len());
+		Ok(frame)
+	}
+
+	/// Feed the [`OperationsFrame`]s the remote sends us into the ingest actor until it sends the last one.
+	///
+	/// Frames are ingested as they arrive whatever their [`ModelClass`], so what the remote sends first is usable first.
+	/// If the remote stops before the last frame, our timestamps are rewound with [`BackfillWatermarks`].
+	async fn ingest_frames(
+		stream: &mut (impl AsyncRead + Unpin),
+		sync: &sync::Manager,
+		progress: &mut SyncProgress,
+	) -> Result<(), Error> {
+		let ingest = &sync.ingest;
+
+		let Ok(mut rx) = ingest.req_rx.try_lock() else {
+			return Err(Error::Other(
+				"unable to backfill as the ingest actor is already in use".into(),
+			));
+		};
+
+		use sync::ingest::*;
+
+		let mut watermarks = BackfillWatermarks::new(sync.timestamps.read().await.clone());
+
+		ingest
+			.event_tx
+			.send(Event::Notification)
+			.await
+			.map_err(|err| Error::Other(format!("failed to notify the ingest actor: {err}")))?;
+
+		while let Some(req) = rx.recv().await {
+			// Held until we've replied so the actor doesn't think the request was ignored
+			let _tx = match req {
+				Request::FinishedIngesting => break,
+				Request::Messages { tx, .. } => tx,
+				_ => continue,
+			};
+
+			// The actor asking for more means it's done applying the last frame
+			let OperationsFrame { class, ops } = match receive_operations(stream, progress).await {
+				Ok(frame) => frame,
+				Err(err) => {
+					watermarks.rewind(&mut *sync.timestamps.write().await);
+					return Err(decode_failed("reading operations frame")(err));
+				}
+			};
+			debug!("Ingesting {} operations of {class:?}", ops.len());
+			watermarks.received(class, &ops);
+
+			ingest
+				.event_tx
+				.send(Event::Messages(MessagesEvent {
+					instance_id: sync.instance,
+					// An empty frame marks the end
+					has_more: !ops.is_empty(),
+					messages: ops,
+				}))
+				.await
+				.map_err(|err| {
+					Error::Other(format!(
+						"failed to send operations to the ingest actor: {err}"
+					))
+				})?;
 		}
+
+		debug!("Backfill done");
+
+		Ok(())
 	}
 }
 
-pub use responder::run as responder;
+pub use responder::{reject as reject_sync, run as responder, send_operations_since};
 mod responder {
 	use super::*;
 	use originator::tx as rx;
@@ -145,18 +981,21 @@ mod responder {
 			// TODO: Per field errors for better error handling
 			pub async fn from_stream(
 				stream: &mut (impl AsyncRead + Unpin),
+				framing: &mut Framing,
 			) -> std::io::Result<Self> {
-				Ok(
-					// TODO: Error handling
-					rmp_serde::from_slice(&decode::buf(stream).await.unwrap()).unwrap(),
-				)
+				rmp_serde::from_slice(&framing.read(stream).await?)
+					.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
 			}
 
-			pub fn to_bytes(&self) -> Vec<u8> {
-				let mut buf = vec![];
+			pub async fn write(
+				&self,
+				stream: &mut (impl AsyncWrite + Unpin),
+				framing: &mut Framing,
+			) -> std::io::Result<usize> {
 				// TODO: Error handling
-				encode::buf(&mut buf, &rmp_serde::to_vec_named(&self).unwrap());
-				buf
+				framing
+					.write(stream, &rmp_serde::to_vec_named(&self).unwrap())
+					.await
 			}
 		}
 
@@ -169,43 +1008,198 @@ mod responder {
 					count: 0,
 				});
 
-				let mut cursor = std::io::Cursor::new(original.to_bytes());
-				let result = MainRequest::from_stream(&mut cursor).await.unwrap();
+				let mut buf = vec![];
+				original
+					.write(&mut buf, &mut Framing::default())
+					.await
+					.unwrap();
+				let mut cursor = std::io::Cursor::new(buf);
+				let result = MainRequest::from_stream(&mut cursor, &mut Framing::default())
+					.await
+					.unwrap();
 				assert_eq!(original, result);
 			}
 
 			{
 				let original = MainRequest::Done;
 
-				let mut cursor = std::io::Cursor::new(original.to_bytes());
-				let result = MainRequest::from_stream(&mut cursor).await.unwrap();
+				let mut buf = vec![];
+				original
+					.write(&mut buf, &mut Framing::default())
+					.await
+					.unwrap();
+				let mut cursor = std::io::Cursor::new(buf);
+				let result = MainRequest::from_stream(&mut cursor, &mut Framing::default())
+					.await
+					.unwrap();
 				assert_eq!(original, result);
 			}
 		}
 	}
 
-	pub async fn run(
+	/// Stream every operation we have since `timestamp` to the originator of a [`SyncMessage::RequestOperationsSince`].
+	pub async fn send_operations_since(
 		stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
 		library: Arc<Library>,
-	) -> Result<(), ()> {
-		let ingest = &library.sync.ingest;
+		timestamp: sync::NTP64,
+		progress: &mut SyncProgress,
+	) -> Result<(), Error> {
+		// Instances which aren't in `clocks` get all of their operations sent so every instance we know, including ourselves, must be in it
+		let mut clocks = library
+			.sync
+			.timestamps
+			.read()
+			.await
+			.keys()
+			.map(|&instance| (instance, timestamp))
+			.collect::<Vec<_>>();
+		if !clocks
+			.iter()
+			.any(|(instance, _)| *instance == library.sync.instance)
+		{
+			clocks.push((library.sync.instance, timestamp));
+		}
 
-		async fn early_return(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) {
-			// TODO: Proper error returned to remote instead of this.
-			// TODO: We can't just abort the connection when the remote is expecting data.
-			stream
-				.write_all(&tx::MainRequest::Done.to_bytes())
+		stream
+			.write_all(&SyncResponse::Ok.to_bytes())
+			.await
+			.map_err(tunnel_failed("sending sync response"))?;
+		progress.phase(SyncPhase::Backfill);
+
+		send_operations(
+			stream,
+			clocks,
+			|class, args| library.sync.get_ops_of_models(args, class.filter()),
+			progress,
+		)
+		.await
+	}
+
+	/// Send the operations `get_ops` returns in [`OperationsFrame`]s, batch by batch so a large history is never all in memory.
+	///
+	/// Every [`ModelClass`] is sent in turn, each one from `clocks` onwards, so the models which matter most
+	/// arrive first instead of in the order they were created.
+	pub(super) async fn send_operations<Fut, E>(
+		stream: &mut (impl AsyncWrite + Unpin),
+		clocks: Vec<(Uuid, sync::NTP64)>,
+		mut get_ops: impl FnMut(ModelClass, GetOpsArgs) -> Fut,
+		progress: &mut SyncProgress,
+	) -> Result<(), Error>
+	where
+		Fut: Future<Output = Result<Vec<CRDTOperation>, E>>,
+		E: std::fmt::Display,
+	{
+		const OPS_PER_BATCH: u32 = 1000;
+
+		for class in ModelClass::ALL {
+			let mut clocks = clocks.clone();
+
+			loop {
+				let ops = get_ops(
+					class,
+					GetOpsArgs {
+						clocks: clocks.clone(),
+						count: OPS_PER_BATCH,
+					},
+				)
 				.await
-				.unwrap();
-			stream.flush().await.unwrap();
+				.map_err(|err| {
+					Error::Other(format!("failed to get operations of {class:?}: {err}"))
+				})?;
+
+				for op in &ops {
+					match clocks
+						.iter_mut()
+						.find(|(instance, _)| *instance == op.instance)
+					{
+						Some((_, timestamp)) => {
+							*timestamp = sync::NTP64::max(*timestamp, op.timestamp)
+						}
+						None => clocks.push((op.instance, op.timestamp)),
+					}
+				}
+
+				let frames = OperationsFrame::encode(class, &ops, MAX_FRAME_BYTES)
+					.map_err(|err| Error::Other(format!("failed to encode operations: {err}")))?;
+				for frame in frames {
+					stream
+						.write_all(&frame)
+						.await
+						.map_err(tunnel_failed("sending operations frame"))?;
+				}
+				progress.sent(ops.len());
+
+				if ops.len() < OPS_PER_BATCH as usize {
+					break;
+				}
+			}
+		}
+
+		stream
+			.write_all(&OperationsFrame::end())
+			.await
+			.map_err(tunnel_failed("sending final operations frame"))?;
+		stream
+			.flush()
+			.await
+			.map_err(tunnel_failed("flushing operations"))
+	}
+
+	/// Tell the originator we won't sync with it.
+	/// A `legacy` originator doesn't know about [`SyncResponse`]s, so it's told we are done instead.
+	pub async fn reject(
+		stream: &mut (impl AsyncWrite + Unpin),
+		response: SyncResponse,
+		legacy: bool,
+	) -> Result<(), Error> {
+		if legacy {
+			tx::MainRequest::Done
+				.write(stream, &mut Framing::legacy())
+				.await
+				.map_err(tunnel_failed("rejecting sync"))?;
+			return Ok(());
 		}
 
+		stream
+			.write_all(&response.to_bytes())
+			.await
+			.map_err(tunnel_failed("rejecting sync"))?;
+		stream
+			.flush()
+			.await
+			.map_err(tunnel_failed("rejecting sync"))
+	}
+
+	/// Ends the session when the originator stops responding, so the ingest actor isn't held forever.
+	///
+	/// A `legacy` originator gets no [`SyncResponse`] and talks in [`Framing::legacy`] messages.
+	pub async fn run(
+		stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+		library: Arc<Library>,
+		legacy: bool,
+		progress: &mut SyncProgress,
+	) -> Result<(), Error> {
+		let ingest = &library.sync.ingest;
+
 		let Ok(mut rx) = ingest.req_rx.try_lock() else {
 			warn!("Rejected sync due to libraries lock being held!");
+			return reject(stream, SyncResponse::Busy, legacy).await;
+		};
 
-			early_return(stream).await;
-			return Ok(());
+		let mut framing = if legacy {
+			Framing::legacy()
+		} else {
+			stream
+				.write_all(&SyncResponse::Ok.to_bytes())
+				.await
+				.map_err(tunnel_failed("accepting sync"))?;
+			stream
+				.flush()
+				.await
+				.map_err(tunnel_failed("accepting sync"))?;
+			Framing::default()
 		};
+		progress.phase(SyncPhase::Live);
 
 		use sync::ingest::*;
 
@@ -222,19 +1216,18 @@ mod responder {
 
 			debug!("Getting ops for timestamps {timestamps:?}");
 
-			stream
-				.write_all(
-					&tx::MainRequest::GetOperations(sync::GetOpsArgs {
-						clocks: timestamps,
-						count: OPS_PER_REQUEST,
-					})
-					.to_bytes(),
-				)
-				.await
-				.unwrap();
-			stream.flush().await.unwrap();
+			tx::MainRequest::GetOperations(sync::GetOpsArgs {
+				clocks: timestamps,
+				count: OPS_PER_REQUEST,
+			})
+			.write(stream, &mut framing)
+			.await
+			.map_err(tunnel_failed("requesting operations"))?;
 
-			let rx::Operations(ops) = rx::Operations::from_stream(stream).await.unwrap();
+			let rx::Operations(ops) = rx::Operations::from_stream(stream, &mut framing)
+				.await
+				.map_err(tunnel_failed("receiving operations"))?;
+			progress.received(ops.len());
 
 			ingest
 				.event_tx
@@ -249,12 +1242,732 @@ mod responder {
 
 		debug!("Sync responder done");
 
-		stream
-			.write_all(&tx::MainRequest::Done.to_bytes())
+		tx::MainRequest::Done
+			.write(stream, &mut framing)
 			.await
-			.unwrap();
-		stream.flush().await.unwrap();
+			.map_err(tunnel_failed("ending sync"))?;
 
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use sd_p2p::{Identity, Peer};
+
+	use crate::p2p::{OpId, P2POperation, StreamDirection};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn only_one_session_per_peer_and_library() {
+		let sessions = Arc::new(SyncSessions::default());
+		let identity = Identity::default().to_remote_identity();
+		let library_id = Uuid::new_v4();
+
+		let active = Arc::new(AtomicUsize::new(0));
+		let max_active = Arc::new(AtomicUsize::new(0));
+		let ran = Arc::new(AtomicUsize::new(0));
+
+		// Three sync requests for the same library arrive in quick succession
+		let responders = (0..3)
+			.map(|_| {
+				let sessions = sessions.clone();
+				let (active, max_active, ran) = (active.clone(), max_active.clone(), ran.clone());
+				tokio::spawn(async move {
+					let Some(_guard) = sessions.try_start(identity, library_id) else {
+						// They would get `SyncResponse::Busy`
+						return;
+					};
+
+					let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+					max_active.fetch_max(now_active, Ordering::SeqCst);
+					ran.fetch_add(1, Ordering::SeqCst);
+					tokio::time::sleep(Duration::from_millis(50)).await;
+					active.fetch_sub(1, Ordering::SeqCst);
+				})
+			})
+			.collect::<Vec<_>>();
+		for responder in responders {
+			responder.await.unwrap();
+		}
+
+		assert_eq!(max_active.load(Ordering::SeqCst), 1);
+		assert!(ran.load(Ordering::SeqCst) >= 1);
+
+		// Other libraries and peers aren't affected and the guard is released once the session ends
+		let _other_library = sessions.try_start(identity, Uuid::new_v4()).unwrap();
+		let _other_peer = sessions
+			.try_start(Identity::default().to_remote_identity(), library_id)
+			.unwrap();
+		assert!(sessions.try_start(identity, library_id).is_some());
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn originator_backs_off_after_library_not_found() {
+		use crate::library::LibraryName;
+		use tokio::sync::broadcast::error::TryRecvError;
+
+		let (node, other, _dirs) = two_nodes().await;
+		let remote = other.p2p.p2p.remote_identity();
+		let peer = peer_of(&node, &other).await;
+		// Only peers we're connected with are alerted, which we are once they opened a stream to us
+		peer_of(&other, &node).await.new_stream().await.unwrap();
+		assert!(peer.is_connected());
+		let library = node
+			.libraries
+			.create(LibraryName::new("Ours").unwrap(), None, &node)
+			.await
+			.unwrap();
+		let library_id = library.id;
+		let mut events = node.p2p.events.subscribe();
+		// How many sessions for the library were started since last time
+		let mut sessions = move || {
+			let mut started = 0;
+			loop {
+				match events.try_recv() {
+					Ok(P2PEvent::SyncProgress {
+						library_id: id,
+						phase: SyncPhase::Handshake,
+						..
+					}) if id == library_id => started += 1,
+					Ok(_) | Err(TryRecvError::Lagged(_)) => continue,
+					Err(_) => break started,
+				}
+			}
+		};
+
+		// The other node never had the library, but what we last heard from it says otherwise
+		let advertise = |metadata: &String| {
+			peer.metadata_mut()
+				.insert(library_id.to_string(), metadata.clone());
+		};
+		let stale = Identity::default().to_remote_identity().to_string();
+		advertise(&stale);
+
+		originator::run(library.id, &library.sync, &node.p2p).await;
+		timeout(Duration::from_secs(5), async {
+			while !node
+				.p2p
+				.sync_non_participants
+				.should_skip(library.id, remote, Some(&stale))
+			{
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+		assert!(sessions() >= 1);
+
+		// A single rejection is enough, new operations don't start another session
+		originator::run(library.id, &library.sync, &node.p2p).await;
+		tokio::time::sleep(Duration::from_millis(200)).await;
+		assert_eq!(sessions(), 0);
+
+		// Until the other node advertises something else for the library
+		advertise(&Identity::default().to_remote_identity().to_string());
+		originator::run(library.id, &library.sync, &node.p2p).await;
+		timeout(Duration::from_secs(5), async {
+			while sessions() == 0 {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		node.shutdown().await;
+		other.shutdown().await;
+	}
+
+	/// Behaves like `sync::Manager::get_ops_of_models` over `history`
+	fn ops_of(
+		history: &[CRDTOperation],
+		class: ModelClass,
+		args: GetOpsArgs,
+	) -> Vec<CRDTOperation> {
+		history
+			.iter()
+			.filter(|op| ModelClass::of(&op.model) == class)
+			.filter(|op| {
+				args.clocks
+					.iter()
+					.find(|(instance, _)| *instance == op.instance)
+					.map_or(true, |(_, timestamp)| op.timestamp > *timestamp)
+			})
+			.take(args.count as usize)
+			.cloned()
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn backfills_operations_created_while_disconnected() {
+		let instance = Uuid::new_v4();
+		let other_instance = Uuid::new_v4();
+
+		// Everything after `1000` was created while the requester was offline
+		let history = (1..=2500)
+			.map(|i| CRDTOperation {
+				instance: if i % 3 == 0 { other_instance } else { instance },
+				timestamp: sync::NTP64(i),
+				record_id: rmpv::Value::Nil,
+				model: "name".to_string(),
+				data: sd_sync::CRDTOperationData::Create,
+			})
+			.collect::<Vec<_>>();
+
+		let get_ops = |class, args| {
+			let ops = ops_of(&history, class, args);
+			async move { Ok::<_, String>(ops) }
+		};
+
+		let library_id = Uuid::new_v4();
+		let (responder_events, mut responder_rx) = tokio::sync::broadcast::channel(64);
+		let (requester_events, mut requester_rx) = tokio::sync::broadcast::channel(64);
+
+		let (mut requester, mut responder) = tokio::io::duplex(1024);
+		let send = async {
+			let mut progress = SyncProgress::new(
+				responder_events.into(),
+				library_id,
+				Identity::default().to_remote_identity(),
+				OpId::new(),
+			);
+			responder::send_operations(
+				&mut responder,
+				vec![(instance, sync::NTP64(1000))],
+				get_ops,
+				&mut progress,
+			)
+			.await
+		};
+		let receive = async {
+			let mut progress = SyncProgress::new(
+				requester_events.into(),
+				library_id,
+				Identity::default().to_remote_identity(),
+				OpId::new(),
+			);
+			let mut received = vec![];
+			loop {
+				let OperationsFrame { ops, .. } =
+					originator::receive_operations(&mut requester, &mut progress)
+						.await
+						.unwrap();
+				if ops.is_empty() {
+					break received;
+				}
+				received.extend(ops);
+			}
+		};
+
+		let (sent, received) = tokio::join!(send, receive);
+		sent.unwrap();
+
+		// Both sides report their progress
+		let last_progress = |rx: &mut tokio::sync::broadcast::Receiver<crate::p2p::P2PEvent>| {
+			let mut last = None;
+			while let Ok(crate::p2p::P2PEvent::SyncProgress {
+				operations_sent,
+				operations_received,
+				..
+			}) = rx.try_recv()
+			{
+				last = Some((operations_sent, operations_received));
+			}
+			last
+		};
+		let (responder_sent, _) = last_progress(&mut responder_rx).unwrap();
+		let (_, requester_received) = last_progress(&mut requester_rx).unwrap();
+		assert_eq!(responder_sent as usize, received.len());
+		assert_eq!(requester_received as usize, received.len());
+
+		// `other_instance` isn't in the clocks so all of its operations are sent
+		let expected = history
+			.iter()
+			.filter(|op| op.instance == other_instance || op.timestamp > sync::NTP64(1000))
+			.cloned()
+			.collect::<Vec<_>>();
+		assert_eq!(received, expected);
+	}
+
+	#[tokio::test]
+	async fn shared_metadata_arrives_before_file_paths() {
+		use sd_prisma::prisma::{file_path, tag};
+
+		let instance = Uuid::new_v4();
+
+		// The file paths were indexed first, then tagged, and a few more were indexed after that
+		let model = |i: u64| match i {
+			5001..=5100 => tag::NAME,
+			_ => file_path::NAME,
+		};
+		let history = (1..=6000)
+			.map(|i| CRDTOperation {
+				instance,
+				timestamp: sync::NTP64(i),
+				record_id: rmpv::Value::from(i),
+				model: model(i).to_string(),
+				data: sd_sync::CRDTOperationData::Create,
+			})
+			.collect::<Vec<_>>();
+		let get_ops = |class, args| {
+			let ops = ops_of(&history, class, args);
+			async move { Ok::<_, String>(ops) }
+		};
+
+		let library_id = Uuid::new_v4();
+		let (events, _rx) = tokio::sync::broadcast::channel(64);
+		let (mut requester, mut responder) = tokio::io::duplex(1024);
+		let send = async {
+			let mut progress = SyncProgress::new(
+				events.clone(),
+				library_id,
+				Identity::default().to_remote_identity(),
+				OpId::new(),
+			);
+			responder::send_operations(
+				&mut responder,
+				vec![(instance, sync::NTP64(0))],
+				get_ops,
+				&mut progress,
+			)
+			.await
+		};
+		// Applies every frame as soon as it arrives, like the ingest actor
+		let receive = async {
+			let mut progress = SyncProgress::new(
+				events.clone(),
+				library_id,
+				Identity::default().to_remote_identity(),
+				OpId::new(),
+			);
+			let mut applied = vec![];
+			loop {
+				let OperationsFrame { class, ops } =
+					originator::receive_operations(&mut requester, &mut progress)
+						.await
+						.unwrap();
+				if ops.is_empty() {
+					break applied;
+				}
+				assert!(ops.iter().all(|op| ModelClass::of(&op.model) == class));
+				applied.extend(ops);
+			}
+		};
+
+		let (sent, applied) = tokio::join!(send, receive);
+		sent.unwrap();
+
+		let mut sorted = applied.clone();
+		sorted.sort_by_key(|op| op.timestamp);
+		assert_eq!(sorted, history);
+
+		// Every tag was applied before the first file path even arrived
+		let last_tag = applied
+			.iter()
+			.rposition(|op| op.model == tag::NAME)
+			.unwrap();
+		let first_file_path = applied
+			.iter()
+			.position(|op| op.model == file_path::NAME)
+			.unwrap();
+		assert!(last_tag < first_file_path);
+
+		// Each class is still in the order it was created in
+		let file_paths = applied
+			.iter()
+			.filter(|op| op.model == file_path::NAME)
+			.collect::<Vec<_>>();
+		assert!(file_paths
+			.windows(2)
+			.all(|w| w[0].timestamp < w[1].timestamp));
+	}
+
+	/// Runs a single `GetOperations` exchange for `ops`, returning how many bytes the originator sent.
+	async fn exchange(ops: Vec<CRDTOperation>, responder_framing: Framing) -> usize {
+		let (mut originator, mut responder) = tokio::io::duplex(64 * 1024);
+
+		let respond = async {
+			let mut framing = responder_framing;
+			responder::tx::MainRequest::GetOperations(GetOpsArgs {
+				clocks: vec![],
+				count: 1000,
+			})
+			.write(&mut responder, &mut framing)
+			.await
+			.unwrap();
+
+			originator::tx::Operations::from_stream(&mut responder, &mut framing)
+				.await
+				.unwrap()
+		};
+		let originate = async {
+			let mut framing = Framing::default();
+			let request = responder::tx::MainRequest::from_stream(&mut originator, &mut framing)
+				.await
+				.unwrap();
+			assert!(matches!(
+				request,
+				responder::tx::MainRequest::GetOperations(_)
+			));
+
+			originator::tx::Operations(ops.clone())
+				.write(&mut originator, &mut framing)
+				.await
+				.unwrap()
+		};
+
+		let (received, sent) = tokio::join!(respond, originate);
+		assert_eq!(received.0, ops);
+		sent
+	}
+
+	#[tokio::test]
+	async fn sync_payloads_are_compressed_when_supported() {
+		let instance = Uuid::new_v4();
+		let ops = (0..3000)
+			.map(|i| CRDTOperation {
+				instance,
+				timestamp: sync::NTP64(i),
+				record_id: rmpv::Value::String(format!("record-{i}").into()),
+				model: "file_path".to_string(),
+				data: sd_sync::CRDTOperationData::Create,
+			})
+			.collect::<Vec<_>>();
+
+		let uncompressed = exchange(ops.clone(), Framing::default().without_compression()).await;
+		let compressed = exchange(ops, Framing::default()).await;
+		assert!(
+			compressed < uncompressed,
+			"compressed: {compressed}, uncompressed: {uncompressed}"
+		);
+	}
+
+	#[tokio::test]
+	async fn sessions_end_when_the_tunnel_dies_mid_batch() {
+		use sd_p2p_tunnel::{
+			testing::{faulty_tunnels, FaultyTunnels},
+			KeepAlive,
+		};
+
+		let instance = Uuid::new_v4();
+		let history = (1..=2500)
+			.map(|i| CRDTOperation {
+				instance,
+				timestamp: sync::NTP64(i),
+				record_id: rmpv::Value::from(i),
+				model: "name".to_string(),
+				data: sd_sync::CRDTOperationData::Create,
+			})
+			.collect::<Vec<_>>();
+		let get_ops = |class, args| {
+			let ops = ops_of(&history, class, args);
+			async move { Ok::<_, String>(ops) }
+		};
+
+		let keep_alive = KeepAlive {
+			interval: Duration::from_millis(20),
+			max_missed: 3,
+		};
+		let FaultyTunnels {
+			mut initiator,
+			mut responder,
+			responder_faults,
+			..
+		} = faulty_tunnels(keep_alive, 64 * 1024).await;
+		// The link goes down in the middle of the first batch
+		responder_faults.drop_after(16 * 1024);
+
+		let library_id = Uuid::new_v4();
+		let (events, _rx) = tokio::sync::broadcast::channel(64);
+		let send = async {
+			let mut progress = SyncProgress::new(
+				events.clone(),
+				library_id,
+				Identity::default().to_remote_identity(),
+				OpId::new(),
+			);
+			responder::send_operations(
+				&mut responder,
+				vec![(instance, sync::NTP64(0))],
+				get_ops,
+				&mut progress,
+			)
+			.await
+		};
+		let receive = async {
+			let mut progress = SyncProgress::new(
+				events.clone(),
+				library_id,
+				Identity::default().to_remote_identity(),
+				OpId::new(),
+			);
+			let mut received = 0;
+			loop {
+				match originator::receive_operations(&mut initiator, &mut progress).await {
+					Ok(OperationsFrame { ops, .. }) if ops.is_empty() => break Ok(received),
+					Ok(OperationsFrame { ops, .. }) => received += ops.len(),
+					Err(err) => break Err((received, decode_failed("receiving")(err))),
+				}
+			}
+		};
+
+		let (sent, received) = tokio::time::timeout(Duration::from_secs(10), async {
+			tokio::join!(send, receive)
+		})
+		.await
+		.expect("the session should end instead of hanging");
+
+		// Everything the responder wrote was accepted, it can't tell the link went down
+		sent.unwrap();
+		let (received, err) = received.unwrap_err();
+		assert!(received < history.len());
+		assert!(
+			matches!(err, Error::TimedOut(silent_for) if silent_for == keep_alive.dead_after()),
+			"{err:?}"
+		);
+	}
+
+	#[tokio::test]
+	async fn tunnel_deaths_are_retried_with_backoff() {
+		let identity = Identity::default().to_remote_identity();
+		fn died() -> Result<(), Error> {
+			Err(tunnel_failed("testing")(std::io::Error::new(
+				std::io::ErrorKind::TimedOut,
+				PeerStoppedResponding {
+					silent_for: Duration::from_secs(1),
+				},
+			)))
+		}
+		fn done() -> Result<(), Error> {
+			Ok(())
+		}
+		fn refused() -> Result<(), Error> {
+			Err(Error::LibraryNotFound)
+		}
+
+		/// A session which ends with each of `outcomes` in turn
+		fn session<'a>(
+			outcomes: &'a [fn() -> Result<(), Error>],
+			attempts: &'a AtomicUsize,
+		) -> impl FnMut() -> std::future::Ready<Result<(), Error>> + 'a {
+			attempts.store(0, Ordering::SeqCst);
+			move || std::future::ready(outcomes[attempts.fetch_add(1, Ordering::SeqCst)]())
+		}
+		let attempts = AtomicUsize::new(0);
+		let backoff = Duration::from_millis(10);
+
+		let start = tokio::time::Instant::now();
+		assert!(
+			retry_on_tunnel_death(identity, backoff, session(&[died, done], &attempts))
+				.await
+				.is_ok()
+		);
+		assert_eq!(attempts.load(Ordering::SeqCst), 2);
+		assert!(start.elapsed() >= backoff);
+
+		// Up to `SESSION_ATTEMPTS` times, the backoff doubling every time
+		let start = tokio::time::Instant::now();
+		let always_dies = [died as fn() -> _; SESSION_ATTEMPTS as usize];
+		assert!(matches!(
+			retry_on_tunnel_death(identity, backoff, session(&always_dies, &attempts)).await,
+			Err(Error::TimedOut(_))
+		));
+		assert_eq!(attempts.load(Ordering::SeqCst), SESSION_ATTEMPTS as usize);
+		assert!(start.elapsed() >= backoff * 3);
+
+		// Other failures aren't worth retrying
+		assert!(matches!(
+			retry_on_tunnel_death(identity, backoff, session(&[refused], &attempts)).await,
+			Err(Error::LibraryNotFound)
+		));
+		assert_eq!(attempts.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn legacy_peers_are_offered_operations_like_they_used_to_be() {
+		let history = (0..5)
+			.map(|i| CRDTOperation {
+				instance: Uuid::new_v4(),
+				timestamp: sync::NTP64(i),
+				record_id: rmpv::Value::from(i),
+				model: "name".to_string(),
+				data: sd_sync::CRDTOperationData::Create,
+			})
+			.collect::<Vec<_>>();
+		let (mut originator, mut responder) = tokio::io::duplex(64 * 1024);
+
+		// What a responder from before framing and `SyncResponse`s sends, byte for byte
+		let respond = async {
+			assert_eq!(
+				SyncMessage::from_stream(&mut responder).await.unwrap(),
+				SyncMessage::NewOperations
+			);
+
+			let mut request = vec![];
+			sd_p2p_proto::encode::buf(
+				&mut request,
+				&rmp_serde::to_vec_named(&responder::tx::MainRequest::GetOperations(GetOpsArgs {
+					clocks: vec![],
+					count: 1000,
+				}))
+				.unwrap(),
+			);
+			responder.write_all(&request).await.unwrap();
+
+			let ops: Vec<CRDTOperation> =
+				rmp_serde::from_slice(&sd_p2p_proto::decode::buf(&mut responder).await.unwrap())
+					.unwrap();
+
+			let mut done = vec![];
+			sd_p2p_proto::encode::buf(
+				&mut done,
+				&rmp_serde::to_vec_named(&responder::tx::MainRequest::Done).unwrap(),
+			);
+			responder.write_all(&done).await.unwrap();
+			ops
+		};
+
+		let (events, _rx) = tokio::sync::broadcast::channel(64);
+		let identity = Identity::default().to_remote_identity();
+		let mut progress = SyncProgress::new(events, Uuid::new_v4(), identity, OpId::new());
+		let originate = originator::offer_operations(
+			&mut originator,
+			&SyncMessage::NewOperations.to_bytes(),
+			true,
+			|_| std::future::ready(Ok::<_, String>(history.clone())),
+			&mut progress,
+		);
+
+		let (received, response) = tokio::join!(respond, originate);
+		assert_eq!(response.unwrap(), SyncResponse::Ok);
+		assert_eq!(received, history);
+	}
+
+	/// Two nodes on the same network, answering each other's streams through their real dispatch.
+	/// The directories have to outlive the nodes.
+	async fn two_nodes() -> (Arc<crate::Node>, Arc<crate::Node>, [tempfile::TempDir; 2]) {
+		use crate::{p2p::P2PTransport, Env, Node};
+
+		let network = sd_p2p::MemoryNetwork::default();
+		let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+		let (node, _) = Node::new_with_p2p_transport(
+			dirs[0].path(),
+			Env::new("test"),
+			P2PTransport::Memory(network.clone()),
+		)
+		.await
+		.unwrap();
+		let (other, _) = Node::new_with_p2p_transport(
+			dirs[1].path(),
+			Env::new("test"),
+			P2PTransport::Memory(network),
+		)
+		.await
+		.unwrap();
+
+		(node, other, dirs)
+	}
+
+	/// `node`'s peer for `other`, once it discovered it
+	async fn peer_of(node: &crate::Node, other: &crate::Node) -> Arc<Peer> {
+		let remote = other.p2p.p2p.remote_identity();
+		timeout(Duration::from_secs(5), async {
+			loop {
+				if let Some(peer) = node.p2p.p2p.peers().get(&remote) {
+					break peer.clone();
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap()
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn libraries_shared_with_a_peer_are_synced_over_one_tunnel() {
+		use crate::library::LibraryName;
+
+		let (node, other, _dirs) = two_nodes().await;
+		let remote = other.p2p.p2p.remote_identity();
+		let peer = peer_of(&node, &other).await;
+
+		// The other node has two of our libraries, but not the third
+		let mut libraries = vec![];
+		for name in ["First", "Second", "Missing"] {
+			libraries.push(
+				node.libraries
+					.create(LibraryName::new(name).unwrap(), None, &node)
+					.await
+					.unwrap(),
+			);
+		}
+		for library in &libraries[..2] {
+			other
+				.libraries
+				.create_with_uuid(
+					library.id,
+					LibraryName::new("Shared").unwrap(),
+					None,
+					true,
+					None,
+					&other,
+					false,
+				)
+				.await
+				.unwrap();
+		}
+
+		let alert = |library: &Arc<Library>| {
+			originator::alert_over_shared_tunnel(
+				library.id,
+				library.sync.clone(),
+				node.p2p.clone(),
+				remote,
+				peer.clone(),
+				None,
+			)
+		};
+
+		// All of them want to sync at the same time
+		let (first, second, missing) = tokio::join!(
+			alert(&libraries[0]),
+			alert(&libraries[1]),
+			alert(&libraries[2])
+		);
+		first.unwrap();
+		second.unwrap();
+		// The rejection is for that library alone, the tunnel is still good for the next one
+		missing.unwrap();
+		alert(&libraries[0]).await.unwrap();
+
+		assert_eq!(node.p2p.sync_tunnels.opened(), 1);
+		// The tunnel stays open, it mustn't hold one of the few sync streams on either side
+		assert_eq!(
+			node.p2p
+				.stream_limiter
+				.active(P2POperation::Sync, StreamDirection::Outbound),
+			0
+		);
+		assert_eq!(
+			other
+				.p2p
+				.stream_limiter
+				.active(P2POperation::Sync, StreamDirection::Inbound),
+			0
+		);
+		assert!(node
+			.p2p
+			.sync_non_participants
+			.should_skip(libraries[2].id, remote, None));
+		assert!(!node
+			.p2p
+			.sync_non_participants
+			.should_skip(libraries[0].id, remote, None));
+
+		node.shutdown().await;
+		other.shutdown().await;
+	}
+}
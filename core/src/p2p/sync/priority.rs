@@ -0,0 +1,213 @@
+use crate::sync::{ModelFilter, NTP64};
+
+use sd_prisma::prisma::{
+	file_path, label, label_on_object, location, media_data, object, preference, saved_search, tag,
+	tag_on_object,
+};
+use sd_sync::CRDTOperation;
+
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+/// The groups of models a backfill is sent in, in the order they are sent.
+///
+/// What people notice is missing comes first, so a device catching up on a large library shows its tags
+/// and labels long before the millions of file paths are done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ModelClass {
+	/// The library's preferences and saved searches
+	Preferences,
+	Tags,
+	Labels,
+	/// Objects and what's attached to them, which can only be applied once the tags and labels exist
+	Objects,
+	/// File paths and the locations they are in
+	FilePaths,
+	/// Any model not in another class
+	Other,
+}
+
+/// Every model which is part of a class other than [`ModelClass::Other`]
+const CLASSIFIED: &[&str] = &[
+	preference::NAME,
+	saved_search::NAME,
+	tag::NAME,
+	label::NAME,
+	object::NAME,
+	media_data::NAME,
+	tag_on_object::NAME,
+	label_on_object::NAME,
+	location::NAME,
+	file_path::NAME,
+];
+
+impl ModelClass {
+	pub const ALL: [Self; 6] = [
+		Self::Preferences,
+		Self::Tags,
+		Self::Labels,
+		Self::Objects,
+		Self::FilePaths,
+		Self::Other,
+	];
+
+	const fn models(self) -> &'static [&'static str] {
+		match self {
+			Self::Preferences => &[preference::NAME, saved_search::NAME],
+			Self::Tags => &[tag::NAME],
+			Self::Labels => &[label::NAME],
+			Self::Objects => &[
+				object::NAME,
+				media_data::NAME,
+				tag_on_object::NAME,
+				label_on_object::NAME,
+			],
+			Self::FilePaths => &[location::NAME, file_path::NAME],
+			Self::Other => &[],
+		}
+	}
+
+	pub fn of(model: &str) -> Self {
+		Self::ALL
+			.into_iter()
+			.find(|class| class.models().contains(&model))
+			.unwrap_or(Self::Other)
+	}
+
+	/// What to pass [`sync::Manager::get_ops_of_models`](crate::sync::Manager::get_ops_of_models) to only get the operations of this class
+	pub fn filter(self) -> ModelFilter<'static> {
+		match self {
+			Self::Other => ModelFilter::Except(CLASSIFIED),
+			_ => ModelFilter::Only(self.models()),
+		}
+	}
+
+	pub fn as_u8(self) -> u8 {
+		match self {
+			Self::Preferences => 0,
+			Self::Tags => 1,
+			Self::Labels => 2,
+			Self::Objects => 3,
+			Self::FilePaths => 4,
+			Self::Other => 5,
+		}
+	}
+
+	/// Classes we don't know, from a newer version, are treated like [`Self::Other`]
+	pub fn from_u8(class: u8) -> Self {
+		Self::ALL
+			.into_iter()
+			.find(|c| c.as_u8() == class)
+			.unwrap_or(Self::Other)
+	}
+}
+
+/// What a backfill has delivered of each instance's operations, for when it ends before the last frame.
+///
+/// Classes are sent one after the other, so the newest operation we got of one class says nothing about
+/// the older operations of the classes which were still to come.
+#[derive(Debug)]
+pub struct BackfillWatermarks {
+	before: HashMap<Uuid, NTP64>,
+	class: Option<ModelClass>,
+	received: HashMap<Uuid, NTP64>,
+}
+
+impl BackfillWatermarks {
+	/// `before` is what we had of each instance when the backfill started
+	pub fn new(before: HashMap<Uuid, NTP64>) -> Self {
+		Self {
+			before,
+			class: None,
+			received: HashMap::new(),
+		}
+	}
+
+	pub fn received(&mut self, class: ModelClass, ops: &[CRDTOperation]) {
+		if self.class != Some(class) {
+			self.class = Some(class);
+			self.received.clear();
+		}
+
+		for op in ops {
+			let timestamp = self.received.entry(op.instance).or_default();
+			*timestamp = NTP64::max(*timestamp, op.timestamp);
+		}
+	}
+
+	/// Lower `timestamps` to what we're sure we have, so the operations we missed are asked for again.
+	///
+	/// Only once the last class is being sent is everything up to the newest operation of an instance delivered.
+	pub fn rewind(&self, timestamps: &mut HashMap<Uuid, NTP64>) {
+		let last_class = ModelClass::ALL.last().copied();
+
+		timestamps.retain(|instance, timestamp| {
+			let delivered = (self.class == last_class)
+				.then(|| self.received.get(instance))
+				.flatten();
+
+			match self.before.get(instance).max(delivered) {
+				Some(safe) => {
+					*timestamp = NTP64::min(*timestamp, *safe);
+					true
+				}
+				None => false,
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn models_are_in_a_single_class() {
+		assert_eq!(ModelClass::of(tag::NAME), ModelClass::Tags);
+		assert_eq!(ModelClass::of(tag_on_object::NAME), ModelClass::Objects);
+		assert_eq!(ModelClass::of(file_path::NAME), ModelClass::FilePaths);
+		assert_eq!(ModelClass::of("SomethingNew"), ModelClass::Other);
+
+		let classified = ModelClass::ALL
+			.into_iter()
+			.flat_map(|class| class.models().iter().copied())
+			.collect::<Vec<_>>();
+		assert_eq!(classified, CLASSIFIED);
+
+		for class in ModelClass::ALL {
+			assert_eq!(ModelClass::from_u8(class.as_u8()), class);
+		}
+		assert!(ModelClass::ALL.windows(2).all(|w| w[0] < w[1]));
+	}
+
+	#[test]
+	fn backfills_cut_off_between_classes_are_rewound() {
+		let instance = Uuid::new_v4();
+		let new_instance = Uuid::new_v4();
+		let op = |instance, timestamp| CRDTOperation {
+			instance,
+			timestamp: NTP64(timestamp),
+			record_id: rmpv::Value::Nil,
+			model: tag::NAME.to_string(),
+			data: sd_sync::CRDTOperationData::Create,
+		};
+
+		let mut watermarks = BackfillWatermarks::new(HashMap::from([(instance, NTP64(100))]));
+		// Tags created after file paths which never arrived
+		watermarks.received(
+			ModelClass::Tags,
+			&[op(instance, 5100), op(new_instance, 5200)],
+		);
+
+		let mut timestamps = HashMap::from([(instance, NTP64(5100)), (new_instance, NTP64(5200))]);
+		watermarks.rewind(&mut timestamps);
+		assert_eq!(timestamps, HashMap::from([(instance, NTP64(100))]));
+
+		// Once the last class is arriving, everything before it has been delivered
+		watermarks.received(ModelClass::Other, &[op(instance, 300)]);
+		let mut timestamps = HashMap::from([(instance, NTP64(5100))]);
+		watermarks.rewind(&mut timestamps);
+		assert_eq!(timestamps, HashMap::from([(instance, NTP64(300))]));
+	}
+}
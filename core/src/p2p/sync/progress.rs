@@ -0,0 +1,147 @@
+use crate::p2p::{OpId, P2PEvent, P2PEventsSender, SyncPhase};
+
+use sd_p2p::RemoteIdentity;
+
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// Progress is emitted at most this often, apart from phase changes which are always emitted.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Reports the progress of a sync session with a single peer to the frontend.
+///
+/// [`SyncPhase::Done`] is emitted when this is dropped so the final counts are always reported.
+#[derive(Debug)]
+pub struct SyncProgress {
+	events: P2PEventsSender,
+	library_id: Uuid,
+	identity: RemoteIdentity,
+	op_id: OpId,
+	operations_sent: u32,
+	operations_received: u32,
+	phase: SyncPhase,
+	last_emitted: Option<Instant>,
+}
+
+impl SyncProgress {
+	pub fn new(
+		events: P2PEventsSender,
+		library_id: Uuid,
+		identity: RemoteIdentity,
+		op_id: OpId,
+	) -> Self {
+		let mut this = Self {
+			events,
+			library_id,
+			identity,
+			op_id,
+			operations_sent: 0,
+			operations_received: 0,
+			phase: SyncPhase::Handshake,
+			last_emitted: None,
+		};
+		this.emit();
+		this
+	}
+
+	pub fn phase(&mut self, phase: SyncPhase) {
+		if self.phase != phase {
+			self.phase = phase;
+			self.emit();
+		}
+	}
+
+	pub fn sent(&mut self, operations: usize) {
+		self.operations_sent = self
+			.operations_sent
+			.saturating_add(u32::try_from(operations).unwrap_or(u32::MAX));
+		self.emit_throttled();
+	}
+
+	pub fn received(&mut self, operations: usize) {
+		self.operations_received = self
+			.operations_received
+			.saturating_add(u32::try_from(operations).unwrap_or(u32::MAX));
+		self.emit_throttled();
+	}
+
+	fn emit_throttled(&mut self) {
+		if self
+			.last_emitted
+			.map_or(true, |last| last.elapsed() >= PROGRESS_INTERVAL)
+		{
+			self.emit();
+		}
+	}
+
+	fn emit(&mut self) {
+		// This errors when no frontends are listening which is fine
+		self.events
+			.send(P2PEvent::SyncProgress {
+				library_id: self.library_id,
+				identity: self.identity,
+				op_id: self.op_id,
+				operations_sent: self.operations_sent,
+				operations_received: self.operations_received,
+				phase: self.phase,
+			})
+			.ok();
+		self.last_emitted = Some(Instant::now());
+	}
+}
+
+impl Drop for SyncProgress {
+	fn drop(&mut self) {
+		self.phase = SyncPhase::Done;
+		self.emit();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sd_p2p::Identity;
+	use tokio::sync::broadcast;
+
+	use super::*;
+
+	fn drain(rx: &mut broadcast::Receiver<P2PEvent>) -> Vec<(u32, u32, SyncPhase)> {
+		let mut events = vec![];
+		while let Ok(event) = rx.try_recv() {
+			let P2PEvent::SyncProgress {
+				operations_sent,
+				operations_received,
+				phase,
+				..
+			} = event
+			else {
+				unreachable!("unexpected event");
+			};
+			events.push((operations_sent, operations_received, phase));
+		}
+		events
+	}
+
+	#[test]
+	fn progress_is_throttled() {
+		let (tx, mut rx) = broadcast::channel(64);
+		let mut progress = SyncProgress::new(
+			tx.into(),
+			Uuid::new_v4(),
+			Identity::default().to_remote_identity(),
+			OpId::new(),
+		);
+		progress.phase(SyncPhase::Live);
+		for _ in 0..1000 {
+			progress.sent(10);
+			progress.received(1);
+		}
+		drop(progress);
+
+		let events = drain(&mut rx);
+		assert_eq!(events.first(), Some(&(0, 0, SyncPhase::Handshake)));
+		assert_eq!(events.last(), Some(&(10_000, 1000, SyncPhase::Done)));
+		// The updates happen far quicker than the interval
+		assert!(events.len() < 10, "{events:?}");
+	}
+}
@@ -1,10 +1,31 @@
-use sd_p2p_proto::decode;
+use crate::{p2p::OpId, sync::NTP64};
+
+use super::ModelClass;
+
+use sd_p2p_proto::{decode, encode};
+use sd_sync::CRDTOperation;
 use tokio::io::{AsyncRead, AsyncReadExt};
+use uuid::Uuid;
+
+/// The most bytes we put into a single [`OperationsFrame`].
+/// A single operation larger than this is still sent in a frame of its own.
+pub const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+/// A single operation larger than this is refused instead of allocating whatever the remote asks for.
+const MAX_OPERATION_BYTES: usize = 16 * 1024 * 1024;
+
+/// Every operation in a frame takes at least its `u32` length prefix, so a frame we sent never has more than this.
+pub const MAX_FRAME_OPS: usize = MAX_FRAME_BYTES / 4;
 
 // will probs have more variants in future
 #[derive(Debug, PartialEq, Eq)]
 pub enum SyncMessage {
 	NewOperations,
+	// Ask the responder for every operation it has since `timestamp`, used to backfill after being offline
+	RequestOperationsSince { timestamp: NTP64 },
+	// Everything after this is a [`SyncFrame`], so the libraries we share with the responder can take turns on one tunnel.
+	// The library in the header is ignored.
+	Multiplexed,
 }
 
 impl SyncMessage {
@@ -12,6 +33,10 @@ impl SyncMessage {
 	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
 		match stream.read_u8().await? {
 			b'N' => Ok(Self::NewOperations),
+			b'R' => Ok(Self::RequestOperationsSince {
+				timestamp: NTP64(stream.read_u64_le().await?),
+			}),
+			b'M' => Ok(Self::Multiplexed),
 			header => Err(decode::Error::IoError(std::io::Error::new(
 				std::io::ErrorKind::InvalidData,
 				format!("Invalid sync message header: {}", (header as char)),
@@ -22,6 +47,200 @@ impl SyncMessage {
 	pub fn to_bytes(&self) -> Vec<u8> {
 		match self {
 			Self::NewOperations => vec![b'N'],
+			Self::RequestOperationsSince { timestamp } => {
+				let mut buf = vec![b'R'];
+				buf.extend_from_slice(&timestamp.as_u64().to_le_bytes());
+				buf
+			}
+			Self::Multiplexed => vec![b'M'],
+		}
+	}
+}
+
+/// What the originator sends within a [`SyncMessage::Multiplexed`] session.
+/// The responder answers every [`SyncFrame::Library`] with a [`SyncResponse`] and then carries on as if it got the message on a tunnel of its own.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncFrame {
+	Library {
+		library_id: Uuid,
+		// Picked by the originator for each library's session, like it would be for a tunnel of its own
+		op_id: OpId,
+		message: SyncMessage,
+	},
+	// The originator has nothing left to sync, the tunnel can be closed
+	End,
+}
+
+impl SyncFrame {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
+		match stream.read_u8().await? {
+			b'F' => {
+				let library_id = decode::uuid(stream).await?;
+				let op_id = OpId::from_uuid(decode::uuid(stream).await?);
+				let message = match SyncMessage::from_stream(stream).await? {
+					SyncMessage::Multiplexed => {
+						return Err(invalid_data(
+							"Sync sessions can't be multiplexed within one another".to_string(),
+						))
+					}
+					message => message,
+				};
+
+				Ok(Self::Library {
+					library_id,
+					op_id,
+					message,
+				})
+			}
+			b'E' => Ok(Self::End),
+			header => Err(invalid_data(format!(
+				"Invalid sync frame header: {}",
+				(header as char)
+			))),
+		}
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		match self {
+			Self::Library {
+				library_id,
+				op_id,
+				message,
+			} => {
+				let mut buf = vec![b'F'];
+				encode::uuid(&mut buf, library_id);
+				encode::uuid(&mut buf, op_id.as_uuid());
+				buf.extend(message.to_bytes());
+				buf
+			}
+			Self::End => vec![b'E'],
+		}
+	}
+}
+
+/// A batch of operations of a single [`ModelClass`], prefixed by the class and how many there are.
+/// An empty frame marks the end of the stream.
+#[derive(Debug, PartialEq)]
+pub struct OperationsFrame {
+	pub class: ModelClass,
+	pub ops: Vec<CRDTOperation>,
+}
+
+impl OperationsFrame {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
+		let class = ModelClass::from_u8(stream.read_u8().await?);
+		let count = stream.read_u32_le().await? as usize;
+		if count > MAX_FRAME_OPS {
+			return Err(decode::Error::TooLong {
+				len: count,
+				max: MAX_FRAME_OPS,
+			});
+		}
+
+		let mut ops = Vec::new();
+		let mut frame_bytes = 0;
+		for _ in 0..count {
+			let len = stream.read_u32_le().await? as usize;
+			if len > MAX_OPERATION_BYTES {
+				return Err(decode::Error::TooLong {
+					len,
+					max: MAX_OPERATION_BYTES,
+				});
+			}
+
+			// Only a single operation can be larger than a frame, see [`Self::encode`]
+			frame_bytes += len + 4;
+			if !ops.is_empty() && frame_bytes > MAX_FRAME_BYTES {
+				return Err(decode::Error::TooLong {
+					len: frame_bytes,
+					max: MAX_FRAME_BYTES,
+				});
+			}
+
+			let mut buf = vec![0u8; len];
+			stream.read_exact(&mut buf).await?;
+			ops.push(rmp_serde::from_slice(&buf).map_err(|err| invalid_data(err.to_string()))?);
+		}
+
+		Ok(Self { class, ops })
+	}
+
+	/// Encode `ops`, which are all of `class`, into as many frames as is required to keep each one under `max_bytes`.
+	pub fn encode(
+		class: ModelClass,
+		ops: &[CRDTOperation],
+		max_bytes: usize,
+	) -> Result<Vec<Vec<u8>>, rmp_serde::encode::Error> {
+		let mut frames = vec![];
+		let mut frame = vec![];
+		let mut count = 0u32;
+
+		for op in ops {
+			let op = rmp_serde::to_vec_named(op)?;
+			// The frame's class and count, and the operation's `u32` length prefix
+			if count != 0 && frame.len() + op.len() + 9 > max_bytes {
+				frames.push(Self::finish(class, count, frame));
+				frame = vec![];
+				count = 0;
+			}
+
+			encode::buf(&mut frame, &op);
+			count += 1;
+		}
+
+		if count != 0 {
+			frames.push(Self::finish(class, count, frame));
+		}
+
+		Ok(frames)
+	}
+
+	/// The frame which tells the other side there are no more operations coming.
+	pub fn end() -> Vec<u8> {
+		Self::finish(ModelClass::Other, 0, vec![])
+	}
+
+	fn finish(class: ModelClass, count: u32, frame: Vec<u8>) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(frame.len() + 5);
+		buf.push(class.as_u8());
+		buf.extend_from_slice(&count.to_le_bytes());
+		buf.extend(frame);
+		buf
+	}
+}
+
+fn invalid_data(msg: String) -> decode::Error {
+	decode::Error::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+/// Sent by the responder after the [`SyncMessage`] so the originator knows whether to continue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncResponse {
+	Ok,
+	// The responder doesn't have the library, even if its metadata says otherwise
+	LibraryNotFound,
+	// The responder is already syncing the library, try again later
+	Busy,
+}
+
+impl SyncResponse {
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, decode::Error> {
+		match stream.read_u8().await? {
+			b'O' => Ok(Self::Ok),
+			b'L' => Ok(Self::LibraryNotFound),
+			b'B' => Ok(Self::Busy),
+			header => Err(decode::Error::IoError(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("Invalid sync response: {}", (header as char)),
+			))),
+		}
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		match self {
+			Self::Ok => vec![b'O'],
+			Self::LibraryNotFound => vec![b'L'],
+			Self::Busy => vec![b'B'],
 		}
 	}
 }
@@ -39,5 +258,135 @@ mod tests {
 			let result = SyncMessage::from_stream(&mut cursor).await.unwrap();
 			assert_eq!(original, result);
 		}
+
+		{
+			let original = SyncMessage::RequestOperationsSince {
+				timestamp: NTP64(42),
+			};
+
+			let mut cursor = std::io::Cursor::new(original.to_bytes());
+			let result = SyncMessage::from_stream(&mut cursor).await.unwrap();
+			assert_eq!(original, result);
+		}
+
+		{
+			let original = SyncMessage::Multiplexed;
+
+			let mut cursor = std::io::Cursor::new(original.to_bytes());
+			let result = SyncMessage::from_stream(&mut cursor).await.unwrap();
+			assert_eq!(original, result);
+		}
+
+		for original in [
+			SyncFrame::Library {
+				library_id: Uuid::new_v4(),
+				op_id: OpId::new(),
+				message: SyncMessage::NewOperations,
+			},
+			SyncFrame::Library {
+				library_id: Uuid::new_v4(),
+				op_id: OpId::new(),
+				message: SyncMessage::RequestOperationsSince {
+					timestamp: NTP64(42),
+				},
+			},
+			SyncFrame::End,
+		] {
+			let mut cursor = std::io::Cursor::new(original.to_bytes());
+			let result = SyncFrame::from_stream(&mut cursor).await.unwrap();
+			assert_eq!(original, result);
+		}
+
+		// A session within a session would never end
+		let mut cursor = std::io::Cursor::new(
+			SyncFrame::Library {
+				library_id: Uuid::new_v4(),
+				op_id: OpId::new(),
+				message: SyncMessage::Multiplexed,
+			}
+			.to_bytes(),
+		);
+		assert!(SyncFrame::from_stream(&mut cursor).await.is_err());
+
+		for original in [
+			SyncResponse::Ok,
+			SyncResponse::LibraryNotFound,
+			SyncResponse::Busy,
+		] {
+			let mut cursor = std::io::Cursor::new(original.to_bytes());
+			let result = SyncResponse::from_stream(&mut cursor).await.unwrap();
+			assert_eq!(original, result);
+		}
+	}
+
+	fn op(timestamp: u64) -> CRDTOperation {
+		CRDTOperation {
+			instance: uuid::Uuid::nil(),
+			timestamp: NTP64(timestamp),
+			record_id: rmpv::Value::Nil,
+			model: "name".to_string(),
+			data: sd_sync::CRDTOperationData::Create,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_operations_frames() {
+		let ops = (0..100).map(op).collect::<Vec<_>>();
+		let op_len = rmp_serde::to_vec_named(&ops[0]).unwrap().len() + 4;
+
+		// Small enough that the operations have to be split up
+		let max_bytes = op_len * 10 + 5;
+		let frames = OperationsFrame::encode(ModelClass::Tags, &ops, max_bytes).unwrap();
+		assert!(frames.len() >= 10);
+		assert!(frames.iter().all(|frame| frame.len() <= max_bytes));
+
+		let mut bytes = frames.concat();
+		bytes.extend(OperationsFrame::end());
+		let mut cursor = std::io::Cursor::new(bytes);
+
+		let mut result = vec![];
+		loop {
+			let OperationsFrame { class, ops } =
+				OperationsFrame::from_stream(&mut cursor).await.unwrap();
+			if ops.is_empty() {
+				break;
+			}
+			assert_eq!(class, ModelClass::Tags);
+			result.extend(ops);
+		}
+		assert_eq!(result, ops);
+	}
+
+	#[tokio::test]
+	async fn operations_frames_are_bounded() {
+		// More operations than a frame we send could hold, which we would otherwise keep reading
+		let mut frame = vec![ModelClass::Tags.as_u8()];
+		frame.extend_from_slice(&(MAX_FRAME_OPS as u32 + 1).to_le_bytes());
+		let err = OperationsFrame::from_stream(&mut std::io::Cursor::new(frame))
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			decode::Error::TooLong { len, max: MAX_FRAME_OPS } if len == MAX_FRAME_OPS + 1
+		));
+
+		// Operations which add up to more than a frame
+		let op = rmp_serde::to_vec_named(&op(0)).unwrap();
+		let count = MAX_FRAME_BYTES / op.len() + 1;
+		let mut frame = vec![ModelClass::Tags.as_u8()];
+		frame.extend_from_slice(&(count as u32).to_le_bytes());
+		for _ in 0..count {
+			encode::buf(&mut frame, &op);
+		}
+		let err = OperationsFrame::from_stream(&mut std::io::Cursor::new(frame))
+			.await
+			.unwrap_err();
+		assert!(matches!(
+			err,
+			decode::Error::TooLong {
+				max: MAX_FRAME_BYTES,
+				..
+			}
+		));
 	}
 }
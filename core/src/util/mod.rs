@@ -8,6 +8,7 @@ pub mod mpscrr;
 mod observable;
 mod unsafe_streamed_query;
 pub mod version_manager;
+mod write_atomic;
 
 pub use abort_on_drop::*;
 pub use batched_stream::*;
@@ -15,3 +16,4 @@ pub use infallible_request::*;
 pub use maybe_undefined::*;
 pub use observable::*;
 pub use unsafe_streamed_query::*;
+pub use write_atomic::*;
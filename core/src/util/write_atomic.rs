@@ -0,0 +1,22 @@
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+
+use tokio::{fs, io::AsyncWriteExt};
+
+/// Write `contents` to `path` through a file next to it which then replaces it, so a crash part
+/// way through leaves the previous contents instead of a truncated file.
+pub async fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+	let path = path.as_ref();
+	let mut tmp_path = path.as_os_str().to_owned();
+	tmp_path.push(".tmp");
+	let tmp_path = PathBuf::from(tmp_path);
+
+	let mut file = fs::File::create(&tmp_path).await?;
+	file.write_all(contents.as_ref()).await?;
+	file.sync_all().await?;
+	drop(file);
+
+	fs::rename(&tmp_path, path).await
+}
@@ -4,7 +4,7 @@ use sd_cache::Model;
 use std::{
 	fmt::Display,
 	hash::{Hash, Hasher},
-	path::PathBuf,
+	path::{Path, PathBuf},
 	sync::OnceLock,
 };
 
@@ -297,6 +297,31 @@ struct HDIUtilInfo {
 	images: Vec<ImageInfo>,
 }
 
+/// Find the volume `path` is on, which is the one with the most specific matching mount point.
+pub fn volume_for_path<'a>(volumes: &'a [Volume], path: &Path) -> Option<&'a Volume> {
+	volumes
+		.iter()
+		.filter_map(|volume| {
+			volume
+				.mount_points
+				.iter()
+				.filter(|mount_point| path.starts_with(mount_point))
+				.map(|mount_point| mount_point.components().count())
+				.max()
+				.map(|depth| (depth, volume))
+		})
+		.max_by_key(|(depth, _)| *depth)
+		.map(|(_, volume)| volume)
+}
+
+/// Get the free space available on the volume `path` is on, if we can determine it.
+pub async fn available_space_at(path: impl AsRef<Path>) -> Option<u64> {
+	let path = tokio::fs::canonicalize(path).await.ok()?;
+	let volumes = get_volumes().await;
+
+	volume_for_path(&volumes, &path).map(|volume| volume.available_capacity)
+}
+
 // Android does not work via sysinfo and JNI is a pain to maintain. Therefore, we use React-Native-FS to get the volume data of the device.
 // We leave the function though to be built for Android because otherwise, the build will fail.
 #[cfg(not(any(target_os = "linux", target_os = "ios")))]
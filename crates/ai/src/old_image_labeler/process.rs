@@ -1,6 +1,6 @@
 #![allow(non_camel_case_types)]
 
-use sd_file_path_helper::{file_path_for_media_processor, IsolatedFilePathData};
+use sd_file_path_helper::{file_path_for_media_processor, full_path_on_disk, IsolatedFilePathData};
 use sd_prisma::{
 	prisma::{file_path, label, label_on_object, object, PrismaClient},
 	prisma_sync,
@@ -107,7 +107,11 @@ pub(super) async fn spawned_processing(
 
 			match ImageFormat::from_extension(iso_file_path.extension()) {
 				Some(format) => {
-					let path = location_path.join(&iso_file_path);
+					let path = full_path_on_disk(
+						location_path,
+						&iso_file_path,
+						file_path.name_bytes.as_deref(),
+					);
 					Some((file_path, path, format))
 				}
 				None => {
@@ -0,0 +1,197 @@
+use sd_utils::error::FileIOError;
+
+use std::{fmt, path::Path};
+
+use crate::FilePathError;
+
+/// The filesystems the indexer knows about, as some of them can't be compared with the database like the
+/// others, eg. FAT doesn't have stable inodes and only keeps modification dates to the 2 seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FileSystem {
+	Apfs,
+	Hfs,
+	Ext,
+	Btrfs,
+	Xfs,
+	Zfs,
+	Ntfs,
+	Refs,
+	ExFat,
+	Fat,
+	Tmpfs,
+	Nfs,
+	Smb,
+	/// Anything else, with the name the OS gave it or its magic number
+	Other(String),
+}
+
+impl FileSystem {
+	pub fn as_str(&self) -> &str {
+		match self {
+			Self::Apfs => "apfs",
+			Self::Hfs => "hfs",
+			Self::Ext => "ext",
+			Self::Btrfs => "btrfs",
+			Self::Xfs => "xfs",
+			Self::Zfs => "zfs",
+			Self::Ntfs => "ntfs",
+			Self::Refs => "refs",
+			Self::ExFat => "exfat",
+			Self::Fat => "fat",
+			Self::Tmpfs => "tmpfs",
+			Self::Nfs => "nfs",
+			Self::Smb => "smb",
+			Self::Other(name) => name,
+		}
+	}
+
+	/// From the names macOS and Windows give filesystems, ignoring case
+	pub fn from_name(name: &str) -> Self {
+		match name.to_lowercase().as_str() {
+			"apfs" => Self::Apfs,
+			"hfs" => Self::Hfs,
+			"ext2" | "ext3" | "ext4" => Self::Ext,
+			"btrfs" => Self::Btrfs,
+			"xfs" => Self::Xfs,
+			"zfs" => Self::Zfs,
+			"ntfs" => Self::Ntfs,
+			"refs" => Self::Refs,
+			"exfat" => Self::ExFat,
+			"msdos" | "fat" | "fat12" | "fat16" | "fat32" | "vfat" => Self::Fat,
+			"tmpfs" => Self::Tmpfs,
+			"nfs" => Self::Nfs,
+			"smbfs" | "cifs" | "smb2" => Self::Smb,
+			other => Self::Other(other.to_string()),
+		}
+	}
+
+	/// From the `f_type` magic number `statfs` returns on Linux
+	pub fn from_magic(magic: u32) -> Self {
+		match magic {
+			0xEF53 => Self::Ext,
+			0x9123_683E => Self::Btrfs,
+			0x5846_5342 => Self::Xfs,
+			0x2FC1_2FC1 => Self::Zfs,
+			0x5346_544E | 0x7366_746E => Self::Ntfs,
+			0x2011_BAB0 => Self::ExFat,
+			0x4D44 => Self::Fat,
+			0x0102_1994 => Self::Tmpfs,
+			0x6969 => Self::Nfs,
+			0xFF53_4D42 | 0xFE53_4D42 | 0x517B => Self::Smb,
+			0x4244 => Self::Hfs,
+			other => Self::Other(format!("{other:#x}")),
+		}
+	}
+}
+
+impl fmt::Display for FileSystem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+/// The filesystem `path` is on, from `statfs` on Unix and `GetVolumeInformationW` on Windows
+pub fn get_filesystem_from_path(path: impl AsRef<Path>) -> Result<FileSystem, FilePathError> {
+	let path = path.as_ref();
+
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	{
+		let stat = statfs(path)?;
+
+		// `f_type` is signed on some targets, the magic numbers are all 32 bits
+		#[allow(clippy::unnecessary_cast)]
+		Ok(FileSystem::from_magic(stat.f_type as u32))
+	}
+
+	#[cfg(all(
+		target_family = "unix",
+		not(any(target_os = "linux", target_os = "android"))
+	))]
+	{
+		use std::ffi::CStr;
+
+		let stat = statfs(path)?;
+		// SAFETY: The OS fills `f_fstypename` with a nul terminated string
+		let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+
+		Ok(FileSystem::from_name(&name.to_string_lossy()))
+	}
+
+	#[cfg(target_family = "windows")]
+	{
+		use std::{io, iter::once, os::windows::ffi::OsStrExt};
+
+		use windows::{
+			core::PCWSTR,
+			Win32::Storage::FileSystem::{GetVolumeInformationW, GetVolumePathNameW},
+		};
+
+		const MAX_PATH: usize = 261;
+
+		let wide_path = path
+			.as_os_str()
+			.encode_wide()
+			.chain(once(0))
+			.collect::<Vec<_>>();
+		let mut volume_path = [0u16; MAX_PATH];
+		let mut name = [0u16; MAX_PATH];
+
+		// SAFETY: Both paths are nul terminated and the buffers are passed with their lengths
+		unsafe {
+			GetVolumePathNameW(PCWSTR(wide_path.as_ptr()), &mut volume_path)
+				.and_then(|()| {
+					GetVolumeInformationW(
+						PCWSTR(volume_path.as_ptr()),
+						None,
+						None,
+						None,
+						None,
+						Some(&mut name),
+					)
+				})
+				.map_err(|e| FileIOError::from((path, io::Error::from(e))))?;
+		}
+
+		let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+		Ok(FileSystem::from_name(&String::from_utf16_lossy(
+			&name[..len],
+		)))
+	}
+}
+
+#[cfg(target_family = "unix")]
+fn statfs(path: &Path) -> Result<libc::statfs, FileIOError> {
+	use std::{ffi::CString, io, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+	let c_path = CString::new(path.as_os_str().as_bytes())
+		.map_err(|e| FileIOError::from((path, io::Error::new(io::ErrorKind::InvalidInput, e))))?;
+	let mut stat = MaybeUninit::<libc::statfs>::uninit();
+
+	// SAFETY: `c_path` is nul terminated and `stat` is only read when `statfs` filled it
+	if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+		return Err(FileIOError::from((path, io::Error::last_os_error())));
+	}
+
+	// SAFETY: Just checked `statfs` succeeded
+	Ok(unsafe { stat.assume_init() })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_and_magic_numbers() {
+		assert_eq!(FileSystem::from_name("exFAT"), FileSystem::ExFat);
+		assert_eq!(FileSystem::from_name("FAT32"), FileSystem::Fat);
+		assert_eq!(FileSystem::from_name("msdos"), FileSystem::Fat);
+		assert_eq!(FileSystem::from_magic(0x4D44), FileSystem::Fat);
+		assert_eq!(FileSystem::from_magic(0x2011_BAB0), FileSystem::ExFat);
+		assert_eq!(FileSystem::from_magic(0x1234).to_string(), "0x1234");
+	}
+
+	#[test]
+	fn the_filesystem_of_a_directory_is_found() {
+		assert!(get_filesystem_from_path(std::env::temp_dir()).is_ok());
+	}
+}
@@ -1,5 +1,4 @@
 use sd_prisma::prisma::{file_path, location};
-use sd_utils::error::NonUtf8PathError;
 
 use std::{
 	borrow::Cow,
@@ -14,8 +13,8 @@ use serde::{Deserialize, Serialize};
 use super::{
 	file_path_for_file_identifier, file_path_for_media_processor, file_path_for_object_validator,
 	file_path_to_full_path, file_path_to_handle_custom_uri, file_path_to_handle_p2p_serve_file,
-	file_path_to_isolate, file_path_to_isolate_with_id, file_path_walker, file_path_with_object,
-	FilePathError,
+	file_path_to_isolate, file_path_to_isolate_with_id, file_path_to_spacedrop, file_path_walker,
+	file_path_with_object, FilePathError,
 };
 
 static FORBIDDEN_FILE_NAMES: OnceLock<RegexSet> = OnceLock::new();
@@ -59,7 +58,7 @@ impl IsolatedFilePathData<'static> {
 			.then(|| {
 				full_path
 					.extension()
-					.and_then(|ext| ext.to_str().map(str::to_string))
+					.map(|ext| ext.to_string_lossy().to_string())
 					.unwrap_or_default()
 			})
 			.unwrap_or_default();
@@ -140,6 +139,74 @@ impl<'a> IsolatedFilePathData<'a> {
 		}
 	}
 
+	/// How many directories deep this path is within its location, the root itself has depth 0.
+	pub fn depth(&self) -> usize {
+		if self.materialized_path == "/" && self.name.is_empty() {
+			0
+		} else {
+			self.materialized_path.matches('/').count()
+		}
+	}
+
+	/// The directories containing this path, starting from its parent and ending at the location root.
+	pub fn ancestors(&self) -> impl Iterator<Item = IsolatedFilePathData<'_>> + '_ {
+		let materialized_path = self.materialized_path.as_ref();
+
+		// Every slash after the leading one closes a directory of the chain
+		let directories = materialized_path
+			.match_indices('/')
+			.skip(1)
+			.map(|(trailing_slash_idx, _)| trailing_slash_idx)
+			.collect::<Vec<_>>();
+
+		let root = (self.depth() > 0).then(|| IsolatedFilePathData {
+			location_id: self.location_id,
+			materialized_path: Cow::Borrowed("/"),
+			is_dir: true,
+			name: Cow::Borrowed(""),
+			extension: Cow::Borrowed(""),
+			relative_path: Cow::Borrowed(""),
+		});
+
+		directories
+			.into_iter()
+			.rev()
+			.map(move |trailing_slash_idx| {
+				let last_slash_idx = materialized_path[..trailing_slash_idx]
+					.rfind('/')
+					.expect("materialized paths always start with a slash");
+
+				IsolatedFilePathData {
+					location_id: self.location_id,
+					materialized_path: Cow::Borrowed(&materialized_path[..=last_slash_idx]),
+					is_dir: true,
+					name: Cow::Borrowed(&materialized_path[last_slash_idx + 1..trailing_slash_idx]),
+					extension: Cow::Borrowed(""),
+					relative_path: Cow::Borrowed(&materialized_path[1..trailing_slash_idx]),
+				}
+			})
+			.chain(root)
+	}
+
+	/// Whether `other` is somewhere within this directory, a path isn't an ancestor of itself.
+	pub fn is_ancestor_of(&self, other: &IsolatedFilePathData<'_>) -> bool {
+		if !self.is_dir || self.location_id != other.location_id {
+			return false;
+		}
+
+		if self.depth() == 0 {
+			return other.depth() > 0;
+		}
+
+		let full_name = self.full_name();
+
+		other
+			.materialized_path
+			.strip_prefix(self.materialized_path.as_ref())
+			.and_then(|rest| rest.strip_prefix(full_name.as_str()))
+			.is_some_and(|rest| rest.starts_with('/'))
+	}
+
 	pub fn from_relative_str(
 		location_id: location::id::Type,
 		relative_file_path_str: &'a str,
@@ -167,6 +234,37 @@ impl<'a> IsolatedFilePathData<'a> {
 		}
 	}
 
+	/// The same path with its extension lowercased, which is how it's matched on filesystems telling
+	/// no difference between cases. It's only meant for matching, there may be no such path on disk.
+	pub fn with_normalized_extension(&self) -> IsolatedFilePathData<'static> {
+		self.with_extension(self.extension.to_lowercase())
+	}
+
+	/// Matches the `file_path`s at this path whatever the case of their extension, as SQLite only compares
+	/// them exactly. Ones with other extensions are matched too, telling them apart is left to the caller
+	/// with [`Self::with_normalized_extension`].
+	pub fn any_extension_case_where_param(&self) -> file_path::WhereParam {
+		file_path::WhereParam::And(vec![
+			file_path::location_id::equals(Some(self.location_id)),
+			file_path::materialized_path::equals(Some(self.materialized_path.to_string())),
+			file_path::name::equals(Some(self.name.to_string())),
+		])
+	}
+
+	fn with_extension(&self, extension: String) -> IsolatedFilePathData<'static> {
+		let without_extension =
+			&self.relative_path[..self.relative_path.len() - self.extension.len()];
+
+		IsolatedFilePathData {
+			location_id: self.location_id,
+			materialized_path: Cow::Owned(self.materialized_path.to_string()),
+			is_dir: self.is_dir,
+			name: Cow::Owned(self.name.to_string()),
+			relative_path: Cow::Owned(format!("{without_extension}{extension}")),
+			extension: Cow::Owned(extension),
+		}
+	}
+
 	pub fn materialized_path_for_children(&self) -> Option<String> {
 		if self.materialized_path == "/" && self.name.is_empty() && self.is_dir {
 			// We're at the root file_path
@@ -270,7 +368,7 @@ impl<'a> IsolatedFilePathData<'a> {
 		}
 	}
 
-	fn prepare_name(path: &Path, is_dir: bool) -> &str {
+	fn prepare_name(path: &Path, is_dir: bool) -> Cow<'_, str> {
 		// Not using `impl AsRef<Path>` here because it's an private method
 		if is_dir {
 			path.file_name()
@@ -278,8 +376,7 @@ impl<'a> IsolatedFilePathData<'a> {
 			path.file_stem()
 		}
 		.unwrap_or_default()
-		.to_str()
-		.unwrap_or_default()
+		.to_string_lossy()
 	}
 
 	pub fn from_db_data(
@@ -476,7 +573,8 @@ impl_from_db_without_location_id!(
 	file_path_for_media_processor,
 	file_path_for_object_validator,
 	file_path_to_handle_custom_uri,
-	file_path_to_handle_p2p_serve_file
+	file_path_to_handle_p2p_serve_file,
+	file_path_to_spacedrop
 );
 
 fn extract_relative_path(
@@ -491,12 +589,8 @@ fn extract_relative_path(
 			location_id,
 			path: path.into(),
 		})
-		.and_then(|relative| {
-			relative
-				.to_str()
-				.map(|relative_str| relative_str.replace('\\', "/"))
-				.ok_or_else(|| NonUtf8PathError(path.into()).into())
-		})
+		// Names which aren't valid UTF-8 are stored lossily, the same way everywhere, so they match across scans
+		.map(|relative| relative.to_string_lossy().replace('\\', "/"))
 }
 
 /// This function separates a file path from a location path, and normalizes replacing '\' with '/'
@@ -508,26 +602,21 @@ pub fn extract_normalized_materialized_path_str(
 ) -> Result<String, FilePathError> {
 	let path = path.as_ref();
 
-	path.strip_prefix(location_path)
+	let materialized_path = path
+		.strip_prefix(location_path)
 		.map_err(|_| FilePathError::UnableToExtractMaterializedPath {
 			location_id,
 			path: path.into(),
 		})?
 		.parent()
-		.map(|materialized_path| {
-			materialized_path
-				.to_str()
-				.map(|materialized_path_str| {
-					if !materialized_path_str.is_empty() {
-						format!("/{}/", materialized_path_str.replace('\\', "/"))
-					} else {
-						"/".to_string()
-					}
-				})
-				.ok_or_else(|| NonUtf8PathError(path.into()))
-		})
-		.unwrap_or_else(|| Ok("/".to_string()))
-		.map_err(Into::into)
+		.map(Path::to_string_lossy)
+		.unwrap_or_default();
+
+	Ok(if !materialized_path.is_empty() {
+		format!("/{}/", materialized_path.replace('\\', "/"))
+	} else {
+		"/".to_string()
+	})
 }
 
 fn assemble_relative_path(
@@ -561,6 +650,45 @@ pub fn join_location_relative_path(
 		})
 }
 
+/// Where a file_path is on disk, with its original name from `name_bytes` when it isn't valid UTF-8
+/// and `iso_file_path` only has it lossily
+pub fn full_path_on_disk(
+	location_path: impl AsRef<Path>,
+	iso_file_path: &IsolatedFilePathData<'_>,
+	name_bytes: Option<&[u8]>,
+) -> PathBuf {
+	let mut full_path = join_location_relative_path(location_path, iso_file_path);
+
+	#[cfg(unix)]
+	if let Some(name_bytes) = name_bytes {
+		use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+		full_path.set_file_name(OsStr::from_bytes(name_bytes));
+	}
+	#[cfg(not(unix))]
+	let _ = name_bytes;
+
+	full_path
+}
+
+/// The bytes of `path`'s file name when it isn't valid UTF-8, as isolated file paths only keep it lossily.
+/// Names which are the same once made lossy map to the same `file_path`, the first one found wins.
+#[cfg(unix)]
+pub fn non_utf8_name_bytes(path: impl AsRef<Path>) -> Option<Vec<u8>> {
+	use std::os::unix::ffi::OsStrExt;
+
+	path.as_ref()
+		.file_name()
+		.filter(|name| name.to_str().is_none())
+		.map(|name| name.as_bytes().to_vec())
+}
+
+/// Other platforms don't have names which aren't Unicode to begin with
+#[cfg(not(unix))]
+pub fn non_utf8_name_bytes(_path: impl AsRef<Path>) -> Option<Vec<u8>> {
+	None
+}
+
 pub fn push_location_relative_path(
 	mut location_path: PathBuf,
 	relative_path: impl AsRef<Path>,
@@ -663,6 +791,39 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn extensions_are_normalized_for_matching_only() {
+		let iso_file_path = IsolatedFilePathData::new(
+			1,
+			"/spacedrive/location",
+			"/spacedrive/location/dir/PHOTO.JpG",
+			false,
+		)
+		.unwrap();
+
+		let normalized = iso_file_path.with_normalized_extension();
+		assert_eq!(
+			normalized,
+			expected("/dir/", false, "PHOTO", "jpg", "dir/PHOTO.jpg")
+		);
+		// The name is kept as it is, and so is the path itself
+		assert_eq!(iso_file_path.extension(), "JpG");
+
+		// Whatever case another device stored it with
+		assert_eq!(
+			iso_file_path
+				.with_extension("jPg".to_string())
+				.with_normalized_extension(),
+			normalized
+		);
+		assert_ne!(
+			iso_file_path
+				.with_extension("png".to_string())
+				.with_normalized_extension(),
+			normalized
+		);
+	}
+
 	#[test]
 	fn parent_method() {
 		let tester = |full_path, is_dir, expected, msg| {
@@ -723,6 +884,80 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn depth_and_ancestors() {
+		let root =
+			IsolatedFilePathData::new(1, "/spacedrive/location", "/spacedrive/location", true)
+				.unwrap();
+		assert_eq!(root.depth(), 0);
+		assert_eq!(root.ancestors().count(), 0);
+
+		let file = IsolatedFilePathData::new(
+			1,
+			"/spacedrive/location",
+			"/spacedrive/location/dir/dir2/file.txt",
+			false,
+		)
+		.unwrap();
+		assert_eq!(file.depth(), 3);
+		assert_eq!(
+			file.ancestors().collect::<Vec<_>>(),
+			vec![
+				expected("/dir/", true, "dir2", "", "dir/dir2"),
+				expected("/", true, "dir", "", "dir"),
+				expected("/", true, "", "", ""),
+			]
+		);
+	}
+
+	#[test]
+	fn is_ancestor_of_matches_ancestors() {
+		let relative_paths = [
+			"",
+			"file.txt",
+			"dir",
+			"dir2",
+			"dir.d",
+			"dir/file.txt",
+			"dir/dir",
+			"dir/dir/file",
+			"dir/dir2/dir3",
+			"dir/dir2/dir3/file.tar.gz",
+			"dir2/dir",
+			"dir.d/file",
+			"dir.d/dir/.hidden",
+		];
+
+		let paths = relative_paths
+			.iter()
+			.flat_map(|relative_path| {
+				let full_path = if relative_path.is_empty() {
+					PathBuf::from("/spacedrive/location")
+				} else {
+					Path::new("/spacedrive/location").join(relative_path)
+				};
+				[true, false].map(|is_dir| {
+					IsolatedFilePathData::new(1, "/spacedrive/location", &full_path, is_dir)
+						.unwrap()
+				})
+			})
+			// The root is always a directory
+			.filter(|path| !(path.relative_path.is_empty() && !path.is_dir))
+			.collect::<Vec<_>>();
+
+		for a in &paths {
+			for b in &paths {
+				assert_eq!(
+					a.is_ancestor_of(b),
+					b.ancestors().any(|ancestor| &ancestor == a),
+					"{a:?} and {b:?}"
+				);
+			}
+
+			assert_eq!(a.ancestors().count(), a.depth());
+		}
+	}
+
 	#[test]
 	fn extract_normalized_materialized_path() {
 		let tester = |path, expected, msg| {
@@ -763,4 +998,39 @@ mod tests {
 			"a file inside a third level directory",
 		);
 	}
+
+	#[cfg(unix)]
+	#[test]
+	fn full_path_on_disk_uses_the_original_name_bytes() {
+		use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+		let location_path = Path::new("/spacedrive/location");
+		let original = location_path
+			.join("dir")
+			.join(OsStr::from_bytes(b"caf\xe9.txt"));
+
+		let name_bytes = non_utf8_name_bytes(&original);
+		assert_eq!(name_bytes.as_deref(), Some(&b"caf\xe9.txt"[..]));
+
+		let iso_file_path = IsolatedFilePathData::new(1, location_path, &original, false).unwrap();
+		assert_ne!(
+			join_location_relative_path(location_path, &iso_file_path),
+			original
+		);
+		assert_eq!(
+			full_path_on_disk(location_path, &iso_file_path, name_bytes.as_deref()),
+			original
+		);
+
+		let valid = location_path.join("dir/cafe.txt");
+		assert_eq!(non_utf8_name_bytes(&valid), None);
+		assert_eq!(
+			full_path_on_disk(
+				location_path,
+				&IsolatedFilePathData::new(1, location_path, &valid, false).unwrap(),
+				None
+			),
+			valid
+		);
+	}
 }
@@ -14,12 +14,16 @@ use thiserror::Error;
 use tokio::{fs, io};
 use tracing::error;
 
+pub mod filesystem;
 pub mod isolated_file_path_data;
+pub mod placeholder;
 
+pub use filesystem::{get_filesystem_from_path, FileSystem};
 pub use isolated_file_path_data::{
-	join_location_relative_path, push_location_relative_path, IsolatedFilePathData,
-	IsolatedFilePathDataParts,
+	full_path_on_disk, join_location_relative_path, non_utf8_name_bytes,
+	push_location_relative_path, IsolatedFilePathData, IsolatedFilePathDataParts,
 };
+pub use placeholder::path_is_placeholder;
 
 // File Path selectables!
 file_path::select!(file_path_pub_and_cas_ids { id pub_id cas_id });
@@ -35,6 +39,7 @@ file_path::select!(file_path_for_file_identifier {
 	is_dir
 	name
 	extension
+	name_bytes
 	object_id
 });
 file_path::select!(file_path_for_object_validator {
@@ -43,6 +48,7 @@ file_path::select!(file_path_for_object_validator {
 	is_dir
 	name
 	extension
+	name_bytes
 	integrity_checksum
 });
 file_path::select!(file_path_for_media_processor {
@@ -51,6 +57,7 @@ file_path::select!(file_path_for_media_processor {
 	is_dir
 	name
 	extension
+	name_bytes
 	cas_id
 	object_id
 });
@@ -81,6 +88,8 @@ file_path::select!(file_path_walker {
 	inode
 	size_in_bytes_bytes
 	hidden
+	is_placeholder
+	is_location_boundary
 });
 file_path::select!(file_path_to_handle_custom_uri {
 	pub_id
@@ -88,6 +97,7 @@ file_path::select!(file_path_to_handle_custom_uri {
 	is_dir
 	name
 	extension
+	name_bytes
 	location: select {
 		id
 		path
@@ -101,9 +111,11 @@ file_path::select!(file_path_to_handle_p2p_serve_file {
 	materialized_path
 	name
 	extension
+	name_bytes
 	is_dir // For isolated file path
 	location: select {
 		id
+		pub_id
 		path
 	}
 });
@@ -113,12 +125,28 @@ file_path::select!(file_path_to_full_path {
 	is_dir
 	name
 	extension
+	name_bytes
 	location: select {
 		id
 		path
 	}
 });
 
+file_path::select!(file_path_to_spacedrop {
+	object_id
+	materialized_path
+	is_dir // For isolated file path
+	name
+	extension
+	name_bytes
+	hidden
+	location: select {
+		id
+		pub_id
+		path
+	}
+});
+
 // File Path includes!
 file_path::include!(file_path_with_object { object });
 
@@ -129,6 +157,9 @@ pub struct FilePathMetadata {
 	pub created_at: DateTime<Utc>,
 	pub modified_at: DateTime<Utc>,
 	pub hidden: bool,
+	/// The filesystem doesn't keep when files were created, so `created_at` is only when we read it
+	#[serde(default)]
+	pub created_at_is_fallback: bool,
 }
 
 pub fn path_is_hidden(path: impl AsRef<Path>, metadata: &Metadata) -> bool {
@@ -198,6 +229,7 @@ impl FilePathMetadata {
 			size_in_bytes: metadata.len(),
 			created_at: metadata.created_or_now().into(),
 			modified_at: metadata.modified_or_now().into(),
+			created_at_is_fallback: metadata.created().is_err(),
 		})
 	}
 }
@@ -459,6 +491,32 @@ pub async fn get_inode_from_path(path: impl AsRef<Path>) -> Result<u64, FilePath
 	}
 }
 
+/// The device id of the filesystem `path` is on, or the volume serial number on Windows,
+/// telling apart different volumes mounted at the same path
+pub async fn get_device_from_path(path: impl AsRef<Path>) -> Result<u64, FilePathError> {
+	#[cfg(target_family = "unix")]
+	{
+		use std::os::unix::fs::MetadataExt;
+
+		let metadata = fs::metadata(path.as_ref())
+			.await
+			.map_err(|e| FileIOError::from((path, e)))?;
+
+		Ok(metadata.dev())
+	}
+
+	#[cfg(target_family = "windows")]
+	{
+		use winapi_util::{file::information, Handle};
+
+		let info = Handle::from_path_any(path.as_ref())
+			.and_then(|ref handle| information(handle))
+			.map_err(|e| FileIOError::from((path, e)))?;
+
+		Ok(info.volume_serial_number())
+	}
+}
+
 pub trait MetadataExt {
 	fn created_or_now(&self) -> SystemTime;
 
@@ -0,0 +1,164 @@
+use std::{fs::Metadata, path::Path};
+
+/// The folders cloud sync clients keep their files in, as they're named by default
+#[cfg(not(target_family = "windows"))]
+const CLOUD_SYNC_ROOTS: &[&str] = &[
+	"Dropbox",
+	"OneDrive",
+	"Google Drive",
+	"iCloud Drive",
+	"Mobile Documents",
+	"CloudStorage",
+	"pCloud Drive",
+];
+
+/// Whether the file at `path` is only a placeholder for a file a cloud sync client keeps online, eg. Dropbox or
+/// OneDrive's "online-only" files. Reading it either fails or has the client download it first.
+///
+/// On Windows the client marks them with file attributes, and on macOS File Provider clients mark them as dataless.
+/// Elsewhere we can only guess, so empty files below a cloud sync client's folder are taken as placeholders, along
+/// with iCloud's `.icloud` files. Truly empty files there are taken as placeholders too, which doesn't lose anything
+/// as there's nothing to identify. Sparse files aren't, plenty of programs write those.
+pub fn path_is_placeholder(path: impl AsRef<Path>, metadata: &Metadata) -> bool {
+	if metadata.is_dir() {
+		return false;
+	}
+
+	#[cfg(target_family = "windows")]
+	{
+		use std::os::windows::fs::MetadataExt;
+
+		const FILE_ATTRIBUTE_OFFLINE: u32 = 0x1000;
+		const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x0040_0000;
+
+		let _ = path; // just to avoid warnings on Windows
+
+		metadata.file_attributes() & (FILE_ATTRIBUTE_OFFLINE | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS)
+			!= 0
+	}
+
+	#[cfg(not(target_family = "windows"))]
+	{
+		let path = path.as_ref();
+
+		is_icloud_placeholder(path)
+			|| is_dataless(metadata)
+			|| (metadata.len() == 0 && is_below_cloud_sync_root(path))
+	}
+}
+
+/// iCloud replaces the files it evicted with a hidden `.<name>.icloud` file
+#[cfg(not(target_family = "windows"))]
+fn is_icloud_placeholder(path: &Path) -> bool {
+	path.file_name()
+		.and_then(|name| name.to_str())
+		.is_some_and(|name| name.starts_with('.') && name.ends_with(".icloud"))
+}
+
+/// File Provider clients, like the ones in `~/Library/CloudStorage`, flag the files they evicted
+#[cfg(not(target_family = "windows"))]
+fn is_dataless(metadata: &Metadata) -> bool {
+	#[cfg(target_os = "macos")]
+	{
+		use std::os::macos::fs::MetadataExt;
+
+		const SF_DATALESS: u32 = 0x4000_0000;
+
+		metadata.st_flags() & SF_DATALESS != 0
+	}
+
+	#[cfg(not(target_os = "macos"))]
+	{
+		let _ = metadata; // just to avoid warnings elsewhere
+		false
+	}
+}
+
+#[cfg(not(target_family = "windows"))]
+fn is_below_cloud_sync_root(path: &Path) -> bool {
+	path.ancestors()
+		.skip(1)
+		.filter_map(|ancestor| ancestor.file_name()?.to_str())
+		.any(|name| {
+			CLOUD_SYNC_ROOTS.iter().any(|root| {
+				// Clients add the account to the name, eg. "Dropbox (Personal)" or "OneDrive - Contoso"
+				name == *root || name.starts_with(&format!("{root} "))
+			})
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::fs;
+
+	#[cfg(not(target_family = "windows"))]
+	#[test]
+	fn empty_files_below_a_cloud_sync_folder_are_placeholders() {
+		let dir = tempfile::tempdir().unwrap();
+		let cloud = dir.path().join("Dropbox (Personal)").join("Photos");
+		let local = dir.path().join("Documents");
+		fs::create_dir_all(&cloud).unwrap();
+		fs::create_dir_all(&local).unwrap();
+
+		let online_only = cloud.join("beach.jpg");
+		fs::write(&online_only, b"").unwrap();
+		let downloaded = cloud.join("notes.txt");
+		fs::write(&downloaded, b"some notes").unwrap();
+		let empty = local.join("empty.txt");
+		fs::write(&empty, b"").unwrap();
+		let evicted = local.join(".report.pdf.icloud");
+		fs::write(&evicted, b"bplist00").unwrap();
+		let sparse = cloud.join("disk.img");
+		fs::File::create(&sparse)
+			.unwrap()
+			.set_len(1024 * 1024)
+			.unwrap();
+
+		let is_placeholder = |path: &Path| path_is_placeholder(path, &fs::metadata(path).unwrap());
+		assert!(is_placeholder(&online_only));
+		assert!(!is_placeholder(&downloaded));
+		assert!(!is_placeholder(&empty));
+		assert!(is_placeholder(&evicted));
+		// Without the client marking it, a file with nothing stored on disk is only sparse
+		assert!(!is_placeholder(&sparse));
+		// Only files are placeholders, even an empty folder is just that
+		assert!(!is_placeholder(&cloud));
+
+		// Not every folder starting like a client's is one
+		let lookalike = dir.path().join("Dropboxes");
+		fs::create_dir_all(&lookalike).unwrap();
+		fs::write(lookalike.join("empty.txt"), b"").unwrap();
+		assert!(!is_placeholder(&lookalike.join("empty.txt")));
+	}
+
+	#[cfg(target_family = "windows")]
+	#[test]
+	fn files_marked_offline_are_placeholders() {
+		use std::os::windows::fs::OpenOptionsExt;
+
+		const FILE_ATTRIBUTE_OFFLINE: u32 = 0x1000;
+
+		let dir = tempfile::tempdir().unwrap();
+		let online_only = dir.path().join("beach.jpg");
+		fs::OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.attributes(FILE_ATTRIBUTE_OFFLINE)
+			.open(&online_only)
+			.unwrap();
+		let downloaded = dir.path().join("notes.txt");
+		fs::write(&downloaded, b"").unwrap();
+
+		assert!(path_is_placeholder(
+			&online_only,
+			&fs::metadata(&online_only).unwrap()
+		));
+		// The attributes are what tells, not the size
+		assert!(!path_is_placeholder(
+			&downloaded,
+			&fs::metadata(&downloaded).unwrap()
+		));
+	}
+}
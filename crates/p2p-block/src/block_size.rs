@@ -2,6 +2,10 @@ use std::io;
 
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+/// The receiver allocates a buffer of the block size the sender asks for, so it's capped well
+/// above what [`BlockSize::from_size`] picks
+pub const MAX_BLOCK_SIZE: u32 = 4 * 1024 * 1024;
+
 /// TODO
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BlockSize(u32); // Max block size is gonna be 3.9GB which is stupidly overkill
@@ -10,7 +14,17 @@ impl BlockSize {
 	// TODO: Validating `BlockSize` are multiple of 2, i think. Idk why but BEP does it.
 
 	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Self> {
-		stream.read_u32_le().await.map(Self)
+		match stream.read_u32_le().await? {
+			0 => Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"block size can't be zero",
+			)),
+			size if size > MAX_BLOCK_SIZE => Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("block size of {size} bytes is over the maximum of {MAX_BLOCK_SIZE}"),
+			)),
+			size => Ok(Self(size)),
+		}
 	}
 
 	#[must_use]
@@ -51,5 +65,11 @@ mod tests {
 			.await
 			.unwrap();
 		assert_eq!(req, req2);
+
+		for size in [0, MAX_BLOCK_SIZE + 1, u32::MAX] {
+			assert!(BlockSize::from_stream(&mut Cursor::new(size.to_le_bytes()))
+				.await
+				.is_err());
+		}
 	}
 }
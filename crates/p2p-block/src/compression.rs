@@ -0,0 +1,121 @@
+use std::io::{self, ErrorKind};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How much of the first file the sender compresses to tell whether the rest is worth compressing
+pub const COMPRESSION_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Fast enough to keep up with a LAN while still shrinking text a lot
+const ZSTD_LEVEL: i32 = 1;
+
+/// Extensions of formats which are compressed already, compressing them again only costs CPU time.
+/// Compared case insensitively.
+const PRECOMPRESSED_EXTENSIONS: &[&str] = &[
+	"7z", "aac", "apk", "avi", "avif", "br", "bz2", "dmg", "docx", "epub", "flac", "gif", "gz",
+	"heic", "heif", "jar", "jpeg", "jpg", "jxl", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "ogg",
+	"opus", "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// How the file data of a transfer goes over the wire.
+///
+/// The sender proposes it in its [`super::SpaceblockRequests`], but only uses it once the receiver
+/// accepted it, so both ends have to agree on it before calling [`super::Transfer::with_compression`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+	#[default]
+	None,
+	/// Blocks and segments are compressed with zstd, unless they don't get any smaller
+	Zstd,
+}
+
+impl Compression {
+	/// What's worth proposing for a transfer starting with `sample`, which is up to the first
+	/// [`COMPRESSION_SAMPLE_SIZE`] bytes of its first file
+	#[must_use]
+	pub fn estimate(sample: &[u8]) -> Self {
+		if sample.is_empty() {
+			return Self::None;
+		}
+
+		match compress(sample) {
+			// Less than a 10% saving isn't worth compressing every block for
+			Ok(compressed) if compressed.len() < sample.len() - sample.len() / 10 => Self::Zstd,
+			_ => Self::None,
+		}
+	}
+
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Self> {
+		match stream.read_u8().await? {
+			0 => Ok(Self::None),
+			1 => Ok(Self::Zstd),
+			v => Err(io::Error::new(
+				ErrorKind::InvalidData,
+				format!("invalid compression '{v}'"),
+			)),
+		}
+	}
+
+	#[must_use]
+	pub fn to_byte(self) -> u8 {
+		match self {
+			Self::None => 0,
+			Self::Zstd => 1,
+		}
+	}
+}
+
+/// Whether the file named `name` is in a format which compresses its data itself
+#[must_use]
+pub fn is_precompressed(name: &str) -> bool {
+	name.rsplit_once('.').is_some_and(|(_, extension)| {
+		PRECOMPRESSED_EXTENSIONS
+			.iter()
+			.any(|precompressed| precompressed.eq_ignore_ascii_case(extension))
+	})
+}
+
+pub(crate) fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+	zstd::bulk::compress(data, ZSTD_LEVEL)
+}
+
+/// Decompress `data` into `buf`, returning how long it was decompressed
+pub(crate) fn decompress_into(data: &[u8], buf: &mut [u8]) -> io::Result<usize> {
+	zstd::bulk::decompress_to_buffer(data, buf)
+}
+
+pub(crate) fn decompress(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+	zstd::bulk::decompress(data, max_len)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn text_is_worth_compressing_but_noise_is_not() {
+		let text = b"fn main() { println!(\"Hello, Spacedrive!\"); }\n".repeat(1000);
+		assert_eq!(Compression::estimate(&text), Compression::Zstd);
+
+		// An xorshift keeps the noise the same from run to run
+		let mut state = 0x2545_f491_4f6c_dd1d_u64;
+		let noise = (0..COMPRESSION_SAMPLE_SIZE)
+			.map(|_| {
+				state ^= state << 13;
+				state ^= state >> 7;
+				state ^= state << 17;
+				state as u8
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(Compression::estimate(&noise), Compression::None);
+		assert_eq!(Compression::estimate(&[]), Compression::None);
+	}
+
+	#[test]
+	fn media_and_archives_are_precompressed() {
+		assert!(is_precompressed("Holiday.JPG"));
+		assert!(is_precompressed("dir/movie.mp4"));
+		assert!(is_precompressed("backup.tar.zip"));
+		assert!(!is_precompressed("notes.txt"));
+		assert!(!is_precompressed("Makefile"));
+	}
+}
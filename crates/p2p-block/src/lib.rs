@@ -7,7 +7,7 @@
 #![allow(unused)] // TODO: This module is still in heavy development!
 
 use std::{
-	io,
+	io::{self, Cursor},
 	marker::PhantomData,
 	path::{Path, PathBuf},
 	string::FromUtf8Error,
@@ -29,16 +29,40 @@ use sd_p2p_proto::{decode, encode};
 
 mod block;
 mod block_size;
+mod compression;
 mod sb_request;
+mod segment;
 
 pub use block::*;
 pub use block_size::*;
+pub use compression::{is_precompressed, Compression, COMPRESSION_SAMPLE_SIZE};
 pub use sb_request::*;
+pub use segment::*;
+
+/// The receiver answered a block with a byte that isn't continue, cancel or done
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("unexpected reply '{0}' from the receiver")]
+pub struct UnexpectedReply(pub u8);
+
+impl From<UnexpectedReply> for io::Error {
+	fn from(err: UnexpectedReply) -> Self {
+		io::Error::new(io::ErrorKind::InvalidData, err)
+	}
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Msg<'a> {
 	Block(Block<'a>),
 	Cancelled,
+	Segment(Segment),
+	/// A [`Block`] of `size` bytes, whose `data` is compressed
+	CompressedBlock {
+		offset: u64,
+		size: u64,
+		data: Vec<u8>,
+	},
+	/// The bytes of a [`Segment`], compressed
+	CompressedSegment(Vec<u8>),
 }
 
 impl<'a> Msg<'a> {
@@ -50,6 +74,17 @@ impl<'a> Msg<'a> {
 		match discriminator {
 			0 => Ok(Msg::Block(Block::from_stream(stream, data_buf).await?)),
 			1 => Ok(Msg::Cancelled),
+			2 => Ok(Msg::Segment(Segment::from_stream(stream).await?)),
+			3 => {
+				let offset = stream.read_u64_le().await?;
+				let size = stream.read_u64_le().await?;
+				// Data which doesn't compress is sent as a plain block, so it's never bigger than one
+				let data = read_compressed(stream, data_buf.len()).await?;
+				Ok(Msg::CompressedBlock { offset, size, data })
+			}
+			4 => Ok(Msg::CompressedSegment(
+				read_compressed(stream, MAX_DECOMPRESSED_SEGMENT).await?,
+			)),
 			_ => Err(io::Error::new(
 				io::ErrorKind::Other,
 				"Invalid 'Msg' discriminator!",
@@ -67,10 +102,51 @@ impl<'a> Msg<'a> {
 				bytes
 			}
 			Msg::Cancelled => vec![1],
+			Msg::Segment(segment) => {
+				let mut bytes = vec![2];
+				bytes.extend(segment.to_bytes());
+				bytes
+			}
+			Msg::CompressedBlock { offset, size, data } => {
+				let mut bytes = vec![3];
+				bytes.extend_from_slice(&offset.to_le_bytes());
+				bytes.extend_from_slice(&size.to_le_bytes());
+				bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+				bytes.extend_from_slice(data);
+				bytes
+			}
+			Msg::CompressedSegment(data) => {
+				let mut bytes = vec![4];
+				bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+				bytes.extend_from_slice(data);
+				bytes
+			}
 		}
 	}
 }
 
+/// How big a [`Segment`] can be once decompressed, the files can take up to [`MAX_SEGMENT_SIZE`]
+/// and this leaves room for their index
+const MAX_DECOMPRESSED_SEGMENT: usize = 2 * MAX_SEGMENT_SIZE as usize;
+
+/// Reads compressed data, refusing more than `max_len` so a peer can't make us allocate whatever it likes
+async fn read_compressed(
+	stream: &mut (impl AsyncReadExt + Unpin),
+	max_len: usize,
+) -> Result<Vec<u8>, io::Error> {
+	let len = stream.read_u32_le().await? as usize;
+	if len > max_len {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"compressed data is longer than allowed",
+		));
+	}
+
+	let mut data = vec![0; len];
+	stream.read_exact(&mut data).await?;
+	Ok(data)
+}
+
 /// TODO
 pub struct Transfer<'a, F> {
 	reqs: &'a SpaceblockRequests,
@@ -80,6 +156,9 @@ pub struct Transfer<'a, F> {
 	// TODO: Remove `i` plz
 	i: usize,
 	cancelled: &'a AtomicBool,
+	frames: u64,
+	compression: Compression,
+	wire_bytes: u64,
 }
 
 impl<'a, F> Transfer<'a, F>
@@ -96,9 +175,58 @@ where
 			total_bytes: req.requests.iter().map(|req| req.size).sum(),
 			i: 0,
 			cancelled,
+			frames: 0,
+			compression: Compression::None,
+			wire_bytes: 0,
 		}
 	}
 
+	/// Compress the data sent, or accept compressed data when receiving.
+	/// This must be what both ends agreed on, not only what the sender proposed.
+	#[must_use]
+	pub fn with_compression(mut self, compression: Compression) -> Self {
+		self.compression = compression;
+		self
+	}
+
+	/// How many [`Msg`]s this end has sent or received so far
+	pub fn frames(&self) -> u64 {
+		self.frames
+	}
+
+	/// How many bytes of the files this end has sent or received so far, which progress is reported for
+	pub fn file_bytes(&self) -> u64 {
+		self.total_offset
+	}
+
+	/// How many bytes of file data this end has sent or received so far, after compression
+	pub fn wire_bytes(&self) -> u64 {
+		self.wire_bytes
+	}
+
+	/// `data` compressed, if compression was agreed on and it makes it smaller
+	fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+		if self.compression != Compression::Zstd || data.is_empty() {
+			return None;
+		}
+
+		compression::compress(data)
+			.ok()
+			.filter(|compressed| compressed.len() < data.len())
+	}
+
+	fn invalid_compressed(&self) -> io::Error {
+		io::Error::new(
+			io::ErrorKind::InvalidData,
+			"received compressed data without agreeing on compression",
+		)
+	}
+
+	fn report_progress(&self) {
+		// SAFETY: Percent must be between 0 and 100
+		(self.on_progress)(((self.total_offset as f64 / self.total_bytes as f64) * 100.0) as u8);
+	}
+
 	// TODO: Should `new` take in the streams too cause this means we `Stream` `SpaceblockRequest` could get outta sync.
 	pub async fn send(
 		&mut self,
@@ -108,45 +236,67 @@ where
 		// We manually implement what is basically a `BufReader` so we have more control
 		let mut buf = vec![0u8; self.reqs.block_size.size() as usize];
 		let mut offset: u64 = 0;
+		// Compressing media and archives again gains nothing
+		let compressible = self
+			.reqs
+			.requests
+			.get(self.i)
+			.is_some_and(|req| !is_precompressed(&req.name));
 
 		loop {
 			if self.cancelled.load(Ordering::Relaxed) {
 				stream.write_all(&Msg::Cancelled.to_bytes()).await?;
 				stream.flush().await?;
+				self.frames += 1;
 				return Ok(());
 			}
 
 			let read = file.read(&mut buf[..]).await?;
 			self.total_offset += read as u64;
-			(self.on_progress)(
-				((self.total_offset as f64 / self.total_bytes as f64) * 100.0) as u8,
-			); // SAFETY: Percent must be between 0 and 100
+			self.report_progress();
 
 			if read == 0 {
 				#[allow(clippy::panic)] // TODO: Remove panic
-						// The file may have been modified during sender on the sender and we don't account for that.
-						// TODO: Error handling + send error to remote
+							// The file may have been modified during sender on the sender and we don't account for that.
+							// TODO: Error handling + send error to remote
 				assert!(
 					(offset + read as u64) == self.reqs.requests[self.i].size,
 					"File sending has stopped but it doesn't match the expected length!"
 				);
 
+				self.i += 1;
 				return Ok(());
 			}
 
-			let block = Block {
-				offset,
-				size: read as u64,
-				data: &buf[..read],
+			let data = &buf[..read];
+			let msg = match compressible.then(|| self.compress(data)).flatten() {
+				Some(compressed) => {
+					debug!(
+						"Sending block at offset {offset} of size {read} compressed to {}",
+						compressed.len()
+					);
+					self.wire_bytes += compressed.len() as u64;
+					Msg::CompressedBlock {
+						offset,
+						size: read as u64,
+						data: compressed,
+					}
+				}
+				None => {
+					debug!("Sending block at offset {offset} of size {read}");
+					self.wire_bytes += read as u64;
+					Msg::Block(Block {
+						offset,
+						size: read as u64,
+						data,
+					})
+				}
 			};
-			debug!(
-				"Sending block at offset {} of size {}",
-				block.offset, block.size
-			);
 			offset += read as u64;
 
-			stream.write_all(&Msg::Block(block).to_bytes()).await?;
+			stream.write_all(&msg.to_bytes()).await?;
 			stream.flush().await?;
+			self.frames += 1;
 
 			match stream.read_u8().await? {
 				// Continue sending
@@ -157,8 +307,11 @@ where
 					return Ok(());
 				}
 				// Transfer complete
-				2 => return Ok(()),
-				_ => todo!(),
+				2 => {
+					self.i += 1;
+					return Ok(());
+				}
+				reply => return Err(UnexpectedReply(reply).into()),
 			}
 		}
 	}
@@ -189,40 +342,66 @@ where
 
 			// TODO: Timeout if nothing is being received
 			let msg = Msg::from_stream(stream, &mut data_buf).await?;
-			match msg {
+			self.frames += 1;
+			let size = match msg {
 				Msg::Block(block) => {
-					self.total_offset += block.size;
-					(self.on_progress)(
-						((self.total_offset as f64 / self.total_bytes as f64) * 100.0) as u8,
-					); // SAFETY: Percent must be between 0 and 100
-
 					debug!(
 						"Received block at offset {} of size {}",
 						block.offset, block.size
 					);
-					offset += block.size;
-
-					file.write_all(&data_buf[..block.size as usize]).await?;
-
-					let req = self.reqs.requests.get(self.i).ok_or_else(|| {
-						debug!("Vector read out of bounds!");
-						io::ErrorKind::Other
-					})?;
-					// TODO: Should this be `read == 0`
-					if offset == req.size {
-						break;
+					self.wire_bytes += block.size;
+					block.size
+				}
+				Msg::CompressedBlock { offset, size, data } => {
+					if self.compression != Compression::Zstd {
+						return Err(self.invalid_compressed());
 					}
 
-					stream
-						.write_u8(u8::from(self.cancelled.load(Ordering::Relaxed)))
-						.await?;
-					stream.flush().await?;
+					debug!(
+						"Received block at offset {offset} of size {size} compressed to {}",
+						data.len()
+					);
+					let decompressed = compression::decompress_into(&data, &mut data_buf)?;
+					if decompressed as u64 != size {
+						return Err(io::Error::new(
+							io::ErrorKind::InvalidData,
+							"compressed block doesn't match its size",
+						));
+					}
+					self.wire_bytes += data.len() as u64;
+					size
 				}
 				Msg::Cancelled => {
 					debug!("Sender cancelled Spacedrop transfer!");
 					return Ok(());
 				}
+				Msg::Segment(_) | Msg::CompressedSegment(_) => {
+					return Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						"received a segment while streaming a file",
+					));
+				}
+			};
+
+			self.total_offset += size;
+			self.report_progress();
+			offset += size;
+
+			file.write_all(&data_buf[..size as usize]).await?;
+
+			let req = self.reqs.requests.get(self.i).ok_or_else(|| {
+				debug!("Vector read out of bounds!");
+				io::ErrorKind::Other
+			})?;
+			// TODO: Should this be `read == 0`
+			if offset == req.size {
+				break;
 			}
+
+			stream
+				.write_u8(u8::from(self.cancelled.load(Ordering::Relaxed)))
+				.await?;
+			stream.flush().await?;
 		}
 
 		stream.write_u8(2).await?;
@@ -232,6 +411,148 @@ where
 
 		Ok(())
 	}
+
+	/// Sends the next `files` at once, they must be a [`TransferUnit::Packed`] of the requests
+	pub async fn send_segment(
+		&mut self,
+		stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+		files: &[Vec<u8>],
+	) -> Result<(), io::Error> {
+		if self.cancelled.load(Ordering::Relaxed) {
+			stream.write_all(&Msg::Cancelled.to_bytes()).await?;
+			stream.flush().await?;
+			self.frames += 1;
+			return Ok(());
+		}
+
+		let reqs = self
+			.reqs
+			.requests
+			.get(self.i..self.i + files.len())
+			.ok_or_else(|| {
+				io::Error::new(io::ErrorKind::InvalidInput, "more files than requested")
+			})?;
+		let segment = Segment::pack(
+			reqs.iter()
+				.zip(files)
+				.map(|(req, file)| (req.name.as_str(), file.as_slice())),
+		);
+		debug!(
+			"Sending segment of {} files and {} bytes",
+			segment.entries.len(),
+			segment.data.len()
+		);
+
+		let compressed = if reqs.iter().all(|req| is_precompressed(&req.name)) {
+			None
+		} else {
+			let bytes = segment.to_bytes();
+			// The receiver won't decompress more than this
+			(bytes.len() <= MAX_DECOMPRESSED_SEGMENT)
+				.then(|| self.compress(&bytes))
+				.flatten()
+		};
+		let msg = match compressed {
+			Some(compressed) => {
+				debug!("Segment compressed to {} bytes", compressed.len());
+				self.wire_bytes += compressed.len() as u64;
+				Msg::CompressedSegment(compressed)
+			}
+			None => {
+				self.wire_bytes += segment.data.len() as u64;
+				Msg::Segment(segment)
+			}
+		};
+
+		stream.write_all(&msg.to_bytes()).await?;
+		stream.flush().await?;
+		self.frames += 1;
+
+		match stream.read_u8().await? {
+			0 => {}
+			1 => {
+				debug!("Receiver cancelled Spacedrop transfer!");
+				return Ok(());
+			}
+			reply => return Err(UnexpectedReply(reply).into()),
+		}
+
+		self.i += files.len();
+		self.total_offset += files.iter().map(|file| file.len() as u64).sum::<u64>();
+		self.report_progress();
+
+		Ok(())
+	}
+
+	/// Receives the next files, which were packed together as they're a [`TransferUnit::Packed`]
+	/// of the requests. Returns `None` if the transfer was cancelled by either end.
+	///
+	/// Progress isn't reported until the files are saved, see [`Transfer::unpacked`].
+	pub async fn receive_segment(
+		&mut self,
+		stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+	) -> Result<Option<Segment>, io::Error> {
+		let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+		if self.cancelled.load(Ordering::Relaxed) {
+			stream.write_u8(1).await?;
+			stream.flush().await?;
+			return Ok(None);
+		}
+
+		// Segments are read whole, so they don't go through the block sized buffer
+		let msg = Msg::from_stream(stream, &mut []).await?;
+		self.frames += 1;
+		let segment = match msg {
+			Msg::Segment(segment) => {
+				self.wire_bytes += segment.data.len() as u64;
+				segment
+			}
+			Msg::CompressedSegment(data) => {
+				if self.compression != Compression::Zstd {
+					return Err(self.invalid_compressed());
+				}
+
+				self.wire_bytes += data.len() as u64;
+				let bytes = compression::decompress(&data, MAX_DECOMPRESSED_SEGMENT)?;
+				Segment::from_stream(&mut Cursor::new(bytes)).await?
+			}
+			Msg::Cancelled => {
+				debug!("Sender cancelled Spacedrop transfer!");
+				return Ok(None);
+			}
+			Msg::Block(_) | Msg::CompressedBlock { .. } => {
+				return Err(invalid("received a block when expecting a segment"))
+			}
+		};
+
+		let reqs = self
+			.reqs
+			.requests
+			.get(self.i..self.i + segment.entries.len())
+			.ok_or_else(|| invalid("segment has more files than requested"))?;
+		if reqs
+			.iter()
+			.zip(&segment.entries)
+			.any(|(req, entry)| req.name != entry.name || req.size != entry.len)
+		{
+			return Err(invalid("segment doesn't match the requested files"));
+		}
+
+		stream
+			.write_u8(u8::from(self.cancelled.load(Ordering::Relaxed)))
+			.await?;
+		stream.flush().await?;
+		self.i += segment.entries.len();
+
+		Ok(Some(segment))
+	}
+
+	/// Counts a file of a received [`Segment`] as done, once it's been saved
+	pub fn unpacked(&mut self, entry: &SegmentEntry) {
+		self.total_offset += entry.len;
+		self.report_progress();
+	}
 }
 
 #[cfg(test)]
@@ -252,10 +573,14 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size: BlockSize::from_size(data.len() as u64),
+			compression: Compression::None,
 			requests: vec![SpaceblockRequest {
 				name: "Demo".to_string(),
 				size: data.len() as u64,
 				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
 			}],
 		};
 
@@ -294,10 +619,14 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size,
+			compression: Compression::None,
 			requests: vec![SpaceblockRequest {
 				name: "Demo".to_string(),
 				size: data.len() as u64,
 				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
 			}],
 		};
 
@@ -335,10 +664,14 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size,
+			compression: Compression::None,
 			requests: vec![SpaceblockRequest {
 				name: "Demo".to_string(),
 				size: data.len() as u64,
 				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
 			}],
 		};
 
@@ -365,6 +698,48 @@ mod tests {
 		assert_eq!(result, Vec::<u8>::new()); // Cancelled by sender so no data
 	}
 
+	#[tokio::test]
+	async fn unexpected_replies_fail_the_sender() {
+		let (mut client, mut server) = tokio::io::duplex(64);
+
+		let data = b"Spacedrive".to_vec();
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(data.len() as u64),
+			compression: Compression::None,
+			requests: vec![SpaceblockRequest {
+				name: "Demo".to_string(),
+				size: data.len() as u64,
+				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
+			}],
+		};
+
+		let sender = tokio::spawn({
+			let req = req.clone();
+			async move {
+				Transfer::new(&req, |_| {}, &Default::default())
+					.send(&mut client, BufReader::new(Cursor::new(data)))
+					.await
+			}
+		});
+
+		let mut buf = vec![0u8; req.block_size.size() as usize];
+		Msg::from_stream(&mut server, &mut buf).await.unwrap();
+		server.write_u8(7).await.unwrap();
+
+		let err = sender.await.unwrap().unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		assert_eq!(
+			err.into_inner()
+				.and_then(|err| err.downcast::<UnexpectedReply>().ok())
+				.map(|err| *err),
+			Some(UnexpectedReply(7))
+		);
+	}
+
 	#[tokio::test]
 	async fn test_transfer_sender_cancelled() {
 		let (mut client, mut server) = tokio::io::duplex(64);
@@ -377,10 +752,14 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size,
+			compression: Compression::None,
 			requests: vec![SpaceblockRequest {
 				name: "Demo".to_string(),
 				size: data.len() as u64,
 				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
 			}],
 		};
 
@@ -420,10 +799,14 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size,
+			compression: Compression::None,
 			requests: vec![SpaceblockRequest {
 				name: "Demo".to_string(),
 				size: data.len() as u64,
 				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
 			}],
 		};
 
@@ -450,6 +833,249 @@ mod tests {
 		assert_eq!(result, Vec::<u8>::new()); // Cancelled by sender so no data
 	}
 
+	/// Sends `files` like Spacedrop does, returning what was received with the progress reported by
+	/// the receiver and how many frames the sender sent
+	async fn transfer_files(files: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, Vec<u8>, u64) {
+		let files = files
+			.into_iter()
+			.enumerate()
+			.map(|(i, file)| (format!("dir/{i}"), file))
+			.collect();
+		let transferred = transfer_named_files(files, Compression::None).await;
+
+		(
+			transferred.received,
+			transferred.progress,
+			transferred.frames,
+		)
+	}
+
+	struct Transferred {
+		received: Vec<Vec<u8>>,
+		progress: Vec<u8>,
+		frames: u64,
+		sent_wire_bytes: u64,
+		received_wire_bytes: u64,
+	}
+
+	/// Like [`transfer_files`], with both ends using `compression`
+	async fn transfer_named_files(
+		files: Vec<(String, Vec<u8>)>,
+		compression: Compression,
+	) -> Transferred {
+		let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+
+		let (names, files): (Vec<_>, Vec<_>) = files.into_iter().unzip();
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(0),
+			compression,
+			requests: names
+				.into_iter()
+				.zip(&files)
+				.map(|(name, file)| SpaceblockRequest {
+					name,
+					size: file.len() as u64,
+					range: Range::Full,
+					modified_at: None,
+					created_at: None,
+					mode: None,
+				})
+				.collect(),
+		};
+
+		let sender = tokio::spawn({
+			let req = req.clone();
+			async move {
+				let cancelled = AtomicBool::default();
+				let mut transfer =
+					Transfer::new(&req, |_| {}, &cancelled).with_compression(compression);
+				for unit in TransferUnit::plan(&req.requests) {
+					match unit {
+						TransferUnit::Streamed(i) => {
+							let file = BufReader::new(Cursor::new(files[i].clone()));
+							transfer.send(&mut client, file).await.unwrap();
+						}
+						TransferUnit::Packed(range) => {
+							transfer
+								.send_segment(&mut client, &files[range])
+								.await
+								.unwrap();
+						}
+					}
+				}
+				(transfer.frames(), transfer.wire_bytes())
+			}
+		});
+
+		let cancelled = AtomicBool::default();
+		let progress = std::sync::Mutex::new(Vec::new());
+		let mut transfer = Transfer::new(
+			&req,
+			|percent| progress.lock().unwrap().push(percent),
+			&cancelled,
+		)
+		.with_compression(compression);
+		let mut received = vec![Vec::new(); req.requests.len()];
+		for unit in TransferUnit::plan(&req.requests) {
+			match unit {
+				TransferUnit::Streamed(i) => {
+					transfer
+						.receive(&mut server, &mut received[i])
+						.await
+						.unwrap();
+				}
+				TransferUnit::Packed(range) => {
+					let segment = transfer
+						.receive_segment(&mut server)
+						.await
+						.unwrap()
+						.unwrap();
+					for ((entry, data), i) in segment.files().zip(range) {
+						received[i] = data.to_vec();
+						transfer.unpacked(entry);
+					}
+				}
+			}
+		}
+		let received_wire_bytes = transfer.wire_bytes();
+
+		let (frames, sent_wire_bytes) = sender.await.unwrap();
+		Transferred {
+			received,
+			progress: progress.into_inner().unwrap(),
+			frames,
+			sent_wire_bytes,
+			received_wire_bytes,
+		}
+	}
+
+	/// Bytes which don't compress, the same from run to run
+	fn noise(len: usize) -> Vec<u8> {
+		let mut state = 0x2545_f491_4f6c_dd1d_u64;
+		(0..len)
+			.map(|_| {
+				state ^= state << 13;
+				state ^= state >> 7;
+				state ^= state << 17;
+				state as u8
+			})
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn compressible_files_take_fewer_bytes_on_the_wire() {
+		let source = b"pub fn spacedrop(files: Vec<PathBuf>) -> Result<(), Error> {}\n";
+		let files = vec![
+			("src/big.rs".to_string(), source.repeat(10_000)),
+			("src/small.rs".to_string(), source.repeat(100)),
+			("docs/empty.md".to_string(), vec![]),
+		];
+		let logical_bytes = files.iter().map(|(_, file)| file.len() as u64).sum::<u64>();
+
+		let transferred = transfer_named_files(files.clone(), Compression::Zstd).await;
+		assert_eq!(
+			transferred.received,
+			files.into_iter().map(|(_, file)| file).collect::<Vec<_>>()
+		);
+		// Progress is for the files' own bytes, so it still goes all the way
+		assert_eq!(transferred.progress.last(), Some(&100));
+		assert_eq!(transferred.sent_wire_bytes, transferred.received_wire_bytes);
+		assert!(
+			transferred.sent_wire_bytes * 10 < logical_bytes,
+			"{} bytes went over the wire for {logical_bytes} bytes of files",
+			transferred.sent_wire_bytes
+		);
+	}
+
+	#[tokio::test]
+	async fn incompressible_files_are_sent_as_they_are() {
+		let files = vec![
+			("photo.jpg".to_string(), vec![0; 300 * 1024]),
+			("noise.bin".to_string(), noise(300 * 1024)),
+			("thumb.webp".to_string(), vec![0; 1024]),
+		];
+		let logical_bytes = files.iter().map(|(_, file)| file.len() as u64).sum::<u64>();
+
+		let transferred = transfer_named_files(files.clone(), Compression::Zstd).await;
+		assert_eq!(
+			transferred.received,
+			files.into_iter().map(|(_, file)| file).collect::<Vec<_>>()
+		);
+		// The JPEG and WebP would compress to nothing but are skipped for their extension, and the
+		// noise doesn't get any smaller
+		assert_eq!(transferred.sent_wire_bytes, logical_bytes);
+		assert_eq!(transferred.received_wire_bytes, logical_bytes);
+	}
+
+	#[tokio::test]
+	async fn compressed_data_is_refused_unless_agreed_on() {
+		let text = b"Spacedrive ".repeat(1000);
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(text.len() as u64),
+			compression: Compression::Zstd,
+			requests: vec![SpaceblockRequest {
+				name: "notes.txt".to_string(),
+				size: text.len() as u64,
+				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
+			}],
+		};
+
+		let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+		tokio::spawn({
+			let req = req.clone();
+			let text = text.clone();
+			async move {
+				let cancelled = AtomicBool::default();
+				Transfer::new(&req, |_| {}, &cancelled)
+					.with_compression(Compression::Zstd)
+					.send(&mut client, BufReader::new(Cursor::new(text)))
+					.await
+			}
+		});
+
+		// The receiver never accepted the proposal
+		let mut result = Vec::new();
+		let err = Transfer::new(&req, |_| {}, &Default::default())
+			.receive(&mut server, &mut result)
+			.await
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[tokio::test]
+	async fn small_files_are_sent_in_segments() {
+		let files = (0..1000)
+			.map(|i| vec![(i % 251) as u8; 1024])
+			.collect::<Vec<_>>();
+
+		let (received, progress, frames) = transfer_files(files.clone()).await;
+		assert_eq!(received, files);
+		// Streamed one by one, each file would take at least a frame of its own
+		assert_eq!(frames, 1);
+		// Files in a segment are counted as they're unpacked
+		assert_eq!(progress.len(), files.len());
+		assert_eq!(progress.last(), Some(&100));
+	}
+
+	#[tokio::test]
+	async fn packed_and_streamed_files_are_interleaved() {
+		let files = [10, 200 * 1024, 20, 30, 0, PACK_THRESHOLD as usize + 1, 5]
+			.into_iter()
+			.enumerate()
+			.map(|(i, len)| vec![i as u8; len])
+			.collect::<Vec<_>>();
+
+		let (received, _, frames) = transfer_files(files.clone()).await;
+		assert_eq!(received, files);
+		// A frame for each of the 3 runs of small files, then one per 128 KiB block of the big ones
+		assert_eq!(frames, 3 + 2 + 1);
+	}
+
 	#[tokio::test]
 	async fn test_msg() {
 		let block = Block {
@@ -1,4 +1,7 @@
-use std::io;
+use std::{
+	io,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
@@ -6,7 +9,13 @@ use uuid::Uuid;
 
 use sd_p2p_proto::{decode, encode};
 
-use super::BlockSize;
+use super::{BlockSize, Compression};
+
+/// The most files a single Spacedrop can send, so a peer can't have us parse requests forever
+pub const MAX_SPACEDROP_FILES: u32 = 10_000;
+
+/// The most files a Spacedrop to a node from before [`MAX_SPACEDROP_FILES`] can send, see [`SpaceblockRequests::to_bytes_legacy`]
+pub const MAX_LEGACY_SPACEDROP_FILES: u32 = u8::MAX as u32;
 
 /// TODO
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -28,7 +37,7 @@ impl Range {
 				Ok(Self::Partial(start..end))
 			}
 			_ => Err(io::Error::new(
-				io::ErrorKind::Other,
+				io::ErrorKind::InvalidData,
 				"Invalid range discriminator",
 			)),
 		}
@@ -54,6 +63,9 @@ impl Range {
 pub struct SpaceblockRequests {
 	pub id: Uuid,
 	pub block_size: BlockSize,
+	/// What the sender proposes, the receiver decides whether it's used when accepting the requests.
+	/// Nodes from before compression are never sent it, see [`Self::to_bytes_legacy`]
+	pub compression: Compression,
 	pub requests: Vec<SpaceblockRequest>,
 }
 
@@ -67,6 +79,10 @@ pub enum SpaceblockRequestsError {
 	SpaceblockRequest(#[from] SpaceblockRequestError),
 	#[error("SpaceblockRequestsError::BlockSize({0:?})")]
 	BlockSize(std::io::Error),
+	#[error("SpaceblockRequestsError::Compression({0:?})")]
+	Compression(std::io::Error),
+	#[error("SpaceblockRequestsError::TooManyRequests({0})")]
+	TooManyRequests(u32),
 }
 
 impl SpaceblockRequests {
@@ -81,20 +97,27 @@ impl SpaceblockRequests {
 			.await
 			.map_err(SpaceblockRequestsError::BlockSize)?;
 
+		let compression = Compression::from_stream(stream)
+			.await
+			.map_err(SpaceblockRequestsError::Compression)?;
+
 		let size = stream
-			// Max of 255 files in one request
-			.read_u8()
+			.read_u32_le()
 			.await
 			.map_err(SpaceblockRequestsError::InvalidLen)?;
+		if size > MAX_SPACEDROP_FILES {
+			return Err(SpaceblockRequestsError::TooManyRequests(size));
+		}
 
 		let mut requests = Vec::new();
-		for i in 0..size {
+		for _ in 0..size {
 			requests.push(SpaceblockRequest::from_stream(stream).await?);
 		}
 
 		Ok(Self {
 			id,
 			block_size,
+			compression,
 			requests,
 		})
 	}
@@ -104,23 +127,80 @@ impl SpaceblockRequests {
 		let Self {
 			id,
 			block_size,
+			compression,
 			requests,
 		} = self;
 		#[allow(clippy::panic)] // TODO: Remove this panic
 		assert!(
-			requests.len() <= 255,
-			"Can't Spacedrop more than 255 files at once!"
+			requests.len() <= MAX_SPACEDROP_FILES as usize,
+			"Can't Spacedrop more than {MAX_SPACEDROP_FILES} files at once!",
 		);
 
 		let mut buf = vec![];
 		encode::uuid(&mut buf, id);
 		buf.append(&mut block_size.to_bytes().to_vec());
-		buf.push(requests.len() as u8);
+		buf.push(compression.to_byte());
+		buf.extend_from_slice(&(requests.len() as u32).to_le_bytes());
 		for request in requests {
 			buf.extend_from_slice(&request.to_bytes());
 		}
 		buf
 	}
+
+	/// Reads requests from a node which predates [`Compression`], file metadata and more than [`MAX_LEGACY_SPACEDROP_FILES`] files
+	pub async fn from_stream_legacy(
+		stream: &mut (impl AsyncRead + Unpin),
+	) -> Result<Self, SpaceblockRequestsError> {
+		let id = decode::uuid(stream)
+			.await
+			.map_err(SpaceblockRequestsError::Id)?;
+
+		let block_size = BlockSize::from_stream(stream)
+			.await
+			.map_err(SpaceblockRequestsError::BlockSize)?;
+
+		let size = stream
+			.read_u8()
+			.await
+			.map_err(SpaceblockRequestsError::InvalidLen)?;
+
+		let mut requests = Vec::new();
+		for _ in 0..size {
+			requests.push(SpaceblockRequest::from_stream_legacy(stream).await?);
+		}
+
+		Ok(Self {
+			id,
+			block_size,
+			compression: Compression::None,
+			requests,
+		})
+	}
+
+	/// The requests as a node which predates [`Compression`] and file metadata reads them, it only knows the name, size and range of each file
+	#[must_use]
+	pub fn to_bytes_legacy(&self) -> Vec<u8> {
+		let Self {
+			id,
+			block_size,
+			requests,
+			..
+		} = self;
+		#[allow(clippy::panic)] // TODO: Remove this panic
+		assert!(
+			requests.len() <= MAX_LEGACY_SPACEDROP_FILES as usize,
+			"Can't Spacedrop more than {MAX_LEGACY_SPACEDROP_FILES} files at once to this node!",
+		);
+
+		let mut buf = vec![];
+		encode::uuid(&mut buf, id);
+		buf.append(&mut block_size.to_bytes().to_vec());
+		buf.push(requests.len() as u8);
+		for request in requests {
+			buf.extend_from_slice(&request.to_bytes_legacy());
+		}
+		buf
+	}
 }
 
 /// TODO
@@ -128,8 +208,11 @@ impl SpaceblockRequests {
 pub struct SpaceblockRequest {
 	pub name: String,
 	pub size: u64,
-	// TODO: Include file permissions
 	pub range: Range,
+	pub modified_at: Option<SystemTime>,
+	pub created_at: Option<SystemTime>,
+	/// Unix permission bits. The receiver only applies these if the user opts in.
+	pub mode: Option<u32>,
 }
 
 #[derive(Debug, Error)]
@@ -141,6 +224,8 @@ pub enum SpaceblockRequestError {
 	// TODO: From outside. Probs remove?
 	#[error("SpaceblockRequestError::RangeError({0:?})")]
 	RangeError(io::Error),
+	#[error("SpaceblockRequestError::Metadata({0})")]
+	Metadata(io::Error),
 }
 
 impl SpaceblockRequest {
@@ -156,25 +241,120 @@ impl SpaceblockRequest {
 			.await
 			.map_err(SpaceblockRequestError::Size)?;
 
+		let range = Range::from_stream(stream)
+			.await
+			.map_err(SpaceblockRequestError::Size)?;
+
+		let modified_at = read_time(stream)
+			.await
+			.map_err(SpaceblockRequestError::Metadata)?;
+		let created_at = read_time(stream)
+			.await
+			.map_err(SpaceblockRequestError::Metadata)?;
+		let mode = match stream
+			.read_u8()
+			.await
+			.map_err(SpaceblockRequestError::Metadata)?
+		{
+			0 => None,
+			_ => Some(
+				stream
+					.read_u32_le()
+					.await
+					.map_err(SpaceblockRequestError::Metadata)?,
+			),
+		};
+
 		Ok(Self {
 			name,
 			size,
-			range: Range::from_stream(stream)
-				.await
-				.map_err(SpaceblockRequestError::Size)?,
+			range,
+			modified_at,
+			created_at,
+			mode,
 		})
 	}
 
 	#[must_use]
 	pub fn to_bytes(&self) -> Vec<u8> {
-		let Self { name, size, range } = self;
+		let Self {
+			name,
+			size,
+			range,
+			modified_at,
+			created_at,
+			mode,
+		} = self;
 		let mut buf = Vec::new();
 
 		encode::string(&mut buf, name);
 		buf.extend_from_slice(&self.size.to_le_bytes());
 		buf.extend_from_slice(&self.range.to_bytes());
+		write_time(&mut buf, *modified_at);
+		write_time(&mut buf, *created_at);
+		match mode {
+			Some(mode) => {
+				buf.push(1);
+				buf.extend_from_slice(&mode.to_le_bytes());
+			}
+			None => buf.push(0),
+		}
 		buf
 	}
+
+	async fn from_stream_legacy(
+		stream: &mut (impl AsyncRead + Unpin),
+	) -> Result<Self, SpaceblockRequestError> {
+		let name = decode::string(stream)
+			.await
+			.map_err(SpaceblockRequestError::Name)?;
+
+		let size = stream
+			.read_u64_le()
+			.await
+			.map_err(SpaceblockRequestError::Size)?;
+
+		let range = Range::from_stream(stream)
+			.await
+			.map_err(SpaceblockRequestError::Size)?;
+
+		Ok(Self {
+			name,
+			size,
+			range,
+			modified_at: None,
+			created_at: None,
+			mode: None,
+		})
+	}
+
+	fn to_bytes_legacy(&self) -> Vec<u8> {
+		let mut buf = Vec::new();
+		encode::string(&mut buf, &self.name);
+		buf.extend_from_slice(&self.size.to_le_bytes());
+		buf.extend_from_slice(&self.range.to_bytes());
+		buf
+	}
+}
+
+/// Timestamps are sent as nanoseconds since the Unix epoch. Anything before the epoch is treated as unknown.
+fn write_time(buf: &mut Vec<u8>, time: Option<SystemTime>) {
+	match time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+		Some(since_epoch) => {
+			buf.push(1);
+			buf.extend_from_slice(&(since_epoch.as_nanos() as u64).to_le_bytes());
+		}
+		None => buf.push(0),
+	}
+}
+
+async fn read_time(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SystemTime>> {
+	match stream.read_u8().await? {
+		0 => Ok(None),
+		_ => Ok(Some(
+			UNIX_EPOCH + Duration::from_nanos(stream.read_u64_le().await?),
+		)),
+	}
 }
 
 #[cfg(test)]
@@ -201,6 +381,7 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size: BlockSize::from_size(42069),
+			compression: Compression::None,
 			requests: vec![],
 		};
 
@@ -216,10 +397,14 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size: BlockSize::from_size(42069),
+			compression: Compression::None,
 			requests: vec![SpaceblockRequest {
 				name: "Demo".to_string(),
 				size: 42069,
 				range: Range::Full,
+				modified_at: None,
+				created_at: None,
+				mode: None,
 			}],
 		};
 
@@ -233,6 +418,9 @@ mod tests {
 			name: "Demo".to_string(),
 			size: 42069,
 			range: Range::Partial(0..420),
+			modified_at: Some(UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789)),
+			created_at: Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+			mode: Some(0o644),
 		};
 
 		let bytes = req.to_bytes();
@@ -247,16 +435,23 @@ mod tests {
 		let req = SpaceblockRequests {
 			id: Uuid::new_v4(),
 			block_size: BlockSize::from_size(42069),
+			compression: Compression::Zstd,
 			requests: vec![
 				SpaceblockRequest {
 					name: "Demo".to_string(),
 					size: 42069,
 					range: Range::Full,
+					modified_at: None,
+					created_at: None,
+					mode: None,
 				},
 				SpaceblockRequest {
 					name: "Demo2".to_string(),
 					size: 420,
 					range: Range::Full,
+					modified_at: None,
+					created_at: None,
+					mode: None,
 				},
 			],
 		};
@@ -267,4 +462,56 @@ mod tests {
 			.unwrap();
 		assert_eq!(req, req2);
 	}
+
+	#[tokio::test]
+	async fn legacy_requests_are_read_like_nodes_before_them_wrote_them() {
+		let req = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(42069),
+			compression: Compression::None,
+			requests: vec![SpaceblockRequest {
+				name: "Demo".to_string(),
+				size: 42069,
+				range: Range::Partial(0..420),
+				modified_at: None,
+				created_at: None,
+				mode: None,
+			}],
+		};
+
+		// The id, the block size, a `u8` count and the name, size and range of each file
+		let mut expected = vec![];
+		encode::uuid(&mut expected, &req.id);
+		expected.extend_from_slice(&req.block_size.to_bytes());
+		expected.push(1);
+		encode::string(&mut expected, "Demo");
+		expected.extend_from_slice(&42069u64.to_le_bytes());
+		expected.extend_from_slice(&Range::Partial(0..420).to_bytes());
+
+		let bytes = req.to_bytes_legacy();
+		assert_eq!(bytes, expected);
+		let req2 = SpaceblockRequests::from_stream_legacy(&mut Cursor::new(bytes))
+			.await
+			.unwrap();
+		assert_eq!(req, req2);
+	}
+
+	#[tokio::test]
+	async fn too_many_requests_are_refused_before_reading_them() {
+		let mut bytes = SpaceblockRequests {
+			id: Uuid::new_v4(),
+			block_size: BlockSize::from_size(42069),
+			compression: Compression::None,
+			requests: vec![],
+		}
+		.to_bytes();
+		// Claim more requests than are allowed, without sending any of them
+		let len = bytes.len();
+		bytes[len - 4..].copy_from_slice(&(MAX_SPACEDROP_FILES + 1).to_le_bytes());
+
+		assert!(matches!(
+			SpaceblockRequests::from_stream(&mut Cursor::new(bytes)).await,
+			Err(SpaceblockRequestsError::TooManyRequests(len)) if len == MAX_SPACEDROP_FILES + 1
+		));
+	}
 }
@@ -0,0 +1,250 @@
+use std::{
+	io::{self, ErrorKind},
+	ops,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use sd_p2p_proto::{decode, encode};
+
+use super::{Range, SpaceblockRequest, MAX_SPACEDROP_FILES};
+
+/// Files up to this size are packed into segments instead of being streamed on their own
+pub const PACK_THRESHOLD: u64 = 64 * 1024;
+
+/// Small files are packed into the same segment until it would go over this size
+pub const MAX_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How some of the files of a [`super::SpaceblockRequests`] go over the wire.
+/// Both ends get the same plan from the requests, so they follow each other without saying so.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferUnit {
+	/// The file at this index is streamed block by block
+	Streamed(usize),
+	/// The files at these indexes are sent at once in a single [`Segment`]
+	Packed(ops::Range<usize>),
+}
+
+impl TransferUnit {
+	/// Every file in order, with the small files next to each other packed together
+	#[must_use]
+	pub fn plan(requests: &[SpaceblockRequest]) -> Vec<Self> {
+		let mut units = Vec::new();
+		let mut packing: Option<(ops::Range<usize>, u64)> = None;
+
+		for (i, req) in requests.iter().enumerate() {
+			if req.size > PACK_THRESHOLD || req.range != Range::Full {
+				if let Some((range, _)) = packing.take() {
+					units.push(Self::Packed(range));
+				}
+				units.push(Self::Streamed(i));
+				continue;
+			}
+
+			packing = match packing.take() {
+				Some((range, size)) if size + req.size <= MAX_SEGMENT_SIZE => {
+					Some((range.start..i + 1, size + req.size))
+				}
+				full => {
+					if let Some((range, _)) = full {
+						units.push(Self::Packed(range));
+					}
+					Some((i..i + 1, req.size))
+				}
+			};
+		}
+
+		if let Some((range, _)) = packing {
+			units.push(Self::Packed(range));
+		}
+
+		units
+	}
+
+	/// Every file in order, each streamed on its own, for nodes which predate [`Segment`]s
+	#[must_use]
+	pub fn streamed(requests: &[SpaceblockRequest]) -> Vec<Self> {
+		(0..requests.len()).map(Self::Streamed).collect()
+	}
+}
+
+/// Where a file is in the data of a [`Segment`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentEntry {
+	pub name: String,
+	pub offset: u64,
+	pub len: u64,
+	/// BLAKE3 hash of the file, so a file mixed up with its neighbours isn't saved
+	pub hash: [u8; 32],
+}
+
+/// Many small files sent as one, an index of where each of them is followed by all their data
+#[derive(Debug, PartialEq, Eq)]
+pub struct Segment {
+	pub entries: Vec<SegmentEntry>,
+	pub data: Vec<u8>,
+}
+
+impl Segment {
+	#[must_use]
+	pub fn pack<'a>(files: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Self {
+		let mut entries = Vec::new();
+		let mut data = Vec::new();
+
+		for (name, file) in files {
+			entries.push(SegmentEntry {
+				name: name.to_string(),
+				offset: data.len() as u64,
+				len: file.len() as u64,
+				hash: *blake3::hash(file).as_bytes(),
+			});
+			data.extend_from_slice(file);
+		}
+
+		Self { entries, data }
+	}
+
+	/// Each file with its data, in the order they were packed.
+	/// The entries were checked against the data when the segment was read.
+	pub fn files(&self) -> impl Iterator<Item = (&SegmentEntry, &[u8])> {
+		self.entries.iter().map(|entry| {
+			let start = entry.offset as usize;
+			(entry, &self.data[start..start + entry.len as usize])
+		})
+	}
+
+	pub async fn from_stream(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Self> {
+		let invalid = |message: String| io::Error::new(ErrorKind::InvalidData, message);
+
+		let len = stream.read_u32_le().await?;
+		// A segment packs some of the files of a Spacedrop, so it can't have more than all of them
+		if len > MAX_SPACEDROP_FILES {
+			return Err(invalid(format!(
+				"segment of {len} files is over the maximum of {MAX_SPACEDROP_FILES}"
+			)));
+		}
+		let mut entries = Vec::new();
+		for _ in 0..len {
+			let name = decode::string(stream)
+				.await
+				.map_err(|err| invalid(format!("invalid segment entry name: {err:?}")))?;
+			let offset = stream.read_u64_le().await?;
+			let len = stream.read_u64_le().await?;
+			let mut hash = [0; 32];
+			stream.read_exact(&mut hash).await?;
+
+			entries.push(SegmentEntry {
+				name,
+				offset,
+				len,
+				hash,
+			});
+		}
+
+		let data_len = stream.read_u64_le().await?;
+		if data_len > MAX_SEGMENT_SIZE {
+			return Err(invalid(format!(
+				"segment of {data_len} bytes is larger than the maximum of {MAX_SEGMENT_SIZE}"
+			)));
+		}
+
+		let mut data = vec![0; data_len as usize];
+		stream.read_exact(&mut data).await?;
+
+		for entry in &entries {
+			let file = entry
+				.offset
+				.checked_add(entry.len)
+				.filter(|end| *end <= data_len)
+				.map(|end| &data[entry.offset as usize..end as usize])
+				.ok_or_else(|| invalid(format!("'{}' is outside of its segment", entry.name)))?;
+
+			if *blake3::hash(file).as_bytes() != entry.hash {
+				return Err(invalid(format!("'{}' doesn't match its hash", entry.name)));
+			}
+		}
+
+		Ok(Self { entries, data })
+	}
+
+	#[must_use]
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(self.data.len() + self.entries.len() * 64 + 12);
+
+		buf.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+		for entry in &self.entries {
+			encode::string(&mut buf, &entry.name);
+			buf.extend_from_slice(&entry.offset.to_le_bytes());
+			buf.extend_from_slice(&entry.len.to_le_bytes());
+			buf.extend_from_slice(&entry.hash);
+		}
+		buf.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+		buf.extend_from_slice(&self.data);
+		buf
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	fn request(size: u64) -> SpaceblockRequest {
+		SpaceblockRequest {
+			name: format!("{size}"),
+			size,
+			range: Range::Full,
+			modified_at: None,
+			created_at: None,
+			mode: None,
+		}
+	}
+
+	#[test]
+	fn small_files_next_to_each_other_are_packed() {
+		let requests = [1, 2, PACK_THRESHOLD + 1, 3, PACK_THRESHOLD, 4].map(request);
+		assert_eq!(
+			TransferUnit::plan(&requests),
+			[
+				TransferUnit::Packed(0..2),
+				TransferUnit::Streamed(2),
+				TransferUnit::Packed(3..6),
+			]
+		);
+
+		// A segment is closed once the next file doesn't fit in it anymore
+		let per_segment = (MAX_SEGMENT_SIZE / PACK_THRESHOLD) as usize;
+		let requests = vec![request(PACK_THRESHOLD); per_segment + 1];
+		assert_eq!(
+			TransferUnit::plan(&requests),
+			[
+				TransferUnit::Packed(0..per_segment),
+				TransferUnit::Packed(per_segment..per_segment + 1),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_segment() {
+		let segment = Segment::pack([("a", b"Space".as_ref()), ("", b""), ("b/c", b"drive")]);
+		let bytes = segment.to_bytes();
+		let segment2 = Segment::from_stream(&mut Cursor::new(bytes)).await.unwrap();
+		assert_eq!(segment, segment2);
+		assert_eq!(
+			segment2
+				.files()
+				.map(|(entry, data)| (entry.name.as_str(), data))
+				.collect::<Vec<_>>(),
+			[("a", b"Space".as_ref()), ("", b""), ("b/c", b"drive")]
+		);
+
+		let mut corrupted = segment.to_bytes();
+		let last = corrupted.len() - 1;
+		corrupted[last] ^= 1;
+		let err = Segment::from_stream(&mut Cursor::new(corrupted))
+			.await
+			.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::InvalidData);
+	}
+}
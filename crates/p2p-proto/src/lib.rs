@@ -32,6 +32,8 @@ pub mod decode {
 		NameFormatError(#[from] std::string::FromUtf8Error),
 		#[error("InvalidRemoteIdentity({0})")]
 		InvalidRemoteIdentity(#[from] SpaceTunnelIdentityErr),
+		#[error("TooLong({len} > {max})")]
+		TooLong { len: usize, max: usize },
 	}
 
 	/// Deserialize uuid as it's fixed size data.
@@ -51,6 +53,22 @@ pub mod decode {
 		String::from_utf8(buf).map_err(Into::into)
 	}
 
+	/// Deserialize string like [`string`], refusing ones longer than `max` bytes before allocating them.
+	pub async fn string_max(
+		stream: &mut (impl AsyncRead + Unpin),
+		max: usize,
+	) -> Result<String, Error> {
+		let len = stream.read_u16_le().await? as usize;
+		if len > max {
+			return Err(Error::TooLong { len, max });
+		}
+
+		let mut buf = vec![0u8; len];
+		stream.read_exact(&mut buf).await?;
+
+		String::from_utf8(buf).map_err(Into::into)
+	}
+
 	/// Deserialize buf as it's u16 length and data.
 	pub async fn buf(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, Error> {
 		let len = stream.read_u32_le().await?;
@@ -60,6 +78,22 @@ pub mod decode {
 
 		Ok(buf)
 	}
+
+	/// Deserialize buf like [`buf`], refusing ones longer than `max` before allocating them.
+	pub async fn buf_max(
+		stream: &mut (impl AsyncRead + Unpin),
+		max: usize,
+	) -> Result<Vec<u8>, Error> {
+		let len = stream.read_u32_le().await? as usize;
+		if len > max {
+			return Err(Error::TooLong { len, max });
+		}
+
+		let mut buf = vec![0u8; len];
+		stream.read_exact(&mut buf).await?;
+
+		Ok(buf)
+	}
 }
 
 pub mod encode {
@@ -1,5 +1,6 @@
 //! A system for creating encrypted tunnels between peers over untrusted connections.
 
+pub mod testing;
 mod tunnel;
 
 pub use sd_p2p::{Identity, IdentityErr, RemoteIdentity};
@@ -0,0 +1,196 @@
+//! Streams which misbehave on purpose, to test what goes over a [`Tunnel`] on an unstable link.
+
+use std::{
+	io,
+	pin::Pin,
+	sync::{Arc, Mutex, PoisonError},
+	task::{ready, Context, Poll},
+};
+
+use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+use sd_p2p::{Identity, UnicastStream};
+
+use crate::{KeepAlive, Tunnel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+	Pass,
+	/// Pass this many more bytes, then drop everything
+	PassThenDrop(usize),
+	Hold,
+	Drop,
+}
+
+/// Controls what gets through one end of a [`FaultyStream`] pair, from the end it's for to the other.
+///
+/// Writes always succeed so the end doesn't know anything is wrong, like on a link which went down.
+#[derive(Debug, Clone)]
+pub struct Faults(Arc<Mutex<Mode>>);
+
+impl Faults {
+	fn set(&self, mode: Mode) {
+		*self.0.lock().unwrap_or_else(PoisonError::into_inner) = mode;
+	}
+
+	fn mode(&self) -> Mode {
+		*self.0.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	/// Keep what's written from now on until [`Self::release`]
+	pub fn hold(&self) {
+		self.set(Mode::Hold);
+	}
+
+	/// Let everything through again, what was held goes out with the next write or flush
+	pub fn release(&self) {
+		self.set(Mode::Pass);
+	}
+
+	/// Drop everything written from now on
+	pub fn drop_all(&self) {
+		self.set(Mode::Drop);
+	}
+
+	/// Let `bytes` more bytes through then drop everything, to cut the link in the middle of a message
+	pub fn drop_after(&self, bytes: usize) {
+		self.set(Mode::PassThenDrop(bytes));
+	}
+}
+
+/// One end of an in memory duplex stream whose writes can be held back or dropped with its [`Faults`]
+#[derive(Debug)]
+pub struct FaultyStream {
+	inner: DuplexStream,
+	faults: Faults,
+	held: Vec<u8>,
+}
+
+impl FaultyStream {
+	/// A connected pair of streams, each with the [`Faults`] of what it sends.
+	/// Writes wait for the other end once `max_buf_size` bytes are waiting for it to read.
+	pub fn pair(max_buf_size: usize) -> ((Self, Faults), (Self, Faults)) {
+		let (a, b) = duplex(max_buf_size);
+		let end = |inner| {
+			let faults = Faults(Arc::new(Mutex::new(Mode::Pass)));
+			(
+				Self {
+					inner,
+					faults: faults.clone(),
+					held: Vec::new(),
+				},
+				faults,
+			)
+		};
+
+		(end(a), end(b))
+	}
+
+	fn poll_release_held(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		while !self.held.is_empty() {
+			let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.held))?;
+			self.held.drain(..n);
+		}
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsyncRead for FaultyStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for FaultyStream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+
+		match this.faults.mode() {
+			Mode::Pass => {
+				ready!(this.poll_release_held(cx))?;
+				Pin::new(&mut this.inner).poll_write(cx, buf)
+			}
+			Mode::PassThenDrop(0) | Mode::Drop => Poll::Ready(Ok(buf.len())),
+			Mode::PassThenDrop(left) => {
+				let n =
+					ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..buf.len().min(left)]))?;
+				this.faults.set(Mode::PassThenDrop(left - n));
+				Poll::Ready(Ok(n))
+			}
+			Mode::Hold => {
+				this.held.extend_from_slice(buf);
+				Poll::Ready(Ok(buf.len()))
+			}
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+
+		if this.faults.mode() == Mode::Pass {
+			ready!(this.poll_release_held(cx))?;
+		}
+
+		Pin::new(&mut this.inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+
+		match this.faults.mode() {
+			Mode::Pass => {
+				ready!(this.poll_release_held(cx))?;
+				Pin::new(&mut this.inner).poll_shutdown(cx)
+			}
+			// The other end never hears about it, like the rest of what was written
+			_ => Poll::Ready(Ok(())),
+		}
+	}
+}
+
+/// The two ends of a tunnel over a [`FaultyStream`] pair
+#[derive(Debug)]
+pub struct FaultyTunnels {
+	pub initiator: Tunnel,
+	pub responder: Tunnel,
+	/// What gets from the initiator to the responder
+	pub initiator_faults: Faults,
+	/// What gets from the responder to the initiator
+	pub responder_faults: Faults,
+}
+
+/// Set up a tunnel over a [`FaultyStream`] pair, both ends using `keep_alive`
+pub async fn faulty_tunnels(keep_alive: KeepAlive, max_buf_size: usize) -> FaultyTunnels {
+	let ((initiator, initiator_faults), (responder, responder_faults)) =
+		FaultyStream::pair(max_buf_size);
+
+	// The initiator's handshake fits in the duplex buffer so it doesn't wait on the responder
+	let initiator = Tunnel::initiator_with(
+		UnicastStream::new(Identity::default().to_remote_identity(), initiator),
+		keep_alive,
+	)
+	.await
+	.expect("in memory tunnel handshake can't fail");
+	let responder = Tunnel::responder_with(
+		UnicastStream::new(Identity::default().to_remote_identity(), responder),
+		keep_alive,
+	)
+	.await
+	.expect("in memory tunnel handshake can't fail");
+
+	FaultyTunnels {
+		initiator,
+		responder,
+		initiator_faults,
+		responder_faults,
+	}
+}
@@ -1,44 +1,338 @@
 use std::{
-	io,
+	error, fmt, io,
 	pin::Pin,
-	task::{Context, Poll},
+	sync::{Arc, OnceLock},
+	task::{ready, Context, Poll},
+	time::Duration,
 };
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::{
+	io::{
+		duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+	},
+	time::{sleep, timeout},
+};
 
 use sd_p2p::UnicastStream;
 
+/// What the initiator sends first, so the responder knows the stream is a tunnel
+const DISCRIMINATOR: u8 = b'K';
+/// What the initiator sent before tunnels were framed, see [`Tunnel::unframed_initiator`]
+const UNFRAMED_DISCRIMINATOR: u8 = b'T';
+
+/// A data frame is this followed by a `u32` length prefixed chunk of what was written to the tunnel
+const FRAME_DATA: u8 = 0;
+/// Sent when we haven't sent anything for [`KeepAlive::interval`], it carries nothing
+const FRAME_KEEP_ALIVE: u8 = 1;
+
+/// The most bytes a single data frame carries
+const MAX_DATA_FRAME: usize = 64 * 1024;
+/// How much can be written to a tunnel before writes wait for its driver to send it
+const MAX_PENDING_WRITE: usize = 2 * MAX_DATA_FRAME;
+
+/// How often a [`Tunnel`] tells the remote it's still there, and how many of those can be missed
+/// before the remote is considered dead.
+///
+/// Both ends should use the same interval, as each one expects the other to send at its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAlive {
+	pub interval: Duration,
+	pub max_missed: u32,
+}
+
+impl Default for KeepAlive {
+	fn default() -> Self {
+		Self {
+			interval: Duration::from_secs(15),
+			max_missed: 3,
+		}
+	}
+}
+
+impl KeepAlive {
+	/// How long the remote can go without sending anything, or without accepting what we write,
+	/// before it's considered dead
+	pub fn dead_after(&self) -> Duration {
+		self.interval * self.max_missed.max(1)
+	}
+}
+
+/// The remote of a [`Tunnel`] stopped sending keep-alives, the link to it probably went down.
+///
+/// Reads and writes on the tunnel fail with an [`io::ErrorKind::TimedOut`] error wrapping this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStoppedResponding {
+	pub silent_for: Duration,
+}
+
+impl PeerStoppedResponding {
+	/// The death of the tunnel `err` is about, if it's about one
+	pub fn from_io(err: &io::Error) -> Option<Self> {
+		err.get_ref()
+			.and_then(|err| err.downcast_ref::<Self>())
+			.copied()
+	}
+}
+
+impl fmt::Display for PeerStoppedResponding {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"the remote of the tunnel didn't send or accept anything for {:?}",
+			self.silent_for
+		)
+	}
+}
+
+impl error::Error for PeerStoppedResponding {}
+
+/// Why the driver of a tunnel stopped, what's done with the tunnel after fails with it
+#[derive(Debug, Clone)]
+enum Failure {
+	Stopped(PeerStoppedResponding),
+	Io(io::ErrorKind, String),
+}
+
+impl Failure {
+	fn to_io(&self) -> io::Error {
+		match self {
+			Self::Stopped(stopped) => io::Error::new(io::ErrorKind::TimedOut, *stopped),
+			Self::Io(kind, err) => io::Error::new(*kind, err.clone()),
+		}
+	}
+}
+
+impl From<io::Error> for Failure {
+	fn from(err: io::Error) -> Self {
+		Self::Io(err.kind(), err.to_string())
+	}
+}
+
+#[derive(Debug)]
+enum Inner {
+	/// Carried over the stream in frames by the tunnel's [`drive`]r
+	Framed {
+		local: DuplexStream,
+		failure: Arc<OnceLock<Failure>>,
+	},
+	/// A tunnel with a node from before they were framed, it's the stream as is
+	Unframed(Box<UnicastStream>),
+}
+
 #[derive(Debug)]
 pub struct Tunnel {
-	stream: UnicastStream,
+	inner: Inner,
+	keep_alive: KeepAlive,
 }
 
 impl Tunnel {
 	// TODO: Proper errors
-	pub async fn initiator(mut stream: UnicastStream) -> Result<Self, &'static str> {
+	pub async fn initiator(stream: UnicastStream) -> Result<Self, &'static str> {
+		Self::initiator_with(stream, KeepAlive::default()).await
+	}
+
+	// TODO: Proper errors
+	pub async fn initiator_with(
+		mut stream: UnicastStream,
+		keep_alive: KeepAlive,
+	) -> Result<Self, &'static str> {
 		stream
-			.write_all(&[b'T'])
+			.write_all(&[DISCRIMINATOR])
 			.await
 			.map_err(|_| "Error writing discriminator")?;
 
 		// TODO: Do pairing + authentication
 
-		Ok(Self { stream })
+		Ok(Self::framed(stream, keep_alive))
+	}
+
+	/// A tunnel to a node from before tunnels were framed, which wouldn't understand a framed one.
+	/// It has no keep-alives, so a remote which went away is only noticed once the stream fails.
+	// TODO: Proper errors
+	pub async fn unframed_initiator(mut stream: UnicastStream) -> Result<Self, &'static str> {
+		stream
+			.write_all(&[UNFRAMED_DISCRIMINATOR])
+			.await
+			.map_err(|_| "Error writing discriminator")?;
+
+		Ok(Self {
+			inner: Inner::Unframed(Box::new(stream)),
+			keep_alive: KeepAlive::default(),
+		})
 	}
 
 	// TODO: Proper errors
-	pub async fn responder(mut stream: UnicastStream) -> Result<Self, &'static str> {
+	pub async fn responder(stream: UnicastStream) -> Result<Self, &'static str> {
+		Self::responder_with(stream, KeepAlive::default()).await
+	}
+
+	/// Accepts tunnels from nodes from before they were framed too, see [`Self::unframed_initiator`]
+	// TODO: Proper errors
+	pub async fn responder_with(
+		mut stream: UnicastStream,
+		keep_alive: KeepAlive,
+	) -> Result<Self, &'static str> {
 		let discriminator = stream
 			.read_u8()
 			.await
 			.map_err(|_| "Error reading discriminator. Is this stream actually a tunnel?")?;
-		if discriminator != b'T' {
-			return Err("Invalid discriminator. Is this stream actually a tunnel?");
-		}
 
 		// TODO: Do pairing + authentication
 
-		Ok(Self { stream })
+		match discriminator {
+			DISCRIMINATOR => Ok(Self::framed(stream, keep_alive)),
+			UNFRAMED_DISCRIMINATOR => Ok(Self {
+				inner: Inner::Unframed(Box::new(stream)),
+				keep_alive,
+			}),
+			_ => Err("Invalid discriminator. Is this stream actually a tunnel?"),
+		}
+	}
+
+	fn framed(stream: UnicastStream, keep_alive: KeepAlive) -> Self {
+		let (local, remote) = duplex(MAX_PENDING_WRITE);
+		let failure = Arc::new(OnceLock::new());
+		tokio::spawn(drive(stream, remote, keep_alive, failure.clone()));
+
+		Self {
+			inner: Inner::Framed { local, failure },
+			keep_alive,
+		}
+	}
+
+	pub fn keep_alive(&self) -> KeepAlive {
+		self.keep_alive
+	}
+
+	/// Whether the remote is from before tunnels were framed
+	pub fn is_unframed(&self) -> bool {
+		matches!(self.inner, Inner::Unframed(_))
+	}
+}
+
+/// Carry what's written to the tunnel over `stream` in frames and what the remote sends back to it, until either end closes.
+///
+/// Keep-alives are sent whenever we didn't send anything for a while and the remote is expected to do the same.
+/// This runs on its own, so a remote which went away is noticed even while the tunnel isn't being used.
+async fn drive(
+	stream: UnicastStream,
+	tunnel: DuplexStream,
+	keep_alive: KeepAlive,
+	failure: Arc<OnceLock<Failure>>,
+) {
+	let dead_after = keep_alive.dead_after();
+	let stopped = Failure::Stopped(PeerStoppedResponding {
+		silent_for: dead_after,
+	});
+	let (mut stream_rx, mut stream_tx) = split(stream);
+	let (mut tunnel_rx, mut tunnel_tx) = split(tunnel);
+
+	let receive = async {
+		let mut buf = vec![0; MAX_DATA_FRAME];
+		loop {
+			let kind = match timeout(dead_after, stream_rx.read_u8()).await {
+				Ok(Ok(kind)) => kind,
+				// Closed between frames, which is the end of the tunnel
+				Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+					tunnel_tx.shutdown().await.ok();
+					return Ok(());
+				}
+				Ok(Err(err)) => return Err(err.into()),
+				Err(_) => return Err(stopped.clone()),
+			};
+
+			match kind {
+				FRAME_KEEP_ALIVE => {}
+				FRAME_DATA => {
+					let mut len = match timeout(dead_after, stream_rx.read_u32()).await {
+						Ok(len) => len.map_err(Failure::from)? as usize,
+						Err(_) => return Err(stopped.clone()),
+					};
+					if len > MAX_DATA_FRAME {
+						return Err(Failure::Io(
+							io::ErrorKind::InvalidData,
+							format!("Tunnel frame of '{len}' bytes is too large"),
+						));
+					}
+
+					while len > 0 {
+						let n = match timeout(dead_after, stream_rx.read(&mut buf[..len])).await {
+							Ok(Ok(0)) => {
+								return Err(Failure::Io(
+									io::ErrorKind::UnexpectedEof,
+									"The tunnel was closed in the middle of a frame".into(),
+								))
+							}
+							Ok(n) => n.map_err(Failure::from)?,
+							Err(_) => return Err(stopped.clone()),
+						};
+						len -= n;
+
+						// Waiting on the tunnel to be read isn't the remote's fault, so this isn't timed
+						if tunnel_tx.write_all(&buf[..n]).await.is_err() {
+							// The tunnel was dropped, what's left is of no use
+							return Ok(());
+						}
+					}
+				}
+				kind => {
+					return Err(Failure::Io(
+						io::ErrorKind::InvalidData,
+						format!("Invalid tunnel frame kind '{kind}'"),
+					))
+				}
+			}
+		}
+	};
+
+	let send = async {
+		let mut buf = vec![0; MAX_DATA_FRAME];
+		loop {
+			let frame = tokio::select! {
+				read = tunnel_rx.read(&mut buf) => match read {
+					Ok(0) | Err(_) => {
+						timeout(dead_after, stream_tx.shutdown()).await.ok();
+						return Ok(());
+					}
+					Ok(n) => {
+						let mut frame = Vec::with_capacity(5 + n);
+						frame.push(FRAME_DATA);
+						frame.extend_from_slice(&(n as u32).to_be_bytes());
+						frame.extend_from_slice(&buf[..n]);
+						frame
+					}
+				},
+				_ = sleep(keep_alive.interval) => vec![FRAME_KEEP_ALIVE],
+			};
+
+			let written = async {
+				stream_tx.write_all(&frame).await?;
+				stream_tx.flush().await
+			};
+			match timeout(dead_after, written).await {
+				Ok(result) => result.map_err(Failure::from)?,
+				Err(_) => return Err(stopped.clone()),
+			}
+		}
+	};
+
+	tokio::pin!(receive, send);
+	let result: Result<(), Failure> = tokio::select! {
+		Err(err) = &mut receive => Err(err),
+		Err(err) = &mut send => Err(err),
+		else => Ok(()),
+	};
+
+	// Set before the tunnel's end of the pipe is dropped, so it's there once the tunnel notices
+	if let Err(err) = result {
+		failure.set(err).ok();
+	}
+}
+
+impl Inner {
+	/// The error the tunnel failed with instead of `err`, if it did
+	fn failure_or(failure: &OnceLock<Failure>, err: io::Error) -> io::Error {
+		failure.get().map_or(err, Failure::to_io)
 	}
 }
 
@@ -50,7 +344,23 @@ impl AsyncRead for Tunnel {
 	) -> Poll<io::Result<()>> {
 		// TODO: Do decryption
 
-		Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+		match &mut self.get_mut().inner {
+			Inner::Unframed(stream) => Pin::new(stream).poll_read(cx, buf),
+			Inner::Framed { local, failure } => {
+				let filled = buf.filled().len();
+				if let Err(err) = ready!(Pin::new(local).poll_read(cx, buf)) {
+					return Poll::Ready(Err(Inner::failure_or(failure, err)));
+				}
+
+				// The end of the tunnel, unless its driver stopped because of a failure
+				match failure.get() {
+					Some(failure) if buf.filled().len() == filled && buf.remaining() > 0 => {
+						Poll::Ready(Err(failure.to_io()))
+					}
+					_ => Poll::Ready(Ok(())),
+				}
+			}
+		}
 	}
 }
 
@@ -62,16 +372,201 @@ impl AsyncWrite for Tunnel {
 	) -> Poll<io::Result<usize>> {
 		// TODO: Do encryption
 
-		Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+		match &mut self.get_mut().inner {
+			Inner::Unframed(stream) => Pin::new(stream).poll_write(cx, buf),
+			Inner::Framed { local, failure } => Pin::new(local)
+				.poll_write(cx, buf)
+				.map_err(|err| Inner::failure_or(failure, err)),
+		}
 	}
 
 	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-		Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+		match &mut self.get_mut().inner {
+			Inner::Unframed(stream) => Pin::new(stream).poll_flush(cx),
+			Inner::Framed { local, failure } => Pin::new(local)
+				.poll_flush(cx)
+				.map_err(|err| Inner::failure_or(failure, err)),
+		}
 	}
 
 	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-		Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+		match &mut self.get_mut().inner {
+			Inner::Unframed(stream) => Pin::new(stream).poll_shutdown(cx),
+			Inner::Framed { local, failure } => Pin::new(local)
+				.poll_shutdown(cx)
+				.map_err(|err| Inner::failure_or(failure, err)),
+		}
 	}
 }
 
-// TODO: Unit tests
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use tokio::time::timeout;
+
+	use crate::testing::{faulty_tunnels, FaultyTunnels};
+
+	use super::*;
+
+	const SHORT: KeepAlive = KeepAlive {
+		interval: Duration::from_millis(20),
+		max_missed: 3,
+	};
+
+	#[tokio::test]
+	async fn keep_alives_dont_get_in_the_way_of_data() {
+		let FaultyTunnels {
+			mut initiator,
+			mut responder,
+			..
+		} = faulty_tunnels(SHORT, 1024).await;
+
+		let sent = (0..300_000u32).map(|i| i as u8).collect::<Vec<_>>();
+		let write = async {
+			for chunk in sent.chunks(7_001) {
+				initiator.write_all(chunk).await.unwrap();
+				// Long enough for keep-alives to go out in between
+				sleep(Duration::from_millis(1)).await;
+			}
+			initiator.shutdown().await.unwrap();
+		};
+		let read = async {
+			let mut received = vec![];
+			responder.read_to_end(&mut received).await.unwrap();
+			received
+		};
+
+		let ((), received) = tokio::join!(write, read);
+		assert_eq!(received, sent);
+	}
+
+	#[tokio::test]
+	async fn idle_tunnels_stay_alive() {
+		let FaultyTunnels {
+			mut initiator,
+			mut responder,
+			..
+		} = faulty_tunnels(SHORT, 1024).await;
+
+		// Both ends wait on each other for a lot longer than `dead_after`
+		let wait = async {
+			let mut buf = [0; 1];
+			responder.read_exact(&mut buf).await.unwrap();
+			buf
+		};
+		let answer = async {
+			let mut buf = [0; 1];
+			let late = timeout(SHORT.dead_after() * 4, initiator.read_exact(&mut buf)).await;
+			assert!(late.is_err(), "nothing was sent to the initiator");
+			initiator.write_all(&[42]).await.unwrap();
+			initiator.flush().await.unwrap();
+		};
+
+		let (received, ()) = tokio::join!(wait, answer);
+		assert_eq!(received, [42]);
+	}
+
+	#[tokio::test]
+	async fn silent_remotes_are_dead() {
+		let FaultyTunnels {
+			mut initiator,
+			mut responder,
+			responder_faults,
+			..
+		} = faulty_tunnels(SHORT, 1024).await;
+
+		// Like a link which went down, the responder thinks it's sending keep-alives but none get through
+		responder_faults.hold();
+		let responder = tokio::spawn(async move { responder.read_u8().await });
+
+		let mut buf = [0; 1];
+		let err = timeout(Duration::from_secs(5), initiator.read_exact(&mut buf))
+			.await
+			.expect("the read should fail instead of hanging")
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+		assert_eq!(
+			PeerStoppedResponding::from_io(&err),
+			Some(PeerStoppedResponding {
+				silent_for: SHORT.dead_after()
+			})
+		);
+		assert!(PeerStoppedResponding::from_io(&io::ErrorKind::TimedOut.into()).is_none());
+
+		// The initiator's keep-alives got through while it was waiting, so the responder only finds out once it hangs up
+		let err = timeout(Duration::from_secs(5), responder)
+			.await
+			.unwrap()
+			.unwrap()
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+	}
+
+	#[tokio::test]
+	async fn dead_remotes_are_noticed_while_the_tunnel_isnt_used() {
+		let FaultyTunnels {
+			initiator,
+			mut responder,
+			initiator_faults,
+			..
+		} = faulty_tunnels(SHORT, 1024).await;
+
+		// The responder is busy with something else while the link goes down
+		initiator_faults.hold();
+		sleep(SHORT.dead_after() * 2).await;
+
+		// So it finds out as soon as it gets back to the tunnel, instead of after waiting all over again
+		let err = timeout(SHORT.interval, responder.write_all(b"still there?"))
+			.await
+			.expect("the write should fail straight away")
+			.unwrap_err();
+		assert_eq!(
+			PeerStoppedResponding::from_io(&err),
+			Some(PeerStoppedResponding {
+				silent_for: SHORT.dead_after()
+			})
+		);
+		let err = timeout(SHORT.interval, responder.read_u8())
+			.await
+			.expect("the read should fail straight away")
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+		drop(initiator);
+	}
+
+	#[tokio::test]
+	async fn unframed_tunnels_are_passed_through() {
+		let (a, mut b) = duplex(1024);
+		let identity = sd_p2p::Identity::default().to_remote_identity();
+
+		// What a node from before tunnels were framed sends and expects
+		let mut initiator = Tunnel::unframed_initiator(UnicastStream::new(identity, a))
+			.await
+			.unwrap();
+		assert!(initiator.is_unframed());
+		initiator.write_all(b"hello").await.unwrap();
+		initiator.flush().await.unwrap();
+		let mut sent = [0; 6];
+		b.read_exact(&mut sent).await.unwrap();
+		assert_eq!(&sent, b"Thello");
+
+		let (a, mut b) = duplex(1024);
+		b.write_all(b"Thi").await.unwrap();
+		let mut responder = Tunnel::responder_with(UnicastStream::new(identity, a), SHORT)
+			.await
+			.unwrap();
+		assert!(responder.is_unframed());
+		let mut received = [0; 2];
+		responder.read_exact(&mut received).await.unwrap();
+		assert_eq!(&received, b"hi");
+
+		// Without keep-alives, a quiet remote isn't mistaken for a dead one
+		sleep(SHORT.dead_after() * 2).await;
+		responder.write_all(b"ok").await.unwrap();
+		responder.flush().await.unwrap();
+		let mut answer = [0; 2];
+		b.read_exact(&mut answer).await.unwrap();
+		assert_eq!(&answer, b"ok");
+	}
+}
@@ -6,6 +6,8 @@ use std::{
 };
 
 use flume::Sender;
+use serde::Serialize;
+use specta::Type;
 use tokio::sync::oneshot;
 
 use crate::{Peer, PeerConnectionCandidate, RemoteIdentity};
@@ -46,6 +48,9 @@ pub enum HookEvent {
 	/// A connection closed with a peer.
 	PeerDisconnectedWith(ListenerId, RemoteIdentity),
 
+	/// Something happened within a transport or discovery mechanism, see [`P2P::diagnostic`](crate::P2P::diagnostic).
+	Diagnostic(Diagnostic),
+
 	/// Your hook or the P2P system was told to shutdown.
 	Shutdown {
 		// We can detect when this guard is dropped, it doesn't need to be used.
@@ -53,6 +58,25 @@ pub enum HookEvent {
 	},
 }
 
+/// How much attention a [`Diagnostic`] deserves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Type)]
+pub enum DiagnosticSeverity {
+	Debug,
+	Info,
+	Warning,
+	Error,
+}
+
+/// Details about the internals of a transport or discovery mechanism, for debugging connectivity issues.
+/// Everything reported as a diagnostic is also logged.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+	pub severity: DiagnosticSeverity,
+	/// Eg. `mdns` or `quic`
+	pub subsystem: &'static str,
+	pub message: String,
+}
+
 #[derive(Debug)]
 pub struct ShutdownGuard(pub(crate) Option<oneshot::Sender<()>>);
 
@@ -6,7 +6,7 @@ use std::{
 };
 
 use base64::{engine::general_purpose, Engine};
-use ed25519_dalek::{VerifyingKey, SECRET_KEY_LENGTH};
+use ed25519_dalek::{Signature, Signer, VerifyingKey, SECRET_KEY_LENGTH, SIGNATURE_LENGTH};
 use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
 use specta::Type;
@@ -22,6 +22,8 @@ pub enum IdentityErr {
 	Dalek(#[from] ed25519_dalek::ed25519::Error),
 	#[error("Invalid key length")]
 	InvalidKeyLength,
+	#[error("Invalid identity succession")]
+	InvalidSuccession,
 }
 
 /// TODO
@@ -63,6 +65,14 @@ impl Identity {
 	pub fn to_remote_identity(&self) -> RemoteIdentity {
 		RemoteIdentity(self.0.verifying_key())
 	}
+
+	/// Sign `message` so whoever knows our [`RemoteIdentity`] can check it came from us, see [`RemoteIdentity::verify`].
+	///
+	/// Prefix the message with what it's for, so the signature can't be passed off as one for anything else.
+	#[must_use]
+	pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+		self.0.sign(message).to_bytes()
+	}
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Type)]
@@ -163,6 +173,14 @@ impl RemoteIdentity {
 	pub fn verifying_key(&self) -> VerifyingKey {
 		self.0
 	}
+
+	/// Whether `signature` is one of `message` by the [`Identity`] of this node, see [`Identity::sign`].
+	#[must_use]
+	pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+		Signature::from_slice(signature)
+			.and_then(|signature| self.0.verify_strict(message, &signature))
+			.is_ok()
+	}
 }
 
 impl From<ed25519_dalek::SigningKey> for Identity {
@@ -170,3 +188,113 @@ impl From<ed25519_dalek::SigningKey> for Identity {
 		Self(value)
 	}
 }
+
+/// Prefixed to the next identity before it's signed, so the signature of a succession can't be passed off as anything else.
+const SUCCESSION_CONTEXT: &[u8] = b"sd-p2p-identity-succession";
+
+/// A record, signed by a node's previous identity, which vouches for the identity that replaced it.
+///
+/// Nodes which knew the previous identity can check it and move whatever they hold for it over to the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentitySuccession {
+	previous: RemoteIdentity,
+	next: RemoteIdentity,
+	signature: Signature,
+}
+
+impl IdentitySuccession {
+	#[must_use]
+	pub fn new(previous: &Identity, next: &Identity) -> Self {
+		let next = next.to_remote_identity();
+
+		Self {
+			previous: previous.to_remote_identity(),
+			next,
+			signature: previous.0.sign(&Self::message(&next)),
+		}
+	}
+
+	fn message(next: &RemoteIdentity) -> Vec<u8> {
+		let mut message = SUCCESSION_CONTEXT.to_vec();
+		message.extend_from_slice(next.0.as_bytes());
+		message
+	}
+
+	#[must_use]
+	pub fn previous(&self) -> RemoteIdentity {
+		self.previous
+	}
+
+	#[must_use]
+	pub fn next(&self) -> RemoteIdentity {
+		self.next
+	}
+
+	#[must_use]
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(REMOTE_IDENTITY_LEN * 2 + SIGNATURE_LENGTH);
+		bytes.extend_from_slice(self.previous.0.as_bytes());
+		bytes.extend_from_slice(self.next.0.as_bytes());
+		bytes.extend_from_slice(&self.signature.to_bytes());
+		bytes
+	}
+
+	/// Decode a succession record, which is only returned if it was signed by its previous identity.
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, IdentityErr> {
+		if bytes.len() != REMOTE_IDENTITY_LEN * 2 + SIGNATURE_LENGTH {
+			return Err(IdentityErr::InvalidKeyLength);
+		}
+
+		let (previous, rest) = bytes.split_at(REMOTE_IDENTITY_LEN);
+		let (next, signature) = rest.split_at(REMOTE_IDENTITY_LEN);
+
+		let succession = Self {
+			previous: RemoteIdentity::from_bytes(previous)?,
+			next: RemoteIdentity::from_bytes(next)?,
+			signature: Signature::from_slice(signature)?,
+		};
+
+		succession
+			.previous
+			.0
+			.verify_strict(&Self::message(&succession.next), &succession.signature)
+			.map_err(|_| IdentityErr::InvalidSuccession)?;
+
+		Ok(succession)
+	}
+}
+
+impl std::fmt::Display for IdentitySuccession {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&general_purpose::STANDARD_NO_PAD.encode(self.to_bytes()))
+	}
+}
+
+impl FromStr for IdentitySuccession {
+	type Err = IdentityErr;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::from_bytes(
+			&general_purpose::STANDARD_NO_PAD
+				.decode(s)
+				.map_err(|_| IdentityErr::InvalidSuccession)?,
+		)
+	}
+}
+
+impl Serialize for IdentitySuccession {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&self.to_string())
+	}
+}
+
+impl<'de> Deserialize<'de> for IdentitySuccession {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		String::deserialize(deserializer)?
+			.parse()
+			.map_err(serde::de::Error::custom)
+	}
+}
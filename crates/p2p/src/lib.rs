@@ -4,18 +4,22 @@
 pub(crate) mod hooks;
 mod identity;
 mod mdns;
+mod memory;
 mod p2p;
 mod peer;
 mod quic;
+mod reachability;
 mod smart_guards;
 mod stream;
 
-pub use hooks::{HookEvent, HookId, ListenerId, ShutdownGuard};
-pub use identity::{Identity, IdentityErr, RemoteIdentity};
+pub use hooks::{Diagnostic, DiagnosticSeverity, HookEvent, HookId, ListenerId, ShutdownGuard};
+pub use identity::{Identity, IdentityErr, IdentitySuccession, RemoteIdentity};
 pub use mdns::Mdns;
+pub use memory::{LinkConditions, MemoryNetwork, MemoryTransport};
 pub use p2p::{Listener, P2P};
-pub use peer::{ConnectionRequest, Peer, PeerConnectionCandidate};
+pub use peer::{ConnectionAddr, ConnectionRequest, NewStreamError, Peer, PeerConnectionCandidate};
 pub use quic::{Libp2pPeerId, QuicTransport, RelayServerEntry};
+pub use reachability::{by_preference, race_candidates, AddressPreference, ProbedAddr};
 pub use smart_guards::SmartWriteGuard;
 pub use stream::UnicastStream;
 
@@ -4,10 +4,13 @@ use std::{
 
 use flume::{bounded, Receiver};
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
-use tokio::time::{sleep_until, Instant, Sleep};
+use tokio::time::{sleep_until, timeout, Instant, Sleep};
 use tracing::{error, trace, warn};
 
-use crate::{HookEvent, HookId, PeerConnectionCandidate, RemoteIdentity, ShutdownGuard, P2P};
+use crate::{
+	DiagnosticSeverity, HookEvent, HookId, PeerConnectionCandidate, RemoteIdentity, ShutdownGuard,
+	P2P,
+};
 
 /// The time between re-advertising the mDNS service.
 const MDNS_READVERTISEMENT_INTERVAL: Duration = Duration::from_secs(60); // Every minute re-advertise
@@ -32,6 +35,46 @@ impl Mdns {
 	pub async fn shutdown(self) {
 		self.p2p.unregister_hook(self.hook_id).await;
 	}
+
+	/// Browse for our own service like the other nodes on the network do, returning how long it took to show up.
+	/// This fails when nothing answered within `wait`, eg. if the network drops multicast traffic.
+	pub async fn probe(p2p: &P2P, wait: Duration) -> Result<Duration, String> {
+		let service_domain = service_domain(p2p);
+		let service_name = service_name(p2p, &service_domain);
+		let daemon = ServiceDaemon::new().map_err(|err| err.to_string())?;
+		let events = daemon
+			.browse(&service_domain)
+			.map_err(|err| err.to_string())?;
+
+		let start = Instant::now();
+		let result = timeout(wait, async {
+			while let Ok(event) = events.recv_async().await {
+				if let ServiceEvent::ServiceResolved(info) = event {
+					if info.get_fullname() == service_name {
+						return Ok(start.elapsed());
+					}
+				}
+			}
+
+			Err("the mDNS daemon stopped".to_string())
+		})
+		.await
+		.unwrap_or_else(|_| Err(format!("our service wasn't found within {wait:?}")));
+
+		if let Err(err) = daemon.shutdown() {
+			warn!("error shutting down the mdns probe daemon: {err}");
+		}
+
+		result
+	}
+}
+
+fn service_domain(p2p: &P2P) -> String {
+	format!("_{}._udp.local.", p2p.app_name())
+}
+
+fn service_name(p2p: &P2P, service_domain: &str) -> String {
+	format!("{}.{service_domain}", p2p.remote_identity())
 }
 
 struct State {
@@ -44,10 +87,10 @@ struct State {
 }
 
 fn start(p2p: Arc<P2P>, hook_id: HookId, rx: Receiver<HookEvent>) -> Result<(), mdns_sd::Error> {
-	let service_domain = format!("_{}._udp.local.", p2p.app_name());
+	let service_domain = service_domain(&p2p);
 	let mut state = State {
 		hook_id,
-		service_name: format!("{}.{service_domain}", p2p.remote_identity()),
+		service_name: service_name(&p2p, &service_domain),
 		service_domain,
 		p2p,
 		mdns_daemon: ServiceDaemon::new()?,
@@ -56,6 +99,8 @@ fn start(p2p: Arc<P2P>, hook_id: HookId, rx: Receiver<HookEvent>) -> Result<(),
 		)),
 	};
 	let mdns_service = state.mdns_daemon.browse(&state.service_domain)?;
+	// The listeners may have been bound long before us, so don't leave them unannounced until the next interval
+	advertise(&mut state);
 
 	tokio::spawn(async move {
 		loop {
@@ -103,6 +148,11 @@ fn advertise(state: &mut State) {
 			Ok(service) => service,
 			Err(err) => {
 				warn!("error creating mdns service info: {}", err);
+				state.p2p.diagnostic(
+					DiagnosticSeverity::Warning,
+					"mdns",
+					format!("failed to create service info for port {port}: {err}"),
+				);
 				continue;
 			}
 		};
@@ -110,7 +160,14 @@ fn advertise(state: &mut State) {
 		trace!("advertising mdns service: {:?}", service);
 		match state.mdns_daemon.register(service) {
 			Ok(()) => {}
-			Err(err) => warn!("error registering mdns service: {}", err),
+			Err(err) => {
+				warn!("error registering mdns service: {}", err);
+				state.p2p.diagnostic(
+					DiagnosticSeverity::Warning,
+					"mdns",
+					format!("failed to announce service on port {port}: {err}"),
+				);
+			}
 		}
 	}
 
@@ -171,11 +228,21 @@ fn fullname_to_identity(
 			"resolved peer advertising itself with an invalid fullname '{}'",
 			fullname
 		);
+		p2p.diagnostic(
+			DiagnosticSeverity::Debug,
+			"mdns",
+			format!("ignored peer with an invalid fullname '{fullname}'"),
+		);
 		return None;
 	};
 
 	let Ok(identity) = RemoteIdentity::from_str(identity) else {
 		warn!("resolved peer advertising itself with an invalid remote identity '{identity}'");
+		p2p.diagnostic(
+			DiagnosticSeverity::Debug,
+			"mdns",
+			format!("ignored peer with an invalid remote identity '{identity}'"),
+		);
 		return None;
 	};
 
@@ -212,3 +279,27 @@ fn shutdown(_guard: ShutdownGuard, state: &mut State) {
 		}
 	}
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use crate::Identity;
+
+	use super::*;
+
+	#[tokio::test(flavor = "multi_thread")]
+	async fn probe_finds_a_running_responder() {
+		let (tx, _rx) = bounded(5);
+		let p2p = P2P::new("sd-probe", Identity::new(), tx);
+		let (hook_tx, _hook_rx) = bounded(15);
+		let listener = p2p.register_listener("test", hook_tx, |_, _, _| {});
+		p2p.register_listener_addr(listener, "127.0.0.1:7373".parse().unwrap());
+
+		// Spawned after the listener is bound, so it must announce it without waiting for the next interval
+		let mdns = Mdns::spawn(p2p.clone()).unwrap();
+		Mdns::probe(&p2p, Duration::from_secs(5)).await.unwrap();
+
+		mdns.shutdown().await;
+		assert!(Mdns::probe(&p2p, Duration::from_secs(2)).await.is_err());
+	}
+}
@@ -0,0 +1,469 @@
+use std::{
+	collections::{BTreeSet, HashMap},
+	net::SocketAddr,
+	sync::{Arc, Mutex, PoisonError, Weak},
+	time::Duration,
+};
+
+use flume::{bounded, Receiver};
+use tokio::{
+	io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+	sync::{mpsc, oneshot},
+	time::{sleep, sleep_until, Instant},
+};
+use tracing::debug;
+
+use crate::{
+	quic::utils::identity_to_libp2p_keypair, ConnectionRequest, HookEvent, Libp2pPeerId,
+	ListenerId, RelayServerEntry, RemoteIdentity, UnicastStream, P2P,
+};
+
+/// How much each direction of a stream holds before writes wait for the other end to read
+const STREAM_BUF_SIZE: usize = 64 * 1024;
+
+/// The most a link with [`LinkConditions`] carries at once
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How many chunks can be on their way over a link with latency
+const CHUNKS_IN_FLIGHT: usize = 64;
+
+/// How the streams of a [`MemoryNetwork`] behave, instant and unlimited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkConditions {
+	/// How long what's written takes to reach the other end, establishing a connection takes as long
+	pub latency: Duration,
+	/// The most each direction of a stream carries, unlimited when `None`
+	pub bytes_per_sec: Option<u64>,
+}
+
+impl LinkConditions {
+	fn is_ideal(&self) -> bool {
+		self.latency.is_zero() && self.bytes_per_sec.is_none()
+	}
+
+	/// The two ends of a new stream over a link like this one
+	fn stream_pair(self) -> (DuplexStream, DuplexStream) {
+		let (a, a_link) = duplex(STREAM_BUF_SIZE);
+		if self.is_ideal() {
+			return (a, a_link);
+		}
+
+		let (b, b_link) = duplex(STREAM_BUF_SIZE);
+		let (a_read, a_write) = split(a_link);
+		let (b_read, b_write) = split(b_link);
+		tokio::spawn(carry(a_read, b_write, self));
+		tokio::spawn(carry(b_read, a_write, self));
+
+		(a, b)
+	}
+}
+
+/// Move what's written on one end of a link to the other, as late and as slowly as `conditions` say.
+/// Closing either end is passed on to the other.
+async fn carry(
+	mut from: ReadHalf<DuplexStream>,
+	mut to: WriteHalf<DuplexStream>,
+	conditions: LinkConditions,
+) {
+	let (tx, mut rx) = mpsc::channel::<(Instant, Vec<u8>)>(CHUNKS_IN_FLIGHT);
+
+	let send = async move {
+		let mut buf = vec![0; CHUNK_SIZE];
+		loop {
+			let n = match from.read(&mut buf).await {
+				Ok(0) | Err(_) => break,
+				Ok(n) => n,
+			};
+
+			if let Some(bytes_per_sec) = conditions.bytes_per_sec {
+				sleep(Duration::from_secs_f64(
+					n as f64 / bytes_per_sec.max(1) as f64,
+				))
+				.await;
+			}
+
+			if tx
+				.send((Instant::now() + conditions.latency, buf[..n].to_vec()))
+				.await
+				.is_err()
+			{
+				break;
+			}
+		}
+	};
+
+	let deliver = async move {
+		while let Some((at, chunk)) = rx.recv().await {
+			sleep_until(at).await;
+			if to.write_all(&chunk).await.is_err() {
+				return;
+			}
+		}
+
+		let _ = to.shutdown().await;
+	};
+
+	tokio::join!(send, deliver);
+}
+
+#[derive(Debug)]
+struct Node {
+	p2p: Weak<P2P>,
+	listener: ListenerId,
+}
+
+#[derive(Debug, Default)]
+struct Network {
+	nodes: HashMap<RemoteIdentity, Node>,
+	link: LinkConditions,
+}
+
+/// Nodes within this process reaching each other through their [`MemoryTransport`]s, without opening a single socket.
+///
+/// Every node on the network discovers all the others, like on a LAN with mDNS.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryNetwork(Arc<Mutex<Network>>);
+
+impl MemoryNetwork {
+	pub fn with_link(link: LinkConditions) -> Self {
+		let this = Self::default();
+		this.set_link(link);
+		this
+	}
+
+	pub fn link(&self) -> LinkConditions {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner).link
+	}
+
+	/// Change how the network behaves, this takes effect for new streams.
+	pub fn set_link(&self, link: LinkConditions) {
+		self.0.lock().unwrap_or_else(PoisonError::into_inner).link = link;
+	}
+
+	fn node(&self, identity: RemoteIdentity) -> Option<(Arc<P2P>, ListenerId)> {
+		let network = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+		let node = network.nodes.get(&identity)?;
+
+		Some((node.p2p.upgrade()?, node.listener))
+	}
+
+	fn others(&self, identity: RemoteIdentity) -> Vec<(Arc<P2P>, ListenerId)> {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.nodes
+			.iter()
+			.filter(|(other, _)| **other != identity)
+			.filter_map(|(_, node)| Some((node.p2p.upgrade()?, node.listener)))
+			.collect()
+	}
+
+	fn join(&self, p2p: &Arc<P2P>, listener: ListenerId) {
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.nodes
+			.insert(
+				p2p.remote_identity(),
+				Node {
+					p2p: Arc::downgrade(p2p),
+					listener,
+				},
+			);
+
+		for (other, other_listener) in self.others(p2p.remote_identity()) {
+			discover(&other, other_listener, p2p);
+			discover(p2p, listener, &other);
+		}
+	}
+
+	/// Tell the other nodes about the new metadata of `p2p`
+	fn announce(&self, p2p: &Arc<P2P>) {
+		for (other, other_listener) in self.others(p2p.remote_identity()) {
+			discover(&other, other_listener, p2p);
+		}
+	}
+
+	fn leave(&self, p2p: &P2P) {
+		let identity = p2p.remote_identity();
+		self.0
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.nodes
+			.remove(&identity);
+
+		for (other, other_listener) in self.others(identity) {
+			let peer = other.peers().get(&identity).cloned();
+			if let Some(peer) = peer {
+				peer.undiscover_peer(other_listener.into());
+				peer.disconnected_from(other_listener);
+			}
+		}
+	}
+}
+
+/// `p2p` discovers `node` through its `listener`, there are no addresses to reach it at
+fn discover(p2p: &Arc<P2P>, listener: ListenerId, node: &P2P) {
+	let metadata = node.metadata().clone();
+	p2p.clone().discover_peer(
+		listener.into(),
+		node.remote_identity(),
+		metadata,
+		BTreeSet::new(),
+	);
+}
+
+/// Transport connecting peers on a [`MemoryNetwork`], for tests which need more than one node in the same process.
+///
+/// It can stand in for [`QuicTransport`](crate::QuicTransport) as it has the same methods, which do nothing when they're about sockets.
+#[derive(Debug)]
+pub struct MemoryTransport {
+	id: ListenerId,
+	p2p: Arc<P2P>,
+	relay_config: Mutex<Vec<RelayServerEntry>>,
+}
+
+impl MemoryTransport {
+	/// Spawn the `MemoryTransport`, registering it with the P2P system and joining `network`.
+	pub fn spawn(p2p: Arc<P2P>, network: &MemoryNetwork) -> (Self, Libp2pPeerId) {
+		let libp2p_peer_id = Libp2pPeerId(
+			identity_to_libp2p_keypair(p2p.identity())
+				.public()
+				.to_peer_id(),
+		);
+
+		let (tx, rx) = bounded(15);
+		let (connect_tx, connect_rx) = mpsc::channel(15);
+		let id = p2p.register_listener("memory", tx, move |listener_id, peer, _addrs| {
+			peer.listener_available(listener_id, connect_tx.clone());
+		});
+
+		network.join(&p2p, id);
+		tokio::spawn(start(p2p.clone(), network.clone(), rx, connect_rx));
+
+		(
+			Self {
+				id,
+				p2p,
+				relay_config: Mutex::new(Vec::new()),
+			},
+			libp2p_peer_id,
+		)
+	}
+
+	/// Relays are never used in memory, they are only kept for [`Self::get_relay_config`].
+	pub async fn set_relay_config(&self, relays: Vec<RelayServerEntry>) {
+		*self
+			.relay_config
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner) = relays;
+	}
+
+	pub fn get_relay_config(&self) -> Vec<RelayServerEntry> {
+		self.relay_config
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.clone()
+	}
+
+	/// There are no ports in memory, the node can be reached as long as the transport is spawned.
+	pub async fn set_ipv4_enabled(&self, _port: Option<u16>) -> Result<(), String> {
+		Ok(())
+	}
+
+	/// See [`Self::set_ipv4_enabled`]
+	pub async fn set_ipv6_enabled(&self, _port: Option<u16>) -> Result<(), String> {
+		Ok(())
+	}
+
+	/// Succeeds when `identity` is on the network, wherever `addr` is.
+	pub async fn probe(&self, identity: RemoteIdentity, _addr: SocketAddr) -> Result<(), String> {
+		let peer = self.p2p.peers().get(&identity).cloned();
+		match peer {
+			Some(peer) if peer.connection_methods().contains(&self.id) => Ok(()),
+			_ => Err(format!("'{identity}' isn't on the memory network")),
+		}
+	}
+
+	pub async fn shutdown(self) {
+		self.p2p.unregister_hook(self.id.into()).await;
+	}
+}
+
+async fn start(
+	p2p: Arc<P2P>,
+	network: MemoryNetwork,
+	rx: Receiver<HookEvent>,
+	mut connect_rx: mpsc::Receiver<ConnectionRequest>,
+) {
+	loop {
+		tokio::select! {
+			Ok(event) = rx.recv_async() => match event {
+				HookEvent::MetadataModified => network.announce(&p2p),
+				HookEvent::Shutdown { _guard } => {
+					network.leave(&p2p);
+					break;
+				},
+				_ => {},
+			},
+			Some(req) = connect_rx.recv() => {
+				tokio::spawn(connect(p2p.clone(), network.clone(), req));
+			},
+			else => break,
+		}
+	}
+}
+
+async fn connect(p2p: Arc<P2P>, network: MemoryNetwork, req: ConnectionRequest) {
+	let Some((remote, remote_listener)) = network.node(req.to) else {
+		let _ = req
+			.tx
+			.send(Err(format!("'{}' isn't on the memory network", req.to)));
+		return;
+	};
+
+	let link = network.link();
+	// Like the handshake of a real connection
+	if !link.latency.is_zero() {
+		sleep(link.latency).await;
+	}
+
+	let (stream, remote_stream) = link.stream_pair();
+	let (shutdown_tx, shutdown_rx) = oneshot::channel();
+	let peer = remote.connected_to(
+		remote_listener,
+		HashMap::new(),
+		UnicastStream::new(p2p.remote_identity(), remote_stream),
+		None,
+		shutdown_tx,
+	);
+
+	// There is nothing to tear down in memory, closing the connection is only letting the peer know.
+	// The sender is also dropped when a newer stream replaces it, which doesn't close anything.
+	tokio::spawn(async move {
+		if shutdown_rx.await.is_ok() {
+			peer.disconnected_from(remote_listener);
+		}
+	});
+
+	debug!("Established memory stream with '{}'", req.to);
+	let _ = req.tx.send(Ok(UnicastStream::new(req.to, stream)));
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use tokio::time::timeout;
+
+	use crate::Identity;
+
+	use super::*;
+
+	fn node(network: &MemoryNetwork) -> (Arc<P2P>, MemoryTransport, Receiver<UnicastStream>) {
+		let (handler_tx, handler_rx) = bounded(5);
+		let p2p = P2P::new("sd-test", Identity::new(), handler_tx);
+		let (transport, _) = MemoryTransport::spawn(p2p.clone(), network);
+
+		(p2p, transport, handler_rx)
+	}
+
+	#[tokio::test]
+	async fn nodes_on_a_network_reach_each_other() {
+		let network = MemoryNetwork::default();
+		let (a, _a_transport, _a_streams) = node(&network);
+		let (b, b_transport, b_streams) = node(&network);
+		b.metadata_mut().insert("name".into(), "b".into());
+
+		let peer = a.peers().get(&b.remote_identity()).cloned().unwrap();
+		assert!(peer.can_connect());
+		timeout(Duration::from_secs(1), async {
+			while peer.metadata().get("name").map(String::as_str) != Some("b") {
+				sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		let mut stream = peer.new_stream().await.unwrap();
+		assert_eq!(stream.remote_identity(), b.remote_identity());
+		stream.write_all(b"ping").await.unwrap();
+
+		let mut remote_stream = b_streams.recv_async().await.unwrap();
+		assert_eq!(remote_stream.remote_identity(), a.remote_identity());
+		let mut buf = [0; 4];
+		remote_stream.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"ping");
+		remote_stream.write_all(b"pong").await.unwrap();
+		stream.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"pong");
+		assert!(b.peers().get(&a.remote_identity()).unwrap().is_connected());
+
+		// Leaving the network is like going out of range
+		b_transport.shutdown().await;
+		assert!(a.peers().get(&b.remote_identity()).is_none());
+		assert!(peer.new_stream().await.is_err());
+	}
+
+	#[tokio::test]
+	async fn disconnecting_lets_the_hooks_know() {
+		let network = MemoryNetwork::default();
+		let (a, _a_transport, _a_streams) = node(&network);
+		let (b, _b_transport, _b_streams) = node(&network);
+		let (hook_tx, hook_rx) = bounded(15);
+		b.register_hook("test", hook_tx);
+
+		let peer = a.peers().get(&b.remote_identity()).cloned().unwrap();
+		let _stream = peer.new_stream().await.unwrap();
+		let remote = b.peers().get(&a.remote_identity()).cloned().unwrap();
+		assert!(remote.is_connected());
+
+		remote.disconnect();
+		assert!(!remote.is_connected());
+		timeout(Duration::from_secs(1), async {
+			loop {
+				if let HookEvent::PeerDisconnectedWith(_, identity) =
+					hook_rx.recv_async().await.unwrap()
+				{
+					break assert_eq!(identity, a.remote_identity());
+				}
+			}
+		})
+		.await
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn streams_are_as_slow_as_the_link() {
+		let network = MemoryNetwork::with_link(LinkConditions {
+			latency: Duration::from_millis(100),
+			bytes_per_sec: Some(100_000),
+		});
+		let (a, _a_transport, _a_streams) = node(&network);
+		let (b, _b_transport, b_streams) = node(&network);
+
+		let start = Instant::now();
+		let peer = a.peers().get(&b.remote_identity()).cloned().unwrap();
+		let mut stream = peer.new_stream().await.unwrap();
+		assert!(start.elapsed() >= Duration::from_millis(100));
+
+		let data = vec![1; 20_000];
+		let sent = data.clone();
+		tokio::spawn(async move {
+			stream.write_all(&sent).await.unwrap();
+			stream.close().await.unwrap();
+		});
+
+		let start = Instant::now();
+		let mut received = vec![];
+		b_streams
+			.recv_async()
+			.await
+			.unwrap()
+			.read_to_end(&mut received)
+			.await
+			.unwrap();
+		// 100ms of latency and 200ms to carry it all
+		assert!(start.elapsed() >= Duration::from_millis(300));
+		assert_eq!(received, data);
+	}
+}
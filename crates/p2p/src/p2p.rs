@@ -13,9 +13,12 @@ use tokio::{sync::oneshot, time::timeout};
 use tracing::info;
 
 use crate::{
-	hooks::{HandlerFn, Hook, HookEvent, ListenerData, ListenerId, ShutdownGuard},
+	hooks::{
+		Diagnostic, DiagnosticSeverity, HandlerFn, Hook, HookEvent, ListenerData, ListenerId,
+		ShutdownGuard,
+	},
 	smart_guards::SmartWriteGuard,
-	HookId, Identity, Peer, PeerConnectionCandidate, RemoteIdentity, UnicastStream,
+	ConnectionAddr, HookId, Identity, Peer, PeerConnectionCandidate, RemoteIdentity, UnicastStream,
 };
 
 /// Manager for the entire P2P system.
@@ -167,6 +170,7 @@ impl P2P {
 		listener: ListenerId,
 		metadata: HashMap<String, String>,
 		stream: UnicastStream,
+		addr: Option<ConnectionAddr>,
 		shutdown_tx: oneshot::Sender<()>,
 	) -> Arc<Peer> {
 		let identity = stream.remote_identity();
@@ -183,6 +187,9 @@ impl P2P {
 		{
 			let mut state = peer.state.write().unwrap_or_else(PoisonError::into_inner);
 			state.active_connections.insert(listener, shutdown_tx);
+			if let Some(addr) = addr {
+				state.connection_addrs.insert(listener, addr);
+			}
 		}
 
 		peer.metadata_mut().extend(metadata);
@@ -298,6 +305,29 @@ impl P2P {
 			.collect()
 	}
 
+	/// Tell every hook about something which happened within a transport or discovery mechanism.
+	/// Unlike other events diagnostics are dropped for hooks which are behind, so they can never hold up the transport reporting them.
+	pub fn diagnostic(
+		&self,
+		severity: DiagnosticSeverity,
+		subsystem: &'static str,
+		message: impl Into<String>,
+	) {
+		let diagnostic = Diagnostic {
+			severity,
+			subsystem,
+			message: message.into(),
+		};
+
+		self.hooks
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.iter()
+			.for_each(|(_, hook)| {
+				let _ = hook.tx.try_send(HookEvent::Diagnostic(diagnostic.clone()));
+			});
+	}
+
 	/// Register a new hook which can be used to react to state changes in the P2P system.
 	pub fn register_hook(&self, name: &'static str, tx: Sender<HookEvent>) -> HookId {
 		HookId(
@@ -1,14 +1,19 @@
 use std::{
 	collections::{BTreeSet, HashMap, HashSet},
+	future::Future,
 	net::SocketAddr,
 	sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak},
+	time::Duration,
 };
 
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 use tracing::warn;
 
-use crate::{HookEvent, HookId, ListenerId, RemoteIdentity, UnicastStream, P2P};
+use crate::{
+	by_preference, race_candidates, HookEvent, HookId, ListenerId, ProbedAddr, RemoteIdentity,
+	UnicastStream, P2P,
+};
 
 #[derive(Debug)]
 pub struct Peer {
@@ -31,15 +36,47 @@ pub enum PeerConnectionCandidate {
 	// Custom(String),
 }
 
+/// Where an active connection with the remote reaches it.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum ConnectionAddr {
+	Direct(SocketAddr),
+	Relay,
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct State {
 	/// Active connections with the remote
 	pub(crate) active_connections: HashMap<ListenerId, oneshot::Sender<()>>,
+	/// The addresses of the active connections, if the listener knows them
+	pub(crate) connection_addrs: HashMap<ListenerId, ConnectionAddr>,
 	/// Methods for establishing an active connections with the remote
 	/// These should be inject by `Listener::acceptor` which is called when a new peer is discovered.
 	pub(crate) connection_methods: HashMap<ListenerId, mpsc::Sender<ConnectionRequest>>,
 	/// Methods that have discovered this peer.
 	pub(crate) discovered: HashMap<HookId, BTreeSet<PeerConnectionCandidate>>,
+	/// The candidate which answered the last reachability probe first, while it's still discovered
+	pub(crate) fastest_addr: Option<ProbedAddr>,
+}
+
+impl State {
+	fn direct_candidates(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+		self.discovered
+			.values()
+			.flatten()
+			.filter_map(|candidate| match candidate {
+				PeerConnectionCandidate::SocketAddr(addr) => Some(*addr),
+				PeerConnectionCandidate::Relay => None,
+			})
+	}
+
+	// Forget the probe once its address isn't a candidate anymore
+	fn check_fastest_addr(&mut self) {
+		if let Some(fastest) = self.fastest_addr {
+			if !self.direct_candidates().any(|addr| addr == fastest.addr) {
+				self.fastest_addr = None;
+			}
+		}
+	}
 }
 
 /// A request to connect to a client.
@@ -49,6 +86,8 @@ pub(crate) struct State {
 pub struct ConnectionRequest {
 	pub to: RemoteIdentity,
 	pub addrs: BTreeSet<PeerConnectionCandidate>,
+	/// Addresses to try before the others, in this order. When empty the listener picks.
+	pub preference: Vec<SocketAddr>,
 	pub tx: oneshot::Sender<Result<UnicastStream, String>>,
 }
 
@@ -125,6 +164,60 @@ impl Peer {
 			.collect()
 	}
 
+	/// The candidates `hook_id` discovered the peer at, empty when it didn't discover the peer
+	pub fn candidates_discovered_by(&self, hook_id: HookId) -> BTreeSet<PeerConnectionCandidate> {
+		self.state
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.discovered
+			.get(&hook_id)
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	/// The addresses the peer can be reached at directly, found by mDNS or entered manually, in the order
+	/// they should be tried. See [`crate::by_preference`].
+	pub fn candidate_addrs(&self) -> Vec<SocketAddr> {
+		let state = self.state.read().unwrap_or_else(PoisonError::into_inner);
+		by_preference(
+			state.direct_candidates(),
+			state.fastest_addr.map(|fastest| fastest.addr),
+		)
+	}
+
+	pub fn fastest_addr(&self) -> Option<ProbedAddr> {
+		self.state
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.fastest_addr
+	}
+
+	/// Race `attempt` to every direct candidate of the peer, remembering the first which succeeds so
+	/// [`Self::candidate_addrs`] puts it first.
+	pub async fn probe_reachability<Fut, T, E>(
+		&self,
+		attempt_timeout: Duration,
+		attempt: impl Fn(SocketAddr) -> Fut,
+	) -> Option<ProbedAddr>
+	where
+		Fut: Future<Output = Result<T, E>>,
+	{
+		let candidates = self
+			.state
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.direct_candidates()
+			.collect::<HashSet<_>>();
+
+		let fastest = race_candidates(candidates, attempt_timeout, attempt).await;
+
+		let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
+		state.fastest_addr = fastest;
+		// It might have gone away while we were probing
+		state.check_fastest_addr();
+		state.fastest_addr
+	}
+
 	pub fn is_connected(&self) -> bool {
 		!self
 			.state
@@ -142,6 +235,25 @@ impl Peer {
 			.len()
 	}
 
+	/// The listeners we have an active connection through, and where it reaches the remote if known.
+	pub fn connections(&self) -> Vec<(ListenerId, Option<ConnectionAddr>)> {
+		let state = self.state.read().unwrap_or_else(PoisonError::into_inner);
+		state
+			.active_connections
+			.keys()
+			.map(|id| (*id, state.connection_addrs.get(id).copied()))
+			.collect()
+	}
+
+	pub fn connection_addr(&self, listener_id: ListenerId) -> Option<ConnectionAddr> {
+		self.state
+			.read()
+			.unwrap_or_else(PoisonError::into_inner)
+			.connection_addrs
+			.get(&listener_id)
+			.copied()
+	}
+
 	// TODO: Possibly remove this, it's not great???
 	pub fn is_connected_with_hook(&self, hook_id: HookId) -> bool {
 		self.state
@@ -181,6 +293,15 @@ impl Peer {
 
 	/// Construct a new Quic stream to the peer.
 	pub async fn new_stream(&self) -> Result<UnicastStream, NewStreamError> {
+		self.new_stream_preferring(vec![]).await
+	}
+
+	/// Like [`Self::new_stream`], but asking the listener to try the addresses in `preference` first,
+	/// in order, eg. the ones from [`Self::candidate_addrs`].
+	pub async fn new_stream_preferring(
+		&self,
+		preference: Vec<SocketAddr>,
+	) -> Result<UnicastStream, NewStreamError> {
 		let (addrs, connect_tx) = {
 			let state = self.state.read().unwrap_or_else(PoisonError::into_inner);
 
@@ -208,12 +329,13 @@ impl Peer {
 			.send(ConnectionRequest {
 				to: self.identity,
 				addrs,
+				preference,
 				tx,
 			})
 			.await
 			.map_err(|err| {
 				warn!("Failed to send connect request to peer: {}", err);
-				NewStreamError::EventLoopOffline(err)
+				NewStreamError::EventLoopOffline(Box::new(err))
 			})?;
 		rx.await
 			.map_err(|err| {
@@ -225,6 +347,24 @@ impl Peer {
 				NewStreamError::Connecting(err)
 			})
 	}
+
+	/// Ask the listeners to close every connection with the peer.
+	/// [`HookEvent::PeerDisconnectedWith`] fires once the listener closed one, or right away if it can't be asked anymore.
+	pub fn disconnect(&self) {
+		let connections = std::mem::take(
+			&mut self
+				.state
+				.write()
+				.unwrap_or_else(PoisonError::into_inner)
+				.active_connections,
+		);
+
+		for (listener_id, shutdown_tx) in connections {
+			if shutdown_tx.send(()).is_err() {
+				self.disconnected_from(listener_id);
+			}
+		}
+	}
 }
 
 // Hook-facing methods
@@ -232,11 +372,9 @@ impl Peer {
 	pub fn hook_discovered(&self, hook: HookId, addrs: BTreeSet<PeerConnectionCandidate>) {
 		// TODO: Emit event maybe???
 
-		self.state
-			.write()
-			.unwrap_or_else(PoisonError::into_inner)
-			.discovered
-			.insert(hook, addrs);
+		let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
+		state.discovered.insert(hook, addrs);
+		state.check_fastest_addr();
 	}
 
 	pub fn listener_available(&self, listener: ListenerId, tx: mpsc::Sender<ConnectionRequest>) {
@@ -254,6 +392,7 @@ impl Peer {
 
 		let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
 		state.discovered.remove(&hook_id);
+		state.check_fastest_addr();
 
 		let hooks = p2p.hooks.read().unwrap_or_else(PoisonError::into_inner);
 		hooks.iter().for_each(|(_, hook)| {
@@ -280,6 +419,7 @@ impl Peer {
 		let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
 		state.connection_methods.remove(&listener_id);
 		state.active_connections.remove(&listener_id);
+		state.connection_addrs.remove(&listener_id);
 
 		let hooks = p2p.hooks.read().unwrap_or_else(PoisonError::into_inner);
 		hooks.iter().for_each(|(_, hook)| {
@@ -304,9 +444,55 @@ pub enum NewStreamError {
 	#[error("No connection methods available for peer")]
 	NoConnectionMethodsAvailable,
 	#[error("The event loop is offline")]
-	EventLoopOffline(mpsc::error::SendError<ConnectionRequest>),
+	EventLoopOffline(Box<mpsc::error::SendError<ConnectionRequest>>),
 	#[error("Failed to establish the connection w/ error: {0}")]
 	ConnectionNeverEstablished(oneshot::error::RecvError),
 	#[error("error connecting to peer: {0}")]
 	Connecting(String),
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use tokio::net::{TcpListener, TcpStream};
+
+	use crate::{flume::bounded, Identity};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn the_reachable_address_is_selected() {
+		let (tx, _rx) = bounded(1);
+		let p2p = P2P::new("sd-test", Identity::new(), tx);
+		let peer = Peer::new(Identity::new().to_remote_identity(), p2p.clone());
+
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+		let loopback = listener.local_addr().unwrap();
+		tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+		// TEST-NET-1 is never routed, so connecting there hangs until the attempt times out
+		let unreachable = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), loopback.port()));
+		peer.hook_discovered(
+			HookId(0),
+			[PeerConnectionCandidate::SocketAddr(unreachable)].into(),
+		);
+		peer.hook_discovered(
+			HookId(1),
+			[PeerConnectionCandidate::SocketAddr(loopback)].into(),
+		);
+
+		let fastest = peer
+			.probe_reachability(Duration::from_secs(1), TcpStream::connect)
+			.await
+			.unwrap();
+		assert_eq!(fastest.addr, loopback);
+		assert_eq!(peer.fastest_addr(), Some(fastest));
+		assert_eq!(peer.candidate_addrs(), [loopback, unreachable]);
+
+		// Once the address is gone, so is what the probe found
+		peer.hook_discovered(HookId(1), BTreeSet::new());
+		assert_eq!(peer.fastest_addr(), None);
+		assert_eq!(peer.candidate_addrs(), [unreachable]);
+	}
+}
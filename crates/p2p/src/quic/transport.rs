@@ -1,5 +1,5 @@
 use std::{
-	collections::HashMap,
+	collections::{HashMap, HashSet},
 	net::{Ipv4Addr, Ipv6Addr, SocketAddr},
 	str::FromStr,
 	sync::{Arc, Mutex, PoisonError, RwLock},
@@ -12,7 +12,10 @@ use libp2p::{
 	futures::{AsyncReadExt, AsyncWriteExt, StreamExt},
 	multiaddr::Protocol,
 	noise, relay,
-	swarm::{NetworkBehaviour, SwarmEvent},
+	swarm::{
+		dial_opts::{DialOpts, PeerCondition},
+		ConnectionId, NetworkBehaviour, SwarmEvent,
+	},
 	yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
 };
 use serde::{Deserialize, Serialize};
@@ -28,18 +31,22 @@ use uuid::Uuid;
 use crate::{
 	identity::REMOTE_IDENTITY_LEN,
 	quic::utils::{
-		identity_to_libp2p_keypair, remote_identity_to_libp2p_peerid, socketaddr_to_quic_multiaddr,
+		identity_to_libp2p_keypair, quic_multiaddr_to_connection_addr,
+		remote_identity_to_libp2p_peerid, socketaddr_to_quic_multiaddr,
 	},
-	ConnectionRequest, HookEvent, ListenerId, PeerConnectionCandidate, RemoteIdentity,
-	UnicastStream, P2P,
+	ConnectionAddr, ConnectionRequest, DiagnosticSeverity, HookEvent, ListenerId,
+	PeerConnectionCandidate, RemoteIdentity, UnicastStream, P2P,
 };
 
 const PROTOCOL: StreamProtocol = StreamProtocol::new("/sdp2p/1");
 
+/// How long the address a connection request prefers gets on its own, before all of them are tried
+const PREFERRED_ADDR_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// [libp2p::PeerId] for debugging purposes only.
 #[derive(Debug)]
 #[allow(dead_code)]
-pub struct Libp2pPeerId(libp2p::PeerId);
+pub struct Libp2pPeerId(pub(crate) libp2p::PeerId);
 
 #[derive(Debug)]
 enum InternalEvent {
@@ -58,6 +65,21 @@ enum InternalEvent {
 		relays: Vec<RelayServerEntry>,
 		result: oneshot::Sender<Result<(), String>>,
 	},
+	Probe {
+		peer_id: PeerId,
+		addr: SocketAddr,
+		result: oneshot::Sender<Result<(), String>>,
+	},
+	/// Like [`InternalEvent::Probe`] but the connection is kept for streams
+	Dial {
+		peer_id: PeerId,
+		addr: SocketAddr,
+		result: oneshot::Sender<Result<(), String>>,
+	},
+	/// Close every connection with the peer, see [`Peer::disconnect`](crate::Peer::disconnect)
+	Disconnect {
+		peer_id: PeerId,
+	},
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -119,7 +141,15 @@ impl QuicTransport {
 			.with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
 			.build();
 
-		tokio::spawn(start(p2p.clone(), id, swarm, rx, internal_rx, connect_rx));
+		tokio::spawn(start(
+			p2p.clone(),
+			id,
+			swarm,
+			rx,
+			internal_tx.clone(),
+			internal_rx,
+			connect_rx,
+		));
 
 		Ok((
 			Self {
@@ -217,6 +247,24 @@ impl QuicTransport {
 			.and_then(|r| r)
 	}
 
+	/// Dial `identity` at `addr` only, succeeding once a connection is established there. The connection
+	/// is closed right away, it's only to tell which of the addresses of a peer answer first.
+	pub async fn probe(&self, identity: RemoteIdentity, addr: SocketAddr) -> Result<(), String> {
+		let (tx, rx) = oneshot::channel();
+		let event = InternalEvent::Probe {
+			peer_id: remote_identity_to_libp2p_peerid(&identity),
+			addr,
+			result: tx,
+		};
+
+		let Ok(_) = self.internal_tx.send(event) else {
+			return Err("internal channel closed".to_string());
+		};
+		rx.await
+			.map_err(|_| "internal response channel closed".to_string())
+			.and_then(|r| r)
+	}
+
 	pub async fn shutdown(self) {
 		self.p2p.unregister_hook(self.id.into()).await;
 	}
@@ -227,6 +275,7 @@ async fn start(
 	id: ListenerId,
 	mut swarm: Swarm<MyBehaviour>,
 	rx: Receiver<HookEvent>,
+	internal_tx: Sender<InternalEvent>,
 	internal_rx: Receiver<InternalEvent>,
 	mut connect_rx: mpsc::Receiver<ConnectionRequest>,
 ) {
@@ -237,7 +286,14 @@ async fn start(
 	#[allow(clippy::unwrap_used)] // TODO: Error handling
 	let mut incoming = control.accept(PROTOCOL).unwrap();
 	let map = Arc::new(RwLock::new(HashMap::new()));
+	// The remote address of each connection with each peer, so it can be attached to streams, see `stream_addr`
+	let connection_addrs: ConnectionAddrs = Default::default();
 	let mut relay_config = Vec::new();
+	// Dials started by `QuicTransport::probe` and the connections they established, which are closed right away
+	let mut probes: HashMap<ConnectionId, oneshot::Sender<Result<(), String>>> = HashMap::new();
+	let mut probe_connections = HashSet::new();
+	// Dials to the address a connection request prefers, see `InternalEvent::Dial`
+	let mut dials: HashMap<ConnectionId, oneshot::Sender<Result<(), String>>> = HashMap::new();
 
 	loop {
 		tokio::select! {
@@ -288,12 +344,15 @@ async fn start(
 			Some((peer_id, mut stream)) = incoming.next() => {
 				let p2p = p2p.clone();
 				let map = map.clone();
+				let internal_tx = internal_tx.clone();
+				let addr = stream_addr(&connection_addrs, peer_id);
 				tokio::spawn(async move {
 					let mut actual = [0; REMOTE_IDENTITY_LEN];
 					match stream.read_exact(&mut actual).await {
 						Ok(_) => {},
 						Err(e) => {
 							warn!("Failed to read remote identity with libp2p::PeerId({peer_id:?}): {e:?}");
+							p2p.diagnostic(DiagnosticSeverity::Warning, "quic", format!("rejected connection from {peer_id:?}, failed to read its identity: {e}"));
 							return;
 						},
 					}
@@ -301,6 +360,7 @@ async fn start(
 						Ok(i) => i,
 						Err(e) => {
 							warn!("Failed to parse remote identity with libp2p::PeerId({peer_id:?}): {e:?}");
+							p2p.diagnostic(DiagnosticSeverity::Warning, "quic", format!("rejected connection from {peer_id:?}, invalid identity: {e:?}"));
 							return;
 						},
 					};
@@ -312,6 +372,7 @@ async fn start(
 					let remote_identity_peer_id = remote_identity_to_libp2p_peerid(&identity);
 					if peer_id != remote_identity_peer_id {
 						warn!("Derived remote identity '{remote_identity_peer_id:?}' does not match libp2p::PeerId({peer_id:?})");
+						p2p.diagnostic(DiagnosticSeverity::Warning, "quic", format!("rejected connection from {peer_id:?}, its identity belongs to {remote_identity_peer_id:?}"));
 						return;
 					}
 					map.write().unwrap_or_else(PoisonError::into_inner).insert(peer_id, identity);
@@ -319,23 +380,64 @@ async fn start(
 					// TODO: Sync metadata
 					let metadata = HashMap::new();
 
-					let stream = UnicastStream::new(identity, stream.compat());
+					let stream = UnicastStream::new(identity, stream.compat()).with_remote_addr(addr);
 					let (shutdown_tx, shutdown_rx) = oneshot::channel();
 					p2p.connected_to(
 						id,
 						metadata,
 						stream,
+						addr,
 						shutdown_tx,
 					);
 
 					debug!("established inbound stream with '{}'", identity);
 
-					let _todo = shutdown_rx; // TODO: Handle `shutdown_rx`
+					// The sender is also dropped when a newer stream replaces it, which doesn't close anything
+					if shutdown_rx.await.is_ok() {
+						let _ = internal_tx.send_async(InternalEvent::Disconnect { peer_id }).await;
+					}
 				});
 			},
-			event = swarm.select_next_some() => if let SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } = event {
+			event = swarm.select_next_some() => match event {
+				SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+					if let Some(result) = probes.remove(&connection_id) {
+						let _ = result.send(Ok(()));
+						probe_connections.insert(connection_id);
+						swarm.close_connection(connection_id);
+						continue;
+					}
+
+					if let Some(addr) = quic_multiaddr_to_connection_addr(endpoint.get_remote_address()) {
+						connection_addrs.write().unwrap_or_else(PoisonError::into_inner).entry(peer_id).or_default().insert(connection_id, addr);
+					}
+					if let Some(result) = dials.remove(&connection_id) {
+						let _ = result.send(Ok(()));
+					}
+				},
+				SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+					if let Some(result) = probes.remove(&connection_id).or_else(|| dials.remove(&connection_id)) {
+						let _ = result.send(Err(error.to_string()));
+					}
+				},
+				SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, .. } => {
+					let was_probe = probe_connections.remove(&connection_id);
+					{
+						let mut connection_addrs = connection_addrs.write().unwrap_or_else(PoisonError::into_inner);
+						if let Some(connections) = connection_addrs.get_mut(&peer_id) {
+							connections.remove(&connection_id);
+							if connections.is_empty() {
+								connection_addrs.remove(&peer_id);
+							}
+						}
+					}
+					if num_established != 0 {
+						continue;
+					}
+
 					let Some(identity) = map.write().unwrap_or_else(PoisonError::into_inner).remove(&peer_id) else {
-						warn!("Tried to remove a peer that wasn't in the map.");
+						if !was_probe {
+							warn!("Tried to remove a peer that wasn't in the map.");
+						}
 						continue;
 					};
 
@@ -346,6 +448,8 @@ async fn start(
 					};
 
 					peer.disconnected_from(id);
+				},
+				_ => {},
 			},
 			Ok(event) = internal_rx.recv_async() => match event {
 				InternalEvent::RegisterListener { id, ipv4, addr, result } => {
@@ -361,9 +465,11 @@ async fn start(
 								p2p.register_listener_addr(id, addr);
 							}
 
+							p2p.diagnostic(DiagnosticSeverity::Info, "quic", format!("listening on {addr}"));
 							let _ = result.send(Ok(()));
 						},
 						Err(e) => {
+							p2p.diagnostic(DiagnosticSeverity::Error, "quic", format!("failed to listen on {addr}: {e}"));
 							let _ = result.send(Err(e.to_string()));
 						},
 					}
@@ -389,6 +495,7 @@ async fn start(
 							Ok(peer_id) => peer_id,
 							Err(err) => {
 								error!("Failed to parse Relay peer ID '{}': {err:?}", relay.peer_id);
+								p2p.diagnostic(DiagnosticSeverity::Error, "quic", format!("invalid peer ID '{}' for relay '{}'", relay.peer_id, relay.id));
 								continue;
 							},
 						};
@@ -416,6 +523,7 @@ async fn start(
 							Ok(_) => {},
 							Err(e) => {
 								error!("Failed to listen on relay server '{}': {e}", relay.id);
+								p2p.diagnostic(DiagnosticSeverity::Error, "quic", format!("failed to listen on relay '{}': {e}", relay.id));
 
 								// TODO: Try again if this fails
 							},
@@ -427,27 +535,101 @@ async fn start(
 					// TODO: Proper error handling
 					result.send(Ok(())).ok();
 				},
+				InternalEvent::Probe { peer_id, addr, result } => {
+					let opts = DialOpts::peer_id(peer_id)
+						.addresses(vec![socketaddr_to_quic_multiaddr(&addr)])
+						.condition(PeerCondition::Always)
+						.build();
+					let connection_id = opts.connection_id();
+
+					match swarm.dial(opts) {
+						Ok(()) => {
+							probes.insert(connection_id, result);
+						},
+						Err(e) => {
+							let _ = result.send(Err(e.to_string()));
+						},
+					}
+				},
+				InternalEvent::Dial { peer_id, addr, result } => {
+					let opts = DialOpts::peer_id(peer_id)
+						.addresses(vec![socketaddr_to_quic_multiaddr(&addr)])
+						.condition(PeerCondition::Always)
+						.build();
+					let connection_id = opts.connection_id();
+
+					match swarm.dial(opts) {
+						Ok(()) => {
+							dials.insert(connection_id, result);
+						},
+						Err(e) => {
+							let _ = result.send(Err(e.to_string()));
+						},
+					}
+				},
+				InternalEvent::Disconnect { peer_id } => {
+					// `SwarmEvent::ConnectionClosed` lets the peer know once the connections are closed
+					if swarm.disconnect_peer_id(peer_id).is_err() {
+						let identity = map.write().unwrap_or_else(PoisonError::into_inner).remove(&peer_id);
+						let peer = identity.and_then(|identity| p2p.peers.read().unwrap_or_else(PoisonError::into_inner).get(&identity).cloned());
+						if let Some(peer) = peer {
+							peer.disconnected_from(id);
+						}
+					}
+				},
 			},
 			Some(req) = connect_rx.recv() => {
 				let mut control = control.clone();
 				let self_remote_identity = p2p.identity().to_remote_identity();
 				let map = map.clone();
+				let connection_addrs = connection_addrs.clone();
+				let internal_tx = internal_tx.clone();
 				let peer_id = remote_identity_to_libp2p_peerid(&req.to);
 				let addrs = get_addrs(peer_id, &relay_config, req.addrs.iter());
 
 				tokio::spawn(async move {
-					match control.open_stream_with_addrs(
-						peer_id,
-						PROTOCOL,
-						addrs,
-					).await {
+					// Each preferred address is tried on its own in order, with all of them the transport would go with whichever answers first
+					let mut preferred_stream = None;
+					for preferred in &req.preference {
+						if let Err(e) = connect_at(&internal_tx, &connection_addrs, peer_id, *preferred).await {
+							debug!("Failed to reach '{}' at the preferred {preferred}: {e}", req.to);
+							continue;
+						}
+
+						match timeout(
+							PREFERRED_ADDR_TIMEOUT,
+							control.open_stream_with_addrs(peer_id, PROTOCOL, vec![socketaddr_to_quic_multiaddr(preferred)]),
+						).await {
+							Ok(Ok(stream)) => {
+								preferred_stream = Some(stream);
+								break;
+							},
+							Ok(Err(e)) => debug!("Failed to open a stream to '{}' at the preferred {preferred}: {e}", req.to),
+							Err(_) => debug!("Timed out opening a stream to '{}' at the preferred {preferred}", req.to),
+						}
+					}
+					if preferred_stream.is_none() && !req.preference.is_empty() {
+						debug!("Couldn't reach '{}' at any of its preferred addresses, trying every address", req.to);
+					}
+
+					let stream = match preferred_stream {
+						Some(stream) => Ok(stream),
+						None => control.open_stream_with_addrs(
+							peer_id,
+							PROTOCOL,
+							addrs,
+						).await,
+					};
+
+					match stream {
 						Ok(mut stream) => {
 							map.write().unwrap_or_else(PoisonError::into_inner).insert(peer_id, req.to);
+							let addr = stream_addr(&connection_addrs, peer_id);
 
 							match stream.write_all(&self_remote_identity.get_bytes()).await {
 								Ok(_) => {
-									debug!("Established outbound stream with '{}'", req.to);
-									let _ = req.tx.send(Ok(UnicastStream::new(req.to, stream.compat())));
+									debug!("Established outbound stream with '{}' through {addr:?}", req.to);
+									let _ = req.tx.send(Ok(UnicastStream::new(req.to, stream.compat()).with_remote_addr(addr)));
 								},
 								Err(e) => {
 									let _ = req.tx.send(Err(e.to_string()));
@@ -464,6 +646,58 @@ async fn start(
 	}
 }
 
+/// The remote address of each connection with each peer
+type ConnectionAddrs = Arc<RwLock<HashMap<PeerId, HashMap<ConnectionId, ConnectionAddr>>>>;
+
+/// The address a stream with `peer_id` goes through. libp2p opens streams on any connection with the peer,
+/// so it's only known when all of them reach the peer through the same address.
+fn stream_addr(connection_addrs: &ConnectionAddrs, peer_id: PeerId) -> Option<ConnectionAddr> {
+	let connection_addrs = connection_addrs
+		.read()
+		.unwrap_or_else(PoisonError::into_inner);
+	let mut addrs = connection_addrs.get(&peer_id)?.values();
+	let first = *addrs.next()?;
+	addrs.all(|addr| *addr == first).then_some(first)
+}
+
+/// Make sure there is a connection with `peer_id` at `addr`, dialing it unless there is one already.
+/// It's given [`PREFERRED_ADDR_TIMEOUT`] to answer.
+async fn connect_at(
+	internal_tx: &Sender<InternalEvent>,
+	connection_addrs: &ConnectionAddrs,
+	peer_id: PeerId,
+	addr: SocketAddr,
+) -> Result<(), String> {
+	let connected = connection_addrs
+		.read()
+		.unwrap_or_else(PoisonError::into_inner)
+		.get(&peer_id)
+		.is_some_and(|connections| {
+			connections
+				.values()
+				.any(|connection| *connection == ConnectionAddr::Direct(addr))
+		});
+	if connected {
+		return Ok(());
+	}
+
+	let (tx, rx) = oneshot::channel();
+	internal_tx
+		.send_async(InternalEvent::Dial {
+			peer_id,
+			addr,
+			result: tx,
+		})
+		.await
+		.map_err(|_| "internal channel closed".to_string())?;
+
+	match timeout(PREFERRED_ADDR_TIMEOUT, rx).await {
+		Ok(Ok(result)) => result,
+		Ok(Err(_)) => Err("internal response channel closed".to_string()),
+		Err(_) => Err("timed out".to_string()),
+	}
+}
+
 fn get_addrs<'a>(
 	peer_id: PeerId,
 	relay_config: &[RelayServerEntry],
@@ -493,3 +727,34 @@ fn get_addrs<'a>(
 		})
 		.collect::<Vec<_>>()
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn stream_addrs_are_only_known_when_connections_agree() {
+		let peer_id = PeerId::random();
+		let lan = ConnectionAddr::Direct(SocketAddr::from((Ipv4Addr::new(192, 168, 1, 2), 7373)));
+		let vpn = ConnectionAddr::Direct(SocketAddr::from((Ipv4Addr::new(100, 64, 1, 2), 7373)));
+
+		let connection_addrs = ConnectionAddrs::default();
+		assert_eq!(stream_addr(&connection_addrs, peer_id), None);
+
+		let mut connections = HashMap::from([(ConnectionId::new_unchecked(0), lan)]);
+		connection_addrs
+			.write()
+			.unwrap()
+			.insert(peer_id, connections.clone());
+		assert_eq!(stream_addr(&connection_addrs, peer_id), Some(lan));
+
+		// libp2p could open the stream on either of them
+		connections.insert(ConnectionId::new_unchecked(1), vpn);
+		connection_addrs
+			.write()
+			.unwrap()
+			.insert(peer_id, connections);
+		assert_eq!(stream_addr(&connection_addrs, peer_id), None);
+	}
+}
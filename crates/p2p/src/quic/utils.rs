@@ -1,10 +1,10 @@
 //! This file contains some fairly meaningless glue code for integrating with libp2p.
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr, PeerId};
 
-use crate::{Identity, RemoteIdentity};
+use crate::{ConnectionAddr, Identity, RemoteIdentity};
 
 #[must_use]
 pub(crate) fn socketaddr_to_quic_multiaddr(m: &SocketAddr) -> Multiaddr {
@@ -18,6 +18,24 @@ pub(crate) fn socketaddr_to_quic_multiaddr(m: &SocketAddr) -> Multiaddr {
 	addr
 }
 
+/// Where a connection with `addr` as its remote address reaches the peer.
+#[must_use]
+pub(crate) fn quic_multiaddr_to_connection_addr(addr: &Multiaddr) -> Option<ConnectionAddr> {
+	let mut ip = None;
+	let mut port = None;
+	for protocol in addr.iter() {
+		match protocol {
+			Protocol::P2pCircuit => return Some(ConnectionAddr::Relay),
+			Protocol::Ip4(addr) => ip = Some(IpAddr::V4(addr)),
+			Protocol::Ip6(addr) => ip = Some(IpAddr::V6(addr)),
+			Protocol::Udp(p) => port = Some(p),
+			_ => {}
+		}
+	}
+
+	Some(ConnectionAddr::Direct(SocketAddr::new(ip?, port?)))
+}
+
 // This is sketchy, but it makes the whole system a lot easier to work with
 // We are assuming the libp2p `PublicKey` is the same format as our `RemoteIdentity` type.
 // This is *acktually* true but they reserve the right to change it at any point.
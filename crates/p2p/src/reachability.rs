@@ -0,0 +1,118 @@
+use std::{
+	future::Future,
+	net::{IpAddr, SocketAddr},
+	time::Duration,
+};
+
+use libp2p::futures::{stream::FuturesUnordered, StreamExt};
+use serde::Serialize;
+use tokio::time::{timeout, Instant};
+
+/// How much we'd rather reach a peer through an address, the order of this enum is the preference.
+///
+/// Addresses on the LAN come first, so a transfer doesn't go through a VPN address of the same peer.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub enum AddressPreference {
+	/// Loopback, private and link local ranges
+	Lan,
+	Other,
+}
+
+impl AddressPreference {
+	pub fn of(ip: IpAddr) -> Self {
+		let lan = match ip {
+			IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+			IpAddr::V6(ip) => {
+				let first = ip.segments()[0];
+				// `fc00::/7` is unique local and `fe80::/10` link local
+				ip.is_loopback() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80
+			}
+		};
+
+		if lan {
+			Self::Lan
+		} else {
+			Self::Other
+		}
+	}
+}
+
+/// The address which answered a reachability probe first, see [`crate::Peer::probe_reachability`].
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Serialize)]
+pub struct ProbedAddr {
+	pub addr: SocketAddr,
+	pub latency: Duration,
+}
+
+/// Sorts `addrs` in the order they should be tried, `fastest` first when it's among them
+pub fn by_preference(
+	addrs: impl IntoIterator<Item = SocketAddr>,
+	fastest: Option<SocketAddr>,
+) -> Vec<SocketAddr> {
+	let mut addrs = addrs.into_iter().collect::<Vec<_>>();
+	addrs.sort_by_key(|addr| {
+		(
+			Some(*addr) != fastest,
+			AddressPreference::of(addr.ip()),
+			*addr,
+		)
+	});
+	addrs.dedup();
+	addrs
+}
+
+/// Races `attempt` to each of `candidates`, returning the first one which succeeded.
+///
+/// Every attempt is given at most `attempt_timeout`, they all run at once.
+pub async fn race_candidates<Fut, T, E>(
+	candidates: impl IntoIterator<Item = SocketAddr>,
+	attempt_timeout: Duration,
+	attempt: impl Fn(SocketAddr) -> Fut,
+) -> Option<ProbedAddr>
+where
+	Fut: Future<Output = Result<T, E>>,
+{
+	let start = Instant::now();
+	let mut attempts = candidates
+		.into_iter()
+		.map(|addr| {
+			let attempt = attempt(addr);
+			async move { (addr, timeout(attempt_timeout, attempt).await) }
+		})
+		.collect::<FuturesUnordered<_>>();
+
+	while let Some((addr, result)) = attempts.next().await {
+		if let Ok(Ok(_)) = result {
+			return Some(ProbedAddr {
+				addr,
+				latency: start.elapsed(),
+			});
+		}
+	}
+
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{Ipv4Addr, Ipv6Addr};
+
+	use super::*;
+
+	#[test]
+	fn lan_addresses_come_first() {
+		let vpn = SocketAddr::from((Ipv4Addr::new(100, 64, 1, 2), 7373));
+		let lan = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 2), 7373));
+		let link_local = SocketAddr::from((Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1), 7373));
+
+		assert_eq!(
+			by_preference([vpn, lan, link_local], None),
+			[lan, link_local, vpn]
+		);
+		// What answered a probe first wins over the ranges
+		assert_eq!(
+			by_preference([vpn, lan, link_local], Some(vpn)),
+			[vpn, lan, link_local]
+		);
+	}
+}
@@ -7,7 +7,7 @@ use std::{
 use sync_wrapper::SyncWrapper;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 
-use crate::RemoteIdentity;
+use crate::{ConnectionAddr, RemoteIdentity};
 
 trait IoStream: AsyncRead + AsyncWrite {}
 impl<S: AsyncRead + AsyncWrite> IoStream for S {}
@@ -16,12 +16,14 @@ impl<S: AsyncRead + AsyncWrite> IoStream for S {}
 pub struct UnicastStream {
 	io: SyncWrapper<Pin<Box<dyn IoStream + Send>>>,
 	remote: RemoteIdentity,
+	addr: Option<ConnectionAddr>,
 }
 
 impl fmt::Debug for UnicastStream {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		f.debug_struct("UnicastStream")
 			.field("remote", &self.remote)
+			.field("addr", &self.addr)
 			.finish()
 	}
 }
@@ -31,14 +33,27 @@ impl UnicastStream {
 		Self {
 			io: SyncWrapper::new(Box::pin(io)),
 			remote,
+			addr: None,
 		}
 	}
 
+	/// Set where the connection the stream goes over reaches the remote, when the listener knows it
+	#[must_use]
+	pub fn with_remote_addr(mut self, addr: Option<ConnectionAddr>) -> Self {
+		self.addr = addr;
+		self
+	}
+
 	#[must_use]
 	pub fn remote_identity(&self) -> RemoteIdentity {
 		self.remote
 	}
 
+	#[must_use]
+	pub fn remote_addr(&self) -> Option<ConnectionAddr> {
+		self.addr
+	}
+
 	pub async fn close(self) -> Result<(), io::Error> {
 		self.io.into_inner().shutdown().await
 	}
@@ -63,6 +63,14 @@ pub fn inode_to_db(inode: u64) -> Vec<u8> {
 	inode.to_le_bytes().to_vec()
 }
 
+pub fn device_from_db(db_device: &[u8]) -> Option<u64> {
+	db_device.try_into().ok().map(u64::from_le_bytes)
+}
+
+pub fn device_to_db(device: u64) -> Vec<u8> {
+	device.to_le_bytes().to_vec()
+}
+
 #[derive(Error, Debug)]
 #[error("Missing field {0}")]
 pub struct MissingFieldError(&'static str);
@@ -1,7 +1,9 @@
+import { Cloud } from '@phosphor-icons/react';
 import clsx from 'clsx';
 import { memo, useMemo } from 'react';
 import {
 	byteSize,
+	getIndexedItemFilePath,
 	getItemFilePath,
 	useLibraryQuery,
 	useSelector,
@@ -116,6 +118,7 @@ const ItemMetadata = () => {
 				selected={item.selected}
 			/>
 			<ItemSize />
+			<ItemPlaceholderBadge />
 			{item.data.type === 'Label' && <LabelItemCount data={item.data} />}
 		</ExplorerDraggable>
 	);
@@ -154,6 +157,24 @@ const ItemSize = () => {
 	);
 };
 
+// Files a cloud sync client only keeps online, which are downloaded once opened
+const ItemPlaceholderBadge = () => {
+	const item = useGridViewItemContext();
+	const { t } = useLocale();
+
+	if (!getIndexedItemFilePath(item.data)?.is_placeholder) return null;
+
+	return (
+		<div
+			className="flex items-center justify-center gap-1 truncate px-1.5 py-px text-tiny text-ink-dull"
+			title={t('cloud_placeholder_description')}
+		>
+			<Cloud weight="fill" />
+			{t('cloud_placeholder')}
+		</div>
+	);
+};
+
 function LabelItemCount({ data }: { data: Extract<ExplorerItem, { type: 'Label' }> }) {
 	const { t } = useLocale();
 
@@ -35,8 +35,9 @@ export function SpacedropProvider() {
 		} else if (data.type === 'SpacedropProgress') {
 			progressToast(data);
 		} else if (data.type === 'SpacedropRejected') {
-			// TODO: Add more information to this like peer name, etc in future
-			toast.warning(t('spacedrop_rejected'));
+			toast.warning({ title: t('spacedrop_rejected'), body: data.files.join(', ') });
+		} else if (data.type === 'SpacedropFailed') {
+			toast.error({ title: t('spacedrop_failed'), body: data.reason });
 		}
 	});
 
@@ -1,16 +1,32 @@
 import { useEffect, useRef } from 'react';
-import { P2PEvent, useBridgeMutation, useSpacedropProgress } from '@sd/client';
+import { P2PEvent, useBridgeMutation, useBridgeQuery, useSpacedropProgress } from '@sd/client';
 import { Input, ProgressBar, toast, ToastId } from '@sd/ui';
 import { usePlatform } from '~/util/Platform';
 
 const placeholder = '/Users/oscar/Desktop/demo.txt';
 
+// Mirrors `SPACEDROP_TIMEOUT`, `SPACEDROP_MIN_TIMEOUT` and `SPACEDROP_MAX_TIMEOUT` in `core/src/p2p/operations/spacedrop.rs`
+const defaultTimeoutSecs = 60;
+const minTimeoutSecs = 30;
+const maxTimeoutSecs = 5 * 60;
+
 export function useIncomingSpacedropToast() {
 	const platform = usePlatform();
 	const acceptSpacedrop = useBridgeMutation('p2p.acceptSpacedrop');
+	const keepAliveSpacedrop = useBridgeMutation('p2p.keepAliveSpacedrop');
+	const nodeState = useBridgeQuery(['nodeState']);
 	const filePathInput = useRef<HTMLInputElement>(null);
 
-	return (data: Extract<P2PEvent, { type: 'SpacedropRequest' }>) =>
+	return (data: Extract<P2PEvent, { type: 'SpacedropRequest' }>) => {
+		// Keep the prompt up for as long as the node waits for an answer
+		const configuredSecs = nodeState.data?.spacedrop_timeout_secs ?? defaultTimeoutSecs;
+		const timeoutSecs = Math.min(Math.max(configuredSecs, minTimeoutSecs), maxTimeoutSecs);
+
+		// Stops the Spacedrop from timing out while the prompt or the file picker is open,
+		// a few times within each timeout so a slow keep-alive still gets there in time
+		const keepAliveMs = (timeoutSecs / 4) * 1000;
+		const keepAlive = setInterval(() => keepAliveSpacedrop.mutate(data.id), keepAliveMs);
+
 		toast.info(
 			{
 				title: 'Incoming Spacedrop',
@@ -18,10 +34,12 @@ export function useIncomingSpacedropToast() {
 				body: (
 					<>
 						<p>
-							File '{data.files[0]}' from '{data.peer_name}'
+							{data.kind.type === 'Text'
+								? `Text '${data.kind.preview}' from '${data.display_name}'`
+								: `File '${data.files[0]}' from '${data.display_name}'`}
 						</p>
 						{/* TODO: This will be removed in the future for now it's just a hack */}
-						{platform.saveFilePickerDialog ? null : (
+						{platform.saveFilePickerDialog || data.files.length === 0 ? null : (
 							<Input
 								ref={filePathInput}
 								name="file_path"
@@ -35,46 +53,80 @@ export function useIncomingSpacedropToast() {
 				)
 			},
 			{
-				duration: 30 * 1000,
+				duration: timeoutSecs * 1000,
 				onClose: ({ event }) => {
-					event !== 'on-action' && acceptSpacedrop.mutate([data.id, null]);
+					if (event !== 'on-action') {
+						clearInterval(keepAlive);
+						acceptSpacedrop.mutate({
+							id: data.id,
+							path: null,
+							overwrite: false,
+							apply_permissions: false
+						});
+					}
 				},
 				action: {
 					label: 'Accept',
 					async onClick() {
-						let destinationFilePath = filePathInput.current?.value ?? placeholder;
-
-						if (data.files.length != 1) {
-							if (platform.openDirectoryPickerDialog) {
-								const result = await platform.openDirectoryPickerDialog({
-									title: 'Save Spacedrop',
-									multiple: false
-								});
-								if (!result) {
-									return;
-								}
-								destinationFilePath = result;
-							}
-						} else {
-							if (platform.saveFilePickerDialog) {
-								const result = await platform.saveFilePickerDialog({
-									title: 'Save Spacedrop',
-									defaultPath: data.files?.[0]
-								});
-								if (!result) {
-									return;
-								}
-								destinationFilePath = result;
-							}
+						try {
+							await accept();
+						} finally {
+							clearInterval(keepAlive);
 						}
-
-						if (destinationFilePath === '') return;
-						await acceptSpacedrop.mutateAsync([data.id, destinationFilePath]);
 					}
 				},
 				cancel: 'Reject'
 			}
 		);
+
+		async function accept() {
+			// Small text is returned to us instead of being saved
+			if (data.files.length === 0) {
+				const text = await acceptSpacedrop.mutateAsync({
+					id: data.id,
+					path: '',
+					overwrite: false,
+					apply_permissions: false
+				});
+				if (text !== null) await navigator.clipboard.writeText(text);
+				return;
+			}
+
+			let destinationFilePath = filePathInput.current?.value ?? placeholder;
+
+			if (data.files.length != 1) {
+				if (platform.openDirectoryPickerDialog) {
+					const result = await platform.openDirectoryPickerDialog({
+						title: 'Save Spacedrop',
+						multiple: false
+					});
+					if (!result) {
+						return;
+					}
+					destinationFilePath = result;
+				}
+			} else {
+				if (platform.saveFilePickerDialog) {
+					const result = await platform.saveFilePickerDialog({
+						title: 'Save Spacedrop',
+						defaultPath: data.files?.[0]
+					});
+					if (!result) {
+						return;
+					}
+					destinationFilePath = result;
+				}
+			}
+
+			if (destinationFilePath === '') return;
+			await acceptSpacedrop.mutateAsync({
+				id: data.id,
+				path: destinationFilePath,
+				overwrite: false,
+				apply_permissions: false
+			});
+		}
+	};
 }
 
 export function SpacedropProgress({ toastId, dropId }: { toastId: ToastId; dropId: string }) {
@@ -86,6 +86,10 @@ export const Component = () => {
 				p2p_ipv4_port: null,
 				p2p_ipv6_port: null,
 				p2p_discovery: null,
+				spacedrop_timeout_secs: null,
+				spacedrop_parallelism: null,
+				file_serve_concurrency: null,
+				file_serve_bytes_per_sec: null,
 				// p2p_port: value.customOrDefault === 'Default' ? 0 : Number(value.p2p_port),
 				// p2p_enabled: value.p2p_enabled ?? null,
 				image_labeler_version: value.image_labeler_version ?? null
@@ -26,6 +26,7 @@ import { useLocale, useZodRouteParams } from '~/hooks';
 import DeleteDialog from './DeleteDialog';
 import IndexerRuleEditor from './IndexerRuleEditor';
 import { LocationPathInputField } from './PathInput';
+import ScanHistory from './ScanHistory';
 
 const FlexCol = tw.label`flex flex-col flex-1`;
 const ToggleSection = tw.label`flex flex-row w-full`;
@@ -187,6 +188,8 @@ const EditLocationForm = () => {
 					control={form.control}
 				/>
 				<Divider />
+				<ScanHistory locationId={locationId} />
+				<Divider />
 				<div className="flex space-x-5">
 					<FlexCol>
 						<div>
@@ -0,0 +1,62 @@
+import dayjs from 'dayjs';
+import { byteSize, useLibraryQuery } from '@sd/client';
+import { InfoText, Label } from '@sd/ui';
+import { useLocale } from '~/hooks';
+
+export default ({ locationId }: { locationId: number }) => {
+	const { t } = useLocale();
+	const scanRecords = useLibraryQuery(['locations.scanRecords', locationId]);
+
+	return (
+		<div className="flex flex-col">
+			<Label className="grow">{t('recent_scans')}</Label>
+			<InfoText className="mb-3 mt-1">{t('recent_scans_info')}</InfoText>
+			{scanRecords.data?.length === 0 && <InfoText>{t('recent_scans_empty')}</InfoText>}
+			<div className="flex flex-col divide-y divide-app-line rounded-md border border-app-line bg-app-overlay">
+				{scanRecords.data?.map(({ generation, summary }) => {
+					const errors = summary.errors.reduce((total, { count }) => total + count, 0);
+					const duration = dayjs(summary.finished_at).diff(summary.started_at, 's');
+
+					return (
+						<div key={generation} className="flex flex-col px-4 py-2 text-sm">
+							<div className="flex flex-row justify-between">
+								<span className="font-medium text-ink">
+									{dayjs(summary.finished_at).format('MMM D, YYYY HH:mm:ss')}
+									{summary.sub_path && (
+										<span className="ml-2 text-ink-faint">{summary.sub_path}</span>
+									)}
+								</span>
+								<span className="text-ink-dull">
+									{t('scan_record_duration', { seconds: duration })}
+								</span>
+							</div>
+							<span className="text-ink-dull">
+								{t('scan_record_counts', {
+									found: summary.found_entries,
+									indexed: summary.indexed,
+									updated: summary.updated,
+									removed: summary.removed,
+									size: byteSize(summary.bytes_indexed)
+								})}
+								{errors > 0 && (
+									<span className="ml-2 text-red-500">
+										{t('scan_record_errors', { count: errors })}
+									</span>
+								)}
+							</span>
+							{summary.rule_hits.length > 0 && (
+								<span className="text-ink-faint">
+									{summary.rule_hits
+										.map(({ name, rejected }) =>
+											t('scan_record_rule_hits', { name, rejected })
+										)
+										.join(', ')}
+								</span>
+							)}
+						</div>
+					);
+				})}
+			</div>
+		</div>
+	);
+};
@@ -29,10 +29,16 @@ export type Procedures = {
         { key: "library.statistics", input: LibraryArgs<null>, result: StatisticsResponse } | 
         { key: "locations.get", input: LibraryArgs<number>, result: { item: Reference<Location>; nodes: CacheNode[] } | null } | 
         { key: "locations.getWithRules", input: LibraryArgs<number>, result: { item: Reference<LocationWithIndexerRule>; nodes: CacheNode[] } | null } | 
+        { key: "locations.indexer_rules.export", input: LibraryArgs<IndexerRulesExportArgs>, result: JsonValue } | 
         { key: "locations.indexer_rules.get", input: LibraryArgs<number>, result: NormalisedResult<IndexerRule> } | 
+        { key: "locations.indexer_rules.groups.list", input: LibraryArgs<null>, result: RuleGroup[] } | 
+        { key: "locations.indexer_rules.groups.listForLocation", input: LibraryArgs<number>, result: RuleGroup[] } | 
         { key: "locations.indexer_rules.list", input: LibraryArgs<null>, result: NormalisedResults<IndexerRule> } | 
         { key: "locations.indexer_rules.listForLocation", input: LibraryArgs<number>, result: NormalisedResults<IndexerRule> } | 
+        { key: "locations.indexer_rules.preview", input: IndexerRulesPreviewArgs, result: ([string, RuleDecision])[] } | 
         { key: "locations.list", input: LibraryArgs<null>, result: NormalisedResults<Location> } | 
+        { key: "locations.scanPreview", input: LibraryArgs<ScanPreviewArgs>, result: ScanPreview } | 
+        { key: "locations.scanRecords", input: LibraryArgs<number>, result: ScanRecord[] } | 
         { key: "locations.systemLocations", input: never, result: SystemLocations } | 
         { key: "models.image_detection.list", input: never, result: string[] } | 
         { key: "nodeState", input: never, result: NodeState } | 
@@ -40,7 +46,13 @@ export type Procedures = {
         { key: "notifications.dismiss", input: NotificationId, result: null } | 
         { key: "notifications.dismissAll", input: never, result: null } | 
         { key: "notifications.get", input: never, result: Notification[] } | 
+        { key: "p2p.connectionLog", input: RemoteIdentity | null, result: ConnectionLogEntry[] } | 
+        { key: "p2p.identify", input: RemoteIdentity, result: IdentifyPayload } | 
+        { key: "p2p.knownPeers", input: never, result: KnownPeer[] } | 
+        { key: "p2p.metrics", input: never, result: P2PMetricsSnapshot } | 
+        { key: "p2p.spacedropHistory", input: SpacedropHistoryFilter, result: SpacedropHistoryEntry[] } | 
         { key: "p2p.state", input: never, result: JsonValue } | 
+        { key: "p2p.testPort", input: TestPortArgs, result: PortStatus } | 
         { key: "preferences.get", input: LibraryArgs<null>, result: LibraryPreferences } | 
         { key: "search.objects", input: LibraryArgs<ObjectSearchArgs>, result: SearchData<ExplorerItem> } | 
         { key: "search.objectsCount", input: LibraryArgs<{ filters?: SearchFilterArgs[] }>, result: number } | 
@@ -79,10 +91,12 @@ export type Procedures = {
         { key: "files.cutFiles", input: LibraryArgs<OldFileCutterJobInit>, result: null } | 
         { key: "files.deleteFiles", input: LibraryArgs<OldFileDeleterJobInit>, result: null } | 
         { key: "files.eraseFiles", input: LibraryArgs<OldFileEraserJobInit>, result: null } | 
+        { key: "files.pin", input: LibraryArgs<number[]>, result: null } | 
         { key: "files.removeAccessTime", input: LibraryArgs<number[]>, result: null } | 
         { key: "files.renameFile", input: LibraryArgs<RenameFileArgs>, result: null } | 
         { key: "files.setFavorite", input: LibraryArgs<SetFavoriteArgs>, result: null } | 
         { key: "files.setNote", input: LibraryArgs<SetNoteArgs>, result: null } | 
+        { key: "files.unpin", input: LibraryArgs<number[]>, result: null } | 
         { key: "files.updateAccessTime", input: LibraryArgs<number[]>, result: null } | 
         { key: "invalidation.test-invalidate-mutation", input: LibraryArgs<null>, result: null } | 
         { key: "jobs.cancel", input: LibraryArgs<string>, result: null } | 
@@ -94,6 +108,7 @@ export type Procedures = {
         { key: "jobs.objectValidator", input: LibraryArgs<ObjectValidatorArgs>, result: null } | 
         { key: "jobs.pause", input: LibraryArgs<string>, result: null } | 
         { key: "jobs.resume", input: LibraryArgs<string>, result: null } | 
+        { key: "jobs.verifyLocation", input: LibraryArgs<VerifyLocationArgs>, result: null } | 
         { key: "labels.delete", input: LibraryArgs<number>, result: null } | 
         { key: "library.create", input: CreateLibraryArgs, result: NormalisedResult<LibraryConfigWrapped> } | 
         { key: "library.delete", input: string, result: null } | 
@@ -101,20 +116,38 @@ export type Procedures = {
         { key: "library.startActor", input: LibraryArgs<string>, result: null } | 
         { key: "library.stopActor", input: LibraryArgs<string>, result: null } | 
         { key: "locations.addLibrary", input: LibraryArgs<LocationCreateArgs>, result: number | null } | 
+        { key: "locations.confirmEmpty", input: LibraryArgs<number>, result: null } | 
         { key: "locations.create", input: LibraryArgs<LocationCreateArgs>, result: number | null } | 
         { key: "locations.delete", input: LibraryArgs<number>, result: null } | 
         { key: "locations.fullRescan", input: LibraryArgs<FullRescanArgs>, result: null } | 
         { key: "locations.indexer_rules.create", input: LibraryArgs<IndexerRuleCreateArgs>, result: null } | 
         { key: "locations.indexer_rules.delete", input: LibraryArgs<number>, result: null } | 
+        { key: "locations.indexer_rules.groups.create", input: LibraryArgs<RuleGroupCreateArgs>, result: RuleGroup } | 
+        { key: "locations.indexer_rules.groups.delete", input: LibraryArgs<number>, result: null } | 
+        { key: "locations.indexer_rules.groups.setForLocation", input: LibraryArgs<RuleGroupForLocationArgs>, result: null } | 
+        { key: "locations.indexer_rules.groups.update", input: LibraryArgs<RuleGroupUpdateArgs>, result: null } | 
+        { key: "locations.indexer_rules.import", input: LibraryArgs<IndexerRulesImportArgs>, result: number } | 
         { key: "locations.relink", input: LibraryArgs<string>, result: number } | 
         { key: "locations.subPathRescan", input: LibraryArgs<RescanArgs>, result: null } | 
         { key: "locations.update", input: LibraryArgs<LocationUpdateArgs>, result: null } | 
         { key: "nodes.edit", input: ChangeNodeNameArgs, result: null } | 
         { key: "nodes.updateThumbnailerPreferences", input: UpdateThumbnailerPreferences, result: null } | 
-        { key: "p2p.acceptSpacedrop", input: [string, string | null], result: null } | 
+        { key: "p2p.acceptSpacedrop", input: AcceptSpacedropArgs, result: string | null } | 
         { key: "p2p.cancelSpacedrop", input: string, result: null } | 
+        { key: "p2p.clearHistory", input: never, result: null } | 
+        { key: "p2p.confirmPairing", input: ConfirmPairingArgs, result: null } | 
         { key: "p2p.debugConnect", input: RemoteIdentity, result: string } | 
+        { key: "p2p.downloadFile", input: LibraryArgs<DownloadFileArgs>, result: string } | 
+        { key: "p2p.forgetPeer", input: RemoteIdentity, result: null } | 
+        { key: "p2p.keepAliveSpacedrop", input: string, result: null } | 
+        { key: "p2p.pair", input: RemoteIdentity, result: string } | 
+        { key: "p2p.resetMetrics", input: never, result: null } | 
+        { key: "p2p.rotateIdentity", input: never, result: RemoteIdentity } | 
+        { key: "p2p.selfTest", input: never, result: P2PSelfTestReport } | 
+        { key: "p2p.setPeerAlias", input: SetPeerAliasArgs, result: null } | 
         { key: "p2p.spacedrop", input: SpacedropArgs, result: string } | 
+        { key: "p2p.spacedropObjects", input: LibraryArgs<SpacedropObjectsArgs>, result: SpacedropObjects } | 
+        { key: "p2p.spacedropText", input: SpacedropTextArgs, result: string } | 
         { key: "preferences.update", input: LibraryArgs<LibraryPreferences>, result: null } | 
         { key: "search.saved.create", input: LibraryArgs<{ name: string; search?: string | null; filters?: string | null; description?: string | null; icon?: string | null }>, result: null } | 
         { key: "search.saved.delete", input: LibraryArgs<number>, result: null } | 
@@ -131,15 +164,19 @@ export type Procedures = {
         { key: "jobs.newThumbnail", input: LibraryArgs<null>, result: string[] } | 
         { key: "jobs.progress", input: LibraryArgs<null>, result: JobProgressEvent } | 
         { key: "library.actors", input: LibraryArgs<null>, result: { [key in string]: boolean } } | 
+        { key: "locations.indexingUpdates", input: LibraryArgs<number>, result: IndexingUpdate } | 
         { key: "locations.online", input: never, result: number[][] } | 
         { key: "locations.quickRescan", input: LibraryArgs<LightScanArgs>, result: null } | 
         { key: "notifications.listen", input: never, result: Notification } | 
+        { key: "p2p.diagnostics", input: never, result: P2PEvent } | 
         { key: "p2p.events", input: never, result: P2PEvent } | 
         { key: "search.ephemeralPaths", input: LibraryArgs<EphemeralPathSearchArgs>, result: EphemeralPathsResultItem } | 
         { key: "sync.active", input: LibraryArgs<null>, result: boolean } | 
         { key: "sync.newMessage", input: LibraryArgs<null>, result: null }
 };
 
+export type AcceptSpacedropArgs = { id: string; path: string | null; overwrite: boolean; apply_permissions: boolean }
+
 export type Args = { search?: string | null; filters?: string | null; name?: string | null; icon?: string | null; description?: string | null }
 
 export type AudioMetadata = { duration: number | null; audio_codec: string | null }
@@ -163,7 +200,7 @@ export type CacheNode = { __type: string; __id: string; "#node": any }
 
 export type CameraData = { device_make: string | null; device_model: string | null; color_space: string | null; color_profile: ColorProfile | null; focal_length: number | null; shutter_speed: number | null; flash: Flash | null; orientation: Orientation; lens_make: string | null; lens_model: string | null; bit_depth: number | null; red_eye: boolean | null; zoom: number | null; iso: number | null; software: string | null; serial_number: string | null; lens_serial_number: string | null; contrast: number | null; saturation: number | null; sharpness: number | null; composite: Composite | null }
 
-export type ChangeNodeNameArgs = { name: string | null; p2p_ipv4_port: Port | null; p2p_ipv6_port: Port | null; p2p_discovery: P2PDiscoveryState | null; image_labeler_version: string | null }
+export type ChangeNodeNameArgs = { name: string | null; p2p_ipv4_port: Port | null; p2p_ipv6_port: Port | null; p2p_discovery: P2PDiscoveryState | null; spacedrop_mode: SpacedropMode | null; spacedrop_timeout_secs: number | null; queue_spacedrop_when_unattended: boolean | null; spacedrop_parallelism: number | null; file_serve_concurrency: number | null; file_serve_bytes_per_sec: number | null; p2p_max_bytes_per_sec: number | null; public_locations: string[] | null; keep_alive_interval_secs: number | null; keep_alive_max_failures: number | null; clock_skew_warning_secs: number | null; p2p_header_timeout_secs: number | null; p2p_tunnel_timeout_secs: number | null; p2p_allowed_operations: OperationPolicy | null; p2p_stream_budgets: StreamBudgets | null; p2p_diagnostics: boolean | null; walks_per_spinning_disk: number | null; image_labeler_version: string | null }
 
 export type CloudInstance = { id: string; uuid: string; identity: RemoteIdentity; nodeId: string; metadata: { [key in string]: string } }
 
@@ -191,6 +228,15 @@ export type Composite =
  */
 "Live"
 
+export type ConfirmPairingArgs = { id: string; confirmed: boolean }
+
+export type ConnectionLogEntry = { timestamp: string; identity: RemoteIdentity; event: ConnectionLogEvent; listener: string | null; detail: string | null }
+
+/**
+ * What happened to the connection with a peer.
+ */
+export type ConnectionLogEvent = "Connected" | "Disconnected" | "ConnectFailed" | "Throttled" | "AddressChosen"
+
 /**
  * The method used for the connection with this peer.
  * *Technically* you can have multiple under the hood but this simplifies things for the UX.
@@ -211,6 +257,11 @@ export type CursorOrderItem<T> = { order: SortOrder; data: T }
 
 export type DefaultLocations = { desktop: boolean; documents: boolean; downloads: boolean; pictures: boolean; music: boolean; videos: boolean }
 
+/**
+ * How much attention a [`Diagnostic`] deserves.
+ */
+export type DiagnosticSeverity = "Debug" | "Info" | "Warning" | "Error"
+
 /**
  * The method used for the discovery of this peer.
  * *Technically* you can have multiple under the hood but this simplifies things for the UX.
@@ -221,7 +272,9 @@ export type DiskType = "SSD" | "HDD" | "Removable"
 
 export type DoubleClickAction = "openFile" | "quickPreview"
 
-export type EditLibraryArgs = { id: string; name: LibraryName | null; description: MaybeUndefined<string> }
+export type DownloadFileArgs = { identity: RemoteIdentity; file_path_id: string; to: string }
+
+export type EditLibraryArgs = { id: string; name: LibraryName | null; description: MaybeUndefined<string>; p2p_enabled?: boolean | null }
 
 export type EphemeralFileSystemOps = { sources: string[]; target_dir: string }
 
@@ -254,7 +307,7 @@ export type ExplorerSettings<TOrder> = { layoutMode: ExplorerLayout | null; grid
 
 export type Feedback = { message: string; emoji: number }
 
-export type FilePath = { id: number; pub_id: number[]; is_dir: boolean | null; cas_id: string | null; integrity_checksum: string | null; location_id: number | null; materialized_path: string | null; name: string | null; extension: string | null; hidden: boolean | null; size_in_bytes: string | null; size_in_bytes_bytes: number[] | null; inode: number[] | null; object_id: number | null; key_id: number | null; date_created: string | null; date_modified: string | null; date_indexed: string | null }
+export type FilePath = { id: number; pub_id: number[]; is_dir: boolean | null; cas_id: string | null; integrity_checksum: string | null; location_id: number | null; materialized_path: string | null; name: string | null; extension: string | null; hidden: boolean | null; size_in_bytes: string | null; size_in_bytes_bytes: number[] | null; inode: number[] | null; object_id: number | null; key_id: number | null; date_created: string | null; date_modified: string | null; date_indexed: string | null; scan_generation: number | null; pinned: boolean; is_placeholder: boolean | null; discovery_seq: number[] | null; child_files: number | null; child_dirs: number | null; is_location_boundary: boolean | null }
 
 export type FilePathCursor = { isDir: boolean; variant: FilePathCursorVariant }
 
@@ -268,7 +321,7 @@ export type FilePathOrder = { field: "name"; value: SortOrder } | { field: "size
 
 export type FilePathSearchArgs = { take?: number | null; orderAndPagination?: OrderAndPagination<number, FilePathOrder, FilePathCursor> | null; filters?: SearchFilterArgs[]; groupDirectories?: boolean }
 
-export type FilePathWithObject = { id: number; pub_id: number[]; is_dir: boolean | null; cas_id: string | null; integrity_checksum: string | null; location_id: number | null; materialized_path: string | null; name: string | null; extension: string | null; hidden: boolean | null; size_in_bytes: string | null; size_in_bytes_bytes: number[] | null; inode: number[] | null; object_id: number | null; key_id: number | null; date_created: string | null; date_modified: string | null; date_indexed: string | null; object: { id: number; pub_id: number[]; kind: number | null; key_id: number | null; hidden: boolean | null; favorite: boolean | null; important: boolean | null; note: string | null; date_created: string | null; date_accessed: string | null } | null }
+export type FilePathWithObject = { id: number; pub_id: number[]; is_dir: boolean | null; cas_id: string | null; integrity_checksum: string | null; location_id: number | null; materialized_path: string | null; name: string | null; extension: string | null; hidden: boolean | null; size_in_bytes: string | null; size_in_bytes_bytes: number[] | null; inode: number[] | null; object_id: number | null; key_id: number | null; date_created: string | null; date_modified: string | null; date_indexed: string | null; scan_generation: number | null; pinned: boolean; is_placeholder: boolean | null; discovery_seq: number[] | null; child_files: number | null; child_dirs: number | null; is_location_boundary: boolean | null; object: { id: number; pub_id: number[]; kind: number | null; key_id: number | null; hidden: boolean | null; favorite: boolean | null; important: boolean | null; note: string | null; date_created: string | null; date_accessed: string | null } | null }
 
 export type Flash = { 
 /**
@@ -327,13 +380,30 @@ export type GetAll = { backups: Backup[]; directory: string }
 
 export type HardwareModel = "Other" | "MacStudio" | "MacBookAir" | "MacBookPro" | "MacBook" | "MacMini" | "MacPro" | "IMac" | "IMacPro" | "IPad" | "IPhone" | "Simulator" | "Android"
 
+/**
+ * What a node says about itself when asked, unlike its mDNS metadata it's always up to date.
+ */
+export type IdentifyPayload = { metadata: PeerMetadata; 
+/**
+ * The libraries the node shares with whoever asked, leaving out the ones it excluded from P2P
+ */
+libraries: string[]; protocol_version: number; capabilities: string[]; 
+/**
+ * How long P2P has been running on the node
+ */
+uptime_secs: number }
+
 export type IdentifyUniqueFilesArgs = { id: number; path: string }
 
 export type ImageMetadata = { resolution: Resolution; date_taken: MediaDate | null; location: MediaLocation | null; camera_data: CameraData; artist: string | null; description: string | null; copyright: string | null; exif_version: string | null }
 
 export type InOrNotIn<T> = { in: T[] } | { notIn: T[] }
 
-export type IndexerRule = { id: number; pub_id: number[]; name: string | null; default: boolean | null; rules_per_kind: number[] | null; date_created: string | null; date_modified: string | null }
+export type IndexedEntry = { pub_id: string; kind: IndexedEntryKind; materialized_path: string; name: string; extension: string; is_dir: boolean }
+
+export type IndexedEntryKind = "Created" | "Updated"
+
+export type IndexerRule = { id: number; pub_id: number[]; name: string | null; default: boolean | null; rules_per_kind: number[] | null; date_created: string | null; date_modified: string | null; scope: string | null }
 
 /**
  * `IndexerRuleCreateArgs` is the argument received from the client using rspc to create a new indexer rule.
@@ -344,8 +414,24 @@ export type IndexerRule = { id: number; pub_id: number[]; name: string | null; d
  * 
  * In case of `RuleKind::AcceptIfChildrenDirectoriesArePresent` or `RuleKind::RejectIfChildrenDirectoriesArePresent` the
  * `parameters` field must be a vector of strings containing the names of the directories.
+ * 
+ * `scope` limits the rule to a sub-path of the locations it's applied to, e.g. `Photos/Exports`.
  */
-export type IndexerRuleCreateArgs = { name: string; dry_run: boolean; rules: ([RuleKind, string[]])[] }
+export type IndexerRuleCreateArgs = { name: string; dry_run: boolean; rules: ([RuleKind, string[]])[]; scope?: string | null }
+
+/**
+ * The groups are exported along with all their rules, even the ones missing from `rule_ids`
+ */
+export type IndexerRulesExportArgs = { rule_ids: number[]; group_ids?: number[] }
+
+export type IndexerRulesImportArgs = { document: JsonValue; on_collision: RuleCollision }
+
+/**
+ * An unsaved rule set to try on `path`, see [`super::IndexerRuleCreateArgs`] for `rules`.
+ */
+export type IndexerRulesPreviewArgs = { rules: ([RuleKind, string[]])[]; path: string; limit?: number | null }
+
+export type IndexingUpdate = { type: "Entries"; location_id: number; entries: IndexedEntry[] } | { type: "Resync"; location_id: number }
 
 export type InvalidateOperationEvent = { type: "single"; data: SingleInvalidateOperationEvent } | { type: "all" }
 
@@ -363,6 +449,11 @@ export type KindStatistic = { kind: number; name: string; count: number; total_b
 
 export type KindStatistics = { statistics: KindStatistic[] }
 
+/**
+ * A peer which is online or was seen before, so the device list can show it either way
+ */
+export type KnownPeer = { identity: RemoteIdentity; metadata: PeerMetadata; online: boolean; last_seen: string; addrs: string[] }
+
 export type Label = { id: number; name: string; date_created: string | null; date_modified: string | null }
 
 export type LabelWithObjects = { id: number; name: string; date_created: string | null; date_modified: string | null; label_objects: { object: { id: number; file_paths: FilePath[] } }[] }
@@ -392,7 +483,11 @@ instance_id: number;
  * cloud_id is the ID of the cloud library this library is linked to.
  * If this is set we can assume the library is synced with the Cloud.
  */
-cloud_id?: string | null; generate_sync_operations?: boolean; version: LibraryConfigVersion }
+cloud_id?: string | null; generate_sync_operations?: boolean; 
+/**
+ * p2p_enabled controls whether this library is advertised to, synced with and served to other nodes over P2P.
+ */
+p2p_enabled?: boolean; version: LibraryConfigVersion }
 
 export type LibraryConfigVersion = "V0" | "V1" | "V2" | "V3" | "V4" | "V5" | "V6" | "V7" | "V8" | "V9" | "V10"
 
@@ -406,7 +501,7 @@ export type LightScanArgs = { location_id: number; sub_path: string }
 
 export type Listener2 = { id: string; name: string; addrs: string[] }
 
-export type Location = { id: number; pub_id: number[]; name: string | null; path: string | null; total_capacity: number | null; available_capacity: number | null; size_in_bytes: number[] | null; is_archived: boolean | null; generate_preview_media: boolean | null; sync_preview_media: boolean | null; hidden: boolean | null; date_created: string | null; instance_id: number | null }
+export type Location = { id: number; pub_id: number[]; name: string | null; path: string | null; total_capacity: number | null; available_capacity: number | null; size_in_bytes: number[] | null; is_archived: boolean | null; generate_preview_media: boolean | null; sync_preview_media: boolean | null; hidden: boolean | null; background_indexing: boolean | null; defer_recently_modified: boolean | null; exclude_cloud_placeholders: boolean | null; track_discovery_order: boolean | null; cross_filesystems: boolean | null; max_walk_depth: number | null; walker_memory_budget: number | null; max_noncritical_ratio: number | null; max_noncritical_count: number | null; date_created: string | null; root_device: number[] | null; filesystem: string | null; attention: number | null; scan_generation: number; instance_id: number | null }
 
 /**
  * `LocationCreateArgs` is the argument received from the client using `rspc` to create a new location.
@@ -425,9 +520,45 @@ export type LocationSettings = { explorer: ExplorerSettings<FilePathOrder> }
  * It is important to note that only the indexer rule ids in this vector will be used from now on.
  * Old rules that aren't in this vector will be purged.
  */
-export type LocationUpdateArgs = { id: number; name: string | null; generate_preview_media: boolean | null; sync_preview_media: boolean | null; hidden: boolean | null; indexer_rules_ids: number[]; path: string | null }
+export type LocationUpdateArgs = { id: number; name: string | null; generate_preview_media: boolean | null; sync_preview_media: boolean | null; hidden: boolean | null; 
+/**
+ * Throttles the filesystem operations of this location's indexing, see [`indexer::IoThrottle`]
+ */
+background_indexing?: boolean | null; 
+/**
+ * Leaves the files modified too recently for a later walk, see [`OldIndexerJobInit::defer_recently_modified`]
+ */
+defer_recently_modified?: boolean | null; 
+/**
+ * Leaves out the files cloud sync clients only keep online, see [`OldIndexerJobInit::exclude_cloud_placeholders`]
+ */
+exclude_cloud_placeholders?: boolean | null; 
+/**
+ * Numbers the files scans add in the order they're found, see [`OldIndexerJobInit::track_discovery_order`]
+ */
+track_discovery_order?: boolean | null; 
+/**
+ * Walks into the drives mounted inside the location, see [`OldIndexerJobInit::cross_filesystems`]
+ */
+cross_filesystems?: boolean | null; 
+/**
+ * How deep below the location's root scans go, see [`OldIndexerJobInit::max_walk_depth`]
+ */
+max_walk_depth?: number | null; 
+/**
+ * In MiB, how much memory scans hold before writing, see [`OldIndexerJobInit::walker_memory_budget`]
+ */
+walker_memory_budget?: number | null; 
+/**
+ * Errors per entry found before scans pause, see [`ErrorPolicy::max_noncritical_ratio`]
+ */
+max_noncritical_ratio?: number | null; 
+/**
+ * Errors before scans pause, see [`ErrorPolicy::max_noncritical_count`]
+ */
+max_noncritical_count?: number | null; indexer_rules_ids: number[]; path: string | null }
 
-export type LocationWithIndexerRule = { id: number; pub_id: number[]; name: string | null; path: string | null; total_capacity: number | null; available_capacity: number | null; size_in_bytes: number[] | null; is_archived: boolean | null; generate_preview_media: boolean | null; sync_preview_media: boolean | null; hidden: boolean | null; date_created: string | null; instance_id: number | null; indexer_rules: Reference<IndexerRule>[] }
+export type LocationWithIndexerRule = { id: number; pub_id: number[]; name: string | null; path: string | null; total_capacity: number | null; available_capacity: number | null; size_in_bytes: number[] | null; is_archived: boolean | null; generate_preview_media: boolean | null; sync_preview_media: boolean | null; hidden: boolean | null; background_indexing: boolean | null; defer_recently_modified: boolean | null; exclude_cloud_placeholders: boolean | null; track_discovery_order: boolean | null; cross_filesystems: boolean | null; max_walk_depth: number | null; walker_memory_budget: number | null; max_noncritical_ratio: number | null; max_noncritical_count: number | null; date_created: string | null; root_device: number[] | null; filesystem: string | null; attention: number | null; scan_generation: number; instance_id: number | null; indexer_rules: Reference<IndexerRule>[] }
 
 export type MaybeUndefined<T> = null | T
 
@@ -453,7 +584,7 @@ id: string;
 /**
  * name is the display name of the current node. This is set by the user and is shown in the UI. // TODO: Length validation so it can fit in DNS record
  */
-name: string; identity: RemoteIdentity; p2p_ipv4_port: Port; p2p_ipv6_port: Port; p2p_discovery: P2PDiscoveryState; features: BackendFeature[]; preferences: NodePreferences; image_labeler_version: string | null }) & { data_path: string; listeners: Listener2[]; device_model: string | null }
+name: string; identity: RemoteIdentity; p2p_ipv4_port: Port; p2p_ipv6_port: Port; p2p_discovery: P2PDiscoveryState; spacedrop_mode: SpacedropMode; spacedrop_timeout_secs: number | null; queue_spacedrop_when_unattended: boolean; spacedrop_parallelism: number | null; file_serve_concurrency: number | null; file_serve_bytes_per_sec: number | null; p2p_max_bytes_per_sec: number | null; public_locations: string[]; keep_alive_interval_secs: number | null; keep_alive_max_failures: number | null; clock_skew_warning_secs: number | null; p2p_header_timeout_secs: number | null; p2p_tunnel_timeout_secs: number | null; p2p_allowed_operations: OperationPolicy | null; p2p_stream_budgets: StreamBudgets | null; p2p_diagnostics: boolean; walks_per_spinning_disk: number | null; features: BackendFeature[]; preferences: NodePreferences; image_labeler_version: string | null }) & { data_path: string; listeners: Listener2[]; device_model: string | null }
 
 export type NonIndexedPathItem = { path: string; name: string; extension: string; kind: number; is_dir: boolean; date_created: string; date_modified: string; size_in_bytes_bytes: number[]; hidden: boolean }
 
@@ -512,26 +643,82 @@ export type OldFileDeleterJobInit = { location_id: number; file_path_ids: number
 
 export type OldFileEraserJobInit = { location_id: number; file_path_ids: number[]; passes: string }
 
+/**
+ * Identifies a single operation on both peers, so the logs and events of each side can be matched up.
+ * 
+ * It's picked by the peer starting the operation and sent with its [`Header`].
+ */
+export type OpId = string
+
 /**
  * Represents the operating system which the remote peer is running.
  * This is not used internally and predominantly is designed to be used for display purposes by the embedding application.
  */
 export type OperatingSystem = "Windows" | "Linux" | "MacOS" | "Ios" | "Android" | { Other: string }
 
+export type OperationMetrics = { operation: P2POperation; bytes_sent: string; bytes_received: string; streams: number; failures: number; timeouts: number; 
+/**
+ * Streams which had to wait for one of the operation's streams to be closed, see [`super::StreamBudgets`]
+ */
+queued: number; 
+/**
+ * How long they waited altogether
+ */
+queue_wait_ms: number }
+
+export type OperationPolicy = { lan?: P2POperation[]; manual?: P2POperation[]; relay?: P2POperation[] }
+
 export type OrderAndPagination<TId, TOrder, TCursor> = { orderOnly: TOrder } | { offset: { offset: number; order: TOrder | null } } | { cursor: { id: TId; cursor: TCursor } }
 
 export type Orientation = "Normal" | "CW90" | "CW180" | "CW270" | "MirroredVertical" | "MirroredHorizontal" | "MirroredHorizontalAnd90CW" | "MirroredHorizontalAnd270CW"
 
 export type P2PDiscoveryState = "Everyone" | "ContactsOnly" | "Disabled"
 
-export type P2PEvent = { type: "PeerChange"; identity: RemoteIdentity; connection: ConnectionMethod; discovery: DiscoveryMethod; metadata: PeerMetadata } | { type: "PeerDelete"; identity: RemoteIdentity } | { type: "SpacedropRequest"; id: string; identity: RemoteIdentity; peer_name: string; files: string[] } | { type: "SpacedropProgress"; id: string; percent: number } | { type: "SpacedropTimedOut"; id: string } | { type: "SpacedropRejected"; id: string }
+export type P2PEvent = { type: "PeerChange"; identity: RemoteIdentity; connection: ConnectionMethod; discovery: DiscoveryMethod; metadata: PeerMetadata } | { type: "PeerDelete"; identity: RemoteIdentity } | { type: "ConnectedPeer"; identity: RemoteIdentity; via: string; remote_addr: string | null } | { type: "DisconnectedPeer"; identity: RemoteIdentity; via: string; remote_addr: string | null } | { type: "SpacedropRequest"; id: string; op_id: OpId; identity: RemoteIdentity; peer_name: string; display_name: string; kind: SpacedropKind; files: string[] } | { type: "SpacedropQueued"; id: string; op_id: OpId; position: number } | { type: "SpacedropProgress"; id: string; op_id: OpId; percent: number } | { type: "SpacedropTimedOut"; id: string; op_id: OpId; identity: RemoteIdentity; files: string[]; total_bytes: string } | { type: "SpacedropRejected"; id: string; op_id: OpId; identity: RemoteIdentity; files: string[]; total_bytes: string } | { type: "SpacedropFailed"; id: string; op_id: OpId; reason: string } | { type: "SpacedropCompleted"; id: string; op_id: OpId; identity: RemoteIdentity; files: string[]; total_bytes: string; saved_paths: string[] } | { type: "SyncProgress"; library_id: string; identity: RemoteIdentity; op_id: OpId; operations_sent: number; operations_received: number; phase: SyncPhase } | { type: "SelfMetadataUpdated"; metadata: PeerMetadata; listeners: Listener2[] } | { type: "ListenerError"; v6: boolean; port: number; error: string } | { type: "PairingRequest"; id: string; identity: RemoteIdentity; code: string } | { type: "PairingCompleted"; id: string; identity: RemoteIdentity } | { type: "PairingFailed"; id: string; identity: RemoteIdentity; reason: string } | { type: "FileContentMismatch"; library_id: string; file_path_id: string; expected: string; actual: string } | { type: "ClockSkewWarning"; identity: RemoteIdentity; skew_ms: string } | { type: "Diagnostic"; severity: DiagnosticSeverity; subsystem: string; message: string; timestamp: string }
 
-export type PeerMetadata = { name: string; operating_system: OperatingSystem | null; device_model: HardwareModel | null; version: string | null }
+export type P2PMetricsSnapshot = { since: string; operations: OperationMetrics[]; header_timeouts: number; 
+/**
+ * The size of the files Spacedrops sent and received, compared with the bytes of the Spacedrop
+ * operation it tells how much compression saved
+ */
+spacedrop_file_bytes: string }
+
+export type P2POperation = "Ping" | "Spacedrop" | "Sync" | "File"
+
+/**
+ * The outcome of [`P2PManager::self_test`](super::P2PManager::self_test), for the settings page to
+ * tell why this node can't see or reach other nodes
+ */
+export type P2PSelfTestReport = { ran_at: string; 
+/**
+ * In the order they ran
+ */
+checks: SelfTestCheck[] }
+
+export type PeerMetadata = { name: string; operating_system: OperatingSystem | null; device_model: HardwareModel | null; version: string | null; succession: string | null; 
+/**
+ * Who the peer takes Spacedrops from, older versions don't advertise it and take them from everyone
+ */
+spacedrop_mode: SpacedropMode | null }
 
 export type PlusCode = string
 
 export type Port = null | number
 
+/**
+ * If a port can be used for the P2P listener.
+ */
+export type PortStatus = { type: "Available" } | { type: "InUse" } | { type: "Unavailable"; error: string }
+
+/**
+ * The entries a [`ScanPreview`] found would change one way
+ */
+export type PreviewedChanges = { count: number; 
+/**
+ * Relative to the location, at most [`MAX_SCAN_PREVIEW_PATHS`] of them
+ */
+sample_paths: string[] }
+
 export type Range<T> = { from: T } | { to: T }
 
 /**
@@ -560,32 +747,201 @@ export type Resolution = { width: number; height: number }
 
 export type Response = { Start: { user_code: string; verification_url: string; verification_url_complete: string } } | "Complete" | { Error: string }
 
+/**
+ * What to do with an imported rule or group named like one already in the library
+ */
+export type RuleCollision = 
+/**
+ * Imports it under a free name, like `No Hidden (2)`
+ */
+"Rename" | "Skip" | 
+/**
+ * Overwrites the library's rule, system rules are never overwritten so they're renamed instead
+ */
+"Replace"
+
+/**
+ * What the indexer would do with a previewed entry
+ */
+export type RuleDecision = "Accepted" | "Rejected" | 
+/**
+ * A directory which isn't indexed itself, but whose children would still be checked
+ */
+"WalkedInto" | 
+/**
+ * The entry couldn't be read or evaluated
+ */
+{ Error: string }
+
+/**
+ * A named set of indexer rules, like a preset, which is assigned to locations as a whole.
+ * Changing its rules changes them for every location it's assigned to.
+ */
+export type RuleGroup = { id: number; name: string; rule_ids: number[] }
+
+export type RuleGroupCreateArgs = { name: string; rule_ids: number[] }
+
+export type RuleGroupForLocationArgs = { location_id: number; group_id: number; enabled: boolean }
+
+/**
+ * Fields left out are kept, `rule_ids` replaces all the group's rules at once
+ */
+export type RuleGroupUpdateArgs = { id: number; name?: string | null; rule_ids?: number[] | null }
+
 export type RuleKind = "AcceptFilesByGlob" | "RejectFilesByGlob" | "AcceptIfChildrenDirectoriesArePresent" | "RejectIfChildrenDirectoriesArePresent"
 
 export type SavedSearch = { id: number; pub_id: number[]; search: string | null; filters: string | null; name: string | null; icon: string | null; description: string | null; date_created: string | null; date_modified: string | null }
 
+/**
+ * How many non critical errors of this kind the scan ran into, across all directories
+ */
+export type ScanErrorCount = { kind: string; count: number }
+
+/**
+ * What indexing a location again would change, see [`preview_scan`]
+ */
+export type ScanPreview = { to_create: PreviewedChanges; to_update: PreviewedChanges; to_remove: PreviewedChanges; 
+/**
+ * Non critical errors, the entries they happened on aren't counted
+ */
+errors: number }
+
+export type ScanPreviewArgs = { location_id: number; sub_path?: string | null }
+
+/**
+ * One of a location's last scans, see [`ScanSummary`]
+ */
+export type ScanRecord = { 
+/**
+ * Grows by one with every scan of the location
+ */
+generation: number; summary: ScanSummary }
+
+export type ScanRuleHits = { name: string; accepted: number; rejected: number }
+
+/**
+ * What's left of an indexer job once it's done, to tell how the location's last scans went
+ * without going through the job history
+ */
+export type ScanSummary = { started_at: string; finished_at: string; 
+/**
+ * The directory scanned relative to the location, `None` when the whole location was
+ */
+sub_path: string | null; found_entries: number; indexed: number; updated: number; removed: number; 
+/**
+ * Entries deleted while they were being walked
+ */
+vanished: number; 
+/**
+ * How many directories down from the location's root the scan went
+ */
+max_depth_reached: number; 
+/**
+ * Directories on other filesystems which weren't walked into, see [`super::OldIndexerJobInit::cross_filesystems`]
+ */
+skipped_mounts: number; bytes_indexed: string; scan_read_ms: number; db_write_ms: number; errors: ScanErrorCount[]; rule_hits: ScanRuleHits[] }
+
 export type SearchData<T> = { cursor: number[] | null; items: Reference<T>[]; nodes: CacheNode[] }
 
 export type SearchFilterArgs = { filePath: FilePathFilterArgs } | { object: ObjectFilterArgs }
 
+export type SelfTestCheck = { kind: SelfTestCheckKind; status: SelfTestStatus; 
+/**
+ * What was found, to be shown as is
+ */
+detail: string }
+
+export type SelfTestCheckKind = "Listeners" | "Mdns" | "Udp" | "Ping"
+
+export type SelfTestStatus = "Passed" | "Failed" | "Skipped"
+
 export type SetFavoriteArgs = { id: number; favorite: boolean }
 
 export type SetNoteArgs = { id: number; note: string | null }
 
+export type SetPeerAliasArgs = { identity: RemoteIdentity; alias: string | null }
+
 export type SingleInvalidateOperationEvent = { 
 /**
  * This fields are intentionally private.
  */
 key: string; arg: JsonValue; result: JsonValue | null }
 
+export type SkippedObject = { object_id: number; reason: SpacedropSkipReason }
+
 export type SortOrder = "Asc" | "Desc"
 
 export type SpacedropArgs = { identity: RemoteIdentity; file_path: string[] }
 
+export type SpacedropDirection = "Sent" | "Received"
+
+export type SpacedropHistoryEntry = { id: string; direction: SpacedropDirection; identity: RemoteIdentity; peer_name: string | null; files: string[]; total_bytes: string; outcome: SpacedropOutcome; reason: string | null; saved_paths: string[]; started_at: string; finished_at: string }
+
+/**
+ * Which entries of the [`SpacedropHistory`] to list, everything when empty.
+ */
+export type SpacedropHistoryFilter = { direction: SpacedropDirection | null; identity: RemoteIdentity | null; outcome: SpacedropOutcome | null; file_name: string | null }
+
+/**
+ * What an incoming Spacedrop contains so the frontend can render the right prompt.
+ */
+export type SpacedropKind = { type: "Files" } | { type: "Text"; preview: string; len: number }
+
+/**
+ * Who can Spacedrop to this node. It's advertised to other nodes so they know before trying, but
+ * it's checked again when a Spacedrop comes in as what they saw may be out of date.
+ */
+export type SpacedropMode = "Off" | 
+/**
+ * Only the nodes we paired with
+ */
+"Contacts" | "Everyone"
+
+/**
+ * The outcome of [`spacedrop_objects`]
+ */
+export type SpacedropObjects = { 
+/**
+ * The Spacedrop of the objects which could be found, [`None`] if none of them could be
+ */
+id: string | null; skipped: SkippedObject[] }
+
+export type SpacedropObjectsArgs = { identity: RemoteIdentity; object_ids: number[] }
+
+export type SpacedropOutcome = "Completed" | "Rejected" | "TimedOut" | "Failed" | "Cancelled"
+
+/**
+ * Why an object was left out of a Spacedrop
+ */
+export type SpacedropSkipReason = 
+/**
+ * None of the object's files are in a location on this node
+ */
+"NoFilePath" | 
+/**
+ * The object's files are only in locations which are offline
+ */
+"LocationOffline" | 
+/**
+ * The object's file was indexed but is no longer on disk
+ */
+"FileMissing"
+
+export type SpacedropTextArgs = { identity: RemoteIdentity; text: string }
+
 export type Statistics = { id: number; date_captured: string; total_object_count: number; library_db_size: string; total_bytes_used: string; total_bytes_capacity: string; total_unique_bytes: string; total_bytes_free: string; preview_media_bytes: string }
 
 export type StatisticsResponse = { statistics: Statistics | null }
 
+/**
+ * How many streams of each operation can be open at once, the rest wait for one of them to be closed.
+ * Without them a sync backfill, which opens streams one after the other, can use up all the streams
+ * the transport allows so a Spacedrop times out before it even starts. Unlimited when `None`.
+ */
+export type StreamBudgets = { ping?: number | null; spacedrop?: number | null; sync?: number | null; file?: number | null }
+
+export type SyncPhase = "Handshake" | "Backfill" | "Live" | "Done"
+
 export type SystemLocations = { desktop: string | null; documents: string | null; downloads: string | null; pictures: string | null; music: string | null; videos: string | null }
 
 export type Tag = { id: number; pub_id: number[]; name: string | null; color: string | null; is_hidden: boolean | null; date_created: string | null; date_modified: string | null }
@@ -596,6 +952,8 @@ export type TagUpdateArgs = { id: number; name: string | null; color: string | n
 
 export type Target = { Object: number } | { FilePath: number }
 
+export type TestPortArgs = { port: number; v6: boolean }
+
 export type TestingParams = { id: string; path: string }
 
 export type TextMatch = { contains: string } | { startsWith: string } | { endsWith: string } | { equals: string }
@@ -604,6 +962,8 @@ export type ThumbnailerPreferences = { background_processing_percentage: number
 
 export type UpdateThumbnailerPreferences = { background_processing_percentage: number }
 
+export type VerifyLocationArgs = { id: number; fix?: boolean }
+
 export type VideoMetadata = { duration: number | null; video_codec: string | null; audio_codec: string | null }
 
 export type Volume = { name: string; mount_points: string[]; total_capacity: string; available_capacity: string; disk_type: DiskType; file_system: string | null; is_root_filesystem: boolean }